@@ -22,8 +22,8 @@ use syn::{
     parse_macro_input, parse_quote, parse_str,
     punctuated::Punctuated,
     token::Comma,
-    Attribute, FnArg, Ident, Lit, LitBool, MetaNameValue, Pat, PatType, ReturnType, Token, Type,
-    Visibility,
+    Attribute, FnArg, GenericArgument, Ident, Lit, LitBool, Meta, MetaNameValue, NestedMeta, Pat,
+    PatType, PathArguments, ReturnType, Token, Type, TypeParamBound, Visibility,
 };
 
 struct Service {
@@ -35,11 +35,109 @@ struct Service {
 
 struct RpcMethod {
     attrs: Vec<Attribute>,
+    policy: Policy,
     ident: Ident,
     args: Vec<PatType>,
     output: ReturnType,
 }
 
+/// The policy declared for a method by its `#[tarpc(..)]` attribute.
+#[derive(Default)]
+struct Policy {
+    /// The latest deadline, relative to the call, of the method's requests.
+    deadline: Option<u64>,
+    /// How many times a failed call is retried.
+    retries: u32,
+    /// The fn that validates the method's args before the server serves them.
+    validate: Option<syn::Path>,
+    /// How long the client may cache the method's responses, if at all.
+    cache: Option<u64>,
+    /// Whether the server executes each call once, however many times it's retransmitted.
+    exactly_once: bool,
+}
+
+impl Policy {
+    /// Parses the `#[tarpc(deadline = "1s", idempotent, retries = 2, validate = "f",
+    /// cache = "30s", exactly_once)]` attributes in `attrs`, removing them.
+    fn parse(attrs: &mut Vec<Attribute>) -> syn::Result<Self> {
+        let mut policy = Policy::default();
+        let mut idempotent = false;
+        let mut retries = None;
+        for attr in attrs.iter().filter(|attr| attr.path.is_ident("tarpc")) {
+            let list = match attr.parse_meta()? {
+                Meta::List(list) => list,
+                meta => return Err(syn::Error::new_spanned(meta, "expected `#[tarpc(..)]`")),
+            };
+            for nested in list.nested {
+                match nested {
+                    NestedMeta::Meta(Meta::Path(ref path)) if path.is_ident("idempotent") => {
+                        idempotent = true;
+                    }
+                    NestedMeta::Meta(Meta::Path(ref path)) if path.is_ident("exactly_once") => {
+                        policy.exactly_once = true;
+                    }
+                    NestedMeta::Meta(Meta::NameValue(MetaNameValue {
+                        ref path,
+                        lit: Lit::Str(ref lit),
+                        ..
+                    })) if path.is_ident("deadline") => {
+                        let deadline = humantime::parse_duration(&lit.value()).map_err(|e| {
+                            syn::Error::new_spanned(lit, format!("invalid deadline: {}", e))
+                        })?;
+                        policy.deadline = Some(deadline.as_millis() as u64);
+                    }
+                    NestedMeta::Meta(Meta::NameValue(MetaNameValue {
+                        ref path,
+                        lit: Lit::Str(ref lit),
+                        ..
+                    })) if path.is_ident("cache") => {
+                        let ttl = humantime::parse_duration(&lit.value()).map_err(|e| {
+                            syn::Error::new_spanned(lit, format!("invalid cache duration: {}", e))
+                        })?;
+                        policy.cache = Some(ttl.as_millis() as u64);
+                    }
+                    NestedMeta::Meta(Meta::NameValue(MetaNameValue {
+                        ref path,
+                        lit: Lit::Int(ref lit),
+                        ..
+                    })) if path.is_ident("retries") => {
+                        retries = Some((lit.base10_parse()?, lit.clone()));
+                    }
+                    NestedMeta::Meta(Meta::NameValue(MetaNameValue {
+                        ref path,
+                        lit: Lit::Str(ref lit),
+                        ..
+                    })) if path.is_ident("validate") => {
+                        policy.validate = Some(lit.parse()?);
+                    }
+                    nested => {
+                        return Err(syn::Error::new_spanned(
+                            nested,
+                            "expected `deadline = \"..\"`, `idempotent`, `retries = N`, `validate = \"..\"`, \
+                             `cache = \"..\"`, or `exactly_once`",
+                        ))
+                    }
+                }
+            }
+        }
+        attrs.retain(|attr| !attr.path.is_ident("tarpc"));
+        // Calls executed exactly once are safe to retry.
+        let idempotent = idempotent || policy.exactly_once;
+        policy.retries = match retries {
+            Some((_, lit)) if !idempotent => {
+                return Err(syn::Error::new_spanned(
+                    lit,
+                    "only `idempotent` or `exactly_once` methods can be retried",
+                ))
+            }
+            Some((retries, _)) => retries,
+            None if idempotent => 1,
+            None => 0,
+        };
+        Ok(policy)
+    }
+}
+
 impl Parse for Service {
     fn parse(input: ParseStream) -> syn::Result<Self> {
         let attrs = input.call(Attribute::parse_outer)?;
@@ -78,7 +176,8 @@ impl Parse for Service {
 
 impl Parse for RpcMethod {
     fn parse(input: ParseStream) -> syn::Result<Self> {
-        let attrs = input.call(Attribute::parse_outer)?;
+        let mut attrs = input.call(Attribute::parse_outer)?;
+        let policy = Policy::parse(&mut attrs)?;
         input.parse::<Token![async]>()?;
         input.parse::<Token![fn]>()?;
         let ident = input.parse()?;
@@ -100,6 +199,7 @@ impl Parse for RpcMethod {
 
         Ok(Self {
             attrs,
+            policy,
             ident,
             args,
             output,
@@ -166,6 +266,39 @@ pub fn service(attr: TokenStream, input: TokenStream) -> TokenStream {
         .collect();
     let args: &[&[PatType]] = &rpcs.iter().map(|rpc| &*rpc.args).collect::<Vec<_>>();
     let response_fut_name = &format!("{}ResponseFut", ident.unraw());
+    let response_stream_name = &format!("{}ResponseStream", ident.unraw());
+    let return_types: &Vec<&Type> = &rpcs
+        .iter()
+        .map(|rpc| match rpc.output {
+            ReturnType::Type(_, ref ty) => ty,
+            ReturnType::Default => unit_type,
+        })
+        .collect::<Vec<_>>();
+    // Methods declared to return `impl Stream<Item = T>` stream their replies.
+    let stream_items = &return_types
+        .iter()
+        .map(|ty| stream_item_type(ty))
+        .collect::<Vec<_>>();
+    for (rpc, item) in rpcs.iter().zip(stream_items.iter()) {
+        if rpc.policy.exactly_once && item.is_some() {
+            return syn::Error::new_spanned(
+                &rpc.ident,
+                "streaming methods can't be executed exactly once",
+            )
+            .to_compile_error()
+            .into();
+        }
+        if rpc.policy.retries > 0 && item.is_some() {
+            return syn::Error::new_spanned(&rpc.ident, "streaming methods can't be retried")
+                .to_compile_error()
+                .into();
+        }
+        if rpc.policy.cache.is_some() && item.is_some() {
+            return syn::Error::new_spanned(&rpc.ident, "streaming methods can't be cached")
+                .to_compile_error()
+                .into();
+        }
+    }
     let derive_serialize = if derive_serde.0 {
         Some(quote!(#[derive(serde::Serialize, serde::Deserialize)]))
     } else {
@@ -176,9 +309,12 @@ pub fn service(attr: TokenStream, input: TokenStream) -> TokenStream {
         response_fut_name,
         service_ident: ident,
         server_ident: &format_ident!("Serve{}", ident),
-        response_fut_ident: &Ident::new(&response_fut_name, ident.span()),
+        response_fut_ident: &Ident::new(response_fut_name, ident.span()),
+        response_stream_name,
+        response_stream_ident: &Ident::new(response_stream_name, ident.span()),
         client_ident: &format_ident!("{}Client", ident),
         request_ident: &format_ident!("{}Request", ident),
+        cache_key_ident: &format_ident!("{}CacheKey", ident),
         response_ident: &format_ident!("{}Response", ident),
         vis,
         args,
@@ -186,13 +322,12 @@ pub fn service(attr: TokenStream, input: TokenStream) -> TokenStream {
         method_idents: &rpcs.iter().map(|rpc| &rpc.ident).collect::<Vec<_>>(),
         attrs,
         rpcs,
-        return_types: &rpcs
+        return_types: &return_types
             .iter()
-            .map(|rpc| match rpc.output {
-                ReturnType::Type(_, ref ty) => ty,
-                ReturnType::Default => unit_type,
-            })
+            .zip(stream_items.iter())
+            .map(|(ty, item)| item.unwrap_or(ty))
             .collect::<Vec<_>>(),
+        streaming: &stream_items.iter().map(Option::is_some).collect::<Vec<_>>(),
         arg_pats: &args
             .iter()
             .map(|args| args.iter().map(|arg| &*arg.pat).collect())
@@ -204,7 +339,11 @@ pub fn service(attr: TokenStream, input: TokenStream) -> TokenStream {
             .collect::<Vec<_>>(),
         future_types: &camel_case_fn_names
             .iter()
-            .map(|name| parse_str(&format!("{}Fut", name)).unwrap())
+            .zip(stream_items.iter())
+            .map(|(name, item)| {
+                let suffix = if item.is_some() { "Stream" } else { "Fut" };
+                parse_str(&format!("{}{}", name, suffix)).unwrap()
+            })
             .collect::<Vec<_>>(),
         derive_serialize: derive_serialize.as_ref(),
     }
@@ -219,8 +358,11 @@ struct ServiceGenerator<'a> {
     server_ident: &'a Ident,
     response_fut_ident: &'a Ident,
     response_fut_name: &'a str,
+    response_stream_ident: &'a Ident,
+    response_stream_name: &'a str,
     client_ident: &'a Ident,
     request_ident: &'a Ident,
+    cache_key_ident: &'a Ident,
     response_ident: &'a Ident,
     vis: &'a Visibility,
     attrs: &'a [Attribute],
@@ -230,7 +372,9 @@ struct ServiceGenerator<'a> {
     method_idents: &'a [&'a Ident],
     method_attrs: &'a [&'a [Attribute]],
     args: &'a [&'a [PatType]],
+    /// The type of each method's response, or, for streaming methods, of each item of its reply.
     return_types: &'a [&'a Type],
+    streaming: &'a [bool],
     arg_pats: &'a [Vec<&'a Pat>],
     derive_serialize: Option<&'a TokenStream2>,
 }
@@ -243,6 +387,7 @@ impl<'a> ServiceGenerator<'a> {
             vis,
             future_types,
             return_types,
+            streaming,
             service_ident,
             server_ident,
             ..
@@ -252,20 +397,35 @@ impl<'a> ServiceGenerator<'a> {
             .iter()
             .zip(future_types.iter())
             .zip(return_types.iter())
+            .zip(streaming.iter())
             .map(
                 |(
                     (
-                        RpcMethod {
-                            attrs, ident, args, ..
-                        },
-                        future_type,
+                        (
+                            RpcMethod {
+                                attrs, ident, args, ..
+                            },
+                            future_type,
+                        ),
+                        output,
                     ),
-                    output,
+                    &streaming,
                 )| {
-                    let ty_doc = format!("The response future returned by {}.", ident);
+                    let assoc_type = if streaming {
+                        let ty_doc = format!("The reply stream returned by {}.", ident);
+                        quote! {
+                            #[doc = #ty_doc]
+                            type #future_type: tarpc::futures::Stream<Item = #output>;
+                        }
+                    } else {
+                        let ty_doc = format!("The response future returned by {}.", ident);
+                        quote! {
+                            #[doc = #ty_doc]
+                            type #future_type: std::future::Future<Output = #output>;
+                        }
+                    };
                     quote! {
-                        #[doc = #ty_doc]
-                        type #future_type: std::future::Future<Output = #output>;
+                        #assoc_type
 
                         #( #attrs )*
                         fn #ident(self, context: tarpc::context::Context, #( #args ),*) -> Self::#future_type;
@@ -299,6 +459,14 @@ impl<'a> ServiceGenerator<'a> {
         }
     }
 
+    fn has_unary_methods(&self) -> bool {
+        self.streaming.contains(&false)
+    }
+
+    fn has_streaming_methods(&self) -> bool {
+        self.streaming.contains(&true)
+    }
+
     fn impl_serve_for_server(&self) -> TokenStream2 {
         let &Self {
             request_ident,
@@ -306,30 +474,80 @@ impl<'a> ServiceGenerator<'a> {
             service_ident,
             response_ident,
             response_fut_ident,
+            response_stream_ident,
             camel_case_idents,
             arg_pats,
             method_idents,
+            streaming,
             ..
         } = self;
 
+        if !self.has_streaming_methods() {
+            return quote! {
+                impl<S> tarpc::server::Serve<#request_ident> for #server_ident<S>
+                    where S: #service_ident
+                {
+                    type Resp = #response_ident;
+                    type Fut = #response_fut_ident<S>;
+
+                    fn serve(self, ctx: tarpc::context::Context, req: #request_ident) -> Self::Fut {
+                        match req {
+                            #(
+                                #request_ident::#camel_case_idents{ #( #arg_pats ),* } => {
+                                    #response_fut_ident::#camel_case_idents(
+                                        #service_ident::#method_idents(
+                                            self.service, ctx, #( #arg_pats ),*
+                                        )
+                                    )
+                                }
+                            )*
+                        }
+                    }
+                }
+            };
+        }
+
+        let fut = if self.has_unary_methods() {
+            quote!(#response_fut_ident<S>)
+        } else {
+            quote!(tarpc::futures::future::Pending<#response_ident>)
+        };
+        let arms = camel_case_idents
+            .iter()
+            .zip(arg_pats.iter())
+            .zip(method_idents.iter())
+            .zip(streaming.iter())
+            .map(|(((camel_case_ident, arg_pats), method_ident), &streaming)| {
+                let reply = quote! {
+                    #service_ident::#method_ident(self.service, ctx, #( #arg_pats ),*)
+                };
+                let reply = if streaming {
+                    quote!(tarpc::server::Reply::Stream(#response_stream_ident::#camel_case_ident(#reply)))
+                } else {
+                    quote!(tarpc::server::Reply::Unary(#response_fut_ident::#camel_case_ident(#reply)))
+                };
+                quote! {
+                    #request_ident::#camel_case_ident{ #( #arg_pats ),* } => #reply,
+                }
+            });
+
         quote! {
-            impl<S> tarpc::server::Serve<#request_ident> for #server_ident<S>
+            impl<S> tarpc::server::ServeStream<#request_ident> for #server_ident<S>
                 where S: #service_ident
             {
                 type Resp = #response_ident;
-                type Fut = #response_fut_ident<S>;
+                type Fut = #fut;
+                type Stream = #response_stream_ident<S>;
 
-                fn serve(self, ctx: tarpc::context::Context, req: #request_ident) -> Self::Fut {
+                fn serve_stream(
+                    self,
+                    ctx: tarpc::context::Context,
+                    req: #request_ident,
+                    _: tarpc::server::RequestItems<#request_ident>,
+                ) -> tarpc::server::Reply<Self::Fut, Self::Stream>
+                {
                     match req {
-                        #(
-                            #request_ident::#camel_case_idents{ #( #arg_pats ),* } => {
-                                #response_fut_ident::#camel_case_idents(
-                                    #service_ident::#method_idents(
-                                        self.service, ctx, #( #arg_pats ),*
-                                    )
-                                )
-                            }
-                        )*
+                        #( #arms )*
                     }
                 }
             }
@@ -356,6 +574,184 @@ impl<'a> ServiceGenerator<'a> {
         }
     }
 
+    fn impl_request_name_for_request(&self) -> TokenStream2 {
+        let &Self {
+            request_ident,
+            camel_case_idents,
+            method_idents,
+            ..
+        } = self;
+        let method_names = method_idents.iter().map(|ident| ident.unraw().to_string());
+
+        quote! {
+            impl tarpc::RequestName for #request_ident {
+                fn name(&self) -> &'static str {
+                    match self {
+                        #(
+                            #request_ident::#camel_case_idents{ .. } => #method_names,
+                        )*
+                    }
+                }
+            }
+        }
+    }
+
+    fn impl_validator_for_request(&self) -> TokenStream2 {
+        let &Self {
+            request_ident,
+            camel_case_idents,
+            arg_pats,
+            rpcs,
+            ..
+        } = self;
+        let arms = camel_case_idents
+            .iter()
+            .zip(arg_pats.iter())
+            .zip(rpcs.iter())
+            .map(|((camel_case_ident, arg_pats), rpc)| match rpc.policy.validate {
+                Some(ref validate) => quote! {
+                    #request_ident::#camel_case_ident { #( #arg_pats ),* } => #validate(#( #arg_pats ),*)
+                },
+                None => quote! {
+                    #request_ident::#camel_case_ident { .. } => std::result::Result::Ok(())
+                },
+            });
+
+        quote! {
+            impl tarpc::server::Validator<#request_ident> for tarpc::server::Declared {
+                fn validate(&self, request: &#request_ident) -> std::result::Result<(), String> {
+                    match request {
+                        #( #arms, )*
+                    }
+                }
+            }
+        }
+    }
+
+    fn enum_cache_key(&self) -> TokenStream2 {
+        let &Self {
+            vis,
+            cache_key_ident,
+            camel_case_idents,
+            args,
+            rpcs,
+            ..
+        } = self;
+        let variants = camel_case_idents
+            .iter()
+            .zip(args.iter())
+            .zip(rpcs.iter())
+            .filter(|(_, rpc)| rpc.policy.cache.is_some())
+            .map(|((camel_case_ident, args), _)| quote!(#camel_case_ident { #( #args ),* }));
+
+        quote! {
+            /// Identifies the requests whose responses clients may cache.
+            #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+            #vis enum #cache_key_ident {
+                #( #variants ),*
+            }
+        }
+    }
+
+    fn impl_cacheable_for_request(&self) -> TokenStream2 {
+        let &Self {
+            request_ident,
+            response_ident,
+            cache_key_ident,
+            camel_case_idents,
+            arg_pats,
+            rpcs,
+            ..
+        } = self;
+        let arms = camel_case_idents
+            .iter()
+            .zip(arg_pats.iter())
+            .zip(rpcs.iter())
+            .map(|((camel_case_ident, arg_pats), rpc)| match rpc.policy.cache {
+                Some(millis) => quote! {
+                    #request_ident::#camel_case_ident { #( #arg_pats ),* } => std::option::Option::Some((
+                        #cache_key_ident::#camel_case_ident {
+                            #( #arg_pats: std::clone::Clone::clone(#arg_pats) ),*
+                        },
+                        std::time::Duration::from_millis(#millis),
+                    ))
+                },
+                None => quote! {
+                    #request_ident::#camel_case_ident { .. } => std::option::Option::None
+                },
+            });
+        let cached = camel_case_idents
+            .iter()
+            .zip(rpcs.iter())
+            .filter(|(_, rpc)| rpc.policy.cache.is_some())
+            .map(|(camel_case_ident, _)| camel_case_ident);
+
+        quote! {
+            impl tarpc::client::cache::Cacheable for #request_ident {
+                type Key = #cache_key_ident;
+                type Response = #response_ident;
+
+                fn cache_key(&self) -> std::option::Option<(#cache_key_ident, std::time::Duration)> {
+                    match self {
+                        #( #arms, )*
+                    }
+                }
+
+                #[allow(unreachable_patterns)]
+                fn clone_response(response: &#response_ident) -> #response_ident {
+                    match response {
+                        #(
+                            #response_ident::#cached(response) => {
+                                #response_ident::#cached(std::clone::Clone::clone(response))
+                            }
+                        )*
+                        _ => unreachable!("only the responses to cacheable requests are cached"),
+                    }
+                }
+            }
+        }
+    }
+
+    fn impl_exactly_once_for_request(&self) -> TokenStream2 {
+        let &Self {
+            request_ident,
+            response_ident,
+            camel_case_idents,
+            rpcs,
+            ..
+        } = self;
+        let exactly_once = rpcs.iter().map(|rpc| rpc.policy.exactly_once);
+        let recorded = camel_case_idents
+            .iter()
+            .zip(rpcs.iter())
+            .filter(|(_, rpc)| rpc.policy.exactly_once)
+            .map(|(camel_case_ident, _)| camel_case_ident);
+
+        quote! {
+            impl tarpc::server::ExactlyOnce for #request_ident {
+                type Response = #response_ident;
+
+                fn is_exactly_once(&self) -> bool {
+                    match self {
+                        #( #request_ident::#camel_case_idents { .. } => #exactly_once, )*
+                    }
+                }
+
+                #[allow(unreachable_patterns)]
+                fn clone_response(response: &#response_ident) -> #response_ident {
+                    match response {
+                        #(
+                            #response_ident::#recorded(response) => {
+                                #response_ident::#recorded(std::clone::Clone::clone(response))
+                            }
+                        )*
+                        _ => unreachable!("only the responses to exactly-once requests are recorded"),
+                    }
+                }
+            }
+        }
+    }
+
     fn enum_response(&self) -> TokenStream2 {
         let &Self {
             derive_serialize,
@@ -376,15 +772,29 @@ impl<'a> ServiceGenerator<'a> {
         }
     }
 
+    // Returns the camel-case idents and the associated future (or stream) types of the methods
+    // that stream their replies, if `streaming`, or that don't, otherwise.
+    fn methods(&self, streaming: bool) -> (Vec<&Ident>, Vec<&Type>) {
+        self.camel_case_idents
+            .iter()
+            .zip(self.future_types.iter())
+            .zip(self.streaming.iter())
+            .filter(|(_, &s)| s == streaming)
+            .map(|(method, _)| method)
+            .unzip()
+    }
+
     fn enum_response_future(&self) -> TokenStream2 {
+        if !self.has_unary_methods() {
+            return TokenStream2::new();
+        }
         let &Self {
             vis,
             service_ident,
             response_fut_ident,
-            camel_case_idents,
-            future_types,
             ..
         } = self;
+        let (camel_case_idents, future_types) = self.methods(false);
 
         quote! {
             /// A future resolving to a server response.
@@ -395,6 +805,9 @@ impl<'a> ServiceGenerator<'a> {
     }
 
     fn impl_debug_for_response_future(&self) -> TokenStream2 {
+        if !self.has_unary_methods() {
+            return TokenStream2::new();
+        }
         let &Self {
             service_ident,
             response_fut_ident,
@@ -412,13 +825,16 @@ impl<'a> ServiceGenerator<'a> {
     }
 
     fn impl_future_for_response_future(&self) -> TokenStream2 {
+        if !self.has_unary_methods() {
+            return TokenStream2::new();
+        }
         let &Self {
             service_ident,
             response_fut_ident,
             response_ident,
-            camel_case_idents,
             ..
         } = self;
+        let (camel_case_idents, _) = self.methods(false);
 
         quote! {
             impl<S: #service_ident> std::future::Future for #response_fut_ident<S> {
@@ -442,6 +858,80 @@ impl<'a> ServiceGenerator<'a> {
         }
     }
 
+    fn enum_response_stream(&self) -> TokenStream2 {
+        if !self.has_streaming_methods() {
+            return TokenStream2::new();
+        }
+        let &Self {
+            vis,
+            service_ident,
+            response_stream_ident,
+            ..
+        } = self;
+        let (camel_case_idents, stream_types) = self.methods(true);
+
+        quote! {
+            /// A stream of the items of a server reply.
+            #vis enum #response_stream_ident<S: #service_ident> {
+                #( #camel_case_idents(<S as #service_ident>::#stream_types) ),*
+            }
+        }
+    }
+
+    fn impl_debug_for_response_stream(&self) -> TokenStream2 {
+        if !self.has_streaming_methods() {
+            return TokenStream2::new();
+        }
+        let &Self {
+            service_ident,
+            response_stream_ident,
+            response_stream_name,
+            ..
+        } = self;
+
+        quote! {
+            impl<S: #service_ident> std::fmt::Debug for #response_stream_ident<S> {
+                fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+                    fmt.debug_struct(#response_stream_name).finish()
+                }
+            }
+        }
+    }
+
+    fn impl_stream_for_response_stream(&self) -> TokenStream2 {
+        if !self.has_streaming_methods() {
+            return TokenStream2::new();
+        }
+        let &Self {
+            service_ident,
+            response_stream_ident,
+            response_ident,
+            ..
+        } = self;
+        let (camel_case_idents, _) = self.methods(true);
+
+        quote! {
+            impl<S: #service_ident> tarpc::futures::Stream for #response_stream_ident<S> {
+                type Item = #response_ident;
+
+                fn poll_next(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>)
+                    -> std::task::Poll<std::option::Option<#response_ident>>
+                {
+                    unsafe {
+                        match std::pin::Pin::get_unchecked_mut(self) {
+                            #(
+                                #response_stream_ident::#camel_case_idents(items) =>
+                                    tarpc::futures::Stream::poll_next(
+                                        std::pin::Pin::new_unchecked(items), cx
+                                    ).map(|item| item.map(#response_ident::#camel_case_idents)),
+                            )*
+                        }
+                    }
+                }
+            }
+        }
+    }
+
     fn struct_client(&self) -> TokenStream2 {
         let &Self {
             vis,
@@ -496,7 +986,7 @@ impl<'a> ServiceGenerator<'a> {
                         tarpc::client::channel::RequestDispatch<#request_ident, #response_ident, T>
                     >
                 where
-                    T: tarpc::Transport<tarpc::ClientMessage<#request_ident>, tarpc::Response<#response_ident>>
+                    T: tarpc::Transport<tarpc::ClientMessage<#request_ident>, tarpc::ServerMessage<#response_ident>>
                 {
                     let new_client = tarpc::client::new(config, transport);
                     tarpc::client::NewClient {
@@ -505,6 +995,28 @@ impl<'a> ServiceGenerator<'a> {
                     }
                 }
 
+                /// Subscribes to the notifications the server pushes over the connection.
+                #vis fn notifications(&self) -> tarpc::client::channel::Notifications<#response_ident> {
+                    self.0.notifications()
+                }
+
+                /// Resolves once the connection is ready for requests, failing with the error that
+                /// ended it if it never came up.
+                #vis async fn ready(&self) -> std::io::Result<()> {
+                    self.0.ready().await
+                }
+
+                /// Like `ready`, but fails if the connection isn't ready within `timeout`.
+                #vis async fn wait_connected(&self, timeout: std::time::Duration) -> std::io::Result<()> {
+                    self.0.wait_connected(timeout).await
+                }
+
+                /// Shuts the client down, failing its calls in flight and those made from now on
+                /// without waiting for the server.
+                #vis async fn shutdown(&self) {
+                    self.0.shutdown().await
+                }
+
             }
         }
     }
@@ -521,28 +1033,127 @@ impl<'a> ServiceGenerator<'a> {
             return_types,
             arg_pats,
             camel_case_idents,
+            streaming,
+            rpcs,
             ..
         } = self;
 
-        quote! {
-            impl<C> #client_ident<C>
-                where for<'a> C: tarpc::Client<'a, #request_ident, Response = #response_ident>
-            {
-                #(
-                    #[allow(unused)]
-                    #( #method_attrs )*
-                    #vis fn #method_idents(&mut self, ctx: tarpc::context::Context, #( #args ),*)
-                        -> impl std::future::Future<Output = std::io::Result<#return_types>> + '_ {
-                        let request = #request_ident::#camel_case_idents { #( #arg_pats ),* };
+        let methods = method_attrs
+            .iter()
+            .zip(method_idents.iter())
+            .zip(args.iter())
+            .zip(return_types.iter())
+            .zip(arg_pats.iter())
+            .zip(camel_case_idents.iter())
+            .zip(rpcs.iter().map(|rpc| &rpc.policy))
+            .zip(streaming.iter());
+        let (unary, streaming): (Vec<_>, Vec<_>) = methods.partition(|(_, &streaming)| !streaming);
+
+        let unary = unary.into_iter().map(
+            |(((((((method_attrs, method_ident), args), return_type), arg_pats), camel_case_ident), policy), _)| {
+                let bound_deadline = bound_deadline(policy);
+                let mismatched = mismatched_reply(method_ident);
+                let idempotency_key = if policy.exactly_once {
+                    Some(quote! {
+                        let ctx = ctx.ensure_idempotency_key();
+                    })
+                } else {
+                    None
+                };
+                let call = if policy.retries == 0 {
+                    quote! {
+                        let request = #request_ident::#camel_case_ident { #( #arg_pats ),* };
                         let resp = tarpc::Client::call(&mut self.0, ctx, request);
                         async move {
                             match resp.await? {
-                                #response_ident::#camel_case_idents(msg) => std::result::Result::Ok(msg),
-                                _ => unreachable!(),
+                                #response_ident::#camel_case_ident(msg) => std::result::Result::Ok(msg),
+                                _ => std::result::Result::Err(#mismatched),
                             }
                         }
                     }
-                )*
+                } else {
+                    let retries = policy.retries;
+                    quote! {
+                        async move {
+                            let mut retries: u32 = #retries;
+                            loop {
+                                let request = #request_ident::#camel_case_ident {
+                                    #( #arg_pats: std::clone::Clone::clone(&#arg_pats) ),*
+                                };
+                                match tarpc::Client::call(&mut self.0, ctx.clone(), request).await {
+                                    std::result::Result::Ok(#response_ident::#camel_case_ident(msg)) => {
+                                        return std::result::Result::Ok(msg);
+                                    }
+                                    std::result::Result::Ok(_) => {
+                                        return std::result::Result::Err(#mismatched);
+                                    }
+                                    std::result::Result::Err(e) => {
+                                        if retries == 0
+                                            || !tarpc::client::wait_to_retry::<_, #request_ident>(&self.0, &e, &ctx).await
+                                        {
+                                            return std::result::Result::Err(e);
+                                        }
+                                    }
+                                }
+                                retries -= 1;
+                            }
+                        }
+                    }
+                };
+                quote! {
+                    #[allow(unused)]
+                    #( #method_attrs )*
+                    #vis fn #method_ident(&mut self, ctx: tarpc::context::Context, #( #args ),*)
+                        -> impl std::future::Future<Output = std::io::Result<#return_type>> + '_ {
+                        #bound_deadline
+                        #idempotency_key
+                        #call
+                    }
+                }
+            },
+        );
+        let streaming = streaming.into_iter().map(
+            |(((((((method_attrs, method_ident), args), item_type), arg_pats), camel_case_ident), policy), _)| {
+                let bound_deadline = bound_deadline(policy);
+                let mismatched = mismatched_reply(method_ident);
+                quote! {
+                    #[allow(unused)]
+                    #( #method_attrs )*
+                    #vis fn #method_ident<St>(&mut self, ctx: tarpc::context::Context, #( #args ),*)
+                        -> impl std::future::Future<
+                            Output = std::io::Result<
+                                impl tarpc::futures::Stream<Item = std::io::Result<#item_type>>
+                            >
+                        > + '_
+                    where
+                        for<'a> C: tarpc::client::StreamClient<
+                            'a, #request_ident, Response = #response_ident, Stream = St
+                        >,
+                        St: tarpc::futures::Stream<Item = std::io::Result<#response_ident>>,
+                    {
+                        #bound_deadline
+                        let request = #request_ident::#camel_case_ident { #( #arg_pats ),* };
+                        let items = tarpc::client::StreamClient::call_stream(&mut self.0, ctx, request);
+                        async move {
+                            let items = items.await?;
+                            std::result::Result::Ok(tarpc::futures::StreamExt::map(items, |item| {
+                                match item? {
+                                    #response_ident::#camel_case_ident(msg) => std::result::Result::Ok(msg),
+                                    _ => std::result::Result::Err(#mismatched),
+                                }
+                            }))
+                        }
+                    }
+                }
+            },
+        );
+
+        quote! {
+            impl<C> #client_ident<C>
+                where for<'a> C: tarpc::Client<'a, #request_ident, Response = #response_ident>
+            {
+                #( #unary )*
+                #( #streaming )*
             }
         }
     }
@@ -555,10 +1166,18 @@ impl<'a> ToTokens for ServiceGenerator<'a> {
             self.struct_server(),
             self.impl_serve_for_server(),
             self.enum_request(),
+            self.impl_request_name_for_request(),
+            self.impl_validator_for_request(),
+            self.enum_cache_key(),
+            self.impl_cacheable_for_request(),
+            self.impl_exactly_once_for_request(),
             self.enum_response(),
             self.enum_response_future(),
             self.impl_debug_for_response_future(),
             self.impl_future_for_response_future(),
+            self.enum_response_stream(),
+            self.impl_debug_for_response_stream(),
+            self.impl_stream_for_response_stream(),
             self.struct_client(),
             self.impl_from_for_client(),
             self.impl_client_new(),
@@ -567,6 +1186,55 @@ impl<'a> ToTokens for ServiceGenerator<'a> {
     }
 }
 
+// Moves the deadline of `ctx` up to the method's declared deadline, if it has one.
+fn bound_deadline(policy: &Policy) -> Option<TokenStream2> {
+    let millis = policy.deadline?;
+    Some(quote! {
+        let mut ctx = ctx;
+        let deadline = std::time::SystemTime::now() + std::time::Duration::from_millis(#millis);
+        if deadline < ctx.deadline {
+            ctx.deadline = deadline;
+        }
+    })
+}
+
+// The error of a call to `method_ident` whose reply is to a different method, e.g. because the
+// server runs a different version of the service.
+fn mismatched_reply(method_ident: &Ident) -> TokenStream2 {
+    let message = format!(
+        "The server's reply isn't a reply to {}.",
+        method_ident.unraw()
+    );
+    quote! {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, #message)
+    }
+}
+
+// Returns `T` if `ty` is `impl Stream<Item = T>`.
+fn stream_item_type(ty: &Type) -> Option<&Type> {
+    let bounds = match ty {
+        Type::ImplTrait(impl_trait) => &impl_trait.bounds,
+        _ => return None,
+    };
+    bounds.iter().find_map(|bound| {
+        let segment = match bound {
+            TypeParamBound::Trait(bound) => bound.path.segments.last()?,
+            _ => return None,
+        };
+        if segment.ident != "Stream" {
+            return None;
+        }
+        let args = match segment.arguments {
+            PathArguments::AngleBracketed(ref args) => &args.args,
+            _ => return None,
+        };
+        args.iter().find_map(|arg| match arg {
+            GenericArgument::Binding(binding) if binding.ident == "Item" => Some(&binding.ty),
+            _ => None,
+        })
+    })
+}
+
 fn snake_to_camel(ident_str: &str) -> String {
     let mut camel_ty = String::with_capacity(ident_str.len());
 
@@ -83,3 +83,41 @@ fn syntax() {
         async fn one_arg_implicit_return_error(one: String);
     }
 }
+
+#[test]
+fn streaming() {
+    use futures::{
+        future::{ready, Ready},
+        stream::{self, Iter},
+    };
+
+    #[tarpc::service]
+    trait Streaming {
+        async fn letters(s: String) -> impl futures::Stream<Item = char>;
+        async fn len(s: String) -> usize;
+    }
+
+    impl Streaming for () {
+        type LettersStream = Iter<std::vec::IntoIter<char>>;
+        fn letters(self, _: context::Context, s: String) -> Self::LettersStream {
+            stream::iter(s.chars().collect::<Vec<_>>())
+        }
+
+        type LenFut = Ready<usize>;
+        fn len(self, _: context::Context, s: String) -> Self::LenFut {
+            ready(s.len())
+        }
+    }
+
+    #[tarpc::service]
+    trait OnlyStreaming {
+        async fn repeat(s: String, n: usize) -> impl futures::Stream<Item = String>;
+    }
+
+    impl OnlyStreaming for () {
+        type RepeatStream = Iter<std::vec::IntoIter<String>>;
+        fn repeat(self, _: context::Context, s: String, n: usize) -> Self::RepeatStream {
+            stream::iter(vec![s; n])
+        }
+    }
+}
@@ -0,0 +1,40 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Reports the changes between two versions of an IDL file that break wire compatibility.
+//!
+//! Usage: `tarpc-compat OLD NEW`. Prints a line per breaking change and exits with status 1 if
+//! there are any, or exits with status 2 if either file can't be read or parsed.
+
+use std::{env, fs, process};
+use tarpc_build::Definition;
+
+fn read(path: &str) -> Definition {
+    let source = fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("Couldn't read {}: {}", path, e);
+        process::exit(2);
+    });
+    Definition::parse(&source).unwrap_or_else(|e| {
+        eprintln!("{}:{}", path, e);
+        process::exit(2);
+    })
+}
+
+fn main() {
+    let args: Vec<_> = env::args().skip(1).collect();
+    if args.len() != 2 {
+        eprintln!("Usage: tarpc-compat OLD NEW");
+        process::exit(2);
+    }
+    let (old, new) = (read(&args[0]), read(&args[1]));
+    let changes = old.breaking_changes(&new);
+    for change in &changes {
+        println!("{}", change);
+    }
+    if !changes.is_empty() {
+        process::exit(1);
+    }
+}
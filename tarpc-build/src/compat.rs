@@ -0,0 +1,245 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use crate::{Definition, Enum, Field, Fields, Item, Method, Service, Struct, Type};
+use std::fmt;
+
+/// A change between two definitions that breaks communication between peers built from them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BreakingChange {
+    path: String,
+    message: String,
+}
+
+impl BreakingChange {
+    /// Returns the path of the changed item, e.g. `Geometry::area` or `Shape::Circle`.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl fmt::Display for BreakingChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+pub(crate) fn breaking_changes(old: &Definition, new: &Definition) -> Vec<BreakingChange> {
+    let mut changes = Changes(vec![]);
+    for item in &old.items {
+        let name = item.name();
+        match (item, new.items.iter().find(|new| new.name() == name)) {
+            (_, None) => changes.push(name, format!("The {} was removed.", item.kind())),
+            (Item::Service(old), Some(Item::Service(new))) => changes.service(old, new),
+            (Item::Struct(old), Some(Item::Struct(new))) => changes.structure(old, new),
+            (Item::Enum(old), Some(Item::Enum(new))) => changes.enumeration(old, new),
+            (_, Some(new)) => changes.push(
+                name,
+                format!(
+                    "The {} became {}.",
+                    item.kind(),
+                    match new {
+                        Item::Enum(_) => "an enum",
+                        Item::Struct(_) => "a struct",
+                        Item::Service(_) => "a service",
+                    }
+                ),
+            ),
+        }
+    }
+    changes.0
+}
+
+impl Item {
+    fn name(&self) -> &str {
+        match self {
+            Item::Service(Service { name, .. })
+            | Item::Struct(Struct { name, .. })
+            | Item::Enum(Enum { name, .. }) => name,
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Item::Service(_) => "service",
+            Item::Struct(_) => "struct",
+            Item::Enum(_) => "enum",
+        }
+    }
+}
+
+struct Changes(Vec<BreakingChange>);
+
+impl Changes {
+    fn push(&mut self, path: impl Into<String>, message: impl Into<String>) {
+        self.0.push(BreakingChange {
+            path: path.into(),
+            message: message.into(),
+        });
+    }
+
+    /// Methods are variants of the request and response enums, so, like enum variants, they may
+    /// be added after the existing methods.
+    fn service(&mut self, old: &Service, new: &Service) {
+        for (i, method) in old.methods.iter().enumerate() {
+            let path = format!("{}::{}", old.name, method.name);
+            match new.methods.iter().position(|m| m.name == method.name) {
+                None => self.push(path, "The method was removed."),
+                Some(j) => {
+                    if i != j {
+                        self.push(&path, moved("method", i, j));
+                    }
+                    self.method(&path, method, &new.methods[j]);
+                }
+            }
+        }
+    }
+
+    fn method(&mut self, path: &str, old: &Method, new: &Method) {
+        self.fields(path, "argument", &old.args, &new.args);
+        if old.stream != new.stream {
+            let message = if new.stream {
+                "The reply became a stream."
+            } else {
+                "The reply is no longer a stream."
+            };
+            self.push(path, message);
+        }
+        self.ty(path, "reply", &old.output, &new.output);
+        match (&old.error, &new.error) {
+            (None, None) => {}
+            (Some(_), None) => self.push(path, "The method no longer throws an error."),
+            (None, Some(_)) => self.push(path, "The method began throwing an error."),
+            (Some(old), Some(new)) => self.ty(path, "error", old, new),
+        }
+    }
+
+    fn structure(&mut self, old: &Struct, new: &Struct) {
+        self.fields(&old.name, "field", &old.fields, &new.fields);
+    }
+
+    /// Variants may be added after the existing variants: they're new to peers built from the
+    /// old definition, but don't change how the existing variants are encoded.
+    fn enumeration(&mut self, old: &Enum, new: &Enum) {
+        for (i, variant) in old.variants.iter().enumerate() {
+            let path = format!("{}::{}", old.name, variant.name);
+            let j = match new.variants.iter().position(|v| v.name == variant.name) {
+                Some(j) => j,
+                None => {
+                    self.push(path, "The variant was removed.");
+                    continue;
+                }
+            };
+            if i != j {
+                self.push(&path, moved("variant", i, j));
+            }
+            match (&variant.fields, &new.variants[j].fields) {
+                (Fields::Unit, Fields::Unit) => {}
+                (Fields::Tuple(old), Fields::Tuple(new)) => self.ty(&path, "value", old, new),
+                (Fields::Named(old), Fields::Named(new)) => self.fields(&path, "field", old, new),
+                (old, new) => self.push(
+                    path,
+                    format!("The variant changed from {} to {}.", old.kind(), new.kind()),
+                ),
+            }
+        }
+    }
+
+    /// Some codecs, e.g. bincode, encode fields in order and without their names, so any change
+    /// to fields but to their docs breaks.
+    fn fields(&mut self, path: &str, kind: &str, old: &[Field], new: &[Field]) {
+        for (i, field) in old.iter().enumerate() {
+            match new.iter().position(|f| f.name == field.name) {
+                None => self.push(path, format!("The {} `{}` was removed.", kind, field.name)),
+                Some(j) => {
+                    if i != j {
+                        self.push(path, moved(&format!("{} `{}`", kind, field.name), i, j));
+                    }
+                    let what = format!("{} `{}`", kind, field.name);
+                    self.ty(path, &what, &field.ty, &new[j].ty);
+                }
+            }
+        }
+        for field in new {
+            if !old.iter().any(|f| f.name == field.name) {
+                self.push(path, format!("The {} `{}` was added.", kind, field.name));
+            }
+        }
+    }
+
+    fn ty(&mut self, path: &str, what: &str, old: &Type, new: &Type) {
+        if old != new {
+            self.push(
+                path,
+                format!("The {} changed from `{}` to `{}`.", what, old, new),
+            );
+        }
+    }
+}
+
+impl Fields {
+    fn kind(&self) -> &'static str {
+        match self {
+            Fields::Unit => "a unit variant",
+            Fields::Tuple(_) => "a tuple variant",
+            Fields::Named(_) => "a struct variant",
+        }
+    }
+}
+
+fn moved(what: &str, from: usize, to: usize) -> String {
+    format!(
+        "The {} moved from position {} to {}.",
+        what,
+        from + 1,
+        to + 1
+    )
+}
+
+#[test]
+fn breaking_changes_are_reported() {
+    let old = Definition::parse(
+        r#"
+        struct Point { x: i64, y: i64 }
+        enum Shape { Empty, Circle { center: Point, radius: u64 }, Polygon(Vec<Point>) }
+        struct Unused {}
+        service Geometry {
+            rpc area(shape: Shape) -> u64;
+            rpc vertices(shape: Shape) -> stream Point throws String [deadline = "1s"];
+            rpc reset();
+        }
+        "#,
+    )
+    .unwrap();
+    let new = Definition::parse(
+        r#"
+        /// Docs and deadlines don't matter.
+        struct Point { x: i64, y: i64 }
+        enum Shape { Empty, Circle { center: Point, radius: f64 }, Polygon(Vec<Point>), Square }
+        service Geometry {
+            rpc area(shape: Shape, precise: bool) -> u64;
+            rpc vertices(shape: Shape) -> stream Point throws String [deadline = "5s"];
+            rpc perimeter(shape: Shape) -> u64;
+        }
+        "#,
+    )
+    .unwrap();
+    let changes: Vec<_> = old
+        .breaking_changes(&new)
+        .iter()
+        .map(BreakingChange::to_string)
+        .collect();
+    assert_eq!(
+        changes,
+        [
+            "Shape::Circle: The field `radius` changed from `u64` to `f64`.",
+            "Unused: The struct was removed.",
+            "Geometry::area: The argument `precise` was added.",
+            "Geometry::reset: The method was removed.",
+        ]
+    );
+    assert!(old.breaking_changes(&old).is_empty());
+}
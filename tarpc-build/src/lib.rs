@@ -0,0 +1,312 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Generates tarpc services from IDL files, so that a service's contract can be reviewed apart
+//! from its implementation.
+//!
+//! An IDL file declares services and the types they exchange:
+//!
+//! ```text
+//! /// A point on the plane.
+//! struct Point {
+//!     x: i64,
+//!     y: i64,
+//! }
+//!
+//! enum Shape {
+//!     Circle { center: Point, radius: u64 },
+//!     Polygon(Vec<Point>),
+//! }
+//!
+//! service Geometry {
+//!     /// Returns the area of `shape`.
+//!     rpc area(shape: Shape) -> u64;
+//!     /// Returns the shape's vertices, failing for circles.
+//!     rpc vertices(shape: Shape) -> stream Point throws String [deadline = "30s"];
+//! }
+//! ```
+//!
+//! Each service becomes a [`tarpc::service`] trait, which in turn generates the request and
+//! response enums, the `Serve` dispatcher, and the typed client. `throws E` makes a method return
+//! `Result<T, E>`, `stream T` streams its reply, and the `deadline` option becomes the method's
+//! `#[tarpc(deadline = "..")]`, applied by the typed client, and a constant, e.g.
+//! `GEOMETRY_VERTICES_DEADLINE`, for other clients to set on the method's context. Structs and
+//! enums derive `Clone`, `Debug`, `PartialEq`, and serde's `Serialize` and `Deserialize`, so the
+//! crate using the generated code must depend on `serde` with its `derive` feature, and on
+//! `tarpc` with its `serde1` feature.
+//!
+//! A build script compiles IDL files into Rust files in `OUT_DIR`:
+//!
+//! ```no_run
+//! // build.rs
+//! fn main() -> std::io::Result<()> {
+//!     tarpc_build::compile("geometry.tarpc")
+//! }
+//! ```
+//!
+//! which the crate then includes:
+//!
+//! ```ignore
+//! include!(concat!(env!("OUT_DIR"), "/geometry.rs"));
+//! ```
+//!
+//! [`json_schema`] describes the request and response enums of each service, and the structs and
+//! enums they use, as JSON Schema, so that clients in other languages can generate bindings or
+//! validate payloads sent with a JSON codec:
+//!
+//! ```no_run
+//! # use std::fs;
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let source = fs::read_to_string("geometry.tarpc")?;
+//! fs::write("geometry.schema.json", tarpc_build::json_schema(&source)?)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`typescript`] generates clients for web frontends to call the services with, over a
+//! WebSocket.
+//!
+//! [`Definition::breaking_changes`] lists the changes between two versions of an IDL file that
+//! break peers built from one version when they talk to peers built from the other. The
+//! `tarpc-compat` binary reports them, for CI to check before deploying:
+//!
+//! ```text
+//! $ tarpc-compat deployed/geometry.tarpc geometry.tarpc
+//! Geometry::area: The argument `precise` was added.
+//! ```
+
+#![deny(missing_docs, missing_debug_implementations)]
+
+mod compat;
+mod parse;
+mod rust;
+mod schema;
+mod typescript;
+
+pub use compat::BreakingChange;
+
+use std::{env, error, fmt, fs, io, path::Path, time::Duration};
+
+/// Reads the IDL file at `path` and writes the Rust code it defines to `$OUT_DIR`, in a file
+/// named like the IDL file with an `rs` extension. Meant to be called from a build script, which
+/// it tells cargo to rerun when the IDL file changes.
+pub fn compile(path: impl AsRef<Path>) -> io::Result<()> {
+    let path = path.as_ref();
+    println!("cargo:rerun-if-changed={}", path.display());
+    let source = fs::read_to_string(path)?;
+    let code = generate(&source).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}:{}", path.display(), e),
+        )
+    })?;
+    let out_dir = env::var_os("OUT_DIR").ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "OUT_DIR isn't set; compile must be called from a build script.",
+        )
+    })?;
+    let file_name = path.with_extension("rs");
+    let file_name = file_name.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} isn't a file.", path.display()),
+        )
+    })?;
+    fs::write(Path::new(&out_dir).join(file_name), code)
+}
+
+/// Returns the Rust code defined by the IDL `source`.
+pub fn generate(source: &str) -> Result<String, Error> {
+    Ok(rust::generate(&Definition::parse(source)?))
+}
+
+/// Returns the JSON Schema of the types defined by the IDL `source`, as serde_json encodes them.
+///
+/// The schema's `definitions` hold every struct and enum, and, for each service, e.g.
+/// `Geometry`, the `GeometryRequest` and `GeometryResponse` enums generated by
+/// [`tarpc::service`]. Enums are externally tagged, as serde tags them by default: a request to
+/// `area` is encoded as `{"Area": {"shape": ...}}`, and its response as `{"Area": 12}`. The
+/// response of a streaming method describes each item of its reply. Types not declared in the
+/// IDL file, other than the primitives and the standard collections, allow any value.
+pub fn json_schema(source: &str) -> Result<String, Error> {
+    Ok(schema::generate(&Definition::parse(source)?))
+}
+
+/// Returns a TypeScript module with a client for each service defined by the IDL `source`, e.g.
+/// `GeometryClient`, and a type for each struct and enum.
+///
+/// The clients share a `Channel`, which sends the requests and receives the responses of every
+/// service over a `Connection` carrying one message per frame, encoded as tarpc's serde_json
+/// codec encodes them. `webSocketConnection` adapts a browser `WebSocket`; servers must decode
+/// the text frames of their WebSocket connections into a tarpc transport, as tarpc doesn't
+/// include a WebSocket transport. Streamed replies are async generators, and calls take a
+/// deadline, a tenant ID, an API key, and an `AbortSignal` that cancels them.
+pub fn typescript(source: &str) -> Result<String, Error> {
+    Ok(typescript::generate(&Definition::parse(source)?))
+}
+
+/// The services and types declared by an IDL file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Definition {
+    pub(crate) items: Vec<Item>,
+}
+
+impl Definition {
+    /// Parses the IDL `source`.
+    pub fn parse(source: &str) -> Result<Self, Error> {
+        parse::parse(source)
+    }
+
+    /// Returns the changes from `self` to `new` that break peers built from one definition when
+    /// talking to peers built from the other, in either direction, with any codec.
+    ///
+    /// Removing, reordering, or changing the types of methods, arguments, fields, or variants
+    /// breaks, as do adding arguments or fields. Adding services, types, or methods and variants
+    /// after the existing ones doesn't, though peers built from `self` can't decode the new
+    /// variants, so they must be updated before they're sent the new variants. Docs and deadlines
+    /// aren't part of the wire format.
+    pub fn breaking_changes(&self, new: &Definition) -> Vec<BreakingChange> {
+        compat::breaking_changes(self, new)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Item {
+    Service(Service),
+    Struct(Struct),
+    Enum(Enum),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Service {
+    pub docs: Vec<String>,
+    pub name: String,
+    pub methods: Vec<Method>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Method {
+    pub docs: Vec<String>,
+    pub name: String,
+    pub args: Vec<Field>,
+    pub output: Type,
+    /// Whether the reply is a stream of `output`s.
+    pub stream: bool,
+    pub error: Option<Type>,
+    pub deadline: Option<Duration>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Struct {
+    pub docs: Vec<String>,
+    pub name: String,
+    pub fields: Vec<Field>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Enum {
+    pub docs: Vec<String>,
+    pub name: String,
+    pub variants: Vec<Variant>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Variant {
+    pub docs: Vec<String>,
+    pub name: String,
+    pub fields: Fields,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Fields {
+    Unit,
+    Tuple(Type),
+    Named(Vec<Field>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Field {
+    pub docs: Vec<String>,
+    pub name: String,
+    pub ty: Type,
+}
+
+/// A Rust type, e.g. `u32`, `Vec<Point>`, or `(String, bool)`.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Type {
+    Path { path: String, args: Vec<Type> },
+    Tuple(Vec<Type>),
+    Array(Box<Type>, Option<usize>),
+}
+
+impl Type {
+    pub fn unit() -> Self {
+        Type::Tuple(vec![])
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn list(f: &mut fmt::Formatter, types: &[Type]) -> fmt::Result {
+            for (i, ty) in types.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}", ty)?;
+            }
+            Ok(())
+        }
+
+        match self {
+            Type::Path { path, args } if args.is_empty() => write!(f, "{}", path),
+            Type::Path { path, args } => {
+                write!(f, "{}<", path)?;
+                list(f, args)?;
+                write!(f, ">")
+            }
+            Type::Tuple(types) => {
+                write!(f, "(")?;
+                list(f, types)?;
+                if types.len() == 1 {
+                    write!(f, ",")?;
+                }
+                write!(f, ")")
+            }
+            Type::Array(ty, None) => write!(f, "[{}]", ty),
+            Type::Array(ty, Some(len)) => write!(f, "[{}; {}]", ty, len),
+        }
+    }
+}
+
+/// An error in an IDL file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Error {
+    line: usize,
+    message: String,
+}
+
+impl Error {
+    pub(crate) fn new(line: usize, message: impl Into<String>) -> Self {
+        Error {
+            line,
+            message: message.into(),
+        }
+    }
+
+    /// Returns the line of the error, starting from 1.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.line, self.message)
+    }
+}
+
+impl error::Error for Error {}
@@ -0,0 +1,424 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use crate::{Definition, Enum, Error, Field, Fields, Item, Method, Service, Struct, Type, Variant};
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    /// An identifier, keyword, or integer.
+    Word(String),
+    /// A string literal, without its quotes.
+    Str(String),
+    /// A `///` doc comment, without its slashes.
+    Doc(String),
+    Punct(char),
+}
+
+/// Splits `source` into tokens, each with its line.
+fn lex(source: &str) -> Result<Vec<(Token, usize)>, Error> {
+    let mut tokens = vec![];
+    for (i, line) in source.lines().enumerate() {
+        let line_number = i + 1;
+        let mut chars = line.char_indices().peekable();
+        while let Some((start, c)) = chars.next() {
+            match c {
+                c if c.is_whitespace() => {}
+                '/' if line[start..].starts_with("///") => {
+                    let doc = &line[start + 3..];
+                    tokens.push((
+                        Token::Doc(doc.strip_prefix(' ').unwrap_or(doc).into()),
+                        line_number,
+                    ));
+                    break;
+                }
+                '/' if line[start..].starts_with("//") => break,
+                '"' => {
+                    let mut s = String::new();
+                    loop {
+                        match chars.next() {
+                            Some((_, '"')) => break,
+                            Some((_, c)) => s.push(c),
+                            None => return Err(Error::new(line_number, "Unterminated string.")),
+                        }
+                    }
+                    tokens.push((Token::Str(s), line_number));
+                }
+                c if c.is_alphanumeric() || c == '_' => {
+                    let mut word = c.to_string();
+                    while let Some(&(_, c)) = chars.peek() {
+                        if !(c.is_alphanumeric() || c == '_') {
+                            break;
+                        }
+                        word.push(c);
+                        chars.next();
+                    }
+                    tokens.push((Token::Word(word), line_number));
+                }
+                '(' | ')' | '{' | '}' | '[' | ']' | '<' | '>' | ',' | ';' | ':' | '=' | '-' => {
+                    tokens.push((Token::Punct(c), line_number))
+                }
+                c => return Err(Error::new(line_number, format!("Unexpected `{}`.", c))),
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+pub(crate) fn parse(source: &str) -> Result<Definition, Error> {
+    let mut parser = Parser {
+        tokens: lex(source)?,
+        next: 0,
+    };
+    let mut items = vec![];
+    loop {
+        let docs = parser.docs();
+        if parser.peek().is_none() {
+            break;
+        }
+        let keyword = parser.word()?;
+        let item = match &*keyword {
+            "service" => Item::Service(parser.service(docs)?),
+            "struct" => Item::Struct(parser.structure(docs)?),
+            "enum" => Item::Enum(parser.enumeration(docs)?),
+            other => {
+                return Err(parser.error(format!(
+                    "Expected `service`, `struct`, or `enum`, found `{}`.",
+                    other
+                )))
+            }
+        };
+        items.push(item);
+    }
+    Ok(Definition { items })
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    next: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(token, _)| token)
+    }
+
+    fn line(&self) -> usize {
+        match self.tokens.get(self.next).or_else(|| self.tokens.last()) {
+            Some(&(_, line)) => line,
+            None => 1,
+        }
+    }
+
+    fn error(&self, message: impl Into<String>) -> Error {
+        Error::new(self.line(), message)
+    }
+
+    fn bump(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.next).map(|(token, _)| token.clone());
+        self.next += 1;
+        token
+    }
+
+    fn is_punct(&self, c: char) -> bool {
+        self.peek() == Some(&Token::Punct(c))
+    }
+
+    fn is_word(&self, word: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(w)) if w == word)
+    }
+
+    /// Consumes `c` if it's next.
+    fn eat(&mut self, c: char) -> bool {
+        let is_next = self.is_punct(c);
+        if is_next {
+            self.next += 1;
+        }
+        is_next
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), Error> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(self.error(format!("Expected `{}`.", c)))
+        }
+    }
+
+    fn word(&mut self) -> Result<String, Error> {
+        match self.peek() {
+            Some(Token::Word(_)) => match self.bump() {
+                Some(Token::Word(word)) => Ok(word),
+                _ => unreachable!(),
+            },
+            _ => Err(self.error("Expected a name.")),
+        }
+    }
+
+    fn docs(&mut self) -> Vec<String> {
+        let mut docs = vec![];
+        while let Some(Token::Doc(_)) = self.peek() {
+            if let Some(Token::Doc(doc)) = self.bump() {
+                docs.push(doc);
+            }
+        }
+        docs
+    }
+
+    fn service(&mut self, docs: Vec<String>) -> Result<Service, Error> {
+        let name = self.word()?;
+        self.expect('{')?;
+        let mut methods = vec![];
+        loop {
+            let docs = self.docs();
+            if self.eat('}') {
+                break;
+            }
+            if !self.is_word("rpc") {
+                return Err(self.error("Expected `rpc` or `}`."));
+            }
+            self.next += 1;
+            methods.push(self.method(docs)?);
+        }
+        Ok(Service {
+            docs,
+            name,
+            methods,
+        })
+    }
+
+    fn method(&mut self, docs: Vec<String>) -> Result<Method, Error> {
+        let name = self.word()?;
+        self.expect('(')?;
+        let args = self.fields(')')?;
+        let (mut output, mut stream) = (Type::unit(), false);
+        if self.eat('-') {
+            self.expect('>')?;
+            if self.is_word("stream") {
+                self.next += 1;
+                stream = true;
+            }
+            output = self.ty()?;
+        }
+        let error = if self.is_word("throws") {
+            self.next += 1;
+            Some(self.ty()?)
+        } else {
+            None
+        };
+        let mut deadline = None;
+        if self.eat('[') {
+            while !self.eat(']') {
+                let option = self.word()?;
+                self.expect('=')?;
+                let value = match self.bump() {
+                    Some(Token::Str(value)) => value,
+                    _ => return Err(self.error("Expected a string.")),
+                };
+                match &*option {
+                    "deadline" => {
+                        let parsed = humantime::parse_duration(&value).map_err(|e| {
+                            self.error(format!("Invalid deadline \"{}\": {}", value, e))
+                        })?;
+                        deadline = Some(parsed);
+                    }
+                    other => return Err(self.error(format!("Unknown option `{}`.", other))),
+                }
+                if !self.eat(',') && !self.is_punct(']') {
+                    return Err(self.error("Expected `,` or `]`."));
+                }
+            }
+        }
+        self.expect(';')?;
+        Ok(Method {
+            docs,
+            name,
+            args,
+            output,
+            stream,
+            error,
+            deadline,
+        })
+    }
+
+    fn structure(&mut self, docs: Vec<String>) -> Result<Struct, Error> {
+        let name = self.word()?;
+        self.expect('{')?;
+        let fields = self.fields('}')?;
+        Ok(Struct { docs, name, fields })
+    }
+
+    fn enumeration(&mut self, docs: Vec<String>) -> Result<Enum, Error> {
+        let name = self.word()?;
+        self.expect('{')?;
+        let mut variants = vec![];
+        loop {
+            let docs = self.docs();
+            if self.eat('}') {
+                break;
+            }
+            let name = self.word()?;
+            let fields = if self.eat('(') {
+                let ty = self.ty()?;
+                self.expect(')')?;
+                Fields::Tuple(ty)
+            } else if self.eat('{') {
+                Fields::Named(self.fields('}')?)
+            } else {
+                Fields::Unit
+            };
+            variants.push(Variant { docs, name, fields });
+            if !self.eat(',') && !self.is_punct('}') {
+                return Err(self.error("Expected `,` or `}`."));
+            }
+        }
+        Ok(Enum {
+            docs,
+            name,
+            variants,
+        })
+    }
+
+    /// Parses `name: Type` pairs separated by commas, through the closing `end`.
+    fn fields(&mut self, end: char) -> Result<Vec<Field>, Error> {
+        let mut fields = vec![];
+        loop {
+            let docs = self.docs();
+            if self.eat(end) {
+                return Ok(fields);
+            }
+            let name = self.word()?;
+            self.expect(':')?;
+            let ty = self.ty()?;
+            fields.push(Field { docs, name, ty });
+            if !self.eat(',') && !self.is_punct(end) {
+                return Err(self.error(format!("Expected `,` or `{}`.", end)));
+            }
+        }
+    }
+
+    fn ty(&mut self) -> Result<Type, Error> {
+        if self.eat('(') {
+            let mut types = vec![];
+            while !self.eat(')') {
+                types.push(self.ty()?);
+                if !self.eat(',') && !self.is_punct(')') {
+                    return Err(self.error("Expected `,` or `)`."));
+                }
+            }
+            return Ok(Type::Tuple(types));
+        }
+        if self.eat('[') {
+            let ty = self.ty()?;
+            let len = if self.eat(';') {
+                let len = self.word()?;
+                Some(
+                    len.parse()
+                        .map_err(|_| self.error(format!("Invalid array length `{}`.", len)))?,
+                )
+            } else {
+                None
+            };
+            self.expect(']')?;
+            return Ok(Type::Array(Box::new(ty), len));
+        }
+        let mut path = self.word()?;
+        while self.is_punct(':') {
+            self.next += 1;
+            self.expect(':')?;
+            path.push_str("::");
+            path.push_str(&self.word()?);
+        }
+        let mut args = vec![];
+        if self.eat('<') {
+            while !self.eat('>') {
+                args.push(self.ty()?);
+                if !self.eat(',') && !self.is_punct('>') {
+                    return Err(self.error("Expected `,` or `>`."));
+                }
+            }
+        }
+        Ok(Type::Path { path, args })
+    }
+}
+
+#[test]
+fn definitions_are_parsed() {
+    use std::time::Duration;
+
+    let definition = parse(
+        r#"
+        /// A point.
+        struct Point { x: i64, y: i64 }
+
+        enum Shape {
+            Empty,
+            Circle { center: Point, radius: u64 },
+            Polygon(Vec<Point>),
+        }
+
+        service Geometry {
+            // Not a doc comment.
+            rpc area(shape: Shape) -> u64;
+            rpc vertices(shape: Shape) -> stream (i64, i64) throws String [deadline = "1s 500ms"];
+            rpc reset();
+        }
+        "#,
+    )
+    .unwrap();
+    let point = |name: &str| Field {
+        docs: vec![],
+        name: name.into(),
+        ty: Type::Path {
+            path: "i64".into(),
+            args: vec![],
+        },
+    };
+    assert_eq!(
+        definition.items[0],
+        Item::Struct(Struct {
+            docs: vec!["A point.".into()],
+            name: "Point".into(),
+            fields: vec![point("x"), point("y")],
+        })
+    );
+    let shape = match &definition.items[1] {
+        Item::Enum(shape) => shape,
+        item => panic!("Expected an enum, found {:?}", item),
+    };
+    assert_eq!(shape.variants.len(), 3);
+    assert_eq!(shape.variants[0].fields, Fields::Unit);
+    assert_matches_tuple(&shape.variants[2].fields, "Vec<Point>");
+
+    let geometry = match &definition.items[2] {
+        Item::Service(geometry) => geometry,
+        item => panic!("Expected a service, found {:?}", item),
+    };
+    let vertices = &geometry.methods[1];
+    assert!(vertices.stream);
+    assert_eq!(vertices.output.to_string(), "(i64, i64)");
+    assert_eq!(vertices.error.as_ref().unwrap().to_string(), "String");
+    assert_eq!(vertices.deadline, Some(Duration::from_millis(1500)));
+    assert_eq!(geometry.methods[2].output, Type::unit());
+    assert!(geometry.methods[0].docs.is_empty());
+}
+
+#[cfg(test)]
+fn assert_matches_tuple(fields: &Fields, expected: &str) {
+    match fields {
+        Fields::Tuple(ty) => assert_eq!(ty.to_string(), expected),
+        fields => panic!("Expected a tuple variant, found {:?}", fields),
+    }
+}
+
+#[test]
+fn errors_have_lines() {
+    let error = parse("service S {\n    rpc f(x: u32) -> u32\n}").unwrap_err();
+    assert_eq!(error.line(), 3);
+    assert_eq!(error.to_string(), "3: Expected `;`.");
+
+    let error = parse("service S {\n    rpc f() [timeout = \"1s\"];\n}").unwrap_err();
+    assert_eq!(error.to_string(), "2: Unknown option `timeout`.");
+}
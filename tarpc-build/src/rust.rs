@@ -0,0 +1,197 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use crate::{Definition, Enum, Field, Fields, Item, Method, Service, Struct};
+use std::fmt::Write;
+
+/// The derives of every generated struct and enum.
+const DERIVES: &str = "#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]";
+
+/// Returns the Rust code for `definition`.
+pub(crate) fn generate(definition: &Definition) -> String {
+    let mut code = String::from("// Generated by tarpc-build. Edit the IDL file instead.\n");
+    for item in &definition.items {
+        code.push('\n');
+        match item {
+            Item::Service(service) => generate_service(&mut code, service),
+            Item::Struct(structure) => generate_struct(&mut code, structure),
+            Item::Enum(enumeration) => generate_enum(&mut code, enumeration),
+        }
+    }
+    code
+}
+
+fn docs(code: &mut String, indent: &str, docs: &[String]) {
+    for doc in docs {
+        if doc.is_empty() {
+            writeln!(code, "{}///", indent).unwrap();
+        } else {
+            writeln!(code, "{}/// {}", indent, doc).unwrap();
+        }
+    }
+}
+
+fn fields(code: &mut String, indent: &str, fields: &[Field]) {
+    for field in fields {
+        docs(code, indent, &field.docs);
+        writeln!(code, "{}pub {}: {},", indent, field.name, field.ty).unwrap();
+    }
+}
+
+fn generate_struct(code: &mut String, structure: &Struct) {
+    docs(code, "", &structure.docs);
+    writeln!(code, "{}", DERIVES).unwrap();
+    writeln!(code, "pub struct {} {{", structure.name).unwrap();
+    fields(code, "    ", &structure.fields);
+    code.push_str("}\n");
+}
+
+fn generate_enum(code: &mut String, enumeration: &Enum) {
+    docs(code, "", &enumeration.docs);
+    writeln!(code, "{}", DERIVES).unwrap();
+    writeln!(code, "pub enum {} {{", enumeration.name).unwrap();
+    for variant in &enumeration.variants {
+        docs(code, "    ", &variant.docs);
+        match &variant.fields {
+            Fields::Unit => writeln!(code, "    {},", variant.name).unwrap(),
+            Fields::Tuple(ty) => writeln!(code, "    {}({}),", variant.name, ty).unwrap(),
+            Fields::Named(named) => {
+                writeln!(code, "    {} {{", variant.name).unwrap();
+                // Fields of enum variants can't have visibilities.
+                for field in named {
+                    docs(code, "        ", &field.docs);
+                    writeln!(code, "        {}: {},", field.name, field.ty).unwrap();
+                }
+                code.push_str("    },\n");
+            }
+        }
+    }
+    code.push_str("}\n");
+}
+
+fn generate_service(code: &mut String, service: &Service) {
+    docs(code, "", &service.docs);
+    code.push_str("#[tarpc::service]\n");
+    writeln!(code, "pub trait {} {{", service.name).unwrap();
+    for method in &service.methods {
+        docs(code, "    ", &method.docs);
+        let args: Vec<_> = method
+            .args
+            .iter()
+            .map(|arg| format!("{}: {}", arg.name, arg.ty))
+            .collect();
+        if let Some(deadline) = method.deadline {
+            writeln!(
+                code,
+                "    #[tarpc(deadline = \"{}ms\")]",
+                deadline.as_millis()
+            )
+            .unwrap();
+        }
+        writeln!(
+            code,
+            "    async fn {}({}) -> {};",
+            method.name,
+            args.join(", "),
+            return_type(method)
+        )
+        .unwrap();
+    }
+    code.push_str("}\n");
+
+    for method in &service.methods {
+        if let Some(deadline) = method.deadline {
+            writeln!(
+                code,
+                "\n/// The deadline declared for `{}::{}`.",
+                service.name, method.name
+            )
+            .unwrap();
+            writeln!(
+                code,
+                "pub const {}_{}_DEADLINE: std::time::Duration = \
+                 std::time::Duration::from_millis({});",
+                upper_snake(&service.name),
+                method.name.to_uppercase(),
+                deadline.as_millis()
+            )
+            .unwrap();
+        }
+    }
+}
+
+fn return_type(method: &Method) -> String {
+    let output = match &method.error {
+        Some(error) => format!("Result<{}, {}>", method.output, error),
+        None => method.output.to_string(),
+    };
+    if method.stream {
+        format!("impl tarpc::futures::Stream<Item = {}>", output)
+    } else {
+        output
+    }
+}
+
+/// Converts a CamelCase name to UPPER_SNAKE_CASE.
+fn upper_snake(name: &str) -> String {
+    let mut snake = String::with_capacity(name.len());
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            snake.push('_');
+        }
+        snake.extend(c.to_uppercase());
+    }
+    snake
+}
+
+#[test]
+fn services_become_service_traits() {
+    let code = crate::generate(
+        r#"
+        /// Greets people.
+        service HelloWorld {
+            /// Says hello.
+            rpc hello(name: String, times: u32) -> String;
+            rpc greetings(name: String) -> stream String throws Error [deadline = "5s"];
+            rpc ping();
+        }
+
+        enum Error {
+            Busy,
+            Unknown(String),
+            Invalid { reason: String },
+        }
+        "#,
+    )
+    .unwrap();
+    assert_eq!(
+        code,
+        r#"// Generated by tarpc-build. Edit the IDL file instead.
+
+/// Greets people.
+#[tarpc::service]
+pub trait HelloWorld {
+    /// Says hello.
+    async fn hello(name: String, times: u32) -> String;
+    #[tarpc(deadline = "5000ms")]
+    async fn greetings(name: String) -> impl tarpc::futures::Stream<Item = Result<String, Error>>;
+    async fn ping() -> ();
+}
+
+/// The deadline declared for `HelloWorld::greetings`.
+pub const HELLO_WORLD_GREETINGS_DEADLINE: std::time::Duration = std::time::Duration::from_millis(5000);
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Error {
+    Busy,
+    Unknown(String),
+    Invalid {
+        reason: String,
+    },
+}
+"#
+    );
+}
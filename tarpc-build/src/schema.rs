@@ -0,0 +1,283 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use crate::{Definition, Enum, Field, Fields, Item, Method, Service, Struct, Type};
+use serde_json::{json, Map, Value};
+
+/// Returns the JSON Schema of the types in `definition`, as they're encoded by serde_json.
+pub(crate) fn generate(definition: &Definition) -> String {
+    let schema = Schema { definition };
+    let mut definitions = Map::new();
+    for item in &definition.items {
+        match item {
+            Item::Service(service) => {
+                definitions.insert(format!("{}Request", service.name), schema.request(service));
+                definitions.insert(
+                    format!("{}Response", service.name),
+                    schema.response(service),
+                );
+            }
+            Item::Struct(structure) => {
+                definitions.insert(structure.name.clone(), schema.structure(structure));
+            }
+            Item::Enum(enumeration) => {
+                definitions.insert(enumeration.name.clone(), schema.enumeration(enumeration));
+            }
+        }
+    }
+    let document = json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "definitions": definitions,
+    });
+    serde_json::to_string_pretty(&document).unwrap()
+}
+
+struct Schema<'a> {
+    definition: &'a Definition,
+}
+
+impl Schema<'_> {
+    fn is_declared(&self, name: &str) -> bool {
+        self.definition.items.iter().any(|item| match item {
+            Item::Struct(Struct { name: declared, .. })
+            | Item::Enum(Enum { name: declared, .. }) => declared == name,
+            Item::Service(_) => false,
+        })
+    }
+
+    /// The request enum has a struct variant per method, whose fields are the method's args.
+    fn request(&self, service: &Service) -> Value {
+        let variants = service
+            .methods
+            .iter()
+            .map(|method| {
+                let args = self.object(&method.args);
+                with_docs(variant(&camel_case(&method.name), args), &method.docs)
+            })
+            .collect();
+        with_docs(
+            json!({ "oneOf": Value::Array(variants) }),
+            &[format!("A request to the {} service.", service.name)],
+        )
+    }
+
+    /// The response enum has a newtype variant per method, holding the method's reply, or, for
+    /// streaming methods, an item of the reply.
+    fn response(&self, service: &Service) -> Value {
+        let variants = service
+            .methods
+            .iter()
+            .map(|method| variant(&camel_case(&method.name), self.reply(method)))
+            .collect();
+        with_docs(
+            json!({ "oneOf": Value::Array(variants) }),
+            &[format!("A response from the {} service.", service.name)],
+        )
+    }
+
+    fn reply(&self, method: &Method) -> Value {
+        match &method.error {
+            Some(error) => json!({
+                "oneOf": [
+                    variant("Ok", self.ty(&method.output)),
+                    variant("Err", self.ty(error)),
+                ],
+            }),
+            None => self.ty(&method.output),
+        }
+    }
+
+    fn structure(&self, structure: &Struct) -> Value {
+        with_docs(self.object(&structure.fields), &structure.docs)
+    }
+
+    fn enumeration(&self, enumeration: &Enum) -> Value {
+        let variants = enumeration
+            .variants
+            .iter()
+            .map(|v| {
+                let schema = match &v.fields {
+                    Fields::Unit => json!({ "const": v.name }),
+                    Fields::Tuple(ty) => variant(&v.name, self.ty(ty)),
+                    Fields::Named(fields) => variant(&v.name, self.object(fields)),
+                };
+                with_docs(schema, &v.docs)
+            })
+            .collect();
+        with_docs(
+            json!({ "oneOf": Value::Array(variants) }),
+            &enumeration.docs,
+        )
+    }
+
+    /// Fields of type `Option` may be missing, as serde deserializes them as `None`.
+    fn object(&self, fields: &[Field]) -> Value {
+        let mut properties = Map::new();
+        let mut required = vec![];
+        for field in fields {
+            properties.insert(
+                field.name.clone(),
+                with_docs(self.ty(&field.ty), &field.docs),
+            );
+            if !matches!(&field.ty, Type::Path { path, .. } if path == "Option") {
+                required.push(Value::String(field.name.clone()));
+            }
+        }
+        json!({
+            "type": "object",
+            "properties": properties,
+            "required": required,
+            "additionalProperties": false,
+        })
+    }
+
+    fn ty(&self, ty: &Type) -> Value {
+        let (path, args) = match ty {
+            Type::Tuple(types) if types.is_empty() => return json!({ "type": "null" }),
+            Type::Tuple(types) => {
+                let items: Vec<_> = types.iter().map(|ty| self.ty(ty)).collect();
+                return json!({
+                    "type": "array",
+                    "items": items,
+                    "minItems": types.len(),
+                    "maxItems": types.len(),
+                });
+            }
+            Type::Array(ty, None) => return json!({ "type": "array", "items": self.ty(ty) }),
+            Type::Array(ty, Some(len)) => {
+                return json!({
+                    "type": "array",
+                    "items": self.ty(ty),
+                    "minItems": len,
+                    "maxItems": len,
+                })
+            }
+            Type::Path { path, args } => (path.as_str(), args.as_slice()),
+        };
+        match (path, args) {
+            ("bool", []) => json!({ "type": "boolean" }),
+            ("u8", []) | ("u16", []) | ("u32", []) | ("u64", []) | ("u128", []) | ("usize", []) => {
+                json!({ "type": "integer", "minimum": 0 })
+            }
+            ("i8", []) | ("i16", []) | ("i32", []) | ("i64", []) | ("i128", []) | ("isize", []) => {
+                json!({ "type": "integer" })
+            }
+            ("f32", []) | ("f64", []) => json!({ "type": "number" }),
+            ("String", []) => json!({ "type": "string" }),
+            ("char", []) => json!({ "type": "string", "minLength": 1, "maxLength": 1 }),
+            ("Option", [ty]) => json!({ "anyOf": [self.ty(ty), { "type": "null" }] }),
+            ("Box", [ty]) | ("Rc", [ty]) | ("Arc", [ty]) => self.ty(ty),
+            ("Vec", [ty]) | ("VecDeque", [ty]) => json!({ "type": "array", "items": self.ty(ty) }),
+            ("HashSet", [ty]) | ("BTreeSet", [ty]) => {
+                json!({ "type": "array", "items": self.ty(ty), "uniqueItems": true })
+            }
+            // serde_json writes map keys as strings.
+            ("HashMap", [_, value]) | ("BTreeMap", [_, value]) => {
+                json!({ "type": "object", "additionalProperties": self.ty(value) })
+            }
+            ("Result", [ok, err]) => json!({
+                "oneOf": [variant("Ok", self.ty(ok)), variant("Err", self.ty(err))],
+            }),
+            ("Duration", []) | ("std::time::Duration", []) => json!({
+                "type": "object",
+                "properties": {
+                    "secs": { "type": "integer", "minimum": 0 },
+                    "nanos": { "type": "integer", "minimum": 0 },
+                },
+                "required": ["secs", "nanos"],
+                "additionalProperties": false,
+            }),
+            (name, []) if self.is_declared(name) => {
+                json!({ "$ref": format!("#/definitions/{}", name) })
+            }
+            // Types that aren't declared in the IDL file can't be described, so any value is
+            // allowed.
+            _ => json!({ "description": format!("`{}`", ty) }),
+        }
+    }
+}
+
+/// An externally tagged enum variant, i.e. an object with the variant's name as its only key.
+fn variant(name: &str, value: Value) -> Value {
+    json!({
+        "type": "object",
+        "properties": { name: value },
+        "required": [name],
+        "additionalProperties": false,
+    })
+}
+
+fn with_docs(mut schema: Value, docs: &[String]) -> Value {
+    if let (Value::Object(schema), false) = (&mut schema, docs.is_empty()) {
+        schema
+            .entry("description")
+            .or_insert_with(|| Value::String(docs.join("\n")));
+    }
+    schema
+}
+
+/// Converts a snake_case method name to the CamelCase name of its request and response variants,
+/// as `tarpc::service` does.
+fn camel_case(name: &str) -> String {
+    let mut camel = String::with_capacity(name.len());
+    let mut capitalize = true;
+    for c in name.chars() {
+        match c {
+            '_' => capitalize = true,
+            c if capitalize => {
+                camel.extend(c.to_uppercase());
+                capitalize = false;
+            }
+            c => camel.extend(c.to_lowercase()),
+        }
+    }
+    camel
+}
+
+#[test]
+fn requests_and_responses_are_described() {
+    let schema: Value = serde_json::from_str(
+        &crate::json_schema(
+            r#"
+            struct Point { x: i64, y: i64, label: Option<String> }
+
+            service Geometry {
+                /// Moves a point.
+                rpc move_point(point: Point, by: (i64, i64)) -> Point;
+                rpc vertices(sides: u8) -> stream Point throws String;
+                rpc reset();
+            }
+            "#,
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    let definitions = &schema["definitions"];
+    assert_eq!(definitions["Point"]["required"], json!(["x", "y"]));
+
+    let move_point = &definitions["GeometryRequest"]["oneOf"][0];
+    assert_eq!(move_point["description"], "Moves a point.");
+    let args = &move_point["properties"]["MovePoint"];
+    assert_eq!(
+        args["properties"]["point"],
+        json!({ "$ref": "#/definitions/Point" })
+    );
+    assert_eq!(args["properties"]["by"]["maxItems"], 2);
+    let reset = &definitions["GeometryRequest"]["oneOf"][2]["properties"]["Reset"];
+    assert_eq!(reset["properties"], json!({}));
+
+    let responses = &definitions["GeometryResponse"]["oneOf"];
+    let vertices = &responses[1]["properties"]["Vertices"]["oneOf"];
+    assert_eq!(
+        vertices[0]["properties"]["Ok"]["$ref"],
+        "#/definitions/Point"
+    );
+    assert_eq!(vertices[1]["properties"]["Err"]["type"], "string");
+    assert_eq!(
+        responses[2]["properties"]["Reset"],
+        json!({ "type": "null" })
+    );
+}
@@ -0,0 +1,592 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use crate::{Definition, Enum, Field, Fields, Item, Method, Service, Struct, Type};
+use std::fmt::Write;
+
+/// The code shared by the clients of every service: the connection, the call options, and the
+/// channel that matches the server's messages to their calls.
+const RUNTIME: &str = r#"/** Carries one JSON-encoded tarpc message per frame between a client and a server. */
+export interface Connection {
+  send(message: string): void;
+  onMessage(handler: (message: string) => void): void;
+  onClose(handler: () => void): void;
+}
+
+/** Returns a connection sending and receiving one message per text frame of `socket`. */
+export function webSocketConnection(socket: WebSocket): Connection {
+  const queued: string[] = [];
+  socket.addEventListener("open", () => {
+    for (const message of queued.splice(0)) {
+      socket.send(message);
+    }
+  });
+  return {
+    send(message: string): void {
+      if (socket.readyState === WebSocket.CONNECTING) {
+        queued.push(message);
+      } else {
+        socket.send(message);
+      }
+    },
+    onMessage(handler: (message: string) => void): void {
+      socket.addEventListener("message", (event) => handler(String(event.data)));
+    },
+    onClose(handler: () => void): void {
+      socket.addEventListener("close", () => handler());
+    },
+  };
+}
+
+/** The kinds of errors sent by servers, as tarpc encodes Rust's `std::io::ErrorKind`. */
+export enum ErrorKind {
+  NotFound = 0,
+  PermissionDenied = 1,
+  ConnectionRefused = 2,
+  ConnectionReset = 3,
+  ConnectionAborted = 4,
+  NotConnected = 5,
+  AddrInUse = 6,
+  AddrNotAvailable = 7,
+  BrokenPipe = 8,
+  AlreadyExists = 9,
+  WouldBlock = 10,
+  InvalidInput = 11,
+  InvalidData = 12,
+  TimedOut = 13,
+  WriteZero = 14,
+  Interrupted = 15,
+  Other = 16,
+  UnexpectedEof = 17,
+}
+
+/** A call failed by the server, canceled, or cut short by the connection closing. */
+export class RpcError extends Error {
+  constructor(readonly kind: ErrorKind, readonly detail: string | null) {
+    super(detail ?? ErrorKind[kind]);
+  }
+}
+
+/** Options of a single call. */
+export interface CallOptions {
+  /** When the call must complete by. Defaults to 10 seconds from now. */
+  deadline?: Date;
+  /** The tenant on whose behalf the call is made. */
+  tenantId?: string;
+  /** The API key the call is authenticated with. */
+  apiKey?: string;
+  /** Cancels the call once aborted. */
+  signal?: AbortSignal;
+}
+
+interface Call {
+  /** The trace context of the request, which cancellations must repeat. */
+  traceContext: string;
+  response(message: unknown): void;
+  item(item: unknown): void;
+  end(): void;
+  fail(error: RpcError): void;
+}
+
+/** The credit granted to the server for the items of a streamed reply. */
+const STREAM_WINDOW = 64;
+
+/** Returns a random integer of `words` 32-bit words, in decimal, as JSON numbers are written. */
+function randomId(words: number): string {
+  let id = BigInt(0);
+  for (const word of crypto.getRandomValues(new Uint32Array(words))) {
+    id = (id << BigInt(32)) | BigInt(word);
+  }
+  return id.toString();
+}
+
+/**
+ * Sends requests over a connection, and matches the server's messages to their calls. A channel
+ * is shared by the clients of every service served over its connection.
+ *
+ * Messages are encoded as tarpc's serde_json codec encodes them. JSON numbers are parsed as
+ * JavaScript numbers, so integers beyond 2^53 lose precision.
+ */
+export class Channel {
+  private nextId = 0;
+  private readonly calls = new Map<number, Call>();
+  private closed = false;
+
+  constructor(private readonly connection: Connection) {
+    connection.onMessage((message) => this.receive(JSON.parse(message)));
+    connection.onClose(() => {
+      this.closed = true;
+      const error = new RpcError(ErrorKind.ConnectionReset, "The connection closed.");
+      for (const call of this.calls.values()) {
+        call.fail(error);
+      }
+      this.calls.clear();
+    });
+  }
+
+  /** Calls the method of the request enum's `variant` and resolves with its response. */
+  call<T>(variant: string, args: object, options: CallOptions = {}): Promise<T> {
+    return new Promise((resolve, reject) => {
+      this.start(variant, args, options, false, {
+        traceContext: "",
+        response: (message) => resolve((message as Record<string, T>)[variant]),
+        item: () => {},
+        end: () => {},
+        fail: reject,
+      });
+    });
+  }
+
+  /**
+   * Calls the streaming method of the request enum's `variant` and yields the items of its reply.
+   * The request is sent once iteration starts, and canceled if iteration stops early.
+   */
+  async *stream<T>(variant: string, args: object, options: CallOptions = {}): AsyncGenerator<T> {
+    const items: T[] = [];
+    const state: { done: boolean; error: RpcError | null; wake: (() => void) | null } = {
+      done: false,
+      error: null,
+      wake: null,
+    };
+    const notify = () => {
+      const wake = state.wake;
+      state.wake = null;
+      if (wake) {
+        wake();
+      }
+    };
+    const id = this.start(variant, args, options, true, {
+      traceContext: "",
+      // tarpc ends streams that end successfully with a stream end, and those that fail with an
+      // error response, but a successful response ends them too.
+      response: () => {
+        state.done = true;
+        notify();
+      },
+      item: (item) => {
+        items.push((item as Record<string, T>)[variant]);
+        notify();
+      },
+      end: () => {
+        state.done = true;
+        notify();
+      },
+      fail: (error) => {
+        state.error = error;
+        notify();
+      },
+    });
+    let drained = 0;
+    try {
+      for (;;) {
+        if (items.length > 0) {
+          yield items.shift() as T;
+          drained += 1;
+          if (drained >= STREAM_WINDOW / 2 && this.calls.has(id)) {
+            this.connection.send(
+              `{"WindowUpdate":{"request_id":${id},"credits":${drained}}}`);
+            drained = 0;
+          }
+        } else if (state.error) {
+          throw state.error;
+        } else if (state.done) {
+          return;
+        } else {
+          await new Promise<void>((resolve) => (state.wake = resolve));
+        }
+      }
+    } finally {
+      this.cancel(id);
+    }
+  }
+
+  /** Sends the request to `variant` and returns its ID. */
+  private start(
+    variant: string, args: object, options: CallOptions, stream: boolean, call: Call): number {
+    const id = this.nextId++;
+    if (this.closed) {
+      call.fail(new RpcError(ErrorKind.NotConnected, "The connection closed."));
+      return id;
+    }
+    call.traceContext =
+      `{"trace_id":${randomId(4)},"span_id":${randomId(2)},"parent_id":null}`;
+    this.calls.set(id, call);
+    const deadline = options.deadline ?? new Date(Date.now() + 10000);
+    const context = `{"deadline":${Math.floor(deadline.getTime() / 1000)},` +
+      `"trace_context":${call.traceContext},` +
+      `"tenant_id":${JSON.stringify(options.tenantId ?? null)},` +
+      `"api_key":${JSON.stringify(options.apiKey ?? null)}}`;
+    const message = JSON.stringify({ [variant]: args });
+    this.connection.send(`{"Request":{"context":${context},"id":${id},"message":${message}}}`);
+    if (stream) {
+      this.connection.send(`{"WindowUpdate":{"request_id":${id},"credits":${STREAM_WINDOW}}}`);
+    }
+    const signal = options.signal;
+    if (signal) {
+      signal.addEventListener("abort", () => {
+        if (this.calls.has(id)) {
+          this.cancel(id);
+          call.fail(new RpcError(ErrorKind.Other, "The call was canceled."));
+        }
+      });
+    }
+    return id;
+  }
+
+  /** Tells the server to cancel request `id`, if it's still in flight. */
+  private cancel(id: number): void {
+    const call = this.calls.get(id);
+    if (call && !this.closed) {
+      this.calls.delete(id);
+      this.connection.send(
+        `{"Cancel":{"trace_context":${call.traceContext},"request_id":${id}}}`);
+    }
+  }
+
+  private receive(message: any): void {
+    // Messages that aren't about a call, like "GoAway" and notifications, are ignored.
+    if (typeof message !== "object" || message === null) {
+      return;
+    }
+    if ("Response" in message) {
+      const response = message.Response;
+      const call = this.calls.get(response.request_id);
+      if (call) {
+        this.calls.delete(response.request_id);
+        if ("Ok" in response.message) {
+          call.response(response.message.Ok);
+        } else {
+          const error = response.message.Err;
+          call.fail(new RpcError(error.kind, error.detail ?? null));
+        }
+      }
+    } else if ("StreamItem" in message) {
+      const call = this.calls.get(message.StreamItem.request_id);
+      if (call) {
+        call.item(message.StreamItem.item);
+      }
+    } else if ("StreamEnd" in message) {
+      const call = this.calls.get(message.StreamEnd.request_id);
+      if (call) {
+        this.calls.delete(message.StreamEnd.request_id);
+        call.end();
+      }
+    }
+  }
+}
+"#;
+
+/// Returns the TypeScript code for `definition`.
+pub(crate) fn generate(definition: &Definition) -> String {
+    let declared: Vec<_> = definition
+        .items
+        .iter()
+        .filter_map(|item| match item {
+            Item::Struct(Struct { name, .. }) | Item::Enum(Enum { name, .. }) => Some(&**name),
+            Item::Service(_) => None,
+        })
+        .collect();
+    let mut code = String::from("// Generated by tarpc-build. Edit the IDL file instead.\n\n");
+    code.push_str(RUNTIME);
+    for item in &definition.items {
+        code.push('\n');
+        match item {
+            Item::Service(service) => generate_service(&mut code, &declared, service),
+            Item::Struct(structure) => generate_struct(&mut code, &declared, structure),
+            Item::Enum(enumeration) => generate_enum(&mut code, &declared, enumeration),
+        }
+    }
+    code
+}
+
+fn docs(code: &mut String, indent: &str, docs: &[String]) {
+    match docs {
+        [] => {}
+        [doc] => writeln!(code, "{}/** {} */", indent, doc).unwrap(),
+        docs => {
+            writeln!(code, "{}/**", indent).unwrap();
+            for doc in docs {
+                if doc.is_empty() {
+                    writeln!(code, "{} *", indent).unwrap();
+                } else {
+                    writeln!(code, "{} * {}", indent, doc).unwrap();
+                }
+            }
+            writeln!(code, "{} */", indent).unwrap();
+        }
+    }
+}
+
+/// Writes the members of an object type. Fields of type `Option` may be missing, as serde
+/// deserializes them as `None`.
+fn fields(code: &mut String, declared: &[&str], indent: &str, fields: &[Field]) {
+    for field in fields {
+        docs(code, indent, &field.docs);
+        let optional = if is_option(&field.ty) { "?" } else { "" };
+        writeln!(
+            code,
+            "{}{}{}: {};",
+            indent,
+            field.name,
+            optional,
+            ty(declared, &field.ty)
+        )
+        .unwrap();
+    }
+}
+
+fn is_option(ty: &Type) -> bool {
+    matches!(ty, Type::Path { path, .. } if path == "Option")
+}
+
+fn generate_struct(code: &mut String, declared: &[&str], structure: &Struct) {
+    docs(code, "", &structure.docs);
+    writeln!(code, "export interface {} {{", structure.name).unwrap();
+    fields(code, declared, "  ", &structure.fields);
+    code.push_str("}\n");
+}
+
+/// Enums are externally tagged, as serde tags them by default.
+fn generate_enum(code: &mut String, declared: &[&str], enumeration: &Enum) {
+    docs(code, "", &enumeration.docs);
+    writeln!(code, "export type {} =", enumeration.name).unwrap();
+    if enumeration.variants.is_empty() {
+        code.push_str("  never;\n");
+    }
+    for (i, variant) in enumeration.variants.iter().enumerate() {
+        docs(code, "  ", &variant.docs);
+        match &variant.fields {
+            Fields::Unit => write!(code, "  | \"{}\"", variant.name).unwrap(),
+            Fields::Tuple(t) => {
+                write!(code, "  | {{ {}: {} }}", variant.name, ty(declared, t)).unwrap()
+            }
+            Fields::Named(named) => {
+                writeln!(code, "  | {{").unwrap();
+                writeln!(code, "    {}: {{", variant.name).unwrap();
+                fields(code, declared, "      ", named);
+                code.push_str("    };\n  }");
+            }
+        }
+        code.push_str(if i + 1 == enumeration.variants.len() {
+            ";\n"
+        } else {
+            "\n"
+        });
+    }
+}
+
+fn generate_service(code: &mut String, declared: &[&str], service: &Service) {
+    docs(code, "", &service.docs);
+    writeln!(code, "export class {}Client {{", service.name).unwrap();
+    code.push_str("  constructor(readonly channel: Channel) {}\n");
+    for method in &service.methods {
+        code.push('\n');
+        docs(code, "  ", &method.docs);
+        let options = if method.args.iter().any(|arg| arg.name == "options") {
+            "options_"
+        } else {
+            "options"
+        };
+        let mut params: Vec<_> = method
+            .args
+            .iter()
+            .map(|arg| format!("{}: {}", arg.name, ty(declared, &arg.ty)))
+            .collect();
+        params.push(format!("{}?: CallOptions", options));
+        let args: Vec<_> = method.args.iter().map(|arg| arg.name.as_str()).collect();
+        let args = if args.is_empty() {
+            "{}".to_string()
+        } else {
+            format!("{{ {} }}", args.join(", "))
+        };
+        let (return_type, call) = if method.stream {
+            ("AsyncGenerator", "stream")
+        } else {
+            ("Promise", "call")
+        };
+        let reply = reply(declared, method);
+        writeln!(
+            code,
+            "  {}({}): {}<{}> {{",
+            lower_camel(&method.name),
+            params.join(", "),
+            return_type,
+            reply
+        )
+        .unwrap();
+        writeln!(
+            code,
+            "    return this.channel.{}<{}>(\"{}\", {}, {});",
+            call,
+            reply,
+            upper_camel(&method.name),
+            args,
+            options
+        )
+        .unwrap();
+        code.push_str("  }\n");
+    }
+    code.push_str("}\n");
+}
+
+fn reply(declared: &[&str], method: &Method) -> String {
+    match &method.error {
+        Some(error) => format!(
+            "{{ Ok: {} }} | {{ Err: {} }}",
+            ty(declared, &method.output),
+            ty(declared, error)
+        ),
+        None => ty(declared, &method.output),
+    }
+}
+
+/// Returns the TypeScript type of the JSON that serde_json encodes `ty` as.
+fn ty(declared: &[&str], ty: &Type) -> String {
+    let (path, args) = match ty {
+        Type::Tuple(types) if types.is_empty() => return "null".into(),
+        Type::Tuple(types) => {
+            let types: Vec<_> = types.iter().map(|t| self::ty(declared, t)).collect();
+            return format!("[{}]", types.join(", "));
+        }
+        Type::Array(element, _) => return array(declared, element),
+        Type::Path { path, args } => (path.as_str(), args.as_slice()),
+    };
+    match (path, args) {
+        ("bool", []) => "boolean".into(),
+        ("u8", [])
+        | ("u16", [])
+        | ("u32", [])
+        | ("u64", [])
+        | ("u128", [])
+        | ("usize", [])
+        | ("i8", [])
+        | ("i16", [])
+        | ("i32", [])
+        | ("i64", [])
+        | ("i128", [])
+        | ("isize", [])
+        | ("f32", [])
+        | ("f64", []) => "number".into(),
+        ("String", []) | ("char", []) => "string".into(),
+        ("Option", [t]) => format!("{} | null", self::ty(declared, t)),
+        ("Box", [t]) | ("Rc", [t]) | ("Arc", [t]) => self::ty(declared, t),
+        ("Vec", [t]) | ("VecDeque", [t]) | ("HashSet", [t]) | ("BTreeSet", [t]) => {
+            array(declared, t)
+        }
+        // serde_json writes map keys as strings.
+        ("HashMap", [_, value]) | ("BTreeMap", [_, value]) => {
+            format!("{{ [key: string]: {} }}", self::ty(declared, value))
+        }
+        ("Result", [ok, err]) => format!(
+            "{{ Ok: {} }} | {{ Err: {} }}",
+            self::ty(declared, ok),
+            self::ty(declared, err)
+        ),
+        ("Duration", []) | ("std::time::Duration", []) => "{ secs: number; nanos: number }".into(),
+        (name, []) if declared.contains(&name) => name.into(),
+        // Types that aren't declared in the IDL file can't be described.
+        _ => "unknown".into(),
+    }
+}
+
+fn array(declared: &[&str], element: &Type) -> String {
+    let element = ty(declared, element);
+    if element.contains(' ') {
+        format!("({})[]", element)
+    } else {
+        format!("{}[]", element)
+    }
+}
+
+/// Converts a snake_case method name to the CamelCase name of its request variant, as
+/// `tarpc::service` does.
+fn upper_camel(name: &str) -> String {
+    let mut camel = String::with_capacity(name.len());
+    let mut capitalize = true;
+    for c in name.chars() {
+        match c {
+            '_' => capitalize = true,
+            c if capitalize => {
+                camel.extend(c.to_uppercase());
+                capitalize = false;
+            }
+            c => camel.extend(c.to_lowercase()),
+        }
+    }
+    camel
+}
+
+/// Converts a snake_case method name to the camelCase name of its client method.
+fn lower_camel(name: &str) -> String {
+    let camel = upper_camel(name);
+    let mut chars = camel.chars();
+    match chars.next() {
+        Some(first) => first.to_lowercase().chain(chars).collect(),
+        None => camel,
+    }
+}
+
+#[test]
+fn services_become_clients() {
+    let code = crate::typescript(
+        r#"
+        /// A point.
+        struct Point { x: i64, label: Option<String> }
+
+        enum Shape {
+            Empty,
+            Polygon(Vec<Point>),
+            Circle { center: Point, radius: u64 },
+        }
+
+        service Geometry {
+            /// Returns the area of `shape`.
+            rpc area(shape: Shape) -> u64;
+            rpc vertices(shape: Shape) -> stream Point throws String;
+            rpc reset_all();
+        }
+        "#,
+    )
+    .unwrap();
+    let generated = &code[code.find("\n/** A point. */").unwrap()..];
+    assert_eq!(
+        generated,
+        r#"
+/** A point. */
+export interface Point {
+  x: number;
+  label?: string | null;
+}
+
+export type Shape =
+  | "Empty"
+  | { Polygon: Point[] }
+  | {
+    Circle: {
+      center: Point;
+      radius: number;
+    };
+  };
+
+export class GeometryClient {
+  constructor(readonly channel: Channel) {}
+
+  /** Returns the area of `shape`. */
+  area(shape: Shape, options?: CallOptions): Promise<number> {
+    return this.channel.call<number>("Area", { shape }, options);
+  }
+
+  vertices(shape: Shape, options?: CallOptions): AsyncGenerator<{ Ok: Point } | { Err: string }> {
+    return this.channel.stream<{ Ok: Point } | { Err: string }>("Vertices", { shape }, options);
+  }
+
+  resetAll(options?: CallOptions): Promise<null> {
+    return this.channel.call<null>("ResetAll", {}, options);
+  }
+}
+"#
+    );
+}
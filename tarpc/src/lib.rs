@@ -202,9 +202,16 @@
 #![deny(missing_docs)]
 #![allow(clippy::type_complexity)]
 
+// Lets the services defined in this crate, like the benchmark's echo service, refer to it by name.
+#[cfg(any(feature = "admin", feature = "bench"))]
+extern crate self as tarpc;
+
 pub mod rpc;
 pub use rpc::*;
 
+#[doc(hidden)]
+pub use futures;
+
 #[cfg(feature = "serde-transport")]
 pub mod serde_transport;
 
@@ -232,4 +239,36 @@ pub mod trace;
 ///   * `fn serve` -- turns a service impl into a request handler.
 /// * `Client` -- a client stub with a fn for each RPC.
 ///   * `fn new_stub` -- creates a new Client stub.
+///
+/// An rpc declared to return `impl Stream<Item = T>` streams its reply: implementations provide a
+/// `Stream` associated type in place of a `Fut`, the service is served with `respond_with_stream`,
+/// and the client stub resolves to a stream of items.
+///
+/// A `#[tarpc(..)]` attribute declares the client stub's policy for calling an rpc:
+///
+/// ```
+/// #[tarpc::service]
+/// trait Store {
+///     /// Reads a value, retrying transient failures twice.
+///     #[tarpc(deadline = "500ms", idempotent, retries = 2)]
+///     async fn get(key: String) -> Option<String>;
+/// }
+/// ```
+///
+/// * `deadline = ".."` -- moves the deadline of each call's context up to the given time after
+///   the call, if it's later. Durations are written like `"1s 500ms"`.
+/// * `idempotent` -- permits the stub to retry failed calls, once unless `retries` says otherwise;
+///   the rpc's args must be `Clone`. Streaming rpcs can't be retried. See
+///   [`client::wait_to_retry`] for which failures are retried.
+/// * `retries = N` -- how many times an `idempotent` rpc's failed calls are retried.
+/// * `validate = ".."` -- names a fn that checks the rpc's args before the server serves them,
+///   if the server validates requests with [`server::Declared`]. The fn takes a reference to each
+///   arg and returns a `Result<(), String>`, whose error is sent to the client.
+/// * `cache = ".."` -- permits a [`client::cache::Cache`] to reuse the rpc's responses for the
+///   given time, keyed by its args, which must be `Clone + Eq + Hash`; its return type must be
+///   `Clone`. Streaming rpcs can't be cached.
+/// * `exactly_once` -- gives each call an idempotency key and retries it like an `idempotent` rpc,
+///   so that a server serving requests with [`server::ExactlyOnceChannel`] executes it once
+///   however many times it's retransmitted. The rpc's return type must be `Clone`. Streaming rpcs
+///   can't be executed exactly once.
 pub use tarpc_plugins::service;
@@ -0,0 +1,222 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! An [admin service](Admin) that lets operators reconfigure a running server: change its log
+//! verbosity, switch [debug capture](crate::transport::capture) on and off, adjust its
+//! [method limits](crate::server::MethodLimits), and start [draining](crate::server::Drain) it,
+//! without restarting it.
+//!
+//! Serve an [`AdminServer`], configured with the handles of the server it administers, on a
+//! listener of its own, so that only operators can reach it:
+//!
+//! ```
+//! # use tarpc::{admin::{Admin, AdminClient, AdminServer}, client, context, server::{BaseChannel, Channel, Drain, MethodLimits}, transport};
+//! # use std::io;
+//! # #[tokio::main]
+//! # async fn main() -> io::Result<()> {
+//! let drain = Drain::new();
+//! let limits = MethodLimits::new().with_limit("report", 2, 10);
+//! let admin = AdminServer::new()
+//!     .with_drain(drain.clone())
+//!     .with_method_limits(limits.clone());
+//!
+//! let (client_transport, server_transport) = transport::channel::unbounded();
+//! tokio::spawn(BaseChannel::with_defaults(server_transport).respond_with(admin.serve()).execute());
+//! let mut client = AdminClient::new(client::Config::default(), client_transport).spawn()?;
+//!
+//! client.set_method_limit(context::current(), "report".into(), 4).await?.unwrap();
+//! client.drain(context::current()).await?.unwrap();
+//! assert!(drain.is_draining());
+//! # Ok(())
+//! # }
+//! ```
+
+#[cfg(feature = "debug-capture")]
+use crate::transport::capture::Capture;
+use crate::{
+    context,
+    server::{Drain, MethodLimits},
+};
+use futures::future::{self, Ready};
+use log::{info, LevelFilter};
+
+pub use self::service::*;
+
+mod service {
+    // The generated request and response types aren't documented.
+    #![allow(missing_docs)]
+
+    /// Reconfigures a running server.
+    #[crate::service]
+    pub trait Admin {
+        /// Returns the maximum level of the messages logged, e.g. "INFO".
+        async fn log_level() -> String;
+        /// Sets the maximum level of the messages logged to `level`: one of "off", "error",
+        /// "warn", "info", "debug", or "trace".
+        async fn set_log_level(level: String) -> Result<(), String>;
+        /// Starts or stops capturing the messages of the server's transports.
+        async fn set_capture(enabled: bool) -> Result<(), String>;
+        /// Sets the number of requests to `method` handled at once to `max_concurrent`.
+        async fn set_method_limit(method: String, max_concurrent: u32) -> Result<(), String>;
+        /// Starts draining the server.
+        async fn drain() -> Result<(), String>;
+    }
+}
+
+/// Serves [`Admin`] by reconfiguring the handles it's given. Requests to reconfigure something
+/// the server wasn't given a handle to fail.
+#[derive(Clone, Debug, Default)]
+pub struct AdminServer {
+    drain: Option<Drain>,
+    method_limits: Option<MethodLimits>,
+    #[cfg(feature = "debug-capture")]
+    capture: Option<Capture>,
+}
+
+impl AdminServer {
+    /// Returns an admin server that can only change the log verbosity.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lets the admin server start `drain`.
+    pub fn with_drain(mut self, drain: Drain) -> Self {
+        self.drain = Some(drain);
+        self
+    }
+
+    /// Lets the admin server adjust `method_limits`.
+    pub fn with_method_limits(mut self, method_limits: MethodLimits) -> Self {
+        self.method_limits = Some(method_limits);
+        self
+    }
+
+    /// Lets the admin server switch `capture` on and off.
+    #[cfg(feature = "debug-capture")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "debug-capture")))]
+    pub fn with_capture(mut self, capture: Capture) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+}
+
+impl Admin for AdminServer {
+    type LogLevelFut = Ready<String>;
+
+    fn log_level(self, _: context::Context) -> Self::LogLevelFut {
+        future::ready(log::max_level().to_string())
+    }
+
+    type SetLogLevelFut = Ready<Result<(), String>>;
+
+    fn set_log_level(self, _: context::Context, level: String) -> Self::SetLogLevelFut {
+        future::ready(match level.parse::<LevelFilter>() {
+            Ok(level) => {
+                info!("Setting the log level to {}.", level);
+                log::set_max_level(level);
+                Ok(())
+            }
+            Err(_) => Err(format!("Unknown log level {:?}.", level)),
+        })
+    }
+
+    type SetCaptureFut = Ready<Result<(), String>>;
+
+    #[cfg(feature = "debug-capture")]
+    fn set_capture(self, _: context::Context, enabled: bool) -> Self::SetCaptureFut {
+        future::ready(match self.capture {
+            Some(capture) => {
+                info!(
+                    "{} debug capture.",
+                    if enabled { "Starting" } else { "Stopping" }
+                );
+                if enabled {
+                    capture.enable();
+                } else {
+                    capture.disable();
+                }
+                Ok(())
+            }
+            None => Err("The server has no debug capture.".into()),
+        })
+    }
+
+    #[cfg(not(feature = "debug-capture"))]
+    fn set_capture(self, _: context::Context, _: bool) -> Self::SetCaptureFut {
+        future::ready(Err("The server has no debug capture.".into()))
+    }
+
+    type SetMethodLimitFut = Ready<Result<(), String>>;
+
+    fn set_method_limit(
+        self,
+        _: context::Context,
+        method: String,
+        max_concurrent: u32,
+    ) -> Self::SetMethodLimitFut {
+        let method_limits = match self.method_limits {
+            Some(method_limits) => method_limits,
+            None => return future::ready(Err("The server has no method limits.".into())),
+        };
+        if max_concurrent == 0 {
+            return future::ready(Err("The limit must be positive.".into()));
+        }
+        future::ready(
+            if method_limits.set_limit(&method, max_concurrent as usize) {
+                info!(
+                    "Limiting {} to {} requests at once.",
+                    method, max_concurrent
+                );
+                Ok(())
+            } else {
+                Err(format!("Method {} isn't limited.", method))
+            },
+        )
+    }
+
+    type DrainFut = Ready<Result<(), String>>;
+
+    fn drain(self, _: context::Context) -> Self::DrainFut {
+        future::ready(match self.drain {
+            Some(drain) => {
+                info!("Draining the server.");
+                drain.start();
+                Ok(())
+            }
+            None => Err("The server has no drain.".into()),
+        })
+    }
+}
+
+#[tokio::test]
+async fn admin_server_rejects_what_it_cannot_reconfigure() {
+    let admin = AdminServer::new().with_method_limits(MethodLimits::new().with_limit("a", 1, 0));
+    let ctx = context::current;
+
+    let level = admin.clone().log_level(ctx()).await;
+    assert_eq!(admin.clone().set_log_level(ctx(), level).await, Ok(()));
+    assert!(admin
+        .clone()
+        .set_log_level(ctx(), "loud".into())
+        .await
+        .is_err());
+    assert!(admin.clone().set_capture(ctx(), true).await.is_err());
+    assert_eq!(
+        admin.clone().set_method_limit(ctx(), "a".into(), 2).await,
+        Ok(())
+    );
+    assert!(admin
+        .clone()
+        .set_method_limit(ctx(), "b".into(), 2)
+        .await
+        .is_err());
+    assert!(admin
+        .clone()
+        .set_method_limit(ctx(), "a".into(), 0)
+        .await
+        .is_err());
+    assert!(admin.drain(ctx()).await.is_err());
+}
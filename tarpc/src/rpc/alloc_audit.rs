@@ -0,0 +1,317 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Counts the heap allocations that clients and servers make per request, to find the
+//! allocations and copies on the path of every request, and to confirm that removing them worked.
+//!
+//! Counting requires installing a [`CountingAllocator`] as the global allocator; without it, every
+//! count is zero. Clients report their counts in their [stats](crate::client::channel::Stats), and
+//! servers in the [`AllocAudit`] of their [config](crate::server::Config):
+//!
+//! ```
+//! # use futures::future;
+//! # use tarpc::{alloc_audit::{AllocAudit, CountingAllocator}, client, context, server::{self, BaseChannel, Channel}, transport};
+//! # use std::io;
+//! #[global_allocator]
+//! static ALLOCATOR: CountingAllocator = CountingAllocator::new();
+//!
+//! # #[tokio::main]
+//! # async fn main() -> io::Result<()> {
+//! let audit = AllocAudit::new();
+//! let config = server::Config {
+//!     alloc_audit: Some(audit.clone()),
+//!     ..Default::default()
+//! };
+//! let (client_transport, server_transport) = transport::channel::unbounded();
+//! tokio::spawn(
+//!     BaseChannel::new(config, server_transport)
+//!         .respond_with(|_, x: u32| future::ready(x + 1))
+//!         .execute(),
+//! );
+//! let mut client = client::new(client::Config::default(), client_transport).spawn()?;
+//! client.call(context::current(), 1).await?;
+//!
+//! let client_report = client.stats().allocations;
+//! println!("Client: {:.1} allocations per request.", client_report.allocations_per_request());
+//! println!("Server: {:.1} allocations per request.", audit.report().allocations_per_request());
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Counts are taken on the thread doing a request's work while it's doing it, e.g. while its
+//! handler is polled, and while the channel serving it decodes, routes, and encodes messages.
+//! Work a channel does for many requests at once is counted toward all of them, so the counts are
+//! per-request averages.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    ops::Sub,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// Counts of heap allocations.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct AllocCounts {
+    /// The number of blocks allocated.
+    pub allocations: u64,
+    /// The number of bytes allocated, including by reallocations that grew a block.
+    pub bytes: u64,
+    /// The number of blocks reallocated. A reallocation may move, and so copy, the block.
+    pub reallocations: u64,
+}
+
+impl Sub for AllocCounts {
+    type Output = AllocCounts;
+
+    fn sub(self, earlier: AllocCounts) -> AllocCounts {
+        AllocCounts {
+            allocations: self.allocations.wrapping_sub(earlier.allocations),
+            bytes: self.bytes.wrapping_sub(earlier.bytes),
+            reallocations: self.reallocations.wrapping_sub(earlier.reallocations),
+        }
+    }
+}
+
+thread_local! {
+    /// The allocations made by the current thread.
+    static THREAD_COUNTS: Cell<AllocCounts> = const {
+        Cell::new(AllocCounts {
+            allocations: 0,
+            bytes: 0,
+            reallocations: 0,
+        })
+    };
+}
+
+/// Returns the allocations the current thread has made through a [`CountingAllocator`].
+pub fn thread_counts() -> AllocCounts {
+    THREAD_COUNTS.with(Cell::get)
+}
+
+/// Runs `f`, returning the allocations the current thread made while it ran.
+pub(crate) fn measure<R>(f: impl FnOnce() -> R) -> (R, AllocCounts) {
+    let before = thread_counts();
+    let result = f();
+    (result, thread_counts() - before)
+}
+
+/// Runs `f`, adding the allocations made while it ran to `audit`.
+pub(crate) fn audit<R>(audit: &AllocAudit, f: impl FnOnce() -> R) -> R {
+    let (result, counts) = measure(f);
+    audit.record(counts);
+    result
+}
+
+fn count(f: impl FnOnce(&mut AllocCounts)) {
+    // Allocations made while the thread is being torn down go uncounted.
+    let _ = THREAD_COUNTS.try_with(|counts| {
+        let mut updated = counts.get();
+        f(&mut updated);
+        counts.set(updated);
+    });
+}
+
+/// A global allocator that counts the allocations of each thread, delegating to another
+/// allocator, by default the system's.
+#[derive(Debug, Default)]
+pub struct CountingAllocator<A = System> {
+    inner: A,
+}
+
+impl CountingAllocator {
+    /// Returns an allocator that counts allocations made with the system's allocator.
+    pub const fn new() -> Self {
+        CountingAllocator { inner: System }
+    }
+}
+
+impl<A> CountingAllocator<A> {
+    /// Returns an allocator that counts allocations made with `inner`.
+    pub const fn wrapping(inner: A) -> Self {
+        CountingAllocator { inner }
+    }
+}
+
+// SAFETY: all allocation is delegated to `inner`; counting doesn't allocate.
+unsafe impl<A: GlobalAlloc> GlobalAlloc for CountingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count(|counts| {
+            counts.allocations += 1;
+            counts.bytes += layout.size() as u64;
+        });
+        // SAFETY: the caller upholds `alloc`'s contract.
+        unsafe { self.inner.alloc(layout) }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count(|counts| {
+            counts.allocations += 1;
+            counts.bytes += layout.size() as u64;
+        });
+        // SAFETY: the caller upholds `alloc_zeroed`'s contract.
+        unsafe { self.inner.alloc_zeroed(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // SAFETY: the caller upholds `dealloc`'s contract.
+        unsafe { self.inner.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count(|counts| {
+            counts.reallocations += 1;
+            counts.bytes += new_size.saturating_sub(layout.size()) as u64;
+        });
+        // SAFETY: the caller upholds `realloc`'s contract.
+        unsafe { self.inner.realloc(ptr, layout, new_size) }
+    }
+}
+
+/// The allocations made for requests, and the number of requests they were made for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct AllocReport {
+    /// The number of requests.
+    pub requests: u64,
+    /// The allocations made for them.
+    pub total: AllocCounts,
+}
+
+impl AllocReport {
+    /// Returns the mean number of allocations made per request.
+    pub fn allocations_per_request(&self) -> f64 {
+        self.per_request(self.total.allocations)
+    }
+
+    /// Returns the mean number of bytes allocated per request.
+    pub fn bytes_per_request(&self) -> f64 {
+        self.per_request(self.total.bytes)
+    }
+
+    /// Returns the mean number of reallocations made per request.
+    pub fn reallocations_per_request(&self) -> f64 {
+        self.per_request(self.total.reallocations)
+    }
+
+    fn per_request(&self, count: u64) -> f64 {
+        if self.requests == 0 {
+            0.0
+        } else {
+            count as f64 / self.requests as f64
+        }
+    }
+}
+
+/// Accumulates the allocations made for requests. Clones share the same counts, so one audit can
+/// be shared by every channel of a server.
+#[derive(Clone, Debug, Default)]
+pub struct AllocAudit {
+    inner: Arc<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    requests: AtomicU64,
+    allocations: AtomicU64,
+    bytes: AtomicU64,
+    reallocations: AtomicU64,
+}
+
+impl AllocAudit {
+    /// Returns an audit that has seen no requests.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the allocations made for requests so far.
+    pub fn report(&self) -> AllocReport {
+        AllocReport {
+            requests: self.inner.requests.load(Ordering::Relaxed),
+            total: AllocCounts {
+                allocations: self.inner.allocations.load(Ordering::Relaxed),
+                bytes: self.inner.bytes.load(Ordering::Relaxed),
+                reallocations: self.inner.reallocations.load(Ordering::Relaxed),
+            },
+        }
+    }
+
+    /// Counts a request.
+    pub(crate) fn request(&self) {
+        self.inner.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Adds `counts` to the allocations made for requests.
+    pub(crate) fn record(&self, counts: AllocCounts) {
+        self.inner
+            .allocations
+            .fetch_add(counts.allocations, Ordering::Relaxed);
+        self.inner.bytes.fetch_add(counts.bytes, Ordering::Relaxed);
+        self.inner
+            .reallocations
+            .fetch_add(counts.reallocations, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator::new();
+
+#[test]
+fn allocations_are_counted() {
+    let (vec, counts) = measure(|| {
+        let mut vec = Vec::<u8>::with_capacity(100);
+        vec.extend_from_slice(&[0; 200]);
+        vec
+    });
+    assert_eq!(counts.allocations, 1);
+    assert!(counts.reallocations >= 1, "{:?}", counts);
+    assert!(counts.bytes >= 200, "{:?}", counts);
+    drop(vec);
+
+    let ((), counts) = measure(|| {});
+    assert_eq!(counts, AllocCounts::default());
+}
+
+#[tokio::test]
+async fn clients_and_servers_report_allocations() -> std::io::Result<()> {
+    use crate::{
+        client, context,
+        server::{self, BaseChannel, Channel},
+        transport,
+    };
+    use futures::future;
+
+    let audit = AllocAudit::new();
+    let config = server::Config {
+        alloc_audit: Some(audit.clone()),
+        ..Default::default()
+    };
+    let (client_transport, server_transport) = transport::channel::unbounded();
+    tokio::spawn(
+        BaseChannel::new(config, server_transport)
+            .respond_with(|_, x: u32| future::ready(vec![x; 10]))
+            .execute(),
+    );
+    let mut client = client::new(client::Config::default(), client_transport).spawn()?;
+    for x in 0..3 {
+        client.call(context::current(), x).await?;
+    }
+
+    let report = client.stats().allocations;
+    assert_eq!(report.requests, 3);
+    assert!(report.allocations_per_request() >= 1.0, "{:?}", report);
+    let report = audit.report();
+    assert_eq!(report.requests, 3);
+    // At least the reply of each request is allocated.
+    assert!(report.allocations_per_request() >= 1.0, "{:?}", report);
+    assert!(report.bytes_per_request() >= 40.0, "{:?}", report);
+    Ok(())
+}
@@ -0,0 +1,187 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! A standard [echo service](Echo) and a [load generator](generate_load) for it, to benchmark a
+//! transport and codec configuration without writing a harness.
+//!
+//! Serve [`EchoServer`] over the transport under test, point an [`EchoClient`] at it, and generate
+//! load:
+//!
+//! ```
+//! # use tarpc::{bench::{self, EchoClient, EchoServer, LoadConfig}, client, server::{BaseChannel, Channel}, transport};
+//! # use std::{io, time::Duration};
+//! use tarpc::bench::Echo;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> io::Result<()> {
+//! let (client_transport, server_transport) = transport::channel::unbounded();
+//! tokio::spawn(BaseChannel::with_defaults(server_transport).respond_with(EchoServer.serve()).execute());
+//! let client = EchoClient::new(client::Config::default(), client_transport).spawn()?;
+//!
+//! let mut config = LoadConfig::default();
+//! config.duration = Duration::from_millis(100);
+//! let report = bench::generate_load(client, &config).await;
+//! println!("{}", report);
+//! assert_eq!(report.errors, 0);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::context;
+use futures::future::{self, Ready};
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+pub use self::echo::*;
+
+mod echo {
+    // The generated request and response types aren't documented.
+    #![allow(missing_docs)]
+
+    /// A service that replies to each request with its payload.
+    #[crate::service]
+    pub trait Echo {
+        /// Returns `payload`.
+        async fn echo(payload: Vec<u8>) -> Vec<u8>;
+    }
+}
+
+/// Serves [`Echo`] by replying with each payload as is.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EchoServer;
+
+impl Echo for EchoServer {
+    type EchoFut = Ready<Vec<u8>>;
+
+    fn echo(self, _: context::Context, payload: Vec<u8>) -> Self::EchoFut {
+        future::ready(payload)
+    }
+}
+
+/// The shape of the load [`generate_load`] sends.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct LoadConfig {
+    /// The number of requests kept in flight at once.
+    pub concurrency: usize,
+    /// The length in bytes of each request's payload.
+    pub payload_len: usize,
+    /// How long to send requests for.
+    pub duration: Duration,
+}
+
+impl Default for LoadConfig {
+    fn default() -> Self {
+        LoadConfig {
+            concurrency: 16,
+            payload_len: 1_024,
+            duration: Duration::from_secs(10),
+        }
+    }
+}
+
+/// The results of a run of [`generate_load`]. Displays as a one-line summary of the throughput
+/// and latency percentiles.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct Report {
+    /// The number of requests that completed successfully.
+    pub requests: u64,
+    /// The number of requests that failed, or whose reply wasn't their payload.
+    pub errors: u64,
+    /// How long the run took, from the first request until the last reply.
+    pub elapsed: Duration,
+    /// The latencies of the successful requests, shortest first.
+    latencies: Vec<Duration>,
+}
+
+impl Report {
+    /// Returns the latency that `percentile` percent of the successful requests completed
+    /// within, e.g. 99.0 for the p99. Returns zero if no request succeeded.
+    pub fn latency(&self, percentile: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::default();
+        }
+        let rank = (percentile / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        self.latencies[rank.max(1).min(self.latencies.len()) - 1]
+    }
+
+    /// Returns the number of successful requests per second.
+    pub fn throughput(&self) -> f64 {
+        self.requests as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} requests ({} errors) in {:?}: {:.0} requests/s, latency p50 {:?}, p90 {:?}, \
+             p99 {:?}, max {:?}",
+            self.requests,
+            self.errors,
+            self.elapsed,
+            self.throughput(),
+            self.latency(50.0),
+            self.latency(90.0),
+            self.latency(99.0),
+            self.latency(100.0),
+        )
+    }
+}
+
+/// Sends echo requests through `client` for `config.duration`, keeping `config.concurrency` of
+/// them in flight, and reports their throughput and latencies.
+pub async fn generate_load(client: EchoClient, config: &LoadConfig) -> Report {
+    let start = Instant::now();
+    let end = start + config.duration;
+    let payload = vec![0xab; config.payload_len];
+    let workers = (0..config.concurrency).map(|_| {
+        let mut client = client.clone();
+        let payload = payload.clone();
+        async move {
+            let mut latencies = vec![];
+            let mut errors = 0;
+            while Instant::now() < end {
+                let sent = Instant::now();
+                match client.echo(context::current(), payload.clone()).await {
+                    Ok(reply) if reply == payload => latencies.push(sent.elapsed()),
+                    _ => errors += 1,
+                }
+            }
+            (latencies, errors)
+        }
+    });
+    let mut latencies = vec![];
+    let mut errors = 0;
+    for (worker_latencies, worker_errors) in future::join_all(workers).await {
+        latencies.extend(worker_latencies);
+        errors += worker_errors;
+    }
+    latencies.sort();
+    Report {
+        requests: latencies.len() as u64,
+        errors,
+        elapsed: start.elapsed(),
+        latencies,
+    }
+}
+
+#[test]
+fn latency_percentiles_are_nearest_ranks() {
+    let report = Report {
+        requests: 4,
+        errors: 0,
+        elapsed: Duration::from_secs(2),
+        latencies: (1..=4).map(Duration::from_millis).collect(),
+    };
+    assert_eq!(report.latency(0.0), Duration::from_millis(1));
+    assert_eq!(report.latency(50.0), Duration::from_millis(2));
+    assert_eq!(report.latency(99.0), Duration::from_millis(4));
+    assert_eq!(report.throughput(), 2.0);
+}
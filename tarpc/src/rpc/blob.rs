@@ -0,0 +1,509 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Helpers for sending files and other large blobs of bytes as streams of chunks.
+//!
+//! Sending a large blob as a single message requires both peers to hold all of it in memory, and
+//! holds up other messages while it's written. Instead, [`read`] turns any [`AsyncRead`] into a
+//! stream of [`BlobChunk`]s, suitable for a streamed reply or for the items of a streaming
+//! request, and [`write`] writes such a stream to any [`AsyncWrite`]. The stream ends with a
+//! checksum of the blob, which [`write`] verifies.
+//!
+//! ```
+//! # use futures::prelude::*;
+//! # use tarpc::blob;
+//! # futures::executor::block_on(async {
+//! let file: &[u8] = b"the contents of a file";
+//! let chunks = blob::read(file, 4, |sent| println!("Sent {} bytes.", sent));
+//! let mut copy = vec![];
+//! let len = blob::write(chunks, &mut copy, |received| println!("Received {} bytes.", received))
+//!     .await?;
+//! assert_eq!(len, 22);
+//! assert_eq!(copy, file);
+//! # Ok::<_, std::io::Error>(())
+//! # });
+//! ```
+//!
+//! The checksum detects corruption and truncation, but is not cryptographically secure.
+//!
+//! Chunks, and any other large binary fields, hold their bytes in a [`Payload`], which is
+//! serialized as bytes rather than as a sequence of numbers, and which is shared rather than
+//! copied when it's cloned or sliced.
+
+use fnv::FnvHasher;
+use futures::{prelude::*, ready, task::*};
+use pin_project::pin_project;
+use std::{
+    fmt,
+    hash::{Hash, Hasher},
+    io,
+    ops::{Bound, Deref, RangeBounds},
+    pin::Pin,
+    sync::Arc,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// An immutable, reference-counted view of a byte buffer. Cloning a payload, or
+/// [slicing](Payload::slice) it, shares the buffer rather than copying it, so that a large reply
+/// can be cached, fanned out to many clients, or split into parts without copying its bytes.
+///
+/// With the `serde1` feature, a payload is serialized as bytes, which binary codecs write and
+/// read in one piece rather than a byte at a time, as they do a `Vec<u8>`. Where the codec hands
+/// over a buffer of its own, as bincode does, the payload takes it without copying.
+#[derive(Clone, Default)]
+pub struct Payload {
+    buf: Arc<Vec<u8>>,
+    start: usize,
+    end: usize,
+}
+
+impl Payload {
+    /// Returns the number of bytes in the payload.
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Returns true if the payload has no bytes.
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// Returns a view of `range` of the payload's bytes, sharing its buffer.
+    ///
+    /// # Panics
+    ///
+    /// If `range` is out of bounds.
+    pub fn slice(&self, range: impl RangeBounds<usize>) -> Payload {
+        let start = match range.start_bound() {
+            Bound::Included(&start) => start,
+            Bound::Excluded(&start) => start + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&end) => end + 1,
+            Bound::Excluded(&end) => end,
+            Bound::Unbounded => self.len(),
+        };
+        assert!(
+            start <= end && end <= self.len(),
+            "Range {}..{} is out of bounds of a payload of {} bytes.",
+            start,
+            end,
+            self.len()
+        );
+        Payload {
+            buf: self.buf.clone(),
+            start: self.start + start,
+            end: self.start + end,
+        }
+    }
+
+    /// Returns the payload's bytes, copying them only if the buffer is shared or the payload is a
+    /// view of part of it.
+    pub fn into_vec(self) -> Vec<u8> {
+        let Payload { buf, start, end } = self;
+        match Arc::try_unwrap(buf) {
+            Ok(mut buf) if start == 0 => {
+                buf.truncate(end);
+                buf
+            }
+            Ok(buf) => buf[start..end].to_vec(),
+            Err(buf) => buf[start..end].to_vec(),
+        }
+    }
+}
+
+impl From<Vec<u8>> for Payload {
+    fn from(buf: Vec<u8>) -> Self {
+        Payload {
+            end: buf.len(),
+            start: 0,
+            buf: Arc::new(buf),
+        }
+    }
+}
+
+impl From<&[u8]> for Payload {
+    fn from(bytes: &[u8]) -> Self {
+        bytes.to_vec().into()
+    }
+}
+
+impl From<Payload> for Vec<u8> {
+    fn from(payload: Payload) -> Self {
+        payload.into_vec()
+    }
+}
+
+impl Deref for Payload {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf[self.start..self.end]
+    }
+}
+
+impl AsRef<[u8]> for Payload {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl PartialEq for Payload {
+    fn eq(&self, other: &Payload) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for Payload {}
+
+impl PartialEq<[u8]> for Payload {
+    fn eq(&self, other: &[u8]) -> bool {
+        **self == *other
+    }
+}
+
+impl Hash for Payload {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl fmt::Debug for Payload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Payload({} bytes)", self.len())
+    }
+}
+
+#[cfg(feature = "serde1")]
+impl serde::Serialize for Payload {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self)
+    }
+}
+
+#[cfg(feature = "serde1")]
+impl<'de> serde::Deserialize<'de> for Payload {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = Payload;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("bytes")
+            }
+
+            fn visit_byte_buf<E>(self, buf: Vec<u8>) -> Result<Payload, E> {
+                Ok(buf.into())
+            }
+
+            fn visit_bytes<E>(self, bytes: &[u8]) -> Result<Payload, E> {
+                Ok(bytes.into())
+            }
+
+            // Self-describing text formats, like JSON, write bytes as a sequence of numbers.
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<Payload, A::Error> {
+                let mut buf = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+                while let Some(byte) = seq.next_element()? {
+                    buf.push(byte);
+                }
+                Ok(buf.into())
+            }
+        }
+
+        // Asking for an owned buffer lets codecs that read one hand it over without a copy.
+        deserializer.deserialize_byte_buf(Visitor)
+    }
+}
+
+/// A piece of a blob.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub enum BlobChunk {
+    /// Bytes of the blob, starting at `offset`.
+    Data {
+        /// The position of the bytes in the blob.
+        offset: u64,
+        /// The bytes.
+        bytes: Payload,
+    },
+    /// The end of the blob.
+    End {
+        /// The length of the blob.
+        len: u64,
+        /// The blob's checksum.
+        checksum: u64,
+    },
+}
+
+/// Checksums the bytes of a blob.
+#[derive(Default)]
+struct Checksum(FnvHasher);
+
+impl Checksum {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.write(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0.finish()
+    }
+}
+
+impl fmt::Debug for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Checksum({:x})", self.finish())
+    }
+}
+
+/// Returns a stream of the chunks of the blob read from `reader`, each holding at most
+/// `chunk_len` bytes. Calls `progress` with the number of bytes read so far after each chunk.
+///
+/// # Panics
+///
+/// If `chunk_len` is zero.
+pub fn read<R, P>(reader: R, chunk_len: usize, progress: P) -> ReadBlob<R, P>
+where
+    R: AsyncRead,
+    P: FnMut(u64),
+{
+    assert!(chunk_len > 0, "Chunks must hold at least one byte.");
+    ReadBlob {
+        reader,
+        chunk_len,
+        progress,
+        checksum: Checksum::default(),
+        offset: 0,
+        done: false,
+    }
+}
+
+/// Writes the blob whose chunks are yielded by `chunks` to `writer`, resolving to the blob's
+/// length. Calls `progress` with the number of bytes written so far after each chunk.
+///
+/// Fails with [`io::ErrorKind::InvalidData`] if the chunks are out of order or the blob doesn't
+/// match its checksum, and with [`io::ErrorKind::UnexpectedEof`] if the chunks end before the
+/// blob does.
+pub fn write<St, W, P>(chunks: St, writer: W, progress: P) -> WriteBlob<St, W, P>
+where
+    St: Stream<Item = io::Result<BlobChunk>>,
+    W: AsyncWrite,
+    P: FnMut(u64),
+{
+    WriteBlob {
+        chunks,
+        writer,
+        progress,
+        checksum: Checksum::default(),
+        offset: 0,
+        pending: None,
+        len: None,
+    }
+}
+
+/// A stream of the chunks of a blob. Created by [`read`].
+#[pin_project]
+#[derive(Debug)]
+pub struct ReadBlob<R, P> {
+    #[pin]
+    reader: R,
+    chunk_len: usize,
+    progress: P,
+    checksum: Checksum,
+    offset: u64,
+    done: bool,
+}
+
+impl<R, P> Stream for ReadBlob<R, P>
+where
+    R: AsyncRead,
+    P: FnMut(u64),
+{
+    type Item = io::Result<BlobChunk>;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<io::Result<BlobChunk>>> {
+        let this = self.project();
+        if *this.done {
+            return Poll::Ready(None);
+        }
+        let mut bytes = vec![0; *this.chunk_len];
+        let read = ready!(this.reader.poll_read(cx, &mut bytes)?);
+        if read == 0 {
+            *this.done = true;
+            return Poll::Ready(Some(Ok(BlobChunk::End {
+                len: *this.offset,
+                checksum: this.checksum.finish(),
+            })));
+        }
+        bytes.truncate(read);
+        this.checksum.write(&bytes);
+        let offset = *this.offset;
+        *this.offset += read as u64;
+        (this.progress)(*this.offset);
+        Poll::Ready(Some(Ok(BlobChunk::Data {
+            offset,
+            bytes: bytes.into(),
+        })))
+    }
+}
+
+/// A future that writes a blob. Created by [`write`].
+#[pin_project]
+#[derive(Debug)]
+pub struct WriteBlob<St, W, P> {
+    #[pin]
+    chunks: St,
+    #[pin]
+    writer: W,
+    progress: P,
+    checksum: Checksum,
+    /// The number of bytes written so far.
+    offset: u64,
+    /// The bytes of the current chunk that haven't yet been written.
+    pending: Option<(Payload, usize)>,
+    /// The length of the blob, once its end is verified.
+    len: Option<u64>,
+}
+
+impl<St, W, P> Future for WriteBlob<St, W, P>
+where
+    St: Stream<Item = io::Result<BlobChunk>>,
+    W: AsyncWrite,
+    P: FnMut(u64),
+{
+    type Output = io::Result<u64>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let mut this = self.project();
+        loop {
+            if let Some((bytes, written)) = this.pending {
+                while *written < bytes.len() {
+                    let n = ready!(this.writer.as_mut().poll_write(cx, &bytes[*written..])?);
+                    if n == 0 {
+                        return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                    }
+                    *written += n;
+                }
+                *this.offset += bytes.len() as u64;
+                (this.progress)(*this.offset);
+                *this.pending = None;
+            }
+            if let Some(len) = *this.len {
+                ready!(this.writer.as_mut().poll_flush(cx)?);
+                return Poll::Ready(Ok(len));
+            }
+            match ready!(this.chunks.as_mut().poll_next(cx)?) {
+                Some(BlobChunk::Data { offset, bytes }) => {
+                    if offset != *this.offset {
+                        return Poll::Ready(Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!(
+                                "Expected a chunk at {}, but got one at {}.",
+                                this.offset, offset
+                            ),
+                        )));
+                    }
+                    this.checksum.write(&bytes);
+                    *this.pending = Some((bytes, 0));
+                }
+                Some(BlobChunk::End { len, checksum }) => {
+                    if len != *this.offset || checksum != this.checksum.finish() {
+                        return Poll::Ready(Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "The blob doesn't match its checksum.",
+                        )));
+                    }
+                    *this.len = Some(len);
+                }
+                None => return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into())),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+use assert_matches::assert_matches;
+
+#[cfg(test)]
+async fn copy(chunks: Vec<BlobChunk>) -> io::Result<Vec<u8>> {
+    let mut copy = vec![];
+    write(stream::iter(chunks).map(Ok), &mut copy, |_| {}).await?;
+    Ok(copy)
+}
+
+#[tokio::test]
+async fn write_verifies_chunks() {
+    let blob: &[u8] = b"some bytes";
+    let chunks: Vec<_> = read(blob, 3, |_| {}).try_collect().await.unwrap();
+    assert_eq!(chunks.len(), 5);
+    assert_eq!(copy(chunks.clone()).await.unwrap(), blob);
+
+    let mut corrupted = chunks.clone();
+    if let BlobChunk::Data { ref mut bytes, .. } = corrupted[1] {
+        let mut flipped = bytes.to_vec();
+        flipped[0] ^= 1;
+        *bytes = flipped.into();
+    }
+    assert_matches!(copy(corrupted).await, Err(e) if e.kind() == io::ErrorKind::InvalidData);
+
+    let mut reordered = chunks.clone();
+    reordered.swap(0, 1);
+    assert_matches!(copy(reordered).await, Err(e) if e.kind() == io::ErrorKind::InvalidData);
+
+    let truncated = chunks[..4].to_vec();
+    assert_matches!(copy(truncated).await, Err(e) if e.kind() == io::ErrorKind::UnexpectedEof);
+}
+
+#[tokio::test]
+async fn progress_counts_bytes() {
+    let blob: &[u8] = b"some bytes";
+    let mut sent = vec![];
+    let chunks: Vec<_> = read(blob, 4, |n| sent.push(n)).try_collect().await.unwrap();
+    assert_eq!(sent, vec![4, 8, 10]);
+
+    let mut received = vec![];
+    let mut copy = vec![];
+    write(stream::iter(chunks).map(Ok), &mut copy, |n| {
+        received.push(n)
+    })
+    .await
+    .unwrap();
+    assert_eq!(received, vec![4, 8, 10]);
+}
+
+#[test]
+fn payloads_share_their_buffer() {
+    let payload = Payload::from(b"some bytes".to_vec());
+    let bytes = payload.slice(5..);
+    assert_eq!(&*bytes, b"bytes");
+    assert_eq!(bytes.slice(..=1), *b"by".as_ref());
+    assert_eq!(bytes.as_ptr(), payload[5..].as_ptr());
+
+    // The buffer is copied only while it's shared.
+    let ptr = payload.as_ptr();
+    let copied = payload.clone().into_vec();
+    assert_ne!(copied.as_ptr(), ptr);
+    drop(bytes);
+    let unwrapped = payload.into_vec();
+    assert_eq!(unwrapped.as_ptr(), ptr);
+    assert_eq!(unwrapped, copied);
+}
+
+#[cfg(feature = "serde1")]
+#[test]
+fn payloads_serialize_as_bytes() {
+    let payload = Payload::from(b"some bytes".to_vec()).slice(5..);
+    let json = serde_json::to_string(&payload).unwrap();
+    assert_eq!(json, "[98,121,116,101,115]");
+    assert_eq!(serde_json::from_str::<Payload>(&json).unwrap(), payload);
+}
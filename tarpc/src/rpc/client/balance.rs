@@ -0,0 +1,479 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! A client of a horizontally scaled service that spreads requests across the service's
+//! endpoints.
+
+use super::{channel, failover::Backoff, Balance, Channel, Config, HealthChecks};
+use crate::{context, ClientMessage, ServerMessage, Transport};
+use futures::{channel::mpsc, prelude::*};
+use log::{debug, info};
+use rand::Rng;
+use std::{
+    cmp, io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+};
+
+/// A client connected to every endpoint serving the same service, which sends each request to one
+/// of them as chosen by the [`Balance`] of its [`Config`].
+///
+/// Each endpoint's connection is maintained in the background: if it can't be established or
+/// dies, it's retried with a [`Backoff`], and requests go to the other endpoints meanwhile. If the
+/// config has [`HealthChecks`], each endpoint is also checked periodically, and requests skip the
+/// endpoints evicted for failing their checks. Requests also avoid endpoints whose servers are
+/// draining, unless there's nowhere else to send them. Clones share the same connections.
+#[derive(Debug)]
+pub struct Balancer<Req, Resp> {
+    balance: Balance,
+    endpoints: Arc<Vec<Arc<Endpoint<Req, Resp>>>>,
+    /// The index of the endpoint whose turn it is.
+    next_turn: Arc<AtomicUsize>,
+}
+
+impl<Req, Resp> Clone for Balancer<Req, Resp> {
+    fn clone(&self) -> Self {
+        Balancer {
+            balance: self.balance,
+            endpoints: self.endpoints.clone(),
+            next_turn: self.next_turn.clone(),
+        }
+    }
+}
+
+/// An endpoint and the state of the connection to it.
+#[derive(Debug)]
+pub(super) struct Endpoint<Req, Resp> {
+    pub(super) addr: SocketAddr,
+    /// The channel to the endpoint, while connected.
+    pub(super) channel: Mutex<Option<Channel<Req, Resp>>>,
+    /// The number of requests sent to the endpoint that haven't completed.
+    outstanding: AtomicUsize,
+    /// Set while the endpoint is evicted for failing its health checks.
+    evicted: AtomicBool,
+    /// Told the next time the endpoint connects.
+    on_connect: Mutex<Vec<mpsc::UnboundedSender<()>>>,
+}
+
+impl<Req, Resp> Endpoint<Req, Resp> {
+    /// Returns a disconnected endpoint at `addr`.
+    pub(super) fn new(addr: SocketAddr) -> Self {
+        Endpoint {
+            addr,
+            channel: Mutex::new(None),
+            outstanding: AtomicUsize::new(0),
+            evicted: AtomicBool::new(false),
+            on_connect: Mutex::new(vec![]),
+        }
+    }
+}
+
+impl<Req, Resp> Balancer<Req, Resp>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+{
+    /// Returns a client of `endpoints`, spawning a task per endpoint that connects to it with
+    /// `connect` and reconnects whenever the connection dies.
+    ///
+    /// # Panics
+    ///
+    /// If `endpoints` is empty.
+    pub fn new<F, Fut, T>(
+        config: Config,
+        endpoints: Vec<SocketAddr>,
+        backoff: Backoff,
+        connect: F,
+    ) -> Self
+    where
+        F: Fn(SocketAddr) -> Fut + Clone + Send + 'static,
+        Fut: Future<Output = io::Result<T>> + Send + 'static,
+        T: Transport<ClientMessage<Req>, ServerMessage<Resp>> + Send + 'static,
+    {
+        assert!(
+            !endpoints.is_empty(),
+            "There must be at least one endpoint."
+        );
+        let endpoints: Vec<_> = endpoints
+            .into_iter()
+            .map(|addr| Arc::new(Endpoint::new(addr)))
+            .collect();
+        for endpoint in &endpoints {
+            tokio::spawn(maintain(
+                Arc::downgrade(endpoint),
+                config.clone(),
+                backoff,
+                connect.clone(),
+            ));
+            if let Some(checks) = config.health_checks {
+                tokio::spawn(check_health(Arc::downgrade(endpoint), checks));
+            }
+        }
+        Balancer {
+            balance: config.balance,
+            endpoints: Arc::new(endpoints),
+            next_turn: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Returns the endpoints the client is connected to.
+    pub fn connected(&self) -> Vec<SocketAddr> {
+        self.endpoints
+            .iter()
+            .filter(|endpoint| endpoint.channel.lock().unwrap().is_some())
+            .map(|endpoint| endpoint.addr)
+            .collect()
+    }
+
+    /// Resolves once the client is connected to `endpoints` endpoints, or to all of them if there
+    /// are fewer. Awaiting it at startup, e.g. with a timeout, warms the client up, so that the
+    /// first requests don't wait on connections to be established or fail for want of them.
+    pub async fn ready(&self, endpoints: usize) {
+        let endpoints = cmp::min(endpoints, self.endpoints.len());
+        let (tx, mut connects) = mpsc::unbounded();
+        for endpoint in self.endpoints.iter() {
+            let mut on_connect = endpoint.on_connect.lock().unwrap();
+            // Forget the watchers of readiness futures that were dropped.
+            on_connect.retain(|watcher| !watcher.is_closed());
+            on_connect.push(tx.clone());
+        }
+        // The watchers are registered before counting, so no connection goes unnoticed.
+        while self.connected().len() < endpoints {
+            connects.next().await;
+        }
+    }
+
+    /// Returns the connected endpoints whose servers asked the client to go away because they're
+    /// draining.
+    pub fn going_away(&self) -> Vec<SocketAddr> {
+        self.endpoints
+            .iter()
+            .filter(|endpoint| match *endpoint.channel.lock().unwrap() {
+                Some(ref channel) => channel.is_going_away(),
+                None => false,
+            })
+            .map(|endpoint| endpoint.addr)
+            .collect()
+    }
+
+    /// Returns the endpoints evicted for failing their health checks.
+    pub fn evicted(&self) -> Vec<SocketAddr> {
+        self.endpoints
+            .iter()
+            .filter(|endpoint| endpoint.evicted.load(Ordering::Relaxed))
+            .map(|endpoint| endpoint.addr)
+            .collect()
+    }
+
+    /// Sends `request` to one of the connected endpoints. Fails with
+    /// [`io::ErrorKind::NotConnected`] if the client isn't connected to any.
+    pub async fn call(&self, ctx: context::Context, request: Req) -> io::Result<Resp> {
+        let (index, mut channel) = self.choose().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Not connected to any endpoint.",
+            )
+        })?;
+        let endpoint = &self.endpoints[index];
+        endpoint.outstanding.fetch_add(1, Ordering::Relaxed);
+        let _outstanding = Outstanding(&endpoint.outstanding);
+        channel.call(ctx, request).await
+    }
+
+    /// Chooses the endpoint of the next request from those connected and not evicted.
+    ///
+    /// Endpoints whose servers asked the client to go away are chosen only if no other endpoint
+    /// is available.
+    fn choose(&self) -> Option<(usize, Channel<Req, Resp>)> {
+        let start = self.next_turn.fetch_add(1, Ordering::Relaxed) % self.endpoints.len();
+        self.choose_from(start, false)
+            .or_else(|| self.choose_from(start, true))
+    }
+
+    /// Chooses the endpoint of the next request, starting the turn at endpoint `start`.
+    fn choose_from(&self, start: usize, going_away: bool) -> Option<(usize, Channel<Req, Resp>)> {
+        let len = self.endpoints.len();
+        let mut chosen: Option<(usize, Channel<Req, Resp>, u64)> = None;
+        // The sum of the weights of the endpoints considered so far, when weighted.
+        let mut total_weight = 0.0;
+        for index in (start..len).chain(0..start) {
+            let endpoint = &self.endpoints[index];
+            if endpoint.evicted.load(Ordering::Relaxed) {
+                continue;
+            }
+            let channel = match *endpoint.channel.lock().unwrap() {
+                Some(ref channel) if going_away || !channel.is_going_away() => channel.clone(),
+                _ => continue,
+            };
+            let load = u64::from(channel.server_load().unwrap_or(0));
+            let cost = match self.balance {
+                Balance::RoundRobin => return Some((index, channel)),
+                Balance::LeastOutstanding => endpoint.outstanding.load(Ordering::Relaxed) as u64,
+                Balance::LeastLoaded => load,
+                Balance::Weighted => {
+                    // Keeps each endpoint with probability of its share of the weight so far,
+                    // which chooses each with probability of its share of the total.
+                    let weight = 1.0 / (1.0 + load as f64);
+                    total_weight += weight;
+                    if rand::thread_rng().gen::<f64>() * total_weight < weight {
+                        chosen = Some((index, channel, load));
+                    }
+                    continue;
+                }
+            };
+            let cheaper = match chosen {
+                Some((_, _, least)) => cost < least,
+                None => true,
+            };
+            if cheaper {
+                chosen = Some((index, channel, cost));
+            }
+        }
+        chosen.map(|(index, channel, _)| (index, channel))
+    }
+}
+
+/// Decrements an endpoint's count of outstanding requests when the request completes or is
+/// dropped.
+struct Outstanding<'a>(&'a AtomicUsize);
+
+impl Drop for Outstanding<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Keeps the connection to `endpoint` up until the endpoint is dropped.
+pub(super) async fn maintain<Req, Resp, F, Fut, T>(
+    endpoint: Weak<Endpoint<Req, Resp>>,
+    config: Config,
+    backoff: Backoff,
+    connect: F,
+) where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+    T: Transport<ClientMessage<Req>, ServerMessage<Resp>>,
+{
+    let mut delay = backoff.initial;
+    loop {
+        let addr = match endpoint.upgrade() {
+            Some(endpoint) => endpoint.addr,
+            None => return,
+        };
+        let transport = match connect(addr).await {
+            Ok(transport) => transport,
+            Err(e) => {
+                debug!("Failed to connect to {}: {}", addr, e);
+                tokio::time::delay_for(delay).await;
+                delay = cmp::min(delay * 2, backoff.max);
+                continue;
+            }
+        };
+        info!("Connected to {}.", addr);
+        delay = backoff.initial;
+        let client = channel::new(config.clone(), transport);
+        match endpoint.upgrade() {
+            Some(endpoint) => {
+                *endpoint.channel.lock().unwrap() = Some(client.client);
+                for watcher in endpoint.on_connect.lock().unwrap().drain(..) {
+                    let _ = watcher.unbounded_send(());
+                }
+            }
+            None => return,
+        }
+        // Dispatch ends once the connection dies, or once the endpoint is dropped along with
+        // every clone of the channel.
+        if let Err(e) = client.dispatch.await {
+            info!("Connection to {} broken: {}", addr, e);
+        }
+        if let Some(endpoint) = endpoint.upgrade() {
+            *endpoint.channel.lock().unwrap() = None;
+        }
+    }
+}
+
+/// Checks the health of `endpoint` until it's dropped, evicting it from the rotation while it's
+/// unhealthy. Checks are skipped while the endpoint is disconnected.
+async fn check_health<Req, Resp>(endpoint: Weak<Endpoint<Req, Resp>>, checks: HealthChecks) {
+    let (mut passed, mut failed) = (0, 0);
+    loop {
+        tokio::time::delay_for(checks.interval).await;
+        let channel = match endpoint.upgrade() {
+            Some(endpoint) => endpoint.channel.lock().unwrap().clone(),
+            None => return,
+        };
+        let serving = match channel {
+            Some(channel) => {
+                match tokio::time::timeout(checks.timeout, channel.check_health()).await {
+                    Ok(Ok(serving)) => serving,
+                    _ => false,
+                }
+            }
+            None => continue,
+        };
+        let endpoint = match endpoint.upgrade() {
+            Some(endpoint) => endpoint,
+            None => return,
+        };
+        if serving {
+            failed = 0;
+            passed += 1;
+            if passed >= checks.healthy_threshold && endpoint.evicted.swap(false, Ordering::Relaxed)
+            {
+                info!(
+                    "{} passed {} health checks; restoring it.",
+                    endpoint.addr, passed
+                );
+            }
+        } else {
+            passed = 0;
+            failed += 1;
+            if failed >= checks.unhealthy_threshold
+                && !endpoint.evicted.swap(true, Ordering::Relaxed)
+            {
+                info!(
+                    "{} failed {} health checks; evicting it.",
+                    endpoint.addr, failed
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn balancer(balance: Balance, outstanding: &[usize]) -> Balancer<(), ()> {
+    let loads: Vec<_> = outstanding.iter().map(|_| None).collect();
+    loaded_balancer(balance, outstanding, &loads)
+}
+
+/// Returns a balancer of endpoints that have reported the given loads.
+#[cfg(test)]
+fn loaded_balancer(
+    balance: Balance,
+    outstanding: &[usize],
+    loads: &[Option<u32>],
+) -> Balancer<(), ()> {
+    let reports = loads
+        .iter()
+        .map(|load| load.map(ServerMessage::Load).into_iter().collect())
+        .collect();
+    reporting_balancer(balance, outstanding, reports)
+}
+
+/// Returns a balancer of endpoints whose servers have sent the given messages.
+#[cfg(test)]
+fn reporting_balancer(
+    balance: Balance,
+    outstanding: &[usize],
+    reports: Vec<Vec<ServerMessage<()>>>,
+) -> Balancer<(), ()> {
+    use crate::transport::channel::unbounded;
+
+    let endpoints = outstanding
+        .iter()
+        .zip(reports)
+        .enumerate()
+        .map(|(port, (&outstanding, reports))| {
+            let (transport, mut server) = unbounded();
+            let mut client = channel::new(Config::default(), transport);
+            for report in reports {
+                server.start_send_unpin(report).unwrap();
+            }
+            // Reading the reports is all there is for dispatch to do.
+            let _ = (&mut client.dispatch).now_or_never();
+            Arc::new(Endpoint {
+                addr: SocketAddr::from(([127, 0, 0, 1], port as u16)),
+                channel: Mutex::new(Some(client.client)),
+                outstanding: AtomicUsize::new(outstanding),
+                evicted: AtomicBool::new(false),
+                on_connect: Mutex::new(vec![]),
+            })
+        })
+        .collect();
+    Balancer {
+        balance,
+        endpoints: Arc::new(endpoints),
+        next_turn: Arc::new(AtomicUsize::new(0)),
+    }
+}
+
+#[test]
+fn round_robin_takes_turns() {
+    let balancer = balancer(Balance::RoundRobin, &[5, 0, 0]);
+    *balancer.endpoints[1].channel.lock().unwrap() = None;
+    let chosen: Vec<_> = (0..4).map(|_| balancer.choose().unwrap().0).collect();
+    // Endpoint 1 is disconnected, so its turn goes to the next endpoint.
+    assert_eq!(chosen, [0, 2, 2, 0]);
+}
+
+#[test]
+fn evicted_endpoints_are_skipped() {
+    let balancer = balancer(Balance::LeastOutstanding, &[0, 1, 2]);
+    balancer.endpoints[0].evicted.store(true, Ordering::Relaxed);
+    assert_eq!(balancer.evicted(), [SocketAddr::from(([127, 0, 0, 1], 0))]);
+    let chosen: Vec<_> = (0..3).map(|_| balancer.choose().unwrap().0).collect();
+    assert_eq!(chosen, [1, 1, 1]);
+}
+
+#[test]
+fn endpoints_going_away_are_avoided() {
+    let balancer = reporting_balancer(
+        Balance::RoundRobin,
+        &[0, 0, 0],
+        vec![vec![], vec![ServerMessage::GoAway], vec![]],
+    );
+    let chosen: Vec<_> = (0..4).map(|_| balancer.choose().unwrap().0).collect();
+    assert_eq!(chosen, [0, 2, 2, 0]);
+
+    // With nowhere else to go, requests still go to an endpoint that's going away.
+    *balancer.endpoints[0].channel.lock().unwrap() = None;
+    *balancer.endpoints[2].channel.lock().unwrap() = None;
+    assert_eq!(balancer.choose().unwrap().0, 1);
+}
+
+#[test]
+fn least_outstanding_prefers_idle_endpoints() {
+    let balancer = balancer(Balance::LeastOutstanding, &[2, 1, 1]);
+    let chosen: Vec<_> = (0..3).map(|_| balancer.choose().unwrap().0).collect();
+    // Ties go to the endpoint whose turn it is.
+    assert_eq!(chosen, [1, 1, 2]);
+}
+
+#[test]
+fn least_loaded_prefers_unloaded_endpoints() {
+    let balancer = loaded_balancer(
+        Balance::LeastLoaded,
+        &[0, 0, 0, 0],
+        &[Some(3), Some(1), Some(5), Some(1)],
+    );
+    let chosen: Vec<_> = (0..4).map(|_| balancer.choose().unwrap().0).collect();
+    // Ties go to the endpoint whose turn it is.
+    assert_eq!(chosen, [1, 1, 3, 3]);
+
+    // An endpoint that hasn't reported a load counts as idle.
+    let balancer = loaded_balancer(Balance::LeastLoaded, &[0, 0], &[Some(1), None]);
+    assert_eq!(balancer.choose().unwrap().0, 1);
+}
+
+#[test]
+fn weighted_favors_lightly_loaded_endpoints() {
+    let balancer = loaded_balancer(
+        Balance::Weighted,
+        &[0, 0, 0],
+        &[Some(1_000_000_000), Some(0), Some(1_000_000_000)],
+    );
+    *balancer.endpoints[1].channel.lock().unwrap() = None;
+    // Endpoint 1 is disconnected, leaving two equally loaded endpoints.
+    let chosen: Vec<_> = (0..100).map(|_| balancer.choose().unwrap().0).collect();
+    assert!(chosen.contains(&0) && chosen.contains(&2));
+
+    let balancer = loaded_balancer(Balance::Weighted, &[0, 0], &[Some(0), Some(1_000_000_000)]);
+    let chosen: Vec<_> = (0..100).map(|_| balancer.choose().unwrap().0).collect();
+    assert!(chosen.iter().all(|&index| index == 0));
+}
@@ -0,0 +1,491 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! A client that caches the responses to read-mostly requests, so that repeating a request
+//! doesn't go over the network until its cached response expires.
+//!
+//! Methods opt in with `#[tarpc(cache = "30s")]`, which caches their responses for the given
+//! time, keyed by their args:
+//!
+//! ```
+//! # use futures::future::{self, Ready};
+//! # use tarpc::{client::{self, cache::Cache}, context, server::{BaseChannel, Channel}, transport};
+//! # use std::io;
+//! #[tarpc::service]
+//! trait Catalog {
+//!     #[tarpc(cache = "30s")]
+//!     async fn price(item: String) -> u64;
+//! }
+//! # #[derive(Clone)]
+//! # struct Server;
+//! # impl Catalog for Server {
+//! #     type PriceFut = Ready<u64>;
+//! #     fn price(self, _: context::Context, _: String) -> Self::PriceFut {
+//! #         future::ready(3)
+//! #     }
+//! # }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> io::Result<()> {
+//! # let (client_transport, server_transport) = transport::channel::unbounded();
+//! # tokio::spawn(BaseChannel::with_defaults(server_transport).respond_with(Server.serve()).execute());
+//! let channel = client::new(client::Config::default(), client_transport).spawn()?;
+//! let mut client = CatalogClient::from(Cache::new(channel, 1_000));
+//! assert_eq!(client.price(context::current(), "tea".into()).await?, 3);
+//! // Served from the cache.
+//! assert_eq!(client.price(context::current(), "tea".into()).await?, 3);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! While a cacheable request is in flight, identical requests sent through clones of the cache
+//! wait for its response instead of going over the network, so that a burst of callers missing
+//! the cache at once sends the server a single request.
+
+use super::{Client, StreamClient};
+use crate::context;
+use fnv::FnvHashMap;
+use futures::{channel::oneshot, prelude::*, ready, task::*};
+use pin_project::pin_project;
+use std::{
+    fmt,
+    hash::Hash,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// A request whose responses may be cached.
+///
+/// Implemented for the request types generated by [`service`](crate::service), whose methods
+/// declared with `#[tarpc(cache = "..")]` are cacheable.
+pub trait Cacheable {
+    /// Identifies requests that have the same response, e.g. the method and its args.
+    type Key: Hash + Eq + Clone;
+
+    /// The type of responses to the request.
+    type Response;
+
+    /// Returns the key of the request and how long its response may be cached, or `None` if it
+    /// isn't cacheable.
+    fn cache_key(&self) -> Option<(Self::Key, Duration)>;
+
+    /// Clones a response to a cacheable request.
+    fn clone_response(response: &Self::Response) -> Self::Response;
+}
+
+/// A client that caches the successful responses to [`Cacheable`] requests until they expire.
+///
+/// Clones of a cache share its responses and coalesce identical requests that are in flight at
+/// once. When full, the cache makes room by evicting expired responses and, if there are none, the
+/// response closest to expiring.
+pub struct Cache<C, K, Resp> {
+    inner: C,
+    entries: Arc<Mutex<Entries<K, Resp>>>,
+}
+
+struct Entries<K, Resp> {
+    capacity: usize,
+    responses: FnvHashMap<K, (Resp, Instant)>,
+    /// The callers waiting on each cacheable request in flight.
+    in_flight: FnvHashMap<K, Vec<oneshot::Sender<io::Result<Resp>>>>,
+}
+
+impl<C, K, Resp> Cache<C, K, Resp>
+where
+    K: Hash + Eq,
+{
+    /// Returns a client that sends requests with `inner`, caching up to `capacity` responses.
+    pub fn new(inner: C, capacity: usize) -> Self {
+        Cache {
+            inner,
+            entries: Arc::new(Mutex::new(Entries {
+                capacity,
+                responses: FnvHashMap::default(),
+                in_flight: FnvHashMap::default(),
+            })),
+        }
+    }
+
+    /// Returns the number of responses cached, some of which may have expired.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().responses.len()
+    }
+
+    /// Returns true if no responses are cached.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Discards every cached response.
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().responses.clear();
+    }
+
+    /// Returns the inner client.
+    pub fn get_ref(&self) -> &C {
+        &self.inner
+    }
+}
+
+impl<K, Resp> Entries<K, Resp>
+where
+    K: Hash + Eq + Clone,
+{
+    fn get(&mut self, key: &K) -> Option<&Resp> {
+        let (_, expires) = self.responses.get(key)?;
+        if *expires <= Instant::now() {
+            self.responses.remove(key);
+            return None;
+        }
+        self.responses.get(key).map(|(response, _)| response)
+    }
+
+    fn insert(&mut self, key: K, response: Resp, ttl: Duration) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.responses.len() >= self.capacity && !self.responses.contains_key(&key) {
+            let now = Instant::now();
+            self.responses.retain(|_, (_, expires)| *expires > now);
+        }
+        if self.responses.len() >= self.capacity && !self.responses.contains_key(&key) {
+            let soonest = self
+                .responses
+                .iter()
+                .min_by_key(|(_, (_, expires))| *expires)
+                .map(|(key, _)| key.clone());
+            if let Some(soonest) = soonest {
+                self.responses.remove(&soonest);
+            }
+        }
+        self.responses.insert(key, (response, Instant::now() + ttl));
+    }
+}
+
+impl<C, K, Resp> Clone for Cache<C, K, Resp>
+where
+    C: Clone,
+{
+    fn clone(&self) -> Self {
+        Cache {
+            inner: self.inner.clone(),
+            entries: self.entries.clone(),
+        }
+    }
+}
+
+impl<C, K, Resp> fmt::Debug for Cache<C, K, Resp>
+where
+    C: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Cache")
+            .field("inner", &self.inner)
+            .field("len", &self.entries.lock().unwrap().responses.len())
+            .finish()
+    }
+}
+
+impl<'a, C, Req> Client<'a, Req> for Cache<C, Req::Key, Req::Response>
+where
+    C: Client<'a, Req, Response = Req::Response>,
+    Req: Cacheable,
+    Req::Key: 'a,
+    Req::Response: 'a,
+{
+    type Response = Req::Response;
+    type Future = CacheCall<C::Future, Req::Key, Req::Response>;
+
+    fn call(&'a mut self, ctx: context::Context, request: Req) -> Self::Future {
+        let key = request.cache_key();
+        if let Some((ref key, _)) = key {
+            let mut entries = self.entries.lock().unwrap();
+            if let Some(response) = entries.get(key) {
+                return CacheCall {
+                    state: CallState::Hit(Some(Req::clone_response(response))),
+                };
+            }
+            if let Some(waiters) = entries.in_flight.get_mut(key) {
+                let (tx, rx) = oneshot::channel();
+                waiters.push(tx);
+                return CacheCall {
+                    state: CallState::Waiting(rx),
+                };
+            }
+            entries.in_flight.insert(key.clone(), vec![]);
+        }
+        let flight = key.map(|(key, ttl)| Flight {
+            key,
+            ttl,
+            entries: self.entries.clone(),
+            clone_response: Req::clone_response,
+            landed: false,
+        });
+        CacheCall {
+            state: CallState::Miss {
+                call: self.inner.call(ctx, request),
+                flight,
+            },
+        }
+    }
+
+    fn permit_retry(&self) -> bool {
+        self.inner.permit_retry()
+    }
+}
+
+/// Streamed replies aren't cached.
+impl<'a, C, K, Req, Resp> StreamClient<'a, Req> for Cache<C, K, Resp>
+where
+    C: StreamClient<'a, Req>,
+{
+    type Response = C::Response;
+    type Stream = C::Stream;
+    type Future = C::Future;
+
+    fn call_stream(&'a mut self, ctx: context::Context, request: Req) -> Self::Future {
+        self.inner.call_stream(ctx, request)
+    }
+}
+
+/// The response to a request sent through a [`Cache`].
+#[pin_project]
+#[must_use = "futures do nothing unless polled"]
+pub struct CacheCall<F, K, Resp>
+where
+    K: Hash + Eq,
+{
+    #[pin]
+    state: CallState<F, K, Resp>,
+}
+
+#[pin_project(project = CallStateProj)]
+enum CallState<F, K: Hash + Eq, Resp> {
+    /// The response was cached.
+    Hit(Option<Resp>),
+    /// An identical request was in flight, whose response will be shared.
+    Waiting(#[pin] oneshot::Receiver<io::Result<Resp>>),
+    /// The request was sent to the server.
+    Miss {
+        #[pin]
+        call: F,
+        /// Set if the request is cacheable.
+        flight: Option<Flight<K, Resp>>,
+    },
+}
+
+/// A cacheable request in flight, which shares its response with the identical requests made
+/// meanwhile. If it's dropped before the response arrives, those requests fail.
+struct Flight<K: Hash + Eq, Resp> {
+    key: K,
+    ttl: Duration,
+    entries: Arc<Mutex<Entries<K, Resp>>>,
+    clone_response: fn(&Resp) -> Resp,
+    landed: bool,
+}
+
+impl<K: Hash + Eq + Clone, Resp> Flight<K, Resp> {
+    /// Caches a successful response and shares the result with the waiting requests.
+    fn land(mut self, result: &io::Result<Resp>) {
+        self.landed = true;
+        let clone_response = self.clone_response;
+        let waiters = {
+            let mut entries = self.entries.lock().unwrap();
+            if let Ok(response) = result {
+                entries.insert(self.key.clone(), clone_response(response), self.ttl);
+            }
+            entries.in_flight.remove(&self.key).unwrap_or_default()
+        };
+        for waiter in waiters {
+            let _ = waiter.send(match result {
+                Ok(response) => Ok(clone_response(response)),
+                Err(e) => Err(share_error(e)),
+            });
+        }
+    }
+}
+
+impl<K: Hash + Eq, Resp> Drop for Flight<K, Resp> {
+    fn drop(&mut self) {
+        if !self.landed {
+            // Dropping the waiters' senders fails their requests.
+            if let Ok(mut entries) = self.entries.lock() {
+                entries.in_flight.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// Copies an error for each waiting request, preserving any [`ServerError`](crate::ServerError).
+fn share_error(e: &io::Error) -> io::Error {
+    match e
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<crate::ServerError>())
+    {
+        Some(e) => e.clone().into(),
+        None => io::Error::new(e.kind(), e.to_string()),
+    }
+}
+
+impl<F, K, Resp> fmt::Debug for CacheCall<F, K, Resp>
+where
+    K: Hash + Eq,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = match self.state {
+            CallState::Hit(_) => "Hit",
+            CallState::Waiting(_) => "Waiting",
+            CallState::Miss { .. } => "Miss",
+        };
+        f.debug_struct("CacheCall").field("state", &state).finish()
+    }
+}
+
+impl<F, K, Resp> Future for CacheCall<F, K, Resp>
+where
+    F: Future<Output = io::Result<Resp>>,
+    K: Hash + Eq + Clone,
+{
+    type Output = io::Result<Resp>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<Resp>> {
+        match self.project().state.project() {
+            CallStateProj::Hit(response) => {
+                Poll::Ready(Ok(response.take().expect("polled after completion")))
+            }
+            CallStateProj::Waiting(response) => match ready!(response.poll(cx)) {
+                Ok(result) => Poll::Ready(result),
+                Err(oneshot::Canceled) => Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::Interrupted,
+                    "The identical request in flight was canceled.",
+                ))),
+            },
+            CallStateProj::Miss { call, flight } => {
+                let result = ready!(call.poll(cx));
+                if let Some(flight) = flight.take() {
+                    flight.land(&result);
+                }
+                Poll::Ready(result)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cache, Cacheable};
+    use crate::{client::Client, context};
+    use futures::{
+        future::{self, ready, BoxFuture, Ready},
+        prelude::*,
+    };
+    use std::{
+        io,
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    /// Looks up `.0`, caching for `.1` milliseconds.
+    struct Lookup(u32, Option<u64>);
+
+    impl Cacheable for Lookup {
+        type Key = u32;
+        type Response = u32;
+
+        fn cache_key(&self) -> Option<(u32, Duration)> {
+            self.1.map(|ttl| (self.0, Duration::from_millis(ttl)))
+        }
+
+        fn clone_response(response: &u32) -> u32 {
+            *response
+        }
+    }
+
+    /// Answers with the number of calls so far.
+    #[derive(Default)]
+    struct Counter(u32);
+
+    impl<'a> Client<'a, Lookup> for Counter {
+        type Response = u32;
+        type Future = Ready<io::Result<u32>>;
+
+        fn call(&'a mut self, _: context::Context, _: Lookup) -> Self::Future {
+            self.0 += 1;
+            ready(Ok(self.0))
+        }
+    }
+
+    #[tokio::test]
+    async fn responses_are_cached_until_they_expire() -> io::Result<()> {
+        let mut cache = Cache::new(Counter::default(), 2);
+        let ctx = context::current;
+        assert_eq!(cache.call(ctx(), Lookup(1, Some(60_000))).await?, 1);
+        assert_eq!(cache.call(ctx(), Lookup(1, Some(60_000))).await?, 1);
+        // Uncacheable requests always reach the server.
+        assert_eq!(cache.call(ctx(), Lookup(1, None)).await?, 2);
+        assert_eq!(cache.call(ctx(), Lookup(2, Some(60_000))).await?, 3);
+
+        // The response closest to expiring makes room for a third.
+        assert_eq!(cache.call(ctx(), Lookup(3, Some(120_000))).await?, 4);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.call(ctx(), Lookup(3, Some(120_000))).await?, 4);
+        assert_eq!(cache.call(ctx(), Lookup(1, Some(60_000))).await?, 5);
+
+        assert_eq!(cache.call(ctx(), Lookup(4, Some(1))).await?, 6);
+        tokio::time::delay_for(Duration::from_millis(5)).await;
+        assert_eq!(cache.call(ctx(), Lookup(4, Some(1))).await?, 7);
+        Ok(())
+    }
+
+    /// Answers with the number of calls so far, after a delay.
+    #[derive(Clone, Default)]
+    struct SlowCounter(Arc<AtomicU32>);
+
+    impl<'a> Client<'a, Lookup> for SlowCounter {
+        type Response = u32;
+        type Future = BoxFuture<'static, io::Result<u32>>;
+
+        fn call(&'a mut self, _: context::Context, _: Lookup) -> Self::Future {
+            let calls = self.0.fetch_add(1, Ordering::SeqCst) + 1;
+            tokio::time::delay_for(Duration::from_millis(10))
+                .map(move |()| Ok(calls))
+                .boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn identical_requests_in_flight_are_coalesced() -> io::Result<()> {
+        let counter = SlowCounter::default();
+        let mut caches = vec![Cache::new(counter.clone(), 10); 3];
+        let mut calls = caches
+            .iter_mut()
+            .map(|cache| cache.call(context::current(), Lookup(1, Some(60_000))))
+            .collect::<Vec<_>>();
+        // Canceling the first caller's request fails the others', rather than leaving them
+        // waiting forever.
+        drop(calls.remove(0));
+        for call in calls {
+            assert_eq!(call.await.unwrap_err().kind(), io::ErrorKind::Interrupted);
+        }
+
+        let mut cache = caches.remove(0);
+        let (a, b) = future::join(
+            cache
+                .clone()
+                .call(context::current(), Lookup(1, Some(60_000))),
+            cache.call(context::current(), Lookup(1, Some(60_000))),
+        )
+        .await;
+        assert_eq!((a?, b?), (2, 2));
+        assert_eq!(counter.0.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+}
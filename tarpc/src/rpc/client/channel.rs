@@ -5,48 +5,301 @@
 // https://opensource.org/licenses/MIT.
 
 use crate::{
+    clock::Clock,
     context,
     trace::SpanId,
-    util::{Compact, TimeUntil},
-    ClientMessage, PollIo, Request, Response, Transport,
+    util::{panic_message, Compact},
+    window::WindowGrants,
+    ClientMessage, ErrorFrame, PollIo, ReplyOrder, Request, Response, ServerError, ServerMessage,
+    SessionToken, Transport,
 };
-use fnv::FnvHashMap;
+use fnv::{FnvHashMap, FnvHashSet};
 use futures::{
     channel::{mpsc, oneshot},
     prelude::*,
     ready,
-    stream::Fuse,
+    stream::{Fuse, SelectAll},
     task::*,
 };
-use log::{debug, info, trace};
-use pin_project::{pin_project, pinned_drop, project};
+use log::{debug, error, info, trace, warn};
+use pin_project::{pin_project, pinned_drop};
 use std::{
+    collections::VecDeque,
     io,
+    panic::{self, AssertUnwindSafe},
     pin::Pin,
     sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
     },
+    time::{Duration, Instant, SystemTime},
 };
 
-use super::{Config, NewClient};
+use tokio::time::Delay;
+
+use super::{Backpressure, Config, NewClient, RetryBudget};
 
 /// Handles communication from the client to request dispatch.
+///
+/// Channels are cheap to clone: clones share the same connection, and each holds little more
+/// than a handle to state they share. Channels are `Send` and `Sync` as long as the request and
+/// response types are `Send`, so they can be shared between tasks without wrapping them in an
+/// `Arc`.
 #[derive(Debug)]
 pub struct Channel<Req, Resp> {
     to_dispatch: mpsc::Sender<DispatchRequest<Req, Resp>>,
+    /// The state shared by every clone of the channel.
+    shared: Arc<Shared<Resp>>,
+}
+
+/// The state shared by every clone of a [`Channel`].
+#[derive(Debug)]
+struct Shared<Resp> {
     /// Channel to send a cancel message to the dispatcher.
     cancellation: RequestCancellation,
     /// The ID to use for the next request to stage.
-    next_request_id: Arc<AtomicU64>,
+    next_request_id: AtomicU64,
+    /// The credit to grant the server for the items of each streamed reply.
+    stream_window: u32,
+    /// Channel to send grants of credit for drained reply items to the dispatcher.
+    window_updates: mpsc::UnboundedSender<(u64, u32)>,
+    /// Channel to subscribe to the server's notifications.
+    subscriptions: mpsc::UnboundedSender<mpsc::UnboundedSender<Resp>>,
+    /// The load the server last reported.
+    server_load: Arc<Mutex<Option<u32>>>,
+    /// The session the server put the connection in.
+    session: Arc<Mutex<Option<SessionToken>>>,
+    /// Channel to send health checks to the dispatcher.
+    health_checks: mpsc::UnboundedSender<oneshot::Sender<bool>>,
+    /// Set once the server tells the client to go away.
+    going_away: Arc<AtomicBool>,
+    /// The error that ended request dispatch, if it failed.
+    dispatch_error: Arc<Mutex<Option<DispatchError>>>,
+    /// Channel to ask the dispatcher to shut down. The dispatcher drops the sender once it has.
+    shutdowns: mpsc::UnboundedSender<oneshot::Sender<()>>,
+    /// Set once the channel is shut down, shared with its calls.
+    shut_down: Arc<AtomicBool>,
+    /// How long to wait for the dispatcher to shut down.
+    shutdown_timeout: Duration,
+    /// The clock deadlines are measured against.
+    clock: Arc<dyn Clock>,
+    /// Counts the channel's requests, shared with the dispatcher.
+    counters: Arc<Counters>,
+    /// Counts the channel's requests and retries, if its retries are budgeted.
+    retries: Option<Mutex<RetryWindow>>,
+}
+
+/// The kind and description of the error that ended request dispatch, kept to fail the channel's
+/// later calls to [`Channel::ready`].
+type DispatchError = (io::ErrorKind, String);
+
+/// A snapshot of the statistics of a [`Channel`] and its clones, returned by [`Channel::stats`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Stats {
+    /// The number of requests written to the wire that haven't yet completed.
+    pub in_flight: usize,
+    /// The number of requests written to the wire.
+    pub requests: u64,
+    /// The number of requests that failed, because the server answered with an error, the
+    /// request's deadline passed, or the connection died while the request was in flight.
+    pub errors: u64,
+    /// Whether the connection is up, i.e. request dispatch is still running.
+    pub connected: bool,
+    /// The number of reply messages received for requests whose replies had already completed.
+    /// Only the most recent completions are remembered, so a duplicate of an old reply counts as
+    /// stale.
+    pub duplicate_replies: u64,
+    /// The number of reply messages received for requests the client wasn't waiting for, because
+    /// they'd been canceled or their deadlines had passed. These are expected when requests are
+    /// canceled, since the server may reply before it learns of the cancellation; they're
+    /// dropped.
+    pub stale_replies: u64,
+    /// The smoothed round-trip time of the connection, measured by its health checks. `None`
+    /// until a check is answered.
+    pub rtt: Option<Duration>,
+    /// The smoothed estimate of how far the server's clock is ahead of the client's, in
+    /// microseconds, or behind it if negative. Deadlines are sent as wall-clock times, so a large
+    /// offset skews them. `None` until a check is answered by a server that sends its time.
+    pub clock_offset_micros: Option<i64>,
+    /// The allocations made for the requests written to the wire, by the channel's calls and
+    /// request dispatch.
+    #[cfg(feature = "alloc-audit")]
+    pub allocations: crate::alloc_audit::AllocReport,
+}
+
+/// The counters behind [`Stats`], updated by request dispatch.
+#[derive(Debug)]
+struct Counters {
+    in_flight: AtomicUsize,
+    requests: AtomicU64,
+    errors: AtomicU64,
+    connected: AtomicBool,
+    duplicate_replies: AtomicU64,
+    stale_replies: AtomicU64,
+    /// The smoothed round-trip time in microseconds, or `u64::MAX` if unknown.
+    rtt_micros: AtomicU64,
+    /// The smoothed clock offset in microseconds, or `i64::MIN` if unknown.
+    clock_offset_micros: AtomicI64,
+    #[cfg(feature = "alloc-audit")]
+    allocations: crate::alloc_audit::AllocAudit,
+}
+
+impl Default for Counters {
+    fn default() -> Self {
+        Counters {
+            in_flight: AtomicUsize::new(0),
+            requests: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            connected: AtomicBool::new(true),
+            duplicate_replies: AtomicU64::new(0),
+            stale_replies: AtomicU64::new(0),
+            rtt_micros: AtomicU64::new(u64::MAX),
+            clock_offset_micros: AtomicI64::new(i64::MIN),
+            #[cfg(feature = "alloc-audit")]
+            allocations: Default::default(),
+        }
+    }
+}
+
+impl Counters {
+    fn snapshot(&self) -> Stats {
+        Stats {
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            requests: self.requests.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            connected: self.connected.load(Ordering::Relaxed),
+            duplicate_replies: self.duplicate_replies.load(Ordering::Relaxed),
+            stale_replies: self.stale_replies.load(Ordering::Relaxed),
+            rtt: match self.rtt_micros.load(Ordering::Relaxed) {
+                u64::MAX => None,
+                rtt => Some(Duration::from_micros(rtt)),
+            },
+            clock_offset_micros: match self.clock_offset_micros.load(Ordering::Relaxed) {
+                i64::MIN => None,
+                offset => Some(offset),
+            },
+            #[cfg(feature = "alloc-audit")]
+            allocations: self.allocations.report(),
+        }
+    }
+
+    fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Folds the round trip of a health check, and the clock offset it implies, into the smoothed
+    /// estimates. Like TCP's, each sample is weighted an eighth. Only request dispatch samples, so
+    /// the loads and stores needn't be atomic together.
+    fn sample_clock(&self, rtt: Duration, clock_offset_micros: Option<i64>) {
+        let rtt = (rtt.as_micros() as u64).min(u64::MAX - 1);
+        let smoothed = match self.rtt_micros.load(Ordering::Relaxed) {
+            u64::MAX => rtt,
+            smoothed => smoothed - smoothed / 8 + rtt / 8,
+        };
+        self.rtt_micros.store(smoothed, Ordering::Relaxed);
+
+        if let Some(offset) = clock_offset_micros {
+            let offset = offset.max(i64::MIN + 1);
+            let smoothed = match self.clock_offset_micros.load(Ordering::Relaxed) {
+                i64::MIN => offset,
+                smoothed => smoothed - smoothed / 8 + offset / 8,
+            };
+            self.clock_offset_micros.store(smoothed, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The number of completed requests whose IDs request dispatch remembers, to tell duplicate replies
+/// from stale ones.
+const REMEMBERED_COMPLETIONS: usize = 1_024;
+
+/// The IDs of the requests that most recently completed.
+#[derive(Debug, Default)]
+struct Completions {
+    /// Oldest first.
+    order: VecDeque<u64>,
+    ids: FnvHashSet<u64>,
+}
+
+impl Completions {
+    fn insert(&mut self, request_id: u64) {
+        if self.order.len() == REMEMBERED_COMPLETIONS {
+            let oldest = self.order.pop_front().unwrap();
+            self.ids.remove(&oldest);
+        }
+        self.order.push_back(request_id);
+        self.ids.insert(request_id);
+    }
+
+    fn contains(&self, request_id: u64) -> bool {
+        self.ids.contains(&request_id)
+    }
+}
+
+/// The number of seconds over which a [`RetryBudget`] is enforced.
+const RETRY_WINDOW_SECS: u64 = 10;
+
+/// Counts the requests and retries of the last [`RETRY_WINDOW_SECS`] seconds, to enforce a
+/// [`RetryBudget`].
+#[derive(Debug)]
+struct RetryWindow {
+    budget: RetryBudget,
+    start: Instant,
+    /// The second since `start`, and the requests and retries in it, of each of the window's
+    /// seconds, indexed by the second modulo the window's length.
+    seconds: [(u64, u64, u64); RETRY_WINDOW_SECS as usize],
+}
+
+impl RetryWindow {
+    fn new(budget: RetryBudget) -> Self {
+        RetryWindow {
+            budget,
+            start: Instant::now(),
+            seconds: [(0, 0, 0); RETRY_WINDOW_SECS as usize],
+        }
+    }
+
+    /// Returns the counts of the current second, resetting them if they're of an earlier second.
+    fn now(&mut self) -> &mut (u64, u64, u64) {
+        let second = self.start.elapsed().as_secs();
+        let counts = &mut self.seconds[(second % RETRY_WINDOW_SECS) as usize];
+        if counts.0 != second {
+            *counts = (second, 0, 0);
+        }
+        counts
+    }
+
+    fn request(&mut self) {
+        self.now().1 += 1;
+    }
+
+    /// Spends a retry, if the budget permits it.
+    fn retry(&mut self) -> bool {
+        let second = self.now().0;
+        let (requests, retries) = self
+            .seconds
+            .iter()
+            .filter(|(s, ..)| second - s < RETRY_WINDOW_SECS)
+            .fold((0, 0), |(requests, retries), (_, rq, rt)| {
+                (requests + rq, retries + rt)
+            });
+        let permitted = f64::from(self.budget.min_retries_per_second) * RETRY_WINDOW_SECS as f64
+            + self.budget.ratio * requests as f64;
+        if (retries as f64) < permitted {
+            self.now().2 += 1;
+            true
+        } else {
+            false
+        }
+    }
 }
 
 impl<Req, Resp> Clone for Channel<Req, Resp> {
     fn clone(&self) -> Self {
         Self {
             to_dispatch: self.to_dispatch.clone(),
-            cancellation: self.cancellation.clone(),
-            next_request_id: self.next_request_id.clone(),
+            shared: self.shared.clone(),
         }
     }
 }
@@ -79,12 +332,27 @@ impl<'a, Req, Resp> Future for Send<'a, Req, Resp> {
 pub struct Call<'a, Req, Resp> {
     #[pin]
     fut: tokio::time::Timeout<AndThenIdent<Send<'a, Req, Resp>, DispatchResponse<Resp>>>,
+    #[cfg(feature = "alloc-audit")]
+    allocations: crate::alloc_audit::AllocAudit,
 }
 
 impl<'a, Req, Resp> Future for Call<'a, Req, Resp> {
     type Output = io::Result<Resp>;
 
+    #[cfg(feature = "alloc-audit")]
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let allocations = self.allocations.clone();
+        crate::alloc_audit::audit(&allocations, || self.as_mut().poll_response(cx))
+    }
+
+    #[cfg(not(feature = "alloc-audit"))]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.poll_response(cx)
+    }
+}
+
+impl<'a, Req, Resp> Call<'a, Req, Resp> {
+    fn poll_response(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<Resp>> {
         let resp = ready!(self.as_mut().project().fut.poll(cx));
         Poll::Ready(match resp {
             Ok(resp) => resp,
@@ -96,27 +364,375 @@ impl<'a, Req, Resp> Future for Call<'a, Req, Resp> {
     }
 }
 
+/// The error of a call made with the context of a request that was canceled.
+fn canceled() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Interrupted,
+        "The request the call was made for was canceled.".to_string(),
+    )
+}
+
+/// The error for a message from the server that the client doesn't understand.
+fn unknown_message() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Received a message of an unknown kind from the server.".to_string(),
+    )
+}
+
+/// The error of a call made on, or in flight when, the client was [shut down](Channel::shutdown).
+/// It isn't transient, so the call isn't retried.
+fn shut_down() -> ServerError {
+    ServerError {
+        kind: io::ErrorKind::Other,
+        detail: Some("The client was shut down.".to_string()),
+        retry_after: None,
+    }
+}
+
+/// A future returned by [`Channel::call_stream`] that resolves to a stream of the items of a
+/// server's reply, once the request is sent.
+#[pin_project]
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct CallStream<'a, Req, Resp> {
+    #[pin]
+    fut: SendMapErrConnectionReset<'a, Req, Resp>,
+    stream: Option<ResponseStream<Resp>>,
+}
+
+impl<'a, Req, Resp> Future for CallStream<'a, Req, Resp> {
+    type Output = io::Result<ResponseStream<Resp>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        ready!(self.as_mut().project().fut.poll(cx))?;
+        Poll::Ready(Ok(self.project().stream.take().expect(
+            "CallStream must not be polled after it returned `Poll::Ready`",
+        )))
+    }
+}
+
+/// A future returned by [`Channel::call_with_items`] that resolves to a sink for the request's
+/// items and the future response, once the request is sent.
+#[pin_project]
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct CallWithItems<'a, Req, Resp> {
+    #[pin]
+    fut: SendMapErrConnectionReset<'a, Req, Resp>,
+    call: Option<(RequestSink<Req>, CallResponse<Resp>)>,
+}
+
+impl<'a, Req, Resp> Future for CallWithItems<'a, Req, Resp> {
+    type Output = io::Result<(RequestSink<Req>, CallResponse<Resp>)>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        ready!(self.as_mut().project().fut.poll(cx))?;
+        Poll::Ready(Ok(self.project().call.take().expect(
+            "CallWithItems must not be polled after it returned `Poll::Ready`",
+        )))
+    }
+}
+
+/// A future returned by [`Channel::call_with_progress`] that resolves to a stream of the progress
+/// the server reports and the future response, once the request is sent.
+#[pin_project]
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct CallWithProgress<'a, Req, Resp> {
+    #[pin]
+    fut: SendMapErrConnectionReset<'a, Req, Resp>,
+    call: Option<(ProgressUpdates<Resp>, CallResponse<Resp>)>,
+}
+
+impl<'a, Req, Resp> Future for CallWithProgress<'a, Req, Resp> {
+    type Output = io::Result<(ProgressUpdates<Resp>, CallResponse<Resp>)>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        ready!(self.as_mut().project().fut.poll(cx))?;
+        Poll::Ready(Ok(self.project().call.take().expect(
+            "CallWithProgress must not be polled after it returned `Poll::Ready`",
+        )))
+    }
+}
+
+/// The progress a server reports while handling a request. Created by
+/// [`Channel::call_with_progress`].
+///
+/// The stream ends when the call does. Dropping it doesn't cancel the request; later progress is
+/// simply dropped.
+#[derive(Debug)]
+pub struct ProgressUpdates<Resp> {
+    updates: mpsc::UnboundedReceiver<Resp>,
+}
+
+impl<Resp> Stream for ProgressUpdates<Resp> {
+    type Item = Resp;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Resp>> {
+        self.updates.poll_next_unpin(cx)
+    }
+}
+
+/// The notifications a server pushes to the client. Created by [`Channel::notifications`].
+///
+/// The stream ends when the connection closes or the subscription is replaced.
+#[derive(Debug)]
+pub struct Notifications<Resp> {
+    notifications: mpsc::UnboundedReceiver<Resp>,
+}
+
+impl<Resp> Stream for Notifications<Resp> {
+    type Item = Resp;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Resp>> {
+        self.notifications.poll_next_unpin(cx)
+    }
+}
+
+/// The items of a streaming request, sent to the server as part of the same call. Created by
+/// [`Channel::call_with_items`] and [`Channel::call_bidirectional`].
+///
+/// Closing or dropping the sink tells the server that the request has no more items, without
+/// ending the call. Sending fails once the call has ended, and waits while the server has no room
+/// for more items and the sink has buffered a window of them.
+#[derive(Debug)]
+pub struct RequestSink<Req> {
+    items: mpsc::Sender<Req>,
+}
+
+impl<Req> Sink<Req> for RequestSink<Req> {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.items
+            .poll_ready(cx)
+            .map_err(|_| io::Error::from(io::ErrorKind::ConnectionReset))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Req) -> io::Result<()> {
+        self.items
+            .start_send(item)
+            .map_err(|_| io::Error::from(io::ErrorKind::ConnectionReset))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.items.close_channel();
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A future returned by [`Channel::call_bidirectional`] that resolves to a sink for the request's
+/// items and a stream of the items of the server's reply, once the request is sent.
+#[pin_project]
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct CallBidirectional<'a, Req, Resp> {
+    #[pin]
+    fut: SendMapErrConnectionReset<'a, Req, Resp>,
+    call: Option<(RequestSink<Req>, ResponseStream<Resp>)>,
+}
+
+impl<'a, Req, Resp> Future for CallBidirectional<'a, Req, Resp> {
+    type Output = io::Result<(RequestSink<Req>, ResponseStream<Resp>)>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        ready!(self.as_mut().project().fut.poll(cx))?;
+        Poll::Ready(Ok(self.project().call.take().expect(
+            "CallBidirectional must not be polled after it returned `Poll::Ready`",
+        )))
+    }
+}
+
+/// A future returned by [`Channel::call_with_items`] that resolves to the server's response.
+///
+/// Cancels the request when dropped, if the response has not yet arrived.
+#[pin_project]
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct CallResponse<Resp> {
+    #[pin]
+    fut: tokio::time::Timeout<DispatchResponse<Resp>>,
+}
+
+impl<Resp> Future for CallResponse<Resp> {
+    type Output = io::Result<Resp>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Poll::Ready(match ready!(self.project().fut.poll(cx)) {
+            Ok(resp) => resp,
+            Err(tokio::time::Elapsed { .. }) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Client dropped expired request.".to_string(),
+            )),
+        })
+    }
+}
+
 impl<Req, Resp> Channel<Req, Resp> {
-    /// Sends a request to the dispatch task to forward to the server, returning a [`Future`] that
-    /// resolves when the request is sent (not when the response is received).
-    fn send(&mut self, mut ctx: context::Context, request: Req) -> Send<Req, Resp> {
-        // Convert the context to the call context.
+    /// Returns whether a failed call may be retried within the [`RetryBudget`] of the channel's
+    /// config, spending a retry if so. Clones share the same budget.
+    pub fn permit_retry(&self) -> bool {
+        match self.shared.retries {
+            Some(ref retries) => retries.lock().unwrap().retry(),
+            None => true,
+        }
+    }
+
+    fn count_request(&self) {
+        if let Some(ref retries) = self.shared.retries {
+            retries.lock().unwrap().request();
+        }
+    }
+
+    /// Converts the context of the caller to the context of a call it makes.
+    fn call_context(mut ctx: context::Context) -> context::Context {
         ctx.trace_context.parent_id = Some(ctx.trace_context.span_id);
         ctx.trace_context.span_id = SpanId::random(&mut rand::thread_rng());
+        ctx
+    }
 
+    /// Subscribes to the notifications the server pushes over the connection.
+    ///
+    /// Notifications go only to the latest subscriber: subscribing again ends the previous
+    /// subscription. Notifications that arrive while no one is subscribed are dropped.
+    pub fn notifications(&self) -> Notifications<Resp> {
+        let (tx, notifications) = mpsc::unbounded();
+        // If request dispatch is gone, the stream simply ends.
+        let _ = self.shared.subscriptions.unbounded_send(tx);
+        Notifications { notifications }
+    }
+
+    /// Returns the load the server last reported, or None if it hasn't reported any. Servers
+    /// report their load if configured with a [`Load`](crate::server::Load).
+    pub fn server_load(&self) -> Option<u32> {
+        *self.shared.server_load.lock().unwrap()
+    }
+
+    /// Returns the session the server put the connection in, or None if the client didn't ask for
+    /// one, the server doesn't keep them, or the server hasn't yet answered the handshake. If the
+    /// client asked to resume a session the server no longer has, this is a new session.
+    pub fn session(&self) -> Option<SessionToken> {
+        self.shared.session.lock().unwrap().clone()
+    }
+
+    /// Returns the statistics of the channel and its clones, e.g. to apply backpressure when too
+    /// many requests are in flight, or to export to a monitoring system.
+    pub fn stats(&self) -> Stats {
+        self.shared.counters.snapshot()
+    }
+
+    /// Returns true once the server has told the client to go away because it's draining. The
+    /// server still serves the client's requests, but clients that can should send new requests
+    /// to other servers.
+    pub fn is_going_away(&self) -> bool {
+        self.shared.going_away.load(Ordering::Relaxed)
+    }
+
+    /// Asks the server whether it's serving. Servers answer health checks themselves, so a server
+    /// that answers is at least reachable; it reports that it isn't serving if its
+    /// [`Health`](crate::server::Health) says so.
+    pub async fn check_health(&self) -> io::Result<bool> {
+        let (tx, serving) = oneshot::channel();
+        self.shared
+            .health_checks
+            .unbounded_send(tx)
+            .map_err(|_| io::Error::from(io::ErrorKind::ConnectionReset))?;
+        serving
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::ConnectionReset))
+    }
+
+    /// Resolves once the connection is ready for requests: request dispatch is running, and the
+    /// server has answered the handshake, if the config asks for one, and a health check. Fails
+    /// with the error that ended request dispatch, e.g. because the server doesn't send replies
+    /// in the order the config needs, so that startup can tell a connection that never came up
+    /// from a request that failed.
+    pub async fn ready(&self) -> io::Result<()> {
+        match self.check_health().await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(match *self.shared.dispatch_error.lock().unwrap() {
+                Some((kind, ref error)) => io::Error::new(kind, error.clone()),
+                None => e,
+            }),
+        }
+    }
+
+    /// Like [`ready`](Channel::ready), but fails with [`TimedOut`](io::ErrorKind::TimedOut) if the
+    /// connection isn't ready within `timeout`.
+    pub async fn wait_connected(&self, timeout: Duration) -> io::Result<()> {
+        match tokio::time::timeout(timeout, self.ready()).await {
+            Ok(ready) => ready,
+            Err(tokio::time::Elapsed { .. }) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("Connection wasn't ready within {:?}.", timeout),
+            )),
+        }
+    }
+
+    /// Shuts the client down without waiting for its calls to complete. The calls in flight, and
+    /// those made on any clone of the channel from now on, fail at once with an
+    /// [`Other`](io::ErrorKind::Other) error, which isn't retried. Request dispatch then tells the
+    /// server, best-effort, to stop working on the calls it cancelled, and closes the transport.
+    ///
+    /// Resolves once dispatch has ended, or once the config's
+    /// [`shutdown_timeout`](super::Config::shutdown_timeout) passes, whichever is first. Unlike
+    /// dropping every clone of the channel, which lets the calls in flight complete, shutting down
+    /// doesn't depend on the server.
+    pub async fn shutdown(&self) {
+        self.shared.shut_down.store(true, Ordering::SeqCst);
+        // Calls that haven't reached dispatch fail as soon as they try.
+        self.to_dispatch.clone().close_channel();
+        let (tx, ended) = oneshot::channel();
+        if self.shared.shutdowns.unbounded_send(tx).is_err() {
+            // Dispatch already ended.
+            return;
+        }
+        // Dispatch drops the sender once it ends, and gives up on the transport by the same
+        // timeout, so the wait is bounded both ways.
+        let _ = tokio::time::timeout(self.shared.shutdown_timeout, ended).await;
+    }
+
+    /// Returns true once the client has been [shut down](Channel::shutdown).
+    pub fn is_shut_down(&self) -> bool {
+        self.shared.shut_down.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once the dispatch task can take another request, failing if it's gone.
+    #[cfg(feature = "tower")]
+    pub(crate) fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.to_dispatch
+            .poll_ready(cx)
+            .map_err(|_| io::Error::from(io::ErrorKind::ConnectionReset))
+    }
+
+    /// Sends a request to the dispatch task to forward to the server, returning a [`Future`] that
+    /// resolves when the request is sent (not when the response is received).
+    fn send(&mut self, ctx: context::Context, request: Req) -> Send<'_, Req, Resp> {
+        let ctx = Self::call_context(ctx);
         let (response_completion, response) = oneshot::channel();
-        let cancellation = self.cancellation.clone();
-        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let cancellation = self.shared.cancellation.clone();
+        let request_id = self.shared.next_request_id.fetch_add(1, Ordering::Relaxed);
         Send {
             fut: MapOkDispatchResponse::new(
-                MapErrConnectionReset::new(self.to_dispatch.send(DispatchRequest {
-                    ctx,
-                    request_id,
-                    request,
-                    response_completion,
-                })),
+                MapErrConnectionReset::new(
+                    self.to_dispatch.send(DispatchRequest {
+                        ctx: ctx.clone(),
+                        request_id,
+                        request,
+                        items: None,
+                        progress: None,
+                        response_completion: ResponseCompletion::Unary(response_completion),
+                    }),
+                    self.shared.shut_down.clone(),
+                ),
                 DispatchResponse {
                     response,
+                    canceled: ctx.canceled(),
                     complete: false,
                     request_id,
                     cancellation,
@@ -128,16 +744,225 @@ impl<Req, Resp> Channel<Req, Resp> {
 
     /// Sends a request to the dispatch task to forward to the server, returning a [`Future`] that
     /// resolves to the response.
-    pub fn call(&mut self, ctx: context::Context, request: Req) -> Call<Req, Resp> {
-        let timeout = ctx.deadline.time_until();
+    pub fn call(&mut self, ctx: context::Context, request: Req) -> Call<'_, Req, Resp> {
+        self.count_request();
+        let timeout = self.shared.clock.until(ctx.deadline);
         trace!(
             "[{}] Queuing request with timeout {:?}.",
             ctx.trace_id(),
             timeout,
         );
 
+        #[cfg(feature = "alloc-audit")]
+        let allocations = self.shared.counters.allocations.clone();
         Call {
             fut: tokio::time::timeout(timeout, AndThenIdent::new(self.send(ctx, request))),
+            #[cfg(feature = "alloc-audit")]
+            allocations,
+        }
+    }
+
+    /// Sends a request to the dispatch task to forward to the server, returning a [`Future`] that
+    /// resolves to a stream of the items of the server's reply.
+    ///
+    /// The stream ends when the server finishes its reply, and yields an error if the reply fails,
+    /// including if it does not finish before the request's deadline. Dropping the stream before
+    /// it ends cancels the request.
+    pub fn call_stream(
+        &mut self,
+        ctx: context::Context,
+        request: Req,
+    ) -> CallStream<'_, Req, Resp> {
+        self.count_request();
+        let ctx = Self::call_context(ctx);
+        let timeout = self.shared.clock.until(ctx.deadline);
+        trace!(
+            "[{}] Queuing streaming request with timeout {:?}.",
+            ctx.trace_id(),
+            timeout,
+        );
+
+        let (response_completion, items) = mpsc::unbounded();
+        let request_id = self.shared.next_request_id.fetch_add(1, Ordering::Relaxed);
+        CallStream {
+            fut: MapErrConnectionReset::new(
+                self.to_dispatch.send(DispatchRequest {
+                    ctx: ctx.clone(),
+                    request_id,
+                    request,
+                    items: None,
+                    progress: None,
+                    response_completion: ResponseCompletion::Stream(response_completion),
+                }),
+                self.shared.shut_down.clone(),
+            ),
+            stream: Some(ResponseStream {
+                items,
+                deadline: tokio::time::delay_for(timeout),
+                canceled: ctx.canceled(),
+                complete: false,
+                cancellation: self.shared.cancellation.clone(),
+                grants: WindowGrants::new(
+                    request_id,
+                    self.shared.stream_window,
+                    self.shared.window_updates.clone(),
+                ),
+                request_id,
+                ctx,
+            }),
+        }
+    }
+
+    /// Sends a request to the dispatch task to forward to the server, followed by the items sent
+    /// to the returned [`RequestSink`]. Returns a [`Future`] that resolves, once the request is
+    /// sent, to the sink and the future response.
+    ///
+    /// The server may respond before it has received every item. Dropping the future response
+    /// before it completes cancels the request.
+    pub fn call_with_items(
+        &mut self,
+        ctx: context::Context,
+        request: Req,
+    ) -> CallWithItems<'_, Req, Resp> {
+        let ctx = Self::call_context(ctx);
+        let timeout = self.shared.clock.until(ctx.deadline);
+        trace!(
+            "[{}] Queuing request with items with timeout {:?}.",
+            ctx.trace_id(),
+            timeout,
+        );
+
+        let (response_completion, response) = oneshot::channel();
+        let (items_tx, items) = mpsc::channel(self.shared.stream_window as usize);
+        let request_id = self.shared.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let response = DispatchResponse {
+            response,
+            canceled: ctx.canceled(),
+            complete: false,
+            request_id,
+            cancellation: self.shared.cancellation.clone(),
+            ctx: ctx.clone(),
+        };
+        CallWithItems {
+            fut: MapErrConnectionReset::new(
+                self.to_dispatch.send(DispatchRequest {
+                    ctx,
+                    request_id,
+                    request,
+                    items: Some(items),
+                    progress: None,
+                    response_completion: ResponseCompletion::Unary(response_completion),
+                }),
+                self.shared.shut_down.clone(),
+            ),
+            call: Some((
+                RequestSink { items: items_tx },
+                CallResponse {
+                    fut: tokio::time::timeout(timeout, response),
+                },
+            )),
+        }
+    }
+
+    /// Sends a request to the dispatch task to forward to the server. Returns a [`Future`] that
+    /// resolves, once the request is sent, to a stream of the progress the server reports while
+    /// handling the request, and the future response.
+    ///
+    /// Dropping the future response before it completes cancels the request.
+    pub fn call_with_progress(
+        &mut self,
+        ctx: context::Context,
+        request: Req,
+    ) -> CallWithProgress<'_, Req, Resp> {
+        let ctx = Self::call_context(ctx);
+        let timeout = self.shared.clock.until(ctx.deadline);
+        trace!(
+            "[{}] Queuing request with progress with timeout {:?}.",
+            ctx.trace_id(),
+            timeout,
+        );
+
+        let (response_completion, response) = oneshot::channel();
+        let (progress, updates) = mpsc::unbounded();
+        let request_id = self.shared.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let response = DispatchResponse {
+            response,
+            canceled: ctx.canceled(),
+            complete: false,
+            request_id,
+            cancellation: self.shared.cancellation.clone(),
+            ctx: ctx.clone(),
+        };
+        CallWithProgress {
+            fut: MapErrConnectionReset::new(
+                self.to_dispatch.send(DispatchRequest {
+                    ctx,
+                    request_id,
+                    request,
+                    items: None,
+                    progress: Some(progress),
+                    response_completion: ResponseCompletion::Unary(response_completion),
+                }),
+                self.shared.shut_down.clone(),
+            ),
+            call: Some((
+                ProgressUpdates { updates },
+                CallResponse {
+                    fut: tokio::time::timeout(timeout, response),
+                },
+            )),
+        }
+    }
+
+    /// Sends a request to the dispatch task to forward to the server, followed by the items sent
+    /// to the returned [`RequestSink`]. Returns a [`Future`] that resolves, once the request is
+    /// sent, to the sink and a stream of the items of the server's reply.
+    ///
+    /// Each side ends its items independently: closing the sink doesn't end the reply, and the
+    /// call ends when the reply does. Dropping the reply stream before it ends cancels the request.
+    pub fn call_bidirectional(
+        &mut self,
+        ctx: context::Context,
+        request: Req,
+    ) -> CallBidirectional<'_, Req, Resp> {
+        let ctx = Self::call_context(ctx);
+        let timeout = self.shared.clock.until(ctx.deadline);
+        trace!(
+            "[{}] Queuing bidirectional request with timeout {:?}.",
+            ctx.trace_id(),
+            timeout,
+        );
+
+        let (response_completion, reply_items) = mpsc::unbounded();
+        let (items_tx, items) = mpsc::channel(self.shared.stream_window as usize);
+        let request_id = self.shared.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let reply = ResponseStream {
+            items: reply_items,
+            deadline: tokio::time::delay_for(timeout),
+            canceled: ctx.canceled(),
+            complete: false,
+            cancellation: self.shared.cancellation.clone(),
+            grants: WindowGrants::new(
+                request_id,
+                self.shared.stream_window,
+                self.shared.window_updates.clone(),
+            ),
+            request_id,
+            ctx: ctx.clone(),
+        };
+        CallBidirectional {
+            fut: MapErrConnectionReset::new(
+                self.to_dispatch.send(DispatchRequest {
+                    ctx,
+                    request_id,
+                    request,
+                    items: Some(items),
+                    progress: None,
+                    response_completion: ResponseCompletion::Stream(response_completion),
+                }),
+                self.shared.shut_down.clone(),
+            ),
+            call: Some((RequestSink { items: items_tx }, reply)),
         }
     }
 }
@@ -149,6 +974,7 @@ impl<Req, Resp> Channel<Req, Resp> {
 struct DispatchResponse<Resp> {
     response: oneshot::Receiver<Response<Resp>>,
     ctx: context::Context,
+    canceled: context::Canceled,
     complete: bool,
     cancellation: RequestCancellation,
     request_id: u64,
@@ -158,7 +984,15 @@ impl<Resp> Future for DispatchResponse<Resp> {
     type Output = io::Result<Resp>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<Resp>> {
-        let resp = ready!(self.response.poll_unpin(cx));
+        let resp = match self.response.poll_unpin(cx) {
+            Poll::Ready(resp) => resp,
+            Poll::Pending => {
+                ready!(self.canceled.poll_unpin(cx));
+                // Stop the server from doing any more work on the request.
+                self.cancel();
+                return Poll::Ready(Err(canceled()));
+            }
+        };
         self.complete = true;
         Poll::Ready(match resp {
             Ok(resp) => Ok(resp.message?),
@@ -172,11 +1006,11 @@ impl<Resp> Future for DispatchResponse<Resp> {
     }
 }
 
-// Cancels the request when dropped, if not already complete.
-#[pinned_drop]
-impl<Resp> PinnedDrop for DispatchResponse<Resp> {
-    fn drop(mut self: Pin<&mut Self>) {
+impl<Resp> DispatchResponse<Resp> {
+    /// Cancels the request, if not already complete.
+    fn cancel(&mut self) {
         if !self.complete {
+            self.complete = true;
             // The receiver needs to be closed to handle the edge case that the request has not
             // yet been received by the dispatch task. It is possible for the cancel message to
             // arrive before the request itself, in which case the request could get stuck in the
@@ -188,8 +1022,109 @@ impl<Resp> PinnedDrop for DispatchResponse<Resp> {
             // dispatch task misses an early-arriving cancellation message, then it will see the
             // receiver as closed.
             self.response.close();
-            let request_id = self.request_id;
-            self.cancellation.cancel(request_id);
+            self.cancellation.cancel(self.request_id);
+        }
+    }
+}
+
+// Cancels the request when dropped, if not already complete.
+#[pinned_drop]
+impl<Resp> PinnedDrop for DispatchResponse<Resp> {
+    fn drop(mut self: Pin<&mut Self>) {
+        self.cancel();
+    }
+}
+
+/// The items of a server's streamed reply, returned by [`Channel::call_stream`].
+///
+/// Cancels the request when dropped, if the reply has not yet ended.
+#[pin_project(PinnedDrop)]
+#[derive(Debug)]
+pub struct ResponseStream<Resp> {
+    items: mpsc::UnboundedReceiver<ServerMessage<Resp>>,
+    #[pin]
+    deadline: Delay,
+    ctx: context::Context,
+    canceled: context::Canceled,
+    complete: bool,
+    cancellation: RequestCancellation,
+    grants: WindowGrants,
+    request_id: u64,
+}
+
+impl<Resp> ResponseStream<Resp> {
+    /// Returns the context the request was sent with.
+    pub fn context(&self) -> &context::Context {
+        &self.ctx
+    }
+}
+
+impl<Resp> Stream for ResponseStream<Resp> {
+    type Item = io::Result<Resp>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<Resp>>> {
+        if self.complete {
+            return Poll::Ready(None);
+        }
+        let this = self.as_mut().project();
+        let message = match this.items.poll_next_unpin(cx) {
+            Poll::Ready(message) => message,
+            Poll::Pending => {
+                let error = match this.canceled.poll_unpin(cx) {
+                    Poll::Ready(()) => canceled(),
+                    Poll::Pending => {
+                        ready!(this.deadline.poll(cx));
+                        io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "Client dropped expired request.".to_string(),
+                        )
+                    }
+                };
+                // Stop the server from doing any more work on the request.
+                *this.complete = true;
+                this.items.close();
+                this.cancellation.cancel(*this.request_id);
+                return Poll::Ready(Some(Err(error)));
+            }
+        };
+        Poll::Ready(match message {
+            Some(ServerMessage::StreamItem { item, .. }) => {
+                this.grants.drained();
+                Some(Ok(item))
+            }
+            Some(ServerMessage::StreamEnd { .. }) => {
+                *this.complete = true;
+                None
+            }
+            // A single response is a reply of one item; an error ends the reply.
+            Some(ServerMessage::Response(response)) => {
+                *this.complete = true;
+                Some(response.message.map_err(io::Error::from))
+            }
+            // Request dispatch handles window updates and progress itself, and forwards no other
+            // messages.
+            Some(_) => {
+                *this.complete = true;
+                Some(Err(unknown_message()))
+            }
+            None => {
+                // The dispatch task ended, so there's no point in propagating cancellation.
+                *this.complete = true;
+                Some(Err(io::Error::from(io::ErrorKind::ConnectionReset)))
+            }
+        })
+    }
+}
+
+#[pinned_drop]
+impl<Resp> PinnedDrop for ResponseStream<Resp> {
+    fn drop(self: Pin<&mut Self>) {
+        let this = self.project();
+        if !*this.complete {
+            // See the comment in DispatchResponse's drop for why the receiver must be closed
+            // first.
+            this.items.close();
+            this.cancellation.cancel(*this.request_id);
         }
     }
 }
@@ -201,24 +1136,71 @@ pub fn new<Req, Resp, C>(
     transport: C,
 ) -> NewClient<Channel<Req, Resp>, RequestDispatch<Req, Resp, C>>
 where
-    C: Transport<ClientMessage<Req>, Response<Resp>>,
+    C: Transport<ClientMessage<Req>, ServerMessage<Resp>>,
 {
     let (to_dispatch, pending_requests) = mpsc::channel(config.pending_request_buffer);
     let (cancellation, canceled_requests) = cancellations();
     let canceled_requests = canceled_requests.fuse();
+    let (window_updates_tx, window_updates) = mpsc::unbounded();
+    let (subscriptions_tx, subscriptions) = mpsc::unbounded();
+    let server_load = Arc::new(Mutex::new(None));
+    let session = Arc::new(Mutex::new(None));
+    let (health_checks_tx, health_checks) = mpsc::unbounded();
+    let going_away = Arc::new(AtomicBool::new(false));
+    let dispatch_error = Arc::new(Mutex::new(None));
+    let (shutdowns_tx, shutdowns) = mpsc::unbounded();
+    let counters = Arc::new(Counters::default());
 
     NewClient {
         client: Channel {
             to_dispatch,
-            cancellation,
-            next_request_id: Arc::new(AtomicU64::new(0)),
+            shared: Arc::new(Shared {
+                cancellation,
+                next_request_id: AtomicU64::new(config.request_ids.first()),
+                stream_window: config.stream_window,
+                window_updates: window_updates_tx.clone(),
+                subscriptions: subscriptions_tx,
+                server_load: server_load.clone(),
+                session: session.clone(),
+                health_checks: health_checks_tx,
+                going_away: going_away.clone(),
+                dispatch_error: dispatch_error.clone(),
+                shutdowns: shutdowns_tx,
+                shut_down: Arc::new(AtomicBool::new(false)),
+                shutdown_timeout: config.shutdown_timeout,
+                clock: config.clock.clone(),
+                counters: counters.clone(),
+                retries: config
+                    .retry_budget
+                    .map(|budget| Mutex::new(RetryWindow::new(budget))),
+            }),
         },
         dispatch: RequestDispatch {
+            handshake: config.reply_order != ReplyOrder::Completion || config.session.is_some(),
+            session,
             config,
             canceled_requests,
             transport: transport.fuse(),
             in_flight_requests: FnvHashMap::default(),
             pending_requests: pending_requests.fuse(),
+            outgoing_items: SelectAll::new(),
+            window_updates,
+            window_updates_tx,
+            subscriptions,
+            notifications: None,
+            server_load,
+            health_checks,
+            pending_health_checks: FnvHashMap::default(),
+            next_health_check_id: 0,
+            going_away,
+            dispatch_error,
+            shutdowns,
+            shutting_down: None,
+            counters,
+            completions: Completions::default(),
+            requests_sent: 0,
+            credits_granted: None,
+            protocol_error: None,
         },
     }
 }
@@ -239,19 +1221,72 @@ pub struct RequestDispatch<Req, Resp, C> {
     canceled_requests: Fuse<CanceledRequests>,
     /// Requests already written to the wire that haven't yet received responses.
     in_flight_requests: FnvHashMap<u64, InFlightData<Resp>>,
+    /// Items of streaming requests waiting to be written to the wire.
+    outgoing_items: SelectAll<OutgoingItems<Req>>,
+    /// Grants of credit for streamed replies, waiting to be written to the wire.
+    #[pin]
+    window_updates: mpsc::UnboundedReceiver<(u64, u32)>,
+    /// Used to grant the initial window of each streamed reply.
+    window_updates_tx: mpsc::UnboundedSender<(u64, u32)>,
+    /// New subscribers to the server's notifications.
+    subscriptions: mpsc::UnboundedReceiver<mpsc::UnboundedSender<Resp>>,
+    /// Where to send the server's notifications, if anyone is subscribed.
+    notifications: Option<mpsc::UnboundedSender<Resp>>,
+    /// The load the server last reported, shared with the channels.
+    server_load: Arc<Mutex<Option<u32>>>,
+    /// Health checks waiting to be written to the wire.
+    health_checks: mpsc::UnboundedReceiver<oneshot::Sender<bool>>,
+    /// Health checks already written to the wire that haven't yet been answered.
+    pending_health_checks: FnvHashMap<u64, PendingHealthCheck>,
+    /// The ID to use for the next health check.
+    next_health_check_id: u64,
+    /// Set once the server tells the client to go away, shared with the channels.
+    going_away: Arc<AtomicBool>,
+    /// Set to the error that ended dispatch, if it failed, shared with the channels.
+    dispatch_error: Arc<Mutex<Option<DispatchError>>>,
+    /// The channels' requests to shut down.
+    shutdowns: mpsc::UnboundedReceiver<oneshot::Sender<()>>,
+    /// Set once a channel asks for dispatch to shut down.
+    shutting_down: Option<ShuttingDown>,
+    /// Counts the requests, shared with the channels.
+    counters: Arc<Counters>,
+    /// Whether the handshake asking for the config's reply order and session has yet to be written
+    /// to the wire.
+    handshake: bool,
+    /// The session the server put the connection in, shared with the channels.
+    session: Arc<Mutex<Option<SessionToken>>>,
+    /// The requests that most recently completed, to recognize duplicate replies.
+    completions: Completions,
+    /// The number of requests written to the wire.
+    requests_sent: u64,
+    /// The number of requests the server has granted credit for, if it limits them.
+    credits_granted: Option<u64>,
+    /// The error the server reported about the connection, if it stopped reading from it.
+    protocol_error: Option<ErrorFrame>,
     /// Configures limits to prevent unlimited resource usage.
     config: Config,
 }
 
 impl<Req, Resp, C> RequestDispatch<Req, Resp, C>
 where
-    C: Transport<ClientMessage<Req>, Response<Resp>>,
+    C: Transport<ClientMessage<Req>, ServerMessage<Resp>>,
 {
     fn pump_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> PollIo<()> {
         Poll::Ready(
             match ready!(self.as_mut().project().transport.poll_next(cx)?) {
-                Some(response) => {
-                    self.complete(response);
+                Some(ServerMessage::Handshake { reply_order, .. })
+                    if reply_order != self.config.reply_order =>
+                {
+                    return Poll::Ready(Some(Err(io::Error::new(
+                        io::ErrorKind::ConnectionRefused,
+                        format!(
+                            "Needed replies in {:?} order, but the server sends them in {:?} order.",
+                            self.config.reply_order, reply_order
+                        ),
+                    ))));
+                }
+                Some(message) => {
+                    self.complete(message)?;
                     Some(Ok(()))
                 }
                 None => None,
@@ -265,6 +1300,27 @@ where
             Closed,
         }
 
+        // Requests may only be written after the handshake, so that they're replied to in order
+        // and in the session.
+        if self.handshake {
+            while self
+                .as_mut()
+                .project()
+                .transport
+                .poll_ready(cx)?
+                .is_pending()
+            {
+                ready!(self.as_mut().project().transport.poll_flush(cx)?);
+            }
+            let this = self.as_mut().project();
+            *this.handshake = false;
+            this.transport.start_send(ClientMessage::Handshake {
+                reply_order: this.config.reply_order,
+                session: this.config.session.clone(),
+            })?;
+            return Poll::Ready(Some(Ok(())));
+        }
+
         let pending_requests_status = match self.as_mut().poll_next_request(cx)? {
             Poll::Ready(Some(dispatch_request)) => {
                 self.as_mut().write_request(dispatch_request)?;
@@ -274,6 +1330,21 @@ where
             Poll::Pending => ReceiverStatus::NotReady,
         };
 
+        if let Poll::Ready(Some(item)) = self.as_mut().poll_next_item(cx)? {
+            self.as_mut().project().transport.start_send(item)?;
+            return Poll::Ready(Some(Ok(())));
+        }
+
+        if let Poll::Ready(Some(update)) = self.as_mut().poll_next_window_update(cx)? {
+            self.as_mut().project().transport.start_send(update)?;
+            return Poll::Ready(Some(Ok(())));
+        }
+
+        if let Poll::Ready(Some(check)) = self.as_mut().poll_next_health_check(cx)? {
+            self.as_mut().project().transport.start_send(check)?;
+            return Poll::Ready(Some(Ok(())));
+        }
+
         let canceled_requests_status = match self.as_mut().poll_next_cancellation(cx)? {
             Poll::Ready(Some((context, request_id))) => {
                 self.as_mut().write_cancel(context, request_id)?;
@@ -316,7 +1387,24 @@ where
             return Poll::Pending;
         }
 
-        while let Poll::Pending = self.as_mut().project().transport.poll_ready(cx)? {
+        if let Some(granted) = self.credits_granted {
+            if self.requests_sent >= granted {
+                trace!("Out of credit for requests ({} granted).", granted);
+                match self.config.backpressure {
+                    // Reading the server's next grant wakes the dispatch.
+                    Backpressure::Wait => return Poll::Pending,
+                    Backpressure::Fail => return self.as_mut().poll_fail_requests(cx),
+                }
+            }
+        }
+
+        while self
+            .as_mut()
+            .project()
+            .transport
+            .poll_ready(cx)?
+            .is_pending()
+        {
             // We can't yield a request-to-be-sent before the transport is capable of buffering it.
             ready!(self.as_mut().project().transport.poll_flush(cx)?);
         }
@@ -339,12 +1427,151 @@ where
         }
     }
 
+    /// Fails the pending requests, because the server hasn't granted credit for them. Resolves
+    /// once the channels are gone.
+    fn poll_fail_requests(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> PollIo<DispatchRequest<Req, Resp>> {
+        loop {
+            let request = match ready!(self.as_mut().project().pending_requests.poll_next_unpin(cx))
+            {
+                Some(request) => request,
+                None => return Poll::Ready(None),
+            };
+            debug!(
+                "[{}] Failing request, because the server hasn't granted credit for it.",
+                request.ctx.trace_id()
+            );
+            self.counters.error();
+            request.response_completion.fail(Response {
+                request_id: request.request_id,
+                message: Err(ServerError {
+                    kind: io::ErrorKind::WouldBlock,
+                    detail: Some("The server hasn't granted credit for more requests.".to_string()),
+                    retry_after: None,
+                }),
+            });
+        }
+    }
+
+    /// Yields the next item of a streaming request, if one is ready to be sent.
+    fn poll_next_item(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> PollIo<ClientMessage<Req>> {
+        if self.as_mut().project().outgoing_items.is_empty() {
+            // Items are only added when a request is written, which happens on this task.
+            return Poll::Pending;
+        }
+
+        while self
+            .as_mut()
+            .project()
+            .transport
+            .poll_ready(cx)?
+            .is_pending()
+        {
+            ready!(self.as_mut().project().transport.poll_flush(cx)?);
+        }
+
+        match self.as_mut().project().outgoing_items.poll_next_unpin(cx) {
+            Poll::Ready(Some(item)) => Poll::Ready(Some(Ok(item))),
+            // The last streaming request just sent its last item.
+            Poll::Ready(None) | Poll::Pending => Poll::Pending,
+        }
+    }
+
+    /// Yields the next health check, if one is ready to be sent.
+    fn poll_next_health_check(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> PollIo<ClientMessage<Req>> {
+        while self
+            .as_mut()
+            .project()
+            .transport
+            .poll_ready(cx)?
+            .is_pending()
+        {
+            ready!(self.as_mut().project().transport.poll_flush(cx)?);
+        }
+
+        loop {
+            let this = self.as_mut().project();
+            match ready!(this.health_checks.poll_next_unpin(cx)) {
+                Some(check) if !check.is_canceled() => {
+                    let check_id = *this.next_health_check_id;
+                    *this.next_health_check_id += 1;
+                    this.pending_health_checks.insert(
+                        check_id,
+                        PendingHealthCheck {
+                            answer: check,
+                            sent: Instant::now(),
+                            sent_at: this.config.clock.now(),
+                        },
+                    );
+                    return Poll::Ready(Some(Ok(ClientMessage::HealthCheck { check_id })));
+                }
+                // No one is waiting for the answer.
+                Some(_) => {}
+                // Every channel is gone, so no more checks will come.
+                None => return Poll::Pending,
+            }
+        }
+    }
+
+    /// Yields the next grant of credit for a streamed reply, if one is ready to be sent.
+    fn poll_next_window_update(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> PollIo<ClientMessage<Req>> {
+        while self
+            .as_mut()
+            .project()
+            .transport
+            .poll_ready(cx)?
+            .is_pending()
+        {
+            ready!(self.as_mut().project().transport.poll_flush(cx)?);
+        }
+
+        loop {
+            // The dispatch holds a sender, so the receiver never ends.
+            match ready!(self.as_mut().project().window_updates.poll_next(cx)) {
+                Some((request_id, credits)) => {
+                    // If the reply ended, the server needs no more credit.
+                    if let Some(in_flight_data) = self
+                        .as_mut()
+                        .project()
+                        .in_flight_requests
+                        .get_mut(&request_id)
+                    {
+                        in_flight_data.reply_credits =
+                            in_flight_data.reply_credits.saturating_add(credits);
+                        return Poll::Ready(Some(Ok(ClientMessage::WindowUpdate {
+                            request_id,
+                            credits,
+                        })));
+                    }
+                }
+                None => return Poll::Pending,
+            }
+        }
+    }
+
     /// Yields the next pending cancellation, and, if one is ready, cancels the associated request.
     fn poll_next_cancellation(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> PollIo<(context::Context, u64)> {
-        while let Poll::Pending = self.as_mut().project().transport.poll_ready(cx)? {
+        while self
+            .as_mut()
+            .project()
+            .transport
+            .poll_ready(cx)?
+            .is_pending()
+        {
             ready!(self.as_mut().project().transport.poll_flush(cx)?);
         }
 
@@ -363,6 +1590,15 @@ where
                         .remove(&request_id)
                     {
                         self.as_mut().project().in_flight_requests.compact(0.1);
+                        self.count_in_flight();
+                        if in_flight_data.streams_items {
+                            self.as_mut().end_outgoing_items(request_id);
+                        }
+                        // Requests are canceled either because they expired, which fails them,
+                        // or because the caller stopped waiting for them.
+                        if in_flight_data.ctx.deadline <= self.config.clock.now() {
+                            self.counters.error();
+                        }
                         debug!("[{}] Removed request.", in_flight_data.ctx.trace_id());
                         return Poll::Ready(Some(Ok((in_flight_data.ctx, request_id))));
                     }
@@ -377,25 +1613,118 @@ where
         dispatch_request: DispatchRequest<Req, Resp>,
     ) -> io::Result<()> {
         let request_id = dispatch_request.request_id;
-        let request = ClientMessage::Request(Request {
+        let request = Request {
             id: request_id,
+            method: None,
             message: dispatch_request.request,
-            context: context::Context {
-                deadline: dispatch_request.ctx.deadline,
-                trace_context: dispatch_request.ctx.trace_context,
-            },
-        });
-        self.as_mut().project().transport.start_send(request)?;
+            context: dispatch_request.ctx.clone(),
+        };
+        let streams_items = dispatch_request.items.is_some();
+        if let ResponseCompletion::Stream(_) = dispatch_request.response_completion {
+            // Grant the server its initial window for the reply's items.
+            let window = self.config.stream_window;
+            let _ = self.window_updates_tx.unbounded_send((request_id, window));
+        }
+        match dispatch_request.items {
+            Some(items) => {
+                self.as_mut()
+                    .project()
+                    .transport
+                    .start_send(ClientMessage::StreamingRequest(request))?;
+                self.as_mut().project().outgoing_items.push(OutgoingItems {
+                    request_id,
+                    items: Some(items),
+                    credits: 0,
+                    held: None,
+                    blocked: None,
+                });
+            }
+            None => self
+                .as_mut()
+                .project()
+                .transport
+                .start_send(ClientMessage::Request(request))?,
+        }
+        self.counters.requests.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "alloc-audit")]
+        self.counters.allocations.request();
+        *self.as_mut().project().requests_sent += 1;
         self.as_mut().project().in_flight_requests.insert(
             request_id,
             InFlightData {
                 ctx: dispatch_request.ctx,
+                streams_items,
+                reply_credits: 0,
+                progress: dispatch_request.progress,
                 response_completion: dispatch_request.response_completion,
             },
         );
+        self.count_in_flight();
         Ok(())
     }
 
+    /// Updates the count of requests in flight shared with the channels.
+    fn count_in_flight(&self) {
+        let in_flight = self.in_flight_requests.len();
+        self.counters.in_flight.store(in_flight, Ordering::Relaxed);
+    }
+
+    /// Forwards a notification pushed by the server to the latest subscriber.
+    fn notify(self: Pin<&mut Self>, notification: Resp) {
+        let this = self.project();
+        while let Ok(subscriber) = this.subscriptions.try_recv() {
+            *this.notifications = Some(subscriber);
+        }
+        let delivered = match this.notifications {
+            Some(subscriber) => subscriber.unbounded_send(notification).is_ok(),
+            None => false,
+        };
+        if !delivered {
+            trace!("Dropped a notification, because no one is subscribed.");
+        }
+    }
+
+    /// Lets the items of request `request_id` spend `credits` more credit.
+    fn grant_outgoing_items(self: Pin<&mut Self>, request_id: u64, credits: u32) {
+        for items in self.project().outgoing_items.iter_mut() {
+            if items.request_id == request_id {
+                items.credits = items.credits.saturating_add(credits);
+                if let Some(blocked) = items.blocked.take() {
+                    blocked.wake();
+                }
+            }
+        }
+    }
+
+    /// Forwards the progress the server reports for request `request_id` to the caller, if the
+    /// caller asked for it.
+    fn report_progress(self: Pin<&mut Self>, request_id: u64, progress: Resp) {
+        let delivered = match self.in_flight_requests.get(&request_id) {
+            Some(InFlightData {
+                progress: Some(updates),
+                ..
+            }) => updates.unbounded_send(progress).is_ok(),
+            _ => false,
+        };
+        if !delivered {
+            trace!(
+                "Dropped progress for request {}, because no one is listening.",
+                request_id
+            );
+        }
+    }
+
+    /// Stops sending the items of request `request_id`, because the call has ended.
+    fn end_outgoing_items(self: Pin<&mut Self>, request_id: u64) {
+        for items in self.project().outgoing_items.iter_mut() {
+            if items.request_id == request_id {
+                // Dropping the receiver fails any further sends to the request's sink.
+                items.items = None;
+                items.held = None;
+            }
+        }
+    }
+
     fn write_cancel(
         mut self: Pin<&mut Self>,
         context: context::Context,
@@ -411,40 +1740,345 @@ where
         Ok(())
     }
 
-    /// Sends a server response to the client task that initiated the associated request.
-    fn complete(mut self: Pin<&mut Self>, response: Response<Resp>) -> bool {
-        if let Some(in_flight_data) = self
-            .as_mut()
-            .project()
-            .in_flight_requests
-            .remove(&response.request_id)
+    /// Takes the channels' requests to shut down, starting to on the first: the requests waiting
+    /// to be written and those in flight fail, and no more are taken.
+    fn poll_shutdowns(mut self: Pin<&mut Self>, cx: &mut Context<'_>) {
+        let this = self.as_mut().project();
+        let mut asked = Vec::new();
+        while let Poll::Ready(Some(ended)) = this.shutdowns.poll_next_unpin(cx) {
+            asked.push(ended);
+        }
+        if asked.is_empty() {
+            return;
+        }
+        if let Some(ref mut shutting_down) = this.shutting_down {
+            shutting_down._ended.extend(asked);
+            return;
+        }
+        info!("Shutdown: asked by the client.");
+        let pending_requests = this.pending_requests.get_mut().get_mut();
+        pending_requests.close();
+        while let Ok(request) = pending_requests.try_recv() {
+            this.counters.error();
+            request.response_completion.fail(Response {
+                request_id: request.request_id,
+                message: Err(shut_down()),
+            });
+        }
+        let mut cancels = VecDeque::with_capacity(this.in_flight_requests.len());
+        for (request_id, in_flight_data) in this.in_flight_requests.drain() {
+            this.counters.error();
+            in_flight_data.response_completion.fail(Response {
+                request_id,
+                message: Err(shut_down()),
+            });
+            cancels.push_back((in_flight_data.ctx, request_id));
+        }
+        *this.outgoing_items = SelectAll::new();
+        *this.shutting_down = Some(ShuttingDown {
+            deadline: tokio::time::delay_for(this.config.shutdown_timeout),
+            cancels,
+            _ended: asked,
+        });
+        self.count_in_flight();
+    }
+
+    /// Tells the server to stop working on the requests that were in flight when dispatch shut
+    /// down, and closes the transport. Resolves once the transport is closed, fails, or the
+    /// shutdown timeout passes.
+    fn poll_shut_down(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let shutting_down = self.as_mut().project().shutting_down.as_mut().unwrap();
+        if shutting_down.deadline.poll_unpin(cx).is_ready() {
+            info!("Shutdown: gave up on closing the transport.");
+            return Poll::Ready(());
+        }
+        let closed = loop {
+            match ready!(self.as_mut().project().transport.poll_ready(cx)) {
+                Ok(()) => {}
+                Err(e) => break Err(e),
+            }
+            let this = self.as_mut().project();
+            let (context, request_id) =
+                match this.shutting_down.as_mut().unwrap().cancels.pop_front() {
+                    Some(cancel) => cancel,
+                    None => break Ok(()),
+                };
+            if let Err(e) = self.as_mut().write_cancel(context, request_id) {
+                break Err(e);
+            }
+        };
+        let closed = match closed {
+            Ok(()) => ready!(self.as_mut().project().transport.poll_close(cx)),
+            Err(e) => Err(e),
+        };
+        match closed {
+            Ok(()) => info!("Shutdown: transport closed."),
+            Err(e) => info!("Shutdown: failed to close the transport: {}", e),
+        }
+        Poll::Ready(())
+    }
+
+    /// Sends a server message to the client task that initiated the associated request.
+    /// Fails if the message isn't one the client understands.
+    fn complete(mut self: Pin<&mut Self>, message: ServerMessage<Resp>) -> io::Result<bool> {
+        if let ServerMessage::_NonExhaustive = message {
+            return Err(unknown_message());
+        }
+        // A request the server rejected fails with the equivalent server error.
+        let message = match message {
+            ServerMessage::Error(frame) => match frame.id {
+                Some(request_id) => ServerMessage::Response(Response {
+                    request_id,
+                    message: Err(frame.into()),
+                }),
+                None => {
+                    warn!("Server stopped reading from the connection: {}", frame);
+                    *self.project().protocol_error = Some(frame);
+                    return Ok(true);
+                }
+            },
+            message => message,
+        };
+        if let ServerMessage::WindowUpdate {
+            request_id,
+            credits,
+        } = message
+        {
+            trace!("Received {} credits for request {}.", credits, request_id);
+            self.grant_outgoing_items(request_id, credits);
+            return Ok(true);
+        }
+        if let ServerMessage::Progress {
+            request_id,
+            progress,
+        } = message
+        {
+            self.report_progress(request_id, progress);
+            return Ok(true);
+        }
+        if let ServerMessage::Load(load) = message {
+            trace!("Server reported load {}.", load);
+            *self.server_load.lock().unwrap() = Some(load);
+            return Ok(true);
+        }
+        if let ServerMessage::RequestCredit { credits } = message {
+            trace!("Received credit for {} requests.", credits);
+            *self.as_mut().project().credits_granted.get_or_insert(0) += u64::from(credits);
+            return Ok(true);
+        }
+        if let ServerMessage::Handshake {
+            reply_order,
+            session,
+        } = message
         {
-            self.as_mut().project().in_flight_requests.compact(0.1);
-
-            trace!("[{}] Received response.", in_flight_data.ctx.trace_id());
-            let _ = in_flight_data.response_completion.send(response);
-            return true;
+            debug!(
+                "Server agreed to send replies in {:?} order, in session {:?}.",
+                reply_order, session
+            );
+            *self.session.lock().unwrap() = session;
+            return Ok(true);
+        }
+        if let ServerMessage::GoAway = message {
+            info!("Server is draining; it asked the client to go away.");
+            self.going_away.store(true, Ordering::Relaxed);
+            return Ok(true);
+        }
+        if let ServerMessage::Health {
+            check_id,
+            serving,
+            time,
+        } = message
+        {
+            if let Some(check) = self
+                .as_mut()
+                .project()
+                .pending_health_checks
+                .remove(&check_id)
+            {
+                let rtt = check.sent.elapsed();
+                // The server answered halfway through the round trip, as far as the client can
+                // tell.
+                let clock_offset_micros = time.map(|time| {
+                    let midpoint = check.sent_at + rtt / 2;
+                    match time.duration_since(midpoint) {
+                        Ok(ahead) => ahead.as_micros() as i64,
+                        Err(behind) => -(behind.duration().as_micros() as i64),
+                    }
+                });
+                self.counters.sample_clock(rtt, clock_offset_micros);
+                let _ = check.answer.send(serving);
+            }
+            return Ok(true);
         }
 
-        debug!(
-            "No in-flight request found for request_id = {}.",
-            response.request_id
-        );
+        let request_id = match message.request_id() {
+            Some(request_id) => request_id,
+            None => {
+                if let ServerMessage::Notification(notification) = message {
+                    self.notify(notification);
+                }
+                return Ok(true);
+            }
+        };
+        let in_flight_requests = self.as_mut().project().in_flight_requests;
+        let is_stream = match in_flight_requests.get(&request_id) {
+            Some(in_flight_data) => match in_flight_data.response_completion {
+                ResponseCompletion::Unary(_) => false,
+                ResponseCompletion::Stream(_) => true,
+            },
+            None => {
+                // Replies to requests that are no longer in flight are counted and dropped.
+                if self.completions.contains(request_id) {
+                    debug!("Dropping a duplicate reply to request {}.", request_id);
+                    self.counters
+                        .duplicate_replies
+                        .fetch_add(1, Ordering::Relaxed);
+                } else {
+                    // The request was canceled, or its deadline passed.
+                    debug!("Dropping a stale reply to request {}.", request_id);
+                    self.counters.stale_replies.fetch_add(1, Ordering::Relaxed);
+                }
+                return Ok(false);
+            }
+        };
+
+        // A streamed reply stays in flight until its final message.
+        if is_stream && !message.is_final() {
+            let in_flight_data = in_flight_requests.get_mut(&request_id).unwrap();
+            trace!("[{}] Received stream item.", in_flight_data.ctx.trace_id());
+            // Items are only queued for the caller within the credit granted, so the queue holds
+            // at most a window of them.
+            if in_flight_data.reply_credits == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "The server sent an item of request {} without credit.",
+                        request_id
+                    ),
+                ));
+            }
+            in_flight_data.reply_credits -= 1;
+            if let ResponseCompletion::Stream(ref items) = in_flight_data.response_completion {
+                let _ = items.unbounded_send(message);
+            }
+            return Ok(true);
+        }
 
-        // If the response completion was absent, then the request was already canceled.
-        false
+        let in_flight_data = in_flight_requests.remove(&request_id).unwrap();
+        in_flight_requests.compact(0.1);
+        self.as_mut().project().completions.insert(request_id);
+        // Counted before the response is delivered, so the caller sees it complete.
+        self.count_in_flight();
+        trace!("[{}] Received response.", in_flight_data.ctx.trace_id());
+        if let ServerMessage::Response(Response {
+            message: Err(_), ..
+        }) = message
+        {
+            self.counters.error();
+        }
+        if in_flight_data.streams_items {
+            self.as_mut().end_outgoing_items(request_id);
+        }
+        match in_flight_data.response_completion {
+            ResponseCompletion::Stream(items) => {
+                let _ = items.unbounded_send(message);
+            }
+            ResponseCompletion::Unary(response_completion) => {
+                let response = match message {
+                    ServerMessage::Response(response) => response,
+                    _ => {
+                        debug!(
+                            "[{}] Expected a single response, but the server streamed its reply.",
+                            in_flight_data.ctx.trace_id()
+                        );
+                        Response {
+                            request_id,
+                            message: Err(ServerError {
+                                kind: io::ErrorKind::InvalidData,
+                                detail: Some(
+                                    "Expected a single response, but the server streamed its \
+                                     reply."
+                                        .into(),
+                                ),
+                                retry_after: None,
+                            }),
+                        }
+                    }
+                };
+                let _ = response_completion.send(response);
+            }
+        }
+        Ok(true)
     }
 }
 
 impl<Req, Resp, C> Future for RequestDispatch<Req, Resp, C>
 where
-    C: Transport<ClientMessage<Req>, Response<Resp>>,
+    C: Transport<ClientMessage<Req>, ServerMessage<Resp>>,
 {
     type Output = io::Result<()>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // A panic ends dispatch with an error, so that the channel reports it's disconnected and
+        // its requests fail, rather than the panic silently killing the task running dispatch.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| self.as_mut().run_audited(cx)))
+            .unwrap_or_else(|panic| {
+                let message = format!("Request dispatch panicked: {}", panic_message(&*panic));
+                error!("{}", message);
+                Poll::Ready(Err(io::Error::other(message)))
+            });
+        if result.is_ready() {
+            // The requests still in flight fail, since their responses can no longer arrive.
+            let in_flight = self.in_flight_requests.len() as u64;
+            self.counters.errors.fetch_add(in_flight, Ordering::Relaxed);
+            self.counters.in_flight.store(0, Ordering::Relaxed);
+            self.counters.connected.store(false, Ordering::Relaxed);
+            if let Poll::Ready(Err(ref e)) = result {
+                *self.dispatch_error.lock().unwrap() = Some((e.kind(), e.to_string()));
+            }
+            // Dropping the shutdown wakes the channels waiting on it.
+            if self.as_mut().project().shutting_down.take().is_some() {
+                let e = io::Error::from(shut_down());
+                *self.dispatch_error.lock().unwrap() = Some((e.kind(), e.to_string()));
+            }
+        }
+        result
+    }
+}
+
+impl<Req, Resp, C> RequestDispatch<Req, Resp, C>
+where
+    C: Transport<ClientMessage<Req>, ServerMessage<Resp>>,
+{
+    /// Runs dispatch, counting the allocations it makes toward the channel's requests.
+    #[cfg(feature = "alloc-audit")]
+    fn run_audited(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let counters = self.counters.clone();
+        crate::alloc_audit::audit(&counters.allocations, || self.as_mut().run(cx))
+    }
+
+    #[cfg(not(feature = "alloc-audit"))]
+    fn run_audited(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.run(cx)
+    }
+
+    fn run(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.as_mut().poll_shutdowns(cx);
+        if self.shutting_down.is_some() {
+            ready!(self.as_mut().poll_shut_down(cx));
+            return Poll::Ready(Ok(()));
+        }
         loop {
             match (self.as_mut().pump_read(cx)?, self.as_mut().pump_write(cx)?) {
+                (Poll::Ready(None), _) => {
+                    // In-flight requests fail, since their responses can no longer arrive.
+                    info!("Shutdown: read half closed.");
+                    // The server hung up because of the error it reported.
+                    return Poll::Ready(match self.as_mut().project().protocol_error.take() {
+                        Some(frame) => Err(ServerError::from(frame).into()),
+                        None => Ok(()),
+                    });
+                }
                 (read, Poll::Ready(None)) => {
                     if self.as_mut().project().in_flight_requests.is_empty() {
                         info!("Shutdown: write half closed, and no requests in flight.");
@@ -473,13 +2107,127 @@ struct DispatchRequest<Req, Resp> {
     ctx: context::Context,
     request_id: u64,
     request: Req,
-    response_completion: oneshot::Sender<Response<Resp>>,
+    /// The items to send after the request, if it is a streaming request.
+    items: Option<mpsc::Receiver<Req>>,
+    /// Where to send the progress the server reports, if the caller asked for it.
+    progress: Option<mpsc::UnboundedSender<Resp>>,
+    response_completion: ResponseCompletion<Resp>,
+}
+
+/// The items of a streaming request that has been written to the wire.
+#[derive(Debug)]
+struct OutgoingItems<Req> {
+    request_id: u64,
+    /// None once the end of the items has been yielded.
+    items: Option<mpsc::Receiver<Req>>,
+    /// The number of items the server has room for.
+    credits: u32,
+    /// An item waiting for the server to grant credit.
+    held: Option<Req>,
+    /// Woken when the server grants more credit.
+    blocked: Option<Waker>,
+}
+
+// The held item is never pinned.
+impl<Req> Unpin for OutgoingItems<Req> {}
+
+impl<Req> Stream for OutgoingItems<Req> {
+    type Item = ClientMessage<Req>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<ClientMessage<Req>>> {
+        let request_id = self.request_id;
+        let this = &mut *self;
+        let items = match this.items {
+            Some(ref mut items) => items,
+            None => return Poll::Ready(None),
+        };
+        let item = match this.held.take() {
+            Some(item) => Some(item),
+            None => ready!(items.poll_next_unpin(cx)),
+        };
+        Poll::Ready(Some(match item {
+            Some(item) if self.credits == 0 => {
+                self.held = Some(item);
+                self.blocked = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            Some(item) => {
+                self.credits -= 1;
+                ClientMessage::StreamItem { request_id, item }
+            }
+            None => {
+                self.items = None;
+                ClientMessage::StreamEnd { request_id }
+            }
+        }))
+    }
+}
+
+/// Request dispatch shutting down, because a channel asked it to.
+#[derive(Debug)]
+struct ShuttingDown {
+    /// Elapses once dispatch has waited for the transport for the shutdown timeout.
+    deadline: Delay,
+    /// The requests that were in flight, whose cancellations have yet to be written.
+    cancels: VecDeque<(context::Context, u64)>,
+    /// Dropped once dispatch ends, to tell the channels that asked it to shut down.
+    _ended: Vec<oneshot::Sender<()>>,
+}
+
+/// A health check written to the wire, waiting to be answered.
+#[derive(Debug)]
+struct PendingHealthCheck {
+    answer: oneshot::Sender<bool>,
+    /// When the check was written, to time its round trip.
+    sent: Instant,
+    /// The client's clock when the check was written, to compare with the server's.
+    sent_at: SystemTime,
 }
 
 #[derive(Debug)]
 struct InFlightData<Resp> {
     ctx: context::Context,
-    response_completion: oneshot::Sender<Response<Resp>>,
+    /// Whether the request's items are still being sent.
+    streams_items: bool,
+    /// The number of items of the request's streamed reply the client has room for.
+    reply_credits: u32,
+    /// Where to send the progress the server reports, if the caller asked for it.
+    progress: Option<mpsc::UnboundedSender<Resp>>,
+    response_completion: ResponseCompletion<Resp>,
+}
+
+/// Where to send the reply to a request.
+#[derive(Debug)]
+enum ResponseCompletion<Resp> {
+    /// The request expects a single response.
+    Unary(oneshot::Sender<Response<Resp>>),
+    /// The request expects a streamed reply; every message for the request is forwarded.
+    Stream(mpsc::UnboundedSender<ServerMessage<Resp>>),
+}
+
+impl<Resp> ResponseCompletion<Resp> {
+    /// Completes the request with `response`, an error the client made up for it.
+    fn fail(self, response: Response<Resp>) {
+        match self {
+            ResponseCompletion::Unary(response_completion) => {
+                let _ = response_completion.send(response);
+            }
+            ResponseCompletion::Stream(items) => {
+                let _ = items.unbounded_send(ServerMessage::Response(response));
+            }
+        }
+    }
+
+    /// Returns true if the client is no longer waiting for the reply.
+    fn is_canceled(&self) -> bool {
+        match self {
+            ResponseCompletion::Unary(response_completion) => response_completion.is_canceled(),
+            ResponseCompletion::Stream(items) => items.is_closed(),
+        }
+    }
 }
 
 /// Sends request cancellation signals.
@@ -522,13 +2270,16 @@ struct MapErrConnectionReset<Fut> {
     #[pin]
     future: Fut,
     finished: Option<()>,
+    /// Set once the client is shut down, in which case the error says so instead.
+    shut_down: Arc<AtomicBool>,
 }
 
 impl<Fut> MapErrConnectionReset<Fut> {
-    fn new(future: Fut) -> MapErrConnectionReset<Fut> {
+    fn new(future: Fut, shut_down: Arc<AtomicBool>) -> MapErrConnectionReset<Fut> {
         MapErrConnectionReset {
             future,
             finished: Some(()),
+            shut_down,
         }
     }
 }
@@ -543,10 +2294,17 @@ where
         match self.as_mut().project().future.try_poll(cx) {
             Poll::Pending => Poll::Pending,
             Poll::Ready(result) => {
-                self.project().finished.take().expect(
+                self.as_mut().project().finished.take().expect(
                     "MapErrConnectionReset must not be polled after it returned `Poll::Ready`",
                 );
-                Poll::Ready(result.map_err(|_| io::Error::from(io::ErrorKind::ConnectionReset)))
+                let is_shut_down = self.shut_down.load(Ordering::SeqCst);
+                Poll::Ready(result.map_err(|_| {
+                    if is_shut_down {
+                        shut_down().into()
+                    } else {
+                        io::Error::from(io::ErrorKind::ConnectionReset)
+                    }
+                }))
             }
         }
     }
@@ -628,7 +2386,7 @@ where
     }
 }
 
-#[pin_project]
+#[pin_project(project = TryChainProj)]
 #[must_use = "futures do nothing unless polled"]
 #[derive(Debug)]
 enum TryChain<Fut1, Fut2> {
@@ -654,7 +2412,6 @@ where
         TryChain::First(fut1)
     }
 
-    #[project]
     fn poll<F>(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
@@ -666,20 +2423,19 @@ where
         let mut f = Some(f);
 
         loop {
-            #[project]
             let output = match self.as_mut().project() {
-                TryChain::First(fut1) => {
+                TryChainProj::First(fut1) => {
                     // Poll the first future
                     match fut1.try_poll(cx) {
                         Poll::Pending => return Poll::Pending,
                         Poll::Ready(output) => output,
                     }
                 }
-                TryChain::Second(fut2) => {
+                TryChainProj::Second(fut2) => {
                     // Poll the second future
                     return fut2.try_poll(cx);
                 }
-                TryChain::Empty => {
+                TryChainProj::Empty => {
                     panic!("future must not be polled after it returned `Poll::Ready`");
                 }
             };
@@ -697,22 +2453,32 @@ where
 #[cfg(test)]
 mod tests {
     use super::{
-        cancellations, CanceledRequests, Channel, DispatchResponse, RequestCancellation,
-        RequestDispatch,
+        cancellations, new, CanceledRequests, Channel, Completions, Counters, DispatchResponse,
+        RequestCancellation, RequestDispatch, Shared,
     };
     use crate::{
-        client::Config,
+        client::{Config, RequestIds, RetryBudget},
         context,
         transport::{self, channel::UnboundedChannel},
-        ClientMessage, Response,
+        ClientMessage, Response, ServerMessage,
     };
+    use assert_matches::assert_matches;
     use fnv::FnvHashMap;
     use futures::{
         channel::{mpsc, oneshot},
         prelude::*,
+        stream::SelectAll,
         task::*,
     };
-    use std::{pin::Pin, sync::atomic::AtomicU64, sync::Arc};
+    use std::{
+        io,
+        pin::Pin,
+        sync::{
+            atomic::{AtomicBool, AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+        time::{Duration, SystemTime},
+    };
 
     #[tokio::test(threaded_scheduler)]
     async fn dispatch_response_cancels_on_drop() {
@@ -721,19 +2487,20 @@ mod tests {
         drop(DispatchResponse::<u32> {
             response,
             cancellation,
+            canceled: context::current().canceled(),
             complete: false,
             request_id: 3,
             ctx: context::current(),
         });
         // resp's drop() is run, which should send a cancel message.
-        assert_eq!(canceled_requests.0.try_next().unwrap(), Some(3));
+        assert_eq!(canceled_requests.0.try_recv().unwrap(), 3);
     }
 
     #[tokio::test(threaded_scheduler)]
     async fn stage_request() {
         let (mut dispatch, mut channel, _server_channel) = set_up();
         let dispatch = Pin::new(&mut dispatch);
-        let cx = &mut Context::from_waker(&noop_waker_ref());
+        let cx = &mut Context::from_waker(noop_waker_ref());
 
         let _resp = send_request(&mut channel, "hi").await;
 
@@ -745,14 +2512,32 @@ mod tests {
         assert_eq!(req.request, "hi".to_string());
     }
 
+    #[tokio::test(threaded_scheduler)]
+    async fn clones_share_the_connection() {
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+        let (mut dispatch, mut channel, _server_channel) = set_up();
+        assert_send_sync(&channel);
+        let mut dispatch = Pin::new(&mut dispatch);
+        let cx = &mut Context::from_waker(noop_waker_ref());
+
+        let mut clone = channel.clone();
+        assert!(Arc::ptr_eq(&channel.shared, &clone.shared));
+        let _first = send_request(&mut channel, "hi").await;
+        let first = dispatch.as_mut().poll_next_request(cx).ready().unwrap();
+        let _second = send_request(&mut clone, "there").await;
+        let second = dispatch.as_mut().poll_next_request(cx).ready().unwrap();
+        assert_eq!((first.request_id, second.request_id), (0, 1));
+    }
+
     // Regression test for  https://github.com/google/tarpc/issues/220
     #[tokio::test(threaded_scheduler)]
     async fn stage_request_channel_dropped_doesnt_panic() {
         let (mut dispatch, mut channel, mut server_channel) = set_up();
         let mut dispatch = Pin::new(&mut dispatch);
-        let cx = &mut Context::from_waker(&noop_waker_ref());
+        let cx = &mut Context::from_waker(noop_waker_ref());
 
-        let _ = send_request(&mut channel, "hi").await;
+        drop(send_request(&mut channel, "hi").await);
         drop(channel);
 
         assert!(dispatch.as_mut().poll(cx).is_ready());
@@ -761,7 +2546,8 @@ mod tests {
             Response {
                 request_id: 0,
                 message: Ok("hello".into()),
-            },
+            }
+            .into(),
         )
         .await;
         dispatch.await.unwrap();
@@ -771,9 +2557,9 @@ mod tests {
     async fn stage_request_response_future_dropped_is_canceled_before_sending() {
         let (mut dispatch, mut channel, _server_channel) = set_up();
         let dispatch = Pin::new(&mut dispatch);
-        let cx = &mut Context::from_waker(&noop_waker_ref());
+        let cx = &mut Context::from_waker(noop_waker_ref());
 
-        let _ = send_request(&mut channel, "hi").await;
+        drop(send_request(&mut channel, "hi").await);
 
         // Drop the channel so polling returns none if no requests are currently ready.
         drop(channel);
@@ -785,7 +2571,7 @@ mod tests {
     #[tokio::test(threaded_scheduler)]
     async fn stage_request_response_future_dropped_is_canceled_after_sending() {
         let (mut dispatch, mut channel, _server_channel) = set_up();
-        let cx = &mut Context::from_waker(&noop_waker_ref());
+        let cx = &mut Context::from_waker(noop_waker_ref());
         let mut dispatch = Pin::new(&mut dispatch);
 
         let req = send_request(&mut channel, "hi").await;
@@ -808,7 +2594,7 @@ mod tests {
     async fn stage_request_response_closed_skipped() {
         let (mut dispatch, mut channel, _server_channel) = set_up();
         let dispatch = Pin::new(&mut dispatch);
-        let cx = &mut Context::from_waker(&noop_waker_ref());
+        let cx = &mut Context::from_waker(noop_waker_ref());
 
         // Test that a request future that's closed its receiver but not yet canceled its request --
         // i.e. still in `drop fn` -- will cause the request to not be added to the in-flight request
@@ -819,30 +2605,487 @@ mod tests {
         assert!(dispatch.poll_next_request(cx).is_pending());
     }
 
+    #[tokio::test(threaded_scheduler)]
+    async fn stream_response_yields_items_until_end() {
+        let (mut dispatch, mut channel, mut server_channel) = set_up();
+        let mut dispatch = Pin::new(&mut dispatch);
+        let cx = &mut Context::from_waker(noop_waker_ref());
+
+        let mut items = channel
+            .call_stream(context::current(), "hi".into())
+            .await
+            .unwrap();
+        // The request and the initial window.
+        for _ in 0..2 {
+            assert!(dispatch.as_mut().pump_write(cx).ready().is_some());
+        }
+
+        for message in [
+            ServerMessage::StreamItem {
+                request_id: 0,
+                item: "a".to_string(),
+            },
+            ServerMessage::StreamItem {
+                request_id: 0,
+                item: "b".to_string(),
+            },
+            ServerMessage::StreamEnd { request_id: 0 },
+        ] {
+            send_response(&mut server_channel, message).await;
+            assert!(dispatch.as_mut().pump_read(cx).ready().is_some());
+        }
+        assert!(dispatch.as_mut().project().in_flight_requests.is_empty());
+
+        assert_eq!(items.next().await.unwrap().unwrap(), "a");
+        assert_eq!(items.next().await.unwrap().unwrap(), "b");
+        assert!(items.next().await.is_none());
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn stream_items_without_credit_are_protocol_errors() {
+        let (mut dispatch, mut channel, mut server_channel) = set_up();
+        let mut dispatch = Pin::new(&mut dispatch);
+        let cx = &mut Context::from_waker(noop_waker_ref());
+
+        let _items = channel
+            .call_stream(context::current(), "hi".into())
+            .await
+            .unwrap();
+        // The request, but not yet the initial window.
+        assert!(dispatch.as_mut().pump_write(cx).ready().is_some());
+
+        send_response(
+            &mut server_channel,
+            ServerMessage::StreamItem {
+                request_id: 0,
+                item: "a".to_string(),
+            },
+        )
+        .await;
+        assert_matches!(
+            dispatch.as_mut().pump_read(cx),
+            Poll::Ready(Some(Err(e))) if e.kind() == io::ErrorKind::InvalidData
+        );
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn request_sink_waits_for_room() {
+        let (mut dispatch, mut channel, _server_channel) = set_up();
+        let mut dispatch = Pin::new(&mut dispatch);
+        let cx = &mut Context::from_waker(noop_waker_ref());
+
+        let (mut sink, _response) = channel
+            .call_with_items(context::current(), "hi".into())
+            .await
+            .unwrap();
+        assert!(dispatch.as_mut().pump_write(cx).ready().is_some());
+
+        // The server grants no credit, so the sink fills up once it buffers a window of items.
+        let window = Config::default().stream_window as usize;
+        for _ in 0..=window {
+            sink.send("a".into()).await.unwrap();
+        }
+        assert!(sink.poll_ready_unpin(cx).is_pending());
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn unknown_messages_are_protocol_errors() {
+        let (mut dispatch, _channel, mut server_channel) = set_up();
+        let mut dispatch = Pin::new(&mut dispatch);
+        let cx = &mut Context::from_waker(noop_waker_ref());
+
+        send_response(&mut server_channel, ServerMessage::_NonExhaustive).await;
+        assert_matches!(
+            dispatch.as_mut().pump_read(cx),
+            Poll::Ready(Some(Err(e))) if e.kind() == io::ErrorKind::InvalidData
+        );
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn call_with_items_sends_items_after_request() {
+        let (mut dispatch, mut channel, mut server_channel) = set_up();
+        let mut dispatch = Pin::new(&mut dispatch);
+        let cx = &mut Context::from_waker(noop_waker_ref());
+
+        let (mut items, _response) = channel
+            .call_with_items(context::current(), "hi".into())
+            .await
+            .unwrap();
+        items.send("a".into()).await.unwrap();
+        items.send("b".into()).await.unwrap();
+        drop(items);
+        send_response(
+            &mut server_channel,
+            ServerMessage::WindowUpdate {
+                request_id: 0,
+                credits: 2,
+            },
+        )
+        .await;
+        assert!(dispatch.as_mut().pump_write(cx).ready().is_some());
+        assert!(dispatch.as_mut().pump_read(cx).ready().is_some());
+        // Two items and the end of the items.
+        for _ in 0..3 {
+            assert!(dispatch.as_mut().pump_write(cx).ready().is_some());
+        }
+
+        assert_matches!(
+            server_channel.next().await,
+            Some(Ok(ClientMessage::StreamingRequest(request))) if request.message == "hi"
+        );
+        for expected in &["a", "b"] {
+            assert_matches!(
+                server_channel.next().await,
+                Some(Ok(ClientMessage::StreamItem { request_id: 0, item })) if item == *expected
+            );
+        }
+        assert_matches!(
+            server_channel.next().await,
+            Some(Ok(ClientMessage::StreamEnd { request_id: 0 }))
+        );
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn call_bidirectional_stops_items_when_reply_ends() {
+        let (mut dispatch, mut channel, mut server_channel) = set_up();
+        let mut dispatch = Pin::new(&mut dispatch);
+        let cx = &mut Context::from_waker(noop_waker_ref());
+
+        let (mut items, mut reply) = channel
+            .call_bidirectional(context::current(), "hi".into())
+            .await
+            .unwrap();
+        assert!(dispatch.as_mut().pump_write(cx).ready().is_some());
+        items.send("a".into()).await.unwrap();
+
+        send_response(
+            &mut server_channel,
+            ServerMessage::StreamEnd { request_id: 0 },
+        )
+        .await;
+        assert!(dispatch.as_mut().pump_read(cx).ready().is_some());
+
+        assert!(reply.next().await.is_none());
+        assert_matches!(
+            items.send("b".into()).await,
+            Err(e) if e.kind() == io::ErrorKind::ConnectionReset
+        );
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn request_items_wait_for_credit() {
+        let (mut dispatch, mut channel, mut server_channel) = set_up();
+        let mut dispatch = Pin::new(&mut dispatch);
+        let cx = &mut Context::from_waker(noop_waker_ref());
+
+        let (mut items, _response) = channel
+            .call_with_items(context::current(), "hi".into())
+            .await
+            .unwrap();
+        assert!(dispatch.as_mut().pump_write(cx).ready().is_some());
+        items.send("a".into()).await.unwrap();
+        items.send("b".into()).await.unwrap();
+        assert!(dispatch.as_mut().pump_write(cx).is_pending());
+
+        send_response(
+            &mut server_channel,
+            ServerMessage::WindowUpdate {
+                request_id: 0,
+                credits: 1,
+            },
+        )
+        .await;
+        assert!(dispatch.as_mut().pump_read(cx).ready().is_some());
+        assert!(dispatch.as_mut().pump_write(cx).ready().is_some());
+        assert!(dispatch.as_mut().pump_write(cx).is_pending());
+
+        assert_matches!(
+            server_channel.next().await,
+            Some(Ok(ClientMessage::StreamingRequest(_)))
+        );
+        assert_matches!(
+            server_channel.next().await,
+            Some(Ok(ClientMessage::StreamItem { request_id: 0, item })) if item == "a"
+        );
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn stream_response_grants_credit() {
+        let (mut dispatch, mut channel, mut server_channel) = set_up();
+        let mut dispatch = Pin::new(&mut dispatch);
+        let cx = &mut Context::from_waker(noop_waker_ref());
+
+        let mut items = channel
+            .call_stream(context::current(), "hi".into())
+            .await
+            .unwrap();
+        // The request and the initial window.
+        for _ in 0..2 {
+            assert!(dispatch.as_mut().pump_write(cx).ready().is_some());
+        }
+        assert_matches!(
+            server_channel.next().await,
+            Some(Ok(ClientMessage::Request(_)))
+        );
+        let window = Config::default().stream_window;
+        assert_matches!(
+            server_channel.next().await,
+            Some(Ok(ClientMessage::WindowUpdate { request_id: 0, credits })) if credits == window
+        );
+
+        for _ in 0..window / 2 {
+            send_response(
+                &mut server_channel,
+                ServerMessage::StreamItem {
+                    request_id: 0,
+                    item: "a".to_string(),
+                },
+            )
+            .await;
+            assert!(dispatch.as_mut().pump_read(cx).ready().is_some());
+            assert_eq!(items.next().await.unwrap().unwrap(), "a");
+        }
+        assert!(dispatch.as_mut().pump_write(cx).ready().is_some());
+        assert_matches!(
+            server_channel.next().await,
+            Some(Ok(ClientMessage::WindowUpdate { request_id: 0, credits })) if credits == window / 2
+        );
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn notifications_go_to_latest_subscriber() {
+        let (mut dispatch, channel, mut server_channel) = set_up();
+        let mut dispatch = Pin::new(&mut dispatch);
+        let cx = &mut Context::from_waker(noop_waker_ref());
+
+        let mut first = channel.notifications();
+        send_response(&mut server_channel, ServerMessage::Notification("a".into())).await;
+        assert!(dispatch.as_mut().pump_read(cx).ready().is_some());
+        assert_eq!(first.next().await.unwrap(), "a");
+
+        let mut second = channel.notifications();
+        send_response(&mut server_channel, ServerMessage::Notification("b".into())).await;
+        assert!(dispatch.as_mut().pump_read(cx).ready().is_some());
+        assert!(first.next().await.is_none());
+        assert_eq!(second.next().await.unwrap(), "b");
+    }
+
+    #[tokio::test]
+    async fn progress_precedes_response() {
+        let (mut dispatch, mut channel, mut server_channel) = set_up();
+        let mut dispatch = Pin::new(&mut dispatch);
+        let cx = &mut Context::from_waker(noop_waker_ref());
+
+        let (mut progress, response) = channel
+            .call_with_progress(context::current(), "hi".into())
+            .await
+            .unwrap();
+        assert!(dispatch.as_mut().pump_write(cx).ready().is_some());
+        for update in &["1/2", "2/2"] {
+            send_response(
+                &mut server_channel,
+                ServerMessage::Progress {
+                    request_id: 0,
+                    progress: update.to_string(),
+                },
+            )
+            .await;
+        }
+        send_response(
+            &mut server_channel,
+            ServerMessage::Response(Response {
+                request_id: 0,
+                message: Ok("done".into()),
+            }),
+        )
+        .await;
+        for _ in 0..3 {
+            assert!(dispatch.as_mut().pump_read(cx).ready().is_some());
+        }
+
+        assert_eq!(progress.next().await.unwrap(), "1/2");
+        assert_eq!(progress.next().await.unwrap(), "2/2");
+        // The stream ends with the call.
+        assert!(progress.next().await.is_none());
+        assert_eq!(response.await.unwrap(), "done");
+    }
+
+    #[tokio::test]
+    async fn duplicate_and_stale_replies_are_counted() {
+        let (mut dispatch, mut channel, mut server_channel) = set_up();
+        let mut dispatch = Pin::new(&mut dispatch);
+        let cx = &mut Context::from_waker(noop_waker_ref());
+
+        let completed = send_request(&mut channel, "hi").await;
+        assert!(dispatch.as_mut().pump_write(cx).ready().is_some());
+        let canceled = send_request(&mut channel, "hi").await;
+        assert!(dispatch.as_mut().pump_write(cx).ready().is_some());
+        drop(canceled);
+        assert!(dispatch.as_mut().poll_next_cancellation(cx).is_ready());
+        for request_id in &[0, 0, 1] {
+            send_response(
+                &mut server_channel,
+                ServerMessage::Response(Response {
+                    request_id: *request_id,
+                    message: Ok("hello".into()),
+                }),
+            )
+            .await;
+            assert!(dispatch.as_mut().pump_read(cx).ready().is_some());
+        }
+        assert_eq!(completed.await.unwrap(), "hello");
+        let stats = channel.stats();
+        assert_eq!(stats.duplicate_replies, 1);
+        assert_eq!(stats.stale_replies, 1);
+    }
+
+    #[tokio::test]
+    async fn health_checks_estimate_rtt_and_clock_offset() {
+        let (mut dispatch, channel, mut server_channel) = set_up();
+        let mut dispatch = Pin::new(&mut dispatch);
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        assert_eq!(channel.stats().rtt, None);
+
+        let mut check = Box::pin(channel.check_health());
+        assert!(check.as_mut().poll(cx).is_pending());
+        assert!(dispatch.as_mut().pump_write(cx).ready().is_some());
+        let check_id = match server_channel.next().await {
+            Some(Ok(ClientMessage::HealthCheck { check_id })) => check_id,
+            message => panic!("Expected a health check, got {:?}", message),
+        };
+        // The server's clock is an hour ahead.
+        send_response(
+            &mut server_channel,
+            ServerMessage::Health {
+                check_id,
+                serving: true,
+                time: Some(SystemTime::now() + Duration::from_secs(3_600)),
+            },
+        )
+        .await;
+        assert!(dispatch.as_mut().pump_read(cx).ready().is_some());
+        assert!(check.await.unwrap());
+
+        let stats = channel.stats();
+        assert!(stats.rtt.unwrap() < Duration::from_secs(1));
+        let offset = Duration::from_micros(stats.clock_offset_micros.unwrap() as u64);
+        assert!(offset > Duration::from_secs(3_599) && offset < Duration::from_secs(3_601));
+    }
+
+    #[test]
+    fn epoch_request_ids_are_prefixed() {
+        let config = Config {
+            request_ids: RequestIds::Epoch,
+            ..Config::default()
+        };
+        let first_ids: Vec<u64> = (0..4)
+            .map(|_| {
+                let (transport, _) =
+                    transport::channel::unbounded::<ServerMessage<String>, ClientMessage<String>>();
+                let client = new::<String, String, _>(config.clone(), transport).client;
+                client.shared.next_request_id.load(Ordering::Relaxed)
+            })
+            .collect();
+        assert!(first_ids.iter().all(|id| id & u64::from(u32::MAX) == 0));
+        // Four random epochs are all equal with negligible probability.
+        assert!(first_ids.iter().skip(1).any(|id| *id != first_ids[0]));
+    }
+
+    #[tokio::test]
+    async fn retries_are_budgeted() {
+        let config = Config {
+            retry_budget: Some(RetryBudget {
+                ratio: 0.5,
+                min_retries_per_second: 0,
+            }),
+            ..Config::default()
+        };
+        let (transport, _) =
+            transport::channel::unbounded::<ServerMessage<String>, ClientMessage<String>>();
+        let mut client = new::<String, String, _>(config, transport).client;
+        assert!(!client.permit_retry());
+        for _ in 0..4 {
+            drop(client.call(context::current(), "hi".into()));
+        }
+        // Half of the four requests may be retried.
+        assert!(client.permit_retry());
+        assert!(client.clone().permit_retry());
+        assert!(!client.permit_retry());
+    }
+
     fn set_up() -> (
-        RequestDispatch<String, String, UnboundedChannel<Response<String>, ClientMessage<String>>>,
+        RequestDispatch<
+            String,
+            String,
+            UnboundedChannel<ServerMessage<String>, ClientMessage<String>>,
+        >,
         Channel<String, String>,
-        UnboundedChannel<ClientMessage<String>, Response<String>>,
+        UnboundedChannel<ClientMessage<String>, ServerMessage<String>>,
     ) {
         let _ = env_logger::try_init();
 
         let (to_dispatch, pending_requests) = mpsc::channel(1);
         let (cancel_tx, canceled_requests) = mpsc::unbounded();
         let (client_channel, server_channel) = transport::channel::unbounded();
+        let (window_updates_tx, window_updates) = mpsc::unbounded();
+        let (subscriptions_tx, subscriptions) = mpsc::unbounded();
+        let server_load = Arc::new(Mutex::new(None));
+        let session = Arc::new(Mutex::new(None));
+        let (health_checks_tx, health_checks) = mpsc::unbounded();
+        let going_away = Arc::new(AtomicBool::new(false));
+        let dispatch_error = Arc::new(Mutex::new(None));
+        let (shutdowns_tx, shutdowns) = mpsc::unbounded();
+        let counters = Arc::new(Counters::default());
 
         let dispatch = RequestDispatch::<String, String, _> {
             transport: client_channel.fuse(),
             pending_requests: pending_requests.fuse(),
             canceled_requests: CanceledRequests(canceled_requests).fuse(),
             in_flight_requests: FnvHashMap::default(),
+            outgoing_items: SelectAll::new(),
+            window_updates,
+            window_updates_tx: window_updates_tx.clone(),
+            subscriptions,
+            notifications: None,
+            server_load: server_load.clone(),
+            health_checks,
+            pending_health_checks: FnvHashMap::default(),
+            next_health_check_id: 0,
+            going_away: going_away.clone(),
+            dispatch_error: dispatch_error.clone(),
+            shutdowns,
+            shutting_down: None,
+            counters: counters.clone(),
+            handshake: false,
+            session: session.clone(),
+            completions: Completions::default(),
+            requests_sent: 0,
+            credits_granted: None,
+            protocol_error: None,
             config: Config::default(),
         };
 
         let cancellation = RequestCancellation(cancel_tx);
         let channel = Channel {
             to_dispatch,
-            cancellation,
-            next_request_id: Arc::new(AtomicU64::new(0)),
+            shared: Arc::new(Shared {
+                cancellation,
+                next_request_id: AtomicU64::new(0),
+                stream_window: Config::default().stream_window,
+                window_updates: window_updates_tx,
+                subscriptions: subscriptions_tx,
+                server_load,
+                session,
+                health_checks: health_checks_tx,
+                going_away,
+                dispatch_error,
+                shutdowns: shutdowns_tx,
+                shut_down: Arc::new(AtomicBool::new(false)),
+                shutdown_timeout: Config::default().shutdown_timeout,
+                clock: Config::default().clock,
+                counters,
+                retries: None,
+            }),
         };
 
         (dispatch, channel, server_channel)
@@ -859,8 +3102,8 @@ mod tests {
     }
 
     async fn send_response(
-        channel: &mut UnboundedChannel<ClientMessage<String>, Response<String>>,
-        response: Response<String>,
+        channel: &mut UnboundedChannel<ClientMessage<String>, ServerMessage<String>>,
+        response: ServerMessage<String>,
     ) {
         channel.send(response).await.unwrap();
     }
@@ -881,7 +3124,7 @@ mod tests {
             match self {
                 Poll::Ready(Some(Ok(t))) => Poll::Ready(Some(t)),
                 Poll::Ready(None) => Poll::Ready(None),
-                Poll::Ready(Some(Err(e))) => panic!(e.to_string()),
+                Poll::Ready(Some(Err(e))) => panic!("{}", e),
                 Poll::Pending => Poll::Pending,
             }
         }
@@ -890,7 +3133,7 @@ mod tests {
             match self {
                 Poll::Ready(Some(Ok(t))) => Some(t),
                 Poll::Ready(None) => None,
-                Poll::Ready(Some(Err(e))) => panic!(e.to_string()),
+                Poll::Ready(Some(Err(e))) => panic!("{}", e),
                 Poll::Pending => panic!("Pending"),
             }
         }
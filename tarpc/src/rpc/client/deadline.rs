@@ -0,0 +1,169 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! A deadline shared by a sequence of calls, so that a workflow of several calls respects one
+//! overall time budget.
+//!
+//! A call times out when its context's deadline passes, so each call made with a context from
+//! [`Deadline::context`] times out when the workflow's budget, less a margin, runs out. The margin
+//! leaves the caller time to act on a late failure, e.g. to answer its own caller, rather than
+//! find that the budget expired along with the call:
+//!
+//! ```
+//! # use futures::future;
+//! # use tarpc::{client::{self, Deadline}, server::{BaseChannel, Channel}, transport};
+//! # use std::{io, time::Duration};
+//! # #[tokio::main]
+//! # async fn main() -> io::Result<()> {
+//! # let (client_transport, server_transport) = transport::channel::unbounded();
+//! # tokio::spawn(
+//! #     BaseChannel::with_defaults(server_transport)
+//! #         .respond_with(|_, x: u64| future::ready(x + 1))
+//! #         .execute(),
+//! # );
+//! let mut client = client::new(client::Config::default(), client_transport).spawn()?;
+//! let deadline = Deadline::new(Duration::from_secs(2)).with_margin(Duration::from_millis(100));
+//! let x = client.call(deadline.context()?, 1).await?;
+//! // Gets only what's left of the two seconds.
+//! let y = client.call(deadline.context()?, x).await?;
+//! assert_eq!(y, 3);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+    clock::{Clock, SystemClock},
+    context,
+};
+use std::{
+    io,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+/// A time budget split across sequential calls: each call gets the budget remaining, less a
+/// margin.
+#[derive(Clone, Debug)]
+pub struct Deadline {
+    end: SystemTime,
+    margin: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+impl Deadline {
+    /// Returns a deadline `budget` from now, with no margin, measured against the system's clock.
+    pub fn new(budget: Duration) -> Self {
+        Self::with_clock(budget, Arc::new(SystemClock))
+    }
+
+    /// Returns a deadline `budget` from now, with no margin, measured against `clock`. Pass the
+    /// [clock](crate::client::Config::clock) of the client the calls are made with.
+    pub fn with_clock(budget: Duration, clock: Arc<dyn Clock>) -> Self {
+        Deadline {
+            end: clock.now() + budget,
+            margin: Duration::from_secs(0),
+            clock,
+        }
+    }
+
+    /// Returns this deadline, reserving `margin` of the budget from every call.
+    pub fn with_margin(mut self, margin: Duration) -> Self {
+        self.margin = margin;
+        self
+    }
+
+    /// Returns when the budget runs out.
+    pub fn end(&self) -> SystemTime {
+        self.end
+    }
+
+    /// Returns how much of the budget remains, or zero if it's spent.
+    pub fn remaining(&self) -> Duration {
+        self.clock.until(self.end)
+    }
+
+    /// Returns how much of the budget the next call would get: what remains, less the margin.
+    pub fn next_timeout(&self) -> Duration {
+        self.remaining()
+            .checked_sub(self.margin)
+            .unwrap_or_default()
+    }
+
+    /// Returns true if no budget remains for another call.
+    pub fn is_expired(&self) -> bool {
+        self.next_timeout() == Duration::from_secs(0)
+    }
+
+    /// Returns the context of the next call, from [`context::current`], whose deadline leaves
+    /// the margin of the budget. If the current task is handling a request, the call doesn't
+    /// outlive the request, either.
+    ///
+    /// Fails with [`TimedOut`](io::ErrorKind::TimedOut) if no budget remains for the call.
+    pub fn context(&self) -> io::Result<context::Context> {
+        let mut ctx = context::current();
+        ctx.deadline = match context::scoped_deadline() {
+            Some(deadline) => deadline,
+            None => self.end,
+        };
+        self.bound(ctx)
+    }
+
+    /// Returns `ctx`, with its deadline moved up if it would otherwise outlast the budget, less
+    /// the margin.
+    ///
+    /// Fails with [`TimedOut`](io::ErrorKind::TimedOut) if no budget remains for the call.
+    pub fn bound(&self, mut ctx: context::Context) -> io::Result<context::Context> {
+        let timeout = self.next_timeout().min(self.clock.until(ctx.deadline));
+        if timeout == Duration::from_secs(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Deadline has no budget left for another call.",
+            ));
+        }
+        ctx.deadline = self.clock.now() + timeout;
+        Ok(ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Deadline;
+    use crate::{
+        clock::{Clock, VirtualClock},
+        context,
+    };
+    use std::{io, sync::Arc, time::Duration};
+
+    #[tokio::test]
+    async fn calls_split_the_budget() -> io::Result<()> {
+        tokio::time::pause();
+        let clock = Arc::new(VirtualClock::new());
+        let deadline = Deadline::with_clock(Duration::from_secs(10), clock.clone())
+            .with_margin(Duration::from_secs(1));
+
+        let ctx = deadline.context()?;
+        assert_eq!(clock.until(ctx.deadline), Duration::from_secs(9));
+
+        tokio::time::advance(Duration::from_secs(4)).await;
+        assert_eq!(deadline.remaining(), Duration::from_secs(6));
+        let ctx = deadline.context()?;
+        assert_eq!(clock.until(ctx.deadline), Duration::from_secs(5));
+
+        // A context that expires sooner keeps its deadline.
+        let mut tight = context::current();
+        tight.deadline = clock.now() + Duration::from_secs(2);
+        let ctx = deadline.bound(tight)?;
+        assert_eq!(clock.until(ctx.deadline), Duration::from_secs(2));
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert!(deadline.is_expired());
+        assert_eq!(
+            deadline.context().unwrap_err().kind(),
+            io::ErrorKind::TimedOut
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,289 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! A client of a replicated service that fails over between the service's endpoints.
+
+use super::{channel, Channel, Config};
+use crate::{context, ClientMessage, ServerMessage, Transport};
+use futures::prelude::*;
+use log::{debug, info};
+use std::{
+    cmp, io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// How long to wait between attempts to connect to an endpoint. The delay starts at `initial` and
+/// doubles after every failed attempt, up to `max`.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde1", serde(default))]
+pub struct Backoff {
+    /// The delay before the second attempt.
+    #[cfg_attr(
+        feature = "serde1",
+        serde(serialize_with = "crate::util::serde::serialize_duration_human")
+    )]
+    #[cfg_attr(
+        feature = "serde1",
+        serde(deserialize_with = "crate::util::serde::deserialize_duration_human")
+    )]
+    pub initial: Duration,
+    /// The longest delay between attempts.
+    #[cfg_attr(
+        feature = "serde1",
+        serde(serialize_with = "crate::util::serde::serialize_duration_human")
+    )]
+    #[cfg_attr(
+        feature = "serde1",
+        serde(deserialize_with = "crate::util::serde::deserialize_duration_human")
+    )]
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(5),
+        }
+    }
+}
+
+/// A client connected to one of several endpoints serving the same service.
+///
+/// The client connects to the endpoints in order, using the first that accepts the connection.
+/// When that connection dies, the next call reconnects, starting with the endpoint after the one
+/// that failed. Calls in flight when a connection dies fail, rather than being retried, because
+/// they may already have taken effect.
+///
+/// A client [with a standby](Failover::with_standby) also keeps an idle connection to the endpoint
+/// after the active one, and switches to it as soon as the active connection dies, so that the
+/// next call doesn't wait to reconnect.
+#[derive(Debug)]
+pub struct Failover<Req, Resp, F> {
+    config: Config,
+    endpoints: Vec<SocketAddr>,
+    connect: F,
+    backoff: Backoff,
+    /// The index of the endpoint to connect to next, or of the active endpoint if connected.
+    next_endpoint: usize,
+    active: Option<Active<Req, Resp>>,
+    standby: Option<Standby<Req, Resp>>,
+    /// Connects a new standby. Set by [`Failover::with_standby`], whose bounds allow spawning the
+    /// connection.
+    start_standby: Option<fn(&mut Failover<Req, Resp, F>)>,
+}
+
+/// The connection to the active endpoint.
+#[derive(Debug)]
+struct Active<Req, Resp> {
+    endpoint: SocketAddr,
+    channel: Channel<Req, Resp>,
+    /// Set once the connection's dispatch ends.
+    closed: Arc<AtomicBool>,
+}
+
+/// The idle connection the client switches to when the active connection dies.
+#[derive(Debug)]
+struct Standby<Req, Resp> {
+    /// The index of the standby's endpoint.
+    index: usize,
+    /// Filled once the standby connects.
+    connection: Arc<Mutex<Option<Active<Req, Resp>>>>,
+}
+
+impl<Req, Resp, F, Fut, T> Failover<Req, Resp, F>
+where
+    F: FnMut(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+    T: Transport<ClientMessage<Req>, ServerMessage<Resp>> + Send + 'static,
+    Req: Send + 'static,
+    Resp: Send + 'static,
+{
+    /// Returns a client of `endpoints`, which connects to an endpoint with `connect` when first
+    /// called.
+    ///
+    /// # Panics
+    ///
+    /// If `endpoints` is empty.
+    pub fn new(config: Config, endpoints: Vec<SocketAddr>, connect: F) -> Self {
+        assert!(
+            !endpoints.is_empty(),
+            "There must be at least one endpoint."
+        );
+        Failover {
+            config,
+            endpoints,
+            connect,
+            backoff: Backoff::default(),
+            next_endpoint: 0,
+            active: None,
+            standby: None,
+            start_standby: None,
+        }
+    }
+
+    /// Waits according to `backoff` between attempts to connect.
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Returns the endpoint the client is connected to, or None if it isn't connected.
+    pub fn active(&self) -> Option<SocketAddr> {
+        match self.active {
+            Some(ref active) if !active.is_closed() => Some(active.endpoint),
+            _ => None,
+        }
+    }
+
+    /// Sends `request` to the active endpoint, connecting first if the client isn't connected.
+    pub async fn call(&mut self, ctx: context::Context, request: Req) -> io::Result<Resp> {
+        self.connect().await?.call(ctx, request).await
+    }
+
+    /// Returns the channel to the active endpoint, connecting if the client isn't connected.
+    /// Tries each endpoint once, and fails with the last endpoint's error if none accepts the
+    /// connection.
+    pub async fn connect(&mut self) -> io::Result<&mut Channel<Req, Resp>> {
+        if let Some(active) = self.active.take() {
+            if !active.is_closed() {
+                return Ok(&mut self.active.get_or_insert(active).channel);
+            }
+            info!("Connection to {} died.", active.endpoint);
+            if let Some((index, standby)) = self.take_standby() {
+                info!("Failing over to the standby at {}.", standby.endpoint);
+                self.next_endpoint = index;
+                self.restart_standby();
+                return Ok(&mut self.active.get_or_insert(standby).channel);
+            }
+            self.next_endpoint = (self.next_endpoint + 1) % self.endpoints.len();
+        }
+
+        let mut delay = self.backoff.initial;
+        let mut error = None;
+        for attempt in 0..self.endpoints.len() {
+            if attempt > 0 {
+                tokio::time::delay_for(delay).await;
+                delay = cmp::min(delay * 2, self.backoff.max);
+            }
+            let endpoint = self.endpoints[self.next_endpoint];
+            match (self.connect)(endpoint).await {
+                Ok(transport) => {
+                    info!("Connected to {}.", endpoint);
+                    let active = spawn(self.config.clone(), endpoint, transport);
+                    self.restart_standby();
+                    return Ok(&mut self.active.get_or_insert(active).channel);
+                }
+                Err(e) => {
+                    debug!("Failed to connect to {}: {}", endpoint, e);
+                    error = Some(e);
+                    self.next_endpoint = (self.next_endpoint + 1) % self.endpoints.len();
+                }
+            }
+        }
+        Err(error.unwrap())
+    }
+
+    /// Returns the standby and the index of its endpoint, if it's connected and hasn't died.
+    fn take_standby(&mut self) -> Option<(usize, Active<Req, Resp>)> {
+        let standby = self.standby.take()?;
+        let connection = standby.connection.lock().unwrap().take()?;
+        if connection.is_closed() {
+            info!("Standby connection to {} died.", connection.endpoint);
+            return None;
+        }
+        Some((standby.index, connection))
+    }
+
+    /// Replaces the standby with a new connection to the endpoint after the active one, if the
+    /// client keeps a standby.
+    fn restart_standby(&mut self) {
+        if let Some(start_standby) = self.start_standby {
+            start_standby(self);
+        }
+    }
+}
+
+impl<Req, Resp, F, Fut, T> Failover<Req, Resp, F>
+where
+    F: FnMut(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<T>> + Send + 'static,
+    T: Transport<ClientMessage<Req>, ServerMessage<Resp>> + Send + 'static,
+    Req: Send + 'static,
+    Resp: Send + 'static,
+{
+    /// Keeps a standby connection to the endpoint after the active one, or to the active endpoint
+    /// if it's the only one, and switches to it when the active connection dies. The standby
+    /// connects in the background whenever the client connects or fails over. If it can't
+    /// connect, or dies while idle, the client reconnects as it would without one.
+    pub fn with_standby(mut self) -> Self {
+        self.start_standby = Some(Self::start_standby);
+        self
+    }
+
+    /// Returns the endpoint of the standby connection, or None if there's no live standby.
+    pub fn standby(&self) -> Option<SocketAddr> {
+        let standby = self.standby.as_ref()?;
+        match *standby.connection.lock().unwrap() {
+            Some(ref connection) if !connection.is_closed() => Some(connection.endpoint),
+            _ => None,
+        }
+    }
+
+    fn start_standby(&mut self) {
+        let index = (self.next_endpoint + 1) % self.endpoints.len();
+        let endpoint = self.endpoints[index];
+        let connection = Arc::new(Mutex::new(None));
+        let slot = connection.clone();
+        let config = self.config.clone();
+        let connecting = (self.connect)(endpoint);
+        tokio::spawn(async move {
+            match connecting.await {
+                Ok(transport) => {
+                    debug!("Connected standby to {}.", endpoint);
+                    *slot.lock().unwrap() = Some(spawn(config, endpoint, transport));
+                }
+                Err(e) => debug!("Failed to connect standby to {}: {}", endpoint, e),
+            }
+        });
+        self.standby = Some(Standby { index, connection });
+    }
+}
+
+/// Starts a client over `transport`, spawning its dispatch.
+fn spawn<Req, Resp, T>(config: Config, endpoint: SocketAddr, transport: T) -> Active<Req, Resp>
+where
+    T: Transport<ClientMessage<Req>, ServerMessage<Resp>> + Send + 'static,
+    Req: Send + 'static,
+    Resp: Send + 'static,
+{
+    let closed = Arc::new(AtomicBool::new(false));
+    let client = channel::new(config, transport);
+    let dispatch_closed = closed.clone();
+    tokio::spawn(client.dispatch.map(move |result| {
+        if let Err(e) = result {
+            info!("Connection to {} broken: {}", endpoint, e);
+        }
+        dispatch_closed.store(true, Ordering::Release);
+    }));
+    Active {
+        endpoint,
+        channel: client.client,
+        closed,
+    }
+}
+
+impl<Req, Resp> Active<Req, Resp> {
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+}
@@ -6,13 +6,34 @@
 
 //! Provides a client that connects to a server and sends multiplexed requests.
 
-use crate::context;
+use crate::{
+    clock::{Clock, SystemClock},
+    context,
+};
 use futures::prelude::*;
-use std::io;
+use log::debug;
+use std::{
+    io,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 /// Provides a [`Client`] backed by a transport.
 pub mod channel;
 pub use channel::{new, Channel};
+#[cfg(feature = "tokio1")]
+pub mod balance;
+pub mod cache;
+pub mod deadline;
+pub use deadline::Deadline;
+#[cfg(feature = "tokio1")]
+pub mod failover;
+/// Resolves the names clients connect to into server addresses.
+pub mod resolver;
+pub use resolver::Resolver;
+pub mod scatter;
+#[cfg(feature = "tokio1")]
+pub mod shard;
 
 /// Sends multiplexed requests to, and receives responses from, a server.
 pub trait Client<'a, Req> {
@@ -30,6 +51,12 @@ pub trait Client<'a, Req> {
     /// [`Future`]: futures::Future
     fn call(&'a mut self, ctx: context::Context, request: Req) -> Self::Future;
 
+    /// Returns whether the client may retry a failed call, spending from its retry budget if it
+    /// has one. Clients without budgets always may; see [`RetryBudget`].
+    fn permit_retry(&self) -> bool {
+        true
+    }
+
     /// Returns a Client that applies a post-processing function to the returned response.
     fn map_response<F, R>(self, f: F) -> MapResponse<Self, F>
     where
@@ -49,6 +76,26 @@ pub trait Client<'a, Req> {
     }
 }
 
+/// Sends multiplexed requests whose replies are streamed by the server.
+pub trait StreamClient<'a, Req> {
+    /// The type of each item of a reply.
+    type Response;
+
+    /// The stream of a reply's items.
+    type Stream: Stream<Item = io::Result<Self::Response>>;
+
+    /// The future stream.
+    type Future: Future<Output = io::Result<Self::Stream>> + 'a;
+
+    /// Initiates a request, sending it to the dispatch task.
+    ///
+    /// Returns a [`Future`] that resolves to a stream of the reply's items once the request is
+    /// successfully enqueued.
+    ///
+    /// [`Future`]: futures::Future
+    fn call_stream(&'a mut self, ctx: context::Context, request: Req) -> Self::Future;
+}
+
 /// A Client that applies a function to the returned response.
 #[derive(Clone, Debug)]
 pub struct MapResponse<C, F> {
@@ -67,6 +114,10 @@ where
     fn call(&'a mut self, ctx: context::Context, request: Req) -> Self::Future {
         self.inner.call(ctx, request).map_ok(&mut self.f)
     }
+
+    fn permit_retry(&self) -> bool {
+        self.inner.permit_retry()
+    }
 }
 
 /// A Client that applies a pre-processing function to the request.
@@ -87,6 +138,38 @@ where
     fn call(&'a mut self, ctx: context::Context, request: Req2) -> Self::Future {
         self.inner.call(ctx, (self.f)(request))
     }
+
+    fn permit_retry(&self) -> bool {
+        self.inner.permit_retry()
+    }
+}
+
+impl<'a, C, F, Req, Req2> StreamClient<'a, Req2> for WithRequest<C, F>
+where
+    C: StreamClient<'a, Req>,
+    F: FnMut(Req2) -> Req,
+{
+    type Response = C::Response;
+    type Stream = C::Stream;
+    type Future = C::Future;
+
+    fn call_stream(&'a mut self, ctx: context::Context, request: Req2) -> Self::Future {
+        self.inner.call_stream(ctx, (self.f)(request))
+    }
+}
+
+impl<'a, Req, Resp> StreamClient<'a, Req> for Channel<Req, Resp>
+where
+    Req: 'a,
+    Resp: 'a,
+{
+    type Response = Resp;
+    type Stream = channel::ResponseStream<Resp>;
+    type Future = channel::CallStream<'a, Req, Resp>;
+
+    fn call_stream(&'a mut self, ctx: context::Context, request: Req) -> Self::Future {
+        Channel::call_stream(self, ctx, request)
+    }
 }
 
 impl<'a, Req, Resp> Client<'a, Req> for Channel<Req, Resp>
@@ -100,11 +183,82 @@ where
     fn call(&'a mut self, ctx: context::Context, request: Req) -> channel::Call<'a, Req, Resp> {
         self.call(ctx, request)
     }
+
+    fn permit_retry(&self) -> bool {
+        Channel::permit_retry(self)
+    }
+}
+
+/// Waits to retry a call to `client` that failed with `error`, returning whether to retry it. The
+/// client stubs of rpcs declared `#[tarpc(idempotent)]` call this between attempts.
+///
+/// Only transient failures are retried: the connection failing, the call being
+/// [interrupted](io::ErrorKind::Interrupted), e.g. because the [coalesced](cache) call it waited on
+/// was canceled, and the server [shedding load](crate::ServerError::resource_exhausted), in which
+/// case the retry waits for the server's `retry_after`. Calls aren't retried if `ctx`'s deadline
+/// would pass first, or if the client [exhausted its retry budget](Client::permit_retry).
+pub fn wait_to_retry<'a, C, Req>(
+    client: &C,
+    error: &io::Error,
+    ctx: &context::Context,
+) -> impl Future<Output = bool>
+where
+    C: Client<'a, Req>,
+{
+    let delay = retry_delay(error, ctx).filter(|_| {
+        let permitted = client.permit_retry();
+        if !permitted {
+            debug!("[{}] Retry budget exhausted: {}", ctx.trace_id(), error);
+        }
+        permitted
+    });
+    async move {
+        match delay {
+            Some(delay) if delay > Duration::ZERO => {
+                tokio::time::delay_for(delay).await;
+                true
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
+}
+
+/// Returns how long to wait before retrying a call that failed with `error`, or `None` if it
+/// shouldn't be retried.
+fn retry_delay(error: &io::Error, ctx: &context::Context) -> Option<Duration> {
+    if ctx.is_canceled() {
+        return None;
+    }
+    match error.kind() {
+        io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::NotConnected
+        | io::ErrorKind::BrokenPipe
+        | io::ErrorKind::UnexpectedEof
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::WouldBlock => {}
+        _ => return None,
+    }
+    let retry_after = error
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<crate::ServerError>())
+        .and_then(|e| e.retry_after)
+        .unwrap_or_default();
+    match ctx.deadline.duration_since(SystemTime::now()) {
+        Ok(remaining) if remaining > retry_after => Some(retry_after),
+        _ => None,
+    }
 }
 
 /// Settings that control the behavior of the client.
+///
+/// With the `serde1` feature, the settings can be deserialized, e.g. from a config file; settings
+/// missing from the file keep their defaults.
 #[derive(Clone, Debug)]
 #[non_exhaustive]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde1", serde(default))]
 pub struct Config {
     /// The number of requests that can be in flight at once.
     /// `max_in_flight_requests` controls the size of the map used by the client
@@ -114,6 +268,43 @@ pub struct Config {
     /// `pending_requests_buffer` controls the size of the channel clients use
     /// to communicate with the request dispatch task.
     pub pending_request_buffer: usize,
+    /// The number of items of each streamed reply that the client buffers before the server must
+    /// wait for it to catch up, and of each streaming request before sending waits for the server.
+    /// The client closes the connection of a server that sends more.
+    pub stream_window: u32,
+    /// How a [`Balancer`](balance::Balancer) spreads requests across endpoints.
+    pub balance: Balance,
+    /// How a [`Balancer`](balance::Balancer) checks the health of its endpoints, if at all.
+    pub health_checks: Option<HealthChecks>,
+    /// How the client numbers its requests.
+    pub request_ids: RequestIds,
+    /// How many of the client's requests may be retries, if the number is limited.
+    pub retry_budget: Option<RetryBudget>,
+    /// The order the client needs the server to send replies in. A client that asks for
+    /// [request order](crate::ReplyOrder::Request) fails if the server doesn't agree to it.
+    pub reply_order: crate::ReplyOrder,
+    /// What the client does with its requests while the server withholds
+    /// [credit](crate::ServerMessage::RequestCredit) for them.
+    pub backpressure: Backpressure,
+    /// The session the client asks the server to start or resume at handshake, if any. A client
+    /// that reconnects can resume the [session](Channel::session) of its previous connection, so
+    /// that the server carries over its state, e.g. its subscriptions.
+    pub session: Option<crate::SessionRequest>,
+    /// How long [`Channel::shutdown`] waits for request dispatch to tell the server and close the
+    /// transport before giving up on them.
+    #[cfg_attr(
+        feature = "serde1",
+        serde(serialize_with = "crate::util::serde::serialize_duration_human")
+    )]
+    #[cfg_attr(
+        feature = "serde1",
+        serde(deserialize_with = "crate::util::serde::deserialize_duration_human")
+    )]
+    pub shutdown_timeout: Duration,
+    /// The clock the client measures deadlines against. Tests can replace it with a
+    /// [`VirtualClock`](crate::clock::VirtualClock) to control time rather than sleep.
+    #[cfg_attr(feature = "serde1", serde(skip))]
+    pub clock: Arc<dyn Clock>,
 }
 
 impl Default for Config {
@@ -121,6 +312,154 @@ impl Default for Config {
         Config {
             max_in_flight_requests: 1_000,
             pending_request_buffer: 100,
+            stream_window: 64,
+            balance: Balance::RoundRobin,
+            health_checks: None,
+            request_ids: RequestIds::Sequential,
+            retry_budget: Some(RetryBudget::default()),
+            reply_order: crate::ReplyOrder::Completion,
+            backpressure: Backpressure::Wait,
+            session: None,
+            shutdown_timeout: Duration::from_secs(1),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+#[cfg(feature = "config")]
+impl Config {
+    /// Reads the settings from the TOML or JSON file at `path`, choosing the format by the file's
+    /// extension. Durations are written like `"1s 500ms"`.
+    pub fn from_file(path: impl AsRef<std::path::Path>) -> io::Result<Self> {
+        crate::util::from_file(path.as_ref())
+    }
+}
+
+/// How a client connected to several endpoints chooses the endpoint of each request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde1", serde(rename_all = "snake_case"))]
+pub enum Balance {
+    /// Each endpoint takes its turn.
+    RoundRobin,
+    /// The endpoint with the fewest requests in flight. Ties go to the endpoint whose turn it
+    /// would be.
+    LeastOutstanding,
+    /// The endpoint that last reported the lowest load. Endpoints that haven't reported a load
+    /// count as idle, and ties go to the endpoint whose turn it would be.
+    LeastLoaded,
+    /// A random endpoint, chosen with probability inversely proportional to one plus its last
+    /// reported load, so that busy endpoints receive fewer requests without being starved of them.
+    Weighted,
+}
+
+/// What a client does with its requests while the server withholds credit for them, because it's
+/// overloaded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde1", serde(rename_all = "snake_case"))]
+pub enum Backpressure {
+    /// Requests wait in the client until the server grants credit for them. Once the client's
+    /// queue of requests is full, callers wait to queue theirs.
+    Wait,
+    /// Requests fail with [`io::ErrorKind::WouldBlock`], so that callers can shed load or send
+    /// them elsewhere.
+    Fail,
+}
+
+/// How a client numbers its requests.
+///
+/// Requests are identified by a `u64`, unique among the requests of a single client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde1", serde(rename_all = "snake_case"))]
+pub enum RequestIds {
+    /// Requests are numbered from zero, so every client's requests share the same IDs.
+    Sequential,
+    /// The upper 32 bits of each ID are an epoch chosen at random when the client is created, and
+    /// the lower 32 bits count its requests from zero. IDs of different clients, including a
+    /// client and the one that replaced it after reconnecting, are then distinct with high
+    /// probability, so they may be logged and correlated across systems.
+    Epoch,
+}
+
+impl RequestIds {
+    /// Returns the ID of the first request of a new client.
+    fn first(self) -> u64 {
+        match self {
+            RequestIds::Sequential => 0,
+            RequestIds::Epoch => u64::from(rand::random::<u32>()) << 32,
+        }
+    }
+}
+
+/// Limits how many of a client's requests may be retries, so that clients retrying the failures of
+/// a struggling server don't overwhelm it. Once the budget is spent, failed calls fail fast rather
+/// than being retried.
+///
+/// Over any ten seconds, a client may retry `min_retries_per_second` calls a second, plus `ratio`
+/// of the requests it sent, counting retries.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde1", serde(default))]
+pub struct RetryBudget {
+    /// The fraction of requests that may be retries.
+    pub ratio: f64,
+    /// The retries permitted each second regardless of the number of requests, so that clients
+    /// sending few requests may still retry them.
+    pub min_retries_per_second: u32,
+}
+
+impl Default for RetryBudget {
+    fn default() -> Self {
+        RetryBudget {
+            ratio: 0.2,
+            min_retries_per_second: 10,
+        }
+    }
+}
+
+/// How a client connected to several endpoints checks their health. Endpoints that fail
+/// `unhealthy_threshold` checks in a row are evicted from the rotation until they pass
+/// `healthy_threshold` checks in a row. A check fails if the endpoint reports that it isn't
+/// serving, or doesn't answer within `timeout`.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde1", serde(default))]
+pub struct HealthChecks {
+    /// How long to wait between checks of an endpoint.
+    #[cfg_attr(
+        feature = "serde1",
+        serde(serialize_with = "crate::util::serde::serialize_duration_human")
+    )]
+    #[cfg_attr(
+        feature = "serde1",
+        serde(deserialize_with = "crate::util::serde::deserialize_duration_human")
+    )]
+    pub interval: Duration,
+    /// How long to wait for an endpoint to answer a check.
+    #[cfg_attr(
+        feature = "serde1",
+        serde(serialize_with = "crate::util::serde::serialize_duration_human")
+    )]
+    #[cfg_attr(
+        feature = "serde1",
+        serde(deserialize_with = "crate::util::serde::deserialize_duration_human")
+    )]
+    pub timeout: Duration,
+    /// The number of consecutive failed checks that evicts an endpoint.
+    pub unhealthy_threshold: u32,
+    /// The number of consecutive passed checks that returns an evicted endpoint to the rotation.
+    pub healthy_threshold: u32,
+}
+
+impl Default for HealthChecks {
+    fn default() -> Self {
+        HealthChecks {
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(1),
+            unhealthy_threshold: 3,
+            healthy_threshold: 2,
         }
     }
 }
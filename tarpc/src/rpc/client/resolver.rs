@@ -0,0 +1,164 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Resolves the names clients connect to into the addresses of the servers serving them.
+//!
+//! A [`Resolver`] is the seam between a client and a service discovery backend: the client asks
+//! for a name, e.g. `"storage"`, rather than for an address, and the backend decides which servers
+//! answer to it. Swapping backends, e.g. DNS in production and a [`Registry`] in tests, doesn't
+//! change the code that connects.
+
+use fnv::FnvHashMap;
+use futures::{channel::mpsc, future, prelude::*, task::*};
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+/// Resolves names into the addresses of the servers serving them.
+pub trait Resolver {
+    /// Type of the future addresses of a name.
+    type Fut: Future<Output = io::Result<Vec<SocketAddr>>>;
+
+    /// Type of the stream of changes to a name's addresses.
+    type Changes: Stream<Item = Vec<SocketAddr>>;
+
+    /// Returns the current addresses of `name`. Fails with [`io::ErrorKind::NotFound`] if the
+    /// name is unknown.
+    fn resolve(&self, name: &str) -> Self::Fut;
+
+    /// Returns a stream that yields the addresses of `name` each time they change. Backends that
+    /// can't detect changes return a stream that never yields.
+    fn watch(&self, name: &str) -> Self::Changes;
+}
+
+/// A [`Resolver`] backed by an in-memory table of names, which the application keeps up to date,
+/// e.g. from a discovery backend that pushes updates.
+///
+/// ```
+/// # use futures::prelude::*;
+/// # use tarpc::client::resolver::{Registry, Resolver};
+/// # futures::executor::block_on(async {
+/// let registry = Registry::new();
+/// let mut changes = registry.watch("storage");
+/// registry.set("storage", vec!["10.0.0.1:443".parse().unwrap()]);
+///
+/// assert_eq!(registry.resolve("storage").await?, vec!["10.0.0.1:443".parse().unwrap()]);
+/// assert_eq!(changes.next().await, Some(vec!["10.0.0.1:443".parse().unwrap()]));
+/// # Ok::<_, std::io::Error>(())
+/// # });
+/// ```
+///
+/// Clones share the same table.
+#[derive(Clone, Debug, Default)]
+pub struct Registry {
+    names: Arc<Mutex<FnvHashMap<String, Entry>>>,
+}
+
+#[derive(Debug, Default)]
+struct Entry {
+    /// None until the name is first set.
+    addrs: Option<Vec<SocketAddr>>,
+    watchers: Vec<mpsc::UnboundedSender<Vec<SocketAddr>>>,
+}
+
+impl Registry {
+    /// Returns a new, empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the addresses of `name`, notifying everyone watching it.
+    pub fn set(&self, name: impl Into<String>, addrs: Vec<SocketAddr>) {
+        let mut names = self.names.lock().unwrap();
+        let entry = names.entry(name.into()).or_default();
+        entry
+            .watchers
+            .retain(|watcher| watcher.unbounded_send(addrs.clone()).is_ok());
+        entry.addrs = Some(addrs);
+    }
+
+    /// Removes `name`, so that resolving it fails. Watchers are notified that it has no
+    /// addresses.
+    pub fn remove(&self, name: &str) {
+        let mut names = self.names.lock().unwrap();
+        if let Some(entry) = names.get_mut(name) {
+            entry
+                .watchers
+                .retain(|watcher| watcher.unbounded_send(vec![]).is_ok());
+            entry.addrs = None;
+        }
+    }
+}
+
+impl Resolver for Registry {
+    type Fut = future::Ready<io::Result<Vec<SocketAddr>>>;
+    type Changes = Changes;
+
+    fn resolve(&self, name: &str) -> Self::Fut {
+        let names = self.names.lock().unwrap();
+        future::ready(
+            match names.get(name).and_then(|entry| entry.addrs.clone()) {
+                Some(addrs) => Ok(addrs),
+                None => Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No addresses for '{}'.", name),
+                )),
+            },
+        )
+    }
+
+    fn watch(&self, name: &str) -> Changes {
+        let (tx, changes) = mpsc::unbounded();
+        self.names
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .watchers
+            .push(tx);
+        Changes { changes }
+    }
+}
+
+/// The changes to the addresses of a name in a [`Registry`]. Created by [`Registry::watch`].
+#[derive(Debug)]
+pub struct Changes {
+    changes: mpsc::UnboundedReceiver<Vec<SocketAddr>>,
+}
+
+impl Stream for Changes {
+    type Item = Vec<SocketAddr>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Vec<SocketAddr>>> {
+        self.changes.poll_next_unpin(cx)
+    }
+}
+
+#[cfg(test)]
+use assert_matches::assert_matches;
+
+#[tokio::test]
+async fn registry_notifies_watchers() {
+    let registry = Registry::new();
+    let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+    assert_matches!(
+        registry.resolve("storage").await,
+        Err(e) if e.kind() == io::ErrorKind::NotFound
+    );
+
+    let mut changes = registry.watch("storage");
+    registry.set("storage", vec![addr]);
+    registry.remove("storage");
+    assert_eq!(changes.next().await, Some(vec![addr]));
+    assert_eq!(changes.next().await, Some(vec![]));
+    assert_matches!(
+        registry.resolve("storage").await,
+        Err(e) if e.kind() == io::ErrorKind::NotFound
+    );
+}
@@ -0,0 +1,205 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Sends the same request to many servers at once and gathers their replies, for queries that
+//! fan out across replicas or partitions.
+//!
+//! ```
+//! # use futures::future::{self, Ready};
+//! # use tarpc::{client::{self, scatter::{self, Quorum}}, context, server::{BaseChannel, Channel}, transport};
+//! # use std::io;
+//! #[tarpc::service]
+//! trait Search {
+//!     async fn count(word: String) -> usize;
+//! }
+//! # #[derive(Clone)]
+//! # struct Server(usize);
+//! # impl Search for Server {
+//! #     type CountFut = Ready<usize>;
+//! #     fn count(self, _: context::Context, _: String) -> Self::CountFut {
+//! #         future::ready(self.0)
+//! #     }
+//! # }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> io::Result<()> {
+//! let mut partitions = vec![];
+//! for count in 1..=3 {
+//!     let (client_transport, server_transport) = transport::channel::unbounded();
+//! #   tokio::spawn(BaseChannel::with_defaults(server_transport).respond_with(Server(count).serve()).execute());
+//!     partitions.push(client::new(client::Config::default(), client_transport).spawn()?);
+//! }
+//! let replies = scatter::call(
+//!     &mut partitions,
+//!     context::current(),
+//!     || SearchRequest::Count { word: "tarpc".into() },
+//!     Quorum::All,
+//! )
+//! .await?;
+//! let total: usize = replies
+//!     .into_iter()
+//!     .map(|(_, response)| match response {
+//!         SearchResponse::Count(count) => count,
+//!     })
+//!     .sum();
+//! assert_eq!(total, 6);
+//! # Ok(())
+//! # }
+//! ```
+
+use super::Client;
+use crate::context;
+use futures::{prelude::*, stream::FuturesUnordered};
+use std::{io, time::SystemTime};
+
+/// How many successful replies a [scattered call](call) gathers before it completes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quorum {
+    /// Every client must reply successfully.
+    All,
+    /// The call completes with the first `n` successful replies, e.g. `First(1)` for whichever
+    /// replica answers first, or a majority for quorum reads.
+    First(usize),
+}
+
+/// Sends a request made by `request` to every client in `clients` concurrently, and returns the successful replies
+/// once there are as many as `quorum` requires, each with the index of the client that sent it,
+/// in the order they arrived.
+///
+/// The calls still in flight when the quorum is reached are canceled. The scattered call fails
+/// early once too many calls have failed to reach the quorum, with the kind of the last failure,
+/// and fails with [`io::ErrorKind::TimedOut`] if `ctx`'s deadline passes first. A quorum larger
+/// than the number of clients can't be reached.
+pub async fn call<'a, I, C, F, Req>(
+    clients: I,
+    ctx: context::Context,
+    mut request: F,
+    quorum: Quorum,
+) -> io::Result<Vec<(usize, C::Response)>>
+where
+    I: IntoIterator<Item = &'a mut C>,
+    C: Client<'a, Req> + 'a,
+    F: FnMut() -> Req,
+{
+    let mut calls: FuturesUnordered<_> = clients
+        .into_iter()
+        .enumerate()
+        .map(|(i, client)| {
+            client
+                .call(ctx.clone(), request())
+                .map(move |reply| (i, reply))
+        })
+        .collect();
+    let required = match quorum {
+        Quorum::All => calls.len(),
+        Quorum::First(n) => n,
+    };
+    let total = calls.len();
+    if required > total {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Can't gather {} replies from {} clients.", required, total),
+        ));
+    }
+    let mut responses = Vec::with_capacity(required);
+    let mut failures = 0;
+    let timeout = ctx
+        .deadline
+        .duration_since(SystemTime::now())
+        .unwrap_or_default();
+    let gather = async {
+        while responses.len() < required {
+            // The quorum is reached, or found unreachable, by the time every call completes.
+            match calls.next().await.expect("the quorum is reachable") {
+                (i, Ok(response)) => responses.push((i, response)),
+                (_, Err(e)) => {
+                    failures += 1;
+                    if total - failures < required {
+                        return Err(io::Error::new(
+                            e.kind(),
+                            format!(
+                                "{} of {} calls failed, so {} replies can't be gathered. \
+                                 The last failed: {}",
+                                failures, total, required, e
+                            ),
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(())
+    };
+    match tokio::time::timeout(timeout, gather).await {
+        Ok(Ok(())) => Ok(responses),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!(
+                "Gathered {} of {} replies before the deadline.",
+                responses.len(),
+                required
+            ),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{call, Quorum};
+    use crate::{client::Client, context};
+    use futures::{future::BoxFuture, prelude::*};
+    use std::{
+        io,
+        time::{Duration, SystemTime},
+    };
+
+    /// Replies with its id after `.1` milliseconds, or fails if it has no id.
+    struct Replica(Option<u32>, u64);
+
+    impl<'a> Client<'a, ()> for Replica {
+        type Response = u32;
+        type Future = BoxFuture<'static, io::Result<u32>>;
+
+        fn call(&'a mut self, _: context::Context, _: ()) -> Self::Future {
+            let reply = self
+                .0
+                .ok_or_else(|| io::Error::new(io::ErrorKind::ConnectionReset, "reset"));
+            tokio::time::delay_for(Duration::from_millis(self.1))
+                .map(move |()| reply)
+                .boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn replies_are_gathered_until_the_quorum() {
+        let mut replicas = vec![
+            Replica(Some(0), 30),
+            Replica(None, 0),
+            Replica(Some(2), 10),
+            Replica(Some(3), 20),
+        ];
+        let ctx = context::current;
+
+        let replies = call(&mut replicas, ctx(), || (), Quorum::First(2)).await;
+        assert_eq!(replies.unwrap(), vec![(2, 2), (3, 3)]);
+
+        let error = call(&mut replicas, ctx(), || (), Quorum::All)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionReset);
+        let error = call(&mut replicas, ctx(), || (), Quorum::First(5))
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+        let mut ctx = ctx();
+        ctx.deadline = SystemTime::now() + Duration::from_millis(15);
+        let error = call(&mut replicas, ctx, || (), Quorum::First(2))
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,239 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! A client of a sharded service that routes each request to the endpoint owning its key.
+//!
+//! Endpoints own the arcs of a consistent-hash ring: each endpoint is hashed onto the ring at many
+//! points, and a key belongs to the endpoint at the first point at or after the key's hash. Adding
+//! or removing an endpoint therefore only moves the keys on the arcs it gains or loses; every
+//! other key stays with its endpoint.
+
+use super::{
+    balance::{maintain, Endpoint},
+    failover::Backoff,
+    Config,
+};
+use crate::{context, ClientMessage, ServerMessage, Transport};
+use fnv::{FnvHashMap, FnvHasher};
+use futures::prelude::*;
+use std::{
+    collections::BTreeMap,
+    hash::{Hash, Hasher},
+    io,
+    net::SocketAddr,
+    sync::Arc,
+};
+
+/// The number of points at which each endpoint is hashed onto the ring. More points spread the
+/// keys more evenly across endpoints.
+const POINTS_PER_ENDPOINT: u32 = 100;
+
+/// A client connected to every shard of a service, which sends each request to the endpoint that
+/// owns the request's key, as extracted by a user-provided function.
+///
+/// Each endpoint's connection is maintained in the background, as by a
+/// [`Balancer`](super::balance::Balancer). A request whose endpoint isn't connected fails rather
+/// than going to another endpoint, which wouldn't hold the key's state.
+#[derive(Debug)]
+pub struct Sharded<Req, Resp, K, F> {
+    config: Config,
+    backoff: Backoff,
+    key: K,
+    connect: F,
+    ring: Ring,
+    endpoints: FnvHashMap<SocketAddr, Arc<Endpoint<Req, Resp>>>,
+}
+
+impl<Req, Resp, K, Key, F, Fut, T> Sharded<Req, Resp, K, F>
+where
+    K: Fn(&Req) -> Key,
+    Key: Hash,
+    F: Fn(SocketAddr) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = io::Result<T>> + Send + 'static,
+    T: Transport<ClientMessage<Req>, ServerMessage<Resp>> + Send + 'static,
+    Req: Send + 'static,
+    Resp: Send + 'static,
+{
+    /// Returns a client of `endpoints` that routes each request by the key `key` extracts from
+    /// it, spawning a task per endpoint that connects to it with `connect` and reconnects whenever
+    /// the connection dies.
+    pub fn new(
+        config: Config,
+        endpoints: Vec<SocketAddr>,
+        backoff: Backoff,
+        key: K,
+        connect: F,
+    ) -> Self {
+        let mut sharded = Sharded {
+            config,
+            backoff,
+            key,
+            connect,
+            ring: Ring::default(),
+            endpoints: FnvHashMap::default(),
+        };
+        for endpoint in endpoints {
+            sharded.add_endpoint(endpoint);
+        }
+        sharded
+    }
+
+    /// Adds `addr` to the ring and starts connecting to it. It takes over the keys on its arcs
+    /// of the ring from the endpoints that owned them.
+    pub fn add_endpoint(&mut self, addr: SocketAddr) {
+        if self.endpoints.contains_key(&addr) {
+            return;
+        }
+        let endpoint = Arc::new(Endpoint::new(addr));
+        tokio::spawn(maintain(
+            Arc::downgrade(&endpoint),
+            self.config.clone(),
+            self.backoff,
+            self.connect.clone(),
+        ));
+        self.endpoints.insert(addr, endpoint);
+        self.ring.add(addr);
+    }
+
+    /// Removes `addr` from the ring, closing its connection once its requests in flight complete.
+    /// Its keys go to the endpoints that follow it on the ring.
+    pub fn remove_endpoint(&mut self, addr: SocketAddr) {
+        if self.endpoints.remove(&addr).is_some() {
+            self.ring.remove(addr);
+        }
+    }
+
+    /// Returns the endpoints on the ring.
+    pub fn endpoints(&self) -> Vec<SocketAddr> {
+        let mut endpoints: Vec<_> = self.endpoints.keys().copied().collect();
+        endpoints.sort();
+        endpoints
+    }
+
+    /// Returns the endpoints the client is connected to.
+    pub fn connected(&self) -> Vec<SocketAddr> {
+        let mut connected: Vec<_> = self
+            .endpoints
+            .values()
+            .filter(|endpoint| endpoint.channel.lock().unwrap().is_some())
+            .map(|endpoint| endpoint.addr)
+            .collect();
+        connected.sort();
+        connected
+    }
+
+    /// Returns the endpoint that owns the key of `request`, or None if the ring is empty.
+    pub fn shard_of(&self, request: &Req) -> Option<SocketAddr> {
+        self.ring.owner(&(self.key)(request))
+    }
+
+    /// Sends `request` to the endpoint that owns its key. Fails with
+    /// [`io::ErrorKind::NotConnected`] if there are no endpoints or the client isn't connected to
+    /// the owner.
+    pub async fn call(&self, ctx: context::Context, request: Req) -> io::Result<Resp> {
+        let addr = self.shard_of(&request).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotConnected, "There are no endpoints.")
+        })?;
+        let channel = self.endpoints[&addr].channel.lock().unwrap().clone();
+        match channel {
+            Some(mut channel) => channel.call(ctx, request).await,
+            None => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!("Not connected to {}.", addr),
+            )),
+        }
+    }
+}
+
+/// A consistent-hash ring of endpoints.
+#[derive(Debug, Default)]
+struct Ring {
+    /// The endpoint at each point of the ring.
+    points: BTreeMap<u64, SocketAddr>,
+}
+
+impl Ring {
+    fn add(&mut self, addr: SocketAddr) {
+        for point in 0..POINTS_PER_ENDPOINT {
+            self.points.insert(hash(&(addr, point)), addr);
+        }
+    }
+
+    fn remove(&mut self, addr: SocketAddr) {
+        self.points.retain(|_, owner| *owner != addr);
+    }
+
+    /// Returns the endpoint at the first point at or after the hash of `key`, wrapping around to
+    /// the first point of the ring.
+    fn owner(&self, key: &impl Hash) -> Option<SocketAddr> {
+        let hash = hash(key);
+        self.points
+            .range(hash..)
+            .chain(self.points.iter())
+            .next()
+            .map(|(_, &owner)| owner)
+    }
+}
+
+/// Hashes `value` with a hasher that's stable across processes, so that every client agrees on
+/// which endpoint owns a key.
+fn hash(value: &impl Hash) -> u64 {
+    let mut hasher = FnvHasher::default();
+    value.hash(&mut hasher);
+    // FNV leaves similar values, like consecutive integers, close together on the ring, so the
+    // hash is mixed with MurmurHash3's finalizer to spread them out.
+    let mut hash = hasher.finish();
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+fn ring(ports: &[u16]) -> Ring {
+    let mut ring = Ring::default();
+    for &port in ports {
+        ring.add(SocketAddr::from(([127, 0, 0, 1], port)));
+    }
+    ring
+}
+
+#[test]
+fn ring_spreads_keys_across_endpoints() {
+    let ring = ring(&[1, 2, 3]);
+    let mut counts = FnvHashMap::default();
+    for key in 0..3000 {
+        *counts.entry(ring.owner(&key).unwrap()).or_insert(0) += 1;
+    }
+    assert_eq!(counts.len(), 3);
+    assert!(counts.values().all(|&count| count > 500), "{:?}", counts);
+}
+
+#[test]
+fn ring_moves_only_the_keys_of_changed_endpoints() {
+    let before = ring(&[1, 2, 3]);
+    let mut after = ring(&[1, 2, 3, 4]);
+    let added = SocketAddr::from(([127, 0, 0, 1], 4));
+    for key in 0..1000 {
+        let owner = after.owner(&key).unwrap();
+        if owner != added {
+            assert_eq!(before.owner(&key), Some(owner));
+        }
+    }
+
+    let removed = SocketAddr::from(([127, 0, 0, 1], 2));
+    let owners: Vec<_> = (0..1000).map(|key| after.owner(&key).unwrap()).collect();
+    after.remove(removed);
+    for (key, owner) in (0..1000).zip(owners) {
+        if owner != removed {
+            assert_eq!(after.owner(&key), Some(owner));
+        } else {
+            assert_ne!(after.owner(&key), Some(removed));
+        }
+    }
+    assert_eq!(Ring::default().owner(&0), None);
+}
@@ -0,0 +1,113 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides the [`Clock`] that clients and servers measure deadlines against, so that tests can
+//! control time rather than sleep.
+//!
+//! Timeouts, keepalives, backoff, idle and lifetime limits, and queue times are timed by tokio's
+//! clock, which a test can pause and advance with tokio's `test-util` feature. Deadlines are
+//! wall-clock times, read from the [`Clock`] in the client's and server's configs. A
+//! [`VirtualClock`] reads the wall-clock time off tokio's clock, so a test that advances tokio's
+//! clock advances deadlines with it:
+//!
+//! ```
+//! # use tarpc::{client, clock::{Clock, VirtualClock}, context, server};
+//! # use std::{sync::Arc, time::Duration};
+//! # #[tokio::main(basic_scheduler)]
+//! # async fn main() {
+//! tokio::time::pause();
+//! let clock = Arc::new(VirtualClock::new());
+//! let server_config = server::Config {
+//!     clock: clock.clone(),
+//!     ..Default::default()
+//! };
+//! let mut client_config = client::Config::default();
+//! client_config.clock = clock.clone();
+//!
+//! let mut ctx = context::current();
+//! ctx.deadline = clock.now() + Duration::from_secs(10);
+//! tokio::time::advance(Duration::from_secs(11)).await;
+//! assert_eq!(clock.until(ctx.deadline), Duration::from_secs(0));
+//! # }
+//! ```
+
+use std::{
+    fmt,
+    time::{Duration, SystemTime},
+};
+use tokio::time::Instant;
+
+/// A source of the current wall-clock time, against which deadlines are measured.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> SystemTime;
+
+    /// Returns how long from now until `deadline`, or zero if it's passed.
+    fn until(&self, deadline: SystemTime) -> Duration {
+        deadline.duration_since(self.now()).unwrap_or_default()
+    }
+}
+
+/// The system's clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that advances with tokio's clock, so that a test that pauses and advances tokio's
+/// clock controls deadlines, too.
+#[derive(Clone, Copy, Debug)]
+pub struct VirtualClock {
+    origin: SystemTime,
+    start: Instant,
+}
+
+impl VirtualClock {
+    /// Returns a clock that reads the system's time now, and advances with tokio's clock.
+    pub fn new() -> Self {
+        Self::starting_at(SystemTime::now())
+    }
+
+    /// Returns a clock that reads `origin` now, and advances with tokio's clock.
+    pub fn starting_at(origin: SystemTime) -> Self {
+        VirtualClock {
+            origin,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> SystemTime {
+        self.origin + self.start.elapsed()
+    }
+}
+
+#[tokio::test]
+async fn virtual_clock_advances_with_tokio() {
+    tokio::time::pause();
+    let origin = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+    let clock = VirtualClock::starting_at(origin);
+    assert_eq!(clock.now(), origin);
+
+    tokio::time::advance(Duration::from_secs(5)).await;
+    assert_eq!(clock.now(), origin + Duration::from_secs(5));
+    assert_eq!(
+        clock.until(origin + Duration::from_secs(7)),
+        Duration::from_secs(2)
+    );
+    assert_eq!(clock.until(origin), Duration::from_secs(0));
+}
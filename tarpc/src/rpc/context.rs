@@ -4,18 +4,38 @@
 // license that can be found in the LICENSE file or at
 // https://opensource.org/licenses/MIT.
 
-//! Provides a request context that carries a deadline and trace context. This context is sent from
-//! client to server and is used by the server to enforce response deadlines.
+//! Provides a request context that carries a deadline, trace context, tenant, credentials, and
+//! idempotency key.
+//! This context is sent from client to server and is used by the server to enforce response
+//! deadlines.
 
-use crate::trace::{self, TraceId};
-use std::time::{Duration, SystemTime};
+use crate::{
+    server::MethodPermit,
+    trace::{self, TraceId},
+    SessionToken,
+};
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{FutureExt, Shared},
+    prelude::*,
+    ready,
+    task::*,
+};
+use pin_project::pin_project;
+use std::{
+    cell::RefCell,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::time::Instant;
 
 /// A request context that carries request-scoped information like deadlines and trace information.
 /// It is sent from client to server and is used by the server to enforce response deadlines.
 ///
 /// The context should not be stored directly in a server implementation, because the context will
 /// be different for each request in scope.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 #[non_exhaustive]
 #[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
 pub struct Context {
@@ -36,6 +56,43 @@ pub struct Context {
     /// include the same `trace_id` as that included on the original request. This way,
     /// users can trace related actions across a distributed system.
     pub trace_context: trace::Context,
+    /// Identifies the tenant on whose behalf the request is made, if the service is shared by
+    /// multiple tenants. Servers can use it to attribute load and enforce per-tenant limits.
+    /// It's chosen by the client and not authenticated.
+    #[cfg_attr(feature = "serde1", serde(default))]
+    pub tenant_id: Option<String>,
+    /// The API key the request is authenticated with, if the service uses API keys.
+    #[cfg_attr(feature = "serde1", serde(default))]
+    pub api_key: Option<String>,
+    /// Identifies the logical operation the request performs, so that a server can recognize
+    /// retransmissions of it and execute it only once. Set by the client stubs of rpcs declared
+    /// `#[tarpc(exactly_once)]`; see [`ExactlyOnceChannel`](crate::server::ExactlyOnceChannel).
+    #[cfg_attr(feature = "serde1", serde(default))]
+    pub idempotency_key: Option<String>,
+    /// Resolves when the request being handled with this context, or with the context it's a
+    /// child of, is canceled. Never sent over the wire.
+    #[cfg_attr(feature = "serde1", serde(skip))]
+    cancellation: Option<Shared<oneshot::Receiver<()>>>,
+    /// The session of the connection the request arrived on, if it belongs to one. Never sent
+    /// over the wire.
+    #[cfg_attr(feature = "serde1", serde(skip))]
+    session: Option<SessionToken>,
+    /// Asks the channel the request arrived on to close, if it's a request being handled. Never
+    /// sent over the wire.
+    #[cfg_attr(feature = "serde1", serde(skip))]
+    close_connection: Option<mpsc::UnboundedSender<()>>,
+    /// The request's slot in its method's [limit](crate::server::MethodLimits), freed when the
+    /// last clone of the context is dropped. Never sent over the wire.
+    #[cfg_attr(feature = "serde1", serde(skip))]
+    method_permit: Option<Arc<MethodPermit>>,
+    /// When the server decoded the request, if it's a request being handled. Never sent over the
+    /// wire.
+    #[cfg_attr(feature = "serde1", serde(skip))]
+    received: Option<Instant>,
+    /// How long the request waited between being decoded and its handler being called, if it's a
+    /// request being handled. Never sent over the wire.
+    #[cfg_attr(feature = "serde1", serde(skip))]
+    queue_time: Option<Duration>,
 }
 
 #[cfg(feature = "serde1")]
@@ -43,12 +100,98 @@ fn ten_seconds_from_now() -> SystemTime {
     SystemTime::now() + Duration::from_secs(10)
 }
 
-/// Returns the context for the current request, or a default Context if no request is active.
-// TODO: populate Context with request-scoped data, with default fallbacks.
+thread_local! {
+    /// The context of the request being handled by the current task, if any.
+    static CURRENT: RefCell<Option<Context>> = const { RefCell::new(None) };
+}
+
+/// Returns the context of a call made while handling the current request, i.e. the
+/// [child](Context::child) of the request's context, or a new root context expiring in ten seconds
+/// if no request is being handled.
+///
+/// Servers handle each request in the [scope](scope) of its context, so handlers can make
+/// downstream calls with `context::current()` and have the request's deadline, trace, and
+/// credentials flow through to them. Tasks spawned by a handler are outside the request's scope
+/// unless they're wrapped in it with [`scope`].
 pub fn current() -> Context {
-    Context {
-        deadline: SystemTime::now() + Duration::from_secs(10),
-        trace_context: trace::Context::new_root(),
+    CURRENT.with(|current| match *current.borrow() {
+        Some(ref ctx) => ctx.child(),
+        None => Context {
+            deadline: SystemTime::now() + Duration::from_secs(10),
+            trace_context: trace::Context::new_root(),
+            tenant_id: None,
+            api_key: None,
+            idempotency_key: None,
+            cancellation: None,
+            session: None,
+            close_connection: None,
+            method_permit: None,
+            received: None,
+            queue_time: None,
+        },
+    })
+}
+
+/// Returns the deadline of the request being handled by the current task, if any.
+pub(crate) fn scoped_deadline() -> Option<SystemTime> {
+    CURRENT.with(|current| current.borrow().as_ref().map(|ctx| ctx.deadline))
+}
+
+/// Returns a future, or stream, that polls `fut` in the scope of `ctx`, so that [`current`]
+/// returns children of `ctx` while `fut` runs.
+pub fn scope<F>(ctx: Context, fut: F) -> Scope<F> {
+    Scope {
+        ctx: Some(ctx),
+        inner: fut,
+    }
+}
+
+/// A future or stream polled in the scope of a context, returned by [`scope`].
+#[pin_project]
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Scope<F> {
+    ctx: Option<Context>,
+    #[pin]
+    inner: F,
+}
+
+/// Runs `f` in the scope of `ctx`, which is only taken while `f` runs.
+pub(crate) fn enter<R>(ctx: &mut Option<Context>, f: impl FnOnce() -> R) -> R {
+    /// Restores the previous scope, even if `f` panics.
+    struct Exit<'a> {
+        ctx: &'a mut Option<Context>,
+        previous: Option<Context>,
+    }
+
+    impl Drop for Exit<'_> {
+        fn drop(&mut self) {
+            *self.ctx = CURRENT.with(|current| current.replace(self.previous.take()));
+        }
+    }
+
+    let previous = CURRENT.with(|current| current.replace(ctx.take()));
+    let _exit = Exit { ctx, previous };
+    f()
+}
+
+impl<F: Future> Future for Scope<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context) -> Poll<F::Output> {
+        let this = self.project();
+        let inner = this.inner;
+        enter(this.ctx, || inner.poll(cx))
+    }
+}
+
+impl<St: Stream> Stream for Scope<St> {
+    type Item = St::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut std::task::Context) -> Poll<Option<St::Item>> {
+        let this = self.project();
+        let inner = this.inner;
+        enter(this.ctx, || inner.poll_next(cx))
     }
 }
 
@@ -57,4 +200,209 @@ impl Context {
     pub fn trace_id(&self) -> &TraceId {
         &self.trace_context.trace_id
     }
+
+    /// Returns the ID of the tenant on whose behalf the request is made, if any.
+    pub fn tenant_id(&self) -> Option<&str> {
+        self.tenant_id.as_deref()
+    }
+
+    /// Returns this context, attributed to `tenant_id`.
+    pub fn with_tenant(mut self, tenant_id: impl Into<String>) -> Self {
+        self.tenant_id = Some(tenant_id.into());
+        self
+    }
+
+    /// Returns this context, authenticated with `api_key`.
+    pub fn with_api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    /// Returns the context of a call made while handling a request with this context. The call
+    /// shares the request's deadline, trace, tenant, and API key, and its span's parent is the
+    /// request's span. It has no idempotency key, since it's a different operation than the
+    /// request. It's canceled along with the request.
+    pub fn child(&self) -> Context {
+        Context {
+            deadline: self.deadline,
+            trace_context: self.trace_context,
+            tenant_id: self.tenant_id.clone(),
+            api_key: self.api_key.clone(),
+            idempotency_key: None,
+            cancellation: self.cancellation.clone(),
+            session: None,
+            close_connection: None,
+            method_permit: None,
+            received: None,
+            queue_time: None,
+        }
+    }
+
+    /// Returns a future that resolves when the request being handled with this context is
+    /// canceled by its client, or its deadline passes, before it completes. Calls made with the
+    /// context or its children fail once it's canceled, so work a handler spawns can also watch
+    /// for it to stop early.
+    ///
+    /// The future never resolves if the request completes, or if the context isn't that of a
+    /// request being handled.
+    pub fn canceled(&self) -> Canceled {
+        Canceled(self.cancellation.clone())
+    }
+
+    /// Returns true if the request being handled with this context has been
+    /// [canceled](Context::canceled).
+    pub fn is_canceled(&self) -> bool {
+        self.canceled().now_or_never().is_some()
+    }
+
+    /// Returns this context, with a cancellation that's signaled by sending to the returned
+    /// sender. Dropping the sender instead means the request completed.
+    pub(crate) fn cancelable(mut self) -> (Self, oneshot::Sender<()>) {
+        let (cancel, cancellation) = oneshot::channel();
+        self.cancellation = Some(cancellation.shared());
+        (self, cancel)
+    }
+
+    /// Returns the [session](crate::server::Sessions) of the connection the request being handled
+    /// with this context arrived on, if the client asked for one. A client that reconnects and
+    /// resumes its session has the same session on its new connection.
+    pub fn session(&self) -> Option<&SessionToken> {
+        self.session.as_ref()
+    }
+
+    /// Returns this context, belonging to `session`.
+    pub(crate) fn in_session(mut self, session: Option<SessionToken>) -> Self {
+        self.session = session;
+        self
+    }
+
+    /// Asks the server to close the connection the request being handled with this context arrived
+    /// on, e.g. after answering a request to reconnect elsewhere. The client is told to go away,
+    /// no more requests are read from the connection, and it closes once the replies in flight,
+    /// including this request's, are written.
+    ///
+    /// Does nothing if the context isn't that of a request being handled.
+    pub fn close_connection(&self) {
+        if let Some(ref close_connection) = self.close_connection {
+            let _ = close_connection.unbounded_send(());
+        }
+    }
+
+    /// Returns this context, closing its connection by sending to `close_connection`.
+    pub(crate) fn closed_by(mut self, close_connection: mpsc::UnboundedSender<()>) -> Self {
+        self.close_connection = Some(close_connection);
+        self
+    }
+
+    /// Returns how long the request being handled with this context waited between the server
+    /// decoding it and calling its handler, to tell queueing latency apart from the handler's own.
+    /// Returns None if the context isn't that of a request being handled.
+    pub fn queue_time(&self) -> Option<Duration> {
+        self.queue_time
+    }
+
+    /// Returns this context, of a request the server decoded at `received`, as read off tokio's
+    /// clock.
+    pub(crate) fn received_at(mut self, received: Instant) -> Self {
+        self.received = Some(received);
+        self
+    }
+
+    /// Returns this context, of a request whose handler is being called now.
+    pub(crate) fn started(mut self) -> Self {
+        self.queue_time = self.received.map(|received| received.elapsed());
+        self
+    }
+
+    /// Returns this context, holding `permit` until it and its clones are dropped.
+    pub(crate) fn holding(mut self, permit: MethodPermit) -> Self {
+        self.method_permit = Some(Arc::new(permit));
+        self
+    }
+
+    /// Returns this context, identifying its operation by `idempotency_key`.
+    pub fn with_idempotency_key(mut self, idempotency_key: impl Into<String>) -> Self {
+        self.idempotency_key = Some(idempotency_key.into());
+        self
+    }
+
+    /// Returns this context, with a new random idempotency key unless it already has one, so that
+    /// every retransmission of an operation carries the same key.
+    pub fn ensure_idempotency_key(mut self) -> Self {
+        if self.idempotency_key.is_none() {
+            self.idempotency_key = Some(format!("{:032x}", rand::random::<u128>()));
+        }
+        self
+    }
+}
+
+/// Contexts are equal if they're sent over the wire alike. What a server attaches to the contexts it
+/// receives, like their cancellation, isn't compared.
+impl PartialEq for Context {
+    fn eq(&self, other: &Self) -> bool {
+        self.deadline == other.deadline
+            && self.trace_context == other.trace_context
+            && self.tenant_id == other.tenant_id
+            && self.api_key == other.api_key
+            && self.idempotency_key == other.idempotency_key
+    }
+}
+
+/// A future that resolves when a request is canceled, returned by [`Context::canceled`].
+#[derive(Clone, Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Canceled(Option<Shared<oneshot::Receiver<()>>>);
+
+impl Future for Canceled {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut std::task::Context) -> Poll<()> {
+        let cancellation = match self.0 {
+            Some(ref mut cancellation) => cancellation,
+            None => return Poll::Pending,
+        };
+        match ready!(cancellation.poll_unpin(cx)) {
+            Ok(()) => Poll::Ready(()),
+            // The request completed, so it won't be canceled.
+            Err(oneshot::Canceled) => {
+                self.0 = None;
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[test]
+fn current_is_a_child_of_the_scope() {
+    let ctx = current().with_api_key("key").with_idempotency_key("op");
+    let child = futures::executor::block_on(scope(ctx.clone(), async { current() }));
+    assert_eq!(child.deadline, ctx.deadline);
+    assert_eq!(child.trace_context, ctx.trace_context);
+    assert_eq!(child.api_key.as_deref(), Some("key"));
+    assert_eq!(child.idempotency_key, None);
+    assert_ne!(current().trace_id(), ctx.trace_id());
+}
+
+#[test]
+fn children_are_canceled_with_their_parent() {
+    let (ctx, cancel) = current().cancelable();
+    let child = ctx.child();
+    assert!(!child.is_canceled());
+    assert!(current().canceled().now_or_never().is_none());
+    cancel.send(()).unwrap();
+    assert!(child.is_canceled());
+    assert_eq!(child.child().canceled().now_or_never(), Some(()));
+
+    let (ctx, cancel) = current().cancelable();
+    drop(cancel);
+    assert!(!ctx.is_canceled());
+    assert!(ctx.canceled().now_or_never().is_none());
+}
+
+#[tokio::test]
+async fn queue_time_is_timed_by_tokio() {
+    tokio::time::pause();
+    let ctx = current().received_at(Instant::now());
+    tokio::time::advance(Duration::from_secs(3)).await;
+    assert_eq!(ctx.started().queue_time(), Some(Duration::from_secs(3)));
 }
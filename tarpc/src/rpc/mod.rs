@@ -24,32 +24,79 @@
 //!          dropped.
 //! * Transport agnostic.
 
+#[cfg(feature = "admin")]
+#[cfg_attr(docsrs, doc(cfg(feature = "admin")))]
+pub mod admin;
+#[cfg(feature = "alloc-audit")]
+#[cfg_attr(docsrs, doc(cfg(feature = "alloc-audit")))]
+pub mod alloc_audit;
+#[cfg(feature = "bench")]
+pub mod bench;
+pub mod blob;
 pub mod client;
+pub mod clock;
 pub mod context;
+#[cfg(feature = "tokio1")]
+pub mod proxy;
 pub mod server;
+#[cfg(feature = "sim")]
+#[cfg_attr(docsrs, doc(cfg(feature = "sim")))]
+pub mod sim;
+#[cfg(feature = "tower")]
+pub mod tower;
 pub mod transport;
 pub(crate) mod util;
+pub(crate) mod window;
 
 pub use crate::{client::Client, server::Server, trace, transport::sealed::Transport};
 
 use futures::task::*;
-use std::{io, time::SystemTime};
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    fmt, io,
+    time::{Duration, SystemTime},
+};
 
 /// A message from a client to a server.
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 #[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
 pub enum ClientMessage<T> {
     /// A request initiated by a user. The server responds to a request by invoking a
     /// service-provided request handler.  The handler completes with a [`response`](Response), which
     /// the server sends back to the client.
     Request(Request<T>),
+    /// A request whose message is followed by a stream of items for the same call. Each item is
+    /// sent in a [`StreamItem`](ClientMessage::StreamItem), and the last is followed by a
+    /// [`StreamEnd`](ClientMessage::StreamEnd).
+    StreamingRequest(Request<T>),
+    /// An item of a streaming request.
+    StreamItem {
+        /// The ID of the request the item belongs to.
+        request_id: u64,
+        /// The item.
+        item: T,
+    },
+    /// Marks the end of a streaming request's items.
+    StreamEnd {
+        /// The ID of the request whose items ended.
+        request_id: u64,
+    },
+    /// Grants the server credit to send more items of a streamed reply.
+    WindowUpdate {
+        /// The ID of the request whose reply may continue.
+        request_id: u64,
+        /// The number of additional items the server may send.
+        credits: u32,
+    },
     /// A command to cancel an in-flight request, automatically sent by the client when a response
     /// future is dropped.
     ///
     /// When received, the server will immediately cancel the main task (top-level future) of the
-    /// request handler for the associated request. Any tasks spawned by the request handler will
-    /// not be canceled, because the framework layer does not
-    /// know about them.
+    /// request handler for the associated request. Tasks spawned by the request handler are not
+    /// canceled, because the framework layer does not know about them, but the calls they make
+    /// with the request's context fail, and they can watch for
+    /// [`Context::canceled`](context::Context::canceled).
     Cancel {
         /// The trace context associates the message with a specific chain of causally-related actions,
         /// possibly orchestrated across many distributed systems.
@@ -58,12 +105,92 @@ pub enum ClientMessage<T> {
         /// The ID of the request to cancel.
         request_id: u64,
     },
+    /// Asks the server whether it's serving. The server answers with a
+    /// [`Health`](ServerMessage::Health) message itself, without involving the service.
+    HealthCheck {
+        /// Identifies the check among those sent over a single channel.
+        check_id: u64,
+    },
+    /// Asks the server to send replies in `reply_order`, and to start or resume a session. Sent
+    /// before any request, by clients that rely on an order or use sessions. The server answers
+    /// with a [`Handshake`](ServerMessage::Handshake) naming the order it agreed to and the
+    /// connection's session.
+    Handshake {
+        /// The order the client asks for.
+        reply_order: ReplyOrder,
+        /// The session the client asks to start or resume, if any.
+        #[cfg_attr(feature = "serde1", serde(default))]
+        session: Option<SessionRequest>,
+    },
     #[doc(hidden)]
+    #[cfg_attr(feature = "serde1", serde(skip))]
     _NonExhaustive,
 }
 
+/// The order in which a server sends the replies to the requests on a channel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde1", serde(rename_all = "snake_case"))]
+pub enum ReplyOrder {
+    /// Each reply is sent as soon as it's ready, so a slow request doesn't hold up the replies to
+    /// the requests after it. The default.
+    Completion,
+    /// Replies are sent in the order their requests arrived, so a reply waits for the replies to
+    /// the requests before it. Clients that rely on replies arriving in order must ask for it.
+    ///
+    /// A [fragmenting](crate::serde_transport::Transport::with_fragmentation) transport
+    /// interleaves the replies to different requests, so it may deliver them out of order anyway.
+    Request,
+}
+
+/// Identifies a session: the state a server keeps for a client across its connections, such as its
+/// subscriptions, so that a client that reconnects can pick up where it left off.
+///
+/// Tokens are unguessable, so a client can only resume its own sessions, and printable, so that
+/// clients can store them.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde1", serde(transparent))]
+pub struct SessionToken(String);
+
+impl SessionToken {
+    /// Returns a new random token.
+    pub(crate) fn random() -> Self {
+        SessionToken(format!("{:032x}", rand::random::<u128>()))
+    }
+
+    /// Returns the token as a string.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for SessionToken {
+    fn from(token: String) -> Self {
+        SessionToken(token)
+    }
+}
+
+impl fmt::Display for SessionToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// The session a client asks to join at [handshake](ClientMessage::Handshake).
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde1", serde(rename_all = "snake_case"))]
+pub enum SessionRequest {
+    /// A new session.
+    Start,
+    /// The session with the given token, e.g. the one the client's previous connection belonged
+    /// to. If the server no longer has it, it starts a new session instead.
+    Resume(SessionToken),
+}
+
 /// A request from a client to a server.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 #[non_exhaustive]
 #[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
 pub struct Request<T> {
@@ -71,6 +198,12 @@ pub struct Request<T> {
     pub context: context::Context,
     /// Uniquely identifies the request across all requests sent over a single channel.
     pub id: u64,
+    /// The name of the method the request invokes, if the client sent it, e.g. by writing to a
+    /// [`NamedRequests`](crate::transport::named::NamedRequests) transport. Proxies, metrics, and
+    /// debuggers can read it without understanding the message, e.g. by decoding a
+    /// `Request<serde::de::IgnoredAny>`.
+    #[cfg_attr(feature = "serde1", serde(default))]
+    pub method: Option<Cow<'static, str>>,
     /// The request body.
     pub message: T,
 }
@@ -86,6 +219,124 @@ pub struct Response<T> {
     pub message: Result<T, ServerError>,
 }
 
+/// A message from a server to a client.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub enum ServerMessage<T> {
+    /// The single response to a request, or an error that ended a streamed reply.
+    Response(Response<T>),
+    /// One item of a streamed reply. The server sends zero or more items, followed by a
+    /// [`StreamEnd`](ServerMessage::StreamEnd) or, if the reply failed, an error
+    /// [`Response`](ServerMessage::Response).
+    StreamItem {
+        /// The ID of the request being responded to.
+        request_id: u64,
+        /// The item.
+        item: T,
+    },
+    /// Marks the successful end of a streamed reply.
+    StreamEnd {
+        /// The ID of the request being responded to.
+        request_id: u64,
+    },
+    /// Grants the client credit to send more items of a streaming request.
+    WindowUpdate {
+        /// The ID of the request whose items may continue.
+        request_id: u64,
+        /// The number of additional items the client may send.
+        credits: u32,
+    },
+    /// Reports the progress of a request the server is still handling.
+    Progress {
+        /// The ID of the request whose progress is reported.
+        request_id: u64,
+        /// The progress so far.
+        progress: T,
+    },
+    /// A message the server pushes to the client unprompted, such as an event the client
+    /// subscribed to.
+    Notification(T),
+    /// Reports the load of the server, so that clients balancing requests across servers can
+    /// steer them away from busy ones.
+    Load(u32),
+    /// Answers a [`HealthCheck`](ClientMessage::HealthCheck).
+    Health {
+        /// The ID of the check being answered.
+        check_id: u64,
+        /// True if the server is serving.
+        serving: bool,
+        /// The server's clock when it answered, so that the client can estimate how far apart
+        /// their clocks are. Absent from the answers of servers that predate it.
+        #[cfg_attr(feature = "serde1", serde(default))]
+        time: Option<SystemTime>,
+    },
+    /// Tells the client that the server is draining, so the client should send new requests to
+    /// other servers. The server still serves requests already on their way.
+    GoAway,
+    /// Grants the client credit to send more requests, if the server limits how many it may have
+    /// in flight. Until the first grant, a client isn't limited.
+    RequestCredit {
+        /// The number of additional requests the client may send.
+        credits: u32,
+    },
+    /// Answers a [`Handshake`](ClientMessage::Handshake) with the order the server agreed to send
+    /// replies in and the connection's session.
+    Handshake {
+        /// The order the server sends replies in.
+        reply_order: ReplyOrder,
+        /// The session the connection belongs to. It's the session the client asked to resume,
+        /// if the server still had it, and `None` if the client didn't ask for a session or the
+        /// server doesn't keep them.
+        #[cfg_attr(feature = "serde1", serde(default))]
+        session: Option<SessionToken>,
+    },
+    /// Reports an error in the protocol, rather than in handling a request, such as a message the
+    /// server couldn't decode. An error about a request ends it; an error about the connection
+    /// means the server has stopped reading from it.
+    Error(ErrorFrame),
+    #[doc(hidden)]
+    #[cfg_attr(feature = "serde1", serde(skip))]
+    _NonExhaustive,
+}
+
+impl<T> ServerMessage<T> {
+    /// Returns the ID of the request the message responds to, or None if the message isn't about
+    /// a request, like a notification.
+    pub fn request_id(&self) -> Option<u64> {
+        match self {
+            ServerMessage::Response(response) => Some(response.request_id),
+            ServerMessage::StreamItem { request_id, .. } => Some(*request_id),
+            ServerMessage::StreamEnd { request_id } => Some(*request_id),
+            ServerMessage::WindowUpdate { request_id, .. } => Some(*request_id),
+            ServerMessage::Progress { request_id, .. } => Some(*request_id),
+            ServerMessage::Error(frame) => frame.id,
+            ServerMessage::Notification(_)
+            | ServerMessage::Load(_)
+            | ServerMessage::Health { .. }
+            | ServerMessage::GoAway
+            | ServerMessage::RequestCredit { .. }
+            | ServerMessage::Handshake { .. }
+            | ServerMessage::_NonExhaustive => None,
+        }
+    }
+
+    /// Returns true if the server will send no more messages for the request.
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self,
+            ServerMessage::Response(_)
+                | ServerMessage::StreamEnd { .. }
+                | ServerMessage::Error(ErrorFrame { id: Some(_), .. })
+        )
+    }
+}
+
+impl<T> From<Response<T>> for ServerMessage<T> {
+    fn from(response: Response<T>) -> Self {
+        ServerMessage::Response(response)
+    }
+}
+
 /// An error response from a server to a client.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 #[non_exhaustive]
@@ -103,12 +354,194 @@ pub struct ServerError {
     pub kind: io::ErrorKind,
     /// A message describing more detail about the error that occurred.
     pub detail: Option<String>,
+    /// When the server rejected the request because a resource was exhausted, how long the client
+    /// should wait before retrying.
+    #[cfg_attr(feature = "serde1", serde(default))]
+    pub retry_after: Option<Duration>,
+}
+
+impl ServerError {
+    /// Returns an error indicating the request was rejected because a resource, such as a quota,
+    /// was exhausted. The client should not retry before `retry_after` has elapsed.
+    pub fn resource_exhausted(detail: impl Into<String>, retry_after: Duration) -> Self {
+        ServerError {
+            kind: io::ErrorKind::WouldBlock,
+            detail: Some(detail.into()),
+            retry_after: Some(retry_after),
+        }
+    }
+
+    /// Returns an error indicating the request was rejected as invalid, e.g. by a
+    /// [`Validator`](crate::server::Validator), for the reason given by `detail`. The error's kind
+    /// is [`InvalidInput`](io::ErrorKind::InvalidInput).
+    pub fn invalid_request(detail: impl Into<String>) -> Self {
+        ServerError {
+            kind: io::ErrorKind::InvalidInput,
+            detail: Some(detail.into()),
+            retry_after: None,
+        }
+    }
+
+    /// Returns an error indicating the request invoked a method the server doesn't know, e.g.
+    /// because the client was built from a newer version of the service. The error's kind is
+    /// [`NotFound`](io::ErrorKind::NotFound).
+    pub fn unknown_method(method: &str) -> Self {
+        ServerError {
+            kind: io::ErrorKind::NotFound,
+            detail: Some(format!("Unknown method `{}`.", method)),
+            retry_after: None,
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.detail {
+            Some(ref detail) => write!(f, "{}", detail),
+            None => write!(f, "{:?}", self.kind),
+        }
+    }
 }
 
+impl std::error::Error for ServerError {}
+
+/// The `ServerError` is preserved as the error's [source](io::Error::get_ref), so that clients
+/// can recover details such as [`retry_after`](ServerError::retry_after).
 impl From<ServerError> for io::Error {
     fn from(e: ServerError) -> io::Error {
-        io::Error::new(e.kind, e.detail.unwrap_or_default())
+        io::Error::new(e.kind, e)
+    }
+}
+
+/// An error in the protocol, rather than in handling a request, that the server sends in place of
+/// a reply. Unlike a [`ServerError`], which a request handler returns, an error frame is sent by
+/// the server itself, when it can't or won't hand a message to a handler: because the message
+/// couldn't be decoded or exceeded a limit, because the client isn't permitted to send it, or
+/// because it's for a method the server doesn't have.
+///
+/// Clients fail the request an error frame is about with the equivalent [`ServerError`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub struct ErrorFrame {
+    /// The ID of the request the error is about, or None if it's about the connection. After an
+    /// error about the connection, the server reads no more messages from it.
+    pub id: Option<u64>,
+    /// What went wrong.
+    pub code: ErrorCode,
+    /// A description of the error, for people.
+    pub message: String,
+    /// Facts about the error, for programs, such as the limit that was exceeded.
+    #[cfg_attr(feature = "serde1", serde(default))]
+    pub details: BTreeMap<String, String>,
+}
+
+/// What went wrong, according to an [`ErrorFrame`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub enum ErrorCode {
+    /// A message couldn't be decoded.
+    InvalidMessage,
+    /// A message exceeded a limit, such as on its size.
+    LimitExceeded,
+    /// The client isn't permitted to send the request.
+    PermissionDenied,
+    /// The request is for a method the server doesn't have.
+    Unimplemented,
+    /// Something else went wrong.
+    Internal,
+}
+
+impl ErrorCode {
+    /// Returns the kind of the [`ServerError`] equivalent to an error with this code.
+    pub fn kind(self) -> io::ErrorKind {
+        match self {
+            ErrorCode::InvalidMessage => io::ErrorKind::InvalidData,
+            ErrorCode::LimitExceeded => io::ErrorKind::OutOfMemory,
+            ErrorCode::PermissionDenied => io::ErrorKind::PermissionDenied,
+            ErrorCode::Unimplemented => io::ErrorKind::NotFound,
+            ErrorCode::Internal => io::ErrorKind::Other,
+        }
+    }
+}
+
+impl ErrorFrame {
+    /// Returns an error about the connection.
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ErrorFrame {
+            id: None,
+            code,
+            message: message.into(),
+            details: BTreeMap::new(),
+        }
+    }
+
+    /// Returns an error about request `request_id`, rather than the connection.
+    pub fn for_request(mut self, request_id: u64) -> Self {
+        self.id = Some(request_id);
+        self
+    }
+
+    /// Adds a fact about the error, such as the limit that was exceeded.
+    pub fn with_detail(mut self, key: impl Into<String>, value: impl ToString) -> Self {
+        self.details.insert(key.into(), value.to_string());
+        self
     }
+
+    /// Returns an error for a request to `method`, which the server doesn't have.
+    pub fn unknown_method(request_id: u64, method: &str) -> Self {
+        ErrorFrame::new(
+            ErrorCode::Unimplemented,
+            format!("Unknown method `{}`.", method),
+        )
+        .for_request(request_id)
+        .with_detail("method", method)
+    }
+
+    /// Returns an error about the connection, for an error reading a message from it. Errors
+    /// whose underlying I/O error is [`InvalidData`](io::ErrorKind::InvalidData), or that carry
+    /// no I/O error, as when a codec fails to decode a message, are
+    /// [`InvalidMessage`](ErrorCode::InvalidMessage), and those whose underlying I/O error is
+    /// [`OutOfMemory`](io::ErrorKind::OutOfMemory), as when a message exceeds the receive buffer
+    /// of a serde transport, are [`LimitExceeded`](ErrorCode::LimitExceeded).
+    pub fn read_error(e: &io::Error) -> Self {
+        let code = match util::root_io_error_kind(e) {
+            io::ErrorKind::InvalidData | io::ErrorKind::Other => ErrorCode::InvalidMessage,
+            io::ErrorKind::OutOfMemory => ErrorCode::LimitExceeded,
+            _ => ErrorCode::Internal,
+        };
+        ErrorFrame::new(code, e.to_string())
+    }
+}
+
+impl fmt::Display for ErrorFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)?;
+        for (key, value) in &self.details {
+            write!(f, " {}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<ErrorFrame> for ServerError {
+    fn from(frame: ErrorFrame) -> ServerError {
+        ServerError {
+            kind: frame.code.kind(),
+            detail: Some(frame.message),
+            retry_after: None,
+        }
+    }
+}
+
+/// Names the RPC method a request invokes, for use by logging, metrics, and other middleware that
+/// classifies requests without understanding their contents.
+///
+/// Implemented for the request types generated by [`service`](crate::service).
+pub trait RequestName {
+    /// Returns the name of the RPC method.
+    fn name(&self) -> &'static str;
 }
 
 impl<T> Request<T> {
@@ -0,0 +1,418 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! A reverse proxy that accepts requests from clients and forwards them to a backend server, so
+//! that policy, such as authentication, can be enforced in front of a service without the service
+//! knowing.
+//!
+//! A [`Proxy`] forwards the requests of any [server channel](crate::server::Channel), so policies
+//! compose by wrapping the channel, e.g. in an [`ApiKeyChannel`](crate::server::ApiKeyChannel) to
+//! authenticate clients by API key. The proxy can also rewrite each request's context before it's
+//! forwarded, and keeps [`ProxyStats`] of the requests it forwards:
+//!
+//! ```
+//! # use futures::future;
+//! # use tarpc::{client, context, proxy::Proxy, server::{BaseChannel, Channel}, transport::channel};
+//! # use std::io;
+//! # #[tokio::main]
+//! # async fn main() -> io::Result<()> {
+//! let (backend_transport, server_transport) = channel::unbounded();
+//! tokio::spawn(
+//!     BaseChannel::with_defaults(server_transport)
+//!         .respond_with(|ctx: context::Context, x: u32| {
+//!             future::ready(format!("{} for {:?}", x, ctx.tenant_id()))
+//!         })
+//!         .execute(),
+//! );
+//! let backend = client::new(client::Config::default(), backend_transport).spawn()?;
+//!
+//! let (client_transport, proxy_transport) = channel::unbounded();
+//! let proxy = Proxy::new(backend).with_rewrite(|ctx| ctx.tenant_id = Some("edge".into()));
+//! let stats = proxy.stats().clone();
+//! tokio::spawn(proxy.serve(BaseChannel::with_defaults(proxy_transport)));
+//!
+//! let mut client = client::new(client::Config::default(), client_transport).spawn()?;
+//! assert_eq!(client.call(context::current(), 1).await?, "1 for Some(\"edge\")");
+//! assert_eq!(stats.forwarded(), 1);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A proxy can also route a fraction of its requests to a [canary](Proxy::with_canary) backend,
+//! or [mirror](Proxy::with_shadow) a fraction to a shadow backend, whose responses are measured and
+//! then discarded.
+//!
+//! The proxy forwards unary requests; streamed requests and replies aren't supported.
+
+use crate::{client, context, server::Channel, Request, Response, ServerError, ServerMessage};
+use futures::{channel::mpsc, future::Abortable, prelude::*, ready, task::*};
+use log::debug;
+use pin_project::pin_project;
+use rand::Rng;
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Forwards requests to a backend server over a client channel. Created by [`Proxy::new`].
+#[derive(Debug)]
+pub struct Proxy<Req, Resp, Rw> {
+    backend: client::Channel<Req, Resp>,
+    rewrite: Rw,
+    stats: ProxyStats,
+    shadow: Option<Shadow<Req, Resp>>,
+    canary: Option<(client::Channel<Req, Resp>, Canary)>,
+}
+
+/// A backend that receives copies of a fraction of the proxied requests.
+#[derive(Debug)]
+struct Shadow<Req, Resp> {
+    backend: client::Channel<Req, Resp>,
+    fraction: f64,
+    /// Copies requests, which needn't otherwise be `Clone`.
+    clone_request: fn(&Req) -> Req,
+    stats: ProxyStats,
+}
+
+impl<Req, Resp> Proxy<Req, Resp, fn(&mut context::Context)> {
+    /// Returns a proxy that forwards requests to `backend` unchanged.
+    pub fn new(backend: client::Channel<Req, Resp>) -> Self {
+        Proxy {
+            backend,
+            rewrite: |_| {},
+            stats: ProxyStats::default(),
+            shadow: None,
+            canary: None,
+        }
+    }
+}
+
+impl<Req, Resp, Rw> Proxy<Req, Resp, Rw>
+where
+    Rw: Fn(&mut context::Context),
+    Req: Send + 'static,
+    Resp: Send + 'static,
+{
+    /// Rewrites the context of each request with `rewrite` before forwarding it, e.g. to attribute
+    /// requests to a tenant or strip credentials the backend shouldn't see.
+    pub fn with_rewrite<Rw2>(self, rewrite: Rw2) -> Proxy<Req, Resp, Rw2>
+    where
+        Rw2: Fn(&mut context::Context),
+    {
+        Proxy {
+            backend: self.backend,
+            rewrite,
+            stats: self.stats,
+            shadow: self.shadow,
+            canary: self.canary,
+        }
+    }
+
+    /// Mirrors a `fraction` of the requests to `shadow`, e.g. a new version of the backend to be
+    /// validated against real traffic. The shadow's responses are discarded, so it can't affect
+    /// clients, but they're measured in [`shadow_stats`](Proxy::shadow_stats).
+    ///
+    /// # Panics
+    ///
+    /// If `fraction` isn't between 0 and 1.
+    pub fn with_shadow(mut self, shadow: client::Channel<Req, Resp>, fraction: f64) -> Self
+    where
+        Req: Clone,
+    {
+        assert!(
+            (0.0..=1.0).contains(&fraction),
+            "The fraction of requests to mirror must be between 0 and 1."
+        );
+        self.shadow = Some(Shadow {
+            backend: shadow,
+            fraction,
+            clone_request: Req::clone,
+            stats: ProxyStats::default(),
+        });
+        self
+    }
+
+    /// Routes a `fraction` of the requests to `canary` instead of the backend, e.g. to roll out a
+    /// new server version gradually. The fraction can be adjusted at runtime through
+    /// [`canary`](Proxy::canary), which also keeps the canary's statistics apart from the
+    /// backend's.
+    ///
+    /// # Panics
+    ///
+    /// If `fraction` isn't between 0 and 1.
+    pub fn with_canary(mut self, canary: client::Channel<Req, Resp>, fraction: f64) -> Self {
+        let handle = Canary::default();
+        handle.set_fraction(fraction);
+        self.canary = Some((canary, handle));
+        self
+    }
+
+    /// Returns the handle of the proxy's canary, if it has one.
+    pub fn canary(&self) -> Option<&Canary> {
+        self.canary.as_ref().map(|(_, canary)| canary)
+    }
+
+    /// Returns the statistics of the requests the proxy forwards to its backend.
+    pub fn stats(&self) -> &ProxyStats {
+        &self.stats
+    }
+
+    /// Returns the statistics of the requests mirrored to the shadow, if the proxy has one.
+    pub fn shadow_stats(&self) -> Option<&ProxyStats> {
+        self.shadow.as_ref().map(|shadow| &shadow.stats)
+    }
+
+    /// Returns a future that forwards the requests of `channel` to the backend, spawning a task
+    /// per request, and writes the backend's responses back to the channel. Requests the backend
+    /// fails to respond to are answered with an error of the same kind.
+    pub fn serve<C>(self, channel: C) -> Forwarding<C, Req, Resp, Rw>
+    where
+        C: Channel<Req = Req, Resp = Resp>,
+    {
+        let (responses_tx, responses) = mpsc::unbounded();
+        Forwarding {
+            channel,
+            proxy: self,
+            responses,
+            responses_tx: Some(responses_tx),
+        }
+    }
+
+    /// Spawns a task that forwards `request` to the backend, sending its response to `responses`.
+    fn forward<C>(
+        &self,
+        channel: Pin<&mut C>,
+        request: Request<Req>,
+        responses: mpsc::UnboundedSender<ServerMessage<Resp>>,
+    ) where
+        C: Channel<Req = Req, Resp = Resp>,
+    {
+        let abort_registration = channel.start_request(request.id);
+        let Request {
+            context: mut ctx,
+            id: request_id,
+            message: request,
+            ..
+        } = request;
+        (self.rewrite)(&mut ctx);
+        if let Some(ref shadow) = self.shadow {
+            if rand::thread_rng().gen_bool(shadow.fraction) {
+                shadow.mirror(ctx.clone(), (shadow.clone_request)(&request));
+            }
+        }
+        let (mut backend, stats) = match self.canary {
+            Some((ref canary, ref handle)) if rand::thread_rng().gen_bool(handle.fraction()) => {
+                (canary.clone(), handle.stats.clone())
+            }
+            _ => (self.backend.clone(), self.stats.clone()),
+        };
+        stats.inner.in_flight.fetch_add(1, Ordering::Relaxed);
+        let forwarding = async move {
+            let in_flight = InFlight(&stats);
+            let start = Instant::now();
+            let message = backend.call(ctx, request).await.map_err(|e| ServerError {
+                kind: e.kind(),
+                detail: Some(format!("The backend failed to respond: {}", e)),
+                retry_after: None,
+            });
+            stats.record(start.elapsed(), message.is_ok());
+            drop(in_flight);
+            let _ = responses.unbounded_send(ServerMessage::Response(Response {
+                request_id,
+                message,
+            }));
+        };
+        // The request is aborted when the client cancels it.
+        tokio::spawn(Abortable::new(forwarding, abort_registration));
+    }
+}
+
+impl<Req, Resp> Shadow<Req, Resp>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+{
+    /// Spawns a task that sends `request` to the shadow and discards its response. The task isn't
+    /// aborted if the client cancels the request, so the shadow sees the same requests whatever
+    /// the primary backend's latency.
+    fn mirror(&self, ctx: context::Context, request: Req) {
+        let mut backend = self.backend.clone();
+        let stats = self.stats.clone();
+        stats.inner.in_flight.fetch_add(1, Ordering::Relaxed);
+        tokio::spawn(async move {
+            let in_flight = InFlight(&stats);
+            let start = Instant::now();
+            let response = backend.call(ctx, request).await;
+            if let Err(ref e) = response {
+                debug!("The shadow failed to respond: {}", e);
+            }
+            stats.record(start.elapsed(), response.is_ok());
+            drop(in_flight);
+        });
+    }
+}
+
+/// Forwards the requests of a channel to a proxy's backend. Created by [`Proxy::serve`].
+#[pin_project]
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Forwarding<C, Req, Resp, Rw> {
+    #[pin]
+    channel: C,
+    proxy: Proxy<Req, Resp, Rw>,
+    /// The backend's responses, waiting to be written to the channel.
+    responses: mpsc::UnboundedReceiver<ServerMessage<Resp>>,
+    /// Cloned into the task of every forwarded request. None once the channel closes, so that
+    /// `responses` ends once every request is answered.
+    responses_tx: Option<mpsc::UnboundedSender<ServerMessage<Resp>>>,
+}
+
+impl<C, Req, Resp, Rw> Future for Forwarding<C, Req, Resp, Rw>
+where
+    C: Channel<Req = Req, Resp = Resp>,
+    Rw: Fn(&mut context::Context),
+    Req: Send + 'static,
+    Resp: Send + 'static,
+{
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        loop {
+            if let Some(ref responses_tx) = this.responses_tx {
+                match this.channel.as_mut().poll_next(cx)? {
+                    Poll::Ready(Some(request)) => {
+                        this.proxy
+                            .forward(this.channel.as_mut(), request, responses_tx.clone());
+                        continue;
+                    }
+                    Poll::Ready(None) => {
+                        debug!("Channel closed; finishing the requests in flight.");
+                        *this.responses_tx = None;
+                    }
+                    Poll::Pending => {}
+                }
+            }
+            ready!(this.channel.as_mut().poll_ready(cx)?);
+            match this.responses.poll_next_unpin(cx) {
+                Poll::Ready(Some(response)) => this.channel.as_mut().start_send(response)?,
+                // The channel closed and every request is answered.
+                Poll::Ready(None) => return this.channel.as_mut().poll_flush(cx),
+                Poll::Pending => {
+                    ready!(this.channel.as_mut().poll_flush(cx)?);
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+}
+
+/// Adjusts the fraction of a [`Proxy`]'s requests that are routed to its canary, and keeps the
+/// statistics of those requests. Created by [`Proxy::with_canary`].
+///
+/// Clones share the same fraction and statistics.
+#[derive(Clone, Debug, Default)]
+pub struct Canary {
+    /// The bits of the fraction, as an `f64`.
+    fraction: Arc<AtomicU64>,
+    stats: ProxyStats,
+}
+
+impl Canary {
+    /// Returns the fraction of requests routed to the canary.
+    pub fn fraction(&self) -> f64 {
+        f64::from_bits(self.fraction.load(Ordering::Relaxed))
+    }
+
+    /// Sets the fraction of requests routed to the canary.
+    ///
+    /// # Panics
+    ///
+    /// If `fraction` isn't between 0 and 1.
+    pub fn set_fraction(&self, fraction: f64) {
+        assert!(
+            (0.0..=1.0).contains(&fraction),
+            "The fraction of requests routed to the canary must be between 0 and 1."
+        );
+        self.fraction.store(fraction.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Returns the statistics of the requests routed to the canary.
+    pub fn stats(&self) -> &ProxyStats {
+        &self.stats
+    }
+}
+
+/// The statistics of the requests a [`Proxy`] forwards to a backend or mirrors to its shadow.
+///
+/// Clones share the same statistics.
+#[derive(Clone, Debug, Default)]
+pub struct ProxyStats {
+    inner: Arc<StatsInner>,
+}
+
+#[derive(Debug, Default)]
+struct StatsInner {
+    forwarded: AtomicU64,
+    failed: AtomicU64,
+    in_flight: AtomicUsize,
+    /// The total latency of the forwarded requests, in microseconds.
+    latency_micros: AtomicU64,
+}
+
+impl ProxyStats {
+    /// Returns the number of requests the backend responded to.
+    pub fn forwarded(&self) -> u64 {
+        self.inner.forwarded.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of requests the backend failed to respond to.
+    pub fn failed(&self) -> u64 {
+        self.inner.failed.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of requests waiting on the backend.
+    pub fn in_flight(&self) -> usize {
+        self.inner.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Returns the mean time the backend took to respond to or fail requests, or None if no
+    /// request has completed.
+    pub fn mean_latency(&self) -> Option<Duration> {
+        let completed = self.forwarded() + self.failed();
+        if completed == 0 {
+            return None;
+        }
+        let micros = self.inner.latency_micros.load(Ordering::Relaxed);
+        Some(Duration::from_micros(micros / completed))
+    }
+
+    fn record(&self, latency: Duration, forwarded: bool) {
+        let counter = if forwarded {
+            &self.inner.forwarded
+        } else {
+            &self.inner.failed
+        };
+        self.inner
+            .latency_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Decrements the proxy's count of requests in flight when a request completes or is aborted.
+struct InFlight<'a>(&'a ProxyStats);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.inner.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
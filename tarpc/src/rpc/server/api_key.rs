@@ -0,0 +1,404 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use super::{Channel, Config, Quota, Quotas, ReplyWindow, RequestItems};
+use crate::{ErrorCode, ErrorFrame, Request, RequestName, Response, ServerError, ServerMessage};
+use fnv::FnvHashSet;
+use futures::{future::AbortRegistration, prelude::*, ready, task::*};
+use log::debug;
+use pin_project::pin_project;
+use std::{collections::HashMap, hash::BuildHasher, io, pin::Pin, sync::Arc, time::Duration};
+
+/// What a single API key is permitted to do.
+///
+/// Clones of a policy share its rate limit, so an [`ApiKeyStore`] should hand out clones of one
+/// policy per key rather than building a new policy on every lookup.
+#[derive(Clone, Debug, Default)]
+pub struct ApiKeyPolicy {
+    quota: Option<Quotas<()>>,
+    allowed_methods: Option<FnvHashSet<&'static str>>,
+}
+
+impl ApiKeyPolicy {
+    /// Returns a policy that permits unlimited requests to every method.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits the rate of requests made with the key to `quota`, across all channels.
+    pub fn with_quota(mut self, quota: Quota) -> Self {
+        self.quota = Some(Quotas::new(quota));
+        self
+    }
+
+    /// Restricts the key to calling the named methods.
+    pub fn with_allowed_methods<I>(mut self, methods: I) -> Self
+    where
+        I: IntoIterator<Item = &'static str>,
+    {
+        self.allowed_methods = Some(methods.into_iter().collect());
+        self
+    }
+
+    /// Returns the rate limit applied to the key, if any.
+    pub fn quota(&self) -> Option<&Quota> {
+        self.quota.as_ref().map(Quotas::quota)
+    }
+
+    /// Returns true if the key may call `method`.
+    pub fn allows(&self, method: &str) -> bool {
+        match self.allowed_methods {
+            Some(ref methods) => methods.contains(method),
+            None => true,
+        }
+    }
+
+    /// Charges one request to the key. If the key has exceeded its quota, returns how long the
+    /// client should wait before retrying.
+    fn check_quota(&self) -> Result<(), Duration> {
+        match self.quota {
+            Some(ref quotas) => quotas.check(()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Looks up the policy for an API key.
+pub trait ApiKeyStore {
+    /// Returns the policy for `api_key`, or `None` if the key is not valid.
+    fn lookup(&self, api_key: &str) -> Option<ApiKeyPolicy>;
+}
+
+impl<H: BuildHasher> ApiKeyStore for HashMap<String, ApiKeyPolicy, H> {
+    fn lookup(&self, api_key: &str) -> Option<ApiKeyPolicy> {
+        self.get(api_key).cloned()
+    }
+}
+
+impl<S: ApiKeyStore + ?Sized> ApiKeyStore for Arc<S> {
+    fn lookup(&self, api_key: &str) -> Option<ApiKeyPolicy> {
+        (**self).lookup(api_key)
+    }
+}
+
+/// A [`Channel`] that rejects requests that don't carry a valid
+/// [API key](crate::context::Context::api_key), or that the key's [`ApiKeyPolicy`] doesn't
+/// permit.
+///
+/// Requests with a missing or unknown key, or that call a method the key is not allowed to call,
+/// receive a [`PermissionDenied`](ErrorCode::PermissionDenied) error frame. Requests that exceed the
+/// key's quota receive a [resource exhausted](ServerError::resource_exhausted) error.
+#[pin_project]
+#[derive(Debug)]
+pub struct ApiKeyChannel<C, St> {
+    #[pin]
+    inner: C,
+    store: St,
+}
+
+impl<C, St> ApiKeyChannel<C, St> {
+    /// Returns a new `ApiKeyChannel` that wraps the given channel and authorizes requests against
+    /// `store`.
+    pub fn new(inner: C, store: St) -> Self {
+        ApiKeyChannel { inner, store }
+    }
+
+    /// Returns the inner channel.
+    pub fn get_ref(&self) -> &C {
+        &self.inner
+    }
+}
+
+impl<C, St> ApiKeyChannel<C, St>
+where
+    C: Channel,
+    C::Req: RequestName,
+    St: ApiKeyStore,
+{
+    /// Returns the message answering `request` if the request's key doesn't permit it.
+    fn authorize(&self, request: &Request<C::Req>) -> Result<(), ServerMessage<C::Resp>> {
+        let rejecting = |reason: &str| {
+            debug!(
+                "[{}] Rejecting request {}: {}",
+                request.context.trace_id(),
+                request.id,
+                reason,
+            );
+        };
+        let denied = |message: String| {
+            rejecting(&message);
+            ServerMessage::Error(
+                ErrorFrame::new(ErrorCode::PermissionDenied, message).for_request(request.id),
+            )
+        };
+        let api_key = match request.context.api_key {
+            Some(ref api_key) => api_key,
+            None => return Err(denied("Missing API key.".into())),
+        };
+        let policy = match self.store.lookup(api_key) {
+            Some(policy) => policy,
+            None => return Err(denied("Invalid API key.".into())),
+        };
+        let method = request.message.name();
+        if !policy.allows(method) {
+            return Err(denied(format!("API key may not call {}.", method)));
+        }
+        policy.check_quota().map_err(|retry_after| {
+            let error =
+                ServerError::resource_exhausted("API key exceeded its request quota.", retry_after);
+            rejecting(&error.to_string());
+            ServerMessage::Response(Response {
+                request_id: request.id,
+                message: Err(error),
+            })
+        })
+    }
+}
+
+impl<C, St> Stream for ApiKeyChannel<C, St>
+where
+    C: Channel,
+    C::Req: RequestName,
+    St: ApiKeyStore,
+{
+    type Item = io::Result<Request<C::Req>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        loop {
+            // Ensure a rejection can be written before reading a request that might need one.
+            ready!(self.as_mut().project().inner.poll_ready(cx)?);
+
+            let request = match ready!(self.as_mut().project().inner.poll_next(cx)?) {
+                Some(request) => request,
+                None => return Poll::Ready(None),
+            };
+            match self.authorize(&request) {
+                Ok(()) => return Poll::Ready(Some(Ok(request))),
+                Err(rejection) => self.as_mut().start_send(rejection)?,
+            }
+        }
+    }
+}
+
+impl<C, St> Sink<ServerMessage<C::Resp>> for ApiKeyChannel<C, St>
+where
+    C: Channel,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: ServerMessage<C::Resp>) -> io::Result<()> {
+        self.project().inner.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+impl<C, St> AsRef<C> for ApiKeyChannel<C, St> {
+    fn as_ref(&self) -> &C {
+        &self.inner
+    }
+}
+
+impl<C, St> Channel for ApiKeyChannel<C, St>
+where
+    C: Channel,
+    C::Req: RequestName,
+    St: ApiKeyStore,
+{
+    type Req = C::Req;
+    type Resp = C::Resp;
+
+    fn config(&self) -> &Config {
+        self.inner.config()
+    }
+
+    fn in_flight_requests(self: Pin<&mut Self>) -> usize {
+        self.project().inner.in_flight_requests()
+    }
+
+    fn start_request(self: Pin<&mut Self>, request_id: u64) -> AbortRegistration {
+        self.project().inner.start_request(request_id)
+    }
+
+    fn take_request_items(self: Pin<&mut Self>, request_id: u64) -> RequestItems<Self::Req> {
+        self.project().inner.take_request_items(request_id)
+    }
+
+    fn start_reply_window(self: Pin<&mut Self>, request_id: u64) -> ReplyWindow {
+        self.project().inner.start_reply_window(request_id)
+    }
+}
+
+/// A stream of channels that authorize requests against a shared [`ApiKeyStore`].
+#[pin_project]
+#[derive(Debug)]
+pub struct ApiKeyStream<S, St> {
+    #[pin]
+    inner: S,
+    store: St,
+}
+
+impl<S, St> ApiKeyStream<S, St>
+where
+    S: Stream,
+    S::Item: Channel,
+{
+    pub(crate) fn new(inner: S, store: St) -> Self {
+        ApiKeyStream { inner, store }
+    }
+
+    /// Returns the store requests are authorized against.
+    pub fn store(&self) -> &St {
+        &self.store
+    }
+}
+
+impl<S, St> Stream for ApiKeyStream<S, St>
+where
+    S: Stream,
+    S::Item: Channel,
+    <S::Item as Channel>::Req: RequestName,
+    St: ApiKeyStore + Clone,
+{
+    type Item = ApiKeyChannel<S::Item, St>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        match ready!(self.as_mut().project().inner.poll_next(cx)) {
+            Some(channel) => Poll::Ready(Some(ApiKeyChannel::new(channel, self.store.clone()))),
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+#[cfg(test)]
+use super::testing::{self, FakeChannel, PollExt};
+#[cfg(test)]
+use crate::context;
+#[cfg(test)]
+use pin_utils::pin_mut;
+
+#[cfg(test)]
+#[derive(Debug, PartialEq)]
+enum Method {
+    Read,
+    Write,
+}
+
+#[cfg(test)]
+impl RequestName for Method {
+    fn name(&self) -> &'static str {
+        match self {
+            Method::Read => "read",
+            Method::Write => "write",
+        }
+    }
+}
+
+#[cfg(test)]
+fn push_req(
+    channel: &mut FakeChannel<io::Result<Request<Method>>, ServerMessage<()>>,
+    id: u64,
+    api_key: Option<&str>,
+    message: Method,
+) {
+    let mut context = context::current();
+    context.api_key = api_key.map(String::from);
+    channel.stream.push_back(Ok(Request {
+        context,
+        id,
+        method: None,
+        message,
+    }));
+}
+
+#[cfg(test)]
+fn store() -> HashMap<String, ApiKeyPolicy> {
+    let mut store = HashMap::new();
+    store.insert("admin".into(), ApiKeyPolicy::new());
+    store.insert(
+        "reader".into(),
+        ApiKeyPolicy::new()
+            .with_allowed_methods(vec!["read"])
+            .with_quota(Quota::new(1., 1)),
+    );
+    store
+}
+
+#[test]
+fn api_key_policy_allows() {
+    let policy = ApiKeyPolicy::new();
+    assert!(policy.allows("anything"));
+    let policy = policy.with_allowed_methods(vec!["read"]);
+    assert!(policy.allows("read"));
+    assert!(!policy.allows("write"));
+    assert_eq!(policy.quota(), None);
+}
+
+#[test]
+fn api_key_channel_poll_next_authorized() -> io::Result<()> {
+    let mut inner = FakeChannel::default::<Method, ()>();
+    push_req(&mut inner, 0, Some("admin"), Method::Write);
+    push_req(&mut inner, 1, Some("reader"), Method::Read);
+    let channel = ApiKeyChannel::new(inner, store());
+    pin_mut!(channel);
+    for expected in [(0, Method::Write), (1, Method::Read)] {
+        assert_eq!(
+            channel
+                .as_mut()
+                .poll_next(&mut testing::cx())?
+                .map(|r| r.map(|r| (r.id, r.message))),
+            Poll::Ready(Some(expected))
+        );
+    }
+    assert!(channel.inner.sink.is_empty());
+    Ok(())
+}
+
+#[test]
+fn api_key_channel_poll_next_rejected() {
+    let mut inner = FakeChannel::default::<Method, ()>();
+    push_req(&mut inner, 0, None, Method::Read);
+    push_req(&mut inner, 1, Some("bogus"), Method::Read);
+    push_req(&mut inner, 2, Some("reader"), Method::Write);
+    push_req(&mut inner, 3, Some("reader"), Method::Read);
+    push_req(&mut inner, 4, Some("reader"), Method::Read);
+    let channel = ApiKeyChannel::new(inner, store());
+    pin_mut!(channel);
+    assert!(channel.as_mut().poll_next(&mut testing::cx()).is_ready());
+    assert!(channel.as_mut().poll_next(&mut testing::cx()).is_done());
+
+    let denied: Vec<_> = channel
+        .inner
+        .errors()
+        .into_iter()
+        .map(|frame| (frame.id, frame.code))
+        .collect();
+    assert_eq!(
+        denied,
+        vec![
+            (Some(0), ErrorCode::PermissionDenied),
+            (Some(1), ErrorCode::PermissionDenied),
+            (Some(2), ErrorCode::PermissionDenied),
+        ]
+    );
+    let exhausted: Vec<_> = channel
+        .inner
+        .responses()
+        .into_iter()
+        .map(|resp| (resp.request_id, resp.message.as_ref().unwrap_err().kind))
+        .collect();
+    assert_eq!(exhausted, vec![(4, io::ErrorKind::WouldBlock)]);
+}
@@ -0,0 +1,357 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use super::Serve;
+use crate::{context, trace::TraceId, RequestName};
+use futures::{prelude::*, task::*};
+use log::info;
+use pin_project::{pin_project, pinned_drop};
+use std::{
+    fmt,
+    marker::PhantomData,
+    pin::Pin,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// How an audited RPC ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum AuditOutcome {
+    /// The handler ran to completion and produced a response.
+    Completed,
+    /// The handler was abandoned before completing, typically because the client canceled the
+    /// request.
+    Canceled,
+    /// The handler was abandoned because the request's deadline passed.
+    DeadlineExceeded,
+}
+
+impl fmt::Display for AuditOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuditOutcome::Completed => write!(f, "completed"),
+            AuditOutcome::Canceled => write!(f, "canceled"),
+            AuditOutcome::DeadlineExceeded => write!(f, "deadline exceeded"),
+        }
+    }
+}
+
+/// A record of a single RPC, with payloads already redacted by the [`AuditSink`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct AuditRecord {
+    /// The trace the RPC belongs to.
+    pub trace_id: TraceId,
+    /// The principal on whose behalf the RPC was made, if known.
+    pub principal: Option<String>,
+    /// The name of the RPC method.
+    pub method: &'static str,
+    /// How the RPC ended.
+    pub outcome: AuditOutcome,
+    /// How long the handler ran.
+    pub duration: Duration,
+    /// How long the request waited for its handler to be called, if the server measured it.
+    pub queue_time: Option<Duration>,
+    /// The redacted request.
+    pub request: String,
+    /// The redacted response, if the handler completed.
+    pub response: Option<String>,
+}
+
+/// Receives a record of every RPC served by an [`Audit`] handler.
+///
+/// Payloads are passed through [`redact_request`](AuditSink::redact_request) and
+/// [`redact_response`](AuditSink::redact_response) before being recorded, so sensitive fields
+/// never reach the sink's storage.
+pub trait AuditSink<Req, Resp> {
+    /// Identifies the principal on whose behalf a request was made. Defaults to the tenant ID.
+    fn principal(&self, ctx: &context::Context, _request: &Req) -> Option<String> {
+        ctx.tenant_id.clone()
+    }
+
+    /// Renders a request, omitting or masking any sensitive fields.
+    fn redact_request(&self, request: &Req) -> String;
+
+    /// Renders a response, omitting or masking any sensitive fields.
+    fn redact_response(&self, response: &Resp) -> String;
+
+    /// Records a completed, canceled, or expired RPC.
+    fn record(&self, record: AuditRecord);
+}
+
+/// An [`AuditSink`] that writes records to the `tarpc::audit` log target at info level, after
+/// redacting payloads with the given functions.
+#[derive(Clone)]
+pub struct AuditLog<RF, PF> {
+    redact_request: RF,
+    redact_response: PF,
+}
+
+impl<RF, PF> AuditLog<RF, PF> {
+    /// Returns an audit log that renders requests with `redact_request` and responses with
+    /// `redact_response`.
+    pub fn new(redact_request: RF, redact_response: PF) -> Self {
+        AuditLog {
+            redact_request,
+            redact_response,
+        }
+    }
+}
+
+impl<RF, PF> fmt::Debug for AuditLog<RF, PF> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AuditLog").finish()
+    }
+}
+
+impl<Req, Resp, RF, PF> AuditSink<Req, Resp> for AuditLog<RF, PF>
+where
+    RF: Fn(&Req) -> String,
+    PF: Fn(&Resp) -> String,
+{
+    fn redact_request(&self, request: &Req) -> String {
+        (self.redact_request)(request)
+    }
+
+    fn redact_response(&self, response: &Resp) -> String {
+        (self.redact_response)(response)
+    }
+
+    fn record(&self, record: AuditRecord) {
+        info!(
+            target: "tarpc::audit",
+            "[{}] principal={} method={} outcome={} duration={:?} queue_time={} request={} \
+             response={}",
+            record.trace_id,
+            record.principal.as_deref().unwrap_or("-"),
+            record.method,
+            record.outcome,
+            record.duration,
+            record
+                .queue_time
+                .map(|queue_time| format!("{:?}", queue_time))
+                .as_deref()
+                .unwrap_or("-"),
+            record.request,
+            record.response.as_deref().unwrap_or("-"),
+        );
+    }
+}
+
+/// A [`Serve`] that records every request it serves in an [`AuditSink`].
+#[derive(Clone, Debug)]
+pub struct Audit<S, A> {
+    serve: S,
+    sink: A,
+}
+
+impl<S, A> Audit<S, A> {
+    /// Returns a request handler that serves requests with `serve` and records them in `sink`.
+    pub fn new(serve: S, sink: A) -> Self {
+        Audit { serve, sink }
+    }
+}
+
+impl<Req, S, A> Serve<Req> for Audit<S, A>
+where
+    Req: RequestName,
+    S: Serve<Req>,
+    A: AuditSink<Req, S::Resp> + Clone,
+{
+    type Resp = S::Resp;
+    type Fut = AuditFuture<S::Fut, A, Req>;
+
+    fn serve(self, ctx: context::Context, req: Req) -> Self::Fut {
+        let record = PendingRecord {
+            trace_id: *ctx.trace_id(),
+            deadline: ctx.deadline,
+            principal: self.sink.principal(&ctx, &req),
+            method: req.name(),
+            request: self.sink.redact_request(&req),
+            queue_time: ctx.queue_time(),
+            started: Instant::now(),
+        };
+        AuditFuture {
+            fut: self.serve.serve(ctx, req),
+            sink: self.sink,
+            record: Some(record),
+            ghost: PhantomData,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PendingRecord {
+    trace_id: TraceId,
+    deadline: SystemTime,
+    principal: Option<String>,
+    method: &'static str,
+    request: String,
+    queue_time: Option<Duration>,
+    started: Instant,
+}
+
+impl PendingRecord {
+    fn finish(self, outcome: AuditOutcome, response: Option<String>) -> AuditRecord {
+        AuditRecord {
+            trace_id: self.trace_id,
+            principal: self.principal,
+            method: self.method,
+            outcome,
+            duration: self.started.elapsed(),
+            queue_time: self.queue_time,
+            request: self.request,
+            response,
+        }
+    }
+}
+
+/// A response future that records its RPC in an [`AuditSink`] when it completes or is dropped.
+#[pin_project(PinnedDrop)]
+pub struct AuditFuture<F, A, Req>
+where
+    F: Future,
+    A: AuditSink<Req, F::Output>,
+{
+    #[pin]
+    fut: F,
+    sink: A,
+    record: Option<PendingRecord>,
+    ghost: PhantomData<fn(Req)>,
+}
+
+impl<F, A, Req> fmt::Debug for AuditFuture<F, A, Req>
+where
+    F: Future,
+    A: AuditSink<Req, F::Output>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AuditFuture")
+            .field("record", &self.record)
+            .finish()
+    }
+}
+
+impl<F, A, Req> Future for AuditFuture<F, A, Req>
+where
+    F: Future,
+    A: AuditSink<Req, F::Output>,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let this = self.project();
+        let response = futures::ready!(this.fut.poll(cx));
+        if let Some(record) = this.record.take() {
+            let redacted = this.sink.redact_response(&response);
+            this.sink
+                .record(record.finish(AuditOutcome::Completed, Some(redacted)));
+        }
+        Poll::Ready(response)
+    }
+}
+
+#[pinned_drop]
+impl<F, A, Req> PinnedDrop for AuditFuture<F, A, Req>
+where
+    F: Future,
+    A: AuditSink<Req, F::Output>,
+{
+    fn drop(self: Pin<&mut Self>) {
+        let this = self.project();
+        if let Some(record) = this.record.take() {
+            let outcome = if SystemTime::now() >= record.deadline {
+                AuditOutcome::DeadlineExceeded
+            } else {
+                AuditOutcome::Canceled
+            };
+            this.sink.record(record.finish(outcome, None));
+        }
+    }
+}
+
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct Recorder(Arc<Mutex<Vec<AuditRecord>>>);
+
+#[cfg(test)]
+#[derive(Debug)]
+struct Login {
+    user: &'static str,
+    password: &'static str,
+}
+
+#[cfg(test)]
+impl RequestName for Login {
+    fn name(&self) -> &'static str {
+        "login"
+    }
+}
+
+#[cfg(test)]
+impl AuditSink<Login, String> for Recorder {
+    fn redact_request(&self, request: &Login) -> String {
+        format!("Login {{ user: {:?}, password: <redacted> }}", request.user)
+    }
+
+    fn redact_response(&self, _response: &String) -> String {
+        "<token>".into()
+    }
+
+    fn record(&self, record: AuditRecord) {
+        self.0.lock().unwrap().push(record);
+    }
+}
+
+#[tokio::test]
+async fn audit_records_redacted_payloads() {
+    let recorder = Recorder::default();
+    let serve = Audit::new(
+        |_, login: Login| future::ready(format!("{}:{}", login.user, login.password)),
+        recorder.clone(),
+    );
+
+    let mut ctx = context::current();
+    ctx.tenant_id = Some("acme".into());
+    let login = Login {
+        user: "alice",
+        password: "hunter2",
+    };
+    assert_eq!(serve.serve(ctx, login).await, "alice:hunter2");
+
+    let records = recorder.0.lock().unwrap();
+    assert_eq!(records.len(), 1);
+    let record = &records[0];
+    assert_eq!(record.method, "login");
+    assert_eq!(record.principal.as_deref(), Some("acme"));
+    assert_eq!(record.outcome, AuditOutcome::Completed);
+    assert!(!record.request.contains("hunter2"));
+    assert_eq!(record.response.as_deref(), Some("<token>"));
+}
+
+#[test]
+fn audit_records_abandoned_requests() {
+    let recorder = Recorder::default();
+    let serve = Audit::new(|_, _: Login| future::pending::<String>(), recorder.clone());
+    let login = || Login {
+        user: "alice",
+        password: "hunter2",
+    };
+
+    drop(serve.clone().serve(context::current(), login()));
+    let mut expired = context::current();
+    expired.deadline = SystemTime::now() - Duration::from_secs(1);
+    drop(serve.serve(expired, login()));
+
+    let records = recorder.0.lock().unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].outcome, AuditOutcome::Canceled);
+    assert_eq!(records[0].response, None);
+    assert_eq!(records[1].outcome, AuditOutcome::DeadlineExceeded);
+}
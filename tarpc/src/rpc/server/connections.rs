@@ -0,0 +1,218 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use super::Notifier;
+use fnv::FnvHashMap;
+use log::debug;
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+/// The live connections of a server, through which it can notify one or all of its clients, e.g.
+/// of an impending shutdown.
+///
+/// Each connection is registered with the [`Notifier`] of its channel:
+///
+/// ```
+/// # use tarpc::{server::{BaseChannel, Connections}, transport::channel};
+/// # use std::io;
+/// # fn main() -> io::Result<()> {
+/// let connections = Connections::new();
+/// let (_client, server) = channel::unbounded();
+/// let channel = BaseChannel::<String, String, _>::with_defaults(server);
+/// let id = connections.register(channel.notifier());
+///
+/// assert_eq!(connections.ids(), vec![id]);
+/// connections.send_to(id, "just you".to_string())?;
+/// assert_eq!(connections.broadcast("everyone".to_string()), 1);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Clones share the same connections. Connections whose channels have closed are forgotten the
+/// next time they're enumerated or notified, as are connections closed for falling behind on
+/// broadcasts.
+#[derive(Debug)]
+pub struct Connections<Resp> {
+    inner: Arc<Mutex<ConnectionsInner<Resp>>>,
+}
+
+#[derive(Debug)]
+struct ConnectionsInner<Resp> {
+    next_id: u64,
+    notifiers: FnvHashMap<u64, Notifier<Resp>>,
+}
+
+impl<Resp> Clone for Connections<Resp> {
+    fn clone(&self) -> Self {
+        Connections {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<Resp> Default for Connections<Resp> {
+    fn default() -> Self {
+        Connections {
+            inner: Arc::new(Mutex::new(ConnectionsInner {
+                next_id: 0,
+                notifiers: FnvHashMap::default(),
+            })),
+        }
+    }
+}
+
+impl<Resp> Connections<Resp> {
+    /// Returns a new, empty set of connections.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the connection notified by `notifier`, returning its ID.
+    pub fn register(&self, notifier: Notifier<Resp>) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.notifiers.insert(id, notifier);
+        id
+    }
+
+    /// Returns the IDs of the live connections, in the order they were registered.
+    pub fn ids(&self) -> Vec<u64> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.notifiers.retain(|_, notifier| !notifier.is_closed());
+        let mut ids: Vec<_> = inner.notifiers.keys().cloned().collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Pushes `notification` to connection `id`. Fails with [`io::ErrorKind::NotFound`] if there's
+    /// no such connection, with [`io::ErrorKind::ConnectionReset`] if it has closed, and with
+    /// [`io::ErrorKind::WouldBlock`] if its notification buffer is full.
+    pub fn send_to(&self, id: u64, notification: Resp) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let result = match inner.notifiers.get_mut(&id) {
+            Some(notifier) => notifier.notify(notification),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No connection {}.", id),
+                ))
+            }
+        };
+        if let Err(ref e) = result {
+            if e.kind() == io::ErrorKind::ConnectionReset {
+                inner.notifiers.remove(&id);
+            }
+        }
+        result
+    }
+
+    /// Pushes `notification` to every live connection. Returns the number of connections
+    /// notified. Connections whose notification buffers are full are closed, rather than let
+    /// them miss notifications.
+    pub fn broadcast(&self, notification: Resp) -> usize
+    where
+        Resp: Clone,
+    {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .notifiers
+            .retain(|id, notifier| match notifier.notify(notification.clone()) {
+                Ok(()) => true,
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::WouldBlock {
+                        debug!(
+                            "Connection {} fell behind on notifications; closing it.",
+                            id
+                        );
+                        notifier.close();
+                    }
+                    false
+                }
+            });
+        inner.notifiers.len()
+    }
+}
+
+#[cfg(test)]
+use assert_matches::assert_matches;
+#[cfg(test)]
+use futures::channel::mpsc;
+
+#[cfg(test)]
+fn notifier() -> (Notifier<String>, mpsc::Receiver<String>) {
+    let (notifications, rx) = mpsc::channel(1);
+    let (close_requests, _) = mpsc::unbounded();
+    let notifier = Notifier {
+        notifications,
+        close_requests,
+    };
+    (notifier, rx)
+}
+
+#[test]
+fn broadcast_notifies_live_connections() {
+    let connections = Connections::new();
+    let (a, mut a_rx) = notifier();
+    let (b, b_rx) = notifier();
+    let a = connections.register(a);
+    connections.register(b);
+    drop(b_rx);
+
+    assert_eq!(connections.broadcast("bye".to_string()), 1);
+    assert_eq!(a_rx.try_recv().unwrap(), "bye");
+    assert_eq!(connections.ids(), vec![a]);
+}
+
+#[test]
+fn send_to_notifies_one_connection() {
+    let connections = Connections::new();
+    let (a, mut a_rx) = notifier();
+    let (b, mut b_rx) = notifier();
+    let a = connections.register(a);
+    let b = connections.register(b);
+
+    connections.send_to(b, "hi".to_string()).unwrap();
+    assert_eq!(b_rx.try_recv().unwrap(), "hi");
+    assert!(a_rx.try_recv().is_err());
+
+    drop(a_rx);
+    assert_matches!(
+        connections.send_to(a, "hi".to_string()),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionReset
+    );
+    assert_matches!(
+        connections.send_to(a, "hi".to_string()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound
+    );
+    assert_eq!(connections.ids(), vec![b]);
+}
+
+#[test]
+fn broadcast_closes_connections_that_fall_behind() {
+    let connections = Connections::new();
+    let (a, _a_rx) = notifier();
+    let (close_requests, mut closed) = mpsc::unbounded();
+    let a = connections.register(Notifier {
+        close_requests,
+        ..a
+    });
+
+    // The buffer holds one notification, plus one for the notifier.
+    connections.send_to(a, "1".to_string()).unwrap();
+    connections.send_to(a, "2".to_string()).unwrap();
+    assert_matches!(
+        connections.send_to(a, "3".to_string()),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock
+    );
+    assert_eq!(connections.ids(), vec![a]);
+
+    assert_eq!(connections.broadcast("bye".to_string()), 0);
+    assert!(closed.try_recv().is_ok());
+    assert_eq!(connections.ids(), Vec::<u64>::new());
+}
@@ -0,0 +1,394 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use super::{Channel, Config, ReplyWindow, RequestItems};
+use crate::{Request, Response, ServerError, ServerMessage};
+use futures::{future::AbortRegistration, prelude::*, ready, task::*};
+use humantime::format_rfc3339;
+use log::debug;
+use pin_project::pin_project;
+use std::{collections::BTreeMap, fmt, io, pin::Pin, time::SystemTime};
+
+/// A [`Channel`] that limits the number of concurrent requests, queueing the rest and serving them
+/// earliest deadline first.
+///
+/// Under overload, serving requests in the order they arrived lets them all wait out the queue,
+/// so that the ones with short deadlines expire before they're served. Serving the most urgent
+/// first instead, and dropping requests whose deadlines pass while they're queued, lets more of
+/// them complete within their deadlines.
+///
+/// Requests whose deadlines pass while queued fail with [`io::ErrorKind::TimedOut`]. When the
+/// queue is full, the request with the latest deadline fails with
+/// [`io::ErrorKind::WouldBlock`].
+#[pin_project]
+pub struct DeadlineQueue<C: Channel> {
+    max_in_flight_requests: usize,
+    capacity: usize,
+    /// The queued requests, by deadline and then arrival.
+    queue: BTreeMap<(SystemTime, u64), Request<C::Req>>,
+    /// The number of requests queued so far, which orders requests with equal deadlines.
+    arrivals: u64,
+    /// True once the inner channel has no more requests.
+    exhausted: bool,
+    #[pin]
+    inner: C,
+}
+
+impl<C: Channel + fmt::Debug> fmt::Debug for DeadlineQueue<C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DeadlineQueue")
+            .field("max_in_flight_requests", &self.max_in_flight_requests)
+            .field("capacity", &self.capacity)
+            .field("queued_requests", &self.queue.len())
+            .field("exhausted", &self.exhausted)
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+/// Why a queued request was dropped.
+enum Shed {
+    Expired,
+    Overflow,
+}
+
+impl<C: Channel> DeadlineQueue<C> {
+    /// Returns a new `DeadlineQueue` that wraps the given channel, limits concurrent requests to
+    /// `max_in_flight_requests`, and queues up to `capacity` more.
+    pub fn new(inner: C, max_in_flight_requests: usize, capacity: usize) -> Self {
+        DeadlineQueue {
+            max_in_flight_requests,
+            capacity,
+            queue: BTreeMap::new(),
+            arrivals: 0,
+            exhausted: false,
+            inner,
+        }
+    }
+
+    /// Returns the inner channel.
+    pub fn get_ref(&self) -> &C {
+        &self.inner
+    }
+
+    /// Returns the number of queued requests.
+    pub fn queued_requests(&self) -> usize {
+        self.queue.len()
+    }
+
+    fn enqueue(self: Pin<&mut Self>, request: Request<C::Req>) {
+        let this = self.project();
+        this.queue
+            .insert((request.context.deadline, *this.arrivals), request);
+        *this.arrivals += 1;
+    }
+
+    /// Returns the next request to drop: one whose deadline has passed, else the least urgent one
+    /// if the queue's over capacity.
+    fn next_to_shed(&self) -> Option<((SystemTime, u64), Shed)> {
+        match self.queue.keys().next() {
+            Some(&key) if key.0 <= self.inner.config().clock.now() => {
+                return Some((key, Shed::Expired))
+            }
+            _ => {}
+        }
+        if self.queue.len() > self.capacity {
+            return self
+                .queue
+                .keys()
+                .next_back()
+                .map(|&key| (key, Shed::Overflow));
+        }
+        None
+    }
+
+    fn shed(mut self: Pin<&mut Self>, key: (SystemTime, u64), shed: Shed) -> io::Result<()> {
+        let request = self
+            .as_mut()
+            .project()
+            .queue
+            .remove(&key)
+            .expect("the request is queued");
+        let error = match shed {
+            Shed::Expired => {
+                debug!(
+                    "[{}] Request's deadline of {} passed while it was queued.",
+                    request.context.trace_id(),
+                    format_rfc3339(request.context.deadline),
+                );
+                ServerError {
+                    kind: io::ErrorKind::TimedOut,
+                    detail: Some(format!(
+                        "Request's deadline of {} passed while it was queued.",
+                        format_rfc3339(request.context.deadline)
+                    )),
+                    retry_after: None,
+                }
+            }
+            Shed::Overflow => {
+                debug!(
+                    "[{}] Client's request queue is full ({} requests).",
+                    request.context.trace_id(),
+                    self.capacity,
+                );
+                ServerError {
+                    kind: io::ErrorKind::WouldBlock,
+                    detail: Some("Server's request queue is full.".into()),
+                    retry_after: None,
+                }
+            }
+        };
+        self.project()
+            .inner
+            .start_send(ServerMessage::Response(Response {
+                request_id: request.id,
+                message: Err(error),
+            }))
+    }
+}
+
+impl<C> Stream for DeadlineQueue<C>
+where
+    C: Channel,
+{
+    type Item = <C as Stream>::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some((key, shed)) = self.next_to_shed() {
+                ready!(self.as_mut().project().inner.poll_ready(cx)?);
+                self.as_mut().shed(key, shed)?;
+                continue;
+            }
+            // Read all the requests the client's sent, so that the most urgent of them is served
+            // first.
+            if !self.exhausted {
+                match self.as_mut().project().inner.poll_next(cx)? {
+                    Poll::Ready(Some(request)) => {
+                        self.as_mut().enqueue(request);
+                        continue;
+                    }
+                    Poll::Ready(None) => *self.as_mut().project().exhausted = true,
+                    Poll::Pending => {}
+                }
+            }
+            if self.as_mut().in_flight_requests() < self.max_in_flight_requests {
+                if let Some((_, request)) = self.as_mut().project().queue.pop_first() {
+                    return Poll::Ready(Some(Ok(request)));
+                }
+            }
+            return if self.exhausted && self.queue.is_empty() {
+                Poll::Ready(None)
+            } else {
+                Poll::Pending
+            };
+        }
+    }
+}
+
+impl<C> Sink<ServerMessage<<C as Channel>::Resp>> for DeadlineQueue<C>
+where
+    C: Channel,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(
+        self: Pin<&mut Self>,
+        item: ServerMessage<<C as Channel>::Resp>,
+    ) -> io::Result<()> {
+        self.project().inner.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+impl<C: Channel> AsRef<C> for DeadlineQueue<C> {
+    fn as_ref(&self) -> &C {
+        &self.inner
+    }
+}
+
+impl<C> Channel for DeadlineQueue<C>
+where
+    C: Channel,
+{
+    type Req = <C as Channel>::Req;
+    type Resp = <C as Channel>::Resp;
+
+    fn in_flight_requests(self: Pin<&mut Self>) -> usize {
+        self.project().inner.in_flight_requests()
+    }
+
+    fn config(&self) -> &Config {
+        self.inner.config()
+    }
+
+    fn start_request(self: Pin<&mut Self>, request_id: u64) -> AbortRegistration {
+        self.project().inner.start_request(request_id)
+    }
+
+    fn take_request_items(self: Pin<&mut Self>, request_id: u64) -> RequestItems<Self::Req> {
+        self.project().inner.take_request_items(request_id)
+    }
+
+    fn start_reply_window(self: Pin<&mut Self>, request_id: u64) -> ReplyWindow {
+        self.project().inner.start_reply_window(request_id)
+    }
+}
+
+/// A stream of channels that queue requests by deadline.
+#[pin_project]
+#[derive(Debug)]
+pub struct DeadlineQueueStream<S> {
+    #[pin]
+    inner: S,
+    max_in_flight_requests: usize,
+    capacity: usize,
+}
+
+impl<S> DeadlineQueueStream<S>
+where
+    S: Stream,
+    <S as Stream>::Item: Channel,
+{
+    pub(crate) fn new(inner: S, max_in_flight_requests: usize, capacity: usize) -> Self {
+        Self {
+            inner,
+            max_in_flight_requests,
+            capacity,
+        }
+    }
+}
+
+impl<S> Stream for DeadlineQueueStream<S>
+where
+    S: Stream,
+    <S as Stream>::Item: Channel,
+{
+    type Item = DeadlineQueue<<S as Stream>::Item>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        match ready!(this.inner.poll_next(cx)) {
+            Some(channel) => Poll::Ready(Some(DeadlineQueue::new(
+                channel,
+                *this.max_in_flight_requests,
+                *this.capacity,
+            ))),
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+#[cfg(test)]
+use super::testing::{self, FakeChannel};
+#[cfg(test)]
+use crate::context;
+#[cfg(test)]
+use pin_utils::pin_mut;
+#[cfg(test)]
+use std::time::Duration;
+
+#[cfg(test)]
+fn push_req_due_in(
+    channel: &mut FakeChannel<io::Result<Request<isize>>, ServerMessage<isize>>,
+    id: u64,
+    secs: u64,
+) {
+    let mut context = context::current();
+    context.deadline = SystemTime::now() + Duration::from_secs(secs);
+    channel.stream.push_back(Ok(Request {
+        context,
+        id,
+        method: None,
+        message: 0,
+    }));
+}
+
+#[test]
+fn deadline_queue_serves_earliest_deadline_first() -> io::Result<()> {
+    let queue = DeadlineQueue::new(FakeChannel::default::<isize, isize>(), 1, 10);
+    pin_mut!(queue);
+    push_req_due_in(&mut queue.inner, 0, 30);
+    push_req_due_in(&mut queue.inner, 1, 10);
+    push_req_due_in(&mut queue.inner, 2, 20);
+    push_req_due_in(&mut queue.inner, 3, 10);
+
+    let mut served = vec![];
+    while let Poll::Ready(Some(request)) = queue.as_mut().poll_next(&mut testing::cx()) {
+        served.push(request?.id);
+    }
+    assert_eq!(served, [1, 3, 2, 0]);
+    Ok(())
+}
+
+#[test]
+fn deadline_queue_waits_for_in_flight_requests() -> io::Result<()> {
+    let queue = DeadlineQueue::new(FakeChannel::default::<isize, isize>(), 1, 10);
+    pin_mut!(queue);
+    push_req_due_in(&mut queue.inner, 0, 10);
+    push_req_due_in(&mut queue.inner, 1, 10);
+
+    let request = queue
+        .as_mut()
+        .poll_next(&mut testing::cx())?
+        .map(|r| r.map(|r| r.id));
+    assert_eq!(request, Poll::Ready(Some(0)));
+    queue.as_mut().start_request(0);
+    assert!(queue.as_mut().poll_next(&mut testing::cx()).is_pending());
+    assert_eq!(queue.queued_requests(), 1);
+
+    queue
+        .as_mut()
+        .start_send(ServerMessage::Response(Response {
+            request_id: 0,
+            message: Ok(0),
+        }))?;
+    let request = queue
+        .as_mut()
+        .poll_next(&mut testing::cx())?
+        .map(|r| r.map(|r| r.id));
+    assert_eq!(request, Poll::Ready(Some(1)));
+    Ok(())
+}
+
+#[test]
+fn deadline_queue_drops_expired_and_least_urgent_requests() {
+    let queue = DeadlineQueue::new(FakeChannel::default::<isize, isize>(), 0, 1);
+    pin_mut!(queue);
+    // Expired.
+    queue.inner.push_req(0, 0);
+    push_req_due_in(&mut queue.inner, 1, 10);
+    push_req_due_in(&mut queue.inner, 2, 20);
+
+    assert!(queue.as_mut().poll_next(&mut testing::cx()).is_pending());
+    assert_eq!(queue.queued_requests(), 1);
+    let errors: Vec<_> = queue
+        .inner
+        .responses()
+        .into_iter()
+        .map(|response| {
+            (
+                response.request_id,
+                response.message.as_ref().unwrap_err().kind,
+            )
+        })
+        .collect();
+    assert_eq!(
+        errors,
+        [(0, io::ErrorKind::TimedOut), (2, io::ErrorKind::WouldBlock)]
+    );
+}
@@ -0,0 +1,138 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use futures::{channel::mpsc, prelude::*};
+use std::sync::{Arc, Mutex};
+
+/// Announces that a server is draining, e.g. ahead of a restart, so that its clients send new
+/// requests elsewhere.
+///
+/// Every channel whose config has the `Drain` sends its client a
+/// [`GoAway`](crate::ServerMessage::GoAway) once draining starts. The channels keep serving the
+/// requests they receive, so requests already on their way aren't lost.
+///
+/// ```
+/// # use tarpc::server::{self, BaseChannel, Drain};
+/// # use tarpc::transport::channel;
+/// let drain = Drain::new();
+/// let config = server::Config {
+///     drain: Some(drain.clone()),
+///     ..Default::default()
+/// };
+/// let (_client, transport) = channel::unbounded();
+/// let channel = BaseChannel::<String, String, _>::new(config, transport);
+///
+/// drain.start();
+/// assert!(drain.is_draining());
+/// ```
+///
+/// [`Drain::drained`] waits for the channels to close, e.g. to shut down once their clients have
+/// gone elsewhere.
+///
+/// Clones share the same state.
+#[derive(Clone, Debug, Default)]
+pub struct Drain {
+    inner: Arc<Mutex<DrainInner>>,
+}
+
+#[derive(Debug, Default)]
+struct DrainInner {
+    draining: bool,
+    watchers: Vec<mpsc::UnboundedSender<()>>,
+    /// The number of open channels configured with the drain.
+    channels: usize,
+    /// Notified once draining has started and every channel has closed.
+    drained_watchers: Vec<mpsc::UnboundedSender<()>>,
+}
+
+impl DrainInner {
+    fn is_drained(&self) -> bool {
+        self.draining && self.channels == 0
+    }
+
+    fn notify_if_drained(&mut self) {
+        if self.is_drained() {
+            for watcher in self.drained_watchers.drain(..) {
+                let _ = watcher.unbounded_send(());
+            }
+        }
+    }
+}
+
+/// Counts a channel as open until dropped.
+#[derive(Debug)]
+pub(super) struct Tracked {
+    drain: Drain,
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        let mut inner = self.drain.inner.lock().unwrap();
+        inner.channels -= 1;
+        inner.notify_if_drained();
+    }
+}
+
+impl Drain {
+    /// Returns a new drain that hasn't started.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts draining, telling the clients of every channel to go away.
+    pub fn start(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.draining = true;
+        for watcher in inner.watchers.drain(..) {
+            let _ = watcher.unbounded_send(());
+        }
+        inner.notify_if_drained();
+    }
+
+    /// Returns true once draining has started.
+    pub fn is_draining(&self) -> bool {
+        self.inner.lock().unwrap().draining
+    }
+
+    /// Returns the number of open channels configured with the drain.
+    pub fn channels(&self) -> usize {
+        self.inner.lock().unwrap().channels
+    }
+
+    /// Resolves once draining has started and every channel configured with the drain has closed.
+    pub async fn drained(&self) {
+        let mut drained = {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.is_drained() {
+                return;
+            }
+            let (tx, rx) = mpsc::unbounded();
+            inner.drained_watchers.push(tx);
+            rx
+        };
+        drained.next().await;
+    }
+
+    /// Counts a channel as open until the returned guard is dropped.
+    pub(super) fn track(&self) -> Tracked {
+        self.inner.lock().unwrap().channels += 1;
+        Tracked {
+            drain: self.clone(),
+        }
+    }
+
+    /// Returns a receiver that yields once draining starts.
+    pub(super) fn watch(&self) -> mpsc::UnboundedReceiver<()> {
+        let (tx, rx) = mpsc::unbounded();
+        let mut inner = self.inner.lock().unwrap();
+        if inner.draining {
+            let _ = tx.unbounded_send(());
+        } else {
+            inner.watchers.push(tx);
+        }
+        rx
+    }
+}
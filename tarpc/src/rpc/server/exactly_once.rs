@@ -0,0 +1,691 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use super::{Channel, Config, ReplyWindow, RequestItems};
+use crate::{Request, RequestName, Response, ServerError, ServerMessage};
+use fnv::FnvHashMap;
+use futures::{future::AbortRegistration, prelude::*, ready, task::*};
+use log::debug;
+use pin_project::{pin_project, pinned_drop};
+use std::{
+    fmt, io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// How long the client of a duplicate request is told to wait while the original is served.
+const IN_PROGRESS_RETRY_AFTER: Duration = Duration::from_millis(100);
+
+/// How long after its deadline a claimed request is forgotten if it never receives a response,
+/// e.g. because it was canceled.
+const CLAIM_GRACE: Duration = Duration::from_secs(10);
+
+/// A request that may be executed exactly once, recognized by its context's
+/// [idempotency key](crate::context::Context::idempotency_key).
+///
+/// Implemented for the request types generated by [`service`](crate::service), whose methods
+/// declared with `#[tarpc(exactly_once)]` are executed exactly once.
+pub trait ExactlyOnce {
+    /// The type of responses to the request.
+    type Response;
+
+    /// Returns true if the request must be executed exactly once.
+    fn is_exactly_once(&self) -> bool;
+
+    /// Clones a response to a request that's executed exactly once, so that it can be recorded.
+    fn clone_response(response: &Self::Response) -> Self::Response;
+}
+
+/// The state of an idempotency key, as [claimed](DedupStore::claim) by a request.
+#[derive(Debug)]
+pub enum Claim<Resp> {
+    /// The key is new, and the request that claimed it should be served.
+    Claimed,
+    /// A request with the key is being served.
+    InProgress,
+    /// A request with the key was served, with the given response.
+    Completed(Resp),
+}
+
+/// Records which operations have executed, and their responses, by idempotency key. Keys are
+/// scoped by [`ExactlyOnceChannel`] to the method and principal of the request, so clients can't
+/// claim, or read the responses recorded for, one another's keys.
+///
+/// The store decides how long, and how durably, keys are remembered, which bounds the guarantee
+/// of an [`ExactlyOnceChannel`]. [`MemoryDedupStore`] remembers keys in memory; an implementation
+/// that writes through to a database shared by every server of a service keeps the guarantee
+/// across restarts and servers.
+pub trait DedupStore<Req: ExactlyOnce> {
+    /// Claims `key` for a request about to be served, unless it's already claimed or completed.
+    fn claim(&self, key: &str) -> Claim<Req::Response>;
+
+    /// Records the response to the request that claimed `key`.
+    fn complete(&self, key: &str, response: &Req::Response);
+
+    /// Releases the claim on `key` of a request that failed, or that will never receive a
+    /// response, so that it may be served again. A response already recorded for `key` is kept.
+    fn release(&self, key: &str);
+}
+
+/// A [`DedupStore`] that remembers up to a fixed number of keys in memory, each for a fixed time
+/// after it's claimed or completed. Clones share the same keys.
+///
+/// Keys are forgotten when the process exits, and aren't shared with other servers, so a retry
+/// that reaches a restarted or different server is executed again.
+pub struct MemoryDedupStore<Resp> {
+    capacity: usize,
+    ttl: Duration,
+    /// The response to each key, if completed, and when it's forgotten.
+    keys: Arc<Mutex<FnvHashMap<String, (Option<Resp>, Instant)>>>,
+}
+
+impl<Resp> MemoryDedupStore<Resp> {
+    /// Returns a store that remembers up to `capacity` keys for `ttl` each. The `ttl` should
+    /// exceed the deadlines of requests, so that a request isn't forgotten while it's served, and
+    /// the time clients spend retrying.
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        MemoryDedupStore {
+            capacity,
+            ttl,
+            keys: Arc::new(Mutex::new(FnvHashMap::default())),
+        }
+    }
+
+    /// Returns the number of keys remembered, some of which may have expired.
+    pub fn len(&self) -> usize {
+        self.keys.lock().unwrap().len()
+    }
+
+    /// Returns true if no keys are remembered.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Makes room for `key` if it isn't remembered and the store is full, by forgetting expired
+    /// keys or, failing that, the key that expires soonest.
+    fn make_room(&self, keys: &mut FnvHashMap<String, (Option<Resp>, Instant)>, key: &str) {
+        if keys.len() < self.capacity || keys.contains_key(key) {
+            return;
+        }
+        let now = Instant::now();
+        keys.retain(|_, (_, expires)| *expires > now);
+        if keys.len() >= self.capacity {
+            let soonest = keys
+                .iter()
+                .min_by_key(|(_, (_, expires))| *expires)
+                .map(|(key, _)| key.clone());
+            if let Some(soonest) = soonest {
+                keys.remove(&soonest);
+            }
+        }
+    }
+}
+
+impl<Resp> Clone for MemoryDedupStore<Resp> {
+    fn clone(&self) -> Self {
+        MemoryDedupStore {
+            capacity: self.capacity,
+            ttl: self.ttl,
+            keys: self.keys.clone(),
+        }
+    }
+}
+
+impl<Resp> fmt::Debug for MemoryDedupStore<Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MemoryDedupStore")
+            .field("capacity", &self.capacity)
+            .field("ttl", &self.ttl)
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+impl<Req> DedupStore<Req> for MemoryDedupStore<Req::Response>
+where
+    Req: ExactlyOnce,
+{
+    fn claim(&self, key: &str) -> Claim<Req::Response> {
+        let mut keys = self.keys.lock().unwrap();
+        let now = Instant::now();
+        match keys.get(key) {
+            Some((_, expires)) if *expires <= now => {}
+            Some((Some(response), _)) => return Claim::Completed(Req::clone_response(response)),
+            Some((None, _)) => return Claim::InProgress,
+            None => {}
+        }
+        self.make_room(&mut keys, key);
+        keys.insert(key.to_string(), (None, now + self.ttl));
+        Claim::Claimed
+    }
+
+    fn complete(&self, key: &str, response: &Req::Response) {
+        let recorded = (
+            Some(Req::clone_response(response)),
+            Instant::now() + self.ttl,
+        );
+        let mut keys = self.keys.lock().unwrap();
+        self.make_room(&mut keys, key);
+        keys.insert(key.to_string(), recorded);
+    }
+
+    fn release(&self, key: &str) {
+        let mut keys = self.keys.lock().unwrap();
+        if let Some((None, _)) = keys.get(key) {
+            keys.remove(key);
+        }
+    }
+}
+
+/// A [`Channel`] that executes [`ExactlyOnce`] requests at most once per idempotency key, replaying
+/// the recorded response to retransmissions.
+///
+/// The client stubs of rpcs declared `#[tarpc(exactly_once)]` give each call a random
+/// [idempotency key](crate::context::Context::idempotency_key), unless its context already has
+/// one, and retry transient failures with the same key. The server claims each key in its
+/// [`DedupStore`] before serving the first request with it, and records the response. A
+/// retransmission that arrives while the first request is served is told to retry after a short
+/// wait; one that arrives after is answered with the recorded response. Requests without keys,
+/// and requests for methods not declared `exactly_once`, are served as usual.
+///
+/// Keys are scoped to the method of the request and to the principal returned for it by
+/// `principal`, so that a request is only recognized as a retransmission of one to the same
+/// method by the same principal. The principal should be authenticated, e.g. derived from the
+/// request's [API key](crate::context::Context::api_key) once it's been authorized; the
+/// [tenant ID](crate::context::Context::tenant_id) is chosen by the client, so scoping by it
+/// only keeps well-behaved clients apart.
+///
+/// # Failure envelope
+///
+/// An operation executes exactly once when its response is recorded, and retries reach a server
+/// sharing the store before the store forgets the key. Otherwise:
+///
+/// * If the request fails with a [`ServerError`], e.g. because its deadline passed or its handler
+///   panicked, the claim is released and a retry executes it again, so any effects the handler
+///   had before failing may happen twice. Handlers should fail before taking effect, or take
+///   effect atomically.
+/// * If the connection is lost, or the request canceled, while it's served, its response isn't
+///   recorded. The claim is released when the channel is dropped, or a short grace period after
+///   the request's deadline, after which a retry executes the operation again.
+/// * A retry that reaches a server with a different store, or after the store forgot the key,
+///   executes the operation again.
+/// * A client that gives up retrying can't tell whether the operation executed.
+#[pin_project(PinnedDrop)]
+pub struct ExactlyOnceChannel<C, St, F>
+where
+    C: Channel,
+    C::Req: ExactlyOnce<Response = C::Resp>,
+    St: DedupStore<C::Req>,
+{
+    #[pin]
+    inner: C,
+    store: St,
+    principal: F,
+    /// The scoped key and deadline of each request being served that claimed a key.
+    claims: FnvHashMap<u64, (String, SystemTime)>,
+}
+
+impl<C, St, F> fmt::Debug for ExactlyOnceChannel<C, St, F>
+where
+    C: Channel + fmt::Debug,
+    C::Req: ExactlyOnce<Response = C::Resp>,
+    St: DedupStore<C::Req> + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ExactlyOnceChannel")
+            .field("inner", &self.inner)
+            .field("store", &self.store)
+            .field("claims", &self.claims)
+            .finish()
+    }
+}
+
+impl<C, St, F> ExactlyOnceChannel<C, St, F>
+where
+    C: Channel,
+    C::Req: ExactlyOnce<Response = C::Resp>,
+    St: DedupStore<C::Req>,
+{
+    /// Returns a new `ExactlyOnceChannel` that wraps the given channel and records operations in
+    /// `store`, scoped to the principal returned by `principal`.
+    pub fn new(inner: C, store: St, principal: F) -> Self {
+        ExactlyOnceChannel {
+            inner,
+            store,
+            principal,
+            claims: FnvHashMap::default(),
+        }
+    }
+
+    /// Returns the inner channel.
+    pub fn get_ref(&self) -> &C {
+        &self.inner
+    }
+}
+
+/// Scopes an idempotency key to the method and principal of its request. The principal is
+/// prefixed with its length, so that no principal can forge another's scope.
+fn scoped_key(method: &str, principal: &str, key: &str) -> String {
+    format!("{}:{}:{}:{}", method, principal.len(), principal, key)
+}
+
+impl<C, St, F, K> Stream for ExactlyOnceChannel<C, St, F>
+where
+    C: Channel,
+    C::Req: ExactlyOnce<Response = C::Resp> + RequestName,
+    St: DedupStore<C::Req>,
+    F: Fn(&Request<C::Req>) -> K,
+    K: fmt::Display,
+{
+    type Item = io::Result<Request<C::Req>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        loop {
+            // Ensure a recorded response can be written before reading a request that might be
+            // answered with one.
+            ready!(self.as_mut().project().inner.poll_ready(cx)?);
+
+            let request = match ready!(self.as_mut().project().inner.poll_next(cx)?) {
+                Some(request) => request,
+                None => return Poll::Ready(None),
+            };
+            let key = match request.context.idempotency_key {
+                Some(ref key) if request.message.is_exactly_once() => scoped_key(
+                    request.message.name(),
+                    &(self.principal)(&request).to_string(),
+                    key,
+                ),
+                _ => return Poll::Ready(Some(Ok(request))),
+            };
+            let this = self.as_mut().project();
+            let now = SystemTime::now();
+            let store = &*this.store;
+            this.claims.retain(|_, (key, deadline)| {
+                let expired = *deadline + CLAIM_GRACE <= now;
+                if expired {
+                    store.release(key);
+                }
+                !expired
+            });
+            let message = match this.store.claim(&key) {
+                Claim::Claimed => {
+                    this.claims
+                        .insert(request.id, (key, request.context.deadline));
+                    return Poll::Ready(Some(Ok(request)));
+                }
+                Claim::InProgress => {
+                    debug!(
+                        "[{}] Request {} is a retransmission of a request in progress.",
+                        request.context.trace_id(),
+                        request.id,
+                    );
+                    Err(ServerError {
+                        kind: io::ErrorKind::WouldBlock,
+                        detail: Some(
+                            "A request with the same idempotency key is in progress.".into(),
+                        ),
+                        retry_after: Some(IN_PROGRESS_RETRY_AFTER),
+                    })
+                }
+                Claim::Completed(response) => {
+                    debug!(
+                        "[{}] Replaying the recorded response to request {}.",
+                        request.context.trace_id(),
+                        request.id,
+                    );
+                    Ok(response)
+                }
+            };
+            this.inner.start_send(ServerMessage::Response(Response {
+                request_id: request.id,
+                message,
+            }))?;
+        }
+    }
+}
+
+impl<C, St, F> Sink<ServerMessage<C::Resp>> for ExactlyOnceChannel<C, St, F>
+where
+    C: Channel,
+    C::Req: ExactlyOnce<Response = C::Resp>,
+    St: DedupStore<C::Req>,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: ServerMessage<C::Resp>) -> io::Result<()> {
+        let this = self.project();
+        if let ServerMessage::Response(ref response) = item {
+            if let Some((key, _)) = this.claims.remove(&response.request_id) {
+                match response.message {
+                    Ok(ref response) => this.store.complete(&key, response),
+                    Err(_) => this.store.release(&key),
+                }
+            }
+        }
+        this.inner.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+#[pinned_drop]
+impl<C, St, F> PinnedDrop for ExactlyOnceChannel<C, St, F>
+where
+    C: Channel,
+    C::Req: ExactlyOnce<Response = C::Resp>,
+    St: DedupStore<C::Req>,
+{
+    /// Releases the claims of the requests still being served, whose responses can no longer be
+    /// sent, so that retries on another connection needn't wait for the claims to expire.
+    fn drop(self: Pin<&mut Self>) {
+        let this = self.project();
+        for (_, (key, _)) in this.claims.drain() {
+            this.store.release(&key);
+        }
+    }
+}
+
+impl<C, St, F> AsRef<C> for ExactlyOnceChannel<C, St, F>
+where
+    C: Channel,
+    C::Req: ExactlyOnce<Response = C::Resp>,
+    St: DedupStore<C::Req>,
+{
+    fn as_ref(&self) -> &C {
+        &self.inner
+    }
+}
+
+impl<C, St, F, K> Channel for ExactlyOnceChannel<C, St, F>
+where
+    C: Channel,
+    C::Req: ExactlyOnce<Response = C::Resp> + RequestName,
+    St: DedupStore<C::Req>,
+    F: Fn(&Request<C::Req>) -> K,
+    K: fmt::Display,
+{
+    type Req = C::Req;
+    type Resp = C::Resp;
+
+    fn config(&self) -> &Config {
+        self.inner.config()
+    }
+
+    fn in_flight_requests(self: Pin<&mut Self>) -> usize {
+        self.project().inner.in_flight_requests()
+    }
+
+    fn start_request(self: Pin<&mut Self>, request_id: u64) -> AbortRegistration {
+        self.project().inner.start_request(request_id)
+    }
+
+    fn take_request_items(self: Pin<&mut Self>, request_id: u64) -> RequestItems<Self::Req> {
+        self.project().inner.take_request_items(request_id)
+    }
+
+    fn start_reply_window(self: Pin<&mut Self>, request_id: u64) -> ReplyWindow {
+        self.project().inner.start_reply_window(request_id)
+    }
+}
+
+/// A stream of channels that execute requests exactly once, recording them in a shared
+/// [`DedupStore`].
+#[pin_project]
+#[derive(Debug)]
+pub struct ExactlyOnceStream<S, St, F> {
+    #[pin]
+    inner: S,
+    store: St,
+    principal: F,
+}
+
+impl<S, St, F> ExactlyOnceStream<S, St, F>
+where
+    S: Stream,
+    S::Item: Channel,
+{
+    pub(crate) fn new(inner: S, store: St, principal: F) -> Self {
+        ExactlyOnceStream {
+            inner,
+            store,
+            principal,
+        }
+    }
+}
+
+impl<S, St, F> Stream for ExactlyOnceStream<S, St, F>
+where
+    S: Stream,
+    S::Item: Channel,
+    <S::Item as Channel>::Req: ExactlyOnce<Response = <S::Item as Channel>::Resp>,
+    St: DedupStore<<S::Item as Channel>::Req> + Clone,
+    F: Clone,
+{
+    type Item = ExactlyOnceChannel<S::Item, St, F>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        match ready!(self.as_mut().project().inner.poll_next(cx)) {
+            Some(channel) => Poll::Ready(Some(ExactlyOnceChannel::new(
+                channel,
+                self.store.clone(),
+                self.principal.clone(),
+            ))),
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+#[cfg(test)]
+use super::testing::{self, FakeChannel, PollExt};
+#[cfg(test)]
+use crate::context;
+#[cfg(test)]
+use pin_utils::pin_mut;
+
+/// An operation that must be executed exactly once if `.0`.
+#[cfg(test)]
+struct Op(bool);
+
+#[cfg(test)]
+impl ExactlyOnce for Op {
+    type Response = i32;
+
+    fn is_exactly_once(&self) -> bool {
+        self.0
+    }
+
+    fn clone_response(response: &i32) -> i32 {
+        *response
+    }
+}
+
+#[cfg(test)]
+impl RequestName for Op {
+    fn name(&self) -> &'static str {
+        "op"
+    }
+}
+
+/// Returns a request to perform `op` with the given idempotency key, on behalf of `tenant`.
+#[cfg(test)]
+fn op_request(id: u64, key: &str, tenant: &str, op: Op) -> io::Result<Request<Op>> {
+    let mut ctx = context::current();
+    ctx.idempotency_key = Some(key.into());
+    ctx.tenant_id = Some(tenant.into());
+    Ok(Request {
+        context: ctx,
+        id,
+        method: None,
+        message: op,
+    })
+}
+
+#[cfg(test)]
+fn tenant(request: &Request<Op>) -> String {
+    request.context.tenant_id.clone().unwrap_or_default()
+}
+
+#[test]
+fn retransmissions_are_not_executed_again() {
+    let mut inner = FakeChannel::default::<Op, i32>();
+    let mut push = |id, key: Option<&str>, message| {
+        let mut ctx = context::current();
+        ctx.idempotency_key = key.map(String::from);
+        inner.stream.push_back(Ok(Request {
+            context: ctx,
+            id,
+            method: None,
+            message,
+        }));
+    };
+    push(0, Some("a"), Op(true));
+    push(1, Some("a"), Op(true));
+    push(2, None, Op(true));
+    push(3, Some("b"), Op(false));
+    let store = MemoryDedupStore::new(10, Duration::from_secs(60));
+    let channel = ExactlyOnceChannel::new(inner, store.clone(), tenant);
+    pin_mut!(channel);
+    let mut next_id = || {
+        channel
+            .as_mut()
+            .poll_next(&mut testing::cx())
+            .map(|r| r.map(|r| r.unwrap().id))
+    };
+    // Request 1 is a retransmission of request 0, which is in progress.
+    for expected in [0, 2, 3] {
+        assert_eq!(next_id(), Poll::Ready(Some(expected)));
+    }
+    assert!(next_id().is_done());
+    let responses = channel.inner.responses();
+    assert_eq!(responses.len(), 1);
+    assert_eq!(responses[0].request_id, 1);
+    let error = responses[0].message.as_ref().unwrap_err();
+    assert_eq!(error.kind, io::ErrorKind::WouldBlock);
+
+    // Once request 0 completes, its retransmissions are answered with its response.
+    channel
+        .as_mut()
+        .start_send(ServerMessage::Response(Response {
+            request_id: 0,
+            message: Ok(1),
+        }))
+        .unwrap();
+    let mut ctx = context::current();
+    ctx.idempotency_key = Some("a".into());
+    channel
+        .as_mut()
+        .project()
+        .inner
+        .stream
+        .push_back(Ok(Request {
+            context: ctx,
+            id: 4,
+            method: None,
+            message: Op(true),
+        }));
+    assert!(channel.as_mut().poll_next(&mut testing::cx()).is_done());
+    let responses = channel.inner.responses();
+    assert_eq!(responses.len(), 3);
+    assert_eq!(responses[2].request_id, 4);
+    assert_eq!(responses[2].message.as_ref().ok(), Some(&1));
+    assert_eq!(store.len(), 1);
+}
+
+#[test]
+fn keys_are_scoped_to_principals() {
+    let mut inner = FakeChannel::default::<Op, i32>();
+    inner
+        .stream
+        .push_back(op_request(0, "a", "alice", Op(true)));
+    inner.stream.push_back(op_request(1, "a", "bob", Op(true)));
+    let store = MemoryDedupStore::new(10, Duration::from_secs(60));
+    let channel = ExactlyOnceChannel::new(inner, store.clone(), tenant);
+    pin_mut!(channel);
+
+    // Bob's request isn't mistaken for a retransmission of Alice's.
+    for expected in [0, 1] {
+        let request = channel.as_mut().poll_next(&mut testing::cx());
+        assert_eq!(
+            request.map(|r| r.map(|r| r.unwrap().id)),
+            Poll::Ready(Some(expected))
+        );
+    }
+    assert_eq!(store.len(), 2);
+}
+
+#[test]
+fn claims_are_released_when_the_channel_is_dropped() {
+    let mut inner = FakeChannel::default::<Op, i32>();
+    inner
+        .stream
+        .push_back(op_request(0, "a", "alice", Op(true)));
+    let store = MemoryDedupStore::new(10, Duration::from_secs(60));
+    let mut channel = Box::pin(ExactlyOnceChannel::new(inner, store.clone(), tenant));
+    assert!(channel.as_mut().poll_next(&mut testing::cx()).is_ready());
+    assert_eq!(store.len(), 1);
+
+    drop(channel);
+    assert!(store.is_empty());
+}
+
+#[test]
+fn claims_are_released_after_their_grace_period() {
+    let mut inner = FakeChannel::default::<Op, i32>();
+    let mut expired = op_request(0, "a", "alice", Op(true)).unwrap();
+    expired.context.deadline = SystemTime::now() - CLAIM_GRACE;
+    inner.stream.push_back(Ok(expired));
+    let store = MemoryDedupStore::new(10, Duration::from_secs(60));
+    let channel = ExactlyOnceChannel::new(inner, store.clone(), tenant);
+    pin_mut!(channel);
+    assert!(channel.as_mut().poll_next(&mut testing::cx()).is_ready());
+
+    // The next request with a key sweeps the expired claim, and a retry is served again.
+    channel
+        .as_mut()
+        .project()
+        .inner
+        .stream
+        .push_back(op_request(1, "a", "alice", Op(true)));
+    let request = channel.as_mut().poll_next(&mut testing::cx());
+    assert_eq!(
+        request.map(|r| r.map(|r| r.unwrap().id)),
+        Poll::Ready(Some(1))
+    );
+    assert!(channel.inner.responses().is_empty());
+}
+
+#[test]
+fn completed_keys_respect_the_capacity() {
+    let store = MemoryDedupStore::<i32>::new(1, Duration::from_secs(60));
+    assert!(matches!(
+        DedupStore::<Op>::claim(&store, "a"),
+        Claim::Claimed
+    ));
+    DedupStore::<Op>::complete(&store, "b", &1);
+    assert_eq!(store.len(), 1);
+    assert!(matches!(
+        DedupStore::<Op>::claim(&store, "b"),
+        Claim::Completed(1)
+    ));
+
+    // Releasing a completed key keeps its response.
+    DedupStore::<Op>::release(&store, "b");
+    assert!(matches!(
+        DedupStore::<Op>::claim(&store, "b"),
+        Claim::Completed(1)
+    ));
+}
@@ -0,0 +1,326 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use super::{Channel, ServeStream};
+use fnv::FnvHashMap;
+use futures::{future::BoxFuture, prelude::*, ready, task::*};
+use log::info;
+use pin_project::pin_project;
+use std::{
+    collections::VecDeque,
+    fmt,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+/// Runs the tasks of a server, such as its request handlers, in the background. Implement it to
+/// run a server on an application's own thread pool, instead of on tasks that compete with the
+/// pool's threads.
+///
+/// Any `Fn(BoxFuture<'static, ()>)` is an executor, e.g. `move |task| pool.spawn_ok(task)` for a
+/// [`futures::executor::ThreadPool`](https://docs.rs/futures/0.3/futures/executor/struct.ThreadPool.html).
+pub trait Executor {
+    /// Runs `task` to completion in the background.
+    fn spawn(&self, task: BoxFuture<'static, ()>);
+
+    /// Returns the executor to run the request handlers of a new connection on. By default, it's
+    /// this executor; a [`WorkerPool`] gives each connection its own queue.
+    fn for_connection(&self) -> Box<dyn Executor + Send>
+    where
+        Self: Clone + Send + 'static,
+    {
+        Box::new(self.clone())
+    }
+}
+
+impl Executor for Box<dyn Executor + Send> {
+    fn spawn(&self, task: BoxFuture<'static, ()>) {
+        (**self).spawn(task)
+    }
+}
+
+impl<F> Executor for F
+where
+    F: Fn(BoxFuture<'static, ()>),
+{
+    fn spawn(&self, task: BoxFuture<'static, ()>) {
+        self(task)
+    }
+}
+
+/// A future that drives the server by spawning channels and request handlers on an [`Executor`].
+#[pin_project]
+#[derive(Debug)]
+pub struct RunningOn<St, Se, E> {
+    #[pin]
+    incoming: St,
+    server: Se,
+    executor: E,
+}
+
+impl<St, Se, E> RunningOn<St, Se, E> {
+    pub(crate) fn new(incoming: St, server: Se, executor: E) -> Self {
+        RunningOn {
+            incoming,
+            server,
+            executor,
+        }
+    }
+}
+
+impl<St, C, Se, E> Future for RunningOn<St, Se, E>
+where
+    St: Stream<Item = C>,
+    C: Channel + Send + 'static,
+    C::Req: Send + 'static,
+    C::Resp: Send + 'static,
+    Se: ServeStream<C::Req, Resp = C::Resp> + Send + 'static + Clone,
+    Se::Fut: Send + 'static,
+    Se::Stream: Send + 'static,
+    E: Executor + Clone + Send + 'static,
+{
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        while let Some(channel) = ready!(self.as_mut().project().incoming.poll_next(cx)) {
+            let this = self.as_mut().project();
+            let handler = channel
+                .respond_with_stream(this.server.clone())
+                .execute_on(this.executor.for_connection());
+            this.executor.spawn(handler.boxed());
+        }
+        info!("Server shutting down.");
+        Poll::Ready(())
+    }
+}
+
+/// An [`Executor`] that runs at most a fixed number of request handlers at once, taking turns
+/// between connections, so that a client with a backlog of requests can't starve the others.
+///
+/// Each connection's request handlers wait in the connection's own queue. Whenever a worker is
+/// free, it runs the next handler of the next connection with any waiting, round-robin. Channels
+/// themselves, and any other tasks spawned on the pool directly, run on the underlying executor
+/// right away.
+///
+/// ```
+/// # use futures::{future, prelude::*};
+/// # use tarpc::server::{BaseChannel, Handler, WorkerPool};
+/// # use tarpc::transport::channel;
+/// # #[tokio::main]
+/// # async fn main() {
+/// let pool = WorkerPool::new(|task| drop(tokio::spawn(task)), 4);
+/// # let (_, rx) = channel::unbounded::<_, tarpc::ClientMessage<u32>>();
+/// let server = stream::once(future::ready(BaseChannel::with_defaults(rx)))
+///     .respond_with_on(|_, x: u32| future::ready(x + 1), pool);
+/// # server.await;
+/// # }
+/// ```
+pub struct WorkerPool<E> {
+    shared: Arc<Shared<E>>,
+}
+
+struct Shared<E> {
+    executor: E,
+    workers: usize,
+    queues: Mutex<Queues>,
+}
+
+#[derive(Default)]
+struct Queues {
+    /// The number of workers not running a request handler.
+    idle: usize,
+    /// The connections with handlers waiting, in the order they take their turns.
+    turns: VecDeque<usize>,
+    /// The handlers waiting, by connection. Only connections with handlers waiting have entries.
+    waiting: FnvHashMap<usize, VecDeque<BoxFuture<'static, ()>>>,
+    next_connection: usize,
+}
+
+impl<E> WorkerPool<E>
+where
+    E: Executor + Send + Sync + 'static,
+{
+    /// Returns a pool that runs up to `workers` request handlers at once on `executor`, e.g. one
+    /// for each of the executor's threads.
+    ///
+    /// # Panics
+    ///
+    /// If `workers` is zero.
+    pub fn new(executor: E, workers: usize) -> Self {
+        assert!(workers > 0, "There must be at least one worker.");
+        WorkerPool {
+            shared: Arc::new(Shared {
+                executor,
+                workers,
+                queues: Mutex::new(Queues {
+                    idle: workers,
+                    ..Default::default()
+                }),
+            }),
+        }
+    }
+
+    /// Returns the number of request handlers waiting for a worker.
+    pub fn waiting(&self) -> usize {
+        let queues = self.shared.queues.lock().unwrap();
+        queues.waiting.values().map(VecDeque::len).sum()
+    }
+}
+
+impl<E> Shared<E>
+where
+    E: Executor + Send + Sync + 'static,
+{
+    /// Queues `task` behind the waiting handlers of `connection`.
+    fn enqueue(self: &Arc<Self>, connection: usize, task: BoxFuture<'static, ()>) {
+        {
+            let mut queues = self.queues.lock().unwrap();
+            let waiting = queues.waiting.entry(connection).or_default();
+            waiting.push_back(task);
+            if waiting.len() == 1 {
+                queues.turns.push_back(connection);
+            }
+        }
+        self.dispatch();
+    }
+
+    /// Hands waiting handlers to idle workers, taking them from connections in turn.
+    fn dispatch(self: &Arc<Self>) {
+        let mut ready = vec![];
+        {
+            let mut queues = self.queues.lock().unwrap();
+            while queues.idle > 0 {
+                let connection = match queues.turns.pop_front() {
+                    Some(connection) => connection,
+                    None => break,
+                };
+                let waiting = queues.waiting.get_mut(&connection).unwrap();
+                ready.push(waiting.pop_front().unwrap());
+                if waiting.is_empty() {
+                    queues.waiting.remove(&connection);
+                } else {
+                    queues.turns.push_back(connection);
+                }
+                queues.idle -= 1;
+            }
+        }
+        // Spawned outside the lock, in case the executor runs tasks as they're spawned.
+        for task in ready {
+            let worker = Worker(self.clone());
+            self.executor.spawn(
+                async move {
+                    let _worker = worker;
+                    task.await
+                }
+                .boxed(),
+            );
+        }
+    }
+}
+
+/// Frees its worker when the handler it runs completes, or panics.
+struct Worker<E: Executor + Send + Sync + 'static>(Arc<Shared<E>>);
+
+impl<E> Drop for Worker<E>
+where
+    E: Executor + Send + Sync + 'static,
+{
+    fn drop(&mut self) {
+        self.0.queues.lock().unwrap().idle += 1;
+        self.0.dispatch();
+    }
+}
+
+impl<E> Executor for WorkerPool<E>
+where
+    E: Executor + Send + Sync + 'static,
+{
+    fn spawn(&self, task: BoxFuture<'static, ()>) {
+        self.shared.executor.spawn(task)
+    }
+
+    fn for_connection(&self) -> Box<dyn Executor + Send> {
+        let connection = {
+            let mut queues = self.shared.queues.lock().unwrap();
+            queues.next_connection += 1;
+            queues.next_connection
+        };
+        Box::new(ConnectionQueue {
+            connection,
+            shared: self.shared.clone(),
+        })
+    }
+}
+
+impl<E> Clone for WorkerPool<E> {
+    fn clone(&self) -> Self {
+        WorkerPool {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<E> fmt::Debug for WorkerPool<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WorkerPool")
+            .field("workers", &self.shared.workers)
+            .finish()
+    }
+}
+
+/// Queues the request handlers of one connection of a [`WorkerPool`].
+struct ConnectionQueue<E> {
+    connection: usize,
+    shared: Arc<Shared<E>>,
+}
+
+impl<E> Executor for ConnectionQueue<E>
+where
+    E: Executor + Send + Sync + 'static,
+{
+    fn spawn(&self, task: BoxFuture<'static, ()>) {
+        self.shared.enqueue(self.connection, task)
+    }
+}
+
+#[test]
+fn worker_pool_takes_turns_between_connections() {
+    use futures::executor::block_on;
+
+    let spawned = Arc::new(Mutex::new(VecDeque::<BoxFuture<'static, ()>>::new()));
+    let pool = WorkerPool::new(
+        {
+            let spawned = spawned.clone();
+            move |task| spawned.lock().unwrap().push_back(task)
+        },
+        1,
+    );
+    let ran = Arc::new(Mutex::new(vec![]));
+    let handler = |name: &'static str| {
+        let ran = ran.clone();
+        async move { ran.lock().unwrap().push(name) }.boxed()
+    };
+
+    let chatty = pool.for_connection();
+    let quiet = pool.for_connection();
+    for name in &["a1", "a2", "a3"] {
+        chatty.spawn(handler(name));
+    }
+    for name in &["b1", "b2"] {
+        quiet.spawn(handler(name));
+    }
+    assert_eq!(pool.waiting(), 4);
+
+    loop {
+        let task = spawned.lock().unwrap().pop_front();
+        match task {
+            Some(task) => block_on(task),
+            None => break,
+        }
+    }
+    assert_eq!(*ran.lock().unwrap(), ["a1", "a2", "b1", "a3", "b2"]);
+    assert_eq!(pool.waiting(), 0);
+}
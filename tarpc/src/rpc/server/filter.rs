@@ -131,6 +131,17 @@ where
     fn start_request(self: Pin<&mut Self>, request_id: u64) -> AbortRegistration {
         self.project().inner.start_request(request_id)
     }
+
+    fn take_request_items(
+        self: Pin<&mut Self>,
+        request_id: u64,
+    ) -> server::RequestItems<Self::Req> {
+        self.project().inner.take_request_items(request_id)
+    }
+
+    fn start_reply_window(self: Pin<&mut Self>, request_id: u64) -> server::ReplyWindow {
+        self.project().inner.start_reply_window(request_id)
+    }
 }
 
 impl<C, K> TrackedChannel<C, K> {
@@ -140,7 +151,7 @@ impl<C, K> TrackedChannel<C, K> {
     }
 
     /// Returns the pinned inner channel.
-    fn channel<'a>(self: Pin<&'a mut Self>) -> Pin<&'a mut C> {
+    fn channel(self: Pin<&mut Self>) -> Pin<&mut C> {
         self.project().inner
     }
 }
@@ -296,7 +307,7 @@ where
 fn ctx() -> Context<'static> {
     use futures::task::*;
 
-    Context::from_waker(&noop_waker_ref())
+    Context::from_waker(noop_waker_ref())
 }
 
 #[test]
@@ -310,7 +321,7 @@ fn tracker_drop() {
         counter: Counter::new(),
         dropped_keys: tx,
     };
-    assert_matches!(rx.try_next(), Ok(Some(1)));
+    assert_matches!(rx.try_recv(), Ok(1));
 }
 
 #[test]
@@ -356,7 +367,7 @@ fn tracked_channel_sink() {
     assert_matches!(channel.as_mut().poll_ready(&mut ctx()), Poll::Ready(Ok(())));
     assert_matches!(channel.as_mut().start_send("test"), Ok(()));
     assert_matches!(channel.as_mut().poll_flush(&mut ctx()), Poll::Ready(Ok(())));
-    assert_matches!(chan_rx.try_next(), Ok(Some("test")));
+    assert_matches!(chan_rx.try_recv(), Ok("test"));
 }
 
 #[test]
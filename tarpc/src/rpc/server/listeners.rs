@@ -0,0 +1,95 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use crate::{
+    transport::{self, BoxTransport},
+    ClientMessage, ServerMessage, Transport,
+};
+use futures::{
+    prelude::*,
+    stream::{BoxStream, SelectAll},
+    task::*,
+};
+use std::{fmt, pin::Pin};
+
+/// The connections accepted by several listeners at once, e.g. a TCP port and a Unix socket, or
+/// an IPv4 and an IPv6 socket, so that one server serves them all.
+///
+/// The connections of every listener are merged into one stream of [`BoxTransport`]s, which can
+/// be passed to [`Server::incoming`](super::Server::incoming) like that of a single listener.
+/// The server's handler and anything applied to the stream, e.g. a
+/// [`ChannelFilter`](super::ChannelFilter), are shared by the connections of every listener.
+///
+/// ```
+/// # use tarpc::{server::Listeners, transport::channel};
+/// # use futures::stream;
+/// let (_a, a) = channel::unbounded();
+/// let (_b, b) = channel::unbounded();
+/// let listeners = Listeners::<String, String>::new()
+///     .listen(stream::iter(vec![a]))
+///     .listen(stream::iter(vec![b]));
+/// assert_eq!(listeners.len(), 2);
+/// ```
+///
+/// The stream ends once every listener has ended.
+pub struct Listeners<Req, Resp> {
+    listeners: SelectAll<BoxStream<'static, BoxTransport<ServerMessage<Resp>, ClientMessage<Req>>>>,
+}
+
+impl<Req, Resp> Default for Listeners<Req, Resp> {
+    fn default() -> Self {
+        Listeners {
+            listeners: SelectAll::new(),
+        }
+    }
+}
+
+impl<Req, Resp> fmt::Debug for Listeners<Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Listeners")
+            .field("listeners", &self.listeners.len())
+            .finish()
+    }
+}
+
+impl<Req, Resp> Listeners<Req, Resp>
+where
+    Req: 'static,
+    Resp: 'static,
+{
+    /// Returns a stream without listeners.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accepts the connections of `listener` too.
+    pub fn listen<S, T>(mut self, listener: S) -> Self
+    where
+        S: Stream<Item = T> + Send + 'static,
+        T: Transport<ServerMessage<Resp>, ClientMessage<Req>> + Send + 'static,
+    {
+        self.listeners.push(listener.map(transport::boxed).boxed());
+        self
+    }
+
+    /// Returns the number of listeners that haven't ended.
+    pub fn len(&self) -> usize {
+        self.listeners.len()
+    }
+
+    /// Returns true if every listener has ended.
+    pub fn is_empty(&self) -> bool {
+        self.listeners.is_empty()
+    }
+}
+
+impl<Req, Resp> Stream for Listeners<Req, Resp> {
+    type Item = BoxTransport<ServerMessage<Resp>, ClientMessage<Req>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.listeners.poll_next_unpin(cx)
+    }
+}
@@ -0,0 +1,483 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use super::{Channel, Config, ReplyWindow, RequestItems};
+use crate::{Request, RequestName, Response, ServerError, ServerMessage};
+use fnv::FnvHashMap;
+use futures::{future::AbortRegistration, prelude::*, ready, task::*};
+use log::debug;
+use pin_project::pin_project;
+use std::{
+    collections::VecDeque,
+    fmt, io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+/// Limits on how many requests to each method are handled at once, such as at most two expensive
+/// report generations. Clones share their limits, so a server should apply clones of one
+/// `MethodLimits` to all its channels.
+///
+/// A request to a method at its limit waits in its channel's queue for the method, and fails with
+/// [`io::ErrorKind::WouldBlock`], as when a resource is exhausted, if the queue is full. A request
+/// holds its method's slot until its reply is written, or until it's canceled.
+#[derive(Clone, Debug, Default)]
+pub struct MethodLimits {
+    limits: FnvHashMap<&'static str, Arc<Limit>>,
+}
+
+impl MethodLimits {
+    /// Returns limits that don't limit any method.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits the requests to `method` handled at once to `max_concurrent`, queueing up to
+    /// `queue_capacity` more on each channel.
+    pub fn with_limit(
+        mut self,
+        method: &'static str,
+        max_concurrent: usize,
+        queue_capacity: usize,
+    ) -> Self {
+        assert!(max_concurrent > 0, "max_concurrent must be positive");
+        self.limits.insert(
+            method,
+            Arc::new(Limit {
+                queue_capacity,
+                state: Mutex::new(LimitState {
+                    max_concurrent,
+                    running: 0,
+                    waiters: vec![],
+                }),
+            }),
+        );
+        self
+    }
+
+    /// Changes the number of requests to `method` handled at once to `max_concurrent`, e.g. from
+    /// an admin interface while the server runs. Returns false, and changes nothing, if `method`
+    /// isn't limited. Requests already being handled aren't affected.
+    pub fn set_limit(&self, method: &str, max_concurrent: usize) -> bool {
+        assert!(max_concurrent > 0, "max_concurrent must be positive");
+        let limit = match self.limits.get(method) {
+            Some(limit) => limit,
+            None => return false,
+        };
+        let waiters = {
+            let mut state = limit.state.lock().unwrap();
+            state.max_concurrent = max_concurrent;
+            std::mem::take(&mut state.waiters)
+        };
+        // Queued requests might fit under the new limit.
+        for waiter in waiters {
+            waiter.wake();
+        }
+        true
+    }
+
+    /// Returns the number of requests to `method` being handled, or None if `method` isn't
+    /// limited.
+    pub fn running(&self, method: &str) -> Option<usize> {
+        self.limits
+            .get(method)
+            .map(|limit| limit.state.lock().unwrap().running)
+    }
+}
+
+/// The limit on a single method.
+#[derive(Debug)]
+struct Limit {
+    queue_capacity: usize,
+    state: Mutex<LimitState>,
+}
+
+#[derive(Debug)]
+struct LimitState {
+    max_concurrent: usize,
+    running: usize,
+    /// The channels waiting for a request to the method to complete.
+    waiters: Vec<Waker>,
+}
+
+impl Limit {
+    /// Takes a slot if one is free; otherwise, wakes `cx` once one might be.
+    fn try_acquire(self: &Arc<Self>, cx: &mut Context) -> Option<MethodPermit> {
+        let mut state = self.state.lock().unwrap();
+        if state.running < state.max_concurrent {
+            state.running += 1;
+            return Some(MethodPermit(self.clone()));
+        }
+        if !state.waiters.iter().any(|w| w.will_wake(cx.waker())) {
+            state.waiters.push(cx.waker().clone());
+        }
+        None
+    }
+}
+
+/// A request's slot in its method's limit, which is freed when the request's context, and all its
+/// clones, are dropped.
+pub(crate) struct MethodPermit(Arc<Limit>);
+
+impl fmt::Debug for MethodPermit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("MethodPermit")
+    }
+}
+
+impl Drop for MethodPermit {
+    fn drop(&mut self) {
+        let waiters = {
+            let mut state = self.0.state.lock().unwrap();
+            state.running -= 1;
+            std::mem::take(&mut state.waiters)
+        };
+        for waiter in waiters {
+            waiter.wake();
+        }
+    }
+}
+
+/// A [`Channel`] that enforces [`MethodLimits`] on its requests.
+#[pin_project]
+pub struct MethodLimitChannel<C: Channel> {
+    limits: MethodLimits,
+    /// The requests waiting for a slot, by method.
+    queues: FnvHashMap<&'static str, VecDeque<Request<C::Req>>>,
+    /// True once the inner channel has no more requests.
+    exhausted: bool,
+    #[pin]
+    inner: C,
+}
+
+impl<C: Channel + fmt::Debug> fmt::Debug for MethodLimitChannel<C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MethodLimitChannel")
+            .field("limits", &self.limits)
+            .field("queued_requests", &self.queued_requests())
+            .field("exhausted", &self.exhausted)
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+impl<C: Channel> MethodLimitChannel<C> {
+    /// Returns a new `MethodLimitChannel` that wraps the given channel and enforces `limits`.
+    pub fn new(inner: C, limits: MethodLimits) -> Self {
+        MethodLimitChannel {
+            limits,
+            queues: FnvHashMap::default(),
+            exhausted: false,
+            inner,
+        }
+    }
+
+    /// Returns the inner channel.
+    pub fn get_ref(&self) -> &C {
+        &self.inner
+    }
+
+    /// Returns the number of queued requests.
+    pub fn queued_requests(&self) -> usize {
+        self.queues.values().map(VecDeque::len).sum()
+    }
+
+    /// Returns the first queued request whose method has a free slot.
+    fn dequeue(self: Pin<&mut Self>, cx: &mut Context) -> Option<Request<C::Req>> {
+        let this = self.project();
+        for (method, queue) in this.queues.iter_mut().filter(|(_, q)| !q.is_empty()) {
+            if let Some(permit) = this.limits.limits[method].try_acquire(cx) {
+                let request = queue.pop_front().expect("the queue isn't empty");
+                return Some(holding(request, permit));
+            }
+        }
+        None
+    }
+}
+
+impl<C> MethodLimitChannel<C>
+where
+    C: Channel,
+    C::Req: RequestName,
+{
+    /// Returns the request if it may be handled now. Otherwise, queues it, or returns the message
+    /// rejecting it if its method's queue is full.
+    fn admit(
+        self: Pin<&mut Self>,
+        request: Request<C::Req>,
+        cx: &mut Context,
+    ) -> Result<Option<Request<C::Req>>, ServerMessage<C::Resp>> {
+        let this = self.project();
+        let method = request.message.name();
+        let limit = match this.limits.limits.get(method) {
+            Some(limit) => limit,
+            None => return Ok(Some(request)),
+        };
+        let queue = this.queues.entry(method).or_default();
+        // Requests that arrived earlier take free slots first.
+        if queue.is_empty() {
+            if let Some(permit) = limit.try_acquire(cx) {
+                return Ok(Some(holding(request, permit)));
+            }
+        }
+        if queue.len() < limit.queue_capacity {
+            queue.push_back(request);
+            return Ok(None);
+        }
+        debug!(
+            "[{}] Method {} has reached its concurrency limit ({}), and its queue is full.",
+            request.context.trace_id(),
+            method,
+            limit.state.lock().unwrap().max_concurrent,
+        );
+        Err(ServerMessage::Response(Response {
+            request_id: request.id,
+            message: Err(ServerError {
+                kind: io::ErrorKind::WouldBlock,
+                detail: Some(format!(
+                    "Server is handling too many requests to {}.",
+                    method
+                )),
+                retry_after: None,
+            }),
+        }))
+    }
+}
+
+fn holding<Req>(mut request: Request<Req>, permit: MethodPermit) -> Request<Req> {
+    request.context = request.context.holding(permit);
+    request
+}
+
+impl<C> Stream for MethodLimitChannel<C>
+where
+    C: Channel,
+    C::Req: RequestName,
+{
+    type Item = io::Result<Request<C::Req>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(request) = self.as_mut().dequeue(cx) {
+                return Poll::Ready(Some(Ok(request)));
+            }
+            if self.exhausted {
+                return if self.queued_requests() == 0 {
+                    Poll::Ready(None)
+                } else {
+                    Poll::Pending
+                };
+            }
+            // Ensure a rejection can be written before reading a request that might need one.
+            ready!(self.as_mut().project().inner.poll_ready(cx)?);
+            let request = match ready!(self.as_mut().project().inner.poll_next(cx)?) {
+                Some(request) => request,
+                None => {
+                    *self.as_mut().project().exhausted = true;
+                    continue;
+                }
+            };
+            match self.as_mut().admit(request, cx) {
+                Ok(Some(request)) => return Poll::Ready(Some(Ok(request))),
+                Ok(None) => {}
+                Err(rejection) => self.as_mut().project().inner.start_send(rejection)?,
+            }
+        }
+    }
+}
+
+impl<C> Sink<ServerMessage<C::Resp>> for MethodLimitChannel<C>
+where
+    C: Channel,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: ServerMessage<C::Resp>) -> io::Result<()> {
+        self.project().inner.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+impl<C: Channel> AsRef<C> for MethodLimitChannel<C> {
+    fn as_ref(&self) -> &C {
+        &self.inner
+    }
+}
+
+impl<C> Channel for MethodLimitChannel<C>
+where
+    C: Channel,
+    C::Req: RequestName,
+{
+    type Req = C::Req;
+    type Resp = C::Resp;
+
+    fn config(&self) -> &Config {
+        self.inner.config()
+    }
+
+    fn in_flight_requests(self: Pin<&mut Self>) -> usize {
+        self.project().inner.in_flight_requests()
+    }
+
+    fn start_request(self: Pin<&mut Self>, request_id: u64) -> AbortRegistration {
+        self.project().inner.start_request(request_id)
+    }
+
+    fn take_request_items(self: Pin<&mut Self>, request_id: u64) -> RequestItems<Self::Req> {
+        self.project().inner.take_request_items(request_id)
+    }
+
+    fn start_reply_window(self: Pin<&mut Self>, request_id: u64) -> ReplyWindow {
+        self.project().inner.start_reply_window(request_id)
+    }
+}
+
+/// A stream of channels that enforce shared [`MethodLimits`].
+#[pin_project]
+#[derive(Debug)]
+pub struct MethodLimitStream<S> {
+    #[pin]
+    inner: S,
+    limits: MethodLimits,
+}
+
+impl<S> MethodLimitStream<S>
+where
+    S: Stream,
+    S::Item: Channel,
+{
+    pub(crate) fn new(inner: S, limits: MethodLimits) -> Self {
+        MethodLimitStream { inner, limits }
+    }
+
+    /// Returns the limits the channels enforce.
+    pub fn limits(&self) -> &MethodLimits {
+        &self.limits
+    }
+}
+
+impl<S> Stream for MethodLimitStream<S>
+where
+    S: Stream,
+    S::Item: Channel,
+    <S::Item as Channel>::Req: RequestName,
+{
+    type Item = MethodLimitChannel<S::Item>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        match ready!(self.as_mut().project().inner.poll_next(cx)) {
+            Some(channel) => {
+                Poll::Ready(Some(MethodLimitChannel::new(channel, self.limits.clone())))
+            }
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+#[cfg(test)]
+use super::testing::{self, FakeChannel};
+#[cfg(test)]
+use pin_utils::pin_mut;
+
+#[cfg(test)]
+#[derive(Debug, PartialEq)]
+enum Method {
+    Report,
+    Read,
+}
+
+#[cfg(test)]
+impl RequestName for Method {
+    fn name(&self) -> &'static str {
+        match self {
+            Method::Report => "report",
+            Method::Read => "read",
+        }
+    }
+}
+
+#[test]
+fn method_limit_channel_queues_and_rejects_excess_requests() -> io::Result<()> {
+    let limits = MethodLimits::new().with_limit("report", 1, 1);
+    let mut inner = FakeChannel::default::<Method, ()>();
+    inner.push_req(0, Method::Report);
+    inner.push_req(1, Method::Report);
+    inner.push_req(2, Method::Report);
+    inner.push_req(3, Method::Read);
+    let channel = MethodLimitChannel::new(inner, limits.clone());
+    pin_mut!(channel);
+
+    let report = match channel.as_mut().poll_next(&mut testing::cx()) {
+        Poll::Ready(Some(request)) => request?,
+        poll => panic!("Expected the first report, got {:?}", poll.map(|_| ())),
+    };
+    assert_eq!(report.id, 0);
+    assert_eq!(limits.running("report"), Some(1));
+    // The read isn't held up by the reports.
+    let read = channel
+        .as_mut()
+        .poll_next(&mut testing::cx())?
+        .map(|r| r.map(|r| r.id));
+    assert_eq!(read, Poll::Ready(Some(3)));
+    assert!(channel.as_mut().poll_next(&mut testing::cx()).is_pending());
+    assert_eq!(channel.queued_requests(), 1);
+    let rejected: Vec<_> = channel
+        .inner
+        .responses()
+        .into_iter()
+        .map(|resp| (resp.request_id, resp.message.as_ref().unwrap_err().kind))
+        .collect();
+    assert_eq!(rejected, [(2, io::ErrorKind::WouldBlock)]);
+
+    // Completing the first report frees its slot for the queued one.
+    drop(report);
+    assert_eq!(limits.running("report"), Some(0));
+    let queued = match channel.as_mut().poll_next(&mut testing::cx()) {
+        Poll::Ready(Some(request)) => request?,
+        poll => panic!("Expected the queued report, got {:?}", poll.map(|_| ())),
+    };
+    assert_eq!(queued.id, 1);
+    assert_eq!(limits.running("report"), Some(1));
+    assert_eq!(limits.running("read"), None);
+    Ok(())
+}
+
+#[test]
+fn method_limits_can_be_raised_at_runtime() -> io::Result<()> {
+    let limits = MethodLimits::new().with_limit("report", 1, 1);
+    let mut inner = FakeChannel::default::<Method, ()>();
+    inner.push_req(0, Method::Report);
+    inner.push_req(1, Method::Report);
+    let channel = MethodLimitChannel::new(inner, limits.clone());
+    pin_mut!(channel);
+
+    let mut served = vec![];
+    while let Poll::Ready(Some(request)) = channel.as_mut().poll_next(&mut testing::cx()) {
+        served.push(request?);
+    }
+    assert_eq!(served.len(), 1);
+
+    assert!(limits.set_limit("report", 2));
+    assert!(!limits.set_limit("read", 2));
+    while let Poll::Ready(Some(request)) = channel.as_mut().poll_next(&mut testing::cx()) {
+        served.push(request?);
+    }
+    assert_eq!(served.iter().map(|r| r.id).collect::<Vec<_>>(), [0, 1]);
+    assert_eq!(limits.running("report"), Some(2));
+    Ok(())
+}
@@ -7,8 +7,13 @@
 //! Provides a server that concurrently handles many connections sending multiplexed requests.
 
 use crate::{
-    context, trace, util::Compact, util::TimeUntil, ClientMessage, PollIo, Request, Response,
-    ServerError, Transport,
+    clock::{Clock, SystemClock},
+    context, trace,
+    util::panic_message,
+    util::Compact,
+    window::WindowGrants,
+    ClientMessage, ErrorCode, ErrorFrame, PollIo, ReplyOrder, Request, RequestName, Response,
+    ServerError, ServerMessage, SessionToken, Transport,
 };
 use fnv::FnvHashMap;
 use futures::{
@@ -20,19 +25,76 @@ use futures::{
     task::*,
 };
 use humantime::format_rfc3339;
-use log::{debug, trace};
+use log::{debug, error, trace};
 use pin_project::pin_project;
-use std::{fmt, hash::Hash, io, marker::PhantomData, pin::Pin, time::SystemTime};
-use tokio::time::Timeout;
-
+use rand::Rng;
+use std::{
+    collections::VecDeque,
+    fmt,
+    hash::Hash,
+    io,
+    marker::PhantomData,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+use tokio::time::{Delay, Instant};
+
+mod api_key;
+mod audit;
+mod connections;
+mod deadline;
+mod drain;
+mod exactly_once;
+mod executor;
 mod filter;
+mod listeners;
+mod method_limits;
+mod quota;
+#[cfg(feature = "tokio1")]
+mod requests;
+mod sessions;
+#[cfg(feature = "signal")]
+mod shutdown;
+mod tenant;
 #[cfg(test)]
 mod testing;
 mod throttle;
+mod topics;
+#[cfg(feature = "serde1")]
+mod unknown;
+mod validate;
 
+pub(crate) use self::method_limits::MethodPermit;
+#[cfg(feature = "tokio1")]
+pub use self::requests::{ConnectionId, RequestEnvelope, Requests, Responder};
+#[cfg(feature = "signal")]
+pub use self::shutdown::shutdown_on_signal;
+#[cfg(feature = "serde1")]
+pub use self::unknown::{Lenient, UnknownMethods};
 pub use self::{
+    api_key::{ApiKeyChannel, ApiKeyPolicy, ApiKeyStore, ApiKeyStream},
+    audit::{Audit, AuditFuture, AuditLog, AuditOutcome, AuditRecord, AuditSink},
+    connections::Connections,
+    deadline::{DeadlineQueue, DeadlineQueueStream},
+    drain::Drain,
+    exactly_once::{
+        Claim, DedupStore, ExactlyOnce, ExactlyOnceChannel, ExactlyOnceStream, MemoryDedupStore,
+    },
+    executor::{Executor, RunningOn, WorkerPool},
     filter::ChannelFilter,
+    listeners::Listeners,
+    method_limits::{MethodLimitChannel, MethodLimitStream, MethodLimits},
+    quota::{Quota, QuotaChannel, QuotaStream, Quotas},
+    sessions::Sessions,
+    tenant::{TenantAccounting, TenantFuture, TenantServe, TenantStats},
     throttle::{Throttler, ThrottlerStream},
+    topics::{TopicSubscriber, Topics},
+    validate::{Declared, ValidatingChannel, ValidatingStream, Validator},
 };
 
 /// Manages clients, serving multiplexed requests over each connection.
@@ -49,27 +111,206 @@ impl<Req, Resp> Default for Server<Req, Resp> {
 }
 
 /// Settings that control the behavior of the server.
+///
+/// With the `serde1` feature, the settings can be deserialized, e.g. from a config file; settings
+/// missing from the file keep their defaults. The handles the server shares between its channels —
+/// `load`, `health`, `drain`, and `sessions` — are created by the server, so they're never read
+/// from a file.
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde1", serde(default))]
 pub struct Config {
     /// The number of responses per client that can be buffered server-side before being sent.
     /// `pending_response_buffer` controls the buffer size of the channel that a server's
     /// response tasks use to send responses to the client handler task.
     pub pending_response_buffer: usize,
+    /// The number of items of each streaming request that the server buffers before the client
+    /// must wait for it to catch up. The server closes the channel of a client that sends more.
+    pub stream_window: u32,
+    /// The number of notifications per client that can be buffered server-side before being
+    /// sent, plus one per [`Notifier`]. Notifying a client whose buffer is full fails.
+    pub notification_buffer: usize,
+    /// The load the server reports to its clients, if any. Clients that balance requests across
+    /// servers use it to steer requests away from busy ones.
+    #[cfg_attr(feature = "serde1", serde(skip))]
+    pub load: Option<Load>,
+    /// The health the server reports to clients that check it. A server without one always
+    /// reports that it's serving.
+    #[cfg_attr(feature = "serde1", serde(skip))]
+    pub health: Option<Health>,
+    /// Tells the server's clients to go away once the server starts draining.
+    #[cfg_attr(feature = "serde1", serde(skip))]
+    pub drain: Option<Drain>,
+    /// How long a channel may go without requests in flight before it's closed, if at all.
+    #[cfg_attr(
+        feature = "serde1",
+        serde(serialize_with = "crate::util::serde::serialize_optional_duration_human")
+    )]
+    #[cfg_attr(
+        feature = "serde1",
+        serde(deserialize_with = "crate::util::serde::deserialize_optional_duration_human")
+    )]
+    pub idle_timeout: Option<Duration>,
+    /// How long a new channel may wait for its client's first message; None waits forever. A
+    /// channel whose client hasn't sent a complete message, e.g. its handshake or first request,
+    /// within the timeout is closed, so that clients that connect and then stall, or trickle in a
+    /// frame a byte at a time, can't hold connections open.
+    #[cfg_attr(
+        feature = "serde1",
+        serde(serialize_with = "crate::util::serde::serialize_optional_duration_human")
+    )]
+    #[cfg_attr(
+        feature = "serde1",
+        serde(deserialize_with = "crate::util::serde::deserialize_optional_duration_human")
+    )]
+    pub handshake_timeout: Option<Duration>,
+    /// How long a channel may stay open; None keeps it open forever. Once a channel is this old,
+    /// give or take a tenth so that channels opened together don't all retire at once, it tells
+    /// its client to go away, as while draining, and stops reading requests `lifetime_grace`
+    /// later. Retiring channels periodically rebalances clients that connect through a load
+    /// balancer.
+    #[cfg_attr(
+        feature = "serde1",
+        serde(serialize_with = "crate::util::serde::serialize_optional_duration_human")
+    )]
+    #[cfg_attr(
+        feature = "serde1",
+        serde(deserialize_with = "crate::util::serde::deserialize_optional_duration_human")
+    )]
+    pub max_lifetime: Option<Duration>,
+    /// How long a retiring channel keeps reading requests after telling its client to go away.
+    #[cfg_attr(
+        feature = "serde1",
+        serde(serialize_with = "crate::util::serde::serialize_duration_human")
+    )]
+    #[cfg_attr(
+        feature = "serde1",
+        serde(deserialize_with = "crate::util::serde::deserialize_duration_human")
+    )]
+    pub lifetime_grace: Duration,
+    /// Whether the server agrees to send replies in [request order](ReplyOrder::Request) to
+    /// clients that ask for it. A server that doesn't tells them it sends replies in completion
+    /// order, and they give up on it rather than rely on an order that doesn't hold.
+    pub ordered_replies: bool,
+    /// How many requests each client may have in flight, if the number is limited. The channel
+    /// grants its client [credit](ServerMessage::RequestCredit) for this many requests, and
+    /// grants more as requests complete, so that an overloaded server slows its clients down
+    /// instead of letting requests time out in its queue. Clients that ignore the credit aren't
+    /// rejected; [`Channel::max_concurrent_requests`] enforces a limit.
+    pub request_window: Option<u32>,
+    /// The sessions of the server's clients, if it keeps them. A server that doesn't tells
+    /// clients that ask for a session that they have none.
+    #[cfg_attr(feature = "serde1", serde(skip))]
+    pub sessions: Option<Sessions>,
+    /// The clock the server measures deadlines against. Tests can replace it with a
+    /// [`VirtualClock`](crate::clock::VirtualClock) to control time rather than sleep.
+    #[cfg_attr(feature = "serde1", serde(skip))]
+    pub clock: Arc<dyn Clock>,
+    /// Where the server's channels count the allocations they and their request handlers make,
+    /// if they count them. See [`alloc_audit`](crate::alloc_audit).
+    #[cfg(feature = "alloc-audit")]
+    #[cfg_attr(feature = "serde1", serde(skip))]
+    pub alloc_audit: Option<crate::alloc_audit::AllocAudit>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
             pending_response_buffer: 100,
+            stream_window: 64,
+            notification_buffer: 100,
+            load: None,
+            health: None,
+            drain: None,
+            idle_timeout: None,
+            handshake_timeout: None,
+            max_lifetime: None,
+            lifetime_grace: Duration::from_secs(10),
+            ordered_replies: true,
+            request_window: None,
+            sessions: None,
+            clock: Arc::new(SystemClock),
+            #[cfg(feature = "alloc-audit")]
+            alloc_audit: None,
         }
     }
 }
 
+#[cfg(feature = "config")]
+impl Config {
+    /// Reads the settings from the TOML or JSON file at `path`, choosing the format by the file's
+    /// extension.
+    pub fn from_file(path: impl AsRef<std::path::Path>) -> io::Result<Self> {
+        crate::util::from_file(path.as_ref())
+    }
+}
+
+/// The load of a server, e.g. its queue depth or CPU utilization, as a number that grows with how
+/// busy the server is. The server updates it, and each channel sends it to the client alongside
+/// its replies whenever it changes.
+///
+/// Clones share the same load, so a single `Load` can be shared by every channel of a server.
+#[derive(Clone, Debug, Default)]
+pub struct Load {
+    load: Arc<AtomicU32>,
+}
+
+impl Load {
+    /// Returns a new load of zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the load.
+    pub fn set(&self, load: u32) {
+        self.load.store(load, Ordering::Relaxed);
+    }
+
+    /// Returns the load.
+    pub fn get(&self) -> u32 {
+        self.load.load(Ordering::Relaxed)
+    }
+}
+
+/// Whether a server is serving, as reported to clients that check its health, e.g. to evict it
+/// from their rotation while it's unable to serve.
+///
+/// Clones share the same health, so a single `Health` can be shared by every channel of a server.
+#[derive(Clone, Debug)]
+pub struct Health {
+    serving: Arc<AtomicBool>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Health {
+            serving: Arc::new(AtomicBool::new(true)),
+        }
+    }
+}
+
+impl Health {
+    /// Returns a new health that's serving.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets whether the server is serving.
+    pub fn set_serving(&self, serving: bool) {
+        self.serving.store(serving, Ordering::Relaxed);
+    }
+
+    /// Returns true if the server is serving.
+    pub fn is_serving(&self) -> bool {
+        self.serving.load(Ordering::Relaxed)
+    }
+}
+
 impl Config {
     /// Returns a channel backed by `transport` and configured with `self`.
     pub fn channel<Req, Resp, T>(self, transport: T) -> BaseChannel<Req, Resp, T>
     where
-        T: Transport<Response<Resp>, ClientMessage<Req>>,
+        T: Transport<ServerMessage<Resp>, ClientMessage<Req>>,
     {
         BaseChannel::new(self, transport)
     }
@@ -93,7 +334,7 @@ impl<Req, Resp> Server<Req, Resp> {
     pub fn incoming<S, T>(self, listener: S) -> impl Stream<Item = BaseChannel<Req, Resp, T>>
     where
         S: Stream<Item = T>,
-        T: Transport<Response<Resp>, ClientMessage<Req>>,
+        T: Transport<ServerMessage<Resp>, ClientMessage<Req>>,
     {
         listener.map(move |t| BaseChannel::new(self.config.clone(), t))
     }
@@ -124,6 +365,375 @@ where
     }
 }
 
+/// A reply to a single request: either one response, or a stream of items.
+#[pin_project(project = ReplyProj)]
+#[derive(Debug)]
+pub enum Reply<F, S> {
+    /// A single response, sent to the client when the future completes.
+    Unary(#[pin] F),
+    /// A stream of items, each sent to the client as soon as it is produced.
+    Stream(#[pin] S),
+    /// A single response, preceded by the progress yielded by the stream while the future runs.
+    Progress(#[pin] F, #[pin] S),
+}
+
+/// The items a client streams after a request, as part of the same call.
+///
+/// The stream ends when the client finishes sending items. It is empty if the request was not a
+/// streaming request.
+#[derive(Debug)]
+pub struct RequestItems<Req> {
+    items: Option<(mpsc::UnboundedReceiver<Req>, WindowGrants)>,
+}
+
+impl<Req> RequestItems<Req> {
+    fn new(grants: WindowGrants) -> (mpsc::UnboundedSender<Req>, Self) {
+        let (tx, rx) = mpsc::unbounded();
+        (
+            tx,
+            RequestItems {
+                items: Some((rx, grants)),
+            },
+        )
+    }
+
+    /// Returns an empty stream of items.
+    pub fn empty() -> Self {
+        RequestItems { items: None }
+    }
+}
+
+impl<Req> Stream for RequestItems<Req> {
+    type Item = Req;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Req>> {
+        match self.items {
+            Some((ref mut items, ref mut grants)) => {
+                let item = ready!(items.poll_next_unpin(cx));
+                if item.is_some() {
+                    grants.drained();
+                }
+                Poll::Ready(item)
+            }
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+/// Pushes notifications to the client of a [`BaseChannel`]. The channel buffers up to
+/// [`Config::notification_buffer`] notifications waiting to be written.
+#[derive(Debug)]
+pub struct Notifier<Resp> {
+    notifications: mpsc::Sender<Resp>,
+    close_requests: mpsc::UnboundedSender<()>,
+}
+
+impl<Resp> Clone for Notifier<Resp> {
+    fn clone(&self) -> Self {
+        Notifier {
+            notifications: self.notifications.clone(),
+            close_requests: self.close_requests.clone(),
+        }
+    }
+}
+
+impl<Resp> Notifier<Resp> {
+    /// Queues `notification` to be written to the client. Fails with
+    /// [`io::ErrorKind::WouldBlock`] if the channel's buffer is full, e.g. because the client
+    /// isn't keeping up, and with [`io::ErrorKind::ConnectionReset`] if the channel is closed.
+    pub fn notify(&mut self, notification: Resp) -> io::Result<()> {
+        self.notifications.try_send(notification).map_err(|e| {
+            if e.is_full() {
+                io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "The client's notification buffer is full.",
+                )
+            } else {
+                io::Error::from(io::ErrorKind::ConnectionReset)
+            }
+        })
+    }
+
+    /// Returns true if the channel is closed, so notifications can no longer be sent.
+    pub fn is_closed(&self) -> bool {
+        self.notifications.is_closed()
+    }
+
+    /// Closes the channel, as [`Context::close_connection`](context::Context::close_connection)
+    /// does, e.g. because its client isn't keeping up with its notifications.
+    pub fn close(&self) {
+        let _ = self.close_requests.unbounded_send(());
+    }
+}
+
+/// Reports the progress of a request to the client while its handler is still working. Created
+/// for each request by a handler made with [`with_progress`].
+#[derive(Debug)]
+pub struct Progress<Resp> {
+    updates: mpsc::UnboundedSender<Resp>,
+}
+
+impl<Resp> Clone for Progress<Resp> {
+    fn clone(&self) -> Self {
+        Progress {
+            updates: self.updates.clone(),
+        }
+    }
+}
+
+impl<Resp> Progress<Resp> {
+    /// Queues `progress` to be sent to the client ahead of the response. Fails with
+    /// [`io::ErrorKind::ConnectionReset`] if the request is no longer being handled.
+    pub fn report(&self, progress: Resp) -> io::Result<()> {
+        self.updates
+            .unbounded_send(progress)
+            .map_err(|_| io::Error::from(io::ErrorKind::ConnectionReset))
+    }
+}
+
+/// The credit a handler has to send the items of its streamed reply. The client grants credit as
+/// it drains items, so that a fast handler can't overwhelm a slow client.
+#[derive(Debug)]
+pub struct ReplyWindow {
+    credits: u32,
+    grants: Option<mpsc::UnboundedReceiver<u32>>,
+}
+
+impl ReplyWindow {
+    fn new() -> (mpsc::UnboundedSender<u32>, Self) {
+        let (tx, rx) = mpsc::unbounded();
+        (
+            tx,
+            ReplyWindow {
+                credits: 0,
+                grants: Some(rx),
+            },
+        )
+    }
+
+    /// Returns a window that never runs out of credit.
+    pub fn unlimited() -> Self {
+        ReplyWindow {
+            credits: 0,
+            grants: None,
+        }
+    }
+
+    /// Resolves once there is credit to send an item.
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let grants = match self.grants {
+            Some(ref mut grants) => grants,
+            None => return Poll::Ready(()),
+        };
+        while self.credits == 0 {
+            match ready!(grants.poll_next_unpin(cx)) {
+                Some(credits) => self.credits = self.credits.saturating_add(credits),
+                // The channel stopped tracking the request, so there's no one left to wait for.
+                None => {
+                    self.grants = None;
+                    break;
+                }
+            }
+        }
+        Poll::Ready(())
+    }
+
+    /// Spends the credit for one item.
+    fn consume(&mut self) {
+        self.credits = self.credits.saturating_sub(1);
+    }
+}
+
+/// A request handler that may stream its replies, and may receive a stream of items with each
+/// request.
+///
+/// Any [`Serve`] can be used as a `ServeStream` whose replies are all unary by wrapping it in
+/// [`Unary`].
+pub trait ServeStream<Req>: Sized + Clone {
+    /// Type of response, and of each item of a streamed reply.
+    type Resp;
+
+    /// Type of response future.
+    type Fut: Future<Output = Self::Resp>;
+
+    /// Type of reply stream.
+    type Stream: Stream<Item = Self::Resp>;
+
+    /// Replies to a single request, and the items streamed with it.
+    fn serve_stream(
+        self,
+        ctx: context::Context,
+        req: Req,
+        items: RequestItems<Req>,
+    ) -> Reply<Self::Fut, Self::Stream>;
+}
+
+/// A [`ServeStream`] that replies to every request with the single response of a [`Serve`].
+#[derive(Clone, Debug)]
+pub struct Unary<S>(pub S);
+
+impl<Req, S> ServeStream<Req> for Unary<S>
+where
+    S: Serve<Req>,
+{
+    type Resp = S::Resp;
+    type Fut = S::Fut;
+    type Stream = stream::Empty<S::Resp>;
+
+    fn serve_stream(
+        self,
+        ctx: context::Context,
+        req: Req,
+        _: RequestItems<Req>,
+    ) -> Reply<S::Fut, Self::Stream> {
+        Reply::Unary(self.0.serve(ctx, req))
+    }
+}
+
+/// Returns a request handler that replies to every request with the stream returned by `f`.
+pub fn streaming<F>(f: F) -> Streaming<F> {
+    Streaming { f }
+}
+
+/// A [`ServeStream`] that streams every reply. Created by [`streaming`].
+#[derive(Clone, Debug)]
+pub struct Streaming<F> {
+    f: F,
+}
+
+impl<Req, Resp, St, F> ServeStream<Req> for Streaming<F>
+where
+    F: FnOnce(context::Context, Req) -> St + Clone,
+    St: Stream<Item = Resp>,
+{
+    type Resp = Resp;
+    type Fut = future::Pending<Resp>;
+    type Stream = St;
+
+    fn serve_stream(
+        self,
+        ctx: context::Context,
+        req: Req,
+        _: RequestItems<Req>,
+    ) -> Reply<Self::Fut, St> {
+        Reply::Stream((self.f)(ctx, req))
+    }
+}
+
+/// Returns a request handler that responds to every request, and the items streamed with it, with
+/// the future returned by `f`.
+pub fn with_items<F>(f: F) -> WithItems<F> {
+    WithItems { f }
+}
+
+/// A [`ServeStream`] that receives the items streamed with each request and replies with a single
+/// response. Created by [`with_items`].
+#[derive(Clone, Debug)]
+pub struct WithItems<F> {
+    f: F,
+}
+
+impl<Req, Resp, Fut, F> ServeStream<Req> for WithItems<F>
+where
+    F: FnOnce(context::Context, Req, RequestItems<Req>) -> Fut + Clone,
+    Fut: Future<Output = Resp>,
+{
+    type Resp = Resp;
+    type Fut = Fut;
+    type Stream = stream::Empty<Resp>;
+
+    fn serve_stream(
+        self,
+        ctx: context::Context,
+        req: Req,
+        items: RequestItems<Req>,
+    ) -> Reply<Fut, Self::Stream> {
+        Reply::Unary((self.f)(ctx, req, items))
+    }
+}
+
+/// Returns a request handler that responds to every request with the future returned by `f`, which
+/// may report its progress along the way.
+pub fn with_progress<F, Resp>(f: F) -> WithProgress<F, Resp> {
+    WithProgress {
+        f,
+        ghost: PhantomData,
+    }
+}
+
+/// A [`ServeStream`] that reports the progress of each request before responding. Created by
+/// [`with_progress`].
+#[derive(Debug)]
+pub struct WithProgress<F, Resp> {
+    f: F,
+    /// Types the progress reported.
+    ghost: PhantomData<fn(Progress<Resp>)>,
+}
+
+impl<F: Clone, Resp> Clone for WithProgress<F, Resp> {
+    fn clone(&self) -> Self {
+        WithProgress {
+            f: self.f.clone(),
+            ghost: PhantomData,
+        }
+    }
+}
+
+impl<Req, Resp, Fut, F> ServeStream<Req> for WithProgress<F, Resp>
+where
+    F: FnOnce(context::Context, Req, Progress<Resp>) -> Fut + Clone,
+    Fut: Future<Output = Resp>,
+{
+    type Resp = Resp;
+    type Fut = Fut;
+    type Stream = mpsc::UnboundedReceiver<Resp>;
+
+    fn serve_stream(
+        self,
+        ctx: context::Context,
+        req: Req,
+        _: RequestItems<Req>,
+    ) -> Reply<Fut, Self::Stream> {
+        let (updates, progress) = mpsc::unbounded();
+        Reply::Progress((self.f)(ctx, req, Progress { updates }), progress)
+    }
+}
+
+/// Returns a request handler that replies to every request, and the items streamed with it, with
+/// the stream returned by `f`.
+///
+/// The reply may continue after the client stops sending items, and the items end early if the
+/// reply does.
+pub fn bidirectional<F>(f: F) -> Bidirectional<F> {
+    Bidirectional { f }
+}
+
+/// A [`ServeStream`] that receives the items streamed with each request and streams its reply.
+/// Created by [`bidirectional`].
+#[derive(Clone, Debug)]
+pub struct Bidirectional<F> {
+    f: F,
+}
+
+impl<Req, Resp, St, F> ServeStream<Req> for Bidirectional<F>
+where
+    F: FnOnce(context::Context, Req, RequestItems<Req>) -> St + Clone,
+    St: Stream<Item = Resp>,
+{
+    type Resp = Resp;
+    type Fut = future::Pending<Resp>;
+    type Stream = St;
+
+    fn serve_stream(
+        self,
+        ctx: context::Context,
+        req: Req,
+        items: RequestItems<Req>,
+    ) -> Reply<Self::Fut, St> {
+        Reply::Stream((self.f)(ctx, req, items))
+    }
+}
+
 /// A utility trait enabling a stream to fluently chain a request handler.
 pub trait Handler<C>
 where
@@ -144,17 +754,107 @@ where
         ThrottlerStream::new(self, n)
     }
 
+    /// Caps the number of concurrent requests per channel, queueing up to `capacity` more and
+    /// serving them earliest deadline first.
+    fn queue_by_deadline_per_channel(
+        self,
+        max_in_flight_requests: usize,
+        capacity: usize,
+    ) -> DeadlineQueueStream<Self> {
+        DeadlineQueueStream::new(self, max_in_flight_requests, capacity)
+    }
+
+    /// Enforces `quota` on the requests of each principal, as identified by `principal`, across
+    /// all channels.
+    fn quota_per_principal<K, KF>(self, quota: Quota, principal: KF) -> QuotaStream<Self, K, KF>
+    where
+        K: fmt::Display + Eq + Hash + Clone,
+        KF: Fn(&Request<C::Req>) -> K + Clone,
+    {
+        QuotaStream::new(self, quota, principal)
+    }
+
+    /// Enforces `limits` on how many requests to each method are handled at once, across all
+    /// channels.
+    fn limit_methods(self, limits: MethodLimits) -> MethodLimitStream<Self>
+    where
+        C::Req: RequestName,
+    {
+        MethodLimitStream::new(self, limits)
+    }
+
+    /// Authorizes each request against the policy of its [API key](context::Context::api_key),
+    /// as found in `store`.
+    fn authorize_api_keys<St>(self, store: St) -> ApiKeyStream<Self, St>
+    where
+        C::Req: RequestName,
+        St: ApiKeyStore + Clone,
+    {
+        ApiKeyStream::new(self, store)
+    }
+
+    /// Rejects the requests of each channel that `validator` finds invalid, before they're served.
+    /// [`Declared`] validates requests with the validators declared on their methods.
+    fn validate_requests<V>(self, validator: V) -> ValidatingStream<Self, V>
+    where
+        V: Validator<C::Req> + Clone,
+    {
+        ValidatingStream::new(self, validator)
+    }
+
+    /// Executes each channel's [`ExactlyOnce`] requests at most once per idempotency key of each
+    /// principal, as identified by `principal`, recording them in `store`. See
+    /// [`ExactlyOnceChannel`] for when the guarantee holds.
+    fn exactly_once<St, K, KF>(self, store: St, principal: KF) -> ExactlyOnceStream<Self, St, KF>
+    where
+        C::Req: ExactlyOnce<Response = C::Resp> + RequestName,
+        St: DedupStore<C::Req> + Clone,
+        K: fmt::Display,
+        KF: Fn(&Request<C::Req>) -> K + Clone,
+    {
+        ExactlyOnceStream::new(self, store, principal)
+    }
+
     /// Responds to all requests with `server`.
     #[cfg(feature = "tokio1")]
-    fn respond_with<S>(self, server: S) -> Running<Self, S>
+    fn respond_with<S>(self, server: S) -> Running<Self, Unary<S>>
     where
         S: Serve<C::Req, Resp = C::Resp>,
+    {
+        self.respond_with_stream(Unary(server))
+    }
+
+    /// Responds to all requests with `server`, which may stream its replies.
+    #[cfg(feature = "tokio1")]
+    fn respond_with_stream<S>(self, server: S) -> Running<Self, S>
+    where
+        S: ServeStream<C::Req, Resp = C::Resp>,
     {
         Running {
             incoming: self,
             server,
         }
     }
+
+    /// Responds to all requests with `server`, running channels and request handlers on
+    /// `executor`.
+    fn respond_with_on<S, E>(self, server: S, executor: E) -> RunningOn<Self, Unary<S>, E>
+    where
+        S: Serve<C::Req, Resp = C::Resp>,
+        E: Executor,
+    {
+        self.respond_with_stream_on(Unary(server), executor)
+    }
+
+    /// Responds to all requests with `server`, which may stream its replies, running channels and
+    /// request handlers on `executor`.
+    fn respond_with_stream_on<S, E>(self, server: S, executor: E) -> RunningOn<Self, S, E>
+    where
+        S: ServeStream<C::Req, Resp = C::Resp>,
+        E: Executor,
+    {
+        RunningOn::new(self, server, executor)
+    }
 }
 
 impl<S, C> Handler<C> for S
@@ -174,20 +874,122 @@ pub struct BaseChannel<Req, Resp, T> {
     transport: Fuse<T>,
     /// Number of requests currently being responded to.
     in_flight_requests: FnvHashMap<u64, AbortHandle>,
+    /// Forwards the items of in-flight streaming requests to their handlers, along with the credit
+    /// the client has left to send items. A client that sends more items than it has credit for
+    /// has the channel closed on it, so each handler queues at most a window of items.
+    request_items: FnvHashMap<u64, (mpsc::UnboundedSender<Req>, u32)>,
+    /// Items of streaming requests that haven't yet been taken by a handler.
+    pending_request_items: FnvHashMap<u64, RequestItems<Req>>,
+    /// Forwards the client's grants of credit to the handlers of streamed replies.
+    reply_windows: FnvHashMap<u64, mpsc::UnboundedSender<u32>>,
+    /// Grants of credit for request items, waiting to be written to the wire.
+    #[pin]
+    window_updates: mpsc::UnboundedReceiver<(u64, u32)>,
+    /// Handed out to request items to grant credit as they're drained.
+    window_updates_tx: mpsc::UnboundedSender<(u64, u32)>,
+    /// A grant of credit that was received but couldn't yet be written.
+    pending_window_update: Option<(u64, u32)>,
+    /// Notifications waiting to be pushed to the client.
+    #[pin]
+    notifications: mpsc::Receiver<Resp>,
+    /// Cloned into every [`Notifier`] for the channel.
+    notifications_tx: mpsc::Sender<Resp>,
+    /// A notification that was received but couldn't yet be written.
+    pending_notification: Option<Resp>,
+    /// The load last reported to the client.
+    reported_load: Option<u32>,
+    /// The IDs of health checks that haven't yet been answered.
+    health_checks: VecDeque<u64>,
+    /// Yields once the server starts draining. None once the client has been told to go away.
+    draining: Option<mpsc::UnboundedReceiver<()>>,
+    /// Set when the client must be told to go away, until it's told.
+    go_away: bool,
+    /// Yields when a handler asks for the channel to close.
+    #[pin]
+    close_requests: mpsc::UnboundedReceiver<()>,
+    /// Handed out in the contexts of requests, so that their handlers can close the channel.
+    close_requests_tx: mpsc::UnboundedSender<()>,
+    /// Elapses once the channel has gone `idle_timeout` without requests in flight.
+    idle: Option<Delay>,
+    /// Elapses if the client hasn't sent a message within `handshake_timeout`. None once it has.
+    first_message: Option<Delay>,
+    /// Elapses once the channel reaches its lifetime, and again once its grace period ends.
+    lifetime: Option<Delay>,
+    /// Set once the channel's lifetime is reached.
+    retiring: bool,
+    /// Set once the channel stops reading requests, because it was idle or retired.
+    closed: bool,
+    /// The order the channel sends replies in, as agreed with the client.
+    reply_order: ReplyOrder,
+    /// The answer to the client's handshake, waiting to be written to the wire.
+    pending_handshake: Option<ReplyOrder>,
+    /// If replies are sent in request order, the IDs of the requests whose replies haven't yet
+    /// been written, in the order they arrived.
+    reply_queue: VecDeque<u64>,
+    /// The messages of each queued reply that wait for the replies before it to be written.
+    held_replies: FnvHashMap<u64, VecDeque<ServerMessage<Resp>>>,
+    /// Credit for requests, waiting to be granted to the client, if its requests are limited.
+    pending_request_credit: u32,
+    /// The error that stopped the channel reading requests, waiting to be written to the wire.
+    pending_error: Option<ErrorFrame>,
+    /// The channel's membership in the session the client asked for, if any.
+    session: Option<sessions::Membership>,
+    /// Counts the channel as open in its config's drain.
+    _tracked: Option<drain::Tracked>,
     /// Types the request and response.
     ghost: PhantomData<(Req, Resp)>,
 }
 
 impl<Req, Resp, T> BaseChannel<Req, Resp, T>
 where
-    T: Transport<Response<Resp>, ClientMessage<Req>>,
+    T: Transport<ServerMessage<Resp>, ClientMessage<Req>>,
 {
     /// Creates a new channel backed by `transport` and configured with `config`.
     pub fn new(config: Config, transport: T) -> Self {
+        let (window_updates_tx, window_updates) = mpsc::unbounded();
+        let (notifications_tx, notifications) = mpsc::channel(config.notification_buffer);
+        let (close_requests_tx, close_requests) = mpsc::unbounded();
+        let draining = config.drain.as_ref().map(Drain::watch);
+        let tracked = config.drain.as_ref().map(Drain::track);
+        let idle = config.idle_timeout.map(tokio::time::delay_for);
+        let first_message = config.handshake_timeout.map(tokio::time::delay_for);
+        // The client is granted its initial window before any reply.
+        let pending_request_credit = config.request_window.unwrap_or(0);
+        let lifetime = config.max_lifetime.map(|lifetime| {
+            tokio::time::delay_for(lifetime.mul_f64(rand::thread_rng().gen_range(0.9, 1.1)))
+        });
         BaseChannel {
             config,
             transport: transport.fuse(),
             in_flight_requests: FnvHashMap::default(),
+            request_items: FnvHashMap::default(),
+            pending_request_items: FnvHashMap::default(),
+            reply_windows: FnvHashMap::default(),
+            window_updates,
+            window_updates_tx,
+            pending_window_update: None,
+            notifications,
+            notifications_tx,
+            pending_notification: None,
+            reported_load: None,
+            health_checks: VecDeque::new(),
+            draining,
+            go_away: false,
+            close_requests,
+            close_requests_tx,
+            idle,
+            first_message,
+            lifetime,
+            retiring: false,
+            closed: false,
+            reply_order: ReplyOrder::Completion,
+            pending_handshake: None,
+            reply_queue: VecDeque::new(),
+            held_replies: FnvHashMap::default(),
+            pending_request_credit,
+            pending_error: None,
+            session: None,
+            _tracked: tracked,
             ghost: PhantomData,
         }
     }
@@ -202,7 +1004,392 @@ where
         self.transport.get_ref()
     }
 
+    /// Returns a handle that pushes notifications to the client over this channel.
+    pub fn notifier(&self) -> Notifier<Resp> {
+        Notifier {
+            notifications: self.notifications_tx.clone(),
+            close_requests: self.close_requests_tx.clone(),
+        }
+    }
+
+    /// Returns the session of the channel's client, if it asked for one and the server keeps them.
+    /// It's known once the client's handshake has been read.
+    pub fn session(&self) -> Option<&SessionToken> {
+        self.session.as_ref().map(sessions::Membership::token)
+    }
+
+    /// Queues the reply to a newly arrived request, and tells its handler which session it
+    /// belongs to, how to close the channel, and when the request arrived.
+    fn start_session_request(mut self: Pin<&mut Self>, mut request: Request<Req>) -> Request<Req> {
+        self.as_mut().enqueue_reply(request.id);
+        let session = self.session().cloned();
+        request.context = request
+            .context
+            .in_session(session)
+            .closed_by(self.close_requests_tx.clone())
+            .received_at(Instant::now());
+        request
+    }
+
+    /// Queues the reply to request `request_id` behind the replies before it, if replies are sent
+    /// in request order.
+    fn enqueue_reply(self: Pin<&mut Self>, request_id: u64) {
+        let this = self.project();
+        if *this.reply_order == ReplyOrder::Request {
+            this.reply_queue.push_back(request_id);
+            this.held_replies.insert(request_id, VecDeque::new());
+        }
+    }
+
+    /// Stops forwarding items and credit to the handler of request `request_id`.
+    fn end_request_items(mut self: Pin<&mut Self>, request_id: u64) {
+        let this = self.as_mut().project();
+        if this.request_items.remove(&request_id).is_some() {
+            this.request_items.compact(0.1);
+        }
+        if this.pending_request_items.remove(&request_id).is_some() {
+            this.pending_request_items.compact(0.1);
+        }
+        if this.reply_windows.remove(&request_id).is_some() {
+            this.reply_windows.compact(0.1);
+        }
+    }
+
+    /// Writes pushed notifications to the wire. Resolves once no more notifications are ready.
+    fn poll_write_notifications(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if self.pending_notification.is_none() {
+                match self.as_mut().project().notifications.poll_next(cx) {
+                    Poll::Ready(Some(notification)) => {
+                        *self.as_mut().project().pending_notification = Some(notification)
+                    }
+                    Poll::Ready(None) | Poll::Pending => return Poll::Ready(Ok(())),
+                }
+            }
+            while self
+                .as_mut()
+                .project()
+                .transport
+                .poll_ready(cx)?
+                .is_pending()
+            {
+                ready!(self.as_mut().project().transport.poll_flush(cx)?);
+            }
+            let notification = self.as_mut().project().pending_notification.take().unwrap();
+            self.as_mut()
+                .project()
+                .transport
+                .start_send(ServerMessage::Notification(notification))?;
+        }
+    }
+
+    /// Writes the server's load to the wire, if it's changed since it was last reported.
+    fn poll_write_load(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let load = match self.config.load {
+            Some(ref load) => load.get(),
+            None => return Poll::Ready(Ok(())),
+        };
+        if self.reported_load == Some(load) {
+            return Poll::Ready(Ok(()));
+        }
+        while self
+            .as_mut()
+            .project()
+            .transport
+            .poll_ready(cx)?
+            .is_pending()
+        {
+            ready!(self.as_mut().project().transport.poll_flush(cx)?);
+        }
+        let this = self.as_mut().project();
+        this.transport.start_send(ServerMessage::Load(load))?;
+        *this.reported_load = Some(load);
+        Poll::Ready(Ok(()))
+    }
+
+    /// Tells the client to go away once the server starts draining or the channel retires.
+    fn poll_write_go_away(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.as_mut().project();
+        if let Some(draining) = this.draining {
+            match draining.poll_next_unpin(cx) {
+                Poll::Ready(Some(())) => {
+                    debug!("Server is draining; telling the client to go away.");
+                    *this.go_away = true;
+                    *this.draining = None;
+                }
+                // The drain is gone, so it'll never start.
+                Poll::Ready(None) => *this.draining = None,
+                Poll::Pending => {}
+            }
+        }
+        if !self.go_away {
+            return Poll::Ready(Ok(()));
+        }
+        while self
+            .as_mut()
+            .project()
+            .transport
+            .poll_ready(cx)?
+            .is_pending()
+        {
+            ready!(self.as_mut().project().transport.poll_flush(cx)?);
+        }
+        let this = self.as_mut().project();
+        this.transport.start_send(ServerMessage::GoAway)?;
+        *this.go_away = false;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Resolves once the channel should stop reading requests, because it's gone `idle_timeout`
+    /// without requests in flight, its lifetime's grace period has ended, or a handler asked for
+    /// it to close. Starts retiring the channel once it reaches its lifetime.
+    fn poll_expired(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = self.as_mut().project();
+        if let Poll::Ready(Some(())) = this.close_requests.poll_next(cx) {
+            debug!("Asked to close the channel; telling the client to go away.");
+            *this.go_away = true;
+            *this.draining = None;
+            return Poll::Ready(());
+        }
+        if let Some(first_message) = this.first_message {
+            if first_message.poll_unpin(cx).is_ready() {
+                debug!("Closing channel whose client sent nothing before the handshake timeout.");
+                return Poll::Ready(());
+            }
+        }
+        if let Some(idle) = this.idle {
+            if idle.poll_unpin(cx).is_ready() {
+                if this.in_flight_requests.is_empty() {
+                    debug!("Closing idle channel.");
+                    return Poll::Ready(());
+                }
+                idle.reset(Instant::now() + this.config.idle_timeout.unwrap());
+            }
+        }
+        if let Some(lifetime) = this.lifetime {
+            if lifetime.poll_unpin(cx).is_ready() {
+                if *this.retiring {
+                    debug!("Closing retired channel.");
+                    return Poll::Ready(());
+                }
+                debug!("Channel reached its lifetime; telling the client to go away.");
+                *this.retiring = true;
+                // The client needs to be told only once.
+                *this.go_away = true;
+                *this.draining = None;
+                lifetime.reset(Instant::now() + this.config.lifetime_grace);
+                // Poll the new deadline so the grace period's end wakes the channel.
+                if lifetime.poll_unpin(cx).is_ready() {
+                    return Poll::Ready(());
+                }
+            }
+        }
+        Poll::Pending
+    }
+
+    /// Writes the answers to health checks to the wire. Resolves once every check is answered.
+    fn poll_write_health(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        while !self.health_checks.is_empty() {
+            while self
+                .as_mut()
+                .project()
+                .transport
+                .poll_ready(cx)?
+                .is_pending()
+            {
+                ready!(self.as_mut().project().transport.poll_flush(cx)?);
+            }
+            let serving = match self.config.health {
+                Some(ref health) => health.is_serving(),
+                None => true,
+            };
+            let this = self.as_mut().project();
+            let check_id = this.health_checks.pop_front().unwrap();
+            this.transport.start_send(ServerMessage::Health {
+                check_id,
+                serving,
+                time: Some(this.config.clock.now()),
+            })?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Writes the answer to the client's handshake to the wire, if it hasn't yet been written.
+    fn poll_write_handshake(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        if self.pending_handshake.is_none() {
+            return Poll::Ready(Ok(()));
+        }
+        while self
+            .as_mut()
+            .project()
+            .transport
+            .poll_ready(cx)?
+            .is_pending()
+        {
+            ready!(self.as_mut().project().transport.poll_flush(cx)?);
+        }
+        let this = self.as_mut().project();
+        let reply_order = this.pending_handshake.take().unwrap();
+        let session = this
+            .session
+            .as_ref()
+            .map(|membership| membership.token().clone());
+        this.transport.start_send(ServerMessage::Handshake {
+            reply_order,
+            session,
+        })?;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Writes the credit for requests that's waiting to be granted to the wire.
+    fn poll_write_request_credit(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+    ) -> Poll<io::Result<()>> {
+        if self.pending_request_credit == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        while self
+            .as_mut()
+            .project()
+            .transport
+            .poll_ready(cx)?
+            .is_pending()
+        {
+            ready!(self.as_mut().project().transport.poll_flush(cx)?);
+        }
+        let this = self.as_mut().project();
+        let credits = std::mem::take(this.pending_request_credit);
+        this.transport
+            .start_send(ServerMessage::RequestCredit { credits })?;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Tells the client why the channel stopped reading requests, if it failed to read one.
+    fn poll_write_error(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        if self.pending_error.is_none() {
+            return Poll::Ready(Ok(()));
+        }
+        while self
+            .as_mut()
+            .project()
+            .transport
+            .poll_ready(cx)?
+            .is_pending()
+        {
+            ready!(self.as_mut().project().transport.poll_flush(cx)?);
+        }
+        let this = self.as_mut().project();
+        let frame = this.pending_error.take().unwrap();
+        this.transport.start_send(ServerMessage::Error(frame))?;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Grants the client credit for another request, if its requests are limited, because one of
+    /// its requests completed.
+    fn return_request_credit(self: Pin<&mut Self>) {
+        let this = self.project();
+        if this.config.request_window.is_some() {
+            *this.pending_request_credit += 1;
+        }
+    }
+
+    /// Writes the held messages of the replies at the front of the reply queue to the wire.
+    /// Resolves once the reply at the front has no more held messages.
+    fn poll_write_held_replies(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        loop {
+            let request_id = match self.reply_queue.front() {
+                Some(&request_id) if !self.held_replies[&request_id].is_empty() => request_id,
+                _ => return Poll::Ready(Ok(())),
+            };
+            while self
+                .as_mut()
+                .project()
+                .transport
+                .poll_ready(cx)?
+                .is_pending()
+            {
+                ready!(self.as_mut().project().transport.poll_flush(cx)?);
+            }
+            let this = self.as_mut().project();
+            let message = this
+                .held_replies
+                .get_mut(&request_id)
+                .unwrap()
+                .pop_front()
+                .unwrap();
+            let is_final = message.is_final();
+            this.transport.start_send(message)?;
+            if is_final {
+                self.as_mut().dequeue_reply(request_id);
+            }
+        }
+    }
+
+    /// Stops holding replies for the reply to request `request_id`, which was written or won't be.
+    fn dequeue_reply(self: Pin<&mut Self>, request_id: u64) {
+        let this = self.project();
+        if this.held_replies.remove(&request_id).is_some() {
+            this.held_replies.compact(0.1);
+            if this.reply_queue.front() == Some(&request_id) {
+                this.reply_queue.pop_front();
+            } else if let Some(i) = this.reply_queue.iter().position(|&id| id == request_id) {
+                this.reply_queue.remove(i);
+            }
+        }
+    }
+
+    /// Writes grants of credit for drained request items to the wire. Resolves once no more grants
+    /// are ready.
+    fn poll_write_window_updates(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if self.pending_window_update.is_none() {
+                match self.as_mut().project().window_updates.poll_next(cx) {
+                    Poll::Ready(Some(update)) => {
+                        *self.as_mut().project().pending_window_update = Some(update)
+                    }
+                    Poll::Ready(None) | Poll::Pending => return Poll::Ready(Ok(())),
+                }
+            }
+            while self
+                .as_mut()
+                .project()
+                .transport
+                .poll_ready(cx)?
+                .is_pending()
+            {
+                ready!(self.as_mut().project().transport.poll_flush(cx)?);
+            }
+            let (request_id, credits) = self
+                .as_mut()
+                .project()
+                .pending_window_update
+                .take()
+                .unwrap();
+            // Only requests still receiving items need more credit.
+            if let Some((_, credit)) = self.as_mut().project().request_items.get_mut(&request_id) {
+                *credit = credit.saturating_add(credits);
+                self.as_mut()
+                    .project()
+                    .transport
+                    .start_send(ServerMessage::WindowUpdate {
+                        request_id,
+                        credits,
+                    })?;
+            }
+        }
+    }
+
     fn cancel_request(mut self: Pin<&mut Self>, trace_context: &trace::Context, request_id: u64) {
+        self.as_mut().end_request_items(request_id);
+        // The canceled request's reply no longer holds up those after it.
+        self.as_mut().dequeue_reply(request_id);
         // It's possible the request was already completed, so it's fine
         // if this is None.
         if let Some(cancel_handle) = self
@@ -212,6 +1399,8 @@ where
             .remove(&request_id)
         {
             self.as_mut().project().in_flight_requests.compact(0.1);
+            // The aborted handler won't reply.
+            self.as_mut().return_request_credit();
 
             cancel_handle.abort();
             let remaining = self.as_mut().project().in_flight_requests.len();
@@ -239,7 +1428,7 @@ where
 /// requests.
 pub trait Channel
 where
-    Self: Transport<Response<<Self as Channel>::Resp>, Request<<Self as Channel>::Req>>,
+    Self: Transport<ServerMessage<<Self as Channel>::Resp>, Request<<Self as Channel>::Req>>,
 {
     /// Type of request item.
     type Req;
@@ -261,17 +1450,48 @@ where
         Throttler::new(self, n)
     }
 
+    /// Caps the number of concurrent requests, queueing up to `capacity` more and serving them
+    /// earliest deadline first.
+    fn queue_by_deadline(
+        self,
+        max_in_flight_requests: usize,
+        capacity: usize,
+    ) -> DeadlineQueue<Self>
+    where
+        Self: Sized,
+    {
+        DeadlineQueue::new(self, max_in_flight_requests, capacity)
+    }
+
     /// Tells the Channel that request with ID `request_id` is being handled.
     /// The request will be tracked until a response with the same ID is sent
     /// to the Channel.
     fn start_request(self: Pin<&mut Self>, request_id: u64) -> AbortRegistration;
 
+    /// Returns the items the client streams with request `request_id`. Returns an empty stream if
+    /// the request is not a streaming request.
+    fn take_request_items(self: Pin<&mut Self>, request_id: u64) -> RequestItems<Self::Req>;
+
+    /// Tells the Channel that the reply to request `request_id` is streamed. Returns the credit
+    /// the client grants for the reply's items.
+    fn start_reply_window(self: Pin<&mut Self>, request_id: u64) -> ReplyWindow;
+
     /// Respond to requests coming over the channel with `f`. Returns a future that drives the
     /// responses and resolves when the connection is closed.
-    fn respond_with<S>(self, server: S) -> ClientHandler<Self, S>
+    fn respond_with<S>(self, server: S) -> ClientHandler<Self, Unary<S>>
     where
         S: Serve<Self::Req, Resp = Self::Resp>,
         Self: Sized,
+    {
+        self.respond_with_stream(Unary(server))
+    }
+
+    /// Respond to requests coming over the channel with `server`, which may stream its replies.
+    /// Returns a future that drives the responses and resolves when the connection is closed.
+    fn respond_with_stream<S>(self, server: S) -> ClientHandler<Self, S>
+    where
+        S: ServeStream<Self::Req, Resp = Self::Resp>,
+        Self: Sized,
     {
         let (responses_tx, responses) = mpsc::channel(self.config().pending_response_buffer);
         let responses = responses.fuse();
@@ -287,23 +1507,136 @@ where
 
 impl<Req, Resp, T> Stream for BaseChannel<Req, Resp, T>
 where
-    T: Transport<Response<Resp>, ClientMessage<Req>>,
+    T: Transport<ServerMessage<Resp>, ClientMessage<Req>>,
 {
     type Item = io::Result<Request<Req>>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        if self.closed {
+            return Poll::Ready(None);
+        }
         loop {
-            match ready!(self.as_mut().project().transport.poll_next(cx)?) {
+            if self.as_mut().poll_expired(cx).is_ready() {
+                *self.as_mut().project().closed = true;
+                return Poll::Ready(None);
+            }
+            let message = match ready!(self.as_mut().project().transport.poll_next(cx)) {
+                Some(Err(e)) => {
+                    // Tell the client what went wrong, rather than just hang up. The requests in
+                    // flight are still answered.
+                    let frame = ErrorFrame::read_error(&e);
+                    debug!(
+                        "Failed to read a message, so closing the channel: {}",
+                        frame
+                    );
+                    let this = self.as_mut().project();
+                    *this.pending_error = Some(frame);
+                    *this.closed = true;
+                    return Poll::Ready(None);
+                }
+                Some(Ok(message)) => Some(message),
+                None => None,
+            };
+            let this = self.as_mut().project();
+            *this.first_message = None;
+            if let (Some(idle), Some(idle_timeout)) = (this.idle, this.config.idle_timeout) {
+                idle.reset(Instant::now() + idle_timeout);
+            }
+            match message {
                 Some(message) => match message {
                     ClientMessage::Request(request) => {
+                        let request = self.as_mut().start_session_request(request);
+                        return Poll::Ready(Some(Ok(request)));
+                    }
+                    ClientMessage::StreamingRequest(request) => {
+                        let request = self.as_mut().start_session_request(request);
+                        let this = self.as_mut().project();
+                        let window = this.config.stream_window;
+                        let grants =
+                            WindowGrants::new(request.id, window, this.window_updates_tx.clone());
+                        let (tx, items) = RequestItems::new(grants);
+                        this.request_items.insert(request.id, (tx, 0));
+                        this.pending_request_items.insert(request.id, items);
+                        // Grant the client its initial window.
+                        let _ = this.window_updates_tx.unbounded_send((request.id, window));
                         return Poll::Ready(Some(Ok(request)));
                     }
+                    ClientMessage::WindowUpdate {
+                        request_id,
+                        credits,
+                    } => {
+                        if let Some(window) = self.as_mut().project().reply_windows.get(&request_id)
+                        {
+                            let _ = window.unbounded_send(credits);
+                        }
+                    }
+                    ClientMessage::StreamItem { request_id, item } => {
+                        match self.as_mut().project().request_items.get_mut(&request_id) {
+                            Some((_, 0)) => {
+                                let frame = ErrorFrame::new(
+                                    ErrorCode::LimitExceeded,
+                                    "Sent a request item without credit.",
+                                )
+                                .with_detail("request_id", request_id);
+                                debug!("Client overran its window, so closing the channel.");
+                                let this = self.as_mut().project();
+                                *this.pending_error = Some(frame);
+                                *this.closed = true;
+                                return Poll::Ready(None);
+                            }
+                            // The handler may have stopped reading items, which is fine.
+                            Some((items, credit)) => {
+                                *credit -= 1;
+                                let _ = items.unbounded_send(item);
+                            }
+                            None => trace!(
+                                "Received an item for request {}, which is not streaming items.",
+                                request_id
+                            ),
+                        }
+                    }
+                    ClientMessage::StreamEnd { request_id } => {
+                        if self
+                            .as_mut()
+                            .project()
+                            .request_items
+                            .remove(&request_id)
+                            .is_some()
+                        {
+                            self.as_mut().project().request_items.compact(0.1);
+                        }
+                    }
                     ClientMessage::Cancel {
                         trace_context,
                         request_id,
                     } => {
                         self.as_mut().cancel_request(&trace_context, request_id);
                     }
+                    ClientMessage::HealthCheck { check_id } => {
+                        trace!("Received health check {}.", check_id);
+                        self.as_mut().project().health_checks.push_back(check_id);
+                    }
+                    ClientMessage::Handshake {
+                        reply_order,
+                        session,
+                    } => {
+                        let this = self.as_mut().project();
+                        if let (Some(sessions), Some(session)) = (&this.config.sessions, session) {
+                            *this.session = Some(sessions.join(&session));
+                        }
+                        let agreed = match reply_order {
+                            ReplyOrder::Request if this.config.ordered_replies => {
+                                ReplyOrder::Request
+                            }
+                            _ => ReplyOrder::Completion,
+                        };
+                        debug!(
+                            "Client asked for replies in {:?} order; sending them in {:?} order.",
+                            reply_order, agreed
+                        );
+                        *this.reply_order = agreed;
+                        *this.pending_handshake = Some(agreed);
+                    }
                     ClientMessage::_NonExhaustive => unreachable!(),
                 },
                 None => return Poll::Ready(None),
@@ -312,31 +1645,77 @@ where
     }
 }
 
-impl<Req, Resp, T> Sink<Response<Resp>> for BaseChannel<Req, Resp, T>
+impl<Req, Resp, T> Sink<ServerMessage<Resp>> for BaseChannel<Req, Resp, T>
 where
-    T: Transport<Response<Resp>, ClientMessage<Req>>,
+    T: Transport<ServerMessage<Resp>, ClientMessage<Req>>,
 {
     type Error = io::Error;
 
-    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        // The client learns the reply order and its credit before any reply, and held replies go
+        // before new ones.
+        ready!(self.as_mut().poll_write_handshake(cx)?);
+        ready!(self.as_mut().poll_write_request_credit(cx)?);
+        ready!(self.as_mut().poll_write_held_replies(cx)?);
         self.project().transport.poll_ready(cx)
     }
 
-    fn start_send(mut self: Pin<&mut Self>, response: Response<Resp>) -> Result<(), Self::Error> {
-        if self
-            .as_mut()
-            .project()
-            .in_flight_requests
-            .remove(&response.request_id)
-            .is_some()
-        {
-            self.as_mut().project().in_flight_requests.compact(0.1);
+    fn start_send(
+        mut self: Pin<&mut Self>,
+        message: ServerMessage<Resp>,
+    ) -> Result<(), Self::Error> {
+        // A streamed reply remains in flight until its final message is sent.
+        if let (true, Some(request_id)) = (message.is_final(), message.request_id()) {
+            if self
+                .as_mut()
+                .project()
+                .in_flight_requests
+                .remove(&request_id)
+                .is_some()
+            {
+                let this = self.as_mut().project();
+                this.in_flight_requests.compact(0.1);
+                // The channel's idle from the moment its last request completes.
+                if let (true, Some(idle), Some(idle_timeout)) = (
+                    this.in_flight_requests.is_empty(),
+                    this.idle,
+                    this.config.idle_timeout,
+                ) {
+                    idle.reset(Instant::now() + idle_timeout);
+                }
+            }
+            // Items that arrive after the reply are of no use to the handler.
+            self.as_mut().end_request_items(request_id);
+            self.as_mut().return_request_credit();
         }
 
-        self.project().transport.start_send(response)
+        if let Some(request_id) = message.request_id() {
+            let this = self.as_mut().project();
+            if let Some(held) = this.held_replies.get_mut(&request_id) {
+                // Hold the reply until the replies before it, and its own held messages, are
+                // written.
+                if !held.is_empty() || this.reply_queue.front() != Some(&request_id) {
+                    held.push_back(message);
+                    return Ok(());
+                }
+                if message.is_final() {
+                    self.as_mut().dequeue_reply(request_id);
+                }
+            }
+        }
+        self.project().transport.start_send(message)
     }
 
-    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        ready!(self.as_mut().poll_write_handshake(cx)?);
+        ready!(self.as_mut().poll_write_request_credit(cx)?);
+        ready!(self.as_mut().poll_write_held_replies(cx)?);
+        ready!(self.as_mut().poll_write_window_updates(cx)?);
+        ready!(self.as_mut().poll_write_notifications(cx)?);
+        ready!(self.as_mut().poll_write_load(cx)?);
+        ready!(self.as_mut().poll_write_health(cx)?);
+        ready!(self.as_mut().poll_write_go_away(cx)?);
+        ready!(self.as_mut().poll_write_error(cx)?);
         self.project().transport.poll_flush(cx)
     }
 
@@ -353,7 +1732,7 @@ impl<Req, Resp, T> AsRef<T> for BaseChannel<Req, Resp, T> {
 
 impl<Req, Resp, T> Channel for BaseChannel<Req, Resp, T>
 where
-    T: Transport<Response<Resp>, ClientMessage<Req>>,
+    T: Transport<ServerMessage<Resp>, ClientMessage<Req>>,
 {
     type Req = Req;
     type Resp = Resp;
@@ -375,9 +1754,25 @@ where
             .is_none());
         abort_registration
     }
+
+    fn take_request_items(self: Pin<&mut Self>, request_id: u64) -> RequestItems<Req> {
+        self.project()
+            .pending_request_items
+            .remove(&request_id)
+            .unwrap_or_else(RequestItems::empty)
+    }
+
+    fn start_reply_window(self: Pin<&mut Self>, request_id: u64) -> ReplyWindow {
+        let (tx, window) = ReplyWindow::new();
+        self.project().reply_windows.insert(request_id, tx);
+        window
+    }
 }
 
 /// A running handler serving all requests coming over a channel.
+///
+/// When the client half-closes the connection, i.e. stops sending without hanging up, the handler
+/// still answers the requests in flight. It closes the channel once their replies are written.
 #[pin_project]
 #[derive(Debug)]
 pub struct ClientHandler<C, S>
@@ -388,10 +1783,10 @@ where
     channel: C,
     /// Responses waiting to be written to the wire.
     #[pin]
-    pending_responses: Fuse<mpsc::Receiver<(context::Context, Response<C::Resp>)>>,
+    pending_responses: Fuse<mpsc::Receiver<(context::Context, ServerMessage<C::Resp>)>>,
     /// Handed out to request handlers to fan in responses.
     #[pin]
-    responses_tx: mpsc::Sender<(context::Context, Response<C::Resp>)>,
+    responses_tx: mpsc::Sender<(context::Context, ServerMessage<C::Resp>)>,
     /// Server
     server: S,
 }
@@ -399,12 +1794,34 @@ where
 impl<C, S> ClientHandler<C, S>
 where
     C: Channel,
-    S: Serve<C::Req, Resp = C::Resp>,
+    S: ServeStream<C::Req, Resp = C::Resp>,
 {
+    fn pump(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> PollIo<RequestHandler<S::Fut, S::Stream, C::Resp>> {
+        loop {
+            let read = self.as_mut().pump_read(cx)?;
+            let read_closed = matches!(read, Poll::Ready(None));
+            match (read, self.as_mut().pump_write(cx, read_closed)?) {
+                (Poll::Ready(None), Poll::Ready(None)) => {
+                    return Poll::Ready(None);
+                }
+                (Poll::Ready(Some(request_handler)), _) => {
+                    return Poll::Ready(Some(Ok(request_handler)));
+                }
+                (_, Poll::Ready(Some(()))) => {}
+                _ => {
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+
     fn pump_read(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
-    ) -> PollIo<RequestHandler<S::Fut, C::Resp>> {
+    ) -> PollIo<RequestHandler<S::Fut, S::Stream, C::Resp>> {
         match ready!(self.as_mut().project().channel.poll_next(cx)?) {
             Some(request) => Poll::Ready(Some(Ok(self.handle_request(request)))),
             None => Poll::Ready(None),
@@ -417,13 +1834,13 @@ where
         read_half_closed: bool,
     ) -> PollIo<()> {
         match self.as_mut().poll_next_response(cx)? {
-            Poll::Ready(Some((ctx, response))) => {
+            Poll::Ready(Some((ctx, message))) => {
                 trace!(
                     "[{}] Staging response. In-flight requests = {}.",
                     ctx.trace_id(),
                     self.as_mut().project().channel.in_flight_requests(),
                 );
-                self.as_mut().project().channel.start_send(response)?;
+                self.as_mut().project().channel.start_send(message)?;
                 Poll::Ready(Some(Ok(())))
             }
             Poll::Ready(None) => {
@@ -439,6 +1856,8 @@ where
                 // fully flushed. So, if the read half is closed and there are no in-flight
                 // requests, then we can close the write half.
                 if read_half_closed && self.as_mut().project().channel.in_flight_requests() == 0 {
+                    trace!("Client stopped sending and all replies are written; closing.");
+                    ready!(self.as_mut().project().channel.poll_close(cx)?);
                     Poll::Ready(None)
                 } else {
                     Poll::Pending
@@ -450,14 +1869,14 @@ where
     fn poll_next_response(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
-    ) -> PollIo<(context::Context, Response<C::Resp>)> {
+    ) -> PollIo<(context::Context, ServerMessage<C::Resp>)> {
         // Ensure there's room to write a response.
-        while let Poll::Pending = self.as_mut().project().channel.poll_ready(cx)? {
+        while self.as_mut().project().channel.poll_ready(cx)?.is_pending() {
             ready!(self.as_mut().project().channel.poll_flush(cx)?);
         }
 
         match ready!(self.as_mut().project().pending_responses.poll_next(cx)) {
-            Some((ctx, response)) => Poll::Ready(Some(Ok((ctx, response)))),
+            Some((ctx, message)) => Poll::Ready(Some(Ok((ctx, message)))),
             None => {
                 // This branch likely won't happen, since the ClientHandler is holding a Sender.
                 Poll::Ready(None)
@@ -468,32 +1887,75 @@ where
     fn handle_request(
         mut self: Pin<&mut Self>,
         request: Request<C::Req>,
-    ) -> RequestHandler<S::Fut, C::Resp> {
+    ) -> RequestHandler<S::Fut, S::Stream, C::Resp> {
         let request_id = request.id;
         let deadline = request.context.deadline;
-        let timeout = deadline.time_until();
+        let clock = self.channel.config().clock.clone();
+        #[cfg(feature = "alloc-audit")]
+        let alloc_audit = self.channel.config().alloc_audit.clone();
+        #[cfg(feature = "alloc-audit")]
+        if let Some(ref audit) = alloc_audit {
+            audit.request();
+        }
+        let timeout = clock.until(deadline);
         trace!(
             "[{}] Received request with deadline {} (timeout {:?}).",
             request.context.trace_id(),
             format_rfc3339(deadline),
             timeout,
         );
-        let ctx = request.context;
+        // Calls made with the request's context, or its children, are canceled along with it.
+        let (ctx, cancel) = request.context.started().cancelable();
+        if let Some(queue_time) = ctx.queue_time() {
+            trace!(
+                "[{}] Request was queued for {:?}.",
+                ctx.trace_id(),
+                queue_time
+            );
+        }
         let request = request.message;
 
-        let response = self.as_mut().project().server.clone().serve(ctx, request);
+        let items = self
+            .as_mut()
+            .project()
+            .channel
+            .take_request_items(request_id);
+        // Handlers, and the futures they return, run in the scope of the request's context.
+        let mut scope = Some(ctx.clone());
+        let server = self.as_mut().project().server.clone();
+        let reply = context::enter(&mut scope, || {
+            server.serve_stream(ctx.clone(), request, items)
+        });
+        let scope = scope.expect("the scope is restored");
+        let window = match reply {
+            Reply::Stream(_) => self
+                .as_mut()
+                .project()
+                .channel
+                .start_reply_window(request_id),
+            Reply::Unary(_) | Reply::Progress(..) => ReplyWindow::unlimited(),
+        };
         let response = Resp {
             state: RespState::PollResp,
             request_id,
             ctx,
             deadline,
-            f: tokio::time::timeout(timeout, response),
+            timeout: tokio::time::delay_for(timeout),
+            reply,
+            window,
             response: None,
+            progress_done: false,
+            message: None,
             response_tx: self.as_mut().project().responses_tx.clone(),
         };
         let abort_registration = self.as_mut().project().channel.start_request(request_id);
         RequestHandler {
-            resp: Abortable::new(response, abort_registration),
+            resp: context::scope(scope, Abortable::new(response, abort_registration)),
+            cancel: CancelOnDrop(Some(cancel)),
+            deadline,
+            clock,
+            #[cfg(feature = "alloc-audit")]
+            alloc_audit,
         }
     }
 }
@@ -501,35 +1963,82 @@ where
 /// A future fulfilling a single client request.
 #[pin_project]
 #[derive(Debug)]
-pub struct RequestHandler<F, R> {
+pub struct RequestHandler<F, St, R> {
     #[pin]
-    resp: Abortable<Resp<F, R>>,
+    resp: context::Scope<Abortable<Resp<F, St, R>>>,
+    cancel: CancelOnDrop,
+    deadline: SystemTime,
+    clock: Arc<dyn Clock>,
+    #[cfg(feature = "alloc-audit")]
+    alloc_audit: Option<crate::alloc_audit::AllocAudit>,
 }
 
-impl<F, R> Future for RequestHandler<F, R>
+/// Cancels a request's context when dropped, unless the request completed in time.
+#[derive(Debug)]
+struct CancelOnDrop(Option<futures::channel::oneshot::Sender<()>>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if let Some(cancel) = self.0.take() {
+            let _ = cancel.send(());
+        }
+    }
+}
+
+impl<F, St, R> Future for RequestHandler<F, St, R>
 where
     F: Future<Output = R>,
+    St: Stream<Item = R>,
 {
     type Output = ();
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
-        let _ = ready!(self.project().resp.poll(cx));
+        #[cfg(feature = "alloc-audit")]
+        {
+            if let Some(audit) = self.alloc_audit.clone() {
+                return crate::alloc_audit::audit(&audit, || self.poll_handler(cx));
+            }
+        }
+        self.poll_handler(cx)
+    }
+}
+
+impl<F, St, R> RequestHandler<F, St, R>
+where
+    F: Future<Output = R>,
+    St: Stream<Item = R>,
+{
+    fn poll_handler(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.project();
+        let completed = ready!(this.resp.poll(cx)).is_ok();
+        if completed && this.clock.now() < *this.deadline {
+            // Dropping the sender without sending tells the request's calls it completed.
+            this.cancel.0.take();
+        }
         Poll::Ready(())
     }
 }
 
 #[pin_project]
 #[derive(Debug)]
-struct Resp<F, R> {
+struct Resp<F, St, R> {
     state: RespState,
     request_id: u64,
     ctx: context::Context,
     deadline: SystemTime,
     #[pin]
-    f: Timeout<F>,
-    response: Option<Response<R>>,
+    timeout: Delay,
     #[pin]
-    response_tx: mpsc::Sender<(context::Context, Response<R>)>,
+    reply: Reply<F, St>,
+    window: ReplyWindow,
+    /// The response to a request that reports progress, held until the progress reported before
+    /// it is sent.
+    response: Option<R>,
+    /// Whether the handler has stopped reporting progress.
+    progress_done: bool,
+    message: Option<ServerMessage<R>>,
+    #[pin]
+    response_tx: mpsc::Sender<(context::Context, ServerMessage<R>)>,
 }
 
 #[derive(Debug)]
@@ -540,9 +2049,115 @@ enum RespState {
     PollFlush,
 }
 
-impl<F, R> Future for Resp<F, R>
+impl<F, St, R> Resp<F, St, R>
+where
+    F: Future<Output = R>,
+    St: Stream<Item = R>,
+{
+    /// Polls the reply for the next message to send to the client.
+    fn poll_message(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<ServerMessage<R>> {
+        let request_id = self.request_id;
+        let this = self.as_mut().project();
+        let window = this.window;
+        let response = this.response;
+        let progress_done = this.progress_done;
+        let reply = this.reply;
+        // A handler that panics fails its request, rather than leaving the client waiting until
+        // the deadline. The reply isn't polled again, since the Resp completes with the error.
+        let message = panic::catch_unwind(AssertUnwindSafe(|| match reply.project() {
+            ReplyProj::Unary(f) => f.poll(cx).map(|message| {
+                ServerMessage::Response(Response {
+                    request_id,
+                    message: Ok(message),
+                })
+            }),
+            ReplyProj::Stream(s) => match window.poll_ready(cx) {
+                Poll::Ready(()) => s.poll_next(cx).map(|item| match item {
+                    Some(item) => {
+                        window.consume();
+                        ServerMessage::StreamItem { request_id, item }
+                    }
+                    None => ServerMessage::StreamEnd { request_id },
+                }),
+                Poll::Pending => Poll::Pending,
+            },
+            ReplyProj::Progress(f, progress) => {
+                if response.is_none() {
+                    if let Poll::Ready(message) = f.poll(cx) {
+                        *response = Some(message);
+                    }
+                }
+                let update = if *progress_done {
+                    Poll::Ready(None)
+                } else {
+                    progress.poll_next(cx)
+                };
+                match update {
+                    Poll::Ready(Some(progress)) => Poll::Ready(ServerMessage::Progress {
+                        request_id,
+                        progress,
+                    }),
+                    update => {
+                        if update.is_ready() {
+                            *progress_done = true;
+                        }
+                        match response.take() {
+                            Some(message) => Poll::Ready(ServerMessage::Response(Response {
+                                request_id,
+                                message: Ok(message),
+                            })),
+                            None => Poll::Pending,
+                        }
+                    }
+                }
+            }
+        }));
+        let message = match message {
+            Ok(message) => message,
+            Err(panic) => {
+                error!(
+                    "[{}] Request handler panicked: {}",
+                    self.ctx.trace_id(),
+                    panic_message(&*panic)
+                );
+                return Poll::Ready(ServerMessage::Response(Response {
+                    request_id,
+                    message: Err(ServerError {
+                        kind: io::ErrorKind::Other,
+                        detail: Some("The request handler panicked.".into()),
+                        retry_after: None,
+                    }),
+                }));
+            }
+        };
+        if message.is_ready() {
+            return message;
+        }
+        ready!(self.as_mut().project().timeout.poll(cx));
+        debug!(
+            "[{}] Response did not complete before deadline of {}s.",
+            self.ctx.trace_id(),
+            format_rfc3339(self.deadline)
+        );
+        // No point in responding, since the client will have dropped the request.
+        Poll::Ready(ServerMessage::Response(Response {
+            request_id,
+            message: Err(ServerError {
+                kind: io::ErrorKind::TimedOut,
+                detail: Some(format!(
+                    "Response did not complete before deadline of {}s.",
+                    format_rfc3339(self.deadline)
+                )),
+                retry_after: None,
+            }),
+        }))
+    }
+}
+
+impl<F, St, R> Future for Resp<F, St, R>
 where
     F: Future<Output = R>,
+    St: Stream<Item = R>,
 {
     type Output = ();
 
@@ -550,29 +2165,8 @@ where
         loop {
             match self.as_mut().project().state {
                 RespState::PollResp => {
-                    let result = ready!(self.as_mut().project().f.poll(cx));
-                    *self.as_mut().project().response = Some(Response {
-                        request_id: self.request_id,
-                        message: match result {
-                            Ok(message) => Ok(message),
-                            Err(tokio::time::Elapsed { .. }) => {
-                                debug!(
-                                    "[{}] Response did not complete before deadline of {}s.",
-                                    self.ctx.trace_id(),
-                                    format_rfc3339(self.deadline)
-                                );
-                                // No point in responding, since the client will have dropped the
-                                // request.
-                                Err(ServerError {
-                                    kind: io::ErrorKind::TimedOut,
-                                    detail: Some(format!(
-                                        "Response did not complete before deadline of {}s.",
-                                        format_rfc3339(self.deadline)
-                                    )),
-                                })
-                            }
-                        },
-                    });
+                    let message = ready!(self.as_mut().poll_message(cx));
+                    *self.as_mut().project().message = Some(message);
                     *self.as_mut().project().state = RespState::PollReady;
                 }
                 RespState::PollReady => {
@@ -580,17 +2174,25 @@ where
                     if ready.is_err() {
                         return Poll::Ready(());
                     }
-                    let resp = (self.ctx, self.as_mut().project().response.take().unwrap());
+                    let message = (
+                        self.ctx.clone(),
+                        self.as_mut().project().message.take().unwrap(),
+                    );
+                    let last = message.1.is_final();
                     if self
                         .as_mut()
                         .project()
                         .response_tx
-                        .start_send(resp)
+                        .start_send(message)
                         .is_err()
                     {
                         return Poll::Ready(());
                     }
-                    *self.as_mut().project().state = RespState::PollFlush;
+                    if last {
+                        *self.as_mut().project().state = RespState::PollFlush;
+                    } else {
+                        *self.as_mut().project().state = RespState::PollResp;
+                    }
                 }
                 RespState::PollFlush => {
                     let ready = ready!(self.as_mut().project().response_tx.poll_flush(cx));
@@ -607,31 +2209,18 @@ where
 impl<C, S> Stream for ClientHandler<C, S>
 where
     C: Channel,
-    S: Serve<C::Req, Resp = C::Resp>,
+    S: ServeStream<C::Req, Resp = C::Resp>,
 {
-    type Item = io::Result<RequestHandler<S::Fut, C::Resp>>;
+    type Item = io::Result<RequestHandler<S::Fut, S::Stream, C::Resp>>;
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        loop {
-            let read = self.as_mut().pump_read(cx)?;
-            let read_closed = if let Poll::Ready(None) = read {
-                true
-            } else {
-                false
-            };
-            match (read, self.as_mut().pump_write(cx, read_closed)?) {
-                (Poll::Ready(None), Poll::Ready(None)) => {
-                    return Poll::Ready(None);
-                }
-                (Poll::Ready(Some(request_handler)), _) => {
-                    return Poll::Ready(Some(Ok(request_handler)));
-                }
-                (_, Poll::Ready(Some(()))) => {}
-                _ => {
-                    return Poll::Pending;
-                }
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        #[cfg(feature = "alloc-audit")]
+        {
+            if let Some(audit) = self.channel.config().alloc_audit.clone() {
+                return crate::alloc_audit::audit(&audit, || self.pump(cx));
             }
         }
+        self.pump(cx)
     }
 }
 
@@ -642,8 +2231,9 @@ where
     C: Channel + 'static,
     C::Req: Send + 'static,
     C::Resp: Send + 'static,
-    S: Serve<C::Req, Resp = C::Resp> + Send + 'static,
+    S: ServeStream<C::Req, Resp = C::Resp> + Send + 'static,
     S::Fut: Send + 'static,
+    S::Stream: Send + 'static,
 {
     /// Runs the client handler until completion by spawning each
     /// request handler onto the default executor.
@@ -657,6 +2247,21 @@ where
         })
         .unwrap_or_else(|e| info!("ClientHandler errored out: {}", e))
     }
+
+    /// Runs the client handler until completion by spawning each request handler onto
+    /// `executor`.
+    pub fn execute_on<E>(self, executor: E) -> impl Future<Output = ()>
+    where
+        E: Executor,
+    {
+        use log::info;
+
+        self.try_for_each(move |request_handler| {
+            executor.spawn(request_handler.boxed());
+            future::ready(Ok(()))
+        })
+        .unwrap_or_else(|e| info!("ClientHandler errored out: {}", e))
+    }
 }
 
 /// A future that drives the server by spawning channels and request handlers on the default
@@ -677,8 +2282,9 @@ where
     C: Channel + Send + 'static,
     C::Req: Send + 'static,
     C::Resp: Send + 'static,
-    Se: Serve<C::Req, Resp = C::Resp> + Send + 'static + Clone,
+    Se: ServeStream<C::Req, Resp = C::Resp> + Send + 'static + Clone,
     Se::Fut: Send + 'static,
+    Se::Stream: Send + 'static,
 {
     type Output = ();
 
@@ -688,7 +2294,7 @@ where
         while let Some(channel) = ready!(self.as_mut().project().incoming.poll_next(cx)) {
             tokio::spawn(
                 channel
-                    .respond_with(self.as_mut().project().server.clone())
+                    .respond_with_stream(self.as_mut().project().server.clone())
                     .execute(),
             );
         }
@@ -696,3 +2302,133 @@ where
         Poll::Ready(())
     }
 }
+
+#[tokio::test]
+async fn channel_is_closed_after_the_client_half_closes() {
+    /// A transport that records when its write half is closed.
+    struct Transport {
+        requests: mpsc::UnboundedReceiver<ClientMessage<u32>>,
+        replies: mpsc::UnboundedSender<ServerMessage<u32>>,
+        closed: Arc<AtomicBool>,
+    }
+
+    impl Stream for Transport {
+        type Item = io::Result<ClientMessage<u32>>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> PollIo<ClientMessage<u32>> {
+            self.requests
+                .poll_next_unpin(cx)
+                .map(|message| message.map(Ok))
+        }
+    }
+
+    impl Sink<ServerMessage<u32>> for Transport {
+        type Error = io::Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, message: ServerMessage<u32>) -> io::Result<()> {
+            self.replies
+                .unbounded_send(message)
+                .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.closed.store(true, Ordering::SeqCst);
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    let (requests_tx, requests) = mpsc::unbounded();
+    let (replies, replies_rx) = mpsc::unbounded();
+    let closed = Arc::new(AtomicBool::new(false));
+    for id in 0..3 {
+        requests_tx
+            .unbounded_send(ClientMessage::Request(Request {
+                context: context::current(),
+                id,
+                method: None,
+                message: id as u32,
+            }))
+            .unwrap();
+    }
+    // Nothing more to send; the server still owes three replies.
+    drop(requests_tx);
+    let transport = Transport {
+        requests,
+        replies,
+        closed: closed.clone(),
+    };
+    BaseChannel::with_defaults(transport)
+        .respond_with(|_, x: u32| async move {
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+            x + 1
+        })
+        .try_for_each_concurrent(None, |request_handler| request_handler.map(Ok))
+        .await
+        .unwrap();
+
+    assert!(closed.load(Ordering::SeqCst));
+    let mut replies: Vec<_> = replies_rx
+        .filter_map(|message| async move {
+            match message {
+                ServerMessage::Response(response) => response.message.ok(),
+                _ => None,
+            }
+        })
+        .collect()
+        .await;
+    replies.sort_unstable();
+    assert_eq!(replies, [1, 2, 3]);
+}
+
+#[tokio::test]
+async fn clients_that_overrun_their_window_are_disconnected() {
+    let (mut client, server) = crate::transport::channel::unbounded();
+    let config = Config::default();
+    let window = config.stream_window;
+    tokio::spawn(
+        BaseChannel::new(config, server)
+            .respond_with(|_, _: u32| future::pending::<u32>())
+            .try_for_each_concurrent(None, |request_handler| request_handler.map(Ok)),
+    );
+
+    client
+        .send(ClientMessage::StreamingRequest(Request {
+            context: context::current(),
+            id: 0,
+            method: None,
+            message: 0,
+        }))
+        .await
+        .unwrap();
+    match client.next().await {
+        Some(Ok(ServerMessage::WindowUpdate {
+            request_id: 0,
+            credits,
+        })) => assert_eq!(credits, window),
+        message => panic!("Expected the initial window, got {:?}", message),
+    }
+    for _ in 0..=window {
+        client
+            .send(ClientMessage::StreamItem {
+                request_id: 0,
+                item: 1,
+            })
+            .await
+            .unwrap();
+    }
+    match client.next().await {
+        Some(Ok(ServerMessage::Error(frame))) => {
+            assert_eq!(frame.id, None);
+            assert_eq!(frame.code, ErrorCode::LimitExceeded);
+        }
+        message => panic!("Expected an error frame, got {:?}", message),
+    }
+}
@@ -0,0 +1,452 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use super::{Channel, Config, ReplyWindow, RequestItems};
+use crate::{Request, Response, ServerError, ServerMessage};
+use fnv::FnvHashMap;
+use futures::{future::AbortRegistration, prelude::*, ready, task::*};
+use log::debug;
+use pin_project::pin_project;
+use std::{
+    fmt,
+    hash::Hash,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// A limit on the rate of requests a single principal may send: a steady rate, plus an allowance
+/// for bursts above that rate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quota {
+    /// The number of requests replenished each second.
+    pub requests_per_second: f64,
+    /// The maximum number of requests that can be sent in a burst. A principal that has been idle
+    /// long enough can send this many requests at once before being limited to the steady rate.
+    pub burst: u32,
+}
+
+impl Quota {
+    /// Returns a quota allowing `requests_per_second` steady-state, with bursts of up to `burst`.
+    pub fn new(requests_per_second: f64, burst: u32) -> Self {
+        assert!(
+            requests_per_second > 0.,
+            "requests_per_second must be positive"
+        );
+        assert!(burst > 0, "burst must be positive");
+        Quota {
+            requests_per_second,
+            burst,
+        }
+    }
+}
+
+/// A token bucket. Each request takes one token, and tokens refill at the quota's steady rate, up
+/// to the burst size.
+#[derive(Clone, Debug)]
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn full(quota: &Quota, now: Instant) -> Self {
+        TokenBucket {
+            tokens: f64::from(quota.burst),
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, quota: &Quota, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * quota.requests_per_second)
+            .min(f64::from(quota.burst));
+        self.last_refill = now;
+    }
+
+    fn is_full(&self, quota: &Quota) -> bool {
+        self.tokens >= f64::from(quota.burst)
+    }
+
+    /// Takes a token if one is available; otherwise, returns how long until one will be.
+    fn try_acquire(&mut self, quota: &Quota, now: Instant) -> Result<(), Duration> {
+        self.refill(quota, now);
+        if self.tokens >= 1. {
+            self.tokens -= 1.;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1. - self.tokens) / quota.requests_per_second,
+            ))
+        }
+    }
+}
+
+/// How many quota checks to perform between sweeps of idle principals.
+const SWEEP_INTERVAL: u32 = 1024;
+
+#[derive(Debug)]
+struct QuotasInner<K> {
+    buckets: FnvHashMap<K, TokenBucket>,
+    checks_since_sweep: u32,
+}
+
+/// Per-principal request quota state, shared by every channel it is applied to, so that a
+/// principal cannot evade its quota by opening more connections.
+#[derive(Debug)]
+pub struct Quotas<K> {
+    quota: Quota,
+    inner: Arc<Mutex<QuotasInner<K>>>,
+}
+
+impl<K> Clone for Quotas<K> {
+    fn clone(&self) -> Self {
+        Quotas {
+            quota: self.quota,
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<K> Quotas<K>
+where
+    K: Eq + Hash,
+{
+    /// Returns new quota state that allows each principal `quota`.
+    pub fn new(quota: Quota) -> Self {
+        Quotas {
+            quota,
+            inner: Arc::new(Mutex::new(QuotasInner {
+                buckets: FnvHashMap::default(),
+                checks_since_sweep: 0,
+            })),
+        }
+    }
+
+    /// Returns the quota allowed to each principal.
+    pub fn quota(&self) -> &Quota {
+        &self.quota
+    }
+
+    /// Charges one request to `principal`. If the principal has exceeded its quota, returns how
+    /// long it should wait before retrying.
+    pub fn check(&self, principal: K) -> Result<(), Duration> {
+        self.check_at(principal, Instant::now())
+    }
+
+    fn check_at(&self, principal: K, now: Instant) -> Result<(), Duration> {
+        let quota = &self.quota;
+        let mut inner = self.inner.lock().unwrap();
+        inner.checks_since_sweep += 1;
+        if inner.checks_since_sweep >= SWEEP_INTERVAL {
+            inner.checks_since_sweep = 0;
+            // A full bucket is indistinguishable from a fresh one, so it can be forgotten.
+            inner.buckets.retain(|_, bucket| {
+                bucket.refill(quota, now);
+                !bucket.is_full(quota)
+            });
+        }
+        inner
+            .buckets
+            .entry(principal)
+            .or_insert_with(|| TokenBucket::full(quota, now))
+            .try_acquire(quota, now)
+    }
+}
+
+/// A [`Channel`] that rejects requests from principals that have exceeded their [`Quota`].
+///
+/// Rejected requests receive a [resource exhausted](ServerError::resource_exhausted) error
+/// carrying a hint of when the principal may retry.
+#[pin_project]
+#[derive(Debug)]
+pub struct QuotaChannel<C, K, F> {
+    #[pin]
+    inner: C,
+    quotas: Quotas<K>,
+    principal: F,
+}
+
+impl<C, K, F> QuotaChannel<C, K, F> {
+    /// Returns the inner channel.
+    pub fn get_ref(&self) -> &C {
+        &self.inner
+    }
+}
+
+impl<C, K, F> QuotaChannel<C, K, F>
+where
+    C: Channel,
+    K: Eq + Hash,
+    F: Fn(&Request<C::Req>) -> K,
+{
+    /// Returns a new `QuotaChannel` that wraps the given channel and enforces `quotas` on every
+    /// request, keyed by the principal returned by `principal`.
+    pub fn new(inner: C, quotas: Quotas<K>, principal: F) -> Self {
+        QuotaChannel {
+            inner,
+            quotas,
+            principal,
+        }
+    }
+}
+
+impl<C, K, F> Stream for QuotaChannel<C, K, F>
+where
+    C: Channel,
+    K: fmt::Display + Eq + Hash + Clone,
+    F: Fn(&Request<C::Req>) -> K,
+{
+    type Item = io::Result<Request<C::Req>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        loop {
+            // Ensure a rejection can be written before reading a request that might need one.
+            ready!(self.as_mut().project().inner.poll_ready(cx)?);
+
+            let request = match ready!(self.as_mut().project().inner.poll_next(cx)?) {
+                Some(request) => request,
+                None => return Poll::Ready(None),
+            };
+            let principal = (self.principal)(&request);
+            let retry_after = match self.quotas.check(principal.clone()) {
+                Ok(()) => return Poll::Ready(Some(Ok(request))),
+                Err(retry_after) => retry_after,
+            };
+            debug!(
+                "[{}] Principal {} exceeded its quota; retry after {:?}.",
+                request.context.trace_id(),
+                principal,
+                retry_after,
+            );
+            self.as_mut().start_send(ServerMessage::Response(Response {
+                request_id: request.id,
+                message: Err(ServerError::resource_exhausted(
+                    format!("Principal {} exceeded its request quota.", principal),
+                    retry_after,
+                )),
+            }))?;
+        }
+    }
+}
+
+impl<C, K, F> Sink<ServerMessage<C::Resp>> for QuotaChannel<C, K, F>
+where
+    C: Channel,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: ServerMessage<C::Resp>) -> io::Result<()> {
+        self.project().inner.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+impl<C, K, F> AsRef<C> for QuotaChannel<C, K, F> {
+    fn as_ref(&self) -> &C {
+        &self.inner
+    }
+}
+
+impl<C, K, F> Channel for QuotaChannel<C, K, F>
+where
+    C: Channel,
+    K: fmt::Display + Eq + Hash + Clone,
+    F: Fn(&Request<C::Req>) -> K,
+{
+    type Req = C::Req;
+    type Resp = C::Resp;
+
+    fn config(&self) -> &Config {
+        self.inner.config()
+    }
+
+    fn in_flight_requests(self: Pin<&mut Self>) -> usize {
+        self.project().inner.in_flight_requests()
+    }
+
+    fn start_request(self: Pin<&mut Self>, request_id: u64) -> AbortRegistration {
+        self.project().inner.start_request(request_id)
+    }
+
+    fn take_request_items(self: Pin<&mut Self>, request_id: u64) -> RequestItems<Self::Req> {
+        self.project().inner.take_request_items(request_id)
+    }
+
+    fn start_reply_window(self: Pin<&mut Self>, request_id: u64) -> ReplyWindow {
+        self.project().inner.start_reply_window(request_id)
+    }
+}
+
+/// A stream of channels that enforce per-principal quotas shared across all of them.
+#[pin_project]
+#[derive(Debug)]
+pub struct QuotaStream<S, K, F> {
+    #[pin]
+    inner: S,
+    quotas: Quotas<K>,
+    principal: F,
+}
+
+impl<S, K, F> QuotaStream<S, K, F>
+where
+    S: Stream,
+    S::Item: Channel,
+    K: Eq + Hash,
+{
+    pub(crate) fn new(inner: S, quota: Quota, principal: F) -> Self {
+        QuotaStream {
+            inner,
+            quotas: Quotas::new(quota),
+            principal,
+        }
+    }
+
+    /// Returns the quota state shared by all channels yielded by this stream.
+    pub fn quotas(&self) -> &Quotas<K> {
+        &self.quotas
+    }
+}
+
+impl<S, K, F> Stream for QuotaStream<S, K, F>
+where
+    S: Stream,
+    S::Item: Channel,
+    K: fmt::Display + Eq + Hash + Clone,
+    F: Fn(&Request<<S::Item as Channel>::Req>) -> K + Clone,
+{
+    type Item = QuotaChannel<S::Item, K, F>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        match ready!(self.as_mut().project().inner.poll_next(cx)) {
+            Some(channel) => Poll::Ready(Some(QuotaChannel::new(
+                channel,
+                self.quotas.clone(),
+                self.principal.clone(),
+            ))),
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+#[cfg(test)]
+use super::testing::{self, FakeChannel, PollExt};
+#[cfg(test)]
+use pin_utils::pin_mut;
+
+#[test]
+fn token_bucket_bursts_then_limits() {
+    let quota = Quota::new(2., 3);
+    let start = Instant::now();
+    let mut bucket = TokenBucket::full(&quota, start);
+    for _ in 0..3 {
+        assert_eq!(bucket.try_acquire(&quota, start), Ok(()));
+    }
+    assert_eq!(
+        bucket.try_acquire(&quota, start),
+        Err(Duration::from_millis(500))
+    );
+}
+
+#[test]
+fn token_bucket_refills_at_steady_rate() {
+    let quota = Quota::new(2., 3);
+    let start = Instant::now();
+    let mut bucket = TokenBucket::full(&quota, start);
+    for _ in 0..3 {
+        bucket.try_acquire(&quota, start).unwrap();
+    }
+    let later = start + Duration::from_millis(500);
+    assert_eq!(bucket.try_acquire(&quota, later), Ok(()));
+    assert!(bucket.try_acquire(&quota, later).is_err());
+
+    // Refills never exceed the burst size.
+    let much_later = later + Duration::from_secs(60);
+    bucket.refill(&quota, much_later);
+    assert!(bucket.is_full(&quota));
+    assert_eq!(bucket.tokens, 3.);
+}
+
+#[test]
+fn quotas_are_per_principal() {
+    let quotas = Quotas::new(Quota::new(1., 1));
+    let now = Instant::now();
+    assert_eq!(quotas.check_at("alice", now), Ok(()));
+    assert!(quotas.check_at("alice", now).is_err());
+    assert_eq!(quotas.check_at("bob", now), Ok(()));
+}
+
+#[test]
+fn quotas_sweep_forgets_idle_principals() {
+    let quotas = Quotas::new(Quota::new(1., 1));
+    let now = Instant::now();
+    for i in 0..SWEEP_INTERVAL - 1 {
+        quotas.check_at(i, now).unwrap();
+    }
+    assert_eq!(
+        quotas.inner.lock().unwrap().buckets.len(),
+        SWEEP_INTERVAL as usize - 1
+    );
+
+    // The next check sweeps, by which time every earlier bucket has refilled.
+    quotas
+        .check_at(SWEEP_INTERVAL, now + Duration::from_secs(10))
+        .unwrap();
+    assert_eq!(quotas.inner.lock().unwrap().buckets.len(), 1);
+}
+
+#[test]
+fn quota_channel_poll_next_allowed() -> io::Result<()> {
+    let channel = QuotaChannel::new(
+        FakeChannel::default::<isize, isize>(),
+        Quotas::new(Quota::new(1., 1)),
+        |_: &Request<isize>| "principal",
+    );
+    pin_mut!(channel);
+    channel.as_mut().project().inner.push_req(0, 1);
+    assert_eq!(
+        channel
+            .as_mut()
+            .poll_next(&mut testing::cx())?
+            .map(|r| r.map(|r| (r.id, r.message))),
+        Poll::Ready(Some((0, 1)))
+    );
+    assert!(channel.inner.sink.is_empty());
+    Ok(())
+}
+
+#[test]
+fn quota_channel_poll_next_exceeded() {
+    let channel = QuotaChannel::new(
+        FakeChannel::default::<isize, isize>(),
+        Quotas::new(Quota::new(1., 1)),
+        |_: &Request<isize>| "principal",
+    );
+    pin_mut!(channel);
+    channel.as_mut().project().inner.push_req(0, 1);
+    channel.as_mut().project().inner.push_req(1, 1);
+    assert!(channel.as_mut().poll_next(&mut testing::cx()).is_ready());
+    assert!(channel.as_mut().poll_next(&mut testing::cx()).is_done());
+
+    assert_eq!(channel.inner.sink.len(), 1);
+    let resp = channel.inner.responses()[0];
+    assert_eq!(resp.request_id, 1);
+    let error = resp.message.as_ref().unwrap_err();
+    assert_eq!(error.kind, io::ErrorKind::WouldBlock);
+    assert!(error.retry_after.is_some());
+}
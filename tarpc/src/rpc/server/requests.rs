@@ -0,0 +1,295 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use super::Channel;
+use crate::{Request, Response, ServerError, ServerMessage};
+use futures::{
+    channel::mpsc,
+    future::{Abortable, Aborted},
+    prelude::*,
+    ready,
+    task::*,
+};
+use log::{debug, trace};
+use pin_project::pin_project;
+use std::{fmt, io, pin::Pin};
+
+/// Identifies a connection among those served by a [`Requests`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnectionId(u64);
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// A request received by [`Requests`], with the [`Responder`] that answers it.
+#[derive(Debug)]
+pub struct RequestEnvelope<Req, Resp> {
+    /// The connection the request was received on.
+    pub connection: ConnectionId,
+    /// The request, with its context and ID.
+    pub request: Request<Req>,
+    /// Sends the response to the request.
+    pub responder: Responder<Resp>,
+}
+
+/// Answers one request received by [`Requests`].
+///
+/// A responder dropped without responding fails its request, so that the client doesn't wait
+/// for a response until the request's deadline.
+#[derive(Debug)]
+pub struct Responder<Resp> {
+    request_id: u64,
+    responses: Option<mpsc::UnboundedSender<ServerMessage<Resp>>>,
+    /// Resolves with `Aborted` once the client cancels the request.
+    canceled: Abortable<future::Pending<()>>,
+}
+
+impl<Resp> Responder<Resp> {
+    /// Sends `response` to the client. Fails with [`io::ErrorKind::ConnectionReset`] if the
+    /// connection is closed.
+    pub fn respond(mut self, response: Resp) -> io::Result<()> {
+        self.send(Ok(response))
+    }
+
+    /// Fails the request with `error`. Fails with [`io::ErrorKind::ConnectionReset`] if the
+    /// connection is closed.
+    pub fn fail(mut self, error: ServerError) -> io::Result<()> {
+        self.send(Err(error))
+    }
+
+    /// Returns true once the client has canceled the request, e.g. because it no longer needs
+    /// the response, or the connection has closed. Canceled requests needn't be responded to.
+    pub fn is_canceled(&mut self) -> bool {
+        matches!((&mut self.canceled).now_or_never(), Some(Err(Aborted)))
+            || self
+                .responses
+                .as_ref()
+                .is_none_or(mpsc::UnboundedSender::is_closed)
+    }
+
+    fn send(&mut self, message: Result<Resp, ServerError>) -> io::Result<()> {
+        let responses = self
+            .responses
+            .take()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::ConnectionReset))?;
+        responses
+            .unbounded_send(ServerMessage::Response(Response {
+                request_id: self.request_id,
+                message,
+            }))
+            .map_err(|_| io::Error::from(io::ErrorKind::ConnectionReset))
+    }
+}
+
+impl<Resp> Drop for Responder<Resp> {
+    fn drop(&mut self) {
+        if self.responses.is_some() {
+            let _ = self.send(Err(ServerError {
+                kind: io::ErrorKind::Other,
+                detail: Some("The server dropped the request without responding.".into()),
+                retry_after: None,
+            }));
+        }
+    }
+}
+
+/// A stream of the requests received on many connections, for applications that schedule,
+/// prioritize, or defer their responses themselves rather than serving requests as they arrive.
+///
+/// Each request comes with a [`Responder`] to answer it with, at any time and from any task.
+/// Each connection is driven by a task of its own, which writes responses as they're sent, and
+/// stops reading requests while `buffer` requests are waiting to be taken from the stream.
+/// Replies are unary: the request items and streamed replies of [`Serve`](super::Serve) aren't
+/// supported, and the server doesn't enforce deadlines, so handlers should check
+/// `request.context.deadline` themselves.
+///
+/// ```
+/// # use tarpc::{server::{self, BaseChannel, Requests}, transport::channel};
+/// # use futures::prelude::*;
+/// # async fn run() {
+/// let (_client, transport) = channel::unbounded();
+/// let channels = stream::once(future::ready(BaseChannel::with_defaults(transport)));
+/// let mut requests = Requests::<String, String>::new(channels, 100);
+/// while let Some(envelope) = requests.next().await {
+///     let greeting = format!("Hello, {}!", envelope.request.message);
+///     let _ = envelope.responder.respond(greeting);
+/// }
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Requests<Req, Resp> {
+    requests: mpsc::Receiver<RequestEnvelope<Req, Resp>>,
+}
+
+impl<Req, Resp> Requests<Req, Resp>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+{
+    /// Returns the requests received on the connections of `channels`, buffering up to `buffer`
+    /// of them. Spawns a task that accepts the channels, and a task per channel.
+    pub fn new<S, C>(channels: S, buffer: usize) -> Self
+    where
+        S: Stream<Item = C> + Send + 'static,
+        C: Channel<Req = Req, Resp = Resp> + Send + 'static,
+    {
+        let (requests_tx, requests) = mpsc::channel(buffer);
+        tokio::spawn(
+            channels
+                .zip(stream::iter((0..).map(ConnectionId)))
+                .for_each(move |(channel, id)| {
+                    let (responses_tx, responses) = mpsc::unbounded();
+                    tokio::spawn(Connection {
+                        id,
+                        channel,
+                        requests: requests_tx.clone(),
+                        pending_request: None,
+                        responses,
+                        responses_tx: Some(responses_tx),
+                        pending_response: None,
+                    });
+                    future::ready(())
+                }),
+        );
+        Requests { requests }
+    }
+}
+
+impl<Req, Resp> Stream for Requests<Req, Resp> {
+    type Item = RequestEnvelope<Req, Resp>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.requests.poll_next_unpin(cx)
+    }
+}
+
+/// Drives one connection served by [`Requests`].
+#[pin_project]
+struct Connection<C>
+where
+    C: Channel,
+{
+    id: ConnectionId,
+    #[pin]
+    channel: C,
+    requests: mpsc::Sender<RequestEnvelope<C::Req, C::Resp>>,
+    /// A request waiting for room in `requests`.
+    pending_request: Option<RequestEnvelope<C::Req, C::Resp>>,
+    responses: mpsc::UnboundedReceiver<ServerMessage<C::Resp>>,
+    /// Cloned into every responder. None once the channel stops yielding requests, so that
+    /// `responses` ends once every responder is gone.
+    responses_tx: Option<mpsc::UnboundedSender<ServerMessage<C::Resp>>>,
+    /// A response waiting for room in the channel.
+    pending_response: Option<ServerMessage<C::Resp>>,
+}
+
+impl<C> Connection<C>
+where
+    C: Channel,
+{
+    /// Writes the responses sent by responders to the channel. Resolves once every responder is
+    /// gone and their responses have been flushed.
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        loop {
+            if this.pending_response.is_none() {
+                match this.responses.poll_next_unpin(cx) {
+                    Poll::Ready(Some(response)) => *this.pending_response = Some(response),
+                    Poll::Ready(None) => {
+                        ready!(this.channel.as_mut().poll_flush(cx)?);
+                        return Poll::Ready(Ok(()));
+                    }
+                    Poll::Pending => break,
+                }
+            }
+            match this.channel.as_mut().poll_ready(cx)? {
+                Poll::Ready(()) => {
+                    let response = this.pending_response.take().unwrap();
+                    this.channel.as_mut().start_send(response)?;
+                }
+                Poll::Pending => break,
+            }
+        }
+        ready!(this.channel.poll_flush(cx)?);
+        Poll::Pending
+    }
+
+    /// Reads requests off the channel into the stream of requests. Resolves once the channel or
+    /// the stream is closed.
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        loop {
+            if this.pending_request.is_some() {
+                match this.requests.poll_ready(cx) {
+                    Poll::Ready(Ok(())) => {
+                        let request = this.pending_request.take().unwrap();
+                        if this.requests.start_send(request).is_err() {
+                            return Poll::Ready(Ok(()));
+                        }
+                    }
+                    // No one is taking requests anymore.
+                    Poll::Ready(Err(_)) => return Poll::Ready(Ok(())),
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+            let responses = match this.responses_tx {
+                Some(responses) => responses.clone(),
+                None => return Poll::Ready(Ok(())),
+            };
+            let request = match ready!(this.channel.as_mut().poll_next(cx)?) {
+                Some(request) => request,
+                None => return Poll::Ready(Ok(())),
+            };
+            trace!(
+                "[{}/{}] Received request {}.",
+                request.context.trace_id(),
+                this.id,
+                request.id
+            );
+            let canceled = this.channel.as_mut().start_request(request.id);
+            *this.pending_request = Some(RequestEnvelope {
+                connection: *this.id,
+                responder: Responder {
+                    request_id: request.id,
+                    responses: Some(responses),
+                    canceled: Abortable::new(future::pending(), canceled),
+                },
+                request,
+            });
+        }
+    }
+}
+
+impl<C> Future for Connection<C>
+where
+    C: Channel,
+{
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.responses_tx.is_some() {
+            match self.as_mut().poll_read(cx) {
+                Poll::Ready(result) => {
+                    if let Err(e) = result {
+                        debug!("Connection {} errored: {}", self.id, e);
+                    }
+                    // Requests no longer arrive, but their responses are still written.
+                    *self.as_mut().project().responses_tx = None;
+                    *self.as_mut().project().pending_request = None;
+                }
+                Poll::Pending => {}
+            }
+        }
+        match ready!(self.as_mut().poll_write(cx)) {
+            Ok(()) => debug!("Connection {} closed.", self.id),
+            Err(e) => debug!("Connection {} errored: {}", self.id, e),
+        }
+        Poll::Ready(())
+    }
+}
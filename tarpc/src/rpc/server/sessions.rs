@@ -0,0 +1,166 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use crate::{SessionRequest, SessionToken};
+use fnv::FnvHashMap;
+use log::debug;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The sessions of a server's clients. A client that asks for a session at handshake is given a
+/// [token](SessionToken) for it, and a client that reconnects can present the token to resume the
+/// session, so that the server can correlate the new connection with the old one, e.g. to keep
+/// its subscriptions, instead of starting from scratch. Handlers find the session of the
+/// connection a request arrived on in [`Context::session`](crate::context::Context::session).
+///
+/// A session outlives its connections by `ttl`, so that there's time to reconnect. Clones share
+/// the same sessions, so a single `Sessions` can be shared by every channel of a server.
+#[derive(Clone, Debug)]
+pub struct Sessions {
+    ttl: Duration,
+    sessions: Arc<Mutex<FnvHashMap<SessionToken, Session>>>,
+}
+
+#[derive(Debug)]
+struct Session {
+    /// The number of open connections that belong to the session.
+    connections: usize,
+    /// When the session's last connection closed, if none are open.
+    idle_since: Option<Instant>,
+}
+
+impl Session {
+    fn is_expired(&self, ttl: Duration, now: Instant) -> bool {
+        match self.idle_since {
+            Some(idle_since) => now.duration_since(idle_since) >= ttl,
+            None => false,
+        }
+    }
+}
+
+impl Sessions {
+    /// Returns new sessions, each of which can be resumed until `ttl` after its last connection
+    /// closed.
+    pub fn new(ttl: Duration) -> Self {
+        Sessions {
+            ttl,
+            sessions: Arc::new(Mutex::new(FnvHashMap::default())),
+        }
+    }
+
+    /// Returns the number of sessions that can be resumed, including those with open connections.
+    pub fn len(&self) -> usize {
+        let now = Instant::now();
+        self.sessions
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|session| !session.is_expired(self.ttl, now))
+            .count()
+    }
+
+    /// Returns true if there are no sessions that can be resumed.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns true if the session identified by `token` can be resumed.
+    pub fn contains(&self, token: &SessionToken) -> bool {
+        match self.sessions.lock().unwrap().get(token) {
+            Some(session) => !session.is_expired(self.ttl, Instant::now()),
+            None => false,
+        }
+    }
+
+    /// Adds a connection to the session the client asked for, or to a new session if the client
+    /// asked to resume one that's expired or unknown. The connection leaves the session when the
+    /// returned membership is dropped.
+    pub(crate) fn join(&self, request: &SessionRequest) -> Membership {
+        let now = Instant::now();
+        let ttl = self.ttl;
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| !session.is_expired(ttl, now));
+        let token = match request {
+            SessionRequest::Resume(token) if sessions.contains_key(token) => {
+                debug!("Resuming session {}.", token);
+                token.clone()
+            }
+            request => {
+                let token = SessionToken::random();
+                if let SessionRequest::Resume(expired) = request {
+                    debug!(
+                        "Can't resume session {}, which is unknown or expired; starting session {}.",
+                        expired, token
+                    );
+                }
+                sessions.insert(
+                    token.clone(),
+                    Session {
+                        connections: 0,
+                        idle_since: None,
+                    },
+                );
+                token
+            }
+        };
+        let session = sessions.get_mut(&token).unwrap();
+        session.connections += 1;
+        session.idle_since = None;
+        Membership {
+            sessions: self.clone(),
+            token,
+        }
+    }
+}
+
+/// A connection's membership in a session, which ends when it's dropped.
+#[derive(Debug)]
+pub(crate) struct Membership {
+    sessions: Sessions,
+    token: SessionToken,
+}
+
+impl Membership {
+    pub(crate) fn token(&self) -> &SessionToken {
+        &self.token
+    }
+}
+
+impl Drop for Membership {
+    fn drop(&mut self) {
+        let mut sessions = self.sessions.sessions.lock().unwrap();
+        if let Some(session) = sessions.get_mut(&self.token) {
+            session.connections -= 1;
+            if session.connections == 0 {
+                session.idle_since = Some(Instant::now());
+            }
+        }
+    }
+}
+
+#[test]
+fn sessions_outlive_their_connections_by_their_ttl() {
+    let sessions = Sessions::new(Duration::from_secs(60));
+    let membership = sessions.join(&SessionRequest::Start);
+    let token = membership.token().clone();
+    drop(membership);
+    assert!(sessions.contains(&token));
+
+    let resumed = sessions.join(&SessionRequest::Resume(token.clone()));
+    assert_eq!(resumed.token(), &token);
+    assert_eq!(sessions.len(), 1);
+
+    let expired = Sessions::new(Duration::from_secs(0));
+    let membership = expired.join(&SessionRequest::Start);
+    let token = membership.token().clone();
+    assert!(expired.contains(&token));
+    drop(membership);
+    assert!(!expired.contains(&token));
+    let restarted = expired.join(&SessionRequest::Resume(token.clone()));
+    assert_ne!(restarted.token(), &token);
+}
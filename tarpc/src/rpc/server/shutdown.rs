@@ -0,0 +1,66 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use super::Drain;
+use log::{info, warn};
+use std::{io, time::Duration};
+
+/// Waits for SIGTERM or SIGINT, then drains the server and waits up to `grace` for its channels
+/// to close. Resolves once they have or the grace period ends, so that the server can exit.
+///
+/// This is the termination sequence expected by e.g. Kubernetes, which sends SIGTERM and kills
+/// the process if it's still running after its termination grace period, so `grace` should be
+/// shorter than that. On platforms other than Unix, only Ctrl-C is waited for.
+///
+/// ```no_run
+/// # use tarpc::server::{self, Drain};
+/// # use std::time::Duration;
+/// # async fn serve(_: server::Config) {}
+/// #[tokio::main]
+/// async fn main() -> std::io::Result<()> {
+///     let drain = Drain::new();
+///     let config = server::Config {
+///         drain: Some(drain.clone()),
+///         ..Default::default()
+///     };
+///     tokio::spawn(serve(config));
+///     server::shutdown_on_signal(drain, Duration::from_secs(20)).await
+/// }
+/// ```
+pub async fn shutdown_on_signal(drain: Drain, grace: Duration) -> io::Result<()> {
+    terminated().await?;
+    info!(
+        "Draining {} channels for up to {:?}.",
+        drain.channels(),
+        grace
+    );
+    drain.start();
+    if tokio::time::timeout(grace, drain.drained()).await.is_err() {
+        warn!(
+            "Shutting down with {} channels still open.",
+            drain.channels()
+        );
+    }
+    Ok(())
+}
+
+/// Resolves once the process is asked to terminate.
+#[cfg(unix)]
+async fn terminated() -> io::Result<()> {
+    use futures::prelude::*;
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    future::select(terminate.recv().boxed(), interrupt.recv().boxed()).await;
+    Ok(())
+}
+
+/// Resolves once the process is asked to terminate.
+#[cfg(not(unix))]
+async fn terminated() -> io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
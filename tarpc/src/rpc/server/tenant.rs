@@ -0,0 +1,252 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use super::Serve;
+use crate::context;
+use fnv::FnvHashMap;
+use futures::{prelude::*, task::*};
+use pin_project::{pin_project, pinned_drop};
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Load attributed to a single tenant.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TenantStats {
+    /// The number of requests started.
+    pub requests: u64,
+    /// The number of requests currently being handled.
+    pub in_flight: u64,
+    /// The number of requests whose handlers ran to completion.
+    pub completed: u64,
+    /// The number of requests abandoned before their handlers completed, e.g. because they were
+    /// canceled by the client.
+    pub abandoned: u64,
+    /// The total time spent in handlers of completed requests.
+    pub handler_time: Duration,
+    /// The total time requests waited between being decoded and their handlers being called.
+    pub queue_time: Duration,
+}
+
+/// Aggregates load per tenant, as identified by [`Context::tenant_id`](context::Context::tenant_id).
+///
+/// Requests that don't specify a tenant are attributed to `None`. Clones share the same
+/// accounting, so a single `TenantAccounting` can be applied to every channel of a server.
+///
+/// The tenant ID is chosen by the client and isn't authenticated, so a client can attribute its
+/// requests to any tenant. Since each tenant is tracked until the accounting is dropped, the
+/// number of tenants is capped; requests of tenants beyond the cap are attributed to the
+/// [overflow](TenantAccounting::overflow) instead.
+///
+/// To limit the rate of requests per tenant, use
+/// [`quota_per_principal`](super::Handler::quota_per_principal) with the tenant as the principal.
+#[derive(Clone, Debug)]
+pub struct TenantAccounting {
+    max_tenants: usize,
+    tenants: Arc<Mutex<Tenants>>,
+}
+
+#[derive(Debug, Default)]
+struct Tenants {
+    stats: FnvHashMap<Option<String>, TenantStats>,
+    overflow: TenantStats,
+}
+
+impl Default for TenantAccounting {
+    fn default() -> Self {
+        Self::with_max_tenants(1_000)
+    }
+}
+
+impl TenantAccounting {
+    /// Returns a new, empty accounting that tracks up to 1,000 tenants.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a new, empty accounting that tracks up to `max_tenants` tenants.
+    pub fn with_max_tenants(max_tenants: usize) -> Self {
+        TenantAccounting {
+            max_tenants,
+            tenants: Default::default(),
+        }
+    }
+
+    /// Returns a request handler that attributes every request served by `serve` to its tenant.
+    pub fn serve<S>(&self, serve: S) -> TenantServe<S> {
+        TenantServe {
+            accounting: self.clone(),
+            serve,
+        }
+    }
+
+    /// Returns the load attributed to `tenant_id`.
+    pub fn stats(&self, tenant_id: Option<&str>) -> TenantStats {
+        self.tenants
+            .lock()
+            .unwrap()
+            .stats
+            .get(&tenant_id.map(String::from))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Returns the load attributed to every tenant tracked.
+    pub fn snapshot(&self) -> FnvHashMap<Option<String>, TenantStats> {
+        self.tenants.lock().unwrap().stats.clone()
+    }
+
+    /// Returns the load of the tenants seen after the accounting tracked as many as it can.
+    pub fn overflow(&self) -> TenantStats {
+        self.tenants.lock().unwrap().overflow.clone()
+    }
+
+    fn update(&self, tenant_id: &Option<String>, f: impl FnOnce(&mut TenantStats)) {
+        let mut tenants = self.tenants.lock().unwrap();
+        // Tenants are never forgotten, so a tenant that overflowed when its request started
+        // still overflows when it completes.
+        if tenants.stats.contains_key(tenant_id) || tenants.stats.len() < self.max_tenants {
+            f(tenants.stats.entry(tenant_id.clone()).or_default())
+        } else {
+            f(&mut tenants.overflow)
+        }
+    }
+}
+
+/// A [`Serve`] that attributes each request to its tenant in a [`TenantAccounting`].
+#[derive(Clone, Debug)]
+pub struct TenantServe<S> {
+    accounting: TenantAccounting,
+    serve: S,
+}
+
+impl<Req, S> Serve<Req> for TenantServe<S>
+where
+    S: Serve<Req>,
+{
+    type Resp = S::Resp;
+    type Fut = TenantFuture<S::Fut>;
+
+    fn serve(self, ctx: context::Context, req: Req) -> Self::Fut {
+        let tenant_id = ctx.tenant_id.clone();
+        let queue_time = ctx.queue_time().unwrap_or_default();
+        self.accounting.update(&tenant_id, |stats| {
+            stats.requests += 1;
+            stats.in_flight += 1;
+            stats.queue_time += queue_time;
+        });
+        TenantFuture {
+            fut: self.serve.serve(ctx, req),
+            accounting: self.accounting,
+            tenant_id,
+            started: Instant::now(),
+            complete: false,
+        }
+    }
+}
+
+/// A response future that records its outcome in a [`TenantAccounting`] when it completes or is
+/// dropped.
+#[pin_project(PinnedDrop)]
+#[derive(Debug)]
+pub struct TenantFuture<F> {
+    #[pin]
+    fut: F,
+    accounting: TenantAccounting,
+    tenant_id: Option<String>,
+    started: Instant,
+    complete: bool,
+}
+
+impl<F: Future> Future for TenantFuture<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let this = self.project();
+        let output = futures::ready!(this.fut.poll(cx));
+        *this.complete = true;
+        let elapsed = this.started.elapsed();
+        this.accounting.update(this.tenant_id, |stats| {
+            stats.in_flight -= 1;
+            stats.completed += 1;
+            stats.handler_time += elapsed;
+        });
+        Poll::Ready(output)
+    }
+}
+
+#[pinned_drop]
+impl<F> PinnedDrop for TenantFuture<F> {
+    fn drop(self: Pin<&mut Self>) {
+        if !self.complete {
+            self.accounting.update(&self.tenant_id, |stats| {
+                stats.in_flight -= 1;
+                stats.abandoned += 1;
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+fn ctx(tenant_id: Option<&str>) -> context::Context {
+    let mut ctx = context::current();
+    ctx.tenant_id = tenant_id.map(String::from);
+    ctx
+}
+
+#[tokio::test]
+async fn tenant_serve_attributes_requests() {
+    let accounting = TenantAccounting::new();
+    let serve = accounting.serve(|_, i: i32| future::ready(i));
+
+    assert_eq!(serve.clone().serve(ctx(Some("a")), 1).await, 1);
+    assert_eq!(serve.clone().serve(ctx(Some("a")), 2).await, 2);
+    assert_eq!(serve.serve(ctx(None), 3).await, 3);
+
+    let a = accounting.stats(Some("a"));
+    assert_eq!(a.requests, 2);
+    assert_eq!(a.completed, 2);
+    assert_eq!(a.in_flight, 0);
+    assert_eq!(accounting.stats(None).requests, 1);
+    assert_eq!(accounting.stats(Some("b")), TenantStats::default());
+    assert_eq!(accounting.snapshot().len(), 2);
+}
+
+#[test]
+fn tenant_future_in_flight_until_dropped() {
+    let accounting = TenantAccounting::new();
+    let serve = accounting.serve(|_, ()| future::pending::<()>());
+
+    let fut = serve.serve(ctx(Some("a")), ());
+    assert_eq!(accounting.stats(Some("a")).in_flight, 1);
+
+    drop(fut);
+    let a = accounting.stats(Some("a"));
+    assert_eq!(a.in_flight, 0);
+    assert_eq!(a.abandoned, 1);
+    assert_eq!(a.completed, 0);
+}
+
+#[tokio::test]
+async fn tenants_beyond_the_cap_overflow() {
+    let accounting = TenantAccounting::with_max_tenants(2);
+    let serve = accounting.serve(|_, i: i32| future::ready(i));
+
+    for tenant_id in &["a", "b", "c", "d", "a"] {
+        serve.clone().serve(ctx(Some(tenant_id)), 0).await;
+    }
+
+    assert_eq!(accounting.snapshot().len(), 2);
+    assert_eq!(accounting.stats(Some("a")).completed, 2);
+    assert_eq!(accounting.stats(Some("c")), TenantStats::default());
+    let overflow = accounting.overflow();
+    assert_eq!(overflow.requests, 2);
+    assert_eq!(overflow.completed, 2);
+    assert_eq!(overflow.in_flight, 0);
+}
@@ -1,5 +1,5 @@
-use crate::server::{Channel, Config};
-use crate::{context, Request, Response};
+use crate::server::{Channel, Config, ReplyWindow, RequestItems};
+use crate::{context, ErrorFrame, Request, Response, ServerMessage};
 use fnv::FnvHashSet;
 use futures::{
     future::{AbortHandle, AbortRegistration},
@@ -33,21 +33,26 @@ where
     }
 }
 
-impl<In, Resp> Sink<Response<Resp>> for FakeChannel<In, Response<Resp>> {
+impl<In, Resp> Sink<ServerMessage<Resp>> for FakeChannel<In, ServerMessage<Resp>> {
     type Error = io::Error;
 
     fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
         self.project().sink.poll_ready(cx).map_err(|e| match e {})
     }
 
-    fn start_send(mut self: Pin<&mut Self>, response: Response<Resp>) -> Result<(), Self::Error> {
-        self.as_mut()
-            .project()
-            .in_flight_requests
-            .remove(&response.request_id);
+    fn start_send(
+        mut self: Pin<&mut Self>,
+        message: ServerMessage<Resp>,
+    ) -> Result<(), Self::Error> {
+        if let (true, Some(request_id)) = (message.is_final(), message.request_id()) {
+            self.as_mut()
+                .project()
+                .in_flight_requests
+                .remove(&request_id);
+        }
         self.project()
             .sink
-            .start_send(response)
+            .start_send(message)
             .map_err(|e| match e {})
     }
 
@@ -60,7 +65,7 @@ impl<In, Resp> Sink<Response<Resp>> for FakeChannel<In, Response<Resp>> {
     }
 }
 
-impl<Req, Resp> Channel for FakeChannel<io::Result<Request<Req>>, Response<Resp>>
+impl<Req, Resp> Channel for FakeChannel<io::Result<Request<Req>>, ServerMessage<Resp>>
 where
     Req: Unpin,
 {
@@ -79,23 +84,54 @@ where
         self.project().in_flight_requests.insert(id);
         AbortHandle::new_pair().1
     }
+
+    fn take_request_items(self: Pin<&mut Self>, _: u64) -> RequestItems<Req> {
+        RequestItems::empty()
+    }
+
+    fn start_reply_window(self: Pin<&mut Self>, _: u64) -> ReplyWindow {
+        ReplyWindow::unlimited()
+    }
 }
 
-impl<Req, Resp> FakeChannel<io::Result<Request<Req>>, Response<Resp>> {
+impl<Req, Resp> FakeChannel<io::Result<Request<Req>>, ServerMessage<Resp>> {
     pub fn push_req(&mut self, id: u64, message: Req) {
+        let mut context = context::current();
+        context.deadline = SystemTime::UNIX_EPOCH;
+        context.trace_context = Default::default();
         self.stream.push_back(Ok(Request {
-            context: context::Context {
-                deadline: SystemTime::UNIX_EPOCH,
-                trace_context: Default::default(),
-            },
+            context,
             id,
+            method: None,
             message,
         }));
     }
 }
 
+impl<In, Resp> FakeChannel<In, ServerMessage<Resp>> {
+    pub fn responses(&self) -> Vec<&Response<Resp>> {
+        self.sink
+            .iter()
+            .filter_map(|message| match message {
+                ServerMessage::Response(response) => Some(response),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn errors(&self) -> Vec<&ErrorFrame> {
+        self.sink
+            .iter()
+            .filter_map(|message| match message {
+                ServerMessage::Error(frame) => Some(frame),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
 impl FakeChannel<(), ()> {
-    pub fn default<Req, Resp>() -> FakeChannel<io::Result<Request<Req>>, Response<Resp>> {
+    pub fn default<Req, Resp>() -> FakeChannel<io::Result<Request<Req>>, ServerMessage<Resp>> {
         FakeChannel {
             stream: Default::default(),
             sink: Default::default(),
@@ -111,13 +147,10 @@ pub trait PollExt {
 
 impl<T> PollExt for Poll<Option<T>> {
     fn is_done(&self) -> bool {
-        match self {
-            Poll::Ready(None) => true,
-            _ => false,
-        }
+        matches!(self, Poll::Ready(None))
     }
 }
 
 pub fn cx() -> Context<'static> {
-    Context::from_waker(&noop_waker_ref())
+    Context::from_waker(noop_waker_ref())
 }
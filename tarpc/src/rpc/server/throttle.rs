@@ -1,5 +1,5 @@
-use super::{Channel, Config};
-use crate::{Response, ServerError};
+use super::{Channel, Config, ReplyWindow, RequestItems};
+use crate::{Response, ServerError, ServerMessage};
 use futures::{future::AbortRegistration, prelude::*, ready, task::*};
 use log::debug;
 use pin_project::pin_project;
@@ -56,13 +56,14 @@ where
                         self.as_mut().project().max_in_flight_requests,
                     );
 
-                    self.as_mut().start_send(Response {
+                    self.as_mut().start_send(ServerMessage::Response(Response {
                         request_id: request.id,
                         message: Err(ServerError {
                             kind: io::ErrorKind::WouldBlock,
                             detail: Some("Server throttled the request.".into()),
+                            retry_after: None,
                         }),
-                    })?;
+                    }))?;
                 }
                 None => return Poll::Ready(None),
             }
@@ -71,7 +72,7 @@ where
     }
 }
 
-impl<C> Sink<Response<<C as Channel>::Resp>> for Throttler<C>
+impl<C> Sink<ServerMessage<<C as Channel>::Resp>> for Throttler<C>
 where
     C: Channel,
 {
@@ -81,7 +82,10 @@ where
         self.project().inner.poll_ready(cx)
     }
 
-    fn start_send(self: Pin<&mut Self>, item: Response<<C as Channel>::Resp>) -> io::Result<()> {
+    fn start_send(
+        self: Pin<&mut Self>,
+        item: ServerMessage<<C as Channel>::Resp>,
+    ) -> io::Result<()> {
         self.project().inner.start_send(item)
     }
 
@@ -118,6 +122,14 @@ where
     fn start_request(self: Pin<&mut Self>, request_id: u64) -> AbortRegistration {
         self.project().inner.start_request(request_id)
     }
+
+    fn take_request_items(self: Pin<&mut Self>, request_id: u64) -> RequestItems<Self::Req> {
+        self.project().inner.take_request_items(request_id)
+    }
+
+    fn start_reply_window(self: Pin<&mut Self>, request_id: u64) -> ReplyWindow {
+        self.project().inner.start_reply_window(request_id)
+    }
 }
 
 /// A stream of throttling channels.
@@ -237,7 +249,7 @@ fn throttler_poll_next_throttled() {
     throttler.inner.push_req(1, 1);
     assert!(throttler.as_mut().poll_next(&mut testing::cx()).is_done());
     assert_eq!(throttler.inner.sink.len(), 1);
-    let resp = throttler.inner.sink.get(0).unwrap();
+    let resp = throttler.inner.responses()[0];
     assert_eq!(resp.request_id, 1);
     assert!(resp.message.is_err());
 }
@@ -255,7 +267,7 @@ fn throttler_poll_next_throttled_sink_not_ready() {
         ghost: PhantomData<fn(Out) -> In>,
     }
     impl PendingSink<(), ()> {
-        pub fn default<Req, Resp>() -> PendingSink<io::Result<Request<Req>>, Response<Resp>> {
+        pub fn default<Req, Resp>() -> PendingSink<io::Result<Request<Req>>, ServerMessage<Resp>> {
             PendingSink { ghost: PhantomData }
         }
     }
@@ -280,7 +292,7 @@ fn throttler_poll_next_throttled_sink_not_ready() {
             Poll::Pending
         }
     }
-    impl<Req, Resp> Channel for PendingSink<io::Result<Request<Req>>, Response<Resp>> {
+    impl<Req, Resp> Channel for PendingSink<io::Result<Request<Req>>, ServerMessage<Resp>> {
         type Req = Req;
         type Resp = Resp;
         fn config(&self) -> &Config {
@@ -292,6 +304,14 @@ fn throttler_poll_next_throttled_sink_not_ready() {
         fn start_request(self: Pin<&mut Self>, _: u64) -> AbortRegistration {
             unimplemented!()
         }
+
+        fn take_request_items(self: Pin<&mut Self>, _: u64) -> RequestItems<Self::Req> {
+            unimplemented!()
+        }
+
+        fn start_reply_window(self: Pin<&mut Self>, _: u64) -> ReplyWindow {
+            unimplemented!()
+        }
     }
 }
 
@@ -306,17 +326,17 @@ fn throttler_start_send() {
     throttler.inner.in_flight_requests.insert(0);
     throttler
         .as_mut()
-        .start_send(Response {
+        .start_send(ServerMessage::Response(Response {
             request_id: 0,
             message: Ok(1),
-        })
+        }))
         .unwrap();
     assert!(throttler.inner.in_flight_requests.is_empty());
     assert_eq!(
-        throttler.inner.sink.get(0),
-        Some(&Response {
+        throttler.inner.sink.front(),
+        Some(&ServerMessage::Response(Response {
             request_id: 0,
             message: Ok(1),
-        })
+        }))
     );
 }
@@ -0,0 +1,451 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use super::{Notifier, Sessions};
+use crate::SessionToken;
+use fnv::{FnvHashMap, FnvHashSet};
+use log::{debug, trace};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+/// Named topics that connections subscribe to, and to which a server publishes notifications.
+///
+/// Each connection joins with the [`Notifier`] of its channel, obtaining a [`TopicSubscriber`]
+/// that its handlers use to subscribe to and unsubscribe from topics. Publishing to a topic
+/// pushes a notification to every connection subscribed to it. Clones share the same topics, so
+/// a single `Topics` can be shared by every channel of a server.
+///
+/// Connections whose channels have closed are forgotten the next time they'd be notified, so
+/// there's no need to unsubscribe on disconnect. Connections whose notification buffers are full
+/// when they'd be notified are closed, and treated as though they'd disconnected, rather than let
+/// them miss notifications.
+///
+/// Topics [with sessions](Topics::with_sessions) keep the subscriptions of connections that
+/// [joined in a session](Topics::join_session) through a transient disconnect instead. While a
+/// session has no connection, the notifications published to its topics are buffered, up to a
+/// bound, and they're replayed when a connection resumes the session and joins in it. Only
+/// notifications published after its channel closed are replayed; those queued to the closed
+/// channel are lost with it.
+#[derive(Debug)]
+pub struct Topics<Resp> {
+    inner: Arc<Mutex<TopicsInner<Resp>>>,
+}
+
+#[derive(Debug)]
+struct TopicsInner<Resp> {
+    next_connection_id: u64,
+    connections: FnvHashMap<u64, Connection<Resp>>,
+    /// The IDs of the connections subscribed to each topic.
+    topics: FnvHashMap<String, FnvHashSet<u64>>,
+    /// The sessions whose subscriptions outlive their connections, if any.
+    sessions: Option<Sessions>,
+    /// The number of notifications buffered for each session without a connection.
+    replay_capacity: usize,
+    /// The ID each session's connections subscribe as.
+    session_connections: FnvHashMap<SessionToken, u64>,
+}
+
+#[derive(Debug)]
+struct Connection<Resp> {
+    /// Notifies the connection, or None if the connection's session is waiting to be resumed.
+    notifier: Option<Notifier<Resp>>,
+    /// The session the connection joined in, if any.
+    session: Option<SessionToken>,
+    /// The notifications published while the connection's session had no connection, oldest
+    /// first.
+    missed: VecDeque<Resp>,
+}
+
+impl<Resp> TopicsInner<Resp> {
+    fn remove_connection(&mut self, connection_id: u64) {
+        if let Some(connection) = self.connections.remove(&connection_id) {
+            if let Some(session) = connection.session {
+                self.session_connections.remove(&session);
+            }
+        }
+        self.topics.retain(|_, subscribers| {
+            subscribers.remove(&connection_id);
+            !subscribers.is_empty()
+        });
+    }
+
+    /// Returns true if the connection's subscriptions should be kept while it has no channel,
+    /// because its session can still be resumed.
+    fn is_resumable(&self, connection_id: u64) -> bool {
+        match (&self.sessions, &self.connections[&connection_id].session) {
+            (Some(sessions), Some(session)) => sessions.contains(session),
+            _ => false,
+        }
+    }
+
+    /// Delivers `notification` to the connection, buffering it if the connection's session is
+    /// waiting to be resumed. Returns true if the notification was delivered, and false if it was
+    /// buffered or the connection was forgotten.
+    fn notify(&mut self, connection_id: u64, notification: Resp) -> bool {
+        let connection = match self.connections.get_mut(&connection_id) {
+            Some(connection) => connection,
+            None => return false,
+        };
+        let notification = match connection.notifier {
+            Some(ref mut notifier) => match notifier.notifications.try_send(notification) {
+                Ok(()) => return true,
+                Err(e) => {
+                    // A connection that falls behind is closed, as though it had disconnected.
+                    if e.is_full() {
+                        debug!(
+                            "Connection {} fell behind on notifications; closing it.",
+                            connection_id
+                        );
+                        notifier.close();
+                    }
+                    e.into_inner()
+                }
+            },
+            None => notification,
+        };
+        if !self.is_resumable(connection_id) {
+            trace!(
+                "Connection {} closed; removing its subscriptions.",
+                connection_id
+            );
+            self.remove_connection(connection_id);
+            return false;
+        }
+        let replay_capacity = self.replay_capacity;
+        let connection = self.connections.get_mut(&connection_id).unwrap();
+        if connection.notifier.take().is_some() {
+            debug!(
+                "Connection {} closed; keeping its subscriptions for its session.",
+                connection_id
+            );
+        }
+        if connection.missed.len() == replay_capacity {
+            connection.missed.pop_front();
+        }
+        if replay_capacity > 0 {
+            connection.missed.push_back(notification);
+        }
+        false
+    }
+}
+
+impl<Resp> Clone for Topics<Resp> {
+    fn clone(&self) -> Self {
+        Topics {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<Resp> Default for Topics<Resp> {
+    fn default() -> Self {
+        Topics {
+            inner: Arc::new(Mutex::new(TopicsInner {
+                next_connection_id: 0,
+                connections: FnvHashMap::default(),
+                topics: FnvHashMap::default(),
+                sessions: None,
+                replay_capacity: 0,
+                session_connections: FnvHashMap::default(),
+            })),
+        }
+    }
+}
+
+impl<Resp> Topics<Resp> {
+    /// Returns a new set of topics, with no subscribers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a new set of topics, with no subscribers, that keeps the subscriptions of the
+    /// connections that join in a session for as long as `sessions` can resume it. Up to
+    /// `replay_capacity` of the notifications published while a session has no connection are
+    /// buffered for it, dropping the oldest first.
+    pub fn with_sessions(sessions: Sessions, replay_capacity: usize) -> Self {
+        let topics = Self::default();
+        {
+            let mut inner = topics.inner.lock().unwrap();
+            inner.sessions = Some(sessions);
+            inner.replay_capacity = replay_capacity;
+        }
+        topics
+    }
+
+    /// Adds the connection notified by `notifier`, returning the handle it subscribes with.
+    pub fn join(&self, notifier: Notifier<Resp>) -> TopicSubscriber<Resp> {
+        let mut inner = self.inner.lock().unwrap();
+        let connection_id = Self::add_connection(&mut inner, notifier, None);
+        TopicSubscriber {
+            topics: self.clone(),
+            connection_id,
+        }
+    }
+
+    /// Adds the connection notified by `notifier` in `session`, e.g. the
+    /// [session](crate::context::Context::session) of the requests it's handling, returning the
+    /// handle it subscribes with.
+    ///
+    /// If a previous connection joined in the session, the new connection takes over its
+    /// subscriptions, and the notifications it missed are pushed to the new connection first.
+    /// Joining again with the same connection changes nothing, so handlers can join whenever
+    /// they need the handle.
+    pub fn join_session(
+        &self,
+        session: &SessionToken,
+        notifier: Notifier<Resp>,
+    ) -> TopicSubscriber<Resp> {
+        let mut inner = self.inner.lock().unwrap();
+        let connection_id = match inner.session_connections.get(session) {
+            Some(&connection_id) => {
+                let connection = inner.connections.get_mut(&connection_id).unwrap();
+                if connection.notifier.is_none() {
+                    debug!(
+                        "Session {} resumed; replaying {} notifications.",
+                        session,
+                        connection.missed.len()
+                    );
+                }
+                connection.notifier = Some(notifier);
+                let missed = std::mem::take(&mut connection.missed);
+                for notification in missed {
+                    inner.notify(connection_id, notification);
+                }
+                connection_id
+            }
+            None => {
+                let connection_id =
+                    Self::add_connection(&mut inner, notifier, Some(session.clone()));
+                inner
+                    .session_connections
+                    .insert(session.clone(), connection_id);
+                connection_id
+            }
+        };
+        TopicSubscriber {
+            topics: self.clone(),
+            connection_id,
+        }
+    }
+
+    fn add_connection(
+        inner: &mut TopicsInner<Resp>,
+        notifier: Notifier<Resp>,
+        session: Option<SessionToken>,
+    ) -> u64 {
+        let connection_id = inner.next_connection_id;
+        inner.next_connection_id += 1;
+        inner.connections.insert(
+            connection_id,
+            Connection {
+                notifier: Some(notifier),
+                session,
+                missed: VecDeque::new(),
+            },
+        );
+        connection_id
+    }
+
+    /// Pushes `notification` to every connection subscribed to `topic`. Returns the number of
+    /// connections notified, which excludes the sessions the notification is buffered for.
+    pub fn publish(&self, topic: &str, notification: Resp) -> usize
+    where
+        Resp: Clone,
+    {
+        let mut inner = self.inner.lock().unwrap();
+        let subscribers: Vec<u64> = match inner.topics.get(topic) {
+            Some(subscribers) => subscribers.iter().cloned().collect(),
+            None => return 0,
+        };
+        let mut notified = 0;
+        for connection_id in subscribers {
+            if inner.notify(connection_id, notification.clone()) {
+                notified += 1;
+            }
+        }
+        notified
+    }
+
+    /// Returns the number of connections subscribed to `topic`, including any that have closed
+    /// but not yet been forgotten, and sessions waiting to be resumed.
+    pub fn subscribers(&self, topic: &str) -> usize {
+        self.inner
+            .lock()
+            .unwrap()
+            .topics
+            .get(topic)
+            .map(FnvHashSet::len)
+            .unwrap_or(0)
+    }
+}
+
+/// A connection's handle for subscribing to [`Topics`]. Clones act on behalf of the same
+/// connection.
+#[derive(Debug)]
+pub struct TopicSubscriber<Resp> {
+    topics: Topics<Resp>,
+    connection_id: u64,
+}
+
+impl<Resp> Clone for TopicSubscriber<Resp> {
+    fn clone(&self) -> Self {
+        TopicSubscriber {
+            topics: self.topics.clone(),
+            connection_id: self.connection_id,
+        }
+    }
+}
+
+impl<Resp> TopicSubscriber<Resp> {
+    /// Subscribes the connection to `topic`.
+    pub fn subscribe(&self, topic: impl Into<String>) {
+        let mut inner = self.topics.inner.lock().unwrap();
+        if inner.connections.contains_key(&self.connection_id) {
+            inner
+                .topics
+                .entry(topic.into())
+                .or_default()
+                .insert(self.connection_id);
+        }
+    }
+
+    /// Unsubscribes the connection from `topic`.
+    pub fn unsubscribe(&self, topic: &str) {
+        let mut inner = self.topics.inner.lock().unwrap();
+        let now_empty = match inner.topics.get_mut(topic) {
+            Some(subscribers) => {
+                subscribers.remove(&self.connection_id);
+                subscribers.is_empty()
+            }
+            None => false,
+        };
+        if now_empty {
+            inner.topics.remove(topic);
+        }
+    }
+
+    /// Unsubscribes the connection from every topic and removes it from the topics.
+    pub fn leave(&self) {
+        self.topics
+            .inner
+            .lock()
+            .unwrap()
+            .remove_connection(self.connection_id);
+    }
+}
+
+#[cfg(test)]
+use futures::channel::mpsc;
+
+#[cfg(test)]
+fn notifier() -> (Notifier<String>, mpsc::Receiver<String>) {
+    let (notifications, rx) = mpsc::channel(2);
+    let (close_requests, _) = mpsc::unbounded();
+    let notifier = Notifier {
+        notifications,
+        close_requests,
+    };
+    (notifier, rx)
+}
+
+#[test]
+fn publish_notifies_subscribers() {
+    let topics = Topics::new();
+    let (a, mut a_rx) = notifier();
+    let (b, mut b_rx) = notifier();
+    let a = topics.join(a);
+    let b = topics.join(b);
+    a.subscribe("news");
+    b.subscribe("news");
+    b.subscribe("weather");
+
+    assert_eq!(topics.publish("news", "extra".to_string()), 2);
+    assert_eq!(topics.publish("weather", "rain".to_string()), 1);
+    assert_eq!(topics.publish("sports", "goal".to_string()), 0);
+    assert_eq!(a_rx.try_recv().unwrap(), "extra");
+    assert!(a_rx.try_recv().is_err());
+    assert_eq!(b_rx.try_recv().unwrap(), "extra");
+    assert_eq!(b_rx.try_recv().unwrap(), "rain");
+
+    a.unsubscribe("news");
+    assert_eq!(topics.subscribers("news"), 1);
+    b.leave();
+    assert_eq!(topics.subscribers("news"), 0);
+    assert_eq!(topics.subscribers("weather"), 0);
+}
+
+#[test]
+fn publish_forgets_closed_connections() {
+    let topics = Topics::new();
+    let (notifier, rx) = notifier();
+    let subscriber = topics.join(notifier);
+    subscriber.subscribe("news");
+    subscriber.subscribe("weather");
+    drop(rx);
+
+    assert_eq!(topics.publish("news", "extra".to_string()), 0);
+    assert_eq!(topics.subscribers("news"), 0);
+    assert_eq!(topics.subscribers("weather"), 0);
+    // A connection that's gone can't resubscribe.
+    subscriber.subscribe("news");
+    assert_eq!(topics.subscribers("news"), 0);
+}
+
+#[test]
+fn sessions_keep_their_subscriptions_through_reconnects() {
+    let sessions = Sessions::new(std::time::Duration::from_secs(60));
+    let topics = Topics::with_sessions(sessions.clone(), 2);
+    let membership = sessions.join(&crate::SessionRequest::Start);
+    let session = membership.token().clone();
+
+    let (first, rx) = notifier();
+    let subscriber = topics.join_session(&session, first);
+    subscriber.subscribe("news");
+    drop(rx);
+    for news in &["1", "2", "3"] {
+        assert_eq!(topics.publish("news", news.to_string()), 0);
+    }
+    assert_eq!(topics.subscribers("news"), 1);
+
+    // The resumed session gets the news it missed, up to the replay capacity.
+    let (second, mut rx) = notifier();
+    topics.join_session(&session, second);
+    assert_eq!(rx.try_recv().unwrap(), "2");
+    assert_eq!(rx.try_recv().unwrap(), "3");
+    assert_eq!(topics.publish("news", "4".to_string()), 1);
+    assert_eq!(rx.try_recv().unwrap(), "4");
+
+    // Once the session can't be resumed, its subscriptions are forgotten.
+    let expired = Topics::with_sessions(Sessions::new(std::time::Duration::from_secs(0)), 2);
+    let (third, rx) = notifier();
+    expired.join_session(&session, third).subscribe("news");
+    drop(rx);
+    assert_eq!(expired.publish("news", "5".to_string()), 0);
+    assert_eq!(expired.subscribers("news"), 0);
+}
+
+#[test]
+fn publish_closes_connections_that_fall_behind() {
+    let topics = Topics::new();
+    let (notifier, rx) = notifier();
+    let (close_requests, mut closed) = mpsc::unbounded();
+    let notifier = Notifier {
+        close_requests,
+        ..notifier
+    };
+    topics.join(notifier).subscribe("news");
+
+    // The buffer holds two notifications, plus one for the notifier.
+    for news in &["1", "2", "3"] {
+        assert_eq!(topics.publish("news", news.to_string()), 1);
+    }
+    assert_eq!(topics.publish("news", "4".to_string()), 0);
+    assert!(closed.try_recv().is_ok());
+    assert_eq!(topics.subscribers("news"), 0);
+    drop(rx);
+}
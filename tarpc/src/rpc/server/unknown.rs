@@ -0,0 +1,307 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use crate::{ClientMessage, ErrorFrame, Request, ServerMessage};
+use futures::{prelude::*, ready, task::*};
+use log::{debug, trace};
+use pin_project::pin_project;
+use serde::{
+    de::{self, IgnoredAny, MapAccess, Visitor},
+    Deserialize, Deserializer,
+};
+use std::{fmt, io, marker::PhantomData, pin::Pin};
+
+/// A request message of type `T`, or of a variant that `T` doesn't have, such as a method added
+/// to the service by a newer client.
+///
+/// Decoding a message that isn't a `T` yields the name of its variant rather than failing.
+/// Telling the two apart requires buffering the message, so only self-describing formats, like
+/// JSON or MessagePack, are supported; decoding fails with formats like bincode.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Lenient<T> {
+    /// A message the server knows.
+    Known(T),
+    /// A message of a variant the server doesn't know, or whose fields it can't decode.
+    Unknown {
+        /// The name of the variant, e.g. `Hello` for a request to `hello`.
+        variant: String,
+    },
+}
+
+impl<'de, T> Deserialize<'de> for Lenient<T>
+where
+    T: Deserialize<'de>,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr<T> {
+            Known(T),
+            Unknown(UnknownVariant),
+        }
+
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::Known(message) => Lenient::Known(message),
+            Repr::Unknown(UnknownVariant(variant)) => Lenient::Unknown { variant },
+        })
+    }
+}
+
+/// The name of an externally tagged enum variant, as serde encodes enums by default: either the
+/// single key of a map, or a string for unit variants.
+struct UnknownVariant(String);
+
+impl<'de> Deserialize<'de> for UnknownVariant {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct VariantVisitor;
+
+        impl<'de> Visitor<'de> for VariantVisitor {
+            type Value = UnknownVariant;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an enum variant")
+            }
+
+            fn visit_str<E: de::Error>(self, variant: &str) -> Result<UnknownVariant, E> {
+                Ok(UnknownVariant(variant.into()))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<UnknownVariant, A::Error> {
+                let variant = map
+                    .next_key::<String>()?
+                    .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+                map.next_value::<IgnoredAny>()?;
+                if map.next_key::<IgnoredAny>()?.is_some() {
+                    return Err(de::Error::invalid_length(2, &self));
+                }
+                Ok(UnknownVariant(variant))
+            }
+        }
+
+        deserializer.deserialize_any(VariantVisitor)
+    }
+}
+
+/// A server transport that answers requests the server doesn't know with an
+/// [unknown method](ErrorFrame::unknown_method) error, rather than failing to decode them and
+/// closing the connection, so that clients newer than the server can keep using its other
+/// methods during rolling upgrades.
+///
+/// The inner transport decodes requests as [`Lenient`] messages:
+///
+/// ```
+/// # use tarpc::{server::{BaseChannel, Lenient, UnknownMethods}, transport, ClientMessage, ServerMessage};
+/// # fn run() {
+/// let (_client, transport) = transport::channel::unbounded::<
+///     ServerMessage<String>,
+///     ClientMessage<Lenient<String>>,
+/// >();
+/// let channel = BaseChannel::with_defaults(UnknownMethods::new(transport));
+/// # let _: &BaseChannel<String, String, _> = &channel;
+/// # }
+/// ```
+///
+/// Items of streaming requests that can't be decoded are dropped.
+#[pin_project]
+#[derive(Debug)]
+pub struct UnknownMethods<T, Resp> {
+    #[pin]
+    inner: T,
+    ghost: PhantomData<fn(Resp)>,
+}
+
+impl<T, Resp> UnknownMethods<T, Resp> {
+    /// Returns a transport that answers unknown requests read from `inner`.
+    pub fn new(inner: T) -> Self {
+        UnknownMethods {
+            inner,
+            ghost: PhantomData,
+        }
+    }
+
+    /// Returns the inner transport.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T, Req, Resp> Stream for UnknownMethods<T, Resp>
+where
+    T: Stream<Item = io::Result<ClientMessage<Lenient<Req>>>>
+        + Sink<ServerMessage<Resp>, Error = io::Error>,
+{
+    type Item = io::Result<ClientMessage<Req>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        loop {
+            // Ensure a rejection can be written before reading a request that might need one.
+            ready!(self.as_mut().project().inner.poll_ready(cx)?);
+
+            let message = match ready!(self.as_mut().project().inner.poll_next(cx)?) {
+                Some(message) => message,
+                None => return Poll::Ready(None),
+            };
+            let (request, streaming) = match message {
+                ClientMessage::Request(request) => (request, false),
+                ClientMessage::StreamingRequest(request) => (request, true),
+                ClientMessage::StreamItem {
+                    request_id,
+                    item: Lenient::Known(item),
+                } => return Poll::Ready(Some(Ok(ClientMessage::StreamItem { request_id, item }))),
+                ClientMessage::StreamItem {
+                    request_id,
+                    item: Lenient::Unknown { variant },
+                } => {
+                    trace!(
+                        "Dropping an item of unknown variant {} for request {}.",
+                        variant,
+                        request_id
+                    );
+                    continue;
+                }
+                ClientMessage::StreamEnd { request_id } => {
+                    return Poll::Ready(Some(Ok(ClientMessage::StreamEnd { request_id })))
+                }
+                ClientMessage::WindowUpdate {
+                    request_id,
+                    credits,
+                } => {
+                    return Poll::Ready(Some(Ok(ClientMessage::WindowUpdate {
+                        request_id,
+                        credits,
+                    })))
+                }
+                ClientMessage::Cancel {
+                    trace_context,
+                    request_id,
+                } => {
+                    return Poll::Ready(Some(Ok(ClientMessage::Cancel {
+                        trace_context,
+                        request_id,
+                    })))
+                }
+                ClientMessage::HealthCheck { check_id } => {
+                    return Poll::Ready(Some(Ok(ClientMessage::HealthCheck { check_id })))
+                }
+                ClientMessage::Handshake {
+                    reply_order,
+                    session,
+                } => {
+                    return Poll::Ready(Some(Ok(ClientMessage::Handshake {
+                        reply_order,
+                        session,
+                    })))
+                }
+                ClientMessage::_NonExhaustive => unreachable!(),
+            };
+            let variant = match request.message {
+                Lenient::Known(message) => {
+                    let request = Request {
+                        context: request.context,
+                        id: request.id,
+                        method: request.method,
+                        message,
+                    };
+                    return Poll::Ready(Some(Ok(if streaming {
+                        ClientMessage::StreamingRequest(request)
+                    } else {
+                        ClientMessage::Request(request)
+                    })));
+                }
+                Lenient::Unknown { variant } => variant,
+            };
+            debug!(
+                "[{}] Rejecting request {} of unknown variant {}.",
+                request.context.trace_id(),
+                request.id,
+                variant,
+            );
+            self.as_mut()
+                .start_send(ServerMessage::Error(ErrorFrame::unknown_method(
+                    request.id, &variant,
+                )))?;
+        }
+    }
+}
+
+impl<T, Resp> Sink<ServerMessage<Resp>> for UnknownMethods<T, Resp>
+where
+    T: Sink<ServerMessage<Resp>, Error = io::Error>,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: ServerMessage<Resp>) -> io::Result<()> {
+        self.project().inner.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+#[test]
+fn unknown_requests_are_answered() {
+    use super::testing::{cx, FakeChannel};
+    use pin_utils::pin_mut;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    enum Method {
+        Read { key: String },
+    }
+
+    let decode =
+        |json: &str| -> ClientMessage<Lenient<Method>> { serde_json::from_str(json).unwrap() };
+    let context = r#""context":{"trace_context":{"trace_id":1,"span_id":2,"parent_id":null}}"#;
+    let mut transport = FakeChannel::<_, ServerMessage<String>> {
+        stream: Default::default(),
+        sink: Default::default(),
+        config: Default::default(),
+        in_flight_requests: Default::default(),
+    };
+    for json in &[
+        format!(
+            r#"{{"Request":{{{},"id":0,"message":{{"Write":{{"key":"k"}}}}}}}}"#,
+            context
+        ),
+        format!(
+            r#"{{"Request":{{{},"id":1,"message":{{"Read":{{"key":"k"}}}}}}}}"#,
+            context
+        ),
+    ] {
+        transport.stream.push_back(Ok(decode(json)));
+    }
+    let transport = UnknownMethods::new(transport);
+    pin_mut!(transport);
+
+    assert_matches::assert_matches!(
+        transport.as_mut().poll_next(&mut cx()),
+        Poll::Ready(Some(Ok(ClientMessage::Request(Request {
+            id: 1,
+            message: Method::Read { .. },
+            ..
+        }))))
+    );
+    let errors = transport.get_ref().errors();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].id, Some(0));
+    assert_eq!(errors[0].code, crate::ErrorCode::Unimplemented);
+    assert_eq!(errors[0].message, "Unknown method `Write`.");
+    assert_eq!(errors[0].details["method"], "Write");
+}
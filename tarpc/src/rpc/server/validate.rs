@@ -0,0 +1,226 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use super::{Channel, Config, ReplyWindow, RequestItems};
+use crate::{Request, Response, ServerError, ServerMessage};
+use futures::{future::AbortRegistration, prelude::*, ready, task::*};
+use log::debug;
+use pin_project::pin_project;
+use std::{io, pin::Pin};
+
+/// Checks requests before they're served, so that handlers needn't check their arguments.
+pub trait Validator<Req> {
+    /// Returns `Ok` if `request` may be served, or else a description of what's wrong with it,
+    /// which is sent to the client.
+    fn validate(&self, request: &Req) -> Result<(), String>;
+}
+
+impl<Req, F> Validator<Req> for F
+where
+    F: Fn(&Req) -> Result<(), String>,
+{
+    fn validate(&self, request: &Req) -> Result<(), String> {
+        self(request)
+    }
+}
+
+/// Validates requests with the validators declared on their methods, as in
+/// `#[tarpc(validate = "path::to::fn")]`. A method's validator takes references to the method's
+/// args, and returns a `Result<(), String>`; methods without validators accept every request.
+///
+/// Implemented for the request types generated by [`service`](crate::service).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Declared;
+
+/// A [`Channel`] that rejects requests its [`Validator`] finds invalid. Rejected requests receive
+/// an [invalid request](ServerError::invalid_request) error and are never served.
+#[pin_project]
+#[derive(Debug)]
+pub struct ValidatingChannel<C, V> {
+    #[pin]
+    inner: C,
+    validator: V,
+}
+
+impl<C, V> ValidatingChannel<C, V> {
+    /// Returns a new `ValidatingChannel` that wraps the given channel and checks requests with
+    /// `validator`.
+    pub fn new(inner: C, validator: V) -> Self {
+        ValidatingChannel { inner, validator }
+    }
+
+    /// Returns the inner channel.
+    pub fn get_ref(&self) -> &C {
+        &self.inner
+    }
+}
+
+impl<C, V> Stream for ValidatingChannel<C, V>
+where
+    C: Channel,
+    V: Validator<C::Req>,
+{
+    type Item = io::Result<Request<C::Req>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        loop {
+            // Ensure a rejection can be written before reading a request that might need one.
+            ready!(self.as_mut().project().inner.poll_ready(cx)?);
+
+            let request = match ready!(self.as_mut().project().inner.poll_next(cx)?) {
+                Some(request) => request,
+                None => return Poll::Ready(None),
+            };
+            let error = match self.validator.validate(&request.message) {
+                Ok(()) => return Poll::Ready(Some(Ok(request))),
+                Err(detail) => ServerError::invalid_request(detail),
+            };
+            debug!(
+                "[{}] Rejecting request {}: {}",
+                request.context.trace_id(),
+                request.id,
+                error,
+            );
+            self.as_mut().start_send(ServerMessage::Response(Response {
+                request_id: request.id,
+                message: Err(error),
+            }))?;
+        }
+    }
+}
+
+impl<C, V> Sink<ServerMessage<C::Resp>> for ValidatingChannel<C, V>
+where
+    C: Channel,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: ServerMessage<C::Resp>) -> io::Result<()> {
+        self.project().inner.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+impl<C, V> AsRef<C> for ValidatingChannel<C, V> {
+    fn as_ref(&self) -> &C {
+        &self.inner
+    }
+}
+
+impl<C, V> Channel for ValidatingChannel<C, V>
+where
+    C: Channel,
+    V: Validator<C::Req>,
+{
+    type Req = C::Req;
+    type Resp = C::Resp;
+
+    fn config(&self) -> &Config {
+        self.inner.config()
+    }
+
+    fn in_flight_requests(self: Pin<&mut Self>) -> usize {
+        self.project().inner.in_flight_requests()
+    }
+
+    fn start_request(self: Pin<&mut Self>, request_id: u64) -> AbortRegistration {
+        self.project().inner.start_request(request_id)
+    }
+
+    fn take_request_items(self: Pin<&mut Self>, request_id: u64) -> RequestItems<Self::Req> {
+        self.project().inner.take_request_items(request_id)
+    }
+
+    fn start_reply_window(self: Pin<&mut Self>, request_id: u64) -> ReplyWindow {
+        self.project().inner.start_reply_window(request_id)
+    }
+}
+
+/// A stream of channels that check requests with a shared [`Validator`].
+#[pin_project]
+#[derive(Debug)]
+pub struct ValidatingStream<S, V> {
+    #[pin]
+    inner: S,
+    validator: V,
+}
+
+impl<S, V> ValidatingStream<S, V>
+where
+    S: Stream,
+    S::Item: Channel,
+{
+    pub(crate) fn new(inner: S, validator: V) -> Self {
+        ValidatingStream { inner, validator }
+    }
+}
+
+impl<S, V> Stream for ValidatingStream<S, V>
+where
+    S: Stream,
+    S::Item: Channel,
+    V: Validator<<S::Item as Channel>::Req> + Clone,
+{
+    type Item = ValidatingChannel<S::Item, V>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        match ready!(self.as_mut().project().inner.poll_next(cx)) {
+            Some(channel) => Poll::Ready(Some(ValidatingChannel::new(
+                channel,
+                self.validator.clone(),
+            ))),
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+#[test]
+fn invalid_requests_are_rejected() {
+    use super::testing::{self, FakeChannel, PollExt};
+    use pin_utils::pin_mut;
+
+    let mut inner = FakeChannel::default::<i32, ()>();
+    inner.push_req(0, 1);
+    inner.push_req(1, -1);
+    inner.push_req(2, 2);
+    let positive = |x: &i32| {
+        if *x > 0 {
+            Ok(())
+        } else {
+            Err(format!("{} isn't positive.", x))
+        }
+    };
+    let channel = ValidatingChannel::new(inner, positive);
+    pin_mut!(channel);
+    for expected in [0, 2] {
+        assert_eq!(
+            channel
+                .as_mut()
+                .poll_next(&mut testing::cx())
+                .map(|r| r.map(|r| r.unwrap().id)),
+            Poll::Ready(Some(expected))
+        );
+    }
+    assert!(channel.as_mut().poll_next(&mut testing::cx()).is_done());
+
+    let responses = channel.inner.responses();
+    assert_eq!(responses.len(), 1);
+    assert_eq!(responses[0].request_id, 1);
+    let error = responses[0].message.as_ref().unwrap_err();
+    assert_eq!(error.kind, io::ErrorKind::InvalidInput);
+    assert_eq!(error.detail.as_deref(), Some("-1 isn't positive."));
+}
@@ -0,0 +1,350 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Deterministic simulation of clients and servers connected by a faulty in-memory network, for
+//! reproducing races in the multiplexing logic.
+//!
+//! [`run`] runs a scenario once per seed, each time on a fresh single-threaded runtime whose clock
+//! is paused, so that timers fire as soon as nothing else can run, and a run takes as long as its
+//! computation rather than its timeouts. Scenarios connect clients and servers with
+//! [`Network::channel`], which drops, delays, and reorders messages as its [`Faults`] dictate,
+//! drawing from a random number generator seeded with the run's seed. A seed replays the same
+//! interleaving every time it's run, so the seed that [`run`] reports on failure reproduces the
+//! failure:
+//!
+//! ```
+//! # use futures::prelude::*;
+//! # use std::time::Duration;
+//! # use tarpc::{client, context, server::{BaseChannel, Channel}, sim::{self, Faults}};
+//! sim::run(0..100, |network| async move {
+//!     let mut faults = Faults::default();
+//!     faults.max_delay = Duration::from_millis(10);
+//!     faults.reorder = true;
+//!     let (client_transport, server_transport) = network.channel(faults);
+//!     tokio::spawn(
+//!         BaseChannel::new(network.server_config(), server_transport)
+//!             .respond_with(|_, x: u64| future::ready(x * 2))
+//!             .execute(),
+//!     );
+//!     let client = client::new(network.client_config(), client_transport).spawn().unwrap();
+//!
+//!     let calls = (0..10).map(|x| {
+//!         let mut client = client.clone();
+//!         async move { assert_eq!(client.call(context::current(), x).await.unwrap(), x * 2) }
+//!     });
+//!     future::join_all(calls).await;
+//! });
+//! ```
+
+use crate::{client, clock::VirtualClock, server};
+use futures::{channel::mpsc, prelude::*, task::*};
+use log::trace;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    any::Any,
+    io,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    runtime,
+    time::{self, Instant},
+};
+
+/// Runs `scenario` once for each of `seeds`, on a fresh single-threaded runtime with a paused
+/// clock, passing it a [`Network`] seeded with the seed.
+///
+/// # Panics
+///
+/// If the scenario panics, panics with a message naming the seed it panicked with. Rerun just
+/// that seed to reproduce the failure.
+pub fn run<F, Fut>(seeds: impl IntoIterator<Item = u64>, mut scenario: F)
+where
+    F: FnMut(Network) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    for seed in seeds {
+        trace!("Simulating with seed {}.", seed);
+        let mut runtime = runtime::Builder::new()
+            .basic_scheduler()
+            .enable_time()
+            .build()
+            .expect("Couldn't build the simulation's runtime.");
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            runtime.block_on(async {
+                time::pause();
+                scenario(Network::new(seed)).await
+            })
+        }));
+        if let Err(panic) = result {
+            panic!(
+                "Simulation failed with seed {}: {}",
+                seed,
+                panic_message(&*panic)
+            );
+        }
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "<non-string panic>"
+    }
+}
+
+/// The faults a [`Network::channel`] injects into the messages sent over it.
+#[derive(Clone, Copy, Debug)]
+#[non_exhaustive]
+pub struct Faults {
+    /// The probability, from 0 to 1, that a message is dropped.
+    pub drop_rate: f64,
+    /// The longest a message is delayed. Each message is delayed by a random duration up to this.
+    pub max_delay: Duration,
+    /// Whether messages may be delivered out of the order they were sent in. If false, a message
+    /// isn't delivered before the messages sent before it.
+    pub reorder: bool,
+}
+
+impl Default for Faults {
+    fn default() -> Self {
+        Faults {
+            drop_rate: 0.0,
+            max_delay: Duration::from_secs(0),
+            reorder: false,
+        }
+    }
+}
+
+/// The source of the faults of a simulation's channels, and of the clock its clients and servers
+/// measure deadlines against. Clones share the random number generator.
+#[derive(Clone, Debug)]
+pub struct Network {
+    seed: u64,
+    rng: Arc<Mutex<StdRng>>,
+    clock: Arc<VirtualClock>,
+}
+
+impl Network {
+    /// Returns a network whose faults are drawn from a random number generator seeded with
+    /// `seed`. [`run`] creates one for each seed; create one directly to simulate within a test's
+    /// own runtime, after pausing its clock.
+    pub fn new(seed: u64) -> Self {
+        Network {
+            seed,
+            rng: Arc::new(Mutex::new(StdRng::seed_from_u64(seed))),
+            clock: Arc::new(VirtualClock::new()),
+        }
+    }
+
+    /// Returns the seed of the network's random number generator.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Returns the clock that advances with the simulation.
+    pub fn clock(&self) -> Arc<VirtualClock> {
+        self.clock.clone()
+    }
+
+    /// Returns the default client config, measuring deadlines against the network's clock.
+    pub fn client_config(&self) -> client::Config {
+        client::Config {
+            clock: self.clock.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the default server config, measuring deadlines against the network's clock.
+    pub fn server_config(&self) -> server::Config {
+        server::Config {
+            clock: self.clock.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Returns a random duration up to `max`, for a scenario to wait before its next step, so
+    /// that seeds vary the interleaving of its steps.
+    pub fn jitter(&self, max: Duration) -> Duration {
+        random_delay(&mut self.rng.lock().unwrap(), max)
+    }
+
+    /// Returns two channel peers. Each [`Stream`] yields items sent through the other's
+    /// [`Sink`], subject to `faults`.
+    ///
+    /// Must be called within the simulation's runtime.
+    pub fn channel<SinkItem, Item>(
+        &self,
+        faults: Faults,
+    ) -> (SimChannel<SinkItem, Item>, SimChannel<Item, SinkItem>)
+    where
+        SinkItem: Send + 'static,
+        Item: Send + 'static,
+    {
+        let (tx1, rx2) = mpsc::unbounded();
+        let (tx2, rx1) = mpsc::unbounded();
+        (
+            SimChannel::new(rx1, tx1, faults, self.rng.clone()),
+            SimChannel::new(rx2, tx2, faults, self.rng.clone()),
+        )
+    }
+}
+
+fn random_delay(rng: &mut StdRng, max: Duration) -> Duration {
+    Duration::from_nanos(rng.gen_range(0, max.as_nanos() as u64 + 1))
+}
+
+/// A bi-directional in-memory channel that injects [`Faults`] into the messages it sends.
+#[derive(Debug)]
+pub struct SimChannel<Item, SinkItem> {
+    rx: mpsc::UnboundedReceiver<Item>,
+    tx: mpsc::UnboundedSender<SinkItem>,
+    /// Delivers messages in order, each no sooner than the instant it's queued with.
+    link: mpsc::UnboundedSender<(Instant, SinkItem)>,
+    last_delivery: Instant,
+    faults: Faults,
+    rng: Arc<Mutex<StdRng>>,
+}
+
+impl<Item, SinkItem> SimChannel<Item, SinkItem>
+where
+    SinkItem: Send + 'static,
+{
+    fn new(
+        rx: mpsc::UnboundedReceiver<Item>,
+        tx: mpsc::UnboundedSender<SinkItem>,
+        faults: Faults,
+        rng: Arc<Mutex<StdRng>>,
+    ) -> Self {
+        let (link, mut queued) = mpsc::unbounded::<(Instant, SinkItem)>();
+        let delivery = tx.clone();
+        tokio::spawn(async move {
+            while let Some((deliver_at, item)) = queued.next().await {
+                time::delay_until(deliver_at).await;
+                if delivery.unbounded_send(item).is_err() {
+                    break;
+                }
+            }
+        });
+        SimChannel {
+            rx,
+            tx,
+            link,
+            last_delivery: Instant::now(),
+            faults,
+            rng,
+        }
+    }
+}
+
+impl<Item, SinkItem> Stream for SimChannel<Item, SinkItem> {
+    type Item = io::Result<Item>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<Item>>> {
+        self.rx.poll_next_unpin(cx).map(|option| option.map(Ok))
+    }
+}
+
+impl<Item, SinkItem> Sink<SinkItem> for SimChannel<Item, SinkItem>
+where
+    SinkItem: Send + 'static,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(if self.tx.is_closed() {
+            Err(io::Error::from(io::ErrorKind::NotConnected))
+        } else {
+            Ok(())
+        })
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: SinkItem) -> io::Result<()> {
+        if self.tx.is_closed() {
+            return Err(io::Error::from(io::ErrorKind::NotConnected));
+        }
+        let delay = {
+            let mut rng = self.rng.lock().unwrap();
+            if rng.gen_bool(self.faults.drop_rate) {
+                trace!("Dropping a message.");
+                return Ok(());
+            }
+            random_delay(&mut rng, self.faults.max_delay)
+        };
+        let deliver_at = Instant::now() + delay;
+        if self.faults.reorder {
+            let tx = self.tx.clone();
+            tokio::spawn(async move {
+                time::delay_until(deliver_at).await;
+                let _ = tx.unbounded_send(item);
+            });
+        } else {
+            let deliver_at = deliver_at.max(self.last_delivery);
+            self.last_delivery = deliver_at;
+            let _ = self.link.unbounded_send((deliver_at, item));
+        }
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Messages already sent are still delivered; the peer's stream ends after them.
+        self.link.close_channel();
+        self.tx.disconnect();
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[test]
+fn seeds_replay_the_same_faults() {
+    fn deliveries(seed: u64, reorder: bool) -> Vec<u32> {
+        let delivered = Arc::new(Mutex::new(vec![]));
+        run(seed..seed + 1, |network| {
+            let delivered = delivered.clone();
+            async move {
+                let faults = Faults {
+                    drop_rate: 0.25,
+                    max_delay: Duration::from_millis(10),
+                    reorder,
+                };
+                let (mut tx, rx) = network.channel::<u32, u32>(faults);
+                for i in 0..20 {
+                    tx.send(i).await.unwrap();
+                    time::delay_for(Duration::from_millis(1)).await;
+                }
+                tx.close().await.unwrap();
+                *delivered.lock().unwrap() = rx.map(Result::unwrap).collect().await;
+            }
+        });
+        let delivered = delivered.lock().unwrap();
+        delivered.clone()
+    }
+
+    let reordered = deliveries(7, true);
+    assert_eq!(reordered, deliveries(7, true));
+    assert!(reordered.len() < 20, "{:?}", reordered);
+    assert!(reordered.windows(2).any(|w| w[0] > w[1]), "{:?}", reordered);
+
+    let ordered = deliveries(7, false);
+    assert!(ordered.len() < 20, "{:?}", ordered);
+    assert!(ordered.windows(2).all(|w| w[0] < w[1]), "{:?}", ordered);
+}
+
+#[test]
+#[should_panic(expected = "Simulation failed with seed 3: unlucky")]
+fn run_reports_the_failing_seed() {
+    run(0..10, |network| async move {
+        assert!(network.seed() != 3, "unlucky");
+    });
+}
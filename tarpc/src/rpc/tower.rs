@@ -0,0 +1,121 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Adapters between tarpc and [`tower_service::Service`], so that middleware written as tower
+//! layers, e.g. timeouts, rate limits, and retries, can wrap tarpc clients and servers.
+//!
+//! A [`client::Channel`] is a service of its requests, and [`ServeService`] serves any service
+//! of requests through a tarpc server.
+
+use crate::{client, context, server::Serve};
+use futures::{future::BoxFuture, prelude::*, task::*};
+use std::io;
+use tower_service::Service;
+
+/// Sends each request with a fresh [`context::current`], so its deadline is the default. Layers
+/// that need their own deadlines, like timeouts, should be shorter than it.
+impl<Req, Resp> Service<Req> for client::Channel<Req, Resp>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+{
+    type Response = Resp;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, io::Result<Resp>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        client::Channel::poll_ready(self, cx)
+    }
+
+    fn call(&mut self, request: Req) -> Self::Future {
+        let mut channel = self.clone();
+        async move { channel.call(context::current(), request).await }.boxed()
+    }
+}
+
+/// Serves requests with a [`Service`], replying with its result. The request's context isn't
+/// passed to the service, but the server still enforces the request's deadline.
+///
+/// Each request is served by a clone of the service, which is polled until it's ready before
+/// being called.
+#[derive(Clone, Debug)]
+pub struct ServeService<S> {
+    service: S,
+}
+
+impl<S> ServeService<S> {
+    /// Returns a [`Serve`] that serves requests with `service`.
+    pub fn new(service: S) -> Self {
+        ServeService { service }
+    }
+
+    /// Returns the service.
+    pub fn into_inner(self) -> S {
+        self.service
+    }
+}
+
+impl<Req, S> Serve<Req> for ServeService<S>
+where
+    S: Service<Req> + Clone + Send + 'static,
+    S::Future: Send,
+    Req: Send + 'static,
+{
+    type Resp = Result<S::Response, S::Error>;
+    type Fut = BoxFuture<'static, Self::Resp>;
+
+    fn serve(self, _: context::Context, request: Req) -> Self::Fut {
+        let mut service = self.service;
+        async move {
+            future::poll_fn(|cx| service.poll_ready(cx)).await?;
+            service.call(request).await
+        }
+        .boxed()
+    }
+}
+
+#[cfg(feature = "tokio1")]
+#[tokio::test(threaded_scheduler)]
+async fn services_compose_with_tarpc() -> io::Result<()> {
+    use crate::{
+        server::{BaseChannel, Channel},
+        transport,
+    };
+
+    /// Doubles its requests, failing on zero.
+    #[derive(Clone)]
+    struct Double;
+
+    impl Service<u32> for Double {
+        type Response = u32;
+        type Error = String;
+        type Future = future::Ready<Result<u32, String>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), String>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: u32) -> Self::Future {
+            future::ready(match request {
+                0 => Err("zero".into()),
+                x => Ok(x * 2),
+            })
+        }
+    }
+
+    let (tx, rx) = transport::channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .respond_with(ServeService::new(Double))
+            .execute(),
+    );
+    let mut client = client::new(client::Config::default(), tx).spawn()?;
+
+    future::poll_fn(|cx| Service::poll_ready(&mut client, cx)).await?;
+    assert_eq!(Service::call(&mut client, 2).await?, Ok(4));
+    assert_eq!(Service::call(&mut client, 0).await?, Err("zero".into()));
+    Ok(())
+}
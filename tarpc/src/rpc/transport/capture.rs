@@ -0,0 +1,246 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Mirrors the messages sent and received over a transport to a writer, such as a file or
+//! stderr, as timestamped, pretty-printed JSON, for debugging.
+//!
+//! A [`Capture`] is shared by the transports it [wraps](Capture::transport), and captures nothing
+//! until it's [enabled](Capture::enable), so a server can wrap every connection and switch capture
+//! on and off at runtime:
+//!
+//! ```
+//! # use futures::prelude::*;
+//! # use tarpc::transport::{capture::Capture, channel};
+//! # use std::io;
+//! # #[tokio::main]
+//! # async fn main() -> io::Result<()> {
+//! let capture = Capture::stderr();
+//! let (client_transport, _server_transport) = channel::unbounded::<String, String>();
+//! let mut transport = capture.transport(client_transport);
+//!
+//! capture.enable();
+//! // Prints the message to stderr, marked outbound.
+//! transport.send("Hello".into()).await?;
+//! capture.disable();
+//! # Ok(())
+//! # }
+//! ```
+
+use futures::{prelude::*, ready, task::*};
+use humantime::format_rfc3339_millis;
+use log::warn;
+use pin_project::pin_project;
+use serde::Serialize;
+use std::{
+    fmt,
+    io::{self, Write},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+
+/// Which way a captured message was going.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Direction {
+    Inbound,
+    Outbound,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Direction::Inbound => write!(f, "<- inbound"),
+            Direction::Outbound => write!(f, "-> outbound"),
+        }
+    }
+}
+
+/// A switchable mirror of the messages of the transports it wraps. Clones share the switch and
+/// the writer.
+#[derive(Clone)]
+pub struct Capture {
+    enabled: Arc<AtomicBool>,
+    writer: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl fmt::Debug for Capture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Capture")
+            .field("enabled", &self.is_enabled())
+            .finish()
+    }
+}
+
+impl Capture {
+    /// Returns a capture that writes to `writer` once enabled.
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        Capture {
+            enabled: Arc::new(AtomicBool::new(false)),
+            writer: Arc::new(Mutex::new(Box::new(writer))),
+        }
+    }
+
+    /// Returns a capture that writes to stderr once enabled.
+    pub fn stderr() -> Self {
+        Self::new(io::stderr())
+    }
+
+    /// Starts capturing messages.
+    pub fn enable(&self) {
+        self.enabled.store(true, Ordering::SeqCst);
+    }
+
+    /// Stops capturing messages.
+    pub fn disable(&self) {
+        self.enabled.store(false, Ordering::SeqCst);
+    }
+
+    /// Returns true if messages are being captured.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::SeqCst)
+    }
+
+    /// Returns `transport`, with its messages captured while the capture is enabled.
+    pub fn transport<T>(&self, transport: T) -> Captured<T> {
+        Captured {
+            inner: transport,
+            capture: self.clone(),
+        }
+    }
+
+    fn record(&self, direction: Direction, message: &impl Serialize) {
+        if !self.is_enabled() {
+            return;
+        }
+        // A message that can't be captured shouldn't break the connection it's sent over.
+        if let Err(e) = self.write(direction, message) {
+            warn!("Couldn't capture {} message: {}", direction, e);
+        }
+    }
+
+    fn write(&self, direction: Direction, message: &impl Serialize) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(message)?;
+        let mut writer = self.writer.lock().unwrap();
+        writeln!(
+            writer,
+            "{} {}\n{}",
+            format_rfc3339_millis(SystemTime::now()),
+            direction,
+            json
+        )?;
+        writer.flush()
+    }
+}
+
+/// A transport whose messages are mirrored by a [`Capture`].
+#[pin_project]
+#[derive(Debug)]
+pub struct Captured<T> {
+    #[pin]
+    inner: T,
+    capture: Capture,
+}
+
+impl<T> Captured<T> {
+    /// Returns the inner transport.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns the capture mirroring the transport's messages.
+    pub fn capture(&self) -> &Capture {
+        &self.capture
+    }
+}
+
+impl<T, Item> Stream for Captured<T>
+where
+    T: Stream<Item = io::Result<Item>>,
+    Item: Serialize,
+{
+    type Item = io::Result<Item>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<io::Result<Item>>> {
+        let this = self.project();
+        let message = ready!(this.inner.poll_next(cx));
+        if let Some(Ok(ref message)) = message {
+            this.capture.record(Direction::Inbound, message);
+        }
+        Poll::Ready(message)
+    }
+}
+
+impl<T, SinkItem> Sink<SinkItem> for Captured<T>
+where
+    T: Sink<SinkItem>,
+    SinkItem: Serialize,
+{
+    type Error = T::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), T::Error>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, message: SinkItem) -> Result<(), T::Error> {
+        let this = self.project();
+        this.capture.record(Direction::Outbound, &message);
+        this.inner.start_send(message)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), T::Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), T::Error>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+#[tokio::test]
+async fn captures_messages_while_enabled() -> io::Result<()> {
+    use crate::transport::channel;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Write::write(&mut *self.0.lock().unwrap(), buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let buffer = Buffer::default();
+    let capture = Capture::new(buffer.clone());
+    let (client, server) = channel::unbounded::<Vec<u32>, Vec<u32>>();
+    let mut client = capture.transport(client);
+    let mut server = capture.transport(server);
+
+    client.send(vec![0]).await?;
+    server.next().await.unwrap()?;
+    assert!(buffer.0.lock().unwrap().is_empty());
+
+    capture.enable();
+    client.send(vec![1, 2]).await?;
+    server.next().await.unwrap()?;
+    capture.disable();
+    client.send(vec![3]).await?;
+
+    let captured = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<_> = captured.lines().collect();
+    assert_eq!(lines.len(), 10, "{}", captured);
+    assert!(lines[0].ends_with(" -> outbound"), "{}", lines[0]);
+    assert_eq!(lines[1..5], ["[", "  1,", "  2", "]"]);
+    assert!(lines[5].ends_with(" <- inbound"), "{}", lines[5]);
+    assert_eq!(lines[6..], ["[", "  1,", "  2", "]"]);
+    Ok(())
+}
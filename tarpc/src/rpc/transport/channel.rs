@@ -77,6 +77,68 @@ impl<Item, SinkItem> Sink<SinkItem> for UnboundedChannel<Item, SinkItem> {
     }
 }
 
+/// Returns two channel peers that each buffer up to `capacity` items sent by the other, plus one
+/// more, so that a slow peer holds back the peer sending to it rather than letting the items pile
+/// up in memory. Each [`Stream`] yields items sent through the other's [`Sink`].
+pub fn bounded<SinkItem, Item>(
+    capacity: usize,
+) -> (Channel<SinkItem, Item>, Channel<Item, SinkItem>) {
+    let (tx1, rx2) = mpsc::channel(capacity);
+    let (tx2, rx1) = mpsc::channel(capacity);
+    (Channel { tx: tx1, rx: rx1 }, Channel { tx: tx2, rx: rx2 })
+}
+
+/// A bi-directional channel backed by a bounded [`Sender`](mpsc::Sender) and
+/// [`Receiver`](mpsc::Receiver).
+#[pin_project]
+#[derive(Debug)]
+pub struct Channel<Item, SinkItem> {
+    #[pin]
+    rx: mpsc::Receiver<Item>,
+    #[pin]
+    tx: mpsc::Sender<SinkItem>,
+}
+
+impl<Item, SinkItem> Stream for Channel<Item, SinkItem> {
+    type Item = Result<Item, io::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> PollIo<Item> {
+        self.project().rx.poll_next(cx).map(|option| option.map(Ok))
+    }
+}
+
+impl<Item, SinkItem> Sink<SinkItem> for Channel<Item, SinkItem> {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project()
+            .tx
+            .poll_ready(cx)
+            .map_err(|_| io::Error::from(io::ErrorKind::NotConnected))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: SinkItem) -> io::Result<()> {
+        self.project()
+            .tx
+            .start_send(item)
+            .map_err(|_| io::Error::from(io::ErrorKind::NotConnected))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project()
+            .tx
+            .poll_flush(cx)
+            .map_err(|_| io::Error::from(io::ErrorKind::NotConnected))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project()
+            .tx
+            .poll_close(cx)
+            .map_err(|_| io::Error::from(io::ErrorKind::NotConnected))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{
@@ -85,7 +147,7 @@ mod tests {
         transport,
     };
     use assert_matches::assert_matches;
-    use futures::{prelude::*, stream};
+    use futures::{prelude::*, stream, task::*};
     use log::trace;
     use std::io;
 
@@ -120,4 +182,19 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn bounded_channels_apply_backpressure() {
+        let (mut tx, mut rx) = transport::channel::bounded::<u32, u32>(1);
+        let mut cx = Context::from_waker(noop_waker_ref());
+        // The capacity, plus the slot every sender has.
+        for i in 0..2 {
+            assert_matches!(tx.poll_ready_unpin(&mut cx), Poll::Ready(Ok(())));
+            tx.start_send_unpin(i).unwrap();
+        }
+        assert!(tx.poll_ready_unpin(&mut cx).is_pending());
+
+        assert_matches!(rx.poll_next_unpin(&mut cx), Poll::Ready(Some(Ok(0))));
+        assert_matches!(tx.poll_ready_unpin(&mut cx), Poll::Ready(Ok(())));
+    }
 }
@@ -0,0 +1,313 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Runs two RPC sessions over one connection, so that the server can call back into the client
+//! without the client listening for connections of its own.
+//!
+//! In the *forward* session the peer that opened the connection is the client, as usual; in the
+//! *reverse* session it's the server. Each session has its own request IDs. Both peers [`split`]
+//! the connection into a transport per session, drive the returned [`Driver`], and then run a
+//! client over one transport and a server over the other:
+//!
+//! ```
+//! # use futures::{future, prelude::*};
+//! # use tarpc::{client, context, server::{BaseChannel, Channel}, transport::{channel, duplex}};
+//! # use std::io;
+//! # #[tokio::main]
+//! # async fn main() -> io::Result<()> {
+//! let (client_conn, server_conn) = channel::unbounded();
+//! let client_side = duplex::split(client_conn);
+//! let server_side = duplex::split(server_conn);
+//! tokio::spawn(client_side.driver);
+//! tokio::spawn(server_side.driver);
+//!
+//! // The server serves requests on the forward session...
+//! tokio::spawn(
+//!     BaseChannel::with_defaults(server_side.forward)
+//!         .respond_with(|_, x: u32| future::ready(x + 1))
+//!         .execute(),
+//! );
+//! // ...and the client serves callbacks on the reverse session.
+//! tokio::spawn(
+//!     BaseChannel::with_defaults(client_side.reverse)
+//!         .respond_with(|_, name: String| future::ready(format!("Hello, {}.", name)))
+//!         .execute(),
+//! );
+//!
+//! let mut client = client::new(client::Config::default(), client_side.forward).spawn()?;
+//! assert_eq!(client.call(context::current(), 1).await?, 2);
+//! let mut callbacks = client::new(client::Config::default(), server_side.reverse).spawn()?;
+//! let greeting = callbacks.call(context::current(), "server".to_string()).await?;
+//! assert_eq!(greeting, "Hello, server.");
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! When neither peer is naturally the client, e.g. between peers of which only one can dial the
+//! other, each can instead [`peer`] the connection: both then act as client and server at once,
+//! without agreeing on who's who.
+
+use super::channel::{self, UnboundedChannel};
+use crate::{ClientMessage, ServerMessage, Transport};
+use futures::{prelude::*, ready, stream::Fuse, task::*};
+use log::trace;
+use pin_project::pin_project;
+use std::{io, pin::Pin};
+
+/// A message of one of the two sessions sharing a connection.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub enum Duplex<Forward, Reverse> {
+    /// A message of the session in which the peer that opened the connection is the client.
+    Forward(Forward),
+    /// A message of the session in which the peer that opened the connection is the server.
+    Reverse(Reverse),
+}
+
+impl<Forward, Reverse> Duplex<Forward, Reverse> {
+    /// Swaps the sessions the message belongs to.
+    pub fn swap(self) -> Duplex<Reverse, Forward> {
+        match self {
+            Duplex::Forward(message) => Duplex::Reverse(message),
+            Duplex::Reverse(message) => Duplex::Forward(message),
+        }
+    }
+}
+
+/// The transports of the two sessions sharing a connection, and the driver that moves their
+/// messages to and from the connection. Created by [`split`].
+#[derive(Debug)]
+pub struct Split<Fw, Rv, D> {
+    /// The transport of the forward session.
+    pub forward: Fw,
+    /// The transport of the reverse session.
+    pub reverse: Rv,
+    /// Moves messages between the sessions' transports and the connection. It must be polled
+    /// continuously or spawned, and resolves once the connection closes or both sessions end.
+    pub driver: D,
+}
+
+/// Splits `transport` into a transport for each of the two sessions sharing it.
+pub fn split<T, FwOut, FwIn, RvOut, RvIn>(
+    transport: T,
+) -> Split<
+    UnboundedChannel<FwIn, FwOut>,
+    UnboundedChannel<RvIn, RvOut>,
+    Driver<T, FwOut, FwIn, RvOut, RvIn>,
+>
+where
+    T: Transport<Duplex<FwOut, RvOut>, Duplex<FwIn, RvIn>>,
+{
+    let (forward, forward_peer) = channel::unbounded();
+    let (reverse, reverse_peer) = channel::unbounded();
+    Split {
+        forward,
+        reverse,
+        driver: Driver {
+            transport: transport.fuse(),
+            forward: forward_peer.fuse(),
+            reverse: reverse_peer.fuse(),
+            pending: None,
+        },
+    }
+}
+
+/// The transports of a peer that is both client and server over one connection, and the driver
+/// that moves their messages to and from the connection. Created by [`peer`].
+#[derive(Debug)]
+pub struct Peer<Cl, Sv, D> {
+    /// The transport of the peer's client.
+    pub client: Cl,
+    /// The transport of the peer's server.
+    pub server: Sv,
+    /// Moves messages between the transports and the connection. It must be polled continuously
+    /// or spawned, and resolves once the connection closes or both the client and server end.
+    pub driver: D,
+}
+
+/// Splits `transport` into a transport for a client and one for a server, both talking to the
+/// peer at the other end, which must also call `peer`.
+///
+/// Each peer sends its requests as [`Duplex::Forward`] messages and its responses as
+/// [`Duplex::Reverse`] messages, so both peers send and receive the same type.
+pub fn peer<T, Req, Resp>(
+    transport: T,
+) -> Peer<
+    UnboundedChannel<ServerMessage<Resp>, ClientMessage<Req>>,
+    UnboundedChannel<ClientMessage<Req>, ServerMessage<Resp>>,
+    Driver<
+        Swap<T>,
+        ClientMessage<Req>,
+        ServerMessage<Resp>,
+        ServerMessage<Resp>,
+        ClientMessage<Req>,
+    >,
+>
+where
+    T: Transport<
+        Duplex<ClientMessage<Req>, ServerMessage<Resp>>,
+        Duplex<ClientMessage<Req>, ServerMessage<Resp>>,
+    >,
+{
+    // The peer's requests are for this peer's server, and its responses for this peer's client.
+    let Split {
+        forward,
+        reverse,
+        driver,
+    } = split(Swap { inner: transport });
+    Peer {
+        client: forward,
+        server: reverse,
+        driver,
+    }
+}
+
+/// Swaps the sessions of the messages read from a transport. Used by [`peer`].
+#[pin_project]
+#[derive(Debug)]
+pub struct Swap<T> {
+    #[pin]
+    inner: T,
+}
+
+impl<T, Forward, Reverse> Stream for Swap<T>
+where
+    T: Stream<Item = io::Result<Duplex<Forward, Reverse>>>,
+{
+    type Item = io::Result<Duplex<Reverse, Forward>>;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<io::Result<Duplex<Reverse, Forward>>>> {
+        self.project()
+            .inner
+            .poll_next(cx)
+            .map(|message| message.map(|message| message.map(Duplex::swap)))
+    }
+}
+
+impl<T, Item> Sink<Item> for Swap<T>
+where
+    T: Sink<Item, Error = io::Error>,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Item) -> io::Result<()> {
+        self.project().inner.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+/// Moves messages between the sessions' transports and the connection they share. Created by
+/// [`split`].
+#[pin_project]
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Driver<T, FwOut, FwIn, RvOut, RvIn> {
+    #[pin]
+    transport: Fuse<T>,
+    #[pin]
+    forward: Fuse<UnboundedChannel<FwOut, FwIn>>,
+    #[pin]
+    reverse: Fuse<UnboundedChannel<RvOut, RvIn>>,
+    /// A message read from a session that hasn't yet been written to the connection.
+    pending: Option<Duplex<FwOut, RvOut>>,
+}
+
+impl<T, FwOut, FwIn, RvOut, RvIn> Driver<T, FwOut, FwIn, RvOut, RvIn>
+where
+    T: Transport<Duplex<FwOut, RvOut>, Duplex<FwIn, RvIn>>,
+{
+    /// Routes a message read off the connection to its session. Resolves to None once the
+    /// connection closes.
+    fn pump_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<()>>> {
+        let this = self.as_mut().project();
+        let delivered = match ready!(this.transport.poll_next(cx)?) {
+            Some(Duplex::Forward(message)) => this.forward.start_send(message),
+            Some(Duplex::Reverse(message)) => this.reverse.start_send(message),
+            None => return Poll::Ready(None),
+        };
+        if delivered.is_err() {
+            trace!("Dropped a message for a session that ended.");
+        }
+        Poll::Ready(Some(Ok(())))
+    }
+
+    /// Writes a message from either session to the connection. Resolves to None once both
+    /// sessions have ended and their messages are flushed.
+    fn pump_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<()>>> {
+        if self.pending.is_none() {
+            let this = self.as_mut().project();
+            let forward = match this.forward.poll_next(cx)? {
+                Poll::Ready(Some(message)) => {
+                    *this.pending = Some(Duplex::Forward(message));
+                    Poll::Ready(Some(()))
+                }
+                poll => poll.map(|_| None),
+            };
+            if this.pending.is_none() {
+                let reverse = match this.reverse.poll_next(cx)? {
+                    Poll::Ready(Some(message)) => {
+                        *this.pending = Some(Duplex::Reverse(message));
+                        Poll::Ready(Some(()))
+                    }
+                    poll => poll.map(|_| None),
+                };
+                if this.pending.is_none() {
+                    ready!(this.transport.poll_flush(cx)?);
+                    return match (forward, reverse) {
+                        (Poll::Ready(None), Poll::Ready(None)) => Poll::Ready(None),
+                        _ => Poll::Pending,
+                    };
+                }
+            }
+        }
+        let mut this = self.as_mut().project();
+        while this.transport.as_mut().poll_ready(cx)?.is_pending() {
+            ready!(this.transport.as_mut().poll_flush(cx)?);
+        }
+        let message = this.pending.take().unwrap();
+        this.transport.start_send(message)?;
+        Poll::Ready(Some(Ok(())))
+    }
+}
+
+impl<T, FwOut, FwIn, RvOut, RvIn> Future for Driver<T, FwOut, FwIn, RvOut, RvIn>
+where
+    T: Transport<Duplex<FwOut, RvOut>, Duplex<FwIn, RvIn>>,
+{
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            match (self.as_mut().pump_read(cx)?, self.as_mut().pump_write(cx)?) {
+                (Poll::Ready(None), _) => {
+                    trace!("Shutdown: connection closed.");
+                    return Poll::Ready(Ok(()));
+                }
+                (_, Poll::Ready(None)) => {
+                    trace!("Shutdown: both sessions ended.");
+                    ready!(self.as_mut().project().transport.poll_close(cx)?);
+                    return Poll::Ready(Ok(()));
+                }
+                (Poll::Ready(Some(())), _) | (_, Poll::Ready(Some(()))) => {}
+                (Poll::Pending, Poll::Pending) => return Poll::Pending,
+            }
+        }
+    }
+}
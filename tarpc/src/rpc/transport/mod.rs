@@ -10,9 +10,28 @@
 //! can be plugged in, using whatever protocol it wants.
 
 use futures::prelude::*;
-use std::io;
+use std::{io, pin::Pin};
 
+#[cfg(feature = "debug-capture")]
+#[cfg_attr(docsrs, doc(cfg(feature = "debug-capture")))]
+pub mod capture;
 pub mod channel;
+pub mod duplex;
+pub mod mux;
+pub mod named;
+pub mod outbound;
+
+/// A [`Transport`](sealed::Transport) of any type, e.g. to handle connections accepted by
+/// different kinds of listeners alike.
+pub type BoxTransport<SinkItem, Item> = Pin<Box<dyn sealed::Transport<SinkItem, Item> + Send>>;
+
+/// Boxes `transport`, erasing its type.
+pub fn boxed<T, SinkItem, Item>(transport: T) -> BoxTransport<SinkItem, Item>
+where
+    T: sealed::Transport<SinkItem, Item> + Send + 'static,
+{
+    Box::pin(transport)
+}
 
 pub(crate) mod sealed {
     use super::*;
@@ -0,0 +1,254 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Runs several logical channels over one connection, so that independent workloads, such as bulk
+//! transfers and latency-sensitive control requests, don't queue up behind each other.
+//!
+//! Each logical channel has its own queue of outgoing messages, and the channels take turns
+//! writing them to the connection, one message at a time. A channel with a long queue therefore
+//! delays the messages of another by at most one message per turn. Both peers [`split`] the
+//! connection into the same number of channels, drive the returned [`Driver`], and then run a
+//! client or server over each channel:
+//!
+//! ```
+//! # use futures::{future, prelude::*};
+//! # use tarpc::{client, context, server::{BaseChannel, Channel}, transport::{channel, mux}};
+//! # use std::io;
+//! # #[tokio::main]
+//! # async fn main() -> io::Result<()> {
+//! let (client_conn, server_conn) = channel::unbounded();
+//! let client_side = mux::split(client_conn, 2);
+//! let server_side = mux::split(server_conn, 2);
+//! tokio::spawn(client_side.driver);
+//! tokio::spawn(server_side.driver);
+//!
+//! for channel in server_side.channels {
+//!     tokio::spawn(
+//!         BaseChannel::with_defaults(channel)
+//!             .respond_with(|_, x: u32| future::ready(x + 1))
+//!             .execute(),
+//!     );
+//! }
+//! let mut clients = vec![];
+//! for channel in client_side.channels {
+//!     clients.push(client::new(client::Config::default(), channel).spawn()?);
+//! }
+//! let (mut control, mut bulk) = (clients.remove(0), clients.remove(0));
+//! assert_eq!(control.call(context::current(), 1).await?, 2);
+//! assert_eq!(bulk.call(context::current(), 2).await?, 3);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Fragmenting large messages, e.g. with
+//! [`with_fragmentation`](crate::serde_transport::Transport::with_fragmentation), keeps a single
+//! large message from holding up the other channels' turns.
+
+use super::channel::{self, UnboundedChannel};
+use crate::Transport;
+use futures::{prelude::*, ready, stream::Fuse, task::*};
+use log::trace;
+use pin_project::pin_project;
+use std::{io, pin::Pin};
+
+/// A message of one of the logical channels sharing a connection.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub struct Frame<T> {
+    /// The index of the logical channel the message belongs to.
+    pub channel: u32,
+    /// The message.
+    pub message: T,
+}
+
+/// The transports of the logical channels sharing a connection, and the driver that moves their
+/// messages to and from the connection. Created by [`split`].
+#[derive(Debug)]
+pub struct Split<Ch, D> {
+    /// The transport of each logical channel, in order of their indices.
+    pub channels: Vec<Ch>,
+    /// Moves messages between the channels' transports and the connection. It must be polled
+    /// continuously or spawned, and resolves once the connection closes or every channel ends.
+    pub driver: D,
+}
+
+/// Splits `transport` into `channels` logical channels. The peer at the other end must split its
+/// end into the same number of channels.
+///
+/// # Panics
+///
+/// If `channels` is zero.
+pub fn split<T, Item, SinkItem>(
+    transport: T,
+    channels: usize,
+) -> Split<UnboundedChannel<Item, SinkItem>, Driver<T, Item, SinkItem>>
+where
+    T: Transport<Frame<SinkItem>, Frame<Item>>,
+{
+    assert!(channels > 0, "A connection must have at least one channel.");
+    let (channels, peers): (Vec<_>, Vec<_>) = (0..channels).map(|_| channel::unbounded()).unzip();
+    Split {
+        channels,
+        driver: Driver {
+            transport: transport.fuse(),
+            peers: peers.into_iter().map(|peer| peer.fuse()).collect(),
+            next_turn: 0,
+            pending: None,
+        },
+    }
+}
+
+/// Moves messages between the logical channels' transports and the connection they share.
+/// Created by [`split`].
+#[pin_project]
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Driver<T, Item, SinkItem> {
+    #[pin]
+    transport: Fuse<T>,
+    /// The other ends of the channels' transports.
+    peers: Vec<Fuse<UnboundedChannel<SinkItem, Item>>>,
+    /// The index of the channel whose turn it is to write a message.
+    next_turn: usize,
+    /// A message read from a channel that hasn't yet been written to the connection.
+    pending: Option<Frame<SinkItem>>,
+}
+
+impl<T, Item, SinkItem> Driver<T, Item, SinkItem>
+where
+    T: Transport<Frame<SinkItem>, Frame<Item>>,
+{
+    /// Routes a message read off the connection to its channel. Resolves to None once the
+    /// connection closes.
+    fn pump_read(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<()>>> {
+        let this = self.project();
+        let frame = match ready!(this.transport.poll_next(cx)?) {
+            Some(frame) => frame,
+            None => return Poll::Ready(None),
+        };
+        let peer = match this.peers.get_mut(frame.channel as usize) {
+            Some(peer) => peer,
+            None => {
+                return Poll::Ready(Some(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Received a message for channel {}, but there are only {} channels.",
+                        frame.channel,
+                        this.peers.len()
+                    ),
+                ))))
+            }
+        };
+        if Pin::new(peer).start_send(frame.message).is_err() {
+            trace!(
+                "Dropped a message for channel {}, which ended.",
+                frame.channel
+            );
+        }
+        Poll::Ready(Some(Ok(())))
+    }
+
+    /// Writes a message from the next channel with one ready to the connection. Resolves to None
+    /// once every channel has ended and their messages are flushed.
+    fn pump_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<()>>> {
+        if self.pending.is_none() {
+            let this = self.as_mut().project();
+            let channels = this.peers.len();
+            let mut ended = 0;
+            for turn in (*this.next_turn..channels).chain(0..*this.next_turn) {
+                match this.peers[turn].poll_next_unpin(cx)? {
+                    Poll::Ready(Some(message)) => {
+                        *this.pending = Some(Frame {
+                            channel: turn as u32,
+                            message,
+                        });
+                        *this.next_turn = (turn + 1) % channels;
+                        break;
+                    }
+                    Poll::Ready(None) => ended += 1,
+                    Poll::Pending => {}
+                }
+            }
+            if this.pending.is_none() {
+                ready!(this.transport.poll_flush(cx)?);
+                return if ended == channels {
+                    Poll::Ready(None)
+                } else {
+                    Poll::Pending
+                };
+            }
+        }
+        let mut this = self.as_mut().project();
+        while this.transport.as_mut().poll_ready(cx)?.is_pending() {
+            ready!(this.transport.as_mut().poll_flush(cx)?);
+        }
+        let frame = this.pending.take().unwrap();
+        this.transport.start_send(frame)?;
+        Poll::Ready(Some(Ok(())))
+    }
+}
+
+impl<T, Item, SinkItem> Future for Driver<T, Item, SinkItem>
+where
+    T: Transport<Frame<SinkItem>, Frame<Item>>,
+{
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            match (self.as_mut().pump_read(cx)?, self.as_mut().pump_write(cx)?) {
+                (Poll::Ready(None), _) => {
+                    trace!("Shutdown: connection closed.");
+                    return Poll::Ready(Ok(()));
+                }
+                (_, Poll::Ready(None)) => {
+                    trace!("Shutdown: every channel ended.");
+                    ready!(self.as_mut().project().transport.poll_close(cx)?);
+                    return Poll::Ready(Ok(()));
+                }
+                (Poll::Ready(Some(())), _) | (_, Poll::Ready(Some(()))) => {}
+                (Poll::Pending, Poll::Pending) => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+use assert_matches::assert_matches;
+
+#[tokio::test]
+async fn channels_take_turns() {
+    let (conn, mut peer) = channel::unbounded::<Frame<&str>, Frame<&str>>();
+    let Split {
+        mut channels,
+        driver,
+    } = split(conn, 2);
+    for bulk in &["bulk 1", "bulk 2", "bulk 3"] {
+        channels[0].send(bulk).await.unwrap();
+    }
+    channels[1].send("control").await.unwrap();
+    tokio::spawn(driver);
+
+    let frames: Vec<_> = (&mut peer).take(4).try_collect().await.unwrap();
+    let order: Vec<_> = frames.iter().map(|frame| frame.message).collect();
+    assert_eq!(order, ["bulk 1", "control", "bulk 2", "bulk 3"]);
+
+    peer.send(Frame {
+        channel: 1,
+        message: "ack",
+    })
+    .await
+    .unwrap();
+    assert_eq!(channels[1].next().await.unwrap().unwrap(), "ack");
+    peer.send(Frame {
+        channel: 2,
+        message: "lost",
+    })
+    .await
+    .unwrap();
+    // The driver fails, ending every channel.
+    assert_matches!(channels[0].next().await, None);
+}
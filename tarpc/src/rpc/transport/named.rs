@@ -0,0 +1,133 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Names the method of each request in its envelope, so that proxies, metrics, and debuggers
+//! between client and server can classify traffic without decoding request messages.
+//!
+//! A client writes to a [`NamedRequests`] transport, which sets the
+//! [`method`](crate::Request::method) of each request it sends to the [name](RequestName) of its
+//! message:
+//!
+//! ```
+//! # use futures::prelude::*;
+//! # use tarpc::{client, context, transport::{channel, named::NamedRequests}, ClientMessage};
+//! # use std::io;
+//! #[tarpc::service]
+//! trait World {
+//!     async fn hello(name: String) -> String;
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> io::Result<()> {
+//! let (client_transport, mut server_transport) = channel::unbounded();
+//! let transport = NamedRequests::new(client_transport);
+//! let mut client = WorldClient::new(client::Config::default(), transport).spawn()?;
+//! tokio::spawn(async move { client.hello(context::current(), "Stim".into()).await });
+//!
+//! match server_transport.next().await {
+//!     Some(Ok(ClientMessage::Request(request))) => assert_eq!(request.method.unwrap(), "hello"),
+//!     _ => unreachable!(),
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{ClientMessage, RequestName};
+use futures::{prelude::*, task::*};
+use pin_project::pin_project;
+use std::{borrow::Cow, pin::Pin};
+
+/// A client transport that names the method of each request sent over it.
+#[pin_project]
+#[derive(Debug)]
+pub struct NamedRequests<T> {
+    #[pin]
+    inner: T,
+}
+
+impl<T> NamedRequests<T> {
+    /// Returns a transport that names the requests sent over `inner`.
+    pub fn new(inner: T) -> Self {
+        NamedRequests { inner }
+    }
+
+    /// Returns the inner transport.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T, Req> Sink<ClientMessage<Req>> for NamedRequests<T>
+where
+    T: Sink<ClientMessage<Req>>,
+    Req: RequestName,
+{
+    type Error = T::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), T::Error>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, mut message: ClientMessage<Req>) -> Result<(), T::Error> {
+        if let ClientMessage::Request(request) | ClientMessage::StreamingRequest(request) =
+            &mut message
+        {
+            request.method = Some(Cow::Borrowed(request.message.name()));
+        }
+        self.project().inner.start_send(message)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), T::Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), T::Error>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+impl<T> Stream for NamedRequests<T>
+where
+    T: Stream,
+{
+    type Item = T::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T::Item>> {
+        self.project().inner.poll_next(cx)
+    }
+}
+
+#[tokio::test]
+async fn requests_are_named() {
+    use crate::{context, transport::channel, Request, ServerMessage};
+
+    struct Hello;
+
+    impl RequestName for Hello {
+        fn name(&self) -> &'static str {
+            "hello"
+        }
+    }
+
+    let (client_transport, mut server_transport) =
+        channel::unbounded::<ServerMessage<()>, ClientMessage<Hello>>();
+    let mut client_transport = NamedRequests::new(client_transport);
+    client_transport
+        .send(ClientMessage::Request(Request {
+            context: context::current(),
+            id: 0,
+            method: None,
+            message: Hello,
+        }))
+        .await
+        .unwrap();
+    match server_transport.next().await {
+        Some(Ok(ClientMessage::Request(request))) => {
+            assert_eq!(request.method.as_deref(), Some("hello"))
+        }
+        _ => panic!("Expected a request."),
+    }
+}
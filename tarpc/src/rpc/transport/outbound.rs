@@ -0,0 +1,332 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Moves a transport's writes off the path of whoever sends on it, onto a writer that drains a
+//! queue of outbound messages.
+//!
+//! Sending on the transport returned by [`queue`] only enqueues the message; a [`Writer`] writes
+//! queued messages to the underlying transport and flushes it when the queue runs dry, or after
+//! [`Config::max_batch`] messages, whichever comes first. Messages written between flushes
+//! coalesce in the transport's write buffer, so a server answering many requests at once sends
+//! their replies in a few large writes rather than many small ones:
+//!
+//! ```
+//! # use futures::future;
+//! # use tarpc::{client, context, server::{BaseChannel, Channel}, transport::{channel, outbound}};
+//! # use std::io;
+//! # #[tokio::main]
+//! # async fn main() -> io::Result<()> {
+//! let (client_transport, server_transport) = channel::unbounded();
+//! let server_transport = outbound::queue(server_transport, outbound::Config::default());
+//! tokio::spawn(server_transport.writer);
+//! tokio::spawn(
+//!     BaseChannel::with_defaults(server_transport.transport)
+//!         .respond_with(|_, x: u32| future::ready(x + 1))
+//!         .execute(),
+//! );
+//!
+//! let mut client = client::new(client::Config::default(), client_transport).spawn()?;
+//! assert_eq!(client.call(context::current(), 1).await?, 2);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::Transport;
+use futures::{
+    channel::{mpsc, oneshot},
+    prelude::*,
+    ready,
+    stream::{SplitSink, SplitStream},
+    task::*,
+};
+use log::trace;
+use pin_project::pin_project;
+use std::{
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+/// Settings for the outbound queue of a transport.
+#[derive(Clone, Copy, Debug)]
+#[non_exhaustive]
+pub struct Config {
+    /// The number of messages that can wait to be written before sending waits for room.
+    pub capacity: usize,
+    /// The most messages written between flushes. The writer also flushes whenever the queue
+    /// runs dry.
+    pub max_batch: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            capacity: 1_000,
+            max_batch: 64,
+        }
+    }
+}
+
+/// A transport whose writes are queued, and the writer that drains its queue. Created by
+/// [`queue`].
+#[derive(Debug)]
+pub struct Queued<T, W> {
+    /// The transport, which reads as the underlying transport does, and queues its writes.
+    pub transport: T,
+    /// Writes queued messages to the underlying transport. It must be polled continuously or
+    /// spawned, and resolves once the transport is closed or dropped and its queue is drained,
+    /// or writing fails.
+    pub writer: W,
+}
+
+/// Queues the writes of `transport`, to be written by the returned [`Writer`].
+pub fn queue<T, SinkItem, Item>(
+    transport: T,
+    config: Config,
+) -> Queued<Outbound<SplitStream<T>, SinkItem>, Writer<SplitSink<T, SinkItem>, SinkItem>>
+where
+    T: Transport<SinkItem, Item>,
+{
+    let (sink, stream) = transport.split();
+    let (tx, rx) = mpsc::channel(config.capacity);
+    let (done_tx, done_rx) = oneshot::channel();
+    let failure = Arc::new(Mutex::new(None));
+    Queued {
+        transport: Outbound {
+            stream,
+            queue: tx,
+            failure: failure.clone(),
+            done: done_rx,
+        },
+        writer: Writer {
+            sink,
+            queue: rx,
+            max_batch: config.max_batch.max(1),
+            unflushed: 0,
+            failure,
+            done: Some(done_tx),
+        },
+    }
+}
+
+/// Why the writer stopped, shared with the transport so that its later sends fail alike.
+type Failure = Arc<Mutex<Option<(io::ErrorKind, String)>>>;
+
+fn failed(failure: &Failure) -> io::Error {
+    match *failure.lock().unwrap() {
+        Some((kind, ref message)) => io::Error::new(kind, message.clone()),
+        None => io::Error::from(io::ErrorKind::BrokenPipe),
+    }
+}
+
+/// A transport that reads as its underlying transport does, and queues its writes for a
+/// [`Writer`]. Created by [`queue`].
+///
+/// Flushing returns as soon as the messages sent are queued. Closing waits for the writer to
+/// write and flush them, and close the underlying transport.
+#[pin_project]
+#[derive(Debug)]
+pub struct Outbound<St, SinkItem> {
+    #[pin]
+    stream: St,
+    queue: mpsc::Sender<SinkItem>,
+    failure: Failure,
+    /// Resolves once the writer is done.
+    done: oneshot::Receiver<()>,
+}
+
+impl<St, SinkItem> Stream for Outbound<St, SinkItem>
+where
+    St: Stream,
+{
+    type Item = St::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<St::Item>> {
+        self.project().stream.poll_next(cx)
+    }
+}
+
+impl<St, SinkItem> Sink<SinkItem> for Outbound<St, SinkItem> {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        this.queue.poll_ready(cx).map_err(|_| failed(this.failure))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: SinkItem) -> io::Result<()> {
+        let this = self.project();
+        this.queue
+            .start_send(item)
+            .map_err(|_| failed(this.failure))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.queue.is_closed() {
+            return Poll::Ready(Err(failed(&self.failure)));
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        this.queue.close_channel();
+        let _ = ready!(this.done.poll_unpin(cx));
+        Poll::Ready(match *this.failure.lock().unwrap() {
+            Some((kind, ref message)) => Err(io::Error::new(kind, message.clone())),
+            None => Ok(()),
+        })
+    }
+}
+
+/// Writes the queued messages of an [`Outbound`] transport to the underlying transport. Created
+/// by [`queue`].
+#[pin_project]
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Writer<Si, SinkItem> {
+    #[pin]
+    sink: Si,
+    queue: mpsc::Receiver<SinkItem>,
+    max_batch: usize,
+    /// The number of messages written since the last flush.
+    unflushed: usize,
+    failure: Failure,
+    done: Option<oneshot::Sender<()>>,
+}
+
+impl<Si, SinkItem> Writer<Si, SinkItem>
+where
+    Si: Sink<SinkItem, Error = io::Error>,
+{
+    /// Writes queued messages until the queue is drained and closed, flushing whenever it runs
+    /// dry or a batch is full, then closes the sink.
+    fn pump(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        loop {
+            if *this.unflushed >= *this.max_batch {
+                trace!("Flushing a full batch of {} messages.", this.unflushed);
+                ready!(this.sink.as_mut().poll_flush(cx)?);
+                *this.unflushed = 0;
+            }
+            ready!(this.sink.as_mut().poll_ready(cx)?);
+            match this.queue.poll_next_unpin(cx) {
+                Poll::Ready(Some(item)) => {
+                    this.sink.as_mut().start_send(item)?;
+                    *this.unflushed += 1;
+                }
+                Poll::Ready(None) => {
+                    ready!(this.sink.as_mut().poll_flush(cx)?);
+                    ready!(this.sink.as_mut().poll_close(cx)?);
+                    return Poll::Ready(Ok(()));
+                }
+                Poll::Pending => {
+                    if *this.unflushed > 0 {
+                        trace!("Queue is idle; flushing {} messages.", this.unflushed);
+                        ready!(this.sink.as_mut().poll_flush(cx)?);
+                        *this.unflushed = 0;
+                    }
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+}
+
+impl<Si, SinkItem> Future for Writer<Si, SinkItem>
+where
+    Si: Sink<SinkItem, Error = io::Error>,
+{
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let result = ready!(self.as_mut().pump(cx));
+        let this = self.project();
+        if let Err(ref e) = result {
+            trace!("Writer failed: {}", e);
+            *this.failure.lock().unwrap() = Some((e.kind(), e.to_string()));
+        }
+        // Fails any sends that race with the writer stopping.
+        this.queue.close();
+        if let Some(done) = this.done.take() {
+            let _ = done.send(());
+        }
+        Poll::Ready(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{queue, Config};
+    use futures::{executor::block_on, prelude::*, stream, task::*};
+    use std::{
+        io,
+        pin::Pin,
+        sync::{Arc, Mutex},
+    };
+
+    /// Records what's written to it.
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl Stream for Recorder {
+        type Item = io::Result<u32>;
+
+        fn poll_next(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<io::Result<u32>>> {
+            Poll::Pending
+        }
+    }
+
+    impl Sink<u32> for Recorder {
+        type Error = io::Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, item: u32) -> io::Result<()> {
+            self.0.lock().unwrap().push(item.to_string());
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.0.lock().unwrap().push("flush".into());
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.0.lock().unwrap().push("close".into());
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn writes_are_batched_and_flushed_when_idle() -> io::Result<()> {
+        let written = Arc::new(Mutex::new(vec![]));
+        let config = Config {
+            max_batch: 2,
+            ..Default::default()
+        };
+        let queued = queue(Recorder(written.clone()), config);
+        let (mut transport, mut writer) = (queued.transport, queued.writer);
+
+        block_on(transport.send_all(&mut stream::iter((0..5).map(Ok))))?;
+        assert!(written.lock().unwrap().is_empty());
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(writer.poll_unpin(&mut cx).is_pending());
+        assert_eq!(
+            *written.lock().unwrap(),
+            ["0", "1", "flush", "2", "3", "flush", "4", "flush"]
+        );
+
+        let closed = async {
+            transport.send(5).await?;
+            transport.close().await
+        };
+        block_on(future::try_join(closed, writer))?;
+        assert_eq!(written.lock().unwrap()[8..], ["5", "flush", "close"]);
+        Ok(())
+    }
+}
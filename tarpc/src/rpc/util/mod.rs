@@ -5,24 +5,44 @@
 // https://opensource.org/licenses/MIT.
 
 use std::{
+    any::Any,
     collections::HashMap,
+    error::Error,
     hash::{BuildHasher, Hash},
-    time::{Duration, SystemTime},
+    io,
 };
+#[cfg(feature = "config")]
+use std::{fs, path::Path};
 
 #[cfg(feature = "serde")]
 pub mod serde;
 
-/// Extension trait for [SystemTimes](SystemTime) in the future, i.e. deadlines.
-pub trait TimeUntil {
-    /// How much time from now until this time is reached.
-    fn time_until(&self) -> Duration;
+/// Returns the message of a panic caught by [`std::panic::catch_unwind`], if it has one.
+pub(crate) fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&'static str>() {
+        Some(message) => message,
+        None => panic
+            .downcast_ref::<String>()
+            .map(String::as_str)
+            .unwrap_or("Box<Any>"),
+    }
 }
 
-impl TimeUntil for SystemTime {
-    fn time_until(&self) -> Duration {
-        self.duration_since(SystemTime::now()).unwrap_or_default()
+/// Returns the kind of the innermost I/O error behind `e`, which transports and codecs may have
+/// wrapped in I/O errors of their own.
+pub(crate) fn root_io_error_kind(e: &io::Error) -> io::ErrorKind {
+    let mut kind = e.kind();
+    let mut next: Option<&(dyn Error + 'static)> = e.get_ref().map(|e| e as _);
+    while let Some(error) = next {
+        next = match error.downcast_ref::<io::Error>() {
+            Some(e) => {
+                kind = e.kind();
+                e.get_ref().map(|e| e as _)
+            }
+            None => error.source(),
+        };
     }
+    kind
 }
 
 /// Collection compaction; configurable `shrink_to_fit`.
@@ -45,3 +65,30 @@ where
         }
     }
 }
+
+/// Reads a `T` from the TOML or JSON file at `path`, choosing the format by the file's extension.
+#[cfg(feature = "config")]
+pub(crate) fn from_file<T>(path: &Path) -> io::Result<T>
+where
+    T: ::serde::de::DeserializeOwned,
+{
+    let invalid_data = |e: &dyn std::fmt::Display| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid config file {}: {}", path.display(), e),
+        )
+    };
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("toml") => toml::from_str(&fs::read_to_string(path)?).map_err(|e| invalid_data(&e)),
+        Some("json") => {
+            serde_json::from_str(&fs::read_to_string(path)?).map_err(|e| invalid_data(&e))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Config file {} must have a .toml or .json extension.",
+                path.display()
+            ),
+        )),
+    }
+}
@@ -30,6 +30,51 @@ where
     Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(u64::deserialize(deserializer)?))
 }
 
+/// Serializes [`Duration`] as a human-readable string, like `"1s 500ms"`.
+pub fn serialize_duration_human<S>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    humantime::format_duration(*duration)
+        .to_string()
+        .serialize(serializer)
+}
+
+/// Deserializes [`Duration`] from a human-readable string, like `"1s 500ms"` or `"2m"`.
+pub fn deserialize_duration_human<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let duration = String::deserialize(deserializer)?;
+    humantime::parse_duration(&duration).map_err(serde::de::Error::custom)
+}
+
+/// Serializes an optional [`Duration`] as a human-readable string, like `"1s 500ms"`.
+pub fn serialize_optional_duration_human<S>(
+    duration: &Option<Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    duration
+        .map(|duration| humantime::format_duration(duration).to_string())
+        .serialize(serializer)
+}
+
+/// Deserializes an optional [`Duration`] from a human-readable string, like `"1s 500ms"` or
+/// `"2m"`.
+pub fn deserialize_optional_duration_human<'de, D>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|duration| humantime::parse_duration(&duration).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
 /// Serializes [`io::ErrorKind`] as a `u32`.
 #[allow(clippy::trivially_copy_pass_by_ref)] // Exact fn signature required by serde derive
 pub fn serialize_io_error_kind_as_u32<S>(
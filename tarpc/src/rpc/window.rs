@@ -0,0 +1,50 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Credit-based flow control for the items of streams.
+//!
+//! The receiver of a stream grants the sender an initial window of credit when the stream opens.
+//! The sender spends one credit per item and waits when it has none left; the receiver grants
+//! more credit as it drains items.
+
+use futures::channel::mpsc;
+
+/// Grants a stream's sender more credit as the receiver drains items.
+#[derive(Debug)]
+pub(crate) struct WindowGrants {
+    request_id: u64,
+    window: u32,
+    drained: u32,
+    grants: mpsc::UnboundedSender<(u64, u32)>,
+}
+
+impl WindowGrants {
+    /// Returns grants for the stream of request `request_id`, whose sender was granted an initial
+    /// `window` of credit.
+    pub(crate) fn new(
+        request_id: u64,
+        window: u32,
+        grants: mpsc::UnboundedSender<(u64, u32)>,
+    ) -> Self {
+        WindowGrants {
+            request_id,
+            window,
+            drained: 0,
+            grants,
+        }
+    }
+
+    /// Records that an item was drained. Once half the window is drained, grants the sender
+    /// credit for the drained items.
+    pub(crate) fn drained(&mut self) {
+        self.drained += 1;
+        if self.drained >= (self.window / 2).max(1) {
+            // If nothing is listening, the stream is over and the grant is moot.
+            let _ = self.grants.unbounded_send((self.request_id, self.drained));
+            self.drained = 0;
+        }
+    }
+}
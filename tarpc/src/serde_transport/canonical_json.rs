@@ -0,0 +1,249 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use bytes::{Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+use std::{fmt, io, marker::PhantomData, pin::Pin};
+use tokio_serde::{Deserializer, Serializer};
+
+/// A JSON codec that encodes equal messages as identical bytes, so that messages can be signed,
+/// or requests cached by their content, no matter which build of a client encoded them.
+///
+/// Messages are encoded as by [serde_json](https://docs.rs/serde_json), then canonicalized:
+/// the members of every object are sorted by key, which fixes the order of the entries of
+/// `HashMap`s; numbers with a fraction or exponent are written in the shortest scientific
+/// notation that round-trips, e.g. `1.5e0`, with negative zero written as `0e0`; and there's no
+/// whitespace. Integers and strings are written as serde_json writes them. The canonical encoding
+/// is still JSON, so peers may decode it with any JSON codec.
+pub struct CanonicalJson<Item, SinkItem> {
+    ghost: PhantomData<(Item, SinkItem)>,
+}
+
+impl<Item, SinkItem> Default for CanonicalJson<Item, SinkItem> {
+    fn default() -> Self {
+        CanonicalJson { ghost: PhantomData }
+    }
+}
+
+impl<Item, SinkItem> fmt::Debug for CanonicalJson<Item, SinkItem> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CanonicalJson").finish()
+    }
+}
+
+impl<Item, SinkItem> Deserializer<Item> for CanonicalJson<Item, SinkItem>
+where
+    for<'a> Item: Deserialize<'a>,
+{
+    type Error = io::Error;
+
+    fn deserialize(self: Pin<&mut Self>, src: &BytesMut) -> io::Result<Item> {
+        Ok(serde_json::from_slice(src)?)
+    }
+}
+
+impl<Item, SinkItem> Serializer<SinkItem> for CanonicalJson<Item, SinkItem>
+where
+    SinkItem: Serialize,
+{
+    type Error = io::Error;
+
+    fn serialize(self: Pin<&mut Self>, item: &SinkItem) -> io::Result<Bytes> {
+        Ok(canonicalize(&serde_json::to_vec(item)?)?.into())
+    }
+}
+
+/// Canonicalizes the JSON written by serde_json. Parsing it into a `serde_json::Value` instead
+/// would lose `u128`s, like trace IDs, which `Value` can't hold.
+fn canonicalize(json: &[u8]) -> io::Result<Vec<u8>> {
+    let mut canonicalizer = Canonicalizer { json, next: 0 };
+    let mut canonical = Vec::with_capacity(json.len());
+    canonicalizer.value(&mut canonical)?;
+    if canonicalizer.next != json.len() {
+        return Err(invalid());
+    }
+    Ok(canonical)
+}
+
+fn invalid() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Invalid JSON.")
+}
+
+struct Canonicalizer<'a> {
+    json: &'a [u8],
+    next: usize,
+}
+
+impl<'a> Canonicalizer<'a> {
+    fn peek(&mut self) -> Option<u8> {
+        while let Some(b' ') | Some(b'\n') | Some(b'\r') | Some(b'\t') = self.json.get(self.next) {
+            self.next += 1;
+        }
+        self.json.get(self.next).copied()
+    }
+
+    fn expect(&mut self, byte: u8) -> io::Result<()> {
+        if self.peek() != Some(byte) {
+            return Err(invalid());
+        }
+        self.next += 1;
+        Ok(())
+    }
+
+    fn value(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        match self.peek() {
+            Some(b'{') => self.object(out),
+            Some(b'[') => self.array(out),
+            Some(b'"') => {
+                out.extend_from_slice(self.string()?);
+                Ok(())
+            }
+            Some(_) => self.scalar(out),
+            None => Err(invalid()),
+        }
+    }
+
+    fn object(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        self.expect(b'{')?;
+        let mut members = vec![];
+        if self.peek() == Some(b'}') {
+            self.next += 1;
+        } else {
+            loop {
+                let raw_key = self.string()?;
+                let key: String = serde_json::from_slice(raw_key)?;
+                self.expect(b':')?;
+                let mut value = vec![];
+                self.value(&mut value)?;
+                members.push((key, raw_key, value));
+                match self.peek() {
+                    Some(b',') => self.next += 1,
+                    Some(b'}') => {
+                        self.next += 1;
+                        break;
+                    }
+                    _ => return Err(invalid()),
+                }
+            }
+        }
+        members.sort_by(|(a, ..), (b, ..)| a.cmp(b));
+        out.push(b'{');
+        for (i, (_, raw_key, value)) in members.iter().enumerate() {
+            if i > 0 {
+                out.push(b',');
+            }
+            out.extend_from_slice(raw_key);
+            out.push(b':');
+            out.extend_from_slice(value);
+        }
+        out.push(b'}');
+        Ok(())
+    }
+
+    fn array(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        self.expect(b'[')?;
+        out.push(b'[');
+        if self.peek() == Some(b']') {
+            self.next += 1;
+        } else {
+            loop {
+                self.value(out)?;
+                match self.peek() {
+                    Some(b',') => {
+                        self.next += 1;
+                        out.push(b',');
+                    }
+                    Some(b']') => {
+                        self.next += 1;
+                        break;
+                    }
+                    _ => return Err(invalid()),
+                }
+            }
+        }
+        out.push(b']');
+        Ok(())
+    }
+
+    /// Returns the string next, with its quotes and escapes.
+    fn string(&mut self) -> io::Result<&'a [u8]> {
+        let start = self.next;
+        self.expect(b'"')?;
+        loop {
+            match self.json.get(self.next) {
+                Some(b'"') => break,
+                Some(b'\\') => self.next += 2,
+                Some(_) => self.next += 1,
+                None => return Err(invalid()),
+            }
+        }
+        self.next += 1;
+        Ok(&self.json[start..self.next])
+    }
+
+    /// Writes the literal or number next.
+    fn scalar(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        let start = self.next;
+        while let Some(&byte) = self.json.get(self.next) {
+            if let b',' | b']' | b'}' | b' ' | b'\n' | b'\r' | b'\t' = byte {
+                break;
+            }
+            self.next += 1;
+        }
+        let scalar = &self.json[start..self.next];
+        let is_number = matches!(scalar.first(), Some(b'-') | Some(b'0'..=b'9'));
+        if is_number && scalar.iter().any(|byte| matches!(byte, b'.' | b'e' | b'E')) {
+            let float: f64 = std::str::from_utf8(scalar)
+                .ok()
+                .and_then(|float| float.parse().ok())
+                .ok_or_else(invalid)?;
+            // Adding zero turns negative zero positive.
+            out.extend_from_slice(format!("{:e}", float + 0.0).as_bytes());
+        } else {
+            out.extend_from_slice(scalar);
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn equal_messages_are_encoded_identically() {
+    use std::collections::HashMap;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Message {
+        z: u128,
+        floats: Vec<f64>,
+        map: HashMap<String, (bool, Option<String>)>,
+    }
+
+    let message = Message {
+        z: u128::MAX,
+        floats: vec![1.0, -0.0, 0.1, 2.5e300, -1e-7],
+        map: (0..10)
+            .map(|i| (format!("key \"{}\"", i), (i % 2 == 0, None)))
+            .collect(),
+    };
+    let mut codec = CanonicalJson::<Message, Message>::default();
+    let encoded = Pin::new(&mut codec).serialize(&message).unwrap();
+    let expected_map: Vec<_> = (0..10)
+        .map(|i| format!(r#""key \"{}\"":[{},null]"#, i, i % 2 == 0))
+        .collect();
+    assert_eq!(
+        std::str::from_utf8(&encoded).unwrap(),
+        format!(
+            r#"{{"floats":[1e0,0e0,1e-1,2.5e300,-1e-7],"map":{{{}}},"z":{}}}"#,
+            expected_map.join(","),
+            u128::MAX
+        )
+    );
+    let decoded = Pin::new(&mut codec)
+        .deserialize(&BytesMut::from(&encoded[..]))
+        .unwrap();
+    assert_eq!(decoded.floats[0], 1.0);
+    assert_eq!(decoded.z, u128::MAX);
+    assert_eq!(decoded.map, message.map);
+}
@@ -0,0 +1,287 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Splits large frames into fragments, so that one large message doesn't hold up every other
+//! message on the connection while it's written.
+//!
+//! Each fragment is prefixed by a header holding the ID of the message it belongs to and whether
+//! it's the message's last fragment. Messages of different streams take turns writing fragments;
+//! messages of the same stream are written one after another, so they arrive in order.
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use fnv::FnvHashMap;
+use futures::{prelude::*, ready, task::*};
+use pin_project::pin_project;
+use std::{
+    collections::VecDeque,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// The length of a fragment's header: a 4-byte message ID followed by a 1-byte flag.
+const HEADER_LEN: usize = 5;
+/// Marks the last fragment of a message.
+const LAST: u8 = 1;
+/// The number of messages that may wait to be written before the sink stops accepting more.
+const MAX_QUEUED: usize = 16;
+
+/// A message whose fragments are waiting to be written.
+#[derive(Debug)]
+struct Outgoing {
+    id: u32,
+    bytes: Bytes,
+}
+
+/// The messages of one stream, in the order they must be written.
+#[derive(Debug)]
+struct OutgoingStream {
+    key: u64,
+    messages: VecDeque<Outgoing>,
+}
+
+/// Fragments the frames written to, and reassembles the frames read from, a framed transport.
+/// Passes frames through untouched until fragmentation is enabled.
+#[pin_project]
+#[derive(Debug)]
+pub(super) struct Fragmented<T> {
+    #[pin]
+    inner: T,
+    /// The maximum length of a fragment's payload, if fragmentation is enabled.
+    fragment_len: Option<usize>,
+    /// The stream of the next frame to be written. Set by the serializing transport, which is the
+    /// only one that can see messages before they're encoded.
+    next_key: Arc<AtomicU64>,
+    next_message_id: u32,
+    /// Streams with messages waiting to be written, in the order they take turns.
+    outgoing: VecDeque<OutgoingStream>,
+    queued: usize,
+    /// The bytes of the queued messages that haven't yet been written.
+    queued_len: usize,
+    /// The most bytes of queued messages before the sink stops accepting more, if limited.
+    max_queued_len: Option<usize>,
+    /// Messages whose last fragment hasn't yet been read.
+    incoming: FnvHashMap<u32, BytesMut>,
+    /// The bytes of the messages whose last fragment hasn't yet been read.
+    incoming_len: usize,
+    /// The most bytes of messages whose last fragment hasn't yet been read, if limited.
+    max_incoming_len: Option<usize>,
+}
+
+impl<T> Fragmented<T> {
+    pub(super) fn new(inner: T) -> Self {
+        Fragmented {
+            inner,
+            fragment_len: None,
+            next_key: Arc::new(AtomicU64::new(0)),
+            next_message_id: 0,
+            outgoing: VecDeque::new(),
+            queued: 0,
+            queued_len: 0,
+            max_queued_len: None,
+            incoming: FnvHashMap::default(),
+            incoming_len: 0,
+            max_incoming_len: None,
+        }
+    }
+
+    pub(super) fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub(super) fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Splits frames longer than `fragment_len` into fragments.
+    pub(super) fn set_fragment_len(&mut self, fragment_len: usize) {
+        assert!(fragment_len > 0, "Fragments must hold at least one byte.");
+        self.fragment_len = Some(fragment_len);
+    }
+
+    /// Stops accepting frames to fragment while `len` bytes of them wait to be written.
+    pub(super) fn set_max_queued_len(&mut self, len: usize) {
+        self.max_queued_len = Some(len);
+    }
+
+    /// Fails to read once more than `len` bytes of messages are partially read.
+    pub(super) fn set_max_incoming_len(&mut self, len: usize) {
+        self.max_incoming_len = Some(len);
+    }
+
+    /// Returns the number of bytes of frames that wait to be written.
+    pub(super) fn queued_len(&self) -> usize {
+        self.queued_len
+    }
+
+    /// Returns the number of bytes of messages that are partially read.
+    pub(super) fn incoming_len(&self) -> usize {
+        self.incoming_len
+    }
+
+    /// Returns the handle used to set the stream of the next frame.
+    pub(super) fn next_key(&self) -> Arc<AtomicU64> {
+        self.next_key.clone()
+    }
+}
+
+impl<T> Fragmented<T>
+where
+    T: Sink<Bytes, Error = io::Error>,
+{
+    /// Writes the next fragment of the stream whose turn it is.
+    fn poll_write_fragment(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        ready!(this.inner.as_mut().poll_ready(cx)?);
+        let fragment_len = this.fragment_len.unwrap();
+        let mut stream = match this.outgoing.pop_front() {
+            Some(stream) => stream,
+            None => return Poll::Ready(Ok(())),
+        };
+        let message = stream.messages.front_mut().unwrap();
+        let payload = message
+            .bytes
+            .split_to(fragment_len.min(message.bytes.len()));
+        let last = message.bytes.is_empty();
+        *this.queued_len -= payload.len();
+
+        let mut fragment = BytesMut::with_capacity(HEADER_LEN + payload.len());
+        fragment.put_u32(message.id);
+        fragment.put_u8(if last { LAST } else { 0 });
+        fragment.put(payload);
+        this.inner.start_send(fragment.freeze())?;
+
+        if last {
+            stream.messages.pop_front();
+            *this.queued -= 1;
+        }
+        if !stream.messages.is_empty() {
+            this.outgoing.push_back(stream);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T> Sink<Bytes> for Fragmented<T>
+where
+    T: Sink<Bytes, Error = io::Error>,
+{
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.fragment_len.is_none() {
+            return self.project().inner.poll_ready(cx);
+        }
+        while self.queued >= MAX_QUEUED
+            || self
+                .max_queued_len
+                .is_some_and(|max| self.queued_len >= max && self.queued_len > 0)
+        {
+            ready!(self.as_mut().poll_write_fragment(cx)?);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, bytes: Bytes) -> io::Result<()> {
+        let this = self.project();
+        if this.fragment_len.is_none() {
+            return this.inner.start_send(bytes);
+        }
+        let key = this.next_key.load(Ordering::Relaxed);
+        let id = *this.next_message_id;
+        *this.next_message_id = id.wrapping_add(1);
+        *this.queued_len += bytes.len();
+        let message = Outgoing { id, bytes };
+        match this.outgoing.iter_mut().find(|stream| stream.key == key) {
+            Some(stream) => stream.messages.push_back(message),
+            None => this.outgoing.push_back(OutgoingStream {
+                key,
+                messages: vec![message].into(),
+            }),
+        }
+        *this.queued += 1;
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.outgoing.is_empty() {
+            ready!(self.as_mut().poll_write_fragment(cx)?);
+        }
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx)?);
+        self.project().inner.poll_close(cx)
+    }
+}
+
+impl<T> Stream for Fragmented<T>
+where
+    T: Stream<Item = io::Result<BytesMut>>,
+{
+    type Item = io::Result<BytesMut>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<BytesMut>>> {
+        let mut this = self.project();
+        if this.fragment_len.is_none() {
+            return this.inner.poll_next(cx);
+        }
+        loop {
+            let mut fragment = match ready!(this.inner.as_mut().poll_next(cx)?) {
+                Some(fragment) => fragment,
+                None => return Poll::Ready(None),
+            };
+            if fragment.len() < HEADER_LEN {
+                return Poll::Ready(Some(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Fragment of {} bytes is missing its header.",
+                        fragment.len()
+                    ),
+                ))));
+            }
+            let id = fragment.get_u32();
+            let last = fragment.get_u8() & LAST != 0;
+            let message = this.incoming.remove(&id);
+            if let Some(message) = &message {
+                *this.incoming_len -= message.len();
+            }
+            match (message, last) {
+                (None, true) => return Poll::Ready(Some(Ok(fragment))),
+                (Some(mut message), true) => {
+                    message.unsplit(fragment);
+                    return Poll::Ready(Some(Ok(message)));
+                }
+                (message, false) => {
+                    let message = match message {
+                        Some(mut message) => {
+                            message.unsplit(fragment);
+                            message
+                        }
+                        None => fragment,
+                    };
+                    *this.incoming_len += message.len();
+                    this.incoming.insert(id, message);
+                    if let Some(max) = *this.max_incoming_len {
+                        if *this.incoming_len > max {
+                            return Poll::Ready(Some(Err(io::Error::new(
+                                io::ErrorKind::OutOfMemory,
+                                format!(
+                                    "Partially read messages exceed the limit of {} bytes.",
+                                    max
+                                ),
+                            ))));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,126 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Delimits the frames of a transport.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use std::io;
+use tokio_util::codec::{length_delimited::LengthDelimitedCodec, Decoder, Encoder};
+
+/// The longest line read by default, matching the default maximum frame length of
+/// [`LengthDelimitedCodec`].
+const MAX_LINE_LEN: usize = 8 * 1024 * 1024;
+
+#[derive(Debug)]
+pub(super) enum FrameCodec {
+    /// Prefixes each frame with its length.
+    LengthDelimited(LengthDelimitedCodec),
+    /// Ends each frame with a newline.
+    Lines {
+        /// Where to resume searching for a newline in the bytes read so far.
+        next_index: usize,
+        /// The longest line read.
+        max_len: usize,
+    },
+}
+
+impl FrameCodec {
+    pub(super) fn length_delimited() -> Self {
+        FrameCodec::LengthDelimited(LengthDelimitedCodec::new())
+    }
+
+    pub(super) fn lines() -> Self {
+        FrameCodec::Lines {
+            next_index: 0,
+            max_len: MAX_LINE_LEN,
+        }
+    }
+
+    /// Returns the length of the longest frame read.
+    pub(super) fn max_frame_len(&self) -> usize {
+        match self {
+            FrameCodec::LengthDelimited(codec) => codec.max_frame_length(),
+            FrameCodec::Lines { max_len, .. } => *max_len,
+        }
+    }
+
+    /// Fails to read frames longer than `len`.
+    pub(super) fn set_max_frame_len(&mut self, len: usize) {
+        match self {
+            FrameCodec::LengthDelimited(codec) => codec.set_max_frame_length(len),
+            FrameCodec::Lines { max_len, .. } => *max_len = len,
+        }
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        let (next_index, max_len) = match self {
+            // The codec only fails on frames longer than the maximum.
+            FrameCodec::LengthDelimited(codec) => {
+                return codec
+                    .decode(src)
+                    .map_err(|e| io::Error::new(io::ErrorKind::OutOfMemory, e))
+            }
+            FrameCodec::Lines {
+                next_index,
+                max_len,
+            } => (next_index, *max_len),
+        };
+        loop {
+            let newline = match src[*next_index..].iter().position(|&b| b == b'\n') {
+                Some(newline) => *next_index + newline,
+                None if src.len() > max_len => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::OutOfMemory,
+                        "Line exceeds the maximum length.",
+                    ));
+                }
+                None => {
+                    *next_index = src.len();
+                    return Ok(None);
+                }
+            };
+            *next_index = 0;
+            let mut line = src.split_to(newline + 1);
+            line.truncate(newline);
+            // Accept the line endings of telnet, too.
+            if line.last() == Some(&b'\r') {
+                line.truncate(newline - 1);
+            }
+            // Skip blank lines, which are easy to type by accident.
+            if !line.is_empty() {
+                return Ok(Some(line));
+            }
+        }
+    }
+}
+
+impl Encoder for FrameCodec {
+    type Item = Bytes;
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: Bytes, dst: &mut BytesMut) -> io::Result<()> {
+        match self {
+            FrameCodec::LengthDelimited(codec) => codec.encode(frame, dst),
+            FrameCodec::Lines { .. } => {
+                if frame.contains(&b'\n') {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Newline-delimited frames can't contain newlines.",
+                    ));
+                }
+                dst.reserve(frame.len() + 1);
+                dst.put(frame);
+                dst.put_u8(b'\n');
+                Ok(())
+            }
+        }
+    }
+}
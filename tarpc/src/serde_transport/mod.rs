@@ -8,19 +8,163 @@
 
 #![deny(missing_docs)]
 
+use self::{fragment::Fragmented, framing::FrameCodec};
+use crate::{ClientMessage, ServerMessage};
 use futures::{prelude::*, task::*};
 use pin_project::pin_project;
 use serde::{Deserialize, Serialize};
-use std::{error::Error, io, pin::Pin};
+use std::{
+    error::Error,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_serde::{Framed as SerdeFramed, *};
-use tokio_util::codec::{length_delimited::LengthDelimitedCodec, Framed};
+use tokio_util::codec::Framed;
+
+#[cfg(feature = "canonical-json")]
+mod canonical_json;
+mod fragment;
+mod framing;
+#[cfg(feature = "negotiate")]
+#[cfg_attr(docsrs, doc(cfg(feature = "negotiate")))]
+pub mod negotiate;
+pub mod rate_limit;
+#[cfg(feature = "round-trip")]
+#[cfg_attr(docsrs, doc(cfg(feature = "round-trip")))]
+pub mod round_trip;
+pub mod sizes;
+
+#[cfg(feature = "canonical-json")]
+#[cfg_attr(docsrs, doc(cfg(feature = "canonical-json")))]
+pub use canonical_json::CanonicalJson;
 
 /// A transport that serializes to, and deserializes from, a [`TcpStream`].
 #[pin_project]
 pub struct Transport<S, Item, SinkItem, Codec> {
     #[pin]
-    inner: SerdeFramed<Framed<S, LengthDelimitedCodec>, Item, SinkItem, Codec>,
+    inner: SerdeFramed<Fragmented<Framed<S, FrameCodec>>, Item, SinkItem, Codec>,
+    /// Tells the fragmenting layer which stream each message belongs to.
+    next_key: Arc<AtomicU64>,
+    stream_key: Option<fn(&SinkItem) -> u64>,
+}
+
+/// A message that is one of many multiplexed over a connection.
+pub trait Multiplexed {
+    /// Returns the ID of the stream the message belongs to. Messages of the same stream are
+    /// delivered in the order they're sent.
+    fn stream_id(&self) -> u64;
+}
+
+impl<T> Multiplexed for ClientMessage<T> {
+    fn stream_id(&self) -> u64 {
+        match self {
+            ClientMessage::Request(request) | ClientMessage::StreamingRequest(request) => {
+                request.id
+            }
+            ClientMessage::StreamItem { request_id, .. }
+            | ClientMessage::StreamEnd { request_id }
+            | ClientMessage::WindowUpdate { request_id, .. }
+            | ClientMessage::Cancel { request_id, .. } => *request_id,
+            // Health checks and handshakes share a stream, apart from any request.
+            ClientMessage::HealthCheck { .. }
+            | ClientMessage::Handshake { .. }
+            | ClientMessage::_NonExhaustive => u64::MAX,
+        }
+    }
+}
+
+impl<T> Multiplexed for ServerMessage<T> {
+    fn stream_id(&self) -> u64 {
+        // Messages that aren't about a request, like notifications, share a stream.
+        self.request_id().unwrap_or(u64::MAX)
+    }
+}
+
+impl<S, Item, SinkItem, Codec> Transport<S, Item, SinkItem, Codec> {
+    /// Ends each message with a newline, rather than prefixing it with its length, so that a
+    /// connection can be typed into with `nc` or `telnet`, and its traffic read by line-oriented
+    /// tools while debugging. Lines may end with `\r\n`, and blank lines are skipped.
+    ///
+    /// Messages can't contain newlines, so the codec must not write them, as
+    /// [`Json`](tokio_serde::formats::Json) doesn't. Binary codecs, like bincode, and
+    /// [fragmentation](Transport::with_fragmentation), whose fragments have binary headers, don't
+    /// work with newline-delimited framing. Both peers must use newline-delimited framing.
+    pub fn with_newline_delimited(mut self) -> Self {
+        let codec = self.inner.get_mut().get_mut().codec_mut();
+        let max_frame_len = codec.max_frame_len();
+        *codec = FrameCodec::lines();
+        codec.set_max_frame_len(max_frame_len);
+        self
+    }
+
+    /// Limits the memory spent on messages that have been partially received to about `len`
+    /// bytes. A message longer than `len`, or fragments of messages that add up to more than
+    /// `len`, fail the transport with an [`OutOfMemory`](io::ErrorKind::OutOfMemory) error, which
+    /// a server reports to its client as [`LimitExceeded`](crate::ErrorCode::LimitExceeded), so
+    /// that a peer that sends a huge message, or starts many messages without finishing them, is
+    /// disconnected instead of exhausting the process's memory. The default limit is 8 MiB per
+    /// message, and unlimited for fragments.
+    pub fn with_max_receive_buffer(mut self, len: usize) -> Self {
+        let fragmented = self.inner.get_mut();
+        fragmented.set_max_incoming_len(len);
+        fragmented.get_mut().codec_mut().set_max_frame_len(len);
+        self
+    }
+
+    /// Limits the memory spent on messages waiting to be fragmented to about `len` bytes. Once
+    /// `len` bytes of messages are queued, the transport stops accepting more until they're
+    /// written, pushing back on whatever sends them, e.g. a server's response handlers. Without
+    /// fragmentation, each message is encoded into a buffer that accepts no more once it holds 8
+    /// KiB, so messages don't queue in the transport.
+    pub fn with_max_send_buffer(mut self, len: usize) -> Self {
+        self.inner.get_mut().set_max_queued_len(len);
+        self
+    }
+
+    /// Returns the memory the transport is spending on buffered messages.
+    pub fn buffered(&self) -> Buffered {
+        let fragmented = self.inner.get_ref();
+        Buffered {
+            received: fragmented.get_ref().read_buffer().len() + fragmented.incoming_len(),
+            queued: fragmented.queued_len(),
+        }
+    }
+}
+
+/// The memory a [`Transport`] is spending on buffered messages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Buffered {
+    /// The bytes that have been read but not yet decoded into messages, including the fragments of
+    /// partially received messages.
+    pub received: usize,
+    /// The bytes of messages waiting to be fragmented and written.
+    pub queued: usize,
+}
+
+impl<S, Item, SinkItem, Codec> Transport<S, Item, SinkItem, Codec>
+where
+    SinkItem: Multiplexed,
+{
+    /// Splits messages longer than `fragment_len` bytes into fragments, which are interleaved with
+    /// the fragments of other requests' messages. This keeps a large reply from delaying every
+    /// other message on the connection until it's written.
+    ///
+    /// Fragmentation changes the framing, so both peers must enable it.
+    ///
+    /// # Panics
+    ///
+    /// If `fragment_len` is zero.
+    pub fn with_fragmentation(mut self, fragment_len: usize) -> Self {
+        self.inner.get_mut().set_fragment_len(fragment_len);
+        self.stream_key = Some(SinkItem::stream_id);
+        self
+    }
 }
 
 impl<S, Item, SinkItem, Codec, CodecError> Stream for Transport<S, Item, SinkItem, Codec>
@@ -29,7 +173,7 @@ where
     Item: for<'a> Deserialize<'a>,
     Codec: Deserializer<Item>,
     CodecError: Into<Box<dyn std::error::Error + Send + Sync>>,
-    SerdeFramed<Framed<S, LengthDelimitedCodec>, Item, SinkItem, Codec>:
+    SerdeFramed<Fragmented<Framed<S, FrameCodec>>, Item, SinkItem, Codec>:
         Stream<Item = Result<Item, CodecError>>,
 {
     type Item = io::Result<Item>;
@@ -40,7 +184,7 @@ where
             Poll::Ready(None) => Poll::Ready(None),
             Poll::Ready(Some(Ok::<_, CodecError>(next))) => Poll::Ready(Some(Ok(next))),
             Poll::Ready(Some(Err::<_, CodecError>(e))) => {
-                Poll::Ready(Some(Err(io::Error::new(io::ErrorKind::Other, e))))
+                Poll::Ready(Some(Err(io::Error::other(e))))
             }
         }
     }
@@ -52,7 +196,7 @@ where
     SinkItem: Serialize,
     Codec: Serializer<SinkItem>,
     CodecError: Into<Box<dyn Error + Send + Sync>>,
-    SerdeFramed<Framed<S, LengthDelimitedCodec>, Item, SinkItem, Codec>:
+    SerdeFramed<Fragmented<Framed<S, FrameCodec>>, Item, SinkItem, Codec>:
         Sink<SinkItem, Error = CodecError>,
 {
     type Error = io::Error;
@@ -62,10 +206,11 @@ where
     }
 
     fn start_send(self: Pin<&mut Self>, item: SinkItem) -> io::Result<()> {
-        self.project()
-            .inner
-            .start_send(item)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        let this = self.project();
+        if let Some(stream_key) = this.stream_key {
+            this.next_key.store(stream_key(&item), Ordering::Relaxed);
+        }
+        this.inner.start_send(item).map_err(io::Error::other)
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
@@ -80,7 +225,7 @@ where
 fn convert<E: Into<Box<dyn Error + Send + Sync>>>(
     poll: Poll<Result<(), E>>,
 ) -> Poll<io::Result<()>> {
-    poll.map(|ready| ready.map_err(|e| io::Error::new(io::ErrorKind::Other, e)))
+    poll.map(|ready| ready.map_err(io::Error::other))
 }
 
 impl<S, Item, SinkItem, Codec> From<(S, Codec)> for Transport<S, Item, SinkItem, Codec>
@@ -91,8 +236,11 @@ where
     Codec: Serializer<SinkItem> + Deserializer<Item>,
 {
     fn from((inner, codec): (S, Codec)) -> Self {
+        let inner = Fragmented::new(Framed::new(inner, FrameCodec::length_delimited()));
         Transport {
-            inner: SerdeFramed::new(Framed::new(inner, LengthDelimitedCodec::new()), codec),
+            next_key: inner.next_key(),
+            stream_key: None,
+            inner: SerdeFramed::new(inner, codec),
         }
     }
 }
@@ -103,27 +251,37 @@ where
 pub mod tcp {
     use {
         super::*,
-        futures::ready,
-        std::{marker::PhantomData, net::SocketAddr},
+        crate::client::Resolver,
+        futures::{future, ready, stream, stream::FuturesUnordered},
+        log::debug,
+        net2::TcpBuilder,
+        std::{
+            collections::VecDeque,
+            fmt,
+            marker::PhantomData,
+            net::{IpAddr, SocketAddr},
+            sync::Arc,
+            time::Duration,
+        },
         tokio::net::{TcpListener, TcpStream, ToSocketAddrs},
     };
 
-    mod private {
-        use super::*;
+    #[cfg(unix)]
+    pub mod handoff;
+    pub mod proxy;
 
-        pub trait Sealed {}
-
-        impl<Item, SinkItem, Codec> Sealed for Transport<TcpStream, Item, SinkItem, Codec> {}
-    }
+    /// How long to wait for a connection attempt before starting the next one in parallel, as
+    /// recommended by RFC 8305.
+    const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
 
     impl<Item, SinkItem, Codec> Transport<TcpStream, Item, SinkItem, Codec> {
         /// Returns the peer address of the underlying TcpStream.
         pub fn peer_addr(&self) -> io::Result<SocketAddr> {
-            self.inner.get_ref().get_ref().peer_addr()
+            self.inner.get_ref().get_ref().get_ref().peer_addr()
         }
         /// Returns the local address of the underlying TcpStream.
         pub fn local_addr(&self) -> io::Result<SocketAddr> {
-            self.inner.get_ref().get_ref().local_addr()
+            self.inner.get_ref().get_ref().get_ref().local_addr()
         }
     }
 
@@ -141,6 +299,8 @@ pub mod tcp {
     }
 
     /// Connects to `addr`, wrapping the connection in a JSON transport.
+    ///
+    /// If `addr` resolves to several addresses, they're raced as by [`connect_resolved`].
     pub async fn connect<A, Item, SinkItem, Codec>(
         addr: A,
         codec: Codec,
@@ -151,7 +311,329 @@ pub mod tcp {
         SinkItem: Serialize,
         Codec: Serializer<SinkItem> + Deserializer<Item>,
     {
-        Ok(new(TcpStream::connect(addr).await?, codec))
+        Connector::new().connect(addr, codec).await
+    }
+
+    /// Resolves `name` with `resolver` and connects to one of its addresses, wrapping the
+    /// connection in a JSON transport.
+    ///
+    /// The addresses are raced following RFC 8305, "Happy Eyeballs": attempts alternate between
+    /// IPv6 and IPv4 addresses, starting with the family of the first address, and each starts
+    /// when the previous attempt fails or has been pending for 250ms. The first connection
+    /// established wins, and the other attempts are abandoned, so a name with unreachable
+    /// addresses, e.g. on a network with broken IPv6, connects without waiting for them to time
+    /// out.
+    ///
+    /// Fails with the error of the last attempt to fail, or with [`io::ErrorKind::NotFound`] if
+    /// the name has no addresses.
+    pub async fn connect_resolved<R, Item, SinkItem, Codec>(
+        resolver: &R,
+        name: &str,
+        codec: Codec,
+    ) -> io::Result<Transport<TcpStream, Item, SinkItem, Codec>>
+    where
+        R: Resolver,
+        Item: for<'de> Deserialize<'de>,
+        SinkItem: Serialize,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+    {
+        Connector::new()
+            .connect_resolved(resolver, name, codec)
+            .await
+    }
+
+    /// Configures outgoing connections, e.g. to originate them from a specific local address on a
+    /// multi-homed host.
+    ///
+    /// ```
+    /// # use tarpc::serde_transport::tcp::Connector;
+    /// # use std::net::Ipv4Addr;
+    /// let connector = Connector::new().bind(Ipv4Addr::LOCALHOST.into());
+    /// ```
+    #[derive(Clone, Debug, Default)]
+    pub struct Connector {
+        local_addr: Option<IpAddr>,
+        device: Option<String>,
+    }
+
+    impl Connector {
+        /// Returns a connector that leaves the local end of connections to the operating system.
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Binds outgoing connections to the local address `local_addr`, with a port chosen by
+        /// the operating system. Only remote addresses of the same family are connected to.
+        pub fn bind(mut self, local_addr: IpAddr) -> Self {
+            self.local_addr = Some(local_addr);
+            self
+        }
+
+        /// Binds outgoing connections to the network interface `device`, e.g. `"eth1"` or the
+        /// interface of a VRF, so that they're routed through it. Binding to a device usually
+        /// requires the `CAP_NET_RAW` capability.
+        #[cfg(target_os = "linux")]
+        pub fn bind_device(mut self, device: impl Into<String>) -> Self {
+            self.device = Some(device.into());
+            self
+        }
+
+        /// Connects to `addr` as by [`connect`].
+        pub async fn connect<A, Item, SinkItem, Codec>(
+            &self,
+            addr: A,
+            codec: Codec,
+        ) -> io::Result<Transport<TcpStream, Item, SinkItem, Codec>>
+        where
+            A: ToSocketAddrs,
+            Item: for<'de> Deserialize<'de>,
+            SinkItem: Serialize,
+            Codec: Serializer<SinkItem> + Deserializer<Item>,
+        {
+            Ok(new(self.stream(addr).await?, codec))
+        }
+
+        /// Connects to `addr`, racing its addresses as described by [`connect_resolved`].
+        async fn stream<A: ToSocketAddrs>(&self, addr: A) -> io::Result<TcpStream> {
+            let addrs = tokio::net::lookup_host(addr).await?.collect();
+            self.race(addrs).await
+        }
+
+        /// Resolves `name` with `resolver` and connects to one of its addresses as by
+        /// [`connect_resolved`].
+        pub async fn connect_resolved<R, Item, SinkItem, Codec>(
+            &self,
+            resolver: &R,
+            name: &str,
+            codec: Codec,
+        ) -> io::Result<Transport<TcpStream, Item, SinkItem, Codec>>
+        where
+            R: Resolver,
+            Item: for<'de> Deserialize<'de>,
+            SinkItem: Serialize,
+            Codec: Serializer<SinkItem> + Deserializer<Item>,
+        {
+            let addrs = resolver.resolve(name).await?;
+            if addrs.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No addresses for '{}'.", name),
+                ));
+            }
+            Ok(new(self.race(addrs).await?, codec))
+        }
+
+        /// Connects to one of `addrs`, racing them as described by [`connect_resolved`].
+        async fn race(&self, mut addrs: Vec<SocketAddr>) -> io::Result<TcpStream> {
+            if let Some(local_addr) = self.local_addr {
+                addrs.retain(|addr| addr.is_ipv6() == local_addr.is_ipv6());
+            }
+            let mut addrs = interleave_families(addrs).into_iter();
+            let mut attempts = FuturesUnordered::new();
+            let mut error = io::Error::new(io::ErrorKind::NotFound, "No addresses to connect to.");
+            loop {
+                match addrs.next() {
+                    Some(addr) => attempts.push(self.attempt(addr).map(move |conn| (addr, conn))),
+                    None if attempts.is_empty() => return Err(error),
+                    None => {}
+                }
+                let attempt = if addrs.len() > 0 {
+                    match tokio::time::timeout(CONNECTION_ATTEMPT_DELAY, attempts.next()).await {
+                        Ok(attempt) => attempt,
+                        // Give the next address a chance too.
+                        Err(_) => continue,
+                    }
+                } else {
+                    attempts.next().await
+                };
+                match attempt {
+                    Some((_, Ok(conn))) => return Ok(conn),
+                    Some((addr, Err(e))) => {
+                        debug!("Failed to connect to {}: {}", addr, e);
+                        error = e;
+                    }
+                    None => {}
+                }
+            }
+        }
+
+        /// Connects to `addr`, binding the local end as configured.
+        async fn attempt(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+            if self.local_addr.is_none() && self.device.is_none() {
+                return TcpStream::connect(addr).await;
+            }
+            let builder = match addr {
+                SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+                SocketAddr::V6(_) => TcpBuilder::new_v6()?,
+            };
+            #[cfg(target_os = "linux")]
+            {
+                if let Some(ref device) = self.device {
+                    bind_device(&builder, device)?;
+                }
+            }
+            if let Some(local_addr) = self.local_addr {
+                builder.bind(SocketAddr::new(local_addr, 0))?;
+            }
+            TcpStream::connect_std(builder.to_tcp_stream()?, &addr).await
+        }
+    }
+
+    /// Binds the socket of `builder` to the network interface `device`.
+    #[cfg(target_os = "linux")]
+    fn bind_device(builder: &TcpBuilder, device: &str) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let fd = builder.as_raw_fd();
+        // SAFETY: `fd` is a valid socket for the duration of the call, and the option value
+        // points to `device.len()` readable bytes.
+        let result = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_BINDTODEVICE,
+                device.as_ptr() as *const libc::c_void,
+                device.len() as libc::socklen_t,
+            )
+        };
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Reorders `addrs` to alternate between address families, starting with the family of the
+    /// first address and otherwise keeping their order.
+    fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let first_is_ipv6 = match addrs.first() {
+            Some(addr) => addr.is_ipv6(),
+            None => return addrs,
+        };
+        let (mut first, mut second): (VecDeque<_>, VecDeque<_>) = addrs
+            .into_iter()
+            .partition(|addr| addr.is_ipv6() == first_is_ipv6);
+        let mut interleaved = Vec::with_capacity(first.len() + second.len());
+        while !first.is_empty() || !second.is_empty() {
+            interleaved.extend(first.pop_front());
+            interleaved.extend(second.pop_front());
+        }
+        interleaved
+    }
+
+    #[test]
+    fn families_are_interleaved() {
+        let v4 = |port| SocketAddr::from(([127, 0, 0, 1], port));
+        let v6 = |port| SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], port));
+        assert_eq!(
+            interleave_families(vec![v6(1), v6(2), v6(3), v4(4), v4(5)]),
+            [v6(1), v4(4), v6(2), v4(5), v6(3)]
+        );
+        assert_eq!(
+            interleave_families(vec![v4(1), v6(2), v4(3)]),
+            [v4(1), v6(2), v4(3)]
+        );
+        assert_eq!(interleave_families(vec![]), []);
+    }
+
+    #[tokio::test]
+    async fn race_skips_refused_addresses() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let up = listener.local_addr()?;
+        let down = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let conn = Connector::new().race(vec![down, up]).await?;
+        assert_eq!(conn.peer_addr()?, up);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connector_binds_local_address() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let local_addr = IpAddr::from([127, 0, 0, 2]);
+        let connector = Connector::new().bind(local_addr);
+        let conn = connector.race(vec![addr]).await?;
+        assert_eq!(conn.local_addr()?.ip(), local_addr);
+
+        // The only remote address is of the other family.
+        let connector = Connector::new().bind(IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1]));
+        assert_matches::assert_matches!(
+            connector.race(vec![addr]).await,
+            Err(e) if e.kind() == io::ErrorKind::NotFound
+        );
+        Ok(())
+    }
+
+    /// A [`Resolver`] that looks names up in DNS. A name is a host and port, e.g.
+    /// `"example.com:443"`.
+    ///
+    /// DNS doesn't notify of changes, so watching a name polls it instead: a resolver created by
+    /// [`Dns::refresh_every`] re-resolves watched names periodically and yields their addresses
+    /// whenever they change. The system resolver doesn't expose the records' TTLs, so the refresh
+    /// interval should be set to match them.
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct Dns {
+        refresh: Option<Duration>,
+    }
+
+    impl Dns {
+        /// Returns a resolver that never re-resolves watched names, so watching yields nothing.
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Returns a resolver that re-resolves watched names every `refresh`.
+        pub fn refresh_every(refresh: Duration) -> Self {
+            Dns {
+                refresh: Some(refresh),
+            }
+        }
+    }
+
+    /// Looks up the addresses of `name`, sorted so that lookups can be compared.
+    async fn lookup(name: String) -> io::Result<Vec<SocketAddr>> {
+        let mut addrs: Vec<_> = tokio::net::lookup_host(name).await?.collect();
+        addrs.sort_unstable();
+        Ok(addrs)
+    }
+
+    impl Resolver for Dns {
+        type Fut = future::BoxFuture<'static, io::Result<Vec<SocketAddr>>>;
+        type Changes = stream::BoxStream<'static, Vec<SocketAddr>>;
+
+        fn resolve(&self, name: &str) -> Self::Fut {
+            lookup(name.to_string()).boxed()
+        }
+
+        fn watch(&self, name: &str) -> Self::Changes {
+            let refresh = match self.refresh {
+                Some(refresh) => refresh,
+                None => return stream::pending().boxed(),
+            };
+            let name = name.to_string();
+            // The first lookup happens immediately, and is what later lookups are compared to.
+            tokio::time::interval(refresh)
+                .then(move |_| {
+                    let name = name.clone();
+                    lookup(name.clone()).map(move |addrs| {
+                        addrs
+                            .map_err(|e| debug!("Failed to re-resolve {}: {}", name, e))
+                            .ok()
+                    })
+                })
+                .filter_map(future::ready)
+                .scan(None, |last: &mut Option<Vec<SocketAddr>>, addrs| {
+                    let changed = match last {
+                        Some(last) => *last != addrs,
+                        None => false,
+                    };
+                    let change = if changed { Some(addrs.clone()) } else { None };
+                    *last = Some(addrs);
+                    future::ready(Some(change))
+                })
+                .filter_map(future::ready)
+                .boxed()
+        }
     }
 
     /// Listens on `addr`, wrapping accepted connections in JSON transports.
@@ -165,31 +647,184 @@ pub mod tcp {
         Codec: Serializer<SinkItem> + Deserializer<Item>,
         CodecFn: Fn() -> Codec,
     {
-        let listener = TcpListener::bind(addr).await?;
+        incoming(TcpListener::bind(addr).await?, codec_fn)
+    }
+
+    /// Listens on `listener`, an already-bound socket, wrapping accepted connections in JSON
+    /// transports.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a tokio runtime.
+    pub fn from_std<Item, SinkItem, Codec, CodecFn>(
+        listener: std::net::TcpListener,
+        codec_fn: CodecFn,
+    ) -> io::Result<Incoming<Item, SinkItem, Codec, CodecFn>>
+    where
+        Item: for<'de> Deserialize<'de>,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        incoming(TcpListener::from_std(listener)?, codec_fn)
+    }
+
+    /// Listens on the sockets passed to this process by systemd socket activation, wrapping
+    /// accepted connections in JSON transports. Returns no listeners if the process wasn't
+    /// socket-activated.
+    ///
+    /// Socket activation lets systemd bind the sockets, e.g. to privileged ports, before starting
+    /// the service unprivileged. The service's socket unit must pass only TCP listening sockets,
+    /// i.e. use `ListenStream=` with `Accept=no`. The listeners are in the order of the unit's
+    /// `ListenStream=` lines, and can be served together with
+    /// [`Listeners`](crate::server::Listeners).
+    ///
+    /// The `LISTEN_PID`, `LISTEN_FDS`, and `LISTEN_FDNAMES` environment variables are removed, so
+    /// that the sockets are adopted only once and aren't passed on to child processes.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a tokio runtime.
+    #[cfg(unix)]
+    pub fn activated<Item, SinkItem, Codec, CodecFn>(
+        codec_fn: CodecFn,
+    ) -> io::Result<Vec<Incoming<Item, SinkItem, Codec, CodecFn>>>
+    where
+        Item: for<'de> Deserialize<'de>,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec + Clone,
+    {
+        use std::{env, os::unix::io::FromRawFd};
+
+        let fds = activated_fds(
+            env::var("LISTEN_PID").ok().as_deref(),
+            env::var("LISTEN_FDS").ok().as_deref(),
+            std::process::id(),
+        );
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+        fds.map(|fd| {
+            // SAFETY: systemd passed `fd` to this process to own, and removing the environment
+            // variables above ensures that it's adopted only once. Setting FD_CLOEXEC only
+            // changes the descriptor's flags.
+            let listener = unsafe {
+                if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                std::net::TcpListener::from_raw_fd(fd)
+            };
+            from_std(listener, codec_fn.clone())
+        })
+        .collect()
+    }
+
+    /// The first file descriptor passed by socket activation, `SD_LISTEN_FDS_START` in
+    /// sd_listen_fds(3).
+    #[cfg(unix)]
+    const LISTEN_FDS_START: std::os::unix::io::RawFd = 3;
+
+    /// Returns the file descriptors passed by socket activation, given the values of the
+    /// `LISTEN_PID` and `LISTEN_FDS` environment variables and the id of this process. The
+    /// variables are ignored if they were meant for another process, e.g. this one's parent.
+    #[cfg(unix)]
+    fn activated_fds(
+        listen_pid: Option<&str>,
+        listen_fds: Option<&str>,
+        pid: u32,
+    ) -> std::ops::Range<std::os::unix::io::RawFd> {
+        let listen_pid = listen_pid.and_then(|listen_pid| listen_pid.parse::<u32>().ok());
+        let listen_fds =
+            listen_fds.and_then(|listen_fds| listen_fds.parse::<std::os::unix::io::RawFd>().ok());
+        match (listen_pid, listen_fds) {
+            (Some(listen_pid), Some(listen_fds)) if listen_pid == pid && listen_fds > 0 => {
+                LISTEN_FDS_START..LISTEN_FDS_START + listen_fds
+            }
+            _ => LISTEN_FDS_START..LISTEN_FDS_START,
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn activated_fds_are_for_this_process() {
+        assert_eq!(activated_fds(Some("7"), Some("2"), 7), 3..5);
+        assert_eq!(activated_fds(Some("7"), Some("2"), 8).len(), 0);
+        assert_eq!(activated_fds(None, Some("2"), 7).len(), 0);
+        assert_eq!(activated_fds(Some("7"), None, 7).len(), 0);
+        assert_eq!(activated_fds(Some("7"), Some("-1"), 7).len(), 0);
+        assert_eq!(activated_fds(Some("seven"), Some("2"), 7).len(), 0);
+    }
+
+    fn incoming<Item, SinkItem, Codec, CodecFn>(
+        listener: TcpListener,
+        codec_fn: CodecFn,
+    ) -> io::Result<Incoming<Item, SinkItem, Codec, CodecFn>> {
         let local_addr = listener.local_addr()?;
         Ok(Incoming {
             listener,
             codec_fn,
             local_addr,
+            accept: None,
             ghost: PhantomData,
         })
     }
 
+    /// Decides whether to accept a connection from a peer address.
+    type PeerFilter = Arc<dyn Fn(&SocketAddr) -> bool + Send + Sync>;
+
     /// A [`TcpListener`] that wraps connections in JSON transports.
     #[pin_project]
-    #[derive(Debug)]
     pub struct Incoming<Item, SinkItem, Codec, CodecFn> {
         listener: TcpListener,
         local_addr: SocketAddr,
         codec_fn: CodecFn,
+        /// Decides which peers' connections are accepted, if not all of them.
+        accept: Option<PeerFilter>,
         ghost: PhantomData<(Item, SinkItem, Codec)>,
     }
 
+    impl<Item, SinkItem, Codec, CodecFn: fmt::Debug> fmt::Debug
+        for Incoming<Item, SinkItem, Codec, CodecFn>
+    {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.debug_struct("Incoming")
+                .field("listener", &self.listener)
+                .field("local_addr", &self.local_addr)
+                .field("codec_fn", &self.codec_fn)
+                .field("filtered", &self.accept.is_some())
+                .finish()
+        }
+    }
+
     impl<Item, SinkItem, Codec, CodecFn> Incoming<Item, SinkItem, Codec, CodecFn> {
         /// Returns the address being listened on.
         pub fn local_addr(&self) -> SocketAddr {
             self.local_addr
         }
+
+        /// Accepts only the connections of peers for which `accept` returns true, e.g. those on
+        /// an allowlist, or not on a denylist.
+        ///
+        /// `accept` is called with each peer's address as soon as its connection is accepted,
+        /// before anything is read from it or a transport is created for it, so it should be
+        /// cheap. Rejected connections are closed immediately.
+        ///
+        /// ```no_run
+        /// # use tarpc::serde_transport::tcp;
+        /// # use tokio_serde::formats::Json;
+        /// # async fn listen() -> std::io::Result<()> {
+        /// let incoming = tcp::listen::<_, String, String, _, _>("0.0.0.0:5000", Json::default)
+        ///     .await?
+        ///     .accept_peers(|peer| peer.ip().is_loopback());
+        /// # Ok(())
+        /// # }
+        /// ```
+        pub fn accept_peers<F>(mut self, accept: F) -> Self
+        where
+            F: Fn(&SocketAddr) -> bool + Send + Sync + 'static,
+        {
+            self.accept = Some(Arc::new(accept));
+            self
+        }
     }
 
     impl<Item, SinkItem, Codec, CodecFn> Stream for Incoming<Item, SinkItem, Codec, CodecFn>
@@ -202,19 +837,40 @@ pub mod tcp {
         type Item = io::Result<Transport<TcpStream, Item, SinkItem, Codec>>;
 
         fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-            let next =
-                ready!(Pin::new(&mut self.as_mut().project().listener.incoming()).poll_next(cx)?);
-            Poll::Ready(next.map(|conn| Ok(new(conn, (self.codec_fn)()))))
+            loop {
+                let conn = match ready!(
+                    Pin::new(&mut self.as_mut().project().listener.incoming()).poll_next(cx)?
+                ) {
+                    Some(conn) => conn,
+                    None => return Poll::Ready(None),
+                };
+                if let Some(ref accept) = self.accept {
+                    match conn.peer_addr() {
+                        Ok(peer) if accept(&peer) => {}
+                        Ok(peer) => {
+                            debug!("Rejecting connection from {}.", peer);
+                            continue;
+                        }
+                        // The connection's already gone.
+                        Err(e) => {
+                            debug!("Dropping connection without a peer address: {}", e);
+                            continue;
+                        }
+                    }
+                }
+                return Poll::Ready(Some(Ok(new(conn, (self.codec_fn)()))));
+            }
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Transport;
+    use super::{Multiplexed, Transport};
     use assert_matches::assert_matches;
     use futures::{task::*, Sink, Stream};
     use pin_utils::pin_mut;
+    use serde::{Deserialize, Serialize};
     use std::{
         io::{self, Cursor},
         pin::Pin,
@@ -223,7 +879,7 @@ mod tests {
     use tokio_serde::formats::SymmetricalJson;
 
     fn ctx() -> Context<'static> {
-        Context::from_waker(&noop_waker_ref())
+        Context::from_waker(noop_waker_ref())
     }
 
     #[test]
@@ -323,4 +979,277 @@ mod tests {
         assert_matches!(transport.poll_flush(&mut ctx()), Poll::Ready(Ok(())));
         assert_eq!(writer, b"\x00\x00\x00\x18\"Test one, check check.\"");
     }
+
+    #[test]
+    fn test_fragmentation() {
+        #[derive(Debug, Serialize, Deserialize)]
+        struct Keyed(u64, String);
+
+        impl Multiplexed for Keyed {
+            fn stream_id(&self) -> u64 {
+                self.0
+            }
+        }
+
+        struct TestIo(Cursor<Vec<u8>>);
+
+        impl AsyncRead for TestIo {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &mut [u8],
+            ) -> Poll<io::Result<usize>> {
+                AsyncRead::poll_read(Pin::new(&mut self.0), cx, buf)
+            }
+        }
+
+        impl AsyncWrite for TestIo {
+            fn poll_write(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                AsyncWrite::poll_write(Pin::new(self.0.get_mut()), cx, buf)
+            }
+
+            fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                AsyncWrite::poll_flush(Pin::new(self.0.get_mut()), cx)
+            }
+
+            fn poll_shutdown(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+            ) -> Poll<io::Result<()>> {
+                AsyncWrite::poll_shutdown(Pin::new(self.0.get_mut()), cx)
+            }
+        }
+
+        let transport = Transport::from((
+            TestIo(Cursor::new(vec![])),
+            SymmetricalJson::<Keyed>::default(),
+        ))
+        .with_fragmentation(4);
+        pin_mut!(transport);
+        let large = "a large message that takes many fragments".to_string();
+        for message in [Keyed(0, large.clone()), Keyed(1, "small".into())] {
+            assert_matches!(
+                transport.as_mut().poll_ready(&mut ctx()),
+                Poll::Ready(Ok(()))
+            );
+            assert_matches!(transport.as_mut().start_send(message), Ok(()));
+        }
+        assert_matches!(
+            transport.as_mut().poll_flush(&mut ctx()),
+            Poll::Ready(Ok(()))
+        );
+
+        let written = transport
+            .inner
+            .get_ref()
+            .get_ref()
+            .get_ref()
+            .0
+            .get_ref()
+            .clone();
+        let transport = Transport::from((
+            TestIo(Cursor::new(written)),
+            SymmetricalJson::<Keyed>::default(),
+        ))
+        .with_fragmentation(4);
+        pin_mut!(transport);
+        // The small message isn't stuck behind the large one.
+        assert_matches!(
+            transport.as_mut().poll_next(&mut ctx()),
+            Poll::Ready(Some(Ok(Keyed(1, ref s)))) if s == "small");
+        assert_matches!(
+            transport.as_mut().poll_next(&mut ctx()),
+            Poll::Ready(Some(Ok(Keyed(0, ref s)))) if *s == large);
+    }
+
+    #[test]
+    fn test_buffer_limits() {
+        #[derive(Debug, Serialize, Deserialize)]
+        struct Keyed(u64, String);
+
+        impl Multiplexed for Keyed {
+            fn stream_id(&self) -> u64 {
+                self.0
+            }
+        }
+
+        /// Reads `0`, and writes nothing until `writable` is set.
+        struct TestIo(Cursor<Vec<u8>>, Vec<u8>, bool);
+
+        impl AsyncRead for TestIo {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &mut [u8],
+            ) -> Poll<io::Result<usize>> {
+                AsyncRead::poll_read(Pin::new(&mut self.0), cx, buf)
+            }
+        }
+
+        impl AsyncWrite for TestIo {
+            fn poll_write(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                if !self.2 {
+                    return Poll::Pending;
+                }
+                AsyncWrite::poll_write(Pin::new(&mut self.1), cx, buf)
+            }
+
+            fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                if !self.2 {
+                    return Poll::Pending;
+                }
+                AsyncWrite::poll_flush(Pin::new(&mut self.1), cx)
+            }
+
+            fn poll_shutdown(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+            ) -> Poll<io::Result<()>> {
+                AsyncWrite::poll_shutdown(Pin::new(&mut self.1), cx)
+            }
+        }
+
+        let transport = Transport::from((
+            TestIo(Cursor::new(vec![]), vec![], false),
+            SymmetricalJson::<Keyed>::default(),
+        ))
+        .with_fragmentation(1024)
+        .with_max_send_buffer(4096);
+        pin_mut!(transport);
+        let large = "a".repeat(32 * 1024);
+        assert_matches!(
+            transport.as_mut().poll_ready(&mut ctx()),
+            Poll::Ready(Ok(()))
+        );
+        assert_matches!(
+            transport.as_mut().start_send(Keyed(0, large.clone())),
+            Ok(())
+        );
+        assert!(transport.buffered().queued > large.len());
+        // The peer isn't reading, so the transport pushes back instead of queuing more.
+        assert_matches!(transport.as_mut().poll_ready(&mut ctx()), Poll::Pending);
+
+        transport.as_mut().inner.get_mut().get_mut().get_mut().2 = true;
+        assert_matches!(
+            transport.as_mut().poll_flush(&mut ctx()),
+            Poll::Ready(Ok(()))
+        );
+        assert_eq!(transport.buffered().queued, 0);
+        let written = transport.inner.get_ref().get_ref().get_ref().1.clone();
+
+        let transport = Transport::from((
+            TestIo(Cursor::new(written.clone()), vec![], true),
+            SymmetricalJson::<Keyed>::default(),
+        ))
+        .with_fragmentation(1024)
+        .with_max_receive_buffer(64 * 1024);
+        pin_mut!(transport);
+        assert_matches!(
+            transport.as_mut().poll_next(&mut ctx()),
+            Poll::Ready(Some(Ok(Keyed(0, ref s)))) if *s == large);
+
+        let transport = Transport::from((
+            TestIo(Cursor::new(written), vec![], true),
+            SymmetricalJson::<Keyed>::default(),
+        ))
+        .with_fragmentation(1024)
+        .with_max_receive_buffer(16 * 1024);
+        pin_mut!(transport);
+        assert_matches!(
+            transport.as_mut().poll_next(&mut ctx()),
+            Poll::Ready(Some(Err(_)))
+        );
+    }
+
+    #[test]
+    fn test_newline_delimited() {
+        struct TestIo(Cursor<Vec<u8>>, Vec<u8>);
+
+        impl AsyncRead for TestIo {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &mut [u8],
+            ) -> Poll<io::Result<usize>> {
+                AsyncRead::poll_read(Pin::new(&mut self.0), cx, buf)
+            }
+        }
+
+        impl AsyncWrite for TestIo {
+            fn poll_write(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                AsyncWrite::poll_write(Pin::new(&mut self.1), cx, buf)
+            }
+
+            fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                AsyncWrite::poll_flush(Pin::new(&mut self.1), cx)
+            }
+
+            fn poll_shutdown(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+            ) -> Poll<io::Result<()>> {
+                AsyncWrite::poll_shutdown(Pin::new(&mut self.1), cx)
+            }
+        }
+
+        let typed = b"\"Test one\"\r\n\n\"check check.\"\n".to_vec();
+        let transport = Transport::from((
+            TestIo(Cursor::new(typed), vec![]),
+            SymmetricalJson::<String>::default(),
+        ))
+        .with_newline_delimited();
+        pin_mut!(transport);
+
+        assert_matches!(
+            transport.as_mut().poll_next(&mut ctx()),
+            Poll::Ready(Some(Ok(ref s))) if s == "Test one");
+        assert_matches!(
+            transport.as_mut().poll_next(&mut ctx()),
+            Poll::Ready(Some(Ok(ref s))) if s == "check check.");
+        assert_matches!(transport.as_mut().poll_next(&mut ctx()), Poll::Ready(None));
+
+        assert_matches!(
+            transport.as_mut().poll_ready(&mut ctx()),
+            Poll::Ready(Ok(()))
+        );
+        assert_matches!(transport.as_mut().start_send("Two\nlines".into()), Ok(()));
+        assert_matches!(
+            transport.as_mut().poll_flush(&mut ctx()),
+            Poll::Ready(Ok(()))
+        );
+        assert_eq!(
+            transport.inner.get_ref().get_ref().get_ref().1,
+            b"\"Two\\nlines\"\n"
+        );
+    }
+
+    #[cfg(feature = "tcp")]
+    #[tokio::test]
+    async fn test_dns_watch_skips_unchanged() {
+        use super::tcp::Dns;
+        use crate::client::Resolver;
+        use futures::StreamExt;
+        use std::time::Duration;
+
+        let dns = Dns::refresh_every(Duration::from_millis(10));
+        assert!(!dns.resolve("localhost:80").await.unwrap().is_empty());
+        let mut changes = dns.watch("localhost:80");
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), changes.next())
+                .await
+                .is_err()
+        );
+    }
 }
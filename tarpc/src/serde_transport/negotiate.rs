@@ -0,0 +1,385 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Negotiates the codec of each connection, so that one listener can serve clients that encode
+//! messages differently, e.g. JSON to old clients and a binary codec to new ones during a
+//! migration.
+//!
+//! Before any message, a client that negotiates announces itself, the server answers with the
+//! names of the [`Codecs`] it supports, and the client chooses the first of its own, in order of
+//! preference, that the server supports. A client that doesn't negotiate, because it predates
+//! negotiation, is served with the server's [legacy codec](Codecs::with_legacy), if it has one:
+//!
+//! ```
+//! # use futures::prelude::*;
+//! # use tarpc::serde_transport::{self, negotiate::{self, Codecs}, CanonicalJson};
+//! # use tokio::net::{TcpListener, TcpStream};
+//! # use tokio_serde::formats::Json;
+//! # use std::io;
+//! # #[tokio::main]
+//! # async fn main() -> io::Result<()> {
+//! let mut listener = TcpListener::bind("localhost:0").await?;
+//! let addr = listener.local_addr()?;
+//! let server_codecs = Codecs::<String, String>::new()
+//!     .with("canonical-json", CanonicalJson::default)
+//!     .with("json", Json::default)
+//!     .with_legacy("json");
+//! let server = async move {
+//!     for _ in 0..2 {
+//!         let (conn, _) = listener.accept().await?;
+//!         let mut transport = negotiate::accept(conn, &server_codecs).await?;
+//!         let message = transport.next().await.unwrap()?;
+//!         transport.send(format!("{} over {}", message, transport.negotiated_codec())).await?;
+//!     }
+//!     io::Result::Ok(())
+//! };
+//! let server = tokio::spawn(server);
+//!
+//! // A new client negotiates.
+//! let client_codecs = Codecs::<String, String>::new().with("canonical-json", CanonicalJson::default);
+//! let mut transport = negotiate::connect(TcpStream::connect(addr).await?, &client_codecs).await?;
+//! transport.send("Hi".to_string()).await?;
+//! assert_eq!(transport.next().await.unwrap()?, "Hi over canonical-json");
+//!
+//! // An old client doesn't.
+//! let mut transport = serde_transport::tcp::connect(addr, Json::<String, String>::default()).await?;
+//! transport.send("Hi".to_string()).await?;
+//! assert_eq!(transport.next().await.unwrap()?, "Hi over json");
+//! # server.await?
+//! # }
+//! ```
+
+use super::Transport;
+use bytes::{Buf, Bytes, BytesMut};
+use log::debug;
+use pin_project::pin_project;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_serde::{Deserializer, Serializer};
+
+/// Announces a client that negotiates. A client that doesn't starts with the length of its first
+/// message, which can't be 4 GiB, or with a JSON value, which can't start with 0xFF.
+const MAGIC: [u8; 4] = *b"\xFFtrp";
+
+/// A codec whose type is erased, so that connections negotiating different codecs have the same
+/// type.
+trait DynCodec<Item, SinkItem>: Send {
+    fn serialize(&mut self, item: &SinkItem) -> io::Result<Bytes>;
+    fn deserialize(&mut self, src: &BytesMut) -> io::Result<Item>;
+}
+
+impl<C, Item, SinkItem> DynCodec<Item, SinkItem> for C
+where
+    C: Serializer<SinkItem> + Deserializer<Item> + Unpin + Send,
+    <C as Serializer<SinkItem>>::Error: Into<io::Error>,
+    <C as Deserializer<Item>>::Error: Into<io::Error>,
+{
+    fn serialize(&mut self, item: &SinkItem) -> io::Result<Bytes> {
+        Serializer::serialize(Pin::new(self), item).map_err(Into::into)
+    }
+
+    fn deserialize(&mut self, src: &BytesMut) -> io::Result<Item> {
+        Deserializer::deserialize(Pin::new(self), src).map_err(Into::into)
+    }
+}
+
+type NewCodec<Item, SinkItem> = Arc<dyn Fn() -> Box<dyn DynCodec<Item, SinkItem>> + Send + Sync>;
+
+/// The named codecs a peer supports, in order of preference.
+pub struct Codecs<Item, SinkItem> {
+    codecs: Vec<(String, NewCodec<Item, SinkItem>)>,
+    legacy: Option<usize>,
+}
+
+impl<Item, SinkItem> Codecs<Item, SinkItem> {
+    /// Returns an empty set of codecs.
+    pub fn new() -> Self {
+        Codecs {
+            codecs: vec![],
+            legacy: None,
+        }
+    }
+
+    /// Supports the codecs made by `new_codec`, negotiated by `name`. A client prefers the codecs
+    /// it supports in the order they're added.
+    ///
+    /// # Panics
+    ///
+    /// If `name` is longer than 255 bytes, or the codecs already number 255.
+    pub fn with<C>(
+        mut self,
+        name: impl Into<String>,
+        new_codec: impl Fn() -> C + Send + Sync + 'static,
+    ) -> Self
+    where
+        C: Serializer<SinkItem> + Deserializer<Item> + Unpin + Send + 'static,
+        <C as Serializer<SinkItem>>::Error: Into<io::Error>,
+        <C as Deserializer<Item>>::Error: Into<io::Error>,
+    {
+        let name = name.into();
+        assert!(name.len() <= 255, "Codec names can't exceed 255 bytes.");
+        assert!(
+            self.codecs.len() < 255,
+            "There can't be more than 255 codecs."
+        );
+        self.codecs.push((
+            name,
+            Arc::new(move || Box::new(new_codec()) as Box<dyn DynCodec<Item, SinkItem>>),
+        ));
+        self
+    }
+
+    /// Serves clients that don't negotiate a codec with the codec named `name`. Without a legacy
+    /// codec, a server refuses them.
+    ///
+    /// # Panics
+    ///
+    /// If no codec is named `name`.
+    pub fn with_legacy(mut self, name: &str) -> Self {
+        self.legacy = Some(
+            self.position(name)
+                .unwrap_or_else(|| panic!("There's no codec named {:?}.", name)),
+        );
+        self
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.codecs.iter().position(|(codec, _)| codec == name)
+    }
+
+    fn transport<S>(
+        &self,
+        index: usize,
+        prefix: Bytes,
+        io: S,
+    ) -> Transport<NegotiatedIo<S>, Item, SinkItem, Negotiated<Item, SinkItem>>
+    where
+        S: AsyncRead + AsyncWrite,
+        Item: for<'de> Deserialize<'de>,
+        SinkItem: Serialize,
+    {
+        let (name, new_codec) = &self.codecs[index];
+        debug!("Negotiated codec {}.", name);
+        let io = NegotiatedIo {
+            inner: io,
+            prefix,
+            codec: name.clone(),
+        };
+        Transport::from((io, Negotiated { codec: new_codec() }))
+    }
+}
+
+impl<Item, SinkItem> Default for Codecs<Item, SinkItem> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Item, SinkItem> Clone for Codecs<Item, SinkItem> {
+    fn clone(&self) -> Self {
+        Codecs {
+            codecs: self.codecs.clone(),
+            legacy: self.legacy,
+        }
+    }
+}
+
+impl<Item, SinkItem> fmt::Debug for Codecs<Item, SinkItem> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Codecs")
+            .field(
+                "codecs",
+                &self.codecs.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+            )
+            .field("legacy", &self.legacy.map(|index| &self.codecs[index].0))
+            .finish()
+    }
+}
+
+/// The codec a connection negotiated.
+pub struct Negotiated<Item, SinkItem> {
+    codec: Box<dyn DynCodec<Item, SinkItem>>,
+}
+
+impl<Item, SinkItem> fmt::Debug for Negotiated<Item, SinkItem> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Negotiated").finish()
+    }
+}
+
+impl<Item, SinkItem> Serializer<SinkItem> for Negotiated<Item, SinkItem> {
+    type Error = io::Error;
+
+    fn serialize(self: Pin<&mut Self>, item: &SinkItem) -> io::Result<Bytes> {
+        self.get_mut().codec.serialize(item)
+    }
+}
+
+impl<Item, SinkItem> Deserializer<Item> for Negotiated<Item, SinkItem> {
+    type Error = io::Error;
+
+    fn deserialize(self: Pin<&mut Self>, src: &BytesMut) -> io::Result<Item> {
+        self.get_mut().codec.deserialize(src)
+    }
+}
+
+/// A connection whose codec was negotiated. Replays the bytes read from a client that didn't
+/// negotiate while checking whether it would.
+#[pin_project]
+#[derive(Debug)]
+pub struct NegotiatedIo<S> {
+    #[pin]
+    inner: S,
+    prefix: Bytes,
+    codec: String,
+}
+
+impl<S> NegotiatedIo<S> {
+    /// Returns the connection.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for NegotiatedIo<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        if this.prefix.is_empty() {
+            return this.inner.poll_read(cx, buf);
+        }
+        let len = this.prefix.len().min(buf.len());
+        buf[..len].copy_from_slice(&this.prefix[..len]);
+        this.prefix.advance(len);
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for NegotiatedIo<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.project().inner.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+/// A transport whose codec was negotiated.
+pub type NegotiatedTransport<S, Item, SinkItem> =
+    Transport<NegotiatedIo<S>, Item, SinkItem, Negotiated<Item, SinkItem>>;
+
+impl<S, Item, SinkItem> NegotiatedTransport<S, Item, SinkItem> {
+    /// Returns the name of the codec the transport negotiated.
+    pub fn negotiated_codec(&self) -> &str {
+        &self.inner.get_ref().get_ref().get_ref().codec
+    }
+}
+
+/// Negotiates the codec of a connection a client opened to a server, offering the client the
+/// server's `codecs`.
+pub async fn accept<S, Item, SinkItem>(
+    mut io: S,
+    codecs: &Codecs<Item, SinkItem>,
+) -> io::Result<NegotiatedTransport<S, Item, SinkItem>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    let mut magic = [0; 4];
+    io.read_exact(&mut magic).await?;
+    if magic != MAGIC {
+        let legacy = codecs.legacy.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "The client didn't negotiate a codec.",
+            )
+        })?;
+        return Ok(codecs.transport(legacy, Bytes::copy_from_slice(&magic), io));
+    }
+
+    let mut offer = vec![codecs.codecs.len() as u8];
+    for (name, _) in &codecs.codecs {
+        offer.push(name.len() as u8);
+        offer.extend_from_slice(name.as_bytes());
+    }
+    io.write_all(&offer).await?;
+    io.flush().await?;
+
+    let choice = read_name(&mut io).await?;
+    match codecs.position(&choice) {
+        Some(index) => Ok(codecs.transport(index, Bytes::new(), io)),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "The client chose no codec the server offered: {:?}.",
+                choice
+            ),
+        )),
+    }
+}
+
+/// Negotiates the codec of a connection to a server, choosing the first of `codecs` the server
+/// supports.
+pub async fn connect<S, Item, SinkItem>(
+    mut io: S,
+    codecs: &Codecs<Item, SinkItem>,
+) -> io::Result<NegotiatedTransport<S, Item, SinkItem>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    io.write_all(&MAGIC).await?;
+    io.flush().await?;
+
+    let mut offered = vec![];
+    for _ in 0..io.read_u8().await? {
+        offered.push(read_name(&mut io).await?);
+    }
+    let index = codecs
+        .codecs
+        .iter()
+        .position(|(name, _)| offered.contains(name));
+    let name = index.map_or("", |index| &codecs.codecs[index].0);
+    io.write_u8(name.len() as u8).await?;
+    io.write_all(name.as_bytes()).await?;
+    io.flush().await?;
+    match index {
+        Some(index) => Ok(codecs.transport(index, Bytes::new(), io)),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "The server supports none of the client's codecs: it offered {:?}.",
+                offered
+            ),
+        )),
+    }
+}
+
+async fn read_name(io: &mut (impl AsyncRead + Unpin)) -> io::Result<String> {
+    let mut name = vec![0; io.read_u8().await? as usize];
+    io.read_exact(&mut name).await?;
+    String::from_utf8(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
@@ -0,0 +1,359 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Limits the rate at which a server's connections read and write bytes, each connection on its
+//! own and all of them together, so that clients moving bulk data can't saturate a shared link,
+//! nor starve the clients whose requests are small but latency-sensitive.
+//!
+//! A [`RateLimiter`] holds the global limits. Each connection it [limits](RateLimiter::limit)
+//! gets token buckets of its own for the per-connection limits, and draws on the limiter's buckets
+//! for the global ones:
+//!
+//! ```no_run
+//! # use tarpc::serde_transport::{rate_limit::{ByteRate, Limits, RateLimiter}, Transport};
+//! # use tokio::net::TcpListener;
+//! # use tokio_serde::formats::Json;
+//! # async fn serve() -> std::io::Result<()> {
+//! let limiter = RateLimiter::new(
+//!     // Each connection reads and writes up to 1 MiB/s, in bursts of up to 256 KiB...
+//!     Limits::both(ByteRate::new(1 << 20, 256 << 10)),
+//!     // ...and all of them together up to 10 MiB/s.
+//!     Limits::both(ByteRate::new(10 << 20, 1 << 20)),
+//! );
+//! let mut listener = TcpListener::bind("0.0.0.0:5000").await?;
+//! loop {
+//!     let (conn, _) = listener.accept().await?;
+//!     let transport: Transport<_, String, String, _> =
+//!         Transport::from((limiter.limit(conn), Json::default()));
+//!     // ... serve the transport ...
+//! }
+//! # }
+//! ```
+
+use futures::{prelude::*, ready, task::*};
+use pin_project::pin_project;
+use std::{
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time::{delay_until, Delay, Instant},
+};
+
+/// A limit on the rate of bytes: a steady rate, plus an allowance for bursts above that rate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ByteRate {
+    /// The number of bytes replenished each second.
+    pub bytes_per_second: u64,
+    /// The maximum number of bytes that can be moved in a burst. A connection that has been idle
+    /// long enough can move this many bytes at once before being limited to the steady rate.
+    pub burst: u64,
+}
+
+impl ByteRate {
+    /// Returns a rate allowing `bytes_per_second` steady-state, with bursts of up to `burst`.
+    pub fn new(bytes_per_second: u64, burst: u64) -> Self {
+        assert!(bytes_per_second > 0, "bytes_per_second must be positive");
+        assert!(burst > 0, "burst must be positive");
+        ByteRate {
+            bytes_per_second,
+            burst,
+        }
+    }
+}
+
+/// The limits on the rates of bytes read and written. `None` leaves a direction unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// The limit on the rate of bytes read.
+    pub read: Option<ByteRate>,
+    /// The limit on the rate of bytes written.
+    pub write: Option<ByteRate>,
+}
+
+impl Limits {
+    /// Returns limits that limit both reads and writes to `rate`, separately.
+    pub fn both(rate: ByteRate) -> Self {
+        Limits {
+            read: Some(rate),
+            write: Some(rate),
+        }
+    }
+}
+
+/// A token bucket. Each byte takes one token, and tokens refill at the rate's steady rate, up to
+/// the burst size.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: ByteRate,
+    /// Negative when connections sharing the bucket took more than it held at once.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn full(rate: ByteRate, now: Instant) -> Self {
+        TokenBucket {
+            rate,
+            tokens: rate.burst as f64,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate.bytes_per_second as f64)
+            .min(self.rate.burst as f64);
+        self.last_refill = now;
+    }
+
+    /// Returns how many of `len` bytes can be moved now, if any can; otherwise, returns how long
+    /// until one can be.
+    fn available(&mut self, len: usize, now: Instant) -> Result<usize, Duration> {
+        self.refill(now);
+        if self.tokens >= 1. {
+            Ok((self.tokens as u64).min(len as u64) as usize)
+        } else {
+            Err(Duration::from_secs_f64(
+                (1. - self.tokens) / self.rate.bytes_per_second as f64,
+            ))
+        }
+    }
+
+    fn take(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+}
+
+/// Limits one direction of a connection.
+#[derive(Debug)]
+struct DirectionLimit {
+    connection: Option<TokenBucket>,
+    global: Option<Arc<Mutex<TokenBucket>>>,
+    /// Wakes the connection once tokens are available again.
+    delay: Option<Delay>,
+}
+
+impl DirectionLimit {
+    /// Returns how many of `len` bytes can be moved now, waiting until at least one can.
+    fn poll_available(&mut self, cx: &mut Context<'_>, len: usize) -> Poll<usize> {
+        loop {
+            let now = Instant::now();
+            let mut available = Ok(len);
+            if let Some(ref mut bucket) = self.connection {
+                available = bucket.available(len, now);
+            }
+            if let Some(ref global) = self.global {
+                available = match (available, global.lock().unwrap().available(len, now)) {
+                    (Ok(len), Ok(global)) => Ok(len.min(global)),
+                    (Err(wait), Err(global)) => Err(wait.max(global)),
+                    (Err(wait), Ok(_)) | (Ok(_), Err(wait)) => Err(wait),
+                };
+            }
+            let wait = match available {
+                Ok(len) => {
+                    self.delay = None;
+                    return Poll::Ready(len);
+                }
+                Err(wait) => wait,
+            };
+            match self.delay {
+                Some(ref mut delay) => delay.reset(now + wait),
+                None => self.delay = Some(delay_until(now + wait)),
+            }
+            ready!(self.delay.as_mut().unwrap().poll_unpin(cx));
+        }
+    }
+
+    /// Takes the tokens for `bytes` moved. Connections that share the global bucket can take more
+    /// than it held between checking and taking; the bucket goes into debt, which they wait out.
+    fn take(&mut self, bytes: usize) {
+        if let Some(ref mut bucket) = self.connection {
+            bucket.take(bytes);
+        }
+        if let Some(ref global) = self.global {
+            global.lock().unwrap().take(bytes);
+        }
+    }
+}
+
+/// Limits the rates at which connections read and write bytes, each on its own and all together.
+/// Clones share the global limits.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    per_connection: Limits,
+    read: Option<Arc<Mutex<TokenBucket>>>,
+    write: Option<Arc<Mutex<TokenBucket>>>,
+}
+
+impl RateLimiter {
+    /// Returns a limiter that limits each connection to `per_connection`, and all connections
+    /// together to `global`.
+    pub fn new(per_connection: Limits, global: Limits) -> Self {
+        let now = Instant::now();
+        let bucket = |rate| Arc::new(Mutex::new(TokenBucket::full(rate, now)));
+        RateLimiter {
+            per_connection,
+            read: global.read.map(bucket),
+            write: global.write.map(bucket),
+        }
+    }
+
+    /// Limits the rates at which `io` reads and writes.
+    pub fn limit<S>(&self, io: S) -> RateLimited<S> {
+        let now = Instant::now();
+        RateLimited {
+            inner: io,
+            read: DirectionLimit {
+                connection: self
+                    .per_connection
+                    .read
+                    .map(|rate| TokenBucket::full(rate, now)),
+                global: self.read.clone(),
+                delay: None,
+            },
+            write: DirectionLimit {
+                connection: self
+                    .per_connection
+                    .write
+                    .map(|rate| TokenBucket::full(rate, now)),
+                global: self.write.clone(),
+                delay: None,
+            },
+        }
+    }
+}
+
+/// A connection whose reads and writes are rate-limited. Created by [`RateLimiter::limit`].
+#[pin_project]
+#[derive(Debug)]
+pub struct RateLimited<S> {
+    #[pin]
+    inner: S,
+    read: DirectionLimit,
+    write: DirectionLimit,
+}
+
+impl<S> RateLimited<S> {
+    /// Returns the connection.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for RateLimited<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        if buf.is_empty() {
+            return this.inner.poll_read(cx, buf);
+        }
+        let len = ready!(this.read.poll_available(cx, buf.len()));
+        let read = ready!(this.inner.poll_read(cx, &mut buf[..len]))?;
+        this.read.take(read);
+        Poll::Ready(Ok(read))
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for RateLimited<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        if buf.is_empty() {
+            return this.inner.poll_write(cx, buf);
+        }
+        let len = ready!(this.write.poll_available(cx, buf.len()));
+        let written = ready!(this.inner.poll_write(cx, &buf[..len]))?;
+        this.write.take(written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ByteRate, Limits, RateLimiter};
+    use std::{io, time::Duration};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        time::{self, Instant},
+    };
+
+    #[tokio::test]
+    async fn connections_are_limited() -> io::Result<()> {
+        time::pause();
+        let per_connection = Limits {
+            write: Some(ByteRate::new(1_000, 100)),
+            ..Default::default()
+        };
+        let limiter = RateLimiter::new(per_connection, Limits::default());
+
+        let start = Instant::now();
+        let mut conn = limiter.limit(vec![]);
+        // The burst is written at once, and the rest at the steady rate.
+        conn.write_all(&[0; 1_100]).await?;
+        assert_eq!(conn.get_ref().len(), 1_100);
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= Duration::from_secs(1) && elapsed < Duration::from_millis(1_100),
+            "{:?}",
+            elapsed
+        );
+
+        // Reads are unlimited, and each connection has its own limit.
+        let start = Instant::now();
+        let mut conn = limiter.limit(&[0u8; 1_000][..]);
+        conn.read_exact(&mut [0; 1_000]).await?;
+        limiter.limit(vec![]).write_all(&[0; 100]).await?;
+        assert_eq!(start.elapsed(), Duration::from_secs(0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connections_share_the_global_limit() -> io::Result<()> {
+        time::pause();
+        let global = Limits {
+            read: Some(ByteRate::new(1_000, 500)),
+            ..Default::default()
+        };
+        let limiter = RateLimiter::new(Limits::default(), global);
+
+        let start = Instant::now();
+        let (mut conn1, mut conn2) = (
+            limiter.limit(&[0u8; 1_000][..]),
+            limiter.limit(&[0u8; 1_000][..]),
+        );
+        let (mut buf1, mut buf2) = ([0; 1_000], [0; 1_000]);
+        let (read1, read2) =
+            futures::join!(conn1.read_exact(&mut buf1), conn2.read_exact(&mut buf2),);
+        read1?;
+        read2?;
+        // 2,000 bytes, less the burst, at 1,000 bytes per second.
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= Duration::from_millis(1_500) && elapsed < Duration::from_millis(1_600),
+            "{:?}",
+            elapsed
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,442 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Property tests that messages survive the wire: a [`Generator`] of arbitrary client and server
+//! messages, and helpers that send messages through a [`Transport`]'s framing and codec, and
+//! back.
+//!
+//! [`check`] round-trips the messages generated for each of many seeds, so that a service can test
+//! that its request and response types survive the codec and framing it's deployed with:
+//!
+//! ```
+//! use tarpc::serde_transport::round_trip::{self, Pipeline, Rng};
+//! use tokio_serde::formats::SymmetricalJson;
+//!
+//! round_trip::check(
+//!     0..100,
+//!     &Pipeline::new().with_fragmentation(8),
+//!     SymmetricalJson::default,
+//!     |gen| gen.client_message(|gen| (gen.string(), gen.rng().gen::<u32>())),
+//! );
+//! ```
+
+use super::{Multiplexed, Transport};
+use crate::{
+    context,
+    trace::{self, SpanId, TraceId},
+    ClientMessage, ErrorCode, ErrorFrame, ReplyOrder, Request, Response, ServerError,
+    ServerMessage, SessionRequest, SessionToken,
+};
+use futures::{executor::block_on, prelude::*};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    fmt,
+    io::{self, Cursor},
+    time::{Duration, SystemTime},
+};
+use tokio_serde::{Deserializer, Serializer};
+
+pub use rand::Rng;
+
+/// The longest string a [`Generator`] generates, in chars.
+const MAX_STRING_LEN: usize = 32;
+/// The most messages [`check`] round-trips at once.
+const MAX_MESSAGES: usize = 8;
+
+/// The error kinds a [`ServerError`] carries over the wire. Other kinds arrive as
+/// [`Other`](io::ErrorKind::Other).
+const ERROR_KINDS: &[io::ErrorKind] = &[
+    io::ErrorKind::NotFound,
+    io::ErrorKind::PermissionDenied,
+    io::ErrorKind::ConnectionRefused,
+    io::ErrorKind::ConnectionReset,
+    io::ErrorKind::ConnectionAborted,
+    io::ErrorKind::NotConnected,
+    io::ErrorKind::AddrInUse,
+    io::ErrorKind::AddrNotAvailable,
+    io::ErrorKind::BrokenPipe,
+    io::ErrorKind::AlreadyExists,
+    io::ErrorKind::WouldBlock,
+    io::ErrorKind::InvalidInput,
+    io::ErrorKind::InvalidData,
+    io::ErrorKind::TimedOut,
+    io::ErrorKind::WriteZero,
+    io::ErrorKind::Interrupted,
+    io::ErrorKind::Other,
+    io::ErrorKind::UnexpectedEof,
+];
+
+const ERROR_CODES: &[ErrorCode] = &[
+    ErrorCode::InvalidMessage,
+    ErrorCode::LimitExceeded,
+    ErrorCode::PermissionDenied,
+    ErrorCode::Unimplemented,
+    ErrorCode::Internal,
+];
+
+/// Generates arbitrary messages, of every kind, from a seeded random number generator. Their
+/// payloads are generated by the caller.
+#[derive(Debug)]
+pub struct Generator {
+    rng: StdRng,
+}
+
+impl Generator {
+    /// Returns a generator that generates the same messages every time it's seeded with `seed`.
+    pub fn new(seed: u64) -> Self {
+        Generator {
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// Returns the generator's random number generator, for generating payloads.
+    pub fn rng(&mut self) -> &mut StdRng {
+        &mut self.rng
+    }
+
+    /// Returns a string of up to 32 chars, mixing printable ASCII with arbitrary Unicode.
+    pub fn string(&mut self) -> String {
+        let len = self.rng.gen_range(0, MAX_STRING_LEN + 1);
+        let mut string = String::with_capacity(len);
+        for _ in 0..len {
+            string.push(if self.rng.gen() {
+                self.rng.gen_range(b' ', b'~' + 1) as char
+            } else {
+                self.rng.gen()
+            });
+        }
+        string
+    }
+
+    /// Returns a time between the epoch and the year 2106, to the nanosecond.
+    fn time(&mut self) -> SystemTime {
+        SystemTime::UNIX_EPOCH
+            + Duration::new(
+                self.rng.gen_range(0, 1 << 32),
+                self.rng.gen_range(0, 1_000_000_000),
+            )
+    }
+
+    fn option<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> Option<T> {
+        if self.rng.gen() {
+            Some(f(self))
+        } else {
+            None
+        }
+    }
+
+    /// Returns a context with arbitrary fields. Its deadline is a whole number of seconds since
+    /// the epoch, the precision it's sent with.
+    pub fn context(&mut self) -> context::Context {
+        let mut context = context::current();
+        context.deadline =
+            SystemTime::UNIX_EPOCH + Duration::from_secs(self.rng.gen_range(0, 1 << 32));
+        context.trace_context = self.trace_context();
+        context.tenant_id = self.option(Self::string);
+        context.api_key = self.option(Self::string);
+        context.idempotency_key = self.option(Self::string);
+        context
+    }
+
+    fn trace_context(&mut self) -> trace::Context {
+        trace::Context {
+            trace_id: TraceId::random(&mut self.rng),
+            span_id: SpanId::random(&mut self.rng),
+            parent_id: self.option(|gen| SpanId::random(&mut gen.rng)),
+        }
+    }
+
+    /// Returns a request with an arbitrary context, ID, and method, carrying `message`.
+    pub fn request<T>(&mut self, message: T) -> Request<T> {
+        Request {
+            context: self.context(),
+            id: self.rng.gen(),
+            method: self.option(|gen| Cow::Owned(gen.string())),
+            message,
+        }
+    }
+
+    /// Returns a server error with an arbitrary kind, detail, and retry delay.
+    pub fn server_error(&mut self) -> ServerError {
+        ServerError {
+            kind: *ERROR_KINDS.choose(&mut self.rng).unwrap(),
+            detail: self.option(Self::string),
+            retry_after: self
+                .option(|gen| Duration::new(gen.rng.gen(), gen.rng.gen_range(0, 1_000_000_000))),
+        }
+    }
+
+    /// Returns an error frame with an arbitrary ID, code, message, and details.
+    pub fn error_frame(&mut self) -> ErrorFrame {
+        let details = (0..self.rng.gen_range(0, 4))
+            .map(|_| (self.string(), self.string()))
+            .collect();
+        ErrorFrame {
+            id: self.option(|gen| gen.rng.gen()),
+            code: *ERROR_CODES.choose(&mut self.rng).unwrap(),
+            message: self.string(),
+            details,
+        }
+    }
+
+    fn reply_order(&mut self) -> ReplyOrder {
+        if self.rng.gen() {
+            ReplyOrder::Completion
+        } else {
+            ReplyOrder::Request
+        }
+    }
+
+    /// Returns a client message of an arbitrary kind, whose payload, if it has one, is generated
+    /// by `payload`.
+    pub fn client_message<T>(&mut self, payload: impl FnOnce(&mut Self) -> T) -> ClientMessage<T> {
+        match self.rng.gen_range(0, 8) {
+            0 => {
+                let message = payload(self);
+                ClientMessage::Request(self.request(message))
+            }
+            1 => {
+                let message = payload(self);
+                ClientMessage::StreamingRequest(self.request(message))
+            }
+            2 => ClientMessage::StreamItem {
+                request_id: self.rng.gen(),
+                item: payload(self),
+            },
+            3 => ClientMessage::StreamEnd {
+                request_id: self.rng.gen(),
+            },
+            4 => ClientMessage::WindowUpdate {
+                request_id: self.rng.gen(),
+                credits: self.rng.gen(),
+            },
+            5 => ClientMessage::Cancel {
+                trace_context: self.trace_context(),
+                request_id: self.rng.gen(),
+            },
+            6 => ClientMessage::HealthCheck {
+                check_id: self.rng.gen(),
+            },
+            _ => ClientMessage::Handshake {
+                reply_order: self.reply_order(),
+                session: self.option(|gen| {
+                    if gen.rng.gen() {
+                        SessionRequest::Start
+                    } else {
+                        SessionRequest::Resume(SessionToken::from(gen.string()))
+                    }
+                }),
+            },
+        }
+    }
+
+    /// Returns a server message of an arbitrary kind, whose payload, if it has one, is generated
+    /// by `payload`.
+    pub fn server_message<T>(&mut self, payload: impl FnOnce(&mut Self) -> T) -> ServerMessage<T> {
+        match self.rng.gen_range(0, 12) {
+            0 => ServerMessage::Response(Response {
+                request_id: self.rng.gen(),
+                message: if self.rng.gen() {
+                    Ok(payload(self))
+                } else {
+                    Err(self.server_error())
+                },
+            }),
+            1 => ServerMessage::StreamItem {
+                request_id: self.rng.gen(),
+                item: payload(self),
+            },
+            2 => ServerMessage::StreamEnd {
+                request_id: self.rng.gen(),
+            },
+            3 => ServerMessage::WindowUpdate {
+                request_id: self.rng.gen(),
+                credits: self.rng.gen(),
+            },
+            4 => ServerMessage::Progress {
+                request_id: self.rng.gen(),
+                progress: payload(self),
+            },
+            5 => ServerMessage::Notification(payload(self)),
+            6 => ServerMessage::Load(self.rng.gen()),
+            7 => ServerMessage::Health {
+                check_id: self.rng.gen(),
+                serving: self.rng.gen(),
+                time: self.option(Self::time),
+            },
+            8 => ServerMessage::GoAway,
+            9 => ServerMessage::RequestCredit {
+                credits: self.rng.gen(),
+            },
+            10 => ServerMessage::Handshake {
+                reply_order: self.reply_order(),
+                session: self.option(|gen| SessionToken::from(gen.string())),
+            },
+            _ => ServerMessage::Error(self.error_frame()),
+        }
+    }
+}
+
+/// The framing messages are round-tripped through. By default, messages are length-delimited and
+/// unfragmented, as they are by a [`Transport`] by default.
+#[derive(Clone, Debug, Default)]
+pub struct Pipeline {
+    fragment_len: Option<usize>,
+    newline_delimited: bool,
+}
+
+impl Pipeline {
+    /// Returns the default pipeline.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fragments messages, as [`Transport::with_fragmentation`] does.
+    pub fn with_fragmentation(mut self, fragment_len: usize) -> Self {
+        self.fragment_len = Some(fragment_len);
+        self
+    }
+
+    /// Delimits messages with newlines, as [`Transport::with_newline_delimited`] does.
+    pub fn with_newline_delimited(mut self) -> Self {
+        self.newline_delimited = true;
+        self
+    }
+
+    fn transport<Item, Codec>(
+        &self,
+        bytes: Vec<u8>,
+        codec: Codec,
+    ) -> Transport<Cursor<Vec<u8>>, Item, Item, Codec>
+    where
+        Item: Multiplexed + Serialize + for<'de> Deserialize<'de>,
+        Codec: Serializer<Item> + Deserializer<Item>,
+    {
+        let mut transport = Transport::from((Cursor::new(bytes), codec));
+        if self.newline_delimited {
+            transport = transport.with_newline_delimited();
+        }
+        if let Some(fragment_len) = self.fragment_len {
+            transport = transport.with_fragmentation(fragment_len);
+        }
+        transport
+    }
+}
+
+/// Writes `messages` to a transport framed by `pipeline` and encoded by a codec made by `codec`,
+/// then reads them back with another. Messages of different streams may be read back in a
+/// different order than they were written, if they're fragmented.
+pub fn round_trip<Item, Codec>(
+    messages: Vec<Item>,
+    pipeline: &Pipeline,
+    codec: impl Fn() -> Codec,
+) -> io::Result<Vec<Item>>
+where
+    Item: Multiplexed + Serialize + for<'de> Deserialize<'de>,
+    Codec: Serializer<Item> + Deserializer<Item>,
+    <Codec as Serializer<Item>>::Error: Into<io::Error>,
+    io::Error: From<<Codec as Deserializer<Item>>::Error>,
+{
+    block_on(async {
+        let mut writer = Box::pin(pipeline.transport(vec![], codec()));
+        // Feeding every message before flushing lets fragments of different streams interleave.
+        for message in messages {
+            writer.feed(message).await?;
+        }
+        writer.flush().await?;
+        let bytes = writer.inner.get_ref().get_ref().get_ref().get_ref().clone();
+
+        let reader = Box::pin(pipeline.transport(bytes, codec()));
+        reader.try_collect().await
+    })
+}
+
+/// Round-trips the messages `generate` generates for each of `seeds`, a random number of them at
+/// a time, and checks that the messages of each stream arrive intact and in order.
+///
+/// # Panics
+///
+/// If a message doesn't survive the round trip, with a message naming the seed it was generated
+/// with.
+pub fn check<Item, Codec>(
+    seeds: impl IntoIterator<Item = u64>,
+    pipeline: &Pipeline,
+    codec: impl Fn() -> Codec,
+    mut generate: impl FnMut(&mut Generator) -> Item,
+) where
+    Item: Clone + fmt::Debug + PartialEq + Multiplexed + Serialize + for<'de> Deserialize<'de>,
+    Codec: Serializer<Item> + Deserializer<Item>,
+    <Codec as Serializer<Item>>::Error: Into<io::Error>,
+    io::Error: From<<Codec as Deserializer<Item>>::Error>,
+{
+    for seed in seeds {
+        let mut gen = Generator::new(seed);
+        let count = gen.rng.gen_range(1, MAX_MESSAGES + 1);
+        let mut sent: Vec<_> = (0..count).map(|_| generate(&mut gen)).collect();
+        let mut received = match round_trip(sent.clone(), pipeline, &codec) {
+            Ok(received) => received,
+            Err(e) => panic!(
+                "Messages generated with seed {} failed to round-trip: {}",
+                seed, e
+            ),
+        };
+        // Only the order of each stream's messages is preserved.
+        sent.sort_by_key(Multiplexed::stream_id);
+        received.sort_by_key(Multiplexed::stream_id);
+        assert_eq!(
+            received, sent,
+            "Messages generated with seed {} didn't survive the round trip.",
+            seed
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, Pipeline, Rng};
+    use tokio_serde::formats::SymmetricalJson;
+
+    #[test]
+    fn client_messages_round_trip() {
+        let pipelines = [
+            Pipeline::new(),
+            Pipeline::new().with_fragmentation(1),
+            Pipeline::new().with_fragmentation(16),
+            Pipeline::new().with_newline_delimited(),
+        ];
+        for pipeline in &pipelines {
+            check(0..200, pipeline, SymmetricalJson::default, |gen| {
+                gen.client_message(|gen| gen.string())
+            });
+        }
+    }
+
+    #[test]
+    fn server_messages_round_trip() {
+        let pipelines = [
+            Pipeline::new(),
+            Pipeline::new().with_fragmentation(1),
+            Pipeline::new().with_fragmentation(16),
+            Pipeline::new().with_newline_delimited(),
+        ];
+        for pipeline in &pipelines {
+            check(0..200, pipeline, SymmetricalJson::default, |gen| {
+                gen.server_message(|gen| (gen.string(), gen.rng().gen::<i64>()))
+            });
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "seed 0")]
+    fn check_reports_the_seed_of_a_message_that_fails() {
+        // JSON can't represent NaN.
+        check(0..10, &Pipeline::new(), SymmetricalJson::default, |gen| {
+            gen.server_message(|_| f64::NAN)
+        });
+    }
+}
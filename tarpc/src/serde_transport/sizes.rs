@@ -0,0 +1,413 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Measures the encoded sizes of the requests and replies of each method, to find the RPCs that
+//! would benefit most from compression, pagination, or streaming.
+//!
+//! [`PayloadSizes`] wraps the codec of each connection, on either side, and records the length of
+//! every message that carries a payload in the histograms of the method it's for:
+//!
+//! ```
+//! # use tarpc::{serde_transport::{self, sizes::PayloadSizes}, ClientMessage, ServerMessage};
+//! # use tokio_serde::formats::Json;
+//! # use std::io;
+//! # #[tarpc::service]
+//! # trait World {
+//! #     async fn hello(name: String) -> String;
+//! # }
+//! # #[tokio::main]
+//! # async fn main() -> io::Result<()> {
+//! let sizes = PayloadSizes::new();
+//! let listener = {
+//!     let sizes = sizes.clone();
+//!     serde_transport::tcp::listen("localhost:0", move || sizes.codec(Json::default())).await?
+//! };
+//! # let _: serde_transport::tcp::Incoming<ClientMessage<WorldRequest>, ServerMessage<WorldResponse>, _, _> = listener;
+//! // ... serve the listener's connections ...
+//! let hello = sizes.stats("hello");
+//! println!("p99 request: {} bytes", hello.requests.percentile(99.0));
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{ClientMessage, ErrorFrame, RequestName, ServerMessage};
+use bytes::{Bytes, BytesMut};
+use fnv::FnvHashMap;
+use pin_project::pin_project;
+use std::{
+    fmt,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+use tokio_serde::{Deserializer, Serializer};
+
+/// A distribution of sizes, in bytes. Sizes are counted in buckets whose bounds are powers of
+/// two, so percentiles are accurate to within a factor of two.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SizeHistogram {
+    /// The number of sizes of each bit length: bucket `i` counts the sizes less than `2^i` and at
+    /// least `2^(i - 1)`.
+    buckets: Vec<u64>,
+    count: u64,
+    total: u64,
+    max: u64,
+}
+
+impl SizeHistogram {
+    fn record(&mut self, size: usize) {
+        let size = size as u64;
+        let bucket = (64 - size.leading_zeros()) as usize;
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
+        }
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.total += size;
+        self.max = self.max.max(size);
+    }
+
+    /// Returns the number of sizes recorded.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Returns the sum of the sizes recorded.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Returns the largest size recorded, or zero if none was.
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// Returns the mean of the sizes recorded, or zero if none was.
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.total as f64 / self.count as f64
+    }
+
+    /// Returns a size that `percentile` percent of the sizes recorded don't exceed, e.g. 99.0 for
+    /// the p99: the upper bound of the bucket holding the percentile, or the largest size, if
+    /// smaller. Returns zero if no size was recorded.
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        let rank = (percentile / 100.0 * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return upper_bound(bucket).min(self.max);
+            }
+        }
+        self.max
+    }
+
+    /// Returns the largest size each bucket holds, with the number of sizes recorded in it, from
+    /// the smallest bucket to the largest.
+    pub fn buckets(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.buckets
+            .iter()
+            .enumerate()
+            .map(|(bucket, &count)| (upper_bound(bucket), count))
+    }
+}
+
+/// The largest size of bit length `bucket`.
+fn upper_bound(bucket: usize) -> u64 {
+    if bucket == 0 {
+        0
+    } else {
+        u64::MAX >> (64 - bucket)
+    }
+}
+
+/// The sizes of the messages of one method.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct MethodSizes {
+    /// The sizes of the method's requests, and of the items streamed with them.
+    pub requests: SizeHistogram,
+    /// The sizes of the method's responses, and of the items and progress reports streamed before
+    /// them.
+    pub replies: SizeHistogram,
+}
+
+/// Records the sizes of the messages of each method. Clones share the same histograms, so a single
+/// `PayloadSizes` can measure every connection of a server or client.
+#[derive(Clone, Default)]
+pub struct PayloadSizes {
+    methods: Arc<Mutex<FnvHashMap<&'static str, MethodSizes>>>,
+}
+
+impl fmt::Debug for PayloadSizes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PayloadSizes")
+            .field("methods", &self.methods.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl PayloadSizes {
+    /// Returns a new, empty set of histograms.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns `codec`, measuring the messages it encodes and decodes. Wrap the codec of each
+    /// connection separately.
+    pub fn codec<C>(&self, codec: C) -> Measured<C> {
+        Measured {
+            codec,
+            sizes: self.clone(),
+            methods: FnvHashMap::default(),
+        }
+    }
+
+    /// Returns the sizes of the messages of `method`.
+    pub fn stats(&self, method: &str) -> MethodSizes {
+        self.methods
+            .lock()
+            .unwrap()
+            .get(method)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Returns the sizes of the messages of every method seen so far.
+    pub fn snapshot(&self) -> FnvHashMap<&'static str, MethodSizes> {
+        self.methods.lock().unwrap().clone()
+    }
+
+    fn update(&self, method: &'static str, f: impl FnOnce(&mut MethodSizes)) {
+        f(self.methods.lock().unwrap().entry(method).or_default())
+    }
+}
+
+/// A codec whose messages are measured by [`PayloadSizes`]. Remembers the method of each request
+/// in flight, to attribute the messages that answer it.
+#[pin_project]
+#[derive(Debug)]
+pub struct Measured<C> {
+    #[pin]
+    codec: C,
+    sizes: PayloadSizes,
+    methods: FnvHashMap<u64, &'static str>,
+}
+
+fn measure_client_message<Req: RequestName>(
+    sizes: &PayloadSizes,
+    methods: &mut FnvHashMap<u64, &'static str>,
+    message: &ClientMessage<Req>,
+    len: usize,
+) {
+    let method = match message {
+        ClientMessage::Request(request) | ClientMessage::StreamingRequest(request) => {
+            let method = request.message.name();
+            methods.insert(request.id, method);
+            method
+        }
+        ClientMessage::StreamItem { request_id, .. } => match methods.get(request_id) {
+            Some(method) => *method,
+            None => return,
+        },
+        ClientMessage::Cancel { request_id, .. } => {
+            methods.remove(request_id);
+            return;
+        }
+        _ => return,
+    };
+    sizes.update(method, |sizes| sizes.requests.record(len));
+}
+
+fn measure_server_message<Resp>(
+    sizes: &PayloadSizes,
+    methods: &mut FnvHashMap<u64, &'static str>,
+    message: &ServerMessage<Resp>,
+    len: usize,
+) {
+    let method = match message {
+        ServerMessage::Response(response) => methods.remove(&response.request_id),
+        ServerMessage::StreamItem { request_id, .. }
+        | ServerMessage::Progress { request_id, .. } => methods.get(request_id).copied(),
+        ServerMessage::StreamEnd { request_id }
+        | ServerMessage::Error(ErrorFrame {
+            id: Some(request_id),
+            ..
+        }) => {
+            methods.remove(request_id);
+            None
+        }
+        _ => None,
+    };
+    if let Some(method) = method {
+        sizes.update(method, |sizes| sizes.replies.record(len));
+    }
+}
+
+/// Measures the requests a server decodes.
+impl<C, Req> Deserializer<ClientMessage<Req>> for Measured<C>
+where
+    C: Deserializer<ClientMessage<Req>>,
+    Req: RequestName,
+{
+    type Error = C::Error;
+
+    fn deserialize(self: Pin<&mut Self>, src: &BytesMut) -> Result<ClientMessage<Req>, C::Error> {
+        let this = self.project();
+        let message = this.codec.deserialize(src)?;
+        measure_client_message(this.sizes, this.methods, &message, src.len());
+        Ok(message)
+    }
+}
+
+/// Measures the replies a server encodes.
+impl<C, Resp> Serializer<ServerMessage<Resp>> for Measured<C>
+where
+    C: Serializer<ServerMessage<Resp>>,
+{
+    type Error = C::Error;
+
+    fn serialize(self: Pin<&mut Self>, item: &ServerMessage<Resp>) -> Result<Bytes, C::Error> {
+        let this = self.project();
+        let bytes = this.codec.serialize(item)?;
+        measure_server_message(this.sizes, this.methods, item, bytes.len());
+        Ok(bytes)
+    }
+}
+
+/// Measures the requests a client encodes.
+impl<C, Req> Serializer<ClientMessage<Req>> for Measured<C>
+where
+    C: Serializer<ClientMessage<Req>>,
+    Req: RequestName,
+{
+    type Error = C::Error;
+
+    fn serialize(self: Pin<&mut Self>, item: &ClientMessage<Req>) -> Result<Bytes, C::Error> {
+        let this = self.project();
+        let bytes = this.codec.serialize(item)?;
+        measure_client_message(this.sizes, this.methods, item, bytes.len());
+        Ok(bytes)
+    }
+}
+
+/// Measures the replies a client decodes.
+impl<C, Resp> Deserializer<ServerMessage<Resp>> for Measured<C>
+where
+    C: Deserializer<ServerMessage<Resp>>,
+{
+    type Error = C::Error;
+
+    fn deserialize(self: Pin<&mut Self>, src: &BytesMut) -> Result<ServerMessage<Resp>, C::Error> {
+        let this = self.project();
+        let message = this.codec.deserialize(src)?;
+        measure_server_message(this.sizes, this.methods, &message, src.len());
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PayloadSizes, SizeHistogram};
+    use crate::{context, ClientMessage, Request, RequestName, Response, ServerMessage};
+    use bytes::BytesMut;
+    use pin_utils::pin_mut;
+    use serde::{Deserialize, Serialize};
+    use tokio_serde::{formats::Json, Deserializer, Serializer};
+
+    #[test]
+    fn histogram_percentiles() {
+        let mut histogram = SizeHistogram::default();
+        assert_eq!(histogram.percentile(50.0), 0);
+        for size in &[0, 1, 3, 100, 100, 100, 100, 100, 100, 5_000] {
+            histogram.record(*size);
+        }
+        assert_eq!(histogram.count(), 10);
+        assert_eq!(histogram.total(), 5_604);
+        assert_eq!(histogram.max(), 5_000);
+        assert_eq!(histogram.percentile(10.0), 0);
+        assert_eq!(histogram.percentile(30.0), 3);
+        assert_eq!(histogram.percentile(50.0), 127);
+        assert_eq!(histogram.percentile(99.0), 5_000);
+        assert_eq!(
+            histogram
+                .buckets()
+                .filter(|(_, count)| *count > 0)
+                .collect::<Vec<_>>(),
+            [(0, 1), (1, 1), (3, 1), (127, 6), (8_191, 1)]
+        );
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    enum Method {
+        Small,
+        Large(String),
+    }
+
+    impl RequestName for Method {
+        fn name(&self) -> &'static str {
+            match self {
+                Method::Small => "small",
+                Method::Large(_) => "large",
+            }
+        }
+    }
+
+    fn request(id: u64, message: Method) -> ClientMessage<Method> {
+        let mut request = Request {
+            context: context::current(),
+            id,
+            method: None,
+            message,
+        };
+        request.context.deadline = std::time::SystemTime::UNIX_EPOCH;
+        ClientMessage::Request(request)
+    }
+
+    fn response(request_id: u64, message: String) -> ServerMessage<String> {
+        ServerMessage::Response(Response {
+            request_id,
+            message: Ok(message),
+        })
+    }
+
+    #[test]
+    fn measures_messages_by_method() {
+        let sizes = PayloadSizes::new();
+        let client = sizes.codec(Json::<ServerMessage<String>, ClientMessage<Method>>::default());
+        let server = sizes.codec(Json::<ClientMessage<Method>, ServerMessage<String>>::default());
+        pin_mut!(client);
+        pin_mut!(server);
+
+        for (id, message) in [(1, Method::Small), (2, Method::Large("x".repeat(1_000)))] {
+            let bytes = client.as_mut().serialize(&request(id, message)).unwrap();
+            server
+                .as_mut()
+                .deserialize(&BytesMut::from(&bytes[..]))
+                .unwrap();
+        }
+        for (id, reply) in [(2, "y".repeat(500)), (1, String::new())] {
+            let bytes = server.as_mut().serialize(&response(id, reply)).unwrap();
+            client
+                .as_mut()
+                .deserialize(&BytesMut::from(&bytes[..]))
+                .unwrap();
+        }
+
+        let small = sizes.stats("small");
+        let large = sizes.stats("large");
+        // Each message was measured once by the client and once by the server.
+        assert_eq!(small.requests.count(), 2);
+        assert_eq!(small.replies.count(), 2);
+        assert!(large.requests.mean() > 1_000.0, "{:?}", large);
+        assert!(large.replies.mean() > 500.0, "{:?}", large);
+        assert!(small.requests.max() < 500, "{:?}", small);
+        assert!(small.replies.max() < 100, "{:?}", small);
+        assert_eq!(sizes.snapshot().len(), 2);
+    }
+}
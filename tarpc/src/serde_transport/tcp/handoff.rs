@@ -0,0 +1,264 @@
+// Copyright 2020 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Hands listening sockets over to another process, e.g. a newly exec'd binary of the same
+//! server, so that a server can be upgraded without refusing connections.
+//!
+//! The old process [`offer`]s its listeners on a Unix socket, and the new process [`take`]s them,
+//! receiving them with `SCM_RIGHTS`. Both processes then share the listening sockets, so
+//! connections queue up rather than being refused while the new process starts. Once the offer is
+//! taken, the old process should stop accepting connections and drain the ones it has, e.g. with
+//! a [`Drain`](crate::server::Drain), so that its clients reconnect to the new process.
+//!
+//! ```no_run
+//! # use tarpc::{serde_transport::tcp::{self, handoff}, server::Drain};
+//! # use tokio_serde::formats::Json;
+//! # use std::{env, process::Command};
+//! # fn serve<T>(_: T) {}
+//! # async fn upgrade(drain: Drain) -> std::io::Result<()> {
+//! let path = "/run/my-service/handoff.sock";
+//! if env::var_os("HANDOFF").is_some() {
+//!     // The new process serves the old process's listeners.
+//!     for listener in handoff::take(path)? {
+//!         serve(tcp::from_std::<String, String, _, _>(listener, Json::default)?);
+//!     }
+//! } else {
+//!     let listener = std::net::TcpListener::bind("[::]:8080")?;
+//!     serve(tcp::from_std::<String, String, _, _>(listener.try_clone()?, Json::default)?);
+//!     // ... until it's time to upgrade:
+//!     Command::new(env::current_exe()?).env("HANDOFF", "1").spawn()?;
+//!     tokio::task::block_in_place(|| handoff::offer(path, &[&listener]))?;
+//!     drain.start();
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    io::{self, Read, Write},
+    mem,
+    net::TcpListener,
+    os::unix::{
+        io::{AsRawFd, FromRawFd, RawFd},
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    ptr,
+};
+
+/// The most listeners that can be handed over at once.
+pub const MAX_LISTENERS: usize = 64;
+
+/// Sent by the new process once it has taken the listeners.
+const ACK: u8 = 1;
+
+/// Where the kernel supports it, received descriptors are marked close-on-exec as they are
+/// installed, so no concurrent `exec` can inherit them.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "illumos"
+))]
+const RECV_FLAGS: libc::c_int = libc::MSG_CMSG_CLOEXEC;
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "illumos"
+)))]
+const RECV_FLAGS: libc::c_int = 0;
+
+/// Offers `listeners` to another process on the Unix socket at `path`, blocking until a process
+/// [`take`]s them. The socket is removed afterward.
+///
+/// Fails with [`io::ErrorKind::AddrInUse`] if `path` exists, and with
+/// [`io::ErrorKind::UnexpectedEof`] if the process that connected exits before taking the
+/// listeners, in which case the old process still owns them and should keep serving.
+pub fn offer(path: impl AsRef<Path>, listeners: &[&TcpListener]) -> io::Result<()> {
+    if listeners.len() > MAX_LISTENERS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Can't hand over more than {} listeners.", MAX_LISTENERS),
+        ));
+    }
+    let path = path.as_ref();
+    let socket = UnixListener::bind(path)?;
+    let result = hand_over(&socket, listeners);
+    let _ = std::fs::remove_file(path);
+    result
+}
+
+/// Takes the listeners [`offer`]ed on the Unix socket at `path`, in the order they were offered.
+pub fn take(path: impl AsRef<Path>) -> io::Result<Vec<TcpListener>> {
+    let mut conn = UnixStream::connect(path)?;
+    let listeners = receive(&conn)?;
+    conn.write_all(&[ACK])?;
+    Ok(listeners)
+}
+
+/// Sends the file descriptors of `listeners` to the first process to connect to `socket`, and
+/// waits for it to acknowledge them.
+fn hand_over(socket: &UnixListener, listeners: &[&TcpListener]) -> io::Result<()> {
+    let (mut conn, _) = socket.accept()?;
+    let fds: Vec<RawFd> = listeners
+        .iter()
+        .map(|listener| listener.as_raw_fd())
+        .collect();
+    send(&conn, &fds)?;
+    let mut ack = [0];
+    conn.read_exact(&mut ack)?;
+    if ack[0] != ACK {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Unexpected acknowledgement.",
+        ));
+    }
+    Ok(())
+}
+
+/// A control message buffer aligned for `cmsghdr`, with room for [`MAX_LISTENERS`] descriptors.
+fn control_buffer() -> Vec<u64> {
+    // SAFETY: CMSG_SPACE only computes a length.
+    let space = unsafe { libc::CMSG_SPACE((MAX_LISTENERS * mem::size_of::<RawFd>()) as u32) };
+    vec![0; (space as usize).div_ceil(mem::size_of::<u64>())]
+}
+
+/// Sends `fds` over `conn` in an `SCM_RIGHTS` control message. The message's one byte of data
+/// is the number of descriptors, so the receiver can tell whether any were lost.
+fn send(conn: &UnixStream, fds: &[RawFd]) -> io::Result<()> {
+    let mut data = [fds.len() as u8];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    let mut control = control_buffer();
+    let fds_len = mem::size_of_val(fds) as u32;
+    // SAFETY: msghdr is plain data, for which zeroes are valid. The control buffer is aligned for
+    // cmsghdr and has room for the header and up to MAX_LISTENERS descriptors, which `fds` doesn't
+    // exceed, and `msg` only points to buffers that outlive the call to sendmsg.
+    let sent = unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        if !fds.is_empty() {
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = libc::CMSG_SPACE(fds_len) as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+            ptr::copy_nonoverlapping(
+                fds.as_ptr() as *const u8,
+                libc::CMSG_DATA(cmsg),
+                fds_len as usize,
+            );
+        }
+        libc::sendmsg(conn.as_raw_fd(), &msg, 0)
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Receives the listeners sent over `conn` by [`send`].
+fn receive(conn: &UnixStream) -> io::Result<Vec<TcpListener>> {
+    let mut data = [0];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    let mut control = control_buffer();
+    let mut listeners = vec![];
+    // SAFETY: as in `send`, `msg` only points to buffers that outlive its use. The kernel fills
+    // the control buffer with well-formed messages, which the CMSG macros walk without leaving
+    // it, and the descriptors it installs in this process belong to no one else, so each is
+    // owned by exactly one listener, which closes it if receiving fails.
+    let (received, truncated) = unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = (control.len() * mem::size_of::<u64>()) as _;
+        let received = libc::recvmsg(conn.as_raw_fd(), &mut msg, RECV_FLAGS);
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let fds = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                for i in 0..len / mem::size_of::<RawFd>() {
+                    let fd = ptr::read_unaligned(fds.add(i));
+                    listeners.push(TcpListener::from_raw_fd(fd));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+        (received, msg.msg_flags & libc::MSG_CTRUNC != 0)
+    };
+    if RECV_FLAGS == 0 {
+        for listener in &listeners {
+            // SAFETY: the descriptor is open and owned by `listener`.
+            if unsafe { libc::fcntl(listener.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    if received == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "The offer was withdrawn.",
+        ));
+    }
+    if truncated || listeners.len() != data[0] as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Expected {} listeners but received {}.",
+                data[0],
+                listeners.len()
+            ),
+        ));
+    }
+    Ok(listeners)
+}
+
+#[test]
+fn listeners_are_handed_over() -> io::Result<()> {
+    let path = std::env::temp_dir().join(format!("tarpc-handoff-{}.sock", std::process::id()));
+    let first = TcpListener::bind("127.0.0.1:0")?;
+    let second = TcpListener::bind("127.0.0.1:0")?;
+    let addrs = [first.local_addr()?, second.local_addr()?];
+
+    let offer_path = path.clone();
+    let offer = std::thread::spawn(move || offer(offer_path, &[&first, &second]));
+    let listeners = loop {
+        match take(&path) {
+            Ok(listeners) => break listeners,
+            // The offer's socket isn't bound yet.
+            Err(_) => std::thread::sleep(std::time::Duration::from_millis(10)),
+        }
+    };
+    offer.join().unwrap()?;
+    assert!(!path.exists());
+
+    assert_eq!(listeners.len(), 2);
+    for (listener, &addr) in listeners.iter().zip(&addrs) {
+        assert_eq!(listener.local_addr()?, addr);
+        let _conn = std::net::TcpStream::connect(addr)?;
+        assert_eq!(listener.accept()?.1.ip(), addr.ip());
+    }
+    Ok(())
+}
@@ -1,14 +1,23 @@
 use assert_matches::assert_matches;
 use futures::{
-    future::{ready, Ready},
+    future::{self, ready, Ready},
     prelude::*,
+    stream,
 };
-use std::io;
+use std::{collections::HashMap, io, net::SocketAddr, time::Duration};
 use tarpc::{
-    client::{self},
-    context, serde_transport,
+    client::{
+        self,
+        balance::Balancer,
+        failover::{Backoff, Failover},
+        resolver::Registry,
+        shard::Sharded,
+    },
+    context,
+    proxy::Proxy,
+    serde_transport,
     server::{self, BaseChannel, Channel, Handler},
-    transport::channel,
+    transport::{channel, duplex},
 };
 use tokio_serde::formats::Json;
 
@@ -82,31 +91,1997 @@ async fn serde() -> io::Result<()> {
     Ok(())
 }
 
+#[cfg(feature = "negotiate")]
 #[tokio::test(threaded_scheduler)]
-async fn concurrent() -> io::Result<()> {
+async fn one_listener_serves_negotiated_and_legacy_codecs() -> io::Result<()> {
+    use tarpc::serde_transport::{
+        negotiate::{self, Codecs},
+        CanonicalJson,
+    };
+    use tokio::net::{TcpListener, TcpStream};
+
+    let _ = env_logger::try_init();
+
+    let mut listener = TcpListener::bind("localhost:0").await?;
+    let addr = listener.local_addr()?;
+    let codecs = Codecs::new()
+        .with("canonical-json", CanonicalJson::default)
+        .with("json", Json::default)
+        .with_legacy("json");
+    tokio::spawn(async move {
+        loop {
+            let (conn, _) = listener.accept().await.unwrap();
+            let transport = negotiate::accept(conn, &codecs).await.unwrap();
+            tokio::spawn(
+                BaseChannel::with_defaults(transport)
+                    .respond_with(Server.serve())
+                    .execute(),
+            );
+        }
+    });
+
+    let codecs = Codecs::new()
+        .with("bincode", Json::default)
+        .with("canonical-json", CanonicalJson::default);
+    let transport = negotiate::connect(TcpStream::connect(addr).await?, &codecs).await?;
+    assert_eq!(transport.negotiated_codec(), "canonical-json");
+    let mut client = ServiceClient::new(client::Config::default(), transport).spawn()?;
+    assert_matches!(client.add(context::current(), 1, 2).await, Ok(3));
+
+    let transport = serde_transport::tcp::connect(addr, Json::default()).await?;
+    let mut legacy = ServiceClient::new(client::Config::default(), transport).spawn()?;
+    assert_matches!(legacy.add(context::current(), 3, 4).await, Ok(7));
+
+    Ok(())
+}
+
+#[cfg(feature = "serde1")]
+#[tokio::test(threaded_scheduler)]
+async fn undecodable_messages_are_reported() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    #[tarpc_plugins::service]
+    trait Other {
+        async fn subtract(x: i32, y: i32) -> i32;
+    }
+
+    let transport = serde_transport::tcp::listen("localhost:0", Json::default).await?;
+    let addr = transport.local_addr();
+    tokio::spawn(
+        tarpc::Server::default()
+            .incoming(transport.take(1).filter_map(|r| async { r.ok() }))
+            .respond_with(Server.serve()),
+    );
+
+    // The server doesn't know the other service's requests, so it can't decode them.
+    let transport = serde_transport::tcp::connect(addr, Json::default()).await?;
+    let client::NewClient {
+        client: mut other,
+        dispatch,
+    } = OtherClient::new(client::Config::default(), transport);
+    let dispatch = tokio::spawn(dispatch);
+
+    assert!(other.subtract(context::current(), 3, 2).await.is_err());
+    let error = dispatch.await.unwrap().unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    let server_error = error
+        .get_ref()
+        .unwrap()
+        .downcast_ref::<tarpc::ServerError>();
+    assert_matches!(
+        server_error,
+        Some(tarpc::ServerError {
+            detail: Some(_),
+            ..
+        })
+    );
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn mismatched_replies_fail_the_call() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .respond_with(|_, _: ServiceRequest| ready(ServiceResponse::Hey("Hey.".into())))
+            .execute(),
+    );
+
+    let mut client = ServiceClient::new(client::Config::default(), tx).spawn()?;
+    assert_matches!(
+        client.add(context::current(), 1, 2).await,
+        Err(e) if e.kind() == io::ErrorKind::InvalidData
+    );
+    assert_eq!(client.hey(context::current(), "Tim".into()).await?, "Hey.");
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn handlers_see_how_long_requests_were_queued() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .respond_with(|ctx: context::Context, _: ServiceRequest| {
+                ready(ServiceResponse::Add(ctx.queue_time().is_some() as i32))
+            })
+            .execute(),
+    );
+
+    let mut client = ServiceClient::new(client::Config::default(), tx).spawn()?;
+    assert_matches!(client.add(context::current(), 1, 2).await, Ok(1));
+    assert_eq!(context::current().queue_time(), None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn deadlines_follow_a_virtual_clock() -> io::Result<()> {
+    use std::sync::Arc;
+    use tarpc::clock::{Clock, VirtualClock};
+
+    let _ = env_logger::try_init();
+
+    tokio::time::pause();
+    let clock = Arc::new(VirtualClock::new());
+    let (tx, rx) = channel::unbounded();
+    let server_config = server::Config {
+        clock: clock.clone(),
+        ..Default::default()
+    };
+    tokio::spawn(
+        BaseChannel::new(server_config, rx)
+            .respond_with(|_, _: ServiceRequest| future::pending())
+            .execute(),
+    );
+    let mut client_config = client::Config::default();
+    client_config.clock = clock.clone();
+    let mut client = ServiceClient::new(client_config, tx).spawn()?;
+
+    let mut ctx = context::current();
+    ctx.deadline = clock.now() + Duration::from_secs(60);
+    let call = tokio::spawn(async move { client.add(ctx, 1, 2).await });
+    tokio::time::advance(Duration::from_secs(61)).await;
+    assert_matches!(
+        call.await.unwrap(),
+        Err(e) if e.kind() == io::ErrorKind::TimedOut
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "sim")]
+#[test]
+fn concurrent_and_canceled_calls_survive_simulated_faults() {
+    use tarpc::sim::{self, Faults};
+
+    let _ = env_logger::try_init();
+
+    sim::run(0..50, |network| async move {
+        let mut faults = Faults::default();
+        faults.max_delay = Duration::from_millis(20);
+        faults.reorder = true;
+        let (tx, rx) = network.channel(faults);
+        tokio::spawn(
+            BaseChannel::new(network.server_config(), rx)
+                .respond_with(Server.serve())
+                .execute(),
+        );
+        let client = ServiceClient::new(network.client_config(), tx)
+            .spawn()
+            .unwrap();
+
+        let calls = (0..10).map(|i| {
+            let mut client = client.clone();
+            let network = network.clone();
+            async move {
+                tokio::time::delay_for(network.jitter(Duration::from_millis(20))).await;
+                let call = client.add(context::current(), i, i);
+                // Cancel some calls while their messages are in flight.
+                let timeout = network.jitter(Duration::from_millis(100));
+                if let Ok(sum) = tokio::time::timeout(timeout, call).await {
+                    assert_eq!(sum.unwrap(), 2 * i);
+                }
+            }
+        });
+        future::join_all(calls).await;
+
+        let mut client = client;
+        assert_eq!(client.add(context::current(), 1, 2).await.unwrap(), 3);
+    });
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn handlers_run_on_the_given_executor() -> io::Result<()> {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
     let _ = env_logger::try_init();
 
+    let spawned = Arc::new(AtomicUsize::new(0));
+    let executor = {
+        let spawned = spawned.clone();
+        move |task| {
+            spawned.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(task);
+        }
+    };
     let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        stream::once(ready(BaseChannel::with_defaults(rx)))
+            .respond_with_on(Server.serve(), executor),
+    );
+
+    let mut client = ServiceClient::new(client::Config::default(), tx).spawn()?;
+    assert_matches!(client.add(context::current(), 1, 2).await, Ok(3));
+    assert_matches!(client.add(context::current(), 3, 4).await, Ok(7));
+    // One task for the channel, and one for each request.
+    assert_eq!(spawned.load(Ordering::SeqCst), 3);
+
+    Ok(())
+}
+
+#[cfg(feature = "serde1")]
+#[tokio::test(threaded_scheduler)]
+async fn resolved() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let transport = serde_transport::tcp::listen("localhost:0", Json::default).await?;
+    let addr = transport.local_addr();
     tokio::spawn(
         tarpc::Server::default()
-            .incoming(stream::once(ready(rx)))
+            .incoming(transport.take(1).filter_map(|r| async { r.ok() }))
             .respond_with(Server.serve()),
     );
 
-    let client = ServiceClient::new(client::Config::default(), tx).spawn()?;
+    // The first address refuses the connection, so the client moves on to the next.
+    let registry = Registry::new();
+    registry.set("service", vec!["127.0.0.1:1".parse().unwrap(), addr]);
+    let transport =
+        serde_transport::tcp::connect_resolved(&registry, "service", Json::default()).await?;
+    let mut client = ServiceClient::new(client::Config::default(), transport).spawn()?;
 
-    let mut c = client.clone();
-    let req1 = c.add(context::current(), 1, 2);
+    assert_matches!(client.add(context::current(), 1, 2).await, Ok(3));
 
-    let mut c = client.clone();
-    let req2 = c.add(context::current(), 3, 4);
+    Ok(())
+}
 
-    let mut c = client.clone();
-    let req3 = c.hey(context::current(), "Tim".to_string());
+#[cfg(feature = "serde1")]
+#[tokio::test(threaded_scheduler)]
+async fn peers_are_filtered_when_accepted() -> io::Result<()> {
+    let _ = env_logger::try_init();
 
-    assert_matches!(req1.await, Ok(3));
-    assert_matches!(req2.await, Ok(7));
-    assert_matches!(req3.await, Ok(ref s) if s == "Hey, Tim.");
+    async fn call_through(accept: fn(&SocketAddr) -> bool) -> io::Result<i32> {
+        let incoming = serde_transport::tcp::listen("localhost:0", Json::default)
+            .await?
+            .accept_peers(accept);
+        let addr = incoming.local_addr();
+        tokio::spawn(
+            tarpc::Server::default()
+                .incoming(incoming.filter_map(|r| async { r.ok() }))
+                .respond_with(Server.serve()),
+        );
+        let transport = serde_transport::tcp::connect(addr, Json::default()).await?;
+        let mut client = ServiceClient::new(client::Config::default(), transport).spawn()?;
+        client.add(context::current(), 1, 2).await
+    }
+
+    assert_matches!(call_through(|peer| peer.ip().is_loopback()).await, Ok(3));
+    assert!(call_through(|peer| !peer.ip().is_loopback()).await.is_err());
+
+    Ok(())
+}
+
+#[cfg(feature = "serde1")]
+#[tokio::test(threaded_scheduler)]
+async fn failover() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let mut primary = serde_transport::tcp::listen("localhost:0", Json::default).await?;
+    let secondary = serde_transport::tcp::listen("localhost:0", Json::default).await?;
+    let endpoints = vec![primary.local_addr(), secondary.local_addr()];
+    let (serve_primary, kill_primary) = future::abortable(async move {
+        let conn = primary.next().await.unwrap().unwrap();
+        BaseChannel::with_defaults(conn)
+            .respond_with(Server.serve())
+            .execute()
+            .await
+    });
+    tokio::spawn(serve_primary);
+    tokio::spawn(
+        tarpc::Server::default()
+            .incoming(secondary.filter_map(|r| async { r.ok() }))
+            .respond_with(Server.serve()),
+    );
+
+    let mut client = Failover::new(client::Config::default(), endpoints.clone(), |addr| {
+        serde_transport::tcp::connect(addr, Json::default())
+    });
+    let add = || ServiceRequest::Add { x: 1, y: 2 };
+    assert_matches!(
+        client.call(context::current(), add()).await,
+        Ok(ServiceResponse::Add(3))
+    );
+    assert_eq!(client.active(), Some(endpoints[0]));
+
+    kill_primary.abort();
+    while client.active().is_some() {
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+    assert_matches!(
+        client.call(context::current(), add()).await,
+        Ok(ServiceResponse::Add(3))
+    );
+    assert_eq!(client.active(), Some(endpoints[1]));
+
+    Ok(())
+}
+
+#[cfg(feature = "serde1")]
+#[tokio::test(threaded_scheduler)]
+async fn failover_to_warm_standby() -> io::Result<()> {
+    use std::sync::{Arc, Mutex};
+
+    let _ = env_logger::try_init();
+
+    let mut primary = serde_transport::tcp::listen("localhost:0", Json::default).await?;
+    let secondary = serde_transport::tcp::listen("localhost:0", Json::default).await?;
+    let endpoints = vec![primary.local_addr(), secondary.local_addr()];
+    let (serve_primary, kill_primary) = future::abortable(async move {
+        let conn = primary.next().await.unwrap().unwrap();
+        BaseChannel::with_defaults(conn)
+            .respond_with(Server.serve())
+            .execute()
+            .await
+    });
+    tokio::spawn(serve_primary);
+    tokio::spawn(
+        tarpc::Server::default()
+            .incoming(secondary.filter_map(|r| async { r.ok() }))
+            .respond_with(Server.serve()),
+    );
+
+    let connected = Arc::new(Mutex::new(vec![]));
+    let mut client = Failover::new(client::Config::default(), endpoints.clone(), {
+        let connected = connected.clone();
+        move |addr| {
+            connected.lock().unwrap().push(addr);
+            serde_transport::tcp::connect(addr, Json::default())
+        }
+    })
+    .with_standby();
+    let add = || ServiceRequest::Add { x: 1, y: 2 };
+    assert_matches!(
+        client.call(context::current(), add()).await,
+        Ok(ServiceResponse::Add(3))
+    );
+    assert_eq!(client.active(), Some(endpoints[0]));
+    while client.standby() != Some(endpoints[1]) {
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+
+    kill_primary.abort();
+    while client.active().is_some() {
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+    assert_matches!(
+        client.call(context::current(), add()).await,
+        Ok(ServiceResponse::Add(3))
+    );
+    assert_eq!(client.active(), Some(endpoints[1]));
+    // The call went over the standby, rather than a new connection.
+    let connected = connected.lock().unwrap();
+    assert_eq!(
+        connected
+            .iter()
+            .filter(|&&addr| addr == endpoints[1])
+            .count(),
+        1
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "serde1")]
+#[tokio::test(threaded_scheduler)]
+async fn balanced() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let mut endpoints = vec![];
+    for name in &["a", "b"] {
+        let listener = serde_transport::tcp::listen("localhost:0", Json::default).await?;
+        endpoints.push(listener.local_addr());
+        tokio::spawn(
+            tarpc::Server::default()
+                .incoming(listener.filter_map(|r| async { r.ok() }))
+                .respond_with(move |_, _: ()| ready(name.to_string())),
+        );
+    }
+
+    let client = Balancer::<(), String>::new(
+        client::Config::default(),
+        endpoints.clone(),
+        Backoff::default(),
+        |addr| serde_transport::tcp::connect(addr, Json::default()),
+    );
+    while client.connected() != endpoints {
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+    let mut served = vec![];
+    for _ in 0..4 {
+        served.push(client.call(context::current(), ()).await?);
+    }
+    assert_eq!(served, ["a", "b", "a", "b"]);
+
+    Ok(())
+}
+
+#[cfg(feature = "serde1")]
+#[tokio::test(threaded_scheduler)]
+async fn multiple_listeners() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let tcp = serde_transport::tcp::listen("localhost:0", Json::default).await?;
+    let addr = tcp.local_addr();
+    let (in_memory_tx, in_memory) = futures::channel::mpsc::unbounded();
+    let listeners = server::Listeners::new()
+        .listen(tcp.filter_map(|r| async { r.ok() }))
+        .listen(in_memory);
+    tokio::spawn(
+        tarpc::Server::default()
+            .incoming(listeners)
+            .respond_with(Server.serve()),
+    );
+
+    let transport = serde_transport::tcp::connect(addr, Json::default()).await?;
+    let mut tcp_client = ServiceClient::new(client::Config::default(), transport).spawn()?;
+    let (tx, rx) = channel::unbounded();
+    in_memory_tx.unbounded_send(rx).unwrap();
+    let mut in_memory_client = ServiceClient::new(client::Config::default(), tx).spawn()?;
+
+    assert_eq!(tcp_client.add(context::current(), 1, 2).await?, 3);
+    assert_eq!(in_memory_client.add(context::current(), 3, 4).await?, 7);
 
     Ok(())
 }
+
+#[cfg(feature = "serde1")]
+#[tokio::test(threaded_scheduler)]
+async fn balancer_warms_up() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let listener = serde_transport::tcp::listen("localhost:0", Json::default).await?;
+    let up = listener.local_addr();
+    tokio::spawn(
+        tarpc::Server::default()
+            .incoming(listener.filter_map(|r| async { r.ok() }))
+            .respond_with(|_, _: ()| ready(())),
+    );
+    // An address nothing listens on yet.
+    let down = std::net::TcpListener::bind("localhost:0")?.local_addr()?;
+
+    let client = Balancer::<(), ()>::new(
+        client::Config::default(),
+        vec![up, down],
+        Backoff {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(10),
+        },
+        |addr| serde_transport::tcp::connect(addr, Json::default()),
+    );
+    client.ready(1).await;
+    assert_eq!(client.connected(), [up]);
+    client.call(context::current(), ()).await?;
+    assert!(
+        tokio::time::timeout(Duration::from_millis(100), client.ready(2))
+            .await
+            .is_err()
+    );
+
+    let listener = serde_transport::tcp::listen(down, Json::default).await?;
+    tokio::spawn(
+        tarpc::Server::default()
+            .incoming(listener.filter_map(|r| async { r.ok() }))
+            .respond_with(|_, _: ()| ready(())),
+    );
+    client.ready(3).await;
+    assert_eq!(client.connected(), [up, down]);
+
+    Ok(())
+}
+
+#[cfg(feature = "serde1")]
+#[tokio::test(threaded_scheduler)]
+async fn sharded() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let mut endpoints = vec![];
+    for _ in 0..3 {
+        let listener = serde_transport::tcp::listen("localhost:0", Json::default).await?;
+        let addr = listener.local_addr();
+        endpoints.push(addr);
+        tokio::spawn(
+            tarpc::Server::default()
+                .incoming(listener.filter_map(|r| async { r.ok() }))
+                .respond_with(move |_, _: String| ready(addr)),
+        );
+    }
+    endpoints.sort();
+
+    let mut client = Sharded::<String, SocketAddr, _, _>::new(
+        client::Config::default(),
+        endpoints.clone(),
+        Backoff::default(),
+        |key: &String| key.clone(),
+        |addr| serde_transport::tcp::connect(addr, Json::default()),
+    );
+    while client.connected() != endpoints {
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+    let keys: Vec<_> = (0..20).map(|i| format!("key {}", i)).collect();
+    let mut owners = vec![];
+    for key in &keys {
+        let owner = client.call(context::current(), key.clone()).await?;
+        assert_eq!(Some(owner), client.shard_of(key));
+        assert_eq!(client.call(context::current(), key.clone()).await?, owner);
+        owners.push(owner);
+    }
+
+    // Only the removed endpoint's keys move.
+    client.remove_endpoint(endpoints[0]);
+    for (key, owner) in keys.into_iter().zip(owners) {
+        let served = client.call(context::current(), key).await?;
+        if owner == endpoints[0] {
+            assert_ne!(served, owner);
+        } else {
+            assert_eq!(served, owner);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "serde1")]
+#[tokio::test(threaded_scheduler)]
+async fn balancer_evicts_unhealthy_endpoints() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let mut endpoints = vec![];
+    let mut healths = vec![];
+    for name in &["a", "b"] {
+        let health = server::Health::new();
+        let server_config = server::Config {
+            health: Some(health.clone()),
+            ..Default::default()
+        };
+        let listener = serde_transport::tcp::listen("localhost:0", Json::default).await?;
+        endpoints.push(listener.local_addr());
+        healths.push(health);
+        tokio::spawn(
+            server::new(server_config)
+                .incoming(listener.filter_map(|r| async { r.ok() }))
+                .respond_with(move |_, _: ()| ready(name.to_string())),
+        );
+    }
+
+    let mut config = client::Config::default();
+    config.health_checks = Some(client::HealthChecks {
+        interval: Duration::from_millis(10),
+        timeout: Duration::from_secs(1),
+        unhealthy_threshold: 2,
+        healthy_threshold: 2,
+    });
+    let client =
+        Balancer::<(), String>::new(config, endpoints.clone(), Backoff::default(), |addr| {
+            serde_transport::tcp::connect(addr, Json::default())
+        });
+    while client.connected() != endpoints {
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+
+    healths[1].set_serving(false);
+    while client.evicted() != endpoints[1..] {
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+    for _ in 0..4 {
+        assert_eq!(client.call(context::current(), ()).await?, "a");
+    }
+
+    healths[1].set_serving(true);
+    while !client.evicted().is_empty() {
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+    let mut served = vec![];
+    for _ in 0..2 {
+        served.push(client.call(context::current(), ()).await?);
+    }
+    served.sort();
+    assert_eq!(served, ["a", "b"]);
+
+    Ok(())
+}
+
+#[cfg(feature = "serde1")]
+#[tokio::test(threaded_scheduler)]
+async fn balancer_avoids_draining_endpoints() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let mut endpoints = vec![];
+    let drain = server::Drain::new();
+    for name in &["a", "b"] {
+        let server_config = server::Config {
+            drain: if *name == "b" {
+                Some(drain.clone())
+            } else {
+                None
+            },
+            ..Default::default()
+        };
+        let listener = serde_transport::tcp::listen("localhost:0", Json::default).await?;
+        endpoints.push(listener.local_addr());
+        tokio::spawn(
+            server::new(server_config)
+                .incoming(listener.filter_map(|r| async { r.ok() }))
+                .respond_with(move |_, _: ()| ready(name.to_string())),
+        );
+    }
+
+    let client = Balancer::<(), String>::new(
+        client::Config::default(),
+        endpoints.clone(),
+        Backoff::default(),
+        |addr| serde_transport::tcp::connect(addr, Json::default()),
+    );
+    while client.connected() != endpoints {
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+
+    drain.start();
+    while client.going_away() != endpoints[1..] {
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+    for _ in 0..4 {
+        assert_eq!(client.call(context::current(), ()).await?, "a");
+    }
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn draining_server_still_serves() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let drain = server::Drain::new();
+    let server_config = server::Config {
+        drain: Some(drain.clone()),
+        ..Default::default()
+    };
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::new(server_config, rx)
+            .respond_with(|_, x: u32| ready(x + 1))
+            .execute(),
+    );
+
+    let mut client = client::new(client::Config::default(), tx).spawn()?;
+    assert!(!client.is_going_away());
+    drain.start();
+    while !client.is_going_away() {
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+    assert_eq!(client.call(context::current(), 1).await?, 2);
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn drain_waits_for_channels_to_close() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let drain = server::Drain::new();
+    let server_config = server::Config {
+        drain: Some(drain.clone()),
+        ..Default::default()
+    };
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::new(server_config, rx)
+            .respond_with(|_, x: u32| ready(x + 1))
+            .execute(),
+    );
+    let mut client = client::new(client::Config::default(), tx).spawn()?;
+    assert_eq!(drain.channels(), 1);
+
+    drain.start();
+    let drained = drain.drained();
+    futures::pin_mut!(drained);
+    assert!(futures::poll!(&mut drained).is_pending());
+    assert_eq!(client.call(context::current(), 1).await?, 2);
+
+    drop(client);
+    tokio::time::timeout(Duration::from_secs(5), drained).await?;
+    assert_eq!(drain.channels(), 0);
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn panicking_handler_fails_its_request() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .respond_with(|_, x: u32| {
+                future::lazy(move |_| {
+                    assert_ne!(x, 0, "Can't handle zero.");
+                    x
+                })
+            })
+            .execute(),
+    );
+    let mut client = client::new(client::Config::default(), tx).spawn()?;
+
+    let e = client.call(context::current(), 0).await.unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::Other);
+    // The channel keeps serving.
+    assert_eq!(client.call(context::current(), 1).await?, 1);
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn requests_are_answered_by_responders() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    let mut requests = server::Requests::<u32, u32>::new(
+        stream::once(future::ready(BaseChannel::with_defaults(rx))),
+        1,
+    );
+    let client = client::new(client::Config::default(), tx).spawn()?;
+
+    let call = |x| {
+        let mut client = client.clone();
+        tokio::spawn(async move { client.call(context::current(), x).await })
+    };
+    let (first, second, dropped) = (call(1), call(2), call(3));
+    let mut envelopes = vec![];
+    for _ in 0..3 {
+        envelopes.push(requests.next().await.unwrap());
+    }
+    envelopes.sort_by_key(|envelope| envelope.request.message);
+    let mut envelopes = envelopes.into_iter();
+    let (one, two) = (envelopes.next().unwrap(), envelopes.next().unwrap());
+    assert_eq!(one.connection, two.connection);
+
+    // Responses can be sent in any order.
+    two.responder.respond(20)?;
+    assert_eq!(second.await??, 20);
+    one.responder.respond(10)?;
+    assert_eq!(first.await??, 10);
+    drop(envelopes);
+    assert_eq!(dropped.await?.unwrap_err().kind(), io::ErrorKind::Other);
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn client_stats() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    let (server, kill_server) = future::abortable(
+        BaseChannel::with_defaults(rx)
+            .respond_with(|_, x: u64| {
+                tokio::time::delay_for(Duration::from_millis(x)).map(move |()| x)
+            })
+            .execute(),
+    );
+    tokio::spawn(server);
+    let mut client = client::new(client::Config::default(), tx).spawn()?;
+
+    assert_eq!(client.call(context::current(), 0).await?, 0);
+    let stats = client.stats();
+    assert_eq!(
+        (
+            stats.requests,
+            stats.errors,
+            stats.in_flight,
+            stats.connected
+        ),
+        (1, 0, 0, true)
+    );
+
+    let mut ctx = context::current();
+    ctx.deadline = std::time::SystemTime::now() + Duration::from_millis(50);
+    assert_matches!(
+        client.call(ctx, 1_000).await,
+        Err(e) if e.kind() == io::ErrorKind::TimedOut
+    );
+    while client.stats().errors < 1 {
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+
+    let mut pending = client.clone();
+    let pending = tokio::spawn(async move { pending.call(context::current(), 5_000).await });
+    while client.stats().in_flight < 1 {
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+    kill_server.abort();
+    assert_matches!(
+        pending.await.unwrap(),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionReset
+    );
+    let stats = client.stats();
+    assert_eq!(
+        (
+            stats.requests,
+            stats.errors,
+            stats.in_flight,
+            stats.connected
+        ),
+        (3, 2, 0, false)
+    );
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn idle_channels_are_closed() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let server_config = server::Config {
+        idle_timeout: Some(Duration::from_millis(100)),
+        ..Default::default()
+    };
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::new(server_config, rx)
+            .respond_with(|_, x: u64| {
+                tokio::time::delay_for(Duration::from_millis(x)).map(move |()| x)
+            })
+            .execute(),
+    );
+
+    let mut client = client::new(client::Config::default(), tx).spawn()?;
+    // A request in flight keeps the channel open past the idle timeout.
+    assert_eq!(client.call(context::current(), 300).await?, 300);
+    assert_eq!(client.call(context::current(), 0).await?, 0);
+
+    tokio::time::delay_for(Duration::from_millis(300)).await;
+    assert!(client.call(context::current(), 0).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn stalled_clients_are_disconnected() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let server_config = server::Config {
+        handshake_timeout: Some(Duration::from_millis(50)),
+        ..Default::default()
+    };
+    let serve = |rx| {
+        BaseChannel::new(server_config.clone(), rx)
+            .respond_with(|_, x: u32| ready(x + 1))
+            .execute()
+    };
+
+    // A client that connects but sends nothing is disconnected.
+    let (_tx, rx) = channel::unbounded();
+    tokio::time::timeout(Duration::from_secs(1), serve(rx))
+        .await
+        .expect("the channel should close");
+
+    // One whose first message arrives in time isn't.
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(serve(rx));
+    let mut client = client::new(client::Config::default(), tx).spawn()?;
+    assert_eq!(client.call(context::current(), 1).await?, 2);
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    assert_eq!(client.call(context::current(), 1).await?, 2);
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn channels_retire_after_their_lifetime() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let server_config = server::Config {
+        max_lifetime: Some(Duration::from_millis(100)),
+        lifetime_grace: Duration::from_millis(200),
+        ..Default::default()
+    };
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::new(server_config, rx)
+            .respond_with(|_, x: u32| ready(x + 1))
+            .execute(),
+    );
+
+    let mut client = client::new(client::Config::default(), tx).spawn()?;
+    assert_eq!(client.call(context::current(), 1).await?, 2);
+    while !client.is_going_away() {
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+    // The channel keeps serving until its grace period ends.
+    assert_eq!(client.call(context::current(), 1).await?, 2);
+
+    tokio::time::delay_for(Duration::from_millis(400)).await;
+    assert!(client.call(context::current(), 1).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn shutdown_fails_calls_without_waiting_for_the_server() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .respond_with(|_, x: u32| async move {
+                if x == 0 {
+                    future::pending::<()>().await;
+                }
+                x + 1
+            })
+            .execute(),
+    );
+
+    let mut client = client::new(client::Config::default(), tx).spawn()?;
+    assert_eq!(client.call(context::current(), 1).await?, 2);
+    let stuck = tokio::spawn({
+        let mut client = client.clone();
+        async move { client.call(context::current(), 0).await }
+    });
+    while client.stats().in_flight == 0 {
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+
+    tokio::time::timeout(Duration::from_secs(5), client.shutdown()).await?;
+    assert!(client.is_shut_down());
+    assert_matches!(stuck.await?, Err(e) if e.kind() == io::ErrorKind::Other);
+    assert_matches!(
+        client.call(context::current(), 1).await,
+        Err(e) if e.kind() == io::ErrorKind::Other
+    );
+    assert!(!client.stats().connected);
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn handlers_close_their_connection() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .respond_with(|ctx: context::Context, x: u32| {
+                if x == 0 {
+                    ctx.close_connection();
+                }
+                ready(x + 1)
+            })
+            .execute(),
+    );
+
+    let mut client = client::new(client::Config::default(), tx).spawn()?;
+    assert_eq!(client.call(context::current(), 1).await?, 2);
+    assert!(!client.is_going_away());
+    // The request that closes the connection is still answered.
+    assert_eq!(client.call(context::current(), 0).await?, 1);
+    while !client.is_going_away() {
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+    assert!(client.call(context::current(), 1).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn health_checks() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let health = server::Health::new();
+    let server_config = server::Config {
+        health: Some(health.clone()),
+        ..Default::default()
+    };
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::new(server_config, rx)
+            .respond_with(|_, x: u32| ready(x + 1))
+            .execute(),
+    );
+
+    let client = client::new(client::Config::default(), tx).spawn()?;
+    assert!(client.check_health().await?);
+    health.set_serving(false);
+    assert!(!client.check_health().await?);
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn proxied() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (backend_tx, backend_rx) = channel::unbounded();
+    let backend_server = future::abortable(
+        BaseChannel::with_defaults(backend_rx)
+            .respond_with(Server.serve())
+            .execute(),
+    );
+    tokio::spawn(backend_server.0);
+    let backend = client::new(client::Config::default(), backend_tx).spawn()?;
+
+    // The proxy authenticates clients, and the backend never sees their keys.
+    let keys: HashMap<_, _> = vec![("key".to_string(), server::ApiKeyPolicy::new())]
+        .into_iter()
+        .collect();
+    let (tx, rx) = channel::unbounded();
+    let proxy = Proxy::new(backend).with_rewrite(|ctx| {
+        assert!(ctx.api_key.is_some());
+        ctx.api_key = None;
+    });
+    let stats = proxy.stats().clone();
+    tokio::spawn(proxy.serve(server::ApiKeyChannel::new(
+        BaseChannel::with_defaults(rx),
+        keys,
+    )));
+
+    let mut client = ServiceClient::new(client::Config::default(), tx).spawn()?;
+    let ctx = context::current().with_api_key("key");
+    assert_eq!(client.add(ctx.clone(), 1, 2).await?, 3);
+    assert_matches!(
+        client.add(context::current(), 1, 2).await,
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied
+    );
+
+    // Requests fail once the backend is gone.
+    backend_server.1.abort();
+    assert_matches!(
+        client.hey(ctx, "Tim".into()).await,
+        Err(e) if e.kind() == io::ErrorKind::ConnectionReset
+    );
+    assert_eq!((stats.forwarded(), stats.failed()), (1, 1));
+    assert_eq!(stats.in_flight(), 0);
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn canary() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let mut backends = vec![];
+    for name in &["stable", "canary"] {
+        let (tx, rx) = channel::unbounded();
+        tokio::spawn(
+            BaseChannel::with_defaults(rx)
+                .respond_with(move |_, _: ()| ready(name.to_string()))
+                .execute(),
+        );
+        backends.push(client::new(client::Config::default(), tx).spawn()?);
+    }
+    let canary = backends.pop().unwrap();
+    let stable = backends.pop().unwrap();
+
+    let (tx, rx) = channel::unbounded();
+    let proxy = Proxy::new(stable).with_canary(canary, 0.0);
+    let (stats, canary) = (proxy.stats().clone(), proxy.canary().unwrap().clone());
+    tokio::spawn(proxy.serve(BaseChannel::with_defaults(rx)));
+
+    let mut client = client::new(client::Config::default(), tx).spawn()?;
+    assert_eq!(client.call(context::current(), ()).await?, "stable");
+    canary.set_fraction(1.0);
+    assert_eq!(client.call(context::current(), ()).await?, "canary");
+    assert_eq!((stats.forwarded(), canary.stats().forwarded()), (1, 1));
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn shadowed() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let mut backends = vec![];
+    for name in &["primary", "shadow"] {
+        let (tx, rx) = channel::unbounded();
+        tokio::spawn(
+            BaseChannel::with_defaults(rx)
+                .respond_with(move |_, _: ()| ready(name.to_string()))
+                .execute(),
+        );
+        backends.push(client::new(client::Config::default(), tx).spawn()?);
+    }
+    let shadow = backends.pop().unwrap();
+    let primary = backends.pop().unwrap();
+
+    let (tx, rx) = channel::unbounded();
+    let proxy = Proxy::new(primary).with_shadow(shadow, 1.0);
+    let stats = proxy.shadow_stats().unwrap().clone();
+    tokio::spawn(proxy.serve(BaseChannel::with_defaults(rx)));
+
+    let mut client = client::new(client::Config::default(), tx).spawn()?;
+    for _ in 0..3 {
+        assert_eq!(client.call(context::current(), ()).await?, "primary");
+    }
+    while stats.forwarded() < 3 {
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+    assert_eq!(stats.failed(), 0);
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn load_reports() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let load = server::Load::new();
+    let server_config = server::Config {
+        load: Some(load.clone()),
+        ..Default::default()
+    };
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::new(server_config, rx)
+            .respond_with(|_, x: u32| ready(x + 1))
+            .execute(),
+    );
+
+    let mut client = client::new(client::Config::default(), tx).spawn()?;
+    assert_eq!(client.server_load(), None);
+    load.set(7);
+    // The report accompanies the first reply, so it's read by the time the second arrives.
+    assert_eq!(client.call(context::current(), 1).await?, 2);
+    assert_eq!(client.call(context::current(), 2).await?, 3);
+    assert_eq!(client.server_load(), Some(7));
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn concurrent() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        tarpc::Server::default()
+            .incoming(stream::once(ready(rx)))
+            .respond_with(Server.serve()),
+    );
+
+    let client = ServiceClient::new(client::Config::default(), tx).spawn()?;
+
+    let mut c = client.clone();
+    let req1 = c.add(context::current(), 1, 2);
+
+    let mut c = client.clone();
+    let req2 = c.add(context::current(), 3, 4);
+
+    let mut c = client.clone();
+    let req3 = c.hey(context::current(), "Tim".to_string());
+
+    assert_matches!(req1.await, Ok(3));
+    assert_matches!(req2.await, Ok(7));
+    assert_matches!(req3.await, Ok(ref s) if s == "Hey, Tim.");
+
+    Ok(())
+}
+
+#[tarpc_plugins::service]
+trait Counter {
+    async fn count(n: u32) -> impl Stream<Item = u32>;
+    async fn total(n: u32) -> u32;
+}
+
+#[derive(Clone)]
+struct CountingServer;
+
+impl Counter for CountingServer {
+    type CountStream = stream::Iter<std::ops::Range<u32>>;
+
+    fn count(self, _: context::Context, n: u32) -> Self::CountStream {
+        stream::iter(0..n)
+    }
+
+    type TotalFut = Ready<u32>;
+
+    fn total(self, _: context::Context, n: u32) -> Self::TotalFut {
+        ready((0..n).sum())
+    }
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn streaming() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::new(server::Config::default(), rx)
+            .respond_with_stream(CountingServer.serve())
+            .execute(),
+    );
+
+    let mut client = CounterClient::new(client::Config::default(), tx).spawn()?;
+
+    let counts = client.count(context::current(), 3).await?;
+    let counts: Vec<u32> = counts.try_collect().await?;
+    assert_eq!(counts, vec![0, 1, 2]);
+    assert_matches!(client.total(context::current(), 3).await, Ok(3));
+    let counts = client.count(context::current(), 0).await?;
+    assert_eq!(counts.try_collect::<Vec<_>>().await?, Vec::<u32>::new());
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn streaming_request() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::new(server::Config::default(), rx)
+            .respond_with_stream(server::with_items(
+                |_: context::Context, first: u32, items: server::RequestItems<u32>| {
+                    items.fold(first, |sum, item| async move { sum + item })
+                },
+            ))
+            .execute(),
+    );
+
+    let mut client = client::new(client::Config::default(), tx).spawn()?;
+
+    let (mut items, response) = client.call_with_items(context::current(), 1).await?;
+    items.send(2).await?;
+    items.send(3).await?;
+    items.close().await?;
+    assert_eq!(response.await?, 6);
+
+    // A request without items still gets a response.
+    assert_eq!(client.call(context::current(), 4).await?, 4);
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn bidirectional() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::new(server::Config::default(), rx)
+            .respond_with_stream(server::bidirectional(
+                |_: context::Context, first: u32, items: server::RequestItems<u32>| {
+                    // Keeps replying after the client stops sending items.
+                    stream::once(ready(first))
+                        .chain(items.map(|item| item * 2))
+                        .chain(stream::once(ready(0)))
+                },
+            ))
+            .execute(),
+    );
+
+    let mut client = client::new(client::Config::default(), tx).spawn()?;
+
+    let (mut items, mut reply) = client.call_bidirectional(context::current(), 1).await?;
+    assert_eq!(reply.next().await.transpose()?, Some(1));
+    items.send(2).await?;
+    assert_eq!(reply.next().await.transpose()?, Some(4));
+    items.send(3).await?;
+    items.close().await?;
+    let rest: Vec<u32> = reply.try_collect().await?;
+    assert_eq!(rest, vec![6, 0]);
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn notifications() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    let channel = BaseChannel::with_defaults(rx);
+    let mut notifier = channel.notifier();
+    tokio::spawn(channel.respond_with(Server.serve()).execute());
+
+    let client = ServiceClient::new(client::Config::default(), tx).spawn()?;
+    let mut notifications = client.notifications();
+    notifier.notify(ServiceResponse::Hey("pushed".into()))?;
+    assert_matches!(
+        notifications.next().await,
+        Some(ServiceResponse::Hey(ref s)) if s == "pushed"
+    );
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn server_calls_back_into_client() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (client_conn, server_conn) = channel::unbounded();
+    let client_side = duplex::split(client_conn);
+    let server_side = duplex::split(server_conn);
+    tokio::spawn(client_side.driver);
+    tokio::spawn(server_side.driver);
+
+    // The client doubles numbers for the server.
+    tokio::spawn(
+        BaseChannel::with_defaults(client_side.reverse)
+            .respond_with(|_: context::Context, x: u32| ready(x * 2))
+            .execute(),
+    );
+    let callbacks = client::new(client::Config::default(), server_side.reverse).spawn()?;
+    tokio::spawn(
+        BaseChannel::with_defaults(server_side.forward)
+            .respond_with(move |ctx: context::Context, x: u32| {
+                let mut callbacks = callbacks.clone();
+                async move { callbacks.call(ctx, x).await.unwrap_or(0) + 1 }
+            })
+            .execute(),
+    );
+
+    let mut client = client::new(client::Config::default(), client_side.forward).spawn()?;
+    assert_eq!(client.call(context::current(), 3).await?, 7);
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn symmetric_peers() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (conn_a, conn_b) = channel::unbounded();
+    let mut clients = vec![];
+    for (conn, greeting) in [(conn_a, "a"), (conn_b, "b")] {
+        let peer = duplex::peer(conn);
+        tokio::spawn(peer.driver);
+        tokio::spawn(
+            BaseChannel::with_defaults(peer.server)
+                .respond_with(move |_: context::Context, name: String| {
+                    ready(format!("{} greets {}", greeting, name))
+                })
+                .execute(),
+        );
+        clients.push(client::new(client::Config::default(), peer.client).spawn()?);
+    }
+
+    // Both peers call each other at the same time.
+    let (mut b, mut a) = (clients.pop().unwrap(), clients.pop().unwrap());
+    let (from_b, from_a) = future::join(
+        a.call(context::current(), "a".into()),
+        b.call(context::current(), "b".into()),
+    )
+    .await;
+    assert_eq!(from_b?, "b greets a");
+    assert_eq!(from_a?, "a greets b");
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn progress() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .respond_with_stream(server::with_progress(
+                |_: context::Context, steps: u32, progress: server::Progress<u32>| async move {
+                    for step in 0..steps {
+                        progress.report(step).unwrap();
+                    }
+                    steps
+                },
+            ))
+            .execute(),
+    );
+
+    let mut client = client::new(client::Config::default(), tx).spawn()?;
+    let (progress, response) = client.call_with_progress(context::current(), 3).await?;
+    assert_eq!(response.await?, 3);
+    assert_eq!(progress.collect::<Vec<_>>().await, vec![0, 1, 2]);
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn small_stream_windows() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let server_config = server::Config {
+        stream_window: 2,
+        ..Default::default()
+    };
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::new(server_config, rx)
+            .respond_with_stream(server::bidirectional(
+                |_: context::Context, _: u32, items: server::RequestItems<u32>| items,
+            ))
+            .execute(),
+    );
+
+    let mut client_config = client::Config::default();
+    client_config.stream_window = 1;
+    let mut client = client::new(client_config, tx).spawn()?;
+
+    let (mut items, reply) = client.call_bidirectional(context::current(), 0).await?;
+    tokio::spawn(async move {
+        for i in 0..20 {
+            items.send(i).await.unwrap();
+        }
+        items.close().await.unwrap();
+    });
+    let reply: Vec<u32> = reply.try_collect().await?;
+    assert_eq!(reply, (0..20).collect::<Vec<_>>());
+
+    Ok(())
+}
+
+#[tarpc_plugins::service]
+trait Store {
+    #[tarpc(deadline = "1s", idempotent, retries = 2, cache = "1m")]
+    async fn get(key: String) -> String;
+    #[tarpc(deadline = "1s")]
+    async fn put(key: String, value: String);
+}
+
+/// Fails calls with `ConnectionReset` until `failures` runs out, recording the calls' contexts.
+struct Flaky {
+    failures: u32,
+    calls: Vec<context::Context>,
+}
+
+impl<'a> tarpc::Client<'a, StoreRequest> for &mut Flaky {
+    type Response = StoreResponse;
+    type Future = Ready<io::Result<StoreResponse>>;
+
+    fn call(&'a mut self, ctx: context::Context, request: StoreRequest) -> Self::Future {
+        self.calls.push(ctx);
+        if self.failures > 0 {
+            self.failures -= 1;
+            return ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        ready(Ok(match request {
+            StoreRequest::Get { key } => StoreResponse::Get(key),
+            StoreRequest::Put { .. } => StoreResponse::Put(()),
+        }))
+    }
+}
+
+#[tokio::test]
+async fn method_policies() -> io::Result<()> {
+    let mut flaky = Flaky {
+        failures: 2,
+        calls: vec![],
+    };
+    let mut client = StoreClient::from(&mut flaky);
+    assert_eq!(client.get(context::current(), "key".into()).await?, "key");
+    assert_matches!(
+        client
+            .put(context::current(), "key".into(), "value".into())
+            .await,
+        Ok(())
+    );
+    assert_eq!(flaky.calls.len(), 4);
+    let latest = std::time::SystemTime::now() + Duration::from_secs(1);
+    assert!(flaky.calls.iter().all(|ctx| ctx.deadline <= latest));
+
+    // Retries run out, and methods that aren't idempotent aren't retried.
+    flaky.failures = 4;
+    flaky.calls.clear();
+    let mut client = StoreClient::from(&mut flaky);
+    assert_matches!(client.get(context::current(), "key".into()).await, Err(e) if e.kind() == io::ErrorKind::ConnectionReset);
+    assert_matches!(
+        client
+            .put(context::current(), "key".into(), "value".into())
+            .await,
+        Err(_)
+    );
+    assert_eq!(flaky.calls.len(), 4);
+
+    // A context's deadline isn't extended.
+    let mut ctx = context::current();
+    ctx.deadline = std::time::SystemTime::now() + Duration::from_millis(10);
+    let deadline = ctx.deadline;
+    flaky.failures = 0;
+    flaky.calls.clear();
+    StoreClient::from(&mut flaky).get(ctx, "key".into()).await?;
+    assert_eq!(flaky.calls[0].deadline, deadline);
+
+    // Cached responses don't reach the server.
+    flaky.calls.clear();
+    let mut client = StoreClient::from(client::cache::Cache::new(&mut flaky, 10));
+    for _ in 0..3 {
+        assert_eq!(client.get(context::current(), "key".into()).await?, "key");
+    }
+    client
+        .put(context::current(), "key".into(), "value".into())
+        .await?;
+    client
+        .put(context::current(), "key".into(), "value".into())
+        .await?;
+    assert_eq!(flaky.calls.len(), 3);
+
+    Ok(())
+}
+
+#[tarpc_plugins::service]
+trait Bank {
+    #[tarpc(validate = "positive")]
+    async fn withdraw(amount: i64) -> i64;
+    async fn balance() -> i64;
+}
+
+fn positive(amount: &i64) -> Result<(), String> {
+    if *amount > 0 {
+        Ok(())
+    } else {
+        Err("Amounts must be positive.".into())
+    }
+}
+
+#[derive(Clone)]
+struct BankServer;
+
+impl Bank for BankServer {
+    type WithdrawFut = Ready<i64>;
+
+    fn withdraw(self, _: context::Context, amount: i64) -> Self::WithdrawFut {
+        ready(100 - amount)
+    }
+
+    type BalanceFut = Ready<i64>;
+
+    fn balance(self, _: context::Context) -> Self::BalanceFut {
+        ready(100)
+    }
+}
+
+#[tokio::test]
+async fn declared_validators() -> io::Result<()> {
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        stream::once(future::ready(BaseChannel::with_defaults(rx)))
+            .validate_requests(server::Declared)
+            .respond_with(BankServer.serve()),
+    );
+    let mut client = BankClient::new(client::Config::default(), tx).spawn()?;
+
+    assert_eq!(client.withdraw(context::current(), 10).await?, 90);
+    assert_matches!(
+        client.withdraw(context::current(), -10).await,
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput
+            && e.to_string() == "Amounts must be positive."
+    );
+    assert_eq!(client.balance(context::current()).await?, 100);
+
+    Ok(())
+}
+
+#[tarpc_plugins::service]
+trait Ledger {
+    #[tarpc(exactly_once)]
+    async fn deposit(amount: u64) -> u64;
+}
+
+#[derive(Clone, Default)]
+struct LedgerServer {
+    balance: std::sync::Arc<std::sync::atomic::AtomicU64>,
+}
+
+impl Ledger for LedgerServer {
+    type DepositFut = Ready<u64>;
+
+    fn deposit(self, ctx: context::Context, amount: u64) -> Self::DepositFut {
+        assert!(ctx.idempotency_key.is_some());
+        ready(
+            self.balance
+                .fetch_add(amount, std::sync::atomic::Ordering::SeqCst)
+                + amount,
+        )
+    }
+}
+
+#[tokio::test]
+async fn exactly_once() -> io::Result<()> {
+    let (tx, rx) = channel::unbounded();
+    let store = server::MemoryDedupStore::new(100, Duration::from_secs(60));
+    tokio::spawn(
+        stream::once(future::ready(BaseChannel::with_defaults(rx)))
+            .exactly_once(store, |request: &tarpc::Request<_>| {
+                request.context.tenant_id.clone().unwrap_or_default()
+            })
+            .respond_with(LedgerServer::default().serve()),
+    );
+    let mut client = LedgerClient::new(client::Config::default(), tx).spawn()?;
+
+    assert_eq!(client.deposit(context::current(), 10).await?, 10);
+    // A retransmission is answered with the recorded response.
+    let ctx = context::current().with_idempotency_key("deposit-1");
+    assert_eq!(client.deposit(ctx.clone(), 5).await?, 15);
+    assert_eq!(client.deposit(ctx, 5).await?, 15);
+    assert_eq!(client.deposit(context::current(), 5).await?, 20);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn handlers_run_in_the_scope_of_their_request() -> io::Result<()> {
+    #[tarpc_plugins::service]
+    trait Scoped {
+        async fn in_scope() -> bool;
+    }
+
+    #[derive(Clone)]
+    struct ScopedServer;
+
+    impl Scoped for ScopedServer {
+        type InScopeFut = Ready<bool>;
+
+        fn in_scope(self, ctx: context::Context) -> Self::InScopeFut {
+            let downstream = context::current();
+            ready(
+                downstream.trace_id() == ctx.trace_id()
+                    && downstream.deadline == ctx.deadline
+                    && downstream.api_key == ctx.api_key,
+            )
+        }
+    }
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .respond_with(ScopedServer.serve())
+            .execute(),
+    );
+    let mut client = ScopedClient::new(client::Config::default(), tx).spawn()?;
+    let ctx = context::current().with_api_key("key");
+    assert!(client.in_scope(ctx).await?);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn canceling_a_request_cancels_its_calls() -> io::Result<()> {
+    #[tarpc_plugins::service]
+    trait Downstream {
+        async fn hang();
+    }
+
+    #[derive(Clone)]
+    struct DownstreamServer;
+
+    impl Downstream for DownstreamServer {
+        type HangFut = future::Pending<()>;
+
+        fn hang(self, _: context::Context) -> Self::HangFut {
+            future::pending()
+        }
+    }
+
+    #[tarpc_plugins::service]
+    trait Upstream {
+        async fn fan_out();
+    }
+
+    #[derive(Clone)]
+    struct UpstreamServer {
+        downstream: DownstreamClient,
+        results: futures::channel::mpsc::UnboundedSender<io::Result<()>>,
+    }
+
+    impl Upstream for UpstreamServer {
+        type FanOutFut = future::Pending<()>;
+
+        fn fan_out(self, _: context::Context) -> Self::FanOutFut {
+            let ctx = context::current();
+            let mut downstream = self.downstream;
+            let results = self.results;
+            // The spawned call outlives the handler's future.
+            tokio::spawn(async move {
+                let _ = results.unbounded_send(downstream.hang(ctx).await);
+            });
+            future::pending()
+        }
+    }
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .respond_with(DownstreamServer.serve())
+            .execute(),
+    );
+    let downstream = DownstreamClient::new(client::Config::default(), tx).spawn()?;
+    let (results, mut call_results) = futures::channel::mpsc::unbounded();
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .respond_with(
+                UpstreamServer {
+                    downstream,
+                    results,
+                }
+                .serve(),
+            )
+            .execute(),
+    );
+    let mut client = UpstreamClient::new(client::Config::default(), tx).spawn()?;
+
+    let call = client.fan_out(context::current());
+    assert!(tokio::time::timeout(Duration::from_millis(50), call)
+        .await
+        .is_err());
+    let error = call_results.next().await.unwrap().unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::Interrupted);
+
+    Ok(())
+}
+
+#[tarpc_plugins::service]
+trait Sleep {
+    async fn sleep(millis: u64) -> u64;
+}
+
+#[derive(Clone)]
+struct SleepServer;
+
+impl Sleep for SleepServer {
+    type SleepFut = future::BoxFuture<'static, u64>;
+
+    fn sleep(self, _: context::Context, millis: u64) -> Self::SleepFut {
+        tokio::time::delay_for(Duration::from_millis(millis))
+            .map(move |()| millis)
+            .boxed()
+    }
+}
+
+#[tokio::test]
+async fn replies_are_ordered_when_the_client_asks() -> io::Result<()> {
+    async fn replies(reply_order: tarpc::ReplyOrder) -> io::Result<Vec<u64>> {
+        let (tx, rx) = channel::unbounded();
+        tokio::spawn(
+            BaseChannel::with_defaults(rx)
+                .respond_with(SleepServer.serve())
+                .execute(),
+        );
+        let mut config = client::Config::default();
+        config.reply_order = reply_order;
+        let client = SleepClient::new(config, tx).spawn()?;
+        let mut calls: stream::FuturesUnordered<_> = vec![50, 0]
+            .into_iter()
+            .map(|millis| {
+                let mut client = client.clone();
+                async move { client.sleep(context::current(), millis).await }
+            })
+            .collect();
+        let mut replies = vec![];
+        while let Some(reply) = calls.next().await {
+            replies.push(reply?);
+        }
+        Ok(replies)
+    }
+
+    assert_eq!(replies(tarpc::ReplyOrder::Completion).await?, vec![0, 50]);
+    assert_eq!(replies(tarpc::ReplyOrder::Request).await?, vec![50, 0]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn clients_needing_ordered_replies_give_up_on_servers_without_them() -> io::Result<()> {
+    let (tx, rx) = channel::unbounded();
+    let config = server::Config {
+        ordered_replies: false,
+        ..server::Config::default()
+    };
+    tokio::spawn(
+        BaseChannel::new(config, rx)
+            .respond_with(SleepServer.serve())
+            .execute(),
+    );
+    let mut config = client::Config::default();
+    config.reply_order = tarpc::ReplyOrder::Request;
+    let mut client = SleepClient::new(config, tx).spawn()?;
+    assert!(client.sleep(context::current(), 0).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn clients_wait_for_their_connections_to_be_ready() -> io::Result<()> {
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .respond_with(Server.serve())
+            .execute(),
+    );
+    let client = ServiceClient::new(client::Config::default(), tx).spawn()?;
+    client.wait_connected(Duration::from_secs(5)).await?;
+
+    // Connection setup errors are reported apart from requests.
+    let (tx, rx) = channel::unbounded();
+    let config = server::Config {
+        ordered_replies: false,
+        ..server::Config::default()
+    };
+    tokio::spawn(
+        BaseChannel::new(config, rx)
+            .respond_with(Server.serve())
+            .execute(),
+    );
+    let mut config = client::Config::default();
+    config.reply_order = tarpc::ReplyOrder::Request;
+    let client = ServiceClient::new(config, tx).spawn()?;
+    assert_matches!(
+        client.ready().await,
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused
+    );
+
+    // No one answers.
+    let (tx, _rx) = channel::unbounded::<_, tarpc::ClientMessage<ServiceRequest>>();
+    let client = ServiceClient::new(client::Config::default(), tx).spawn()?;
+    assert_matches!(
+        client.wait_connected(Duration::from_millis(50)).await,
+        Err(e) if e.kind() == io::ErrorKind::TimedOut
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn busy_servers_push_back_on_clients() -> io::Result<()> {
+    async fn call_while_busy(backpressure: client::Backpressure) -> io::Result<io::Result<u64>> {
+        let (tx, rx) = channel::unbounded();
+        let config = server::Config {
+            request_window: Some(1),
+            ..server::Config::default()
+        };
+        tokio::spawn(
+            BaseChannel::new(config, rx)
+                .respond_with(SleepServer.serve())
+                .execute(),
+        );
+        let mut config = client::Config::default();
+        config.backpressure = backpressure;
+        let mut client = SleepClient::new(config, tx).spawn()?;
+        // Once a request completes and its credit is returned, the client knows its credit.
+        client.sleep(context::current(), 0).await?;
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+
+        let mut busy = client.clone();
+        let busy = tokio::spawn(async move { busy.sleep(context::current(), 50).await });
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+        let reply = client.sleep(context::current(), 0).await;
+        busy.await.unwrap()?;
+        Ok(reply)
+    }
+
+    let error = call_while_busy(client::Backpressure::Fail)
+        .await?
+        .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+    assert_eq!(call_while_busy(client::Backpressure::Wait).await??, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn sessions_are_resumed_after_reconnecting() -> io::Result<()> {
+    #[tarpc_plugins::service]
+    trait Session {
+        async fn current_session() -> Option<String>;
+    }
+
+    #[derive(Clone)]
+    struct SessionServer;
+
+    impl Session for SessionServer {
+        type CurrentSessionFut = Ready<Option<String>>;
+
+        fn current_session(self, ctx: context::Context) -> Self::CurrentSessionFut {
+            ready(ctx.session().map(ToString::to_string))
+        }
+    }
+
+    let sessions = server::Sessions::new(Duration::from_secs(60));
+    let connect = |session| {
+        let (tx, rx) = channel::unbounded();
+        let config = server::Config {
+            sessions: Some(sessions.clone()),
+            ..server::Config::default()
+        };
+        tokio::spawn(
+            BaseChannel::new(config, rx)
+                .respond_with(SessionServer.serve())
+                .execute(),
+        );
+        let mut config = client::Config::default();
+        config.session = session;
+        SessionClient::new(config, tx).spawn()
+    };
+
+    let mut client = connect(Some(tarpc::SessionRequest::Start))?;
+    let token = client.current_session(context::current()).await?.unwrap();
+    assert_eq!(client.0.session().unwrap().as_str(), token);
+    drop(client);
+
+    let resume = tarpc::SessionRequest::Resume(token.clone().into());
+    let mut client = connect(Some(resume))?;
+    assert_eq!(
+        client.current_session(context::current()).await?,
+        Some(token.clone())
+    );
+
+    let resume = tarpc::SessionRequest::Resume("unknown".to_string().into());
+    let mut client = connect(Some(resume))?;
+    let new_token = client.current_session(context::current()).await?.unwrap();
+    assert_ne!(new_token, token);
+    assert!(sessions.contains(&new_token.into()));
+
+    let mut client = connect(None)?;
+    assert_eq!(client.current_session(context::current()).await?, None);
+
+    Ok(())
+}
+
+#[cfg(feature = "config")]
+#[test]
+fn config_from_file() -> io::Result<()> {
+    let dir = std::env::temp_dir().join(format!("tarpc-config-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+
+    let client_path = dir.join("client.toml");
+    std::fs::write(
+        &client_path,
+        r#"
+            max_in_flight_requests = 10
+            balance = "least_loaded"
+
+            [health_checks]
+            interval = "500ms"
+        "#,
+    )?;
+    let client_config = client::Config::from_file(&client_path)?;
+    assert_eq!(client_config.max_in_flight_requests, 10);
+    assert_eq!(client_config.pending_request_buffer, 100);
+    assert_eq!(client_config.balance, client::Balance::LeastLoaded);
+    let health_checks = client_config.health_checks.unwrap();
+    assert_eq!(health_checks.interval, Duration::from_millis(500));
+    assert_eq!(health_checks.timeout, Duration::from_secs(1));
+
+    let server_path = dir.join("server.json");
+    std::fs::write(&server_path, r#"{"stream_window": 8}"#)?;
+    let server_config = server::Config::from_file(&server_path)?;
+    assert_eq!(server_config.stream_window, 8);
+    assert_eq!(server_config.pending_response_buffer, 100);
+    assert!(server_config.drain.is_none());
+
+    std::fs::write(&server_path, r#"{"stream_window": "eight"}"#)?;
+    assert_matches!(
+        server::Config::from_file(&server_path),
+        Err(e) if e.kind() == io::ErrorKind::InvalidData
+    );
+    assert_matches!(
+        server::Config::from_file(dir.join("server.yaml")),
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput
+    );
+
+    std::fs::remove_dir_all(&dir)
+}